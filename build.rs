@@ -0,0 +1,14 @@
+fn main() {
+    // The gRPC control service (see `onetun::control::grpc`) is generated from its definition
+    #[cfg(feature = "grpc")]
+    {
+        let protoc =
+            protoc_bin_vendored::protoc_bin_path().expect("No bundled protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        println!("cargo:rerun-if-changed=proto/onetun/control/v1/control.proto");
+        tonic_build::configure()
+            .build_client(true)
+            .compile(&["proto/onetun/control/v1/control.proto"], &["proto"])
+            .expect("Failed to generate the gRPC control service");
+    }
+}
@@ -1,14 +1,131 @@
-/// Starts the tunnel
-/// # Arguments
-/// * `pointer` - pointer to the config created with `create_wireguard_config`
-/// # Returns
-/// * `0` - on success
-extern int start_wireguard_tunnel(void*);
+#include <stddef.h>
+#include <stdint.h>
+
+/// A tunnel configuration, created with create_wireguard_config.
+/// It is reference-counted: each reference must be released with onetun_config_free.
+typedef struct onetun_config onetun_config;
+
+/// A running tunnel, created by one of the start_wireguard_tunnel functions.
+/// It is reference-counted: each reference must be released with onetun_handle_free.
+/// The tunnel is killed when its last reference is released.
+typedef struct onetun_handle onetun_handle;
+
+/// A local port forward, created with create_port_forward and added to a configuration with
+/// onetun_config_with_port_forward. It is reference-counted: each reference must be released with
+/// onetun_port_forward_free.
+typedef struct onetun_port_forward onetun_port_forward;
+
+/// Adds a reference to a configuration, to be released with onetun_config_free.
+extern void onetun_config_retain(const onetun_config*);
+
+/// Releases a reference to a configuration. Does nothing if NULL.
+extern void onetun_config_free(const onetun_config*);
+
+/// Adds a reference to a tunnel handle, to be released with onetun_handle_free.
+extern void onetun_handle_retain(const onetun_handle*);
+
+/// Releases a reference to a tunnel handle. Does nothing if NULL.
+extern void onetun_handle_free(const onetun_handle*);
+
+/// Returns the identifier of the tunnel, unique among the tunnels started in the process, or 0 if NULL.
+extern uint32_t onetun_handle_id(const onetun_handle*);
+
+/// Starts the tunnel. The config is not consumed.
+/// Returns the handle to the tunnel on success, or NULL on failure (see onetun_last_error).
+extern const onetun_handle* start_wireguard_tunnel(const onetun_config*);
+
+/// Starts the tunnel on a tun device (such as Android's VpnService) instead of port forwards.
+/// Takes ownership of the file descriptor, which is closed when the tunnel is killed. The config is not consumed.
+/// Returns the handle to the tunnel on success, or NULL on failure (see onetun_last_error).
+extern const onetun_handle* start_wireguard_tunnel_with_fd(const onetun_config*, int);
+
+/// Starts the tunnel on the embedder's packet flow (such as iOS NEPacketTunnelFlow) instead of port forwards.
+/// The config is not consumed.
+/// Returns the handle to the tunnel on success, or NULL on failure (see onetun_last_error).
+extern const onetun_handle* start_wireguard_tunnel_with_packet_flow(const onetun_config*);
+
+/// Kills the tunnel. Killing it again has no effect. The handle must still be released with onetun_handle_free.
+extern void kill_wireguard_tunnel(const onetun_handle*);
+
+/// Sends a raw IP packet through a tunnel started with start_wireguard_tunnel_with_packet_flow.
+/// Returns 0 on success, -1 on invalid arguments.
+extern int onetun_write_ip_packet(const onetun_handle*, const uint8_t*, size_t);
+
+/// Receives the raw IP packets of a tunnel. The buffer is only valid during the call.
+typedef void (*onetun_packet_callback)(void* context, const uint8_t* buf, size_t len);
+
+/// Registers the callback receiving the IP packets of a tunnel started with
+/// start_wireguard_tunnel_with_packet_flow (NULL to unregister). It is called from one of the tunnel's threads.
+extern void onetun_set_packet_callback(const onetun_handle*, onetun_packet_callback, void* context);
+
+/// Learns whether a tunnel is ready: ready is 1 once the tunnel is usable, or 0 if it was killed first.
+typedef void (*onetun_ready_callback)(void* context, int ready);
+
+/// Calls the callback once, from another thread, when the first handshake with the WireGuard endpoint completed
+/// and the local port forwards listen. Returns 0 on success, -1 on invalid arguments.
+extern int onetun_on_ready(const onetun_handle*, onetun_ready_callback, void* context);
+
+/// Inspects an IP packet going through the virtual interfaces of a tunnel. direction is 0 for packets received
+/// from the tunnel, and 1 for packets sent into it. The packet may be rewritten in place, keeping its length.
+/// Returns 0 to pass the packet on, or any other value to drop it.
+typedef int (*onetun_packet_filter)(void* context, int direction, uint8_t* buf, size_t len);
+
+/// Creates a copy of the config that passes the IP packets of the local port forwards to the filter,
+/// called from one of the tunnel's threads. The config is not consumed, and the context must stay valid
+/// as long as a tunnel uses the new config. Returns the new config, or NULL on invalid arguments.
+/// It must be released with onetun_config_free.
+extern const onetun_config* onetun_config_with_packet_filter(const onetun_config*, onetun_packet_filter,
+                                                             void* context);
+
+/// Decides whether to serve a new client of the local port forwards. source is the address the port forward
+/// listens on, and client the address of the client, both as "host:port" strings only valid during the call.
+/// Returns 0 to serve the client, or any other value to reject it.
+typedef int (*onetun_connection_authorizer)(void* context, const char* source, const char* client);
+
+/// Creates a copy of the config that asks the authorizer whether to serve each new client of the local port
+/// forwards, called from one of the tunnel's threads. The config is not consumed, and the context must stay
+/// valid as long as a tunnel uses the new config. Returns the new config, or NULL on invalid arguments.
+/// It must be released with onetun_config_free.
+extern const onetun_config* onetun_config_with_connection_authorizer(const onetun_config*,
+                                                                     onetun_connection_authorizer,
+                                                                     void* context);
 
 /// Creates a port forward and returns the pointer to it on success
-/// or NULL on failure.
-extern int create_port_forward(char, char, char);
+/// or NULL on failure. It must be released with onetun_port_forward_free.
+extern const onetun_port_forward* create_port_forward(const char* source, const char* destination,
+                                                      const char* protocol);
+
+/// Adds a reference to a port forward, to be released with onetun_port_forward_free.
+extern void onetun_port_forward_retain(const onetun_port_forward*);
+
+/// Releases a reference to a port forward. Does nothing if NULL.
+extern void onetun_port_forward_free(const onetun_port_forward*);
+
+/// Creates a copy of the config with another local port forward. Neither the config nor the port forward is
+/// consumed. Returns the new config, or NULL on invalid arguments. It must be released with onetun_config_free.
+extern const onetun_config* onetun_config_with_port_forward(const onetun_config*, const onetun_port_forward*);
 
 /// Creates a Wireguard configuration and returns the pointer to it on success
-/// or NULL on failure.
-extern void* create_wireguard_config(const char*, const char*, const char*, const char*);
+/// or NULL on failure. It must be released with onetun_config_free.
+extern const onetun_config* create_wireguard_config(const char* endpoint, const char* assigned_ip,
+                                                    const char* public_key, const char* private_key);
+
+/// Receives a log record: its level (1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace),
+/// target (the module that logged it) and message. The strings are only valid during the call.
+typedef void (*onetun_log_callback)(int level, const char* target, const char* msg);
+
+/// Forwards the logs of onetun to the callback, from any thread, instead of printing them to stderr.
+/// Must be called before starting the first tunnel (NULL to unregister). Records up to the info level are forwarded.
+/// Returns 0 on success, -1 if another logger was already installed.
+extern int set_log_callback(onetun_log_callback);
+
+/// Sets the most verbose level of the logs forwarded to the callback: 0 = off, 1 = error, 2 = warn,
+/// 3 = info, 4 = debug, 5 = trace. Takes effect immediately. Returns 0 on success, -1 on an invalid level.
+extern int onetun_set_log_level(int);
+
+/// Returns the code of the last error that occurred on the calling thread, or 0 if none.
+/// 1: invalid configuration, 2: handshake timeout, 3: failed to bind a socket,
+/// 4: endpoint unreachable, 5: failed to initialize the WireGuard tunnel, 6: failed to start the async runtime,
+/// 7: another instance registered in the lock directory uses the same resources,
+/// 8: the tunnel was killed before it was ready
+extern int onetun_last_error(void);
@@ -0,0 +1,550 @@
+//! The high-level interface of onetun, from which UniFFI generates the Swift and Kotlin bindings.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use onetun::audit::Initiator;
+use onetun::config::{self, PortForwardConfig, PortProtocol};
+use onetun::connection_authorizer;
+use onetun::events::Event;
+use onetun::packet_filter;
+use onetun::Handle;
+
+use crate::log_bridge;
+
+/// Errors returned by the bindings. The message carries the details.
+#[derive(Debug, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum OnetunError {
+    /// The configuration is invalid (bad keys, addresses, etc.)
+    InvalidConfig(String),
+    /// The WireGuard peer did not complete a handshake in time.
+    HandshakeTimeout(String),
+    /// A socket could not be bound.
+    BindFailed(String),
+    /// The WireGuard endpoint could not be reached.
+    EndpointUnreachable(String),
+    /// The WireGuard tunnel could not be initialized.
+    Tunnel(String),
+    /// The async runtime could not be started.
+    Runtime(String),
+    /// The logs could not be forwarded, because another logger was installed.
+    Logger(String),
+    /// Another instance registered in the lock directory uses the same resources.
+    InstanceConflict(String),
+    /// The tunnel was stopped before it was ready.
+    Stopped(String),
+}
+
+impl From<onetun::error::OnetunError> for OnetunError {
+    fn from(e: onetun::error::OnetunError) -> Self {
+        use onetun::error::OnetunError as E;
+        let message = e.to_string();
+        match e {
+            E::Config(_) => Self::InvalidConfig(message),
+            E::HandshakeTimeout => Self::HandshakeTimeout(message),
+            E::BindFailed { .. } => Self::BindFailed(message),
+            E::EndpointUnreachable { .. } => Self::EndpointUnreachable(message),
+            E::Tunnel(_) => Self::Tunnel(message),
+            E::Runtime(_) => Self::Runtime(message),
+            E::InstanceConflict { .. } => Self::InstanceConflict(message),
+            E::Killed => Self::Stopped(message),
+        }
+    }
+}
+
+impl Display for OnetunError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidConfig(message)
+            | Self::HandshakeTimeout(message)
+            | Self::BindFailed(message)
+            | Self::EndpointUnreachable(message)
+            | Self::Tunnel(message)
+            | Self::Runtime(message)
+            | Self::Logger(message)
+            | Self::InstanceConflict(message)
+            | Self::Stopped(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for OnetunError {}
+
+/// The transport protocol of a port forward.
+#[derive(Debug, Clone, Copy, uniffi::Enum)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl From<Protocol> for PortProtocol {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Tcp => PortProtocol::Tcp,
+            Protocol::Udp => PortProtocol::Udp,
+        }
+    }
+}
+
+/// A local port forward: connections to `source` are forwarded to `destination` through the tunnel.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PortForward {
+    /// The local IP and port to listen on, such as `127.0.0.1:8080`.
+    pub source: String,
+    /// The IP and port of the peer to forward to, such as `192.168.4.2:8080`.
+    pub destination: String,
+    pub protocol: Protocol,
+    /// A name identifying the port forward in logs and statistics.
+    pub name: Option<String>,
+}
+
+impl TryFrom<PortForward> for PortForwardConfig {
+    type Error = OnetunError;
+
+    fn try_from(pf: PortForward) -> Result<Self, Self::Error> {
+        let source = parse(&pf.source, "port forward source")?;
+        let destination = parse(&pf.destination, "port forward destination")?;
+        let mut config = PortForwardConfig::new(source, destination, pf.protocol.into());
+        config.name = pf.name.map(Arc::from);
+        Ok(config)
+    }
+}
+
+/// Builds the configuration of a tunnel.
+#[derive(uniffi::Object)]
+pub struct ConfigBuilder {
+    options: Mutex<ConfigOptions>,
+}
+
+#[derive(Clone)]
+struct ConfigOptions {
+    endpoint: String,
+    assigned_ip: String,
+    private_key: String,
+    endpoint_public_key: String,
+    port_forwards: Vec<PortForward>,
+    keepalive_seconds: Option<u16>,
+    mtu: Option<u32>,
+    log_level: Option<String>,
+    audit_log: Option<String>,
+    packet_filter: Option<Arc<dyn packet_filter::PacketFilter>>,
+    connection_authorizer: Option<Arc<dyn connection_authorizer::ConnectionAuthorizer>>,
+}
+
+#[uniffi::export]
+impl ConfigBuilder {
+    /// Starts a configuration for the given WireGuard endpoint (`host:port`), with the IP address
+    /// assigned to this peer and the base64-encoded keys.
+    #[uniffi::constructor]
+    pub fn new(
+        endpoint: String,
+        assigned_ip: String,
+        private_key: String,
+        endpoint_public_key: String,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            options: Mutex::new(ConfigOptions {
+                endpoint,
+                assigned_ip,
+                private_key,
+                endpoint_public_key,
+                port_forwards: vec![],
+                keepalive_seconds: None,
+                mtu: None,
+                log_level: None,
+                audit_log: None,
+                packet_filter: None,
+                connection_authorizer: None,
+            }),
+        })
+    }
+
+    /// Adds a local port forward.
+    pub fn port_forward(self: Arc<Self>, port_forward: PortForward) -> Arc<Self> {
+        self.options
+            .lock()
+            .unwrap()
+            .port_forwards
+            .push(port_forward);
+        self
+    }
+
+    /// Sends a keep-alive packet to the endpoint at the given interval.
+    pub fn keepalive(self: Arc<Self>, seconds: u16) -> Arc<Self> {
+        self.options.lock().unwrap().keepalive_seconds = Some(seconds);
+        self
+    }
+
+    /// Sets the MTU of the tunnel (1420 by default).
+    pub fn mtu(self: Arc<Self>, mtu: u32) -> Arc<Self> {
+        self.options.lock().unwrap().mtu = Some(mtu);
+        self
+    }
+
+    /// Sets the log filter, such as `info` (the default) or `onetun=debug`.
+    pub fn log_level(self: Arc<Self>, level: String) -> Arc<Self> {
+        self.options.lock().unwrap().log_level = Some(level);
+        self
+    }
+
+    /// Records the configuration and the requests to stop the tunnel in the given file, as JSON lines.
+    pub fn audit_log(self: Arc<Self>, path: String) -> Arc<Self> {
+        self.options.lock().unwrap().audit_log = Some(path);
+        self
+    }
+
+    /// Passes the IP packets of the local port forwards to the filter, which may rewrite or drop them.
+    pub fn packet_filter(self: Arc<Self>, filter: Box<dyn PacketFilter>) -> Arc<Self> {
+        self.options.lock().unwrap().packet_filter = Some(Arc::new(ForeignPacketFilter(filter)));
+        self
+    }
+
+    /// Asks the authorizer whether to serve each new client of the local port forwards.
+    pub fn connection_authorizer(
+        self: Arc<Self>,
+        authorizer: Box<dyn ConnectionAuthorizer>,
+    ) -> Arc<Self> {
+        self.options.lock().unwrap().connection_authorizer =
+            Some(Arc::new(ForeignConnectionAuthorizer(authorizer)));
+        self
+    }
+
+    /// Checks the options and builds the configuration.
+    pub fn build(&self) -> Result<Arc<TunnelConfig>, OnetunError> {
+        let options = self.options.lock().unwrap().clone();
+        let port_forwards = options
+            .port_forwards
+            .into_iter()
+            .map(PortForwardConfig::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut builder = config::Config::builder()
+            .port_forwards(port_forwards)
+            .private_key(options.private_key)
+            .endpoint_public_key(options.endpoint_public_key)
+            .endpoint_addr(parse::<SocketAddr>(&options.endpoint, "endpoint")?)
+            .source_peer_ip(parse::<IpAddr>(&options.assigned_ip, "assigned IP")?);
+        if let Some(seconds) = options.keepalive_seconds {
+            builder = builder.keepalive(seconds);
+        }
+        if let Some(mtu) = options.mtu {
+            builder = builder.mtu(mtu as usize);
+        }
+        if let Some(level) = options.log_level {
+            builder = builder.log_level(level);
+        }
+        let mut config = builder.build()?;
+        if let Some(path) = options.audit_log {
+            config = config.with_audit_log(path);
+        }
+        let config = match options.packet_filter {
+            Some(filter) => config.with_packet_filter(filter),
+            None => config,
+        };
+        let config = match options.connection_authorizer {
+            Some(authorizer) => config.with_connection_authorizer(authorizer),
+            None => config,
+        };
+        Ok(Arc::new(TunnelConfig { config }))
+    }
+}
+
+/// The direction of an IP packet going through a tunnel.
+#[derive(Debug, Clone, Copy, uniffi::Enum)]
+pub enum PacketDirection {
+    /// Received from the tunnel.
+    Inbound,
+    /// Sent into the tunnel.
+    Outbound,
+}
+
+/// Inspects the IP packets of the local port forwards, on one of the tunnel's threads. It must not block.
+#[uniffi::export(callback_interface)]
+pub trait PacketFilter: Send + Sync {
+    /// Returns the packet to pass on, possibly rewritten, or nothing to drop it.
+    fn filter(&self, direction: PacketDirection, packet: Vec<u8>) -> Option<Vec<u8>>;
+}
+
+/// Adapts a foreign packet filter to onetun's.
+struct ForeignPacketFilter(Box<dyn PacketFilter>);
+
+impl std::fmt::Debug for ForeignPacketFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ForeignPacketFilter")
+    }
+}
+
+impl packet_filter::PacketFilter for ForeignPacketFilter {
+    fn filter(
+        &self,
+        direction: packet_filter::PacketDirection,
+        packet: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let direction = match direction {
+            packet_filter::PacketDirection::Inbound => PacketDirection::Inbound,
+            packet_filter::PacketDirection::Outbound => PacketDirection::Outbound,
+        };
+        self.0.filter(direction, packet)
+    }
+}
+
+/// Decides whether to serve the new clients of the local port forwards, on one of the tunnel's threads. It
+/// must not block.
+#[uniffi::export(callback_interface)]
+pub trait ConnectionAuthorizer: Send + Sync {
+    /// Returns whether to serve the client (`host:port`) of the port forward listening on `source`.
+    fn authorize(&self, source: String, client: String) -> bool;
+}
+
+/// Adapts a foreign connection authorizer to onetun's.
+struct ForeignConnectionAuthorizer(Box<dyn ConnectionAuthorizer>);
+
+impl std::fmt::Debug for ForeignConnectionAuthorizer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ForeignConnectionAuthorizer")
+    }
+}
+
+impl connection_authorizer::ConnectionAuthorizer for ForeignConnectionAuthorizer {
+    fn authorize(&self, port_forward: &PortForwardConfig, client: SocketAddr) -> bool {
+        self.0
+            .authorize(port_forward.source.to_string(), client.to_string())
+    }
+}
+
+/// The configuration of a tunnel, built with `ConfigBuilder`.
+#[derive(uniffi::Object)]
+pub struct TunnelConfig {
+    pub(crate) config: config::Config,
+}
+
+/// The traffic through a port forward since the tunnel started.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ForwardStats {
+    /// The name of the port forward, or `#N` for the N-th unnamed one.
+    pub forward: String,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+}
+
+/// An event of a running tunnel.
+#[derive(Debug, Clone, uniffi::Enum)]
+pub enum TunnelEvent {
+    /// A handshake with the WireGuard endpoint completed.
+    HandshakeCompleted,
+    /// The traffic statistics of a port forward were updated.
+    Stats { stats: ForwardStats },
+    /// The tunnel was stopped.
+    Stopped,
+}
+
+/// Receives the events of a tunnel, on one of its threads.
+#[uniffi::export(callback_interface)]
+pub trait TunnelListener: Send + Sync {
+    fn on_event(&self, event: TunnelEvent);
+}
+
+/// Learns when a tunnel is ready, on one of its threads.
+#[uniffi::export(callback_interface)]
+pub trait ReadyListener: Send + Sync {
+    /// Called once: with `true` when the tunnel is usable, or `false` if it was stopped first.
+    fn on_ready(&self, ready: bool);
+}
+
+/// A running tunnel.
+#[derive(uniffi::Object)]
+pub struct Tunnel {
+    handle: Handle,
+    state: Arc<Mutex<TunnelState>>,
+}
+
+#[derive(Default)]
+struct TunnelState {
+    stats: HashMap<String, ForwardStats>,
+    listeners: Vec<Arc<dyn TunnelListener>>,
+}
+
+#[uniffi::export]
+impl Tunnel {
+    /// Starts a tunnel and its port forwards. Returns once the tunnel is ready.
+    #[uniffi::constructor]
+    pub fn start(config: Arc<TunnelConfig>) -> Result<Arc<Self>, OnetunError> {
+        let handle = onetun::blocking_start(config.config.clone().with_initiator(Initiator::Ffi))?;
+        let state = Arc::new(Mutex::new(TunnelState::default()));
+        watch(&handle, state.clone());
+        Ok(Arc::new(Self { handle, state }))
+    }
+
+    /// The identifier of the tunnel, unique among the tunnels started in the process.
+    pub fn id(&self) -> u32 {
+        self.handle.id()
+    }
+
+    /// Stops the tunnel and its port forwards. Stopping it again has no effect.
+    pub fn stop(&self) {
+        self.handle.kill();
+    }
+
+    /// The latest traffic statistics of each port forward.
+    pub fn stats(&self) -> Vec<ForwardStats> {
+        let mut stats: Vec<ForwardStats> =
+            self.state.lock().unwrap().stats.values().cloned().collect();
+        stats.sort_by(|a, b| a.forward.cmp(&b.forward));
+        stats
+    }
+
+    /// Registers a listener for the events of the tunnel.
+    pub fn subscribe(&self, listener: Box<dyn TunnelListener>) {
+        self.state
+            .lock()
+            .unwrap()
+            .listeners
+            .push(Arc::from(listener));
+    }
+
+    /// Notifies the listener once the tunnel is usable: the first handshake with the WireGuard endpoint
+    /// completed, and the port forwards listen. Dependent work, such as connecting a client app, can wait
+    /// for it.
+    pub fn on_ready(&self, listener: Box<dyn ReadyListener>) {
+        wait_ready(&self.handle, move |ready| listener.on_ready(ready));
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Follows the events of the tunnel on a separate thread, until it is stopped.
+fn watch(handle: &Handle, state: Arc<Mutex<TunnelState>>) {
+    let mut endpoint = handle.subscribe();
+    let mut kill_switch = handle.get_killer();
+    std::thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread().build() {
+            Ok(runtime) => runtime,
+            Err(_) => return,
+        };
+        runtime.block_on(async {
+            loop {
+                let event = tokio::select! {
+                    event = endpoint.recv() => event,
+                    _ = kill_switch.recv() => break,
+                };
+                let event = match event {
+                    Event::HandshakeCompleted => TunnelEvent::HandshakeCompleted,
+                    Event::ForwardStats(id, tx_bytes, rx_bytes) => {
+                        let stats = ForwardStats {
+                            forward: id.to_string(),
+                            tx_bytes,
+                            rx_bytes,
+                        };
+                        state
+                            .lock()
+                            .unwrap()
+                            .stats
+                            .insert(stats.forward.clone(), stats.clone());
+                        TunnelEvent::Stats { stats }
+                    }
+                    _ => continue,
+                };
+                notify(&state, event);
+            }
+        });
+        notify(&state, TunnelEvent::Stopped);
+    });
+}
+
+/// Waits on a separate thread for the tunnel to be ready, then calls `f` with `false` if it was stopped first.
+pub(crate) fn wait_ready(handle: &Handle, f: impl FnOnce(bool) + Send + 'static) {
+    let ready = handle.ready();
+    std::thread::spawn(move || {
+        let ready = match tokio::runtime::Builder::new_current_thread().build() {
+            Ok(runtime) => runtime.block_on(ready).is_ok(),
+            Err(_) => false,
+        };
+        f(ready);
+    });
+}
+
+/// Calls the listeners without holding the lock of the state, so that they can call back into the tunnel, such as
+/// to read its statistics or subscribe another listener.
+fn notify(state: &Mutex<TunnelState>, event: TunnelEvent) {
+    let listeners = state.lock().unwrap().listeners.clone();
+    for listener in listeners {
+        listener.on_event(event.clone());
+    }
+}
+
+fn parse<T: FromStr>(s: &str, what: &str) -> Result<T, OnetunError> {
+    s.parse()
+        .map_err(|_| OnetunError::InvalidConfig(format!("Invalid {}: {}", what, s)))
+}
+
+/// The level of a log record.
+#[derive(Debug, Clone, Copy, uniffi::Enum)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => Self::Error,
+            log::Level::Warn => Self::Warn,
+            log::Level::Info => Self::Info,
+            log::Level::Debug => Self::Debug,
+            log::Level::Trace => Self::Trace,
+        }
+    }
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => Self::Off,
+            LogLevel::Error => Self::Error,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Info => Self::Info,
+            LogLevel::Debug => Self::Debug,
+            LogLevel::Trace => Self::Trace,
+        }
+    }
+}
+
+/// Receives the logs of onetun, from any thread.
+#[uniffi::export(callback_interface)]
+pub trait LogListener: Send + Sync {
+    fn on_log(&self, level: LogLevel, target: String, message: String);
+}
+
+/// Forwards the logs of onetun to the listener instead of printing them to stderr, replacing the
+/// previous listener. Must be called before starting the first tunnel.
+/// Records up to the `Info` level are forwarded, unless changed with `set_log_level`.
+#[uniffi::export]
+pub fn set_log_listener(listener: Box<dyn LogListener>) -> Result<(), OnetunError> {
+    let sink = Box::new(move |level: log::Level, target: &str, message: &str| {
+        listener.on_log(level.into(), target.to_string(), message.to_string())
+    });
+    log_bridge::set_sink(Some(sink)).map_err(|e| OnetunError::Logger(e.to_string()))
+}
+
+/// Stops forwarding the logs to the listener.
+#[uniffi::export]
+pub fn clear_log_listener() {
+    log_bridge::set_sink(None).ok();
+}
+
+/// Sets the most verbose level of the logs forwarded to the listener. Takes effect immediately.
+#[uniffi::export]
+pub fn set_log_level(level: LogLevel) {
+    log_bridge::set_level(level.into());
+}
@@ -0,0 +1,619 @@
+//! The original C interface, declared in `onetun.h`. New code should prefer the bindings of the `api` module.
+
+use crate::api::ConfigBuilder;
+use crate::log_bridge::{self, LogSink};
+use onetun::audit::Initiator;
+use onetun::connection_authorizer::ConnectionAuthorizer;
+use onetun::packet_filter::{PacketDirection, PacketFilter};
+use onetun::{self, config, Handle};
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use libc::{c_char, c_int, c_void};
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+
+thread_local! {
+    /// The code of the last error that occurred on this thread (see `OnetunError::code`).
+    static LAST_ERROR: Cell<c_int> = Cell::new(0);
+}
+
+/// Returns the code of the last error that occurred on the calling thread, or `0` if none.
+/// * `1` - invalid configuration
+/// * `2` - handshake timeout
+/// * `3` - failed to bind a socket
+/// * `4` - endpoint unreachable
+/// * `5` - failed to initialize the WireGuard tunnel
+/// * `6` - failed to start the async runtime
+/// * `7` - another instance registered in the lock directory uses the same resources
+/// * `8` - the tunnel was killed before it was ready
+#[no_mangle]
+pub extern "C" fn onetun_last_error() -> c_int {
+    LAST_ERROR.with(|e| e.get())
+}
+
+/// Receives a log record of onetun: its level (`1` = error, `2` = warn, `3` = info, `4` = debug,
+/// `5` = trace), target (the module that logged it) and message. The strings are only valid during the call.
+pub type LogCallback = extern "C" fn(level: c_int, target: *const c_char, msg: *const c_char);
+
+/// Forwards the logs of onetun to the callback, from any thread, instead of printing them to stderr.
+/// Must be called before starting the first tunnel. Passing NULL unregisters the callback.
+/// Records up to the `info` level are forwarded, unless changed with `onetun_set_log_level`.
+/// # Returns
+/// * `0` on success, `-1` if another logger was already installed
+#[no_mangle]
+pub extern "C" fn set_log_callback(callback: Option<LogCallback>) -> c_int {
+    let sink = callback.map(|callback| {
+        Box::new(move |level: log::Level, target: &str, message: &str| {
+            let target = c_string(target);
+            let message = c_string(message);
+            callback(level as usize as c_int, target.as_ptr(), message.as_ptr())
+        }) as LogSink
+    });
+    match log_bridge::set_sink(sink) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Sets the most verbose level of the logs forwarded to the callback: `0` = off, `1` = error,
+/// `2` = warn, `3` = info, `4` = debug, `5` = trace. Takes effect immediately.
+/// # Returns
+/// * `0` on success, `-1` on an invalid level
+#[no_mangle]
+pub extern "C" fn onetun_set_log_level(level: c_int) -> c_int {
+    let level = match level {
+        0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Error,
+        2 => log::LevelFilter::Warn,
+        3 => log::LevelFilter::Info,
+        4 => log::LevelFilter::Debug,
+        5 => log::LevelFilter::Trace,
+        _ => return -1,
+    };
+    log_bridge::set_level(level);
+    0
+}
+
+/// Converts a log string for C, dropping the NUL characters it can't hold.
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+#[no_mangle]
+pub extern "C" fn hello_from_rust() {
+    println!("Hello from Rust!");
+}
+
+/// A tunnel configuration shared with the host, created with `create_wireguard_config`.
+/// It is reference-counted (see `onetun_config_retain` and `onetun_config_free`).
+pub struct OnetunConfig(config::Config);
+
+/// A local port forward shared with the host, created with `create_port_forward` and added to configurations
+/// with `onetun_config_with_port_forward`. It is reference-counted (see `onetun_port_forward_retain` and
+/// `onetun_port_forward_free`).
+pub struct OnetunPortForward(config::PortForwardConfig);
+
+/// A running tunnel shared with the host, created by one of the `start_wireguard_tunnel` functions.
+/// It is reference-counted (see `onetun_handle_retain` and `onetun_handle_free`), and the tunnel is
+/// killed when the last reference is released.
+pub struct OnetunHandle(Handle);
+
+impl Drop for OnetunHandle {
+    fn drop(&mut self) {
+        self.0.kill();
+    }
+}
+
+/// Gives a new reference-counted object to the host.
+fn share<T>(value: T) -> *const T {
+    Arc::into_raw(Arc::new(value))
+}
+
+/// Borrows a reference-counted object held by the host, if the pointer is not NULL.
+/// The host must hold a reference for the duration of the call.
+fn borrow<'a, T>(pointer: *const T) -> Option<&'a T> {
+    unsafe { pointer.as_ref() }
+}
+
+/// Adds a reference to an object held by the host, if the pointer is not NULL.
+fn retain<T>(pointer: *const T) {
+    if !pointer.is_null() {
+        unsafe { Arc::increment_strong_count(pointer) }
+    }
+}
+
+/// Releases a reference to an object held by the host, if the pointer is not NULL.
+fn release<T>(pointer: *const T) {
+    if !pointer.is_null() {
+        unsafe { drop(Arc::from_raw(pointer)) }
+    }
+}
+
+/// Adds a reference to a configuration, to be released with `onetun_config_free`.
+#[no_mangle]
+pub extern "C" fn onetun_config_retain(pointer: *const OnetunConfig) {
+    retain(pointer)
+}
+
+/// Releases a reference to a configuration. Does nothing if the pointer is NULL.
+#[no_mangle]
+pub extern "C" fn onetun_config_free(pointer: *const OnetunConfig) {
+    release(pointer)
+}
+
+/// Adds a reference to a tunnel handle, to be released with `onetun_handle_free`.
+#[no_mangle]
+pub extern "C" fn onetun_handle_retain(pointer: *const OnetunHandle) {
+    retain(pointer)
+}
+
+/// Releases a reference to a tunnel handle. The tunnel is killed when the last reference is released.
+/// Does nothing if the pointer is NULL.
+#[no_mangle]
+pub extern "C" fn onetun_handle_free(pointer: *const OnetunHandle) {
+    release(pointer)
+}
+
+/// Returns the identifier of the tunnel, unique among the tunnels started in the process, or 0 if the
+/// pointer is NULL.
+#[no_mangle]
+pub extern "C" fn onetun_handle_id(pointer: *const OnetunHandle) -> u32 {
+    borrow(pointer)
+        .map(|handle| handle.0.id())
+        .unwrap_or_default()
+}
+
+/// Starts the tunnel
+/// # Arguments
+/// * `pointer` - pointer to the config created with `create_wireguard_config`. The config is not
+///   consumed, and must still be released with `onetun_config_free`.
+/// # Returns
+/// * The handle to the tunnel on success, to be released with `onetun_handle_free`
+/// * NULL on failure (see `onetun_last_error`)
+#[no_mangle]
+pub extern "C" fn start_wireguard_tunnel(pointer: *const OnetunConfig) -> *const OnetunHandle {
+    match borrow(pointer) {
+        Some(config) => start(config.0.clone()),
+        None => invalid_config(),
+    }
+}
+
+/// Starts the tunnel on a tun device, such as the one created by Android's `VpnService`,
+/// instead of local port forwards. Raw IP packets read from the device are sent through the
+/// tunnel, and those received from the tunnel are written to it.
+/// The tunnel takes ownership of the file descriptor, which is closed when the tunnel is killed.
+/// On Android, the app should exclude itself from the VPN (`addDisallowedApplication`) so that
+/// the WireGuard traffic is not routed back into the device.
+/// # Arguments
+/// * `pointer` - pointer to the config created with `create_wireguard_config` (not consumed)
+/// * `tun_fd` - the file descriptor of the tun device (`ParcelFileDescriptor.detachFd()`)
+/// # Returns
+/// * The handle to the tunnel on success, to be released with `onetun_handle_free`
+/// * NULL on failure (see `onetun_last_error`)
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn start_wireguard_tunnel_with_fd(
+    pointer: *const OnetunConfig,
+    tun_fd: c_int,
+) -> *const OnetunHandle {
+    match borrow(pointer) {
+        Some(config) => start(config.0.clone().with_tun_fd(tun_fd)),
+        None => invalid_config(),
+    }
+}
+
+/// Starts the tunnel on the embedder's packet flow, such as an iOS `NEPacketTunnelFlow`, instead of
+/// local port forwards. Packets are written with `onetun_write_ip_packet`, and received on the
+/// callback registered with `onetun_set_packet_callback`.
+/// # Arguments
+/// * `pointer` - pointer to the config created with `create_wireguard_config` (not consumed)
+/// # Returns
+/// * The handle to the tunnel on success, to be released with `onetun_handle_free`
+/// * NULL on failure (see `onetun_last_error`)
+#[no_mangle]
+pub extern "C" fn start_wireguard_tunnel_with_packet_flow(
+    pointer: *const OnetunConfig,
+) -> *const OnetunHandle {
+    match borrow(pointer) {
+        Some(config) => start(config.0.clone().with_packet_flow()),
+        None => invalid_config(),
+    }
+}
+
+fn start(config: config::Config) -> *const OnetunHandle {
+    let handle = match onetun::blocking_start(config.with_initiator(Initiator::Ffi)) {
+        Ok(h) => h,
+        Err(e) => {
+            LAST_ERROR.with(|last| last.set(e.code()));
+            return std::ptr::null();
+        }
+    };
+    LAST_ERROR.with(|last| last.set(0));
+
+    share(OnetunHandle(handle))
+}
+
+fn invalid_config() -> *const OnetunHandle {
+    LAST_ERROR.with(|last| last.set(1));
+    std::ptr::null()
+}
+
+/// Kills the tunnel. Killing it again has no effect, and the handle must still be released
+/// with `onetun_handle_free`.
+/// # Arguments
+/// * `pointer` - pointer to the handle created with `start_wireguard_tunnel`
+#[no_mangle]
+pub extern "C" fn kill_wireguard_tunnel(pointer: *const OnetunHandle) {
+    if let Some(handle) = borrow(pointer) {
+        handle.0.kill()
+    }
+}
+
+/// Sends a raw IP packet through a tunnel started with `start_wireguard_tunnel_with_packet_flow`.
+/// # Arguments
+/// * `pointer` - pointer to the handle of the tunnel
+/// * `buf` - the IP packet
+/// * `len` - the length of the IP packet
+/// # Returns
+/// * `0` on success, `-1` on invalid arguments
+#[no_mangle]
+pub extern "C" fn onetun_write_ip_packet(
+    pointer: *const OnetunHandle,
+    buf: *const u8,
+    len: usize,
+) -> c_int {
+    let handle = match borrow(pointer) {
+        Some(handle) if !buf.is_null() => handle,
+        _ => return -1,
+    };
+    let packet = unsafe { std::slice::from_raw_parts(buf, len) };
+
+    handle.0.write_ip_packet(packet);
+    0
+}
+
+/// Receives the raw IP packets of a tunnel, with the context given at registration.
+/// The buffer is only valid during the call.
+pub type PacketCallback = extern "C" fn(context: *mut c_void, buf: *const u8, len: usize);
+
+/// The context of a packet callback, passed back as-is from the tunnel's threads.
+struct CallbackContext(*mut c_void);
+
+impl CallbackContext {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+unsafe impl Send for CallbackContext {}
+unsafe impl Sync for CallbackContext {}
+
+/// Registers the callback receiving the IP packets of a tunnel started with
+/// `start_wireguard_tunnel_with_packet_flow`, replacing the previous one. It is called from one of
+/// the tunnel's threads. Packets received while no callback is registered are dropped.
+/// # Arguments
+/// * `pointer` - pointer to the handle of the tunnel
+/// * `callback` - the callback, or NULL to unregister it
+/// * `context` - passed to each call of the callback
+#[no_mangle]
+pub extern "C" fn onetun_set_packet_callback(
+    pointer: *const OnetunHandle,
+    callback: Option<PacketCallback>,
+    context: *mut c_void,
+) {
+    let handle = match borrow(pointer) {
+        Some(handle) => handle,
+        None => return,
+    };
+
+    let context = CallbackContext(context);
+    handle.0.set_packet_callback(callback.map(|callback| {
+        Box::new(move |packet: &[u8]| callback(context.get(), packet.as_ptr(), packet.len()))
+            as onetun::packet_flow::PacketCallback
+    }));
+}
+
+/// Learns whether a tunnel is ready, with the context given at registration: `ready` is `1` once the
+/// tunnel is usable, or `0` if it was killed first.
+pub type ReadyCallback = extern "C" fn(context: *mut c_void, ready: c_int);
+
+/// Calls the callback once the tunnel is usable: the first handshake with the WireGuard endpoint
+/// completed, and the local port forwards listen. It is called once, from another thread, and right away
+/// if the tunnel is already ready.
+/// # Arguments
+/// * `pointer` - pointer to the handle of the tunnel
+/// * `callback` - the callback
+/// * `context` - passed to the callback
+/// # Returns
+/// * `0` on success, `-1` on invalid arguments
+#[no_mangle]
+pub extern "C" fn onetun_on_ready(
+    pointer: *const OnetunHandle,
+    callback: Option<ReadyCallback>,
+    context: *mut c_void,
+) -> c_int {
+    let (handle, callback) = match (borrow(pointer), callback) {
+        (Some(handle), Some(callback)) => (handle, callback),
+        _ => return -1,
+    };
+
+    let context = CallbackContext(context);
+    crate::api::wait_ready(&handle.0, move |ready| {
+        callback(context.get(), ready as c_int)
+    });
+    0
+}
+
+/// Inspects an IP packet going through the virtual interfaces of a tunnel, with the context given at
+/// registration. `direction` is `0` for packets received from the tunnel, and `1` for packets sent into it.
+/// The packet may be rewritten in place, keeping its length.
+/// Returns `0` to pass the packet on, or any other value to drop it.
+pub type PacketFilterCallback =
+    extern "C" fn(context: *mut c_void, direction: c_int, buf: *mut u8, len: usize) -> c_int;
+
+/// Adapts a C packet filter callback to `PacketFilter`.
+struct CPacketFilter {
+    callback: PacketFilterCallback,
+    context: CallbackContext,
+}
+
+impl Debug for CPacketFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CPacketFilter")
+    }
+}
+
+impl PacketFilter for CPacketFilter {
+    fn filter(&self, direction: PacketDirection, mut packet: Vec<u8>) -> Option<Vec<u8>> {
+        let direction = match direction {
+            PacketDirection::Inbound => 0,
+            PacketDirection::Outbound => 1,
+        };
+        let verdict = (self.callback)(
+            self.context.get(),
+            direction,
+            packet.as_mut_ptr(),
+            packet.len(),
+        );
+        if verdict == 0 {
+            Some(packet)
+        } else {
+            None
+        }
+    }
+}
+
+/// Creates a copy of a configuration that passes the IP packets going through the virtual interfaces of
+/// the tunnel (local port forwards) to the callback, which may rewrite or drop them. The callback is called
+/// from the tunnel's threads, one packet at a time, and must not block.
+/// # Arguments
+/// * `pointer` - pointer to the config created with `create_wireguard_config` (not consumed)
+/// * `callback` - the callback
+/// * `context` - passed to each call of the callback, which must stay valid as long as a tunnel uses the config
+/// # Returns
+/// * The new config on success, to be released with `onetun_config_free`
+/// * NULL on invalid arguments
+#[no_mangle]
+pub extern "C" fn onetun_config_with_packet_filter(
+    pointer: *const OnetunConfig,
+    callback: Option<PacketFilterCallback>,
+    context: *mut c_void,
+) -> *const OnetunConfig {
+    let (config, callback) = match (borrow(pointer), callback) {
+        (Some(config), Some(callback)) => (config, callback),
+        _ => return std::ptr::null(),
+    };
+    let filter = CPacketFilter {
+        callback,
+        context: CallbackContext(context),
+    };
+    share(OnetunConfig(
+        config.0.clone().with_packet_filter(Arc::new(filter)),
+    ))
+}
+
+/// Decides whether to serve a new client of the local port forwards, with the context given at registration.
+/// `source` is the address the port forward listens on, and `client` the address of the client, both as
+/// `host:port` strings only valid during the call.
+/// Returns `0` to serve the client, or any other value to reject it.
+pub type ConnectionAuthorizerCallback =
+    extern "C" fn(context: *mut c_void, source: *const c_char, client: *const c_char) -> c_int;
+
+/// Adapts a C connection authorizer callback to `ConnectionAuthorizer`.
+struct CConnectionAuthorizer {
+    callback: ConnectionAuthorizerCallback,
+    context: CallbackContext,
+}
+
+impl Debug for CConnectionAuthorizer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CConnectionAuthorizer")
+    }
+}
+
+impl ConnectionAuthorizer for CConnectionAuthorizer {
+    fn authorize(&self, port_forward: &config::PortForwardConfig, client: SocketAddr) -> bool {
+        // Socket addresses never contain a nul byte
+        let source = CString::new(port_forward.source.to_string()).unwrap();
+        let client = CString::new(client.to_string()).unwrap();
+        (self.callback)(self.context.get(), source.as_ptr(), client.as_ptr()) == 0
+    }
+}
+
+/// Creates a copy of a configuration that asks the callback whether to serve each new client of the local
+/// port forwards: each accepted TCP connection, and each UDP client. The callback is called from the
+/// tunnel's threads, and must not block.
+/// # Arguments
+/// * `pointer` - pointer to the config created with `create_wireguard_config` (not consumed)
+/// * `callback` - the callback
+/// * `context` - passed to each call of the callback, which must stay valid as long as a tunnel uses the config
+/// # Returns
+/// * The new config on success, to be released with `onetun_config_free`
+/// * NULL on invalid arguments
+#[no_mangle]
+pub extern "C" fn onetun_config_with_connection_authorizer(
+    pointer: *const OnetunConfig,
+    callback: Option<ConnectionAuthorizerCallback>,
+    context: *mut c_void,
+) -> *const OnetunConfig {
+    let (config, callback) = match (borrow(pointer), callback) {
+        (Some(config), Some(callback)) => (config, callback),
+        _ => return std::ptr::null(),
+    };
+    let authorizer = CConnectionAuthorizer {
+        callback,
+        context: CallbackContext(context),
+    };
+    share(OnetunConfig(
+        config
+            .0
+            .clone()
+            .with_connection_authorizer(Arc::new(authorizer)),
+    ))
+}
+
+/// Creates a port forward and returns the pointer to it on success
+/// or NULL on failure. It must be released with `onetun_port_forward_free`.
+#[no_mangle]
+pub extern "C" fn create_port_forward(
+    source: *const c_char,
+    destination: *const c_char,
+    protocol: *const c_char,
+) -> *const OnetunPortForward {
+    // Check to make sure the pointers aren't null
+    if source.is_null() || destination.is_null() || protocol.is_null() {
+        return std::ptr::null();
+    }
+
+    // Grab them pointers
+    let source = unsafe { CStr::from_ptr(source as *mut _) };
+    let destination = unsafe { CStr::from_ptr(destination as *mut _) };
+    let protocol = unsafe { CStr::from_ptr(protocol as *mut _) };
+
+    // Convert the CStrings to Rust strings
+    let source = match source.to_str() {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let destination = match destination.to_str() {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let protocol = match protocol.to_str() {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    // Create socket addresss from the strings
+    let source = match SocketAddr::from_str(source) {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null_mut(),
+    };
+    let destination = match SocketAddr::from_str(destination) {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    let protocol: config::PortProtocol = match protocol.to_uppercase().as_str() {
+        "TCP" => config::PortProtocol::Tcp,
+        "UDP" => config::PortProtocol::Udp,
+        _ => return std::ptr::null_mut(),
+    };
+
+    // Create the port forward
+    let port_forward = config::PortForwardConfig::new(source, destination, protocol);
+
+    // Return the pointer to the port forward
+    share(OnetunPortForward(port_forward))
+}
+
+/// Adds a reference to a port forward, to be released with `onetun_port_forward_free`.
+#[no_mangle]
+pub extern "C" fn onetun_port_forward_retain(pointer: *const OnetunPortForward) {
+    retain(pointer)
+}
+
+/// Releases a reference to a port forward. Does nothing if NULL.
+#[no_mangle]
+pub extern "C" fn onetun_port_forward_free(pointer: *const OnetunPortForward) {
+    release(pointer)
+}
+
+/// Creates a copy of a configuration with another local port forward.
+/// # Arguments
+/// * `pointer` - pointer to the config created with `create_wireguard_config` (not consumed)
+/// * `port_forward` - pointer to the port forward created with `create_port_forward` (not consumed)
+/// # Returns
+/// * The new config on success, to be released with `onetun_config_free`
+/// * NULL on invalid arguments
+#[no_mangle]
+pub extern "C" fn onetun_config_with_port_forward(
+    pointer: *const OnetunConfig,
+    port_forward: *const OnetunPortForward,
+) -> *const OnetunConfig {
+    match (borrow(pointer), borrow(port_forward)) {
+        (Some(config), Some(port_forward)) => share(OnetunConfig(
+            config.0.clone().with_port_forward(port_forward.0.clone()),
+        )),
+        _ => std::ptr::null(),
+    }
+}
+
+/// Creates a Wireguard configuration and returns the pointer to it on success
+/// or NULL on failure. It must be released with `onetun_config_free`.
+#[no_mangle]
+pub extern "C" fn create_wireguard_config(
+    endpoint: *const c_char,
+    assigned_ip: *const c_char,
+    public_key: *const c_char,
+    private_key: *const c_char,
+) -> *const OnetunConfig {
+    // Check to make sure the pointers aren't null
+    if endpoint.is_null() || assigned_ip.is_null() || public_key.is_null() || private_key.is_null()
+    {
+        return std::ptr::null();
+    }
+
+    // Grab them pointers
+    let endpoint = unsafe { CStr::from_ptr(endpoint as *mut _) };
+    let assigned_ip = unsafe { CStr::from_ptr(assigned_ip as *mut _) };
+    let public_key = unsafe { CStr::from_ptr(public_key as *mut _) };
+    let private_key = unsafe { CStr::from_ptr(private_key as *mut _) };
+
+    // Convert the CStrings to Rust strings
+    let endpoint = match endpoint.to_str() {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null(),
+    };
+    let assigned_ip = match assigned_ip.to_str() {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null(),
+    };
+    let public_key = match public_key.to_str() {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null(),
+    };
+    let private_key = match private_key.to_str() {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null(),
+    };
+
+    // Create the wireguard config
+    let wireguard_config = match ConfigBuilder::new(
+        endpoint.to_string(),
+        assigned_ip.to_string(),
+        private_key.to_string(),
+        public_key.to_string(),
+    )
+    .build()
+    {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null(),
+    };
+
+    // Return the pointer to the wireguard config
+    share(OnetunConfig(wireguard_config.config.clone()))
+}
@@ -0,0 +1,55 @@
+//! Forwards the logs of onetun to the host, instead of printing them to stderr.
+//!
+//! The bridge is installed as the global logger when a sink is first registered. It must be registered
+//! before the first tunnel starts, since onetun otherwise installs its own logger.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Receives each log record: its level, target (the module that logged it) and message.
+pub type LogSink = Box<dyn Fn(Level, &str, &str) + Send + Sync>;
+
+static SINK: RwLock<Option<LogSink>> = RwLock::new(None);
+static LOGGER: Bridge = Bridge;
+/// Whether the bridge is the global logger. Only changed with the sink lock held.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+struct Bridge;
+
+impl Log for Bridge {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(sink) = SINK.read().unwrap().as_ref() {
+            sink(record.level(), record.target(), &record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Registers the sink receiving the logs, replacing the previous one, or unregisters it.
+/// The sink must not register another one itself.
+/// Fails if another logger was installed before the bridge.
+pub fn set_sink(sink: Option<LogSink>) -> Result<(), log::SetLoggerError> {
+    let mut current = SINK.write().unwrap();
+    if sink.is_some() && !INSTALLED.load(Ordering::SeqCst) {
+        log::set_logger(&LOGGER)?;
+        log::set_max_level(LevelFilter::Info);
+        INSTALLED.store(true, Ordering::SeqCst);
+    }
+    *current = sink;
+    Ok(())
+}
+
+/// Sets the most verbose level forwarded to the sink. Takes effect immediately.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
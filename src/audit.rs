@@ -0,0 +1,248 @@
+//! The audit log (see `Config::with_audit_log`): an append-only file recording the configuration a tunnel
+//! started with, and the actions that changed or stopped it, with when and by whom they were requested.
+//!
+//! The file is JSON lines, one record per line:
+//! `{"time":"2026-10-16T09:41:07.512Z","tunnel":1,"initiator":"cli","action":"kill","details":{}}`.
+//! The details are strings, and the keys are never written.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::config::Config;
+use crate::engine::packet::encode_key;
+
+/// Written instead of the secrets of the configuration.
+const REDACTED: &str = "<redacted>";
+
+/// Who requested an action on the tunnel.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Initiator {
+    /// The command line of the onetun binary, or the signals it receives.
+    Cli,
+    /// The control socket.
+    ControlSocket,
+    /// The gRPC control service.
+    Grpc,
+    /// The Swift and Kotlin bindings, or the C API.
+    Ffi,
+    /// An application using onetun as a Rust library.
+    Library,
+}
+
+impl Default for Initiator {
+    fn default() -> Self {
+        Self::Library
+    }
+}
+
+impl Display for Initiator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cli => write!(f, "cli"),
+            Self::ControlSocket => write!(f, "control-socket"),
+            Self::Grpc => write!(f, "grpc"),
+            Self::Ffi => write!(f, "ffi"),
+            Self::Library => write!(f, "library"),
+        }
+    }
+}
+
+/// The audit log of a tunnel.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    /// Identifies the tunnel among those started in this process, which may share the file.
+    tunnel: u32,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Opens the file for appending, creating it if it doesn't exist.
+    pub fn open(path: &Path, tunnel: u32) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {:?}", path))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            tunnel,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends a record of the action. Failing to write it is logged: the action isn't undone.
+    pub fn record(&self, initiator: Initiator, action: &str, details: &[(&str, String)]) {
+        let line = format_record(SystemTime::now(), self.tunnel, initiator, action, details);
+        let mut file = self.file.lock().expect("Failed to acquire audit log lock");
+        // One write per record, so that records of tunnels sharing the file don't interleave
+        if let Err(e) = file
+            .write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+        {
+            error!("Failed to write to audit log {:?}: {}", self.path, e);
+        }
+    }
+
+    /// Records the start of the tunnel, with its configuration.
+    pub(crate) fn record_start(&self, config: &Config) {
+        let mut details = vec![
+            ("private_key", REDACTED.to_string()),
+            (
+                "endpoint_public_key",
+                encode_key(config.endpoint_public_key.as_bytes()),
+            ),
+            ("endpoint", list(config.endpoint_addrs())),
+            ("source_peer_ips", list(config.source_peer_ips())),
+            ("port_forwards", list(&config.port_forwards)),
+            ("remote_port_forwards", list(&config.remote_port_forwards)),
+            ("mtu", config.max_transmission_unit.to_string()),
+        ];
+        if let Some(seconds) = config.keepalive_seconds {
+            details.push(("keepalive", format!("{}s", seconds)));
+        }
+        if config.obfuscator.is_some() {
+            details.push(("obfuscated", "true".to_string()));
+        }
+        self.record(config.initiator, "start", &details);
+    }
+}
+
+/// Joins the items with commas.
+fn list<T: Display>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Formats a record as a line of JSON.
+fn format_record(
+    time: SystemTime,
+    tunnel: u32,
+    initiator: Initiator,
+    action: &str,
+    details: &[(&str, String)],
+) -> String {
+    let details = details
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"time\":{},\"tunnel\":{},\"initiator\":{},\"action\":{},\"details\":{{{}}}}}\n",
+        json_string(&rfc3339(time)),
+        tunnel,
+        json_string(&initiator.to_string()),
+        json_string(action),
+        details
+    )
+}
+
+/// Quotes and escapes a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Formats the time in UTC, to the millisecond, such as `2026-10-16T09:41:07.512Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // The civil date of a number of days since 1970-01-01, in the proleptic Gregorian calendar
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_record() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_millis(1_709_210_096_789)),
+            "2024-02-29T12:34:56.789Z"
+        );
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_791_970_867);
+        assert_eq!(
+            format_record(
+                time,
+                2,
+                Initiator::ControlSocket,
+                "remove-forward",
+                &[("forward", "web \"prod\"\n".to_string())]
+            ),
+            "{\"time\":\"2026-10-14T09:41:07.000Z\",\"tunnel\":2,\"initiator\":\"control-socket\",\
+            \"action\":\"remove-forward\",\"details\":{\"forward\":\"web \\\"prod\\\"\\n\"}}\n"
+        );
+    }
+
+    #[test]
+    fn test_append_records() {
+        let path = std::env::temp_dir().join(format!("onetun-audit-{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        AuditLog::open(&path, 1)
+            .unwrap()
+            .record(Initiator::Cli, "start", &[]);
+        // Reopening appends to the records of the previous run
+        AuditLog::open(&path, 1)
+            .unwrap()
+            .record(Initiator::Cli, "kill", &[]);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("\"initiator\":\"cli\",\"action\":\"start\",\"details\":{}}"));
+        assert!(lines[1].contains("\"action\":\"kill\""));
+        std::fs::remove_file(&path).ok();
+    }
+}
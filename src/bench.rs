@@ -0,0 +1,232 @@
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Size of the messages used to measure round-trip time.
+const RTT_MESSAGE_SIZE: usize = 64;
+
+/// How long to wait for a round-trip message to be echoed back.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the echo server to return the data once the throughput test stops sending.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parameters of a benchmark run.
+#[derive(Clone, Debug)]
+pub struct BenchmarkOptions {
+    /// How long to send data for during the throughput test.
+    pub duration: Duration,
+    /// How many round-trips to measure during the latency test.
+    pub rtt_samples: usize,
+    /// The size of each write during the throughput test.
+    pub chunk_size: usize,
+}
+
+impl Default for BenchmarkOptions {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(10),
+            rtt_samples: 100,
+            chunk_size: 16 * 1024,
+        }
+    }
+}
+
+/// Results of a benchmark run.
+#[derive(Clone, Debug)]
+pub struct BenchmarkReport {
+    /// Echoed bytes per second, in megabits.
+    pub throughput_mbps: f64,
+    /// Total bytes echoed back during the throughput test.
+    pub bytes_echoed: usize,
+    /// Median round-trip time.
+    pub rtt_p50: Duration,
+    /// 90th percentile round-trip time.
+    pub rtt_p90: Duration,
+    /// 99th percentile round-trip time.
+    pub rtt_p99: Duration,
+}
+
+impl Display for BenchmarkReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "throughput={:.2}Mbps ({} bytes echoed) rtt p50={:?} p90={:?} p99={:?}",
+            self.throughput_mbps, self.bytes_echoed, self.rtt_p50, self.rtt_p90, self.rtt_p99
+        )
+    }
+}
+
+/// Runs a latency and throughput test against an echo server reachable through the given
+/// local TCP listener (the source of a port forward).
+pub async fn run(
+    listener: SocketAddr,
+    options: &BenchmarkOptions,
+) -> anyhow::Result<BenchmarkReport> {
+    let mut stream = connect(loopback_if_unspecified(listener))
+        .await
+        .with_context(|| format!("Failed to connect to port forward on {}", listener))?;
+    stream.set_nodelay(true).ok();
+
+    // Warm-up round-trip: the first message may have to wait for the WireGuard handshake
+    round_trip(&mut stream).await?;
+
+    let mut rtts = Vec::with_capacity(options.rtt_samples);
+    for _ in 0..options.rtt_samples {
+        rtts.push(round_trip(&mut stream).await?);
+    }
+    rtts.sort();
+
+    let (mut reader, mut writer) = stream.into_split();
+    let start = Instant::now();
+
+    let sent = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    let chunk = vec![0xAB; options.chunk_size.max(1)];
+    let duration = options.duration;
+    let write_task = {
+        let sent = sent.clone();
+        let done = done.clone();
+        tokio::spawn(async move {
+            while start.elapsed() < duration {
+                writer.write_all(&chunk).await?;
+                sent.fetch_add(chunk.len(), Ordering::Relaxed);
+            }
+            done.store(true, Ordering::Release);
+            // Keep the write half open so the connection isn't closed before the echo is drained
+            Ok::<_, std::io::Error>(writer)
+        })
+    };
+
+    let mut received = 0;
+    let mut last_received = start;
+    let mut buffer = vec![0u8; 65536];
+    while !done.load(Ordering::Acquire) || received < sent.load(Ordering::Relaxed) {
+        match tokio::time::timeout(DRAIN_TIMEOUT, reader.read(&mut buffer)).await {
+            Ok(Ok(size)) if size > 0 => {
+                received += size;
+                last_received = Instant::now();
+            }
+            Ok(Err(e)) => return Err(e).with_context(|| "Failed to read echoed data"),
+            // EOF, or nothing echoed for a while
+            _ => break,
+        }
+    }
+    let _writer = write_task
+        .await
+        .with_context(|| "Throughput writer task failed")?
+        .with_context(|| "Failed to send data through port forward")?;
+    let sent = sent.load(Ordering::Relaxed);
+    if received < sent {
+        warn!(
+            "Benchmark stopped with {} of {} bytes echoed",
+            received, sent
+        );
+    }
+
+    let elapsed = last_received.duration_since(start).as_secs_f64();
+    let throughput_mbps = if elapsed > 0.0 {
+        (received as f64 * 8.0) / elapsed / 1_000_000.0
+    } else {
+        0.0
+    };
+
+    Ok(BenchmarkReport {
+        throughput_mbps,
+        bytes_echoed: received,
+        rtt_p50: percentile(&rtts, 50.0),
+        rtt_p90: percentile(&rtts, 90.0),
+        rtt_p99: percentile(&rtts, 99.0),
+    })
+}
+
+/// Connects to the port forward, giving its listener a moment to be bound if the tunnel just started.
+async fn connect(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let mut attempts = 0;
+    loop {
+        match TcpStream::connect(addr).await {
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused && attempts < 10 => {
+                attempts += 1;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends a small message and waits for it to be echoed back, returning the elapsed time.
+async fn round_trip(stream: &mut TcpStream) -> anyhow::Result<Duration> {
+    let message = [0x42u8; RTT_MESSAGE_SIZE];
+    let mut echo = [0u8; RTT_MESSAGE_SIZE];
+
+    let start = Instant::now();
+    stream
+        .write_all(&message)
+        .await
+        .with_context(|| "Failed to send RTT message")?;
+    tokio::time::timeout(ECHO_TIMEOUT, stream.read_exact(&mut echo))
+        .await
+        .with_context(|| "Timed out waiting for the echo server to answer")?
+        .with_context(|| "Failed to read echoed RTT message")?;
+    Ok(start.elapsed())
+}
+
+/// Listeners bound on an unspecified address are reached through loopback.
+fn loopback_if_unspecified(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// Nearest-rank percentile of sorted samples.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let samples: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&samples, 90.0), Duration::from_millis(90));
+        assert_eq!(percentile(&samples, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&samples, 0.0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_run_against_echo_server() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = socket.split();
+            tokio::io::copy(&mut reader, &mut writer).await.ok();
+        });
+
+        let options = BenchmarkOptions {
+            duration: Duration::from_millis(200),
+            rtt_samples: 10,
+            ..Default::default()
+        };
+        let report = run(addr, &options).await.expect("Benchmark failed");
+        assert!(report.bytes_echoed > 0);
+        assert!(report.throughput_mbps > 0.0);
+        assert!(report.rtt_p50 <= report.rtt_p99);
+    }
+}
@@ -0,0 +1,212 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::{PortForwardConfig, PortProtocol};
+use crate::events::{Bus, DropReason, Event};
+use crate::tunnel::tcp::TcpPortPool;
+use crate::tunnel::udp::UdpPortPool;
+use crate::virtual_iface::VirtualPort;
+
+/// Parameters of a reachability check.
+#[derive(Clone, Debug)]
+pub struct CheckOptions {
+    /// How long to wait for the destination to answer.
+    pub timeout: Duration,
+}
+
+impl Default for CheckOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Results of a successful reachability check.
+#[derive(Clone, Debug)]
+pub struct CheckReport {
+    /// Time for the TCP connection to be established, or for the UDP probe to be answered.
+    pub rtt: Duration,
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reachable rtt={:?}", self.rtt)
+    }
+}
+
+/// Checks that the destination of a port forward is reachable through the tunnel, without a local listener.
+/// TCP destinations must accept a connection, and UDP destinations must answer an empty datagram.
+pub(crate) async fn run(
+    port_forward: &Arc<PortForwardConfig>,
+    tcp_port_pool: &TcpPortPool,
+    udp_port_pool: &UdpPortPool,
+    bus: &Bus,
+    options: &CheckOptions,
+) -> anyhow::Result<CheckReport> {
+    match port_forward.protocol {
+        PortProtocol::Tcp => {
+            let virtual_port = tcp_port_pool.next(port_forward.source).await?;
+            let result = check_tcp(port_forward, virtual_port, bus, options.timeout).await;
+            tcp_port_pool.release(virtual_port).await;
+            result
+        }
+        PortProtocol::Udp => {
+            let virtual_port = udp_port_pool
+                .next(port_forward.source, port_forward.session_mode)
+                .await?;
+            let result = check_udp(port_forward, virtual_port, bus, options.timeout).await;
+            udp_port_pool.release(virtual_port).await;
+            result
+        }
+    }
+}
+
+/// Opens a virtual TCP connection to the destination, and closes it once established.
+async fn check_tcp(
+    port_forward: &Arc<PortForwardConfig>,
+    virtual_port: VirtualPort,
+    bus: &Bus,
+    timeout: Duration,
+) -> anyhow::Result<CheckReport> {
+    let mut endpoint = bus.new_endpoint();
+    let start = Instant::now();
+    endpoint.send(Event::ClientConnectionInitiated(
+        port_forward.clone(),
+        virtual_port,
+        start,
+    ));
+
+    let established = async {
+        loop {
+            match endpoint.recv().await {
+                Event::ClientConnectionEstablished(e_vp) if e_vp == virtual_port => return true,
+                Event::ClientConnectionDropped(e_vp, ..) if e_vp == virtual_port => return false,
+                _ => {}
+            }
+        }
+    };
+    let result = tokio::time::timeout(timeout, established).await;
+    let rtt = start.elapsed();
+
+    // Close the virtual connection in all cases
+    let reason = match result {
+        Ok(_) => DropReason::LocalClose,
+        Err(_) => DropReason::Timeout,
+    };
+    endpoint.send(Event::ClientConnectionDropped(
+        virtual_port,
+        reason,
+        Instant::now(),
+    ));
+
+    match result {
+        Ok(true) => Ok(CheckReport { rtt }),
+        Ok(false) => Err(anyhow::anyhow!(
+            "Connection refused by {}",
+            port_forward.destination
+        )),
+        Err(_) => Err(anyhow::anyhow!(
+            "No answer from {} within {:?}",
+            port_forward.destination,
+            timeout
+        )),
+    }
+}
+
+/// Sends an empty datagram to the destination, and waits for any answer.
+async fn check_udp(
+    port_forward: &Arc<PortForwardConfig>,
+    virtual_port: VirtualPort,
+    bus: &Bus,
+    timeout: Duration,
+) -> anyhow::Result<CheckReport> {
+    let mut endpoint = bus.new_endpoint();
+    let start = Instant::now();
+    endpoint.send(Event::LocalData(
+        port_forward.clone(),
+        virtual_port,
+        vec![],
+        start,
+    ));
+
+    let answered = async {
+        loop {
+            if let Event::RemoteData(e_vp, ..) = endpoint.recv().await {
+                if e_vp == virtual_port {
+                    return;
+                }
+            }
+        }
+    };
+    tokio::time::timeout(timeout, answered)
+        .await
+        .map(|_| CheckReport {
+            rtt: start.elapsed(),
+        })
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "No answer from {} within {:?}",
+                port_forward.destination,
+                timeout
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    /// Answers connections on the bus like a virtual interface would: accepted on port 80, refused otherwise.
+    fn spawn_virtual_interface(bus: &Bus) {
+        let mut endpoint = bus.new_endpoint();
+        tokio::spawn(async move {
+            loop {
+                if let Event::ClientConnectionInitiated(pf, vp, _) = endpoint.recv().await {
+                    if pf.destination.port() == 80 {
+                        endpoint.send(Event::ClientConnectionEstablished(vp));
+                    } else {
+                        endpoint.send(Event::ClientConnectionDropped(
+                            vp,
+                            DropReason::PeerReset,
+                            Instant::now(),
+                        ));
+                    }
+                }
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let bus = Bus::default();
+        spawn_virtual_interface(&bus);
+        let tcp_port_pool = TcpPortPool::new();
+        let udp_port_pool = UdpPortPool::new();
+        let options = CheckOptions {
+            timeout: Duration::from_millis(100),
+        };
+        let forward = |destination: &str, protocol| {
+            Arc::new(PortForwardConfig::new(
+                SocketAddr::from(([127, 0, 0, 1], 8080)),
+                destination.parse().unwrap(),
+                protocol,
+            ))
+        };
+
+        let open = forward("192.168.4.1:80", PortProtocol::Tcp);
+        let closed = forward("192.168.4.1:81", PortProtocol::Tcp);
+        let silent = forward("192.168.4.1:53", PortProtocol::Udp);
+        assert!(run(&open, &tcp_port_pool, &udp_port_pool, &bus, &options)
+            .await
+            .is_ok());
+        assert!(run(&closed, &tcp_port_pool, &udp_port_pool, &bus, &options)
+            .await
+            .is_err());
+        assert!(run(&silent, &tcp_port_pool, &udp_port_pool, &bus, &options)
+            .await
+            .is_err());
+    }
+}
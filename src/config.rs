@@ -1,32 +1,168 @@
 use std::collections::HashSet;
 use std::convert::TryFrom;
+#[cfg(feature = "bin")]
+use std::ffi::OsString;
 use std::fmt::{Display, Formatter};
-use std::fs::read_to_string;
-use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+#[cfg(feature = "bin")]
+use std::fs::File;
+#[cfg(feature = "bin")]
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
-use boringtun::crypto::{X25519PublicKey, X25519SecretKey};
-use clap::{App, Arg};
+#[cfg(feature = "bin")]
+use clap::{App, AppSettings, Arg, SubCommand};
+
+use crate::audit::Initiator;
+use crate::bench::BenchmarkOptions;
+use crate::check::CheckOptions;
+use crate::connection_authorizer::ConnectionAuthorizer;
+use crate::connection_log::ClientPrivacy;
+use crate::control::{ControlAddress, ControlToken};
+use crate::engine::{Clock, PrivateKey, ProtocolEngineFactory, PublicKey, TimerPolicy};
+use crate::error::OnetunError;
+use crate::obfuscation::{AmneziaObfuscator, Obfuscator, XorObfuscator};
+use crate::packet_filter::PacketFilter;
+use crate::simulation::NetworkSimulation;
 
-const DEFAULT_PORT_FORWARD_SOURCE: &str = "127.0.0.1";
+#[cfg(any(feature = "bin", feature = "control", test))]
+pub(crate) const DEFAULT_PORT_FORWARD_SOURCE: &str = "127.0.0.1";
+/// The length of the TCP Fast Open queue of a port forward with the `fast-open` option without a value.
+const DEFAULT_FAST_OPEN_QUEUE: u32 = 256;
+/// The most sockets a sharded TCP port forward listens on.
+const MAX_LISTENERS: usize = 64;
+/// The largest window of the virtual TCP sockets: smoltcp can't scale its window beyond 1 GiB.
+const MAX_TCP_WINDOW: usize = 1 << 30;
+/// How many reconnection attempts in a row may fail with `--exit-on-tunnel-failure`, when not given.
+#[cfg(feature = "bin")]
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 3;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub(crate) port_forwards: Vec<PortForwardConfig>,
     pub(crate) remote_port_forwards: Vec<PortForwardConfig>,
-    pub(crate) private_key: Arc<X25519SecretKey>,
-    pub(crate) endpoint_public_key: Arc<X25519PublicKey>,
+    pub(crate) private_key: Arc<PrivateKey>,
+    pub(crate) endpoint_public_key: Arc<PublicKey>,
     pub(crate) endpoint_addr: SocketAddr,
+    /// The other addresses of the endpoint. The one with the fastest handshake is used, starting with
+    /// `endpoint_addr` until they are probed.
+    pub(crate) additional_endpoint_addrs: Vec<SocketAddr>,
+    /// The local UDP port exchanging datagrams with the endpoint. A random port is used if not set.
+    pub(crate) listen_port: Option<u16>,
+    /// Whether the socket exchanging datagrams with the endpoint shares its port (`SO_REUSEADDR`, `SO_REUSEPORT`).
+    pub(crate) endpoint_reuse_port: bool,
     pub(crate) source_peer_ip: IpAddr,
+    /// The other IPs assigned to this peer, which port forwards can connect from.
+    pub(crate) additional_source_peer_ips: Vec<IpAddr>,
     pub(crate) keepalive_seconds: Option<u16>,
     pub(crate) max_transmission_unit: usize,
     pub(crate) log: String,
-    pub(crate) warnings: Vec<String>,
+    /// Leaves the logging to the logger installed by the application, instead of installing onetun's.
+    pub(crate) skip_logger_init: bool,
+    pub(crate) warnings: Vec<ConfigWarning>,
+    /// The warnings of the validation when the configuration was built, returned by `warnings`.
+    pub(crate) validation_warnings: Vec<ConfigWarning>,
     pub(crate) pcap_file: Option<String>,
+    pub(crate) pcap_index_file: Option<String>,
+    /// How many of the last IP packets to keep in memory, for `Handle::dump_recent_traffic`.
+    pub(crate) capture_buffer: usize,
+    /// The directory where a support bundle is written when the tunnel fails, if any.
+    pub(crate) pcap_on_error: Option<PathBuf>,
+    /// A capture whose packets from the peer are fed to the virtual interfaces, instead of the tunnel's.
+    pub(crate) replay: Option<PathBuf>,
+    pub(crate) command: Option<Command>,
+    /// How many reconnection attempts in a row may fail before the onetun binary exits, if it exits on tunnel failures.
+    pub(crate) exit_on_tunnel_failure: Option<u32>,
+    pub(crate) handshake_timeout: Option<Duration>,
+    /// How long a virtual TCP connection may wait for the destination to answer before it is aborted, if limited.
+    pub(crate) connect_timeout: Option<Duration>,
+    /// Whether the port forwards turn clients away until the first handshake with the endpoint.
+    pub(crate) require_handshake: bool,
+    pub(crate) allow_roaming: bool,
+    pub(crate) ignore_system_proxy: bool,
+    /// Whether the route to the endpoint is left unwatched, without recovering from network changes.
+    pub(crate) ignore_network_changes: bool,
+    /// How many handshake messages per second are accepted from the endpoint before requiring a cookie
+    /// (WireGuard's protection against handshake floods). boringtun's default if not set.
+    pub(crate) handshake_rate_limit: Option<u64>,
+    /// When to rekey or retry handshakes earlier than the WireGuard timers.
+    pub(crate) timer_policy: TimerPolicy,
+    pub(crate) crypto_workers: usize,
+    /// The largest outbound IP packet, in bytes, sent ahead of the bulk data, if there are priority lanes.
+    pub(crate) priority_lanes: Option<usize>,
+    /// The number of threads of the runtime dedicated to the data path, 0 to share the embedder's runtime.
+    pub(crate) data_path_threads: usize,
+    /// The nice value of the data path threads, if set.
+    pub(crate) data_path_priority: Option<i32>,
+    /// The worker threads of the runtime built by onetun, if not one per core.
+    pub(crate) runtime_workers: Option<usize>,
+    /// The most threads of that runtime for blocking work, if not tokio's default.
+    pub(crate) blocking_threads: Option<usize>,
+    /// The cores the threads of onetun are restricted to, if any.
+    pub(crate) cpu_affinity: Vec<usize>,
+    #[cfg(unix)]
+    pub(crate) tun_fd: Option<RawFd>,
+    pub(crate) packet_flow: bool,
+    pub(crate) obfuscator: Option<Arc<dyn Obfuscator>>,
+    /// The source IPs the peer may send packets from; packets from other IPs are dropped.
+    pub(crate) allowed_ips: Vec<AllowedIp>,
+    /// The IP ranges reached through the peer acting as a router, besides the destinations of the port forwards.
+    pub(crate) routes: Vec<AllowedIp>,
+    pub(crate) packet_filter: Option<Arc<dyn PacketFilter>>,
+    pub(crate) connection_authorizer: Option<Arc<dyn ConnectionAuthorizer>>,
+    /// Creates the WireGuard protocol implementation, instead of boringtun.
+    pub(crate) protocol_engine: Option<Arc<dyn ProtocolEngineFactory>>,
+    /// The clock of the timers of the tunnel and of the virtual interfaces, if not tokio's.
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+    pub(crate) network_simulation: Option<NetworkSimulation>,
+    /// Whether to reflect mDNS between the local network and the peer network.
+    pub(crate) mdns_reflector: bool,
+    pub(crate) in_process_connections: bool,
+    /// The directory where the running instances register the resources they use, if any.
+    pub(crate) lock_dir: Option<PathBuf>,
+    /// The file where the UDP sessions are saved, and restored from on start.
+    pub(crate) state_file: Option<PathBuf>,
+    /// The file where the configuration and the control actions are recorded, if any.
+    pub(crate) audit_log: Option<PathBuf>,
+    /// Who started the tunnel, and requests the actions on its `Handle`, for the audit log.
+    pub(crate) initiator: Initiator,
+    /// How the clients are written in the connection logs, if the connections are logged.
+    pub(crate) connection_log: Option<ClientPrivacy>,
+    /// The token the requests to the control interfaces must carry, if any.
+    pub(crate) control_token: Option<ControlToken>,
+    /// Whether the control interfaces may listen on other addresses than loopback ones.
+    pub(crate) remote_control: bool,
+    /// Where the control socket listens, if anywhere.
+    pub(crate) control_socket: Option<ControlAddress>,
+    /// Where the gRPC control service listens, if anywhere.
+    pub(crate) grpc_listen: Option<SocketAddr>,
+    /// The DNS server, reachable through the tunnel, resolving the host names of the destinations.
+    pub(crate) tunnel_dns: Option<SocketAddr>,
+    /// The prefix of the NAT64 gateway of the peer network, through which port forwards connecting from an
+    /// IPv6 peer IP reach IPv4 destinations.
+    pub(crate) nat64: Option<Nat64Prefix>,
+    /// What to do when a port forward can't listen on its source address.
+    pub(crate) bind_policy: BindPolicy,
+    /// What to do when a UDP client needs a virtual port, and all of them are assigned to active clients.
+    pub(crate) udp_port_exhaustion: PortPoolExhaustion,
+    /// Whether the virtual ports are assigned in order instead of at random.
+    pub(crate) sequential_ports: bool,
 }
 
 impl Config {
+    /// Starts a configuration with named settings. See `ConfigBuilder`.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    #[deprecated(note = "Use `Config::builder()` instead")]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         port_forwards: Vec<PortForwardConfig>,
         remote_port_forwards: Vec<PortForwardConfig>,
@@ -38,139 +174,547 @@ impl Config {
         max_transmission_unit: Option<usize>,
         log_level: Option<String>,
         pcap_file: Option<String>,
-    ) -> Result<Self, anyhow::Error> {
-        Ok(Self {
+    ) -> Result<Self, OnetunError> {
+        ConfigBuilder {
             port_forwards,
             remote_port_forwards,
-            private_key: Arc::new(
-                parse_private_key(&private_key.into()).with_context(|| "Invalid private key")?,
-            ),
-            endpoint_public_key: Arc::new(
-                parse_public_key(Some(&endpoint_public_key.into()))
-                    .with_context(|| "Invalid public key")?,
-            ),
-            endpoint_addr,
-            source_peer_ip,
+            private_key: Some(private_key.into()),
+            endpoint_public_key: Some(endpoint_public_key.into()),
+            endpoint_addr: Some(endpoint_addr),
+            source_peer_ip: Some(source_peer_ip),
             keepalive_seconds,
-            max_transmission_unit: max_transmission_unit.unwrap_or(1420),
-            log: log_level.unwrap_or_else(|| "info".to_string()),
-            pcap_file: pcap_file,
-            warnings: vec![],
-        })
+            max_transmission_unit,
+            log_level,
+            skip_logger_init: false,
+            pcap_file,
+        }
+        .build()
+    }
+
+    /// Only accepts the packets sent by the peer from the given IP ranges, like WireGuard's `AllowedIPs`.
+    /// By default, packets from any IP are accepted.
+    pub fn with_allowed_ips(mut self, allowed_ips: Vec<AllowedIp>) -> Self {
+        self.allowed_ips = allowed_ips;
+        self
+    }
+
+    /// Routes the given IP ranges through the peer, acting as a router: destinations in them are reachable
+    /// even when they aren't the destination of a port forward, like the hosts of a subnet behind the peer
+    /// (`0.0.0.0/0` and `::/0` for a default route). Each range needs a source peer IP of its IP version.
+    pub fn with_routes(mut self, routes: Vec<AllowedIp>) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Adds IPs assigned to this peer, besides `source_peer_ip`. Port forwards can connect from one of them
+    /// with `PortForwardConfig::source_peer_ip`.
+    pub fn with_additional_source_peer_ips(mut self, ips: Vec<IpAddr>) -> Self {
+        self.additional_source_peer_ips = ips;
+        self
+    }
+
+    /// Adds addresses of the endpoint, besides `endpoint_addr`. The tunnel measures the handshake round-trip
+    /// time to each of them when it starts and every few minutes, and uses the fastest one.
+    pub fn with_additional_endpoint_addrs(mut self, addrs: Vec<SocketAddr>) -> Self {
+        self.additional_endpoint_addrs = addrs;
+        self
+    }
+
+    /// All the addresses of the endpoint, starting with the default one.
+    pub(crate) fn endpoint_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = vec![self.endpoint_addr];
+        addrs.extend(&self.additional_endpoint_addrs);
+        addrs
+    }
+
+    /// All the IPs assigned to this peer, starting with the default one.
+    pub(crate) fn source_peer_ips(&self) -> Vec<IpAddr> {
+        let mut ips = vec![self.source_peer_ip];
+        ips.extend(&self.additional_source_peer_ips);
+        ips
+    }
+
+    /// Obfuscates the datagrams exchanged with the WireGuard endpoint, which must reverse the obfuscation.
+    pub fn with_obfuscator(mut self, obfuscator: Arc<dyn Obfuscator>) -> Self {
+        self.obfuscator = Some(obfuscator);
+        self
+    }
+
+    /// Passes the IP packets going through the virtual interfaces to the filter, which may rewrite or drop them.
+    pub fn with_packet_filter(mut self, packet_filter: Arc<dyn PacketFilter>) -> Self {
+        self.packet_filter = Some(packet_filter);
+        self
+    }
+
+    /// Asks the authorizer whether to serve each new client of the local port forwards, before a virtual port
+    /// is assigned to it.
+    pub fn with_connection_authorizer(mut self, authorizer: Arc<dyn ConnectionAuthorizer>) -> Self {
+        self.connection_authorizer = Some(authorizer);
+        self
+    }
+
+    /// Runs the timers of the tunnel (the ticks of the protocol engine's timers, handshake tracking, link
+    /// quality) and of the sockets of the virtual interfaces (polls, connect timeouts) on the given clock, such as
+    /// a `ManualClock` that only elapses them when advanced. By default, they follow the clock of tokio, which
+    /// tests can pause and advance (`tokio::time::pause`) to elapse them instantly. boringtun measures the age of
+    /// its sessions on the system clock, so its own rekeys and keep-alives fall on the first tick after they are
+    /// due in the time of the system; the stricter timers of `with_timer_policy` follow the given clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Implements the WireGuard protocol with the engines of the factory instead of boringtun, such as one
+    /// using hardware acceleration, or with other licensing terms. The handshake rate limit (see
+    /// `with_handshake_rate_limit`) only applies to boringtun. Required when onetun is built without the
+    /// `boringtun` feature.
+    #[cfg(feature = "custom-engine")]
+    pub fn with_protocol_engine(mut self, factory: Arc<dyn ProtocolEngineFactory>) -> Self {
+        self.protocol_engine = Some(factory);
+        self
+    }
+
+    /// Simulates poor network conditions on the datagrams sent to the WireGuard endpoint. For testing only.
+    pub fn with_network_simulation(mut self, simulation: NetworkSimulation) -> Self {
+        self.network_simulation = Some(simulation);
+        self
+    }
+
+    /// Reflects mDNS between the local network and the peer network, so the services advertised on
+    /// either side can be discovered on the other.
+    pub fn with_mdns_reflector(mut self) -> Self {
+        self.mdns_reflector = true;
+        self
+    }
+
+    /// Runs both virtual interfaces even without port forwards, for the connections opened in-process with
+    /// `Handle::open_tcp` and `Handle::open_udp`.
+    pub fn with_in_process_connections(mut self) -> Self {
+        self.in_process_connections = true;
+        self
+    }
+
+    /// Keeps the given number of the last IP packets in memory, to be dumped with
+    /// `Handle::dump_recent_traffic`. Zero disables it.
+    pub fn with_capture_buffer(mut self, packets: usize) -> Self {
+        self.capture_buffer = packets;
+        self
+    }
+
+    /// Writes a support bundle to the given directory when the session with the endpoint expires, or when
+    /// the connections of a port forward keep failing: the recent traffic (see `with_capture_buffer`) as a
+    /// pcap file, and a report of the configuration, the last events and the handshakes.
+    pub fn with_pcap_on_error(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.pcap_on_error = Some(dir.into());
+        self
+    }
+
+    /// Feeds the IP packets that the peer sent in the given pcap file (as written by `--pcap`) to the virtual
+    /// interfaces at their original timing, instead of exchanging packets with the peer. For reproducing the
+    /// bugs of the virtual interfaces without a live peer.
+    pub fn with_replay(mut self, path: impl Into<PathBuf>) -> Self {
+        self.replay = Some(path.into());
+        self
+    }
+
+    /// Exchanges the datagrams with the endpoint from the given local UDP port, instead of a random one.
+    pub fn with_listen_port(mut self, listen_port: u16) -> Self {
+        self.listen_port = Some(listen_port);
+        self
+    }
+
+    /// Sets `SO_REUSEADDR` and `SO_REUSEPORT` (Unix only) on the socket exchanging datagrams with the endpoint, so
+    /// that a fixed listen port can be bound again while the previous socket lingers: by a restarted onetun, or
+    /// when rebinding after a network change.
+    pub fn with_endpoint_reuse_port(mut self) -> Self {
+        self.endpoint_reuse_port = true;
+        self
+    }
+
+    /// Registers the instance in the given directory, shared by the instances of the host. Starting
+    /// fails if another registered instance uses the same listen addresses or peer IPs.
+    pub fn with_lock_dir(mut self, lock_dir: impl Into<PathBuf>) -> Self {
+        self.lock_dir = Some(lock_dir.into());
+        self
+    }
+
+    /// Saves the virtual ports of the UDP clients to the given file, periodically and when the tunnel is
+    /// killed, and restores them on start, so that the destinations keep seeing the same source ports across
+    /// a restart (see `Handle::save_state`).
+    pub fn with_state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Appends a record of the configuration the tunnel starts with, and of each action requested on its
+    /// `Handle` (such as removing a port forward, or killing the tunnel), to the given file, as JSON lines.
+    /// The keys are never written. Starting fails if the file can't be opened.
+    pub fn with_audit_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.audit_log = Some(path.into());
+        self
+    }
+
+    /// Logs each TCP connection of the port forwards when it closes, with its client, port forward, bytes and
+    /// duration, under the `onetun::connections` log target (see `connection_log`). The clients are written as
+    /// the privacy setting allows: in full, truncated to their network, or hashed.
+    pub fn with_connection_log(mut self, privacy: ClientPrivacy) -> Self {
+        self.connection_log = Some(privacy);
+        self
+    }
+
+    /// Who the records of the audit log attribute the start of the tunnel and the actions requested on its
+    /// `Handle` to. Defaults to `Initiator::Library`, or `Initiator::Cli` with `Config::from_args`.
+    pub fn with_initiator(mut self, initiator: Initiator) -> Self {
+        self.initiator = initiator;
+        self
+    }
+
+    /// Requires the token in each request to the control interfaces of the tunnel (see `onetun::control`),
+    /// so that other users of the machine can't control it.
+    pub fn with_control_token(mut self, token: ControlToken) -> Self {
+        self.control_token = Some(token);
+        self
     }
 
+    /// Lets the control interfaces of the tunnel listen on other addresses than loopback ones, which
+    /// also requires a control token (see `Config::with_control_token`).
+    pub fn with_remote_control(mut self) -> Self {
+        self.remote_control = true;
+        self
+    }
+
+    /// Where the control socket of the tunnel listens: a Unix socket, or a TCP address on loopback unless
+    /// remote control is allowed. It is served by `control::ControlServer`, which the onetun binary runs.
+    pub fn with_control_socket(mut self, address: ControlAddress) -> Self {
+        self.control_socket = Some(address);
+        self
+    }
+
+    /// Serves the gRPC control service (see `control::grpc`) on the given address, on loopback unless remote
+    /// control is allowed. It is run by the onetun binary, built with the `grpc` feature.
+    pub fn with_grpc_listen(mut self, addr: SocketAddr) -> Self {
+        self.grpc_listen = Some(addr);
+        self
+    }
+
+    /// Resolves the host names of the port forward destinations (see `PortForwardConfig::hostname`)
+    /// with the given DNS server, reachable through the tunnel, instead of the system's resolver.
+    pub fn with_tunnel_dns(mut self, server: SocketAddr) -> Self {
+        self.tunnel_dns = Some(server);
+        self
+    }
+
+    /// Reaches the IPv4 destinations of the port forwards connecting from an IPv6 peer IP through the NAT64
+    /// gateway of the peer network, at their address embedded in the prefix. With a tunnel DNS server, the
+    /// host names without an IPv6 address are resolved to their IPv4 address, embedded likewise (DNS64).
+    pub fn with_nat64(mut self, prefix: Nat64Prefix) -> Self {
+        self.nat64 = Some(prefix);
+        self
+    }
+
+    /// Accepts the given number of handshake messages per second from the endpoint, after which it must
+    /// prove its address with a cookie before its handshakes are processed. Zero requires a cookie for every
+    /// handshake. By default, boringtun accepts 10 per second.
+    pub fn with_handshake_rate_limit(mut self, limit: u64) -> Self {
+        self.handshake_rate_limit = Some(limit);
+        self
+    }
+
+    /// Advanced: rekeys the sessions and retries the handshakes earlier than the WireGuard timers, such as
+    /// after a number of data messages for bursty sessions, or well before the sessions expire for long-lived
+    /// ones. The timers of the protocol engine still apply: the policy can only make them shorter.
+    pub fn with_timer_policy(mut self, policy: TimerPolicy) -> Self {
+        self.timer_policy = policy;
+        self
+    }
+
+    /// Aborts the virtual TCP connections whose destination doesn't answer within the timeout, resetting their
+    /// local connection, instead of leaving the client waiting for its own timeout.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Turns the clients of the port forwards away until the first handshake with the endpoint completed,
+    /// instead of accepting connections whose packets go nowhere while the endpoint is unreachable.
+    pub fn with_require_handshake(mut self) -> Self {
+        self.require_handshake = true;
+        self
+    }
+
+    /// Sends the datagrams to the endpoint directly, even when the operating system has a SOCKS proxy set
+    /// (on macOS and Windows), through which they are relayed by default.
+    pub fn with_ignore_system_proxy(mut self) -> Self {
+        self.ignore_system_proxy = true;
+        self
+    }
+
+    /// Stops watching the route to the endpoint for network changes, after which the socket is rebound and a
+    /// handshake initiated by default. For embedders that call `Handle::network_changed` when the OS notifies
+    /// them instead.
+    pub fn with_ignore_network_changes(mut self) -> Self {
+        self.ignore_network_changes = true;
+        self
+    }
+
+    /// Sends the outbound IP packets of at most the given size, in bytes, ahead of the larger ones, so that
+    /// interactive traffic (such as SSH keystrokes, or the acknowledgements of a download) doesn't wait behind
+    /// the bulk data of another port forward. Packets of the same size keep their order.
+    pub fn with_priority_lanes(mut self, max_interactive_size: usize) -> Self {
+        self.priority_lanes = Some(max_interactive_size);
+        self
+    }
+
+    /// Runs the data path (the WireGuard tasks and the poll loops of the virtual interfaces) on a runtime of its
+    /// own, with the given number of threads, so that a busy runtime of the embedder doesn't delay the packets.
+    /// Zero, the default, runs it on the runtime that starts the tunnel.
+    pub fn with_data_path_threads(mut self, threads: usize) -> Self {
+        self.data_path_threads = threads;
+        self
+    }
+
+    /// Sets the nice value of the data path threads (see `with_data_path_threads`), from -20 (highest
+    /// priority) to 19. Raising the priority requires `CAP_SYS_NICE`. Only supported on Linux.
+    pub fn with_data_path_priority(mut self, nice: i32) -> Self {
+        self.data_path_priority = Some(nice);
+        self
+    }
+
+    /// Sets the worker threads of the runtime built by onetun, instead of one per core. It is the runtime of the
+    /// binary, and the one shared by the tunnels started with `blocking_start`, built by the first of them; the
+    /// tunnels started with `start` run on the runtime of the caller.
+    pub fn with_runtime_workers(mut self, workers: usize) -> Self {
+        self.runtime_workers = Some(workers);
+        self
+    }
+
+    /// Sets the most threads the runtime built by onetun (see `with_runtime_workers`) spawns for blocking work,
+    /// such as resolving host names with the system. By default, tokio allows 512.
+    pub fn with_blocking_threads(mut self, threads: usize) -> Self {
+        self.blocking_threads = Some(threads);
+        self
+    }
+
+    /// Restricts the threads of the runtime built by onetun (see `with_runtime_workers`) and of the data path
+    /// (see `with_data_path_threads`) to the given cores, numbered from 0. Only supported on Linux.
+    pub fn with_cpu_affinity(mut self, cores: Vec<usize>) -> Self {
+        self.cpu_affinity = cores;
+        self
+    }
+
+    /// Sets what to do when a port forward can't listen on its source address, such as when its port is
+    /// busy. By default, starting the tunnel fails with `OnetunError::BindFailed`.
+    pub fn with_bind_policy(mut self, bind_policy: BindPolicy) -> Self {
+        self.bind_policy = bind_policy;
+        self
+    }
+
+    /// Sets what to do when a new UDP client needs a virtual port, and all of them are assigned to active
+    /// clients. By default, the datagrams of the new client are dropped.
+    pub fn with_udp_port_exhaustion(mut self, policy: PortPoolExhaustion) -> Self {
+        self.udp_port_exhaustion = policy;
+        self
+    }
+
+    /// Assigns the virtual ports of the clients in order, from the lowest, instead of at random. Random ports,
+    /// the default, avoid the predictable source ports some intrusion detection systems and NAT devices on the
+    /// peer network flag.
+    pub fn with_sequential_ports(mut self) -> Self {
+        self.sequential_ports = true;
+        self
+    }
+
+    /// Adds a local port forward after the configuration was built, such as one created by the FFI bindings. It is
+    /// validated when the tunnel starts.
+    pub fn with_port_forward(mut self, port_forward: PortForwardConfig) -> Self {
+        self.port_forwards.push(port_forward);
+        self
+    }
+
+    /// Reads and writes raw IP packets on the given tun device (such as the one created by Android's
+    /// `VpnService`), instead of serving port forwards. onetun takes ownership of the file descriptor
+    /// and closes it when the tunnel is killed.
+    #[cfg(unix)]
+    pub fn with_tun_fd(mut self, tun_fd: RawFd) -> Self {
+        self.tun_fd = Some(tun_fd);
+        self
+    }
+
+    /// Exchanges raw IP packets with the embedder (see `Handle::write_ip_packet` and
+    /// `Handle::set_packet_callback`), such as an iOS `NEPacketTunnelFlow`, instead of serving port forwards.
+    pub fn with_packet_flow(mut self) -> Self {
+        self.packet_flow = true;
+        self
+    }
+
+    /// Whether IP packets are bridged to a tun device or to the embedder's packet flow.
+    pub(crate) fn uses_tun(&self) -> bool {
+        #[cfg(unix)]
+        return self.tun_fd.is_some() || self.packet_flow;
+        #[cfg(not(unix))]
+        return self.packet_flow;
+    }
+
+    /// The routes of the virtual interfaces. The destinations only known at runtime (resolved through the
+    /// tunnel, or connected to in-process) aren't addresses of the interfaces, so they get a default route
+    /// unless routes are configured.
+    pub(crate) fn interface_routes(&self) -> Vec<AllowedIp> {
+        let runtime_destinations = self.in_process_connections
+            || (self.tunnel_dns.is_some()
+                && self.port_forwards.iter().any(|pf| pf.hostname.is_some()));
+        if self.routes.is_empty() && runtime_destinations {
+            let source_peer_ips = self.source_peer_ips();
+            AllowedIp::any()
+                .into_iter()
+                .filter(|route| {
+                    source_peer_ips
+                        .iter()
+                        .any(|ip| ip.is_ipv4() == route.addr().is_ipv4())
+                })
+                .collect()
+        } else {
+            self.routes.clone()
+        }
+    }
+
+    /// The sub-command given on the command line, if any.
+    pub fn command(&self) -> Option<&Command> {
+        self.command.as_ref()
+    }
+
+    /// With `--exit-on-tunnel-failure`, how many times in a row the tunnel may fail to reconnect after it dropped,
+    /// before the onetun binary exits.
+    pub fn exit_on_tunnel_failure(&self) -> Option<u32> {
+        self.exit_on_tunnel_failure
+    }
+
+    /// The address to run echo services on (see `echo`), when the command line is `onetun echo-server <ADDR>`.
+    /// They run without a tunnel, so the command is parsed before `from_args`, which requires its arguments.
+    #[cfg(feature = "bin")]
+    pub fn echo_server_addr() -> Option<anyhow::Result<SocketAddr>> {
+        if std::env::args_os().nth(1)? != "echo-server" {
+            return None;
+        }
+        let matches = App::new("onetun")
+            .version(env!("CARGO_PKG_VERSION"))
+            .subcommand(echo_server_command())
+            .get_matches();
+        let addr = matches.subcommand_matches("echo-server")?.value_of("ADDR");
+        Some(parse_addr(addr).with_context(|| "Invalid echo server address"))
+    }
+
+    /// Parses the command-line arguments. Options may also be read from a file given with `--config`, or
+    /// from stdin with `--config -`, and the private key from a file descriptor with `--private-key-fd`, so
+    /// that supervisors can pass secrets without writing them to disk or exposing them in the arguments.
+    #[cfg(feature = "bin")]
     pub fn from_args() -> anyhow::Result<Self> {
         let mut warnings = vec![];
+        let (args, config_private_key) = expand_config_args(std::env::args_os().collect())?;
 
-        let matches = App::new("onetun")
+        let app_matches = App::new("onetun")
             .author("Aram Peres <aram.peres@gmail.com>")
             .version(env!("CARGO_PKG_VERSION"))
-            .args(&[
-                Arg::with_name("PORT_FORWARD")
-                    .required(false)
-                    .multiple(true)
-                    .takes_value(true)
-                    .help("Port forward configurations. The format of each argument is [src_host:]<src_port>:<dst_host>:<dst_port>[:TCP,UDP,...], \
-                    where [src_host] is the local IP to listen on, <src_port> is the local port to listen on, <dst_host> is the remote peer IP to forward to, and <dst_port> is the remote port to forward to. \
-                    Environment variables of the form 'ONETUN_PORT_FORWARD_[#]' are also accepted, where [#] starts at 1.\n\
-                    Examples:\n\
-                    \t127.0.0.1:8080:192.168.4.1:8081:TCP,UDP\n\
-                    \t127.0.0.1:8080:192.168.4.1:8081:TCP\n\
-                    \t0.0.0.0:8080:192.168.4.1:8081\n\
-                    \t[::1]:8080:192.168.4.1:8081\n\
-                    \t8080:192.168.4.1:8081\n\
-                    \t8080:192.168.4.1:8081:TCP\n\
-                    \tlocalhost:8080:192.168.4.1:8081:TCP\n\
-                    \tlocalhost:8080:peer.intranet:8081:TCP\
-                    "),
-                Arg::with_name("private-key")
-                    .required_unless("private-key-file")
-                    .takes_value(true)
-                    .long("private-key")
-                    .env("ONETUN_PRIVATE_KEY")
-                    .help("The private key of this peer. The corresponding public key should be registered in the WireGuard endpoint. \
-                    You can also use '--private-key-file' to specify a file containing the key instead."),
-                Arg::with_name("private-key-file")
-                    .takes_value(true)
-                    .long("private-key-file")
-                    .env("ONETUN_PRIVATE_KEY_FILE")
-                    .help("The path to a file containing the private key of this peer. The corresponding public key should be registered in the WireGuard endpoint."),
-                Arg::with_name("endpoint-public-key")
-                    .required(true)
-                    .takes_value(true)
-                    .long("endpoint-public-key")
-                    .env("ONETUN_ENDPOINT_PUBLIC_KEY")
-                    .help("The public key of the WireGuard endpoint (remote)."),
-                Arg::with_name("endpoint-addr")
-                    .required(true)
-                    .takes_value(true)
-                    .long("endpoint-addr")
-                    .env("ONETUN_ENDPOINT_ADDR")
-                    .help("The address (IP + port) of the WireGuard endpoint (remote). Example: 1.2.3.4:51820"),
-                Arg::with_name("source-peer-ip")
-                    .required(true)
-                    .takes_value(true)
-                    .long("source-peer-ip")
-                    .env("ONETUN_SOURCE_PEER_IP")
-                    .help("The source IP to identify this peer as (local). Example: 192.168.4.3"),
-                Arg::with_name("keep-alive")
-                    .required(false)
-                    .takes_value(true)
-                    .long("keep-alive")
-                    .env("ONETUN_KEEP_ALIVE")
-                    .help("Configures a persistent keep-alive for the WireGuard tunnel, in seconds."),
-                Arg::with_name("max-transmission-unit")
-                    .required(false)
-                    .takes_value(true)
-                    .long("max-transmission-unit")
-                    .env("ONETUN_MTU")
-                    .default_value("1420")
-                    .help("Configures the max-transmission-unit (MTU) of the WireGuard tunnel."),
-                Arg::with_name("log")
-                    .required(false)
-                    .takes_value(true)
-                    .long("log")
-                    .env("ONETUN_LOG")
-                    .default_value("info")
-                    .help("Configures the log level and format."),
-                Arg::with_name("pcap")
-                    .required(false)
-                    .takes_value(true)
-                    .long("pcap")
-                    .env("ONETUN_PCAP")
-                    .help("Decrypts and captures IP packets on the WireGuard tunnel to a given output file."),
-                Arg::with_name("remote")
-                    .required(false)
-                    .takes_value(true)
-                    .multiple(true)
-                    .long("remote")
-                    .short("r")
-                    .help("Remote port forward configurations. The format of each argument is <src_port>:<dst_host>:<dst_port>[:TCP,UDP,...], \
-                    where <src_port> is the port the other peers will reach the server with, <dst_host> is the IP to forward to, and <dst_port> is the port to forward to. \
-                    The <src_port> will be bound on onetun's peer IP, as specified by --source-peer-ip. If you pass a different value for <src_host> here, it will be rejected.\n\
-                    Note: <dst_host>:<dst_port> must be reachable by onetun. If referring to another WireGuard peer, use --bridge instead (not supported yet).\n\
-                    Environment variables of the form 'ONETUN_REMOTE_PORT_FORWARD_[#]' are also accepted, where [#] starts at 1.\n\
-                    Examples:\n\
-                    \t--remote 8080:localhost:8081:TCP,UDP\n\
-                    \t--remote 8080:[::1]:8081:TCP\n\
-                    \t--remote 8080:google.com:80\
-                    "),
-            ]).get_matches();
-
-        // Combine `PORT_FORWARD` arg and `ONETUN_PORT_FORWARD_#` envs
-        let mut port_forward_strings = HashSet::new();
-        if let Some(values) = matches.values_of("PORT_FORWARD") {
-            for value in values {
-                port_forward_strings.insert(value.to_owned());
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .args(&tunnel_args())
+            .subcommand(
+                SubCommand::with_name("bench")
+                    .about("Measures the throughput and latency of each TCP port forward, against an echo server running on the destination.")
+                    .args(&tunnel_args())
+                    .args(&[
+                        Arg::with_name("duration")
+                            .required(false)
+                            .takes_value(true)
+                            .long("duration")
+                            .default_value("10")
+                            .help("How long to measure throughput for, in seconds."),
+                        Arg::with_name("rtt-samples")
+                            .required(false)
+                            .takes_value(true)
+                            .long("rtt-samples")
+                            .default_value("100")
+                            .help("How many round-trips to measure latency with."),
+                    ]),
+            )
+            .subcommand(
+                SubCommand::with_name("check")
+                    .about("Checks that the destination of each port forward is reachable through the tunnel, without listening \
+                    locally: TCP destinations must accept a connection, and UDP destinations must answer an empty datagram.")
+                    .args(&tunnel_args())
+                    .arg(
+                        Arg::with_name("timeout")
+                            .required(false)
+                            .takes_value(true)
+                            .long("timeout")
+                            .default_value("5s")
+                            .help("How long to wait for the handshake, then for each destination to answer. Accepts a number \
+                            of seconds, or a duration like '10s', '500ms' or '1m'."),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("doctor")
+                    .about("Checks the environment without starting the tunnel: whether the endpoint answers a handshake, \
+                    the local ports are free, the MTU fits, IPv6 is available and the clock is right.")
+                    .args(&tunnel_args()),
+            )
+            .subcommand(
+                SubCommand::with_name("show")
+                    .about("Starts the tunnel without listening locally, waits for the handshake, and prints the interface \
+                    and its peer in the layout of `wg show`, for the scripts monitoring WireGuard interfaces.")
+                    .args(&tunnel_args()),
+            )
+            // Parsed by `echo_server_addr`, as it runs without a tunnel; listed here for the help
+            .subcommand(echo_server_command())
+            .get_matches_from(args);
+
+        let (matches, command) = match app_matches.subcommand() {
+            ("bench", Some(matches)) => {
+                let options = BenchmarkOptions {
+                    duration: Duration::from_secs(
+                        matches
+                            .value_of("duration")
+                            .unwrap_or_default()
+                            .parse()
+                            .with_context(|| "Invalid benchmark duration")?,
+                    ),
+                    rtt_samples: matches
+                        .value_of("rtt-samples")
+                        .unwrap_or_default()
+                        .parse()
+                        .with_context(|| "Invalid number of RTT samples")?,
+                    ..Default::default()
+                };
+                (matches, Some(Command::Bench(options)))
             }
-        }
+            ("check", Some(matches)) => {
+                let options = CheckOptions {
+                    timeout: parse_duration(matches.value_of("timeout").unwrap_or_default())
+                        .with_context(|| "Invalid check timeout")?,
+                };
+                (matches, Some(Command::Check(options)))
+            }
+            ("doctor", Some(matches)) => (matches, Some(Command::Doctor)),
+            ("show", Some(matches)) => (matches, Some(Command::Show)),
+            _ => (&app_matches, None),
+        };
+
+        // Combine `PORT_FORWARD` arg, `--forward` args and `ONETUN_PORT_FORWARD_#` envs.
+        // The order is kept, so that unnamed port forwards have stable identifiers.
+        let mut port_forward_strings: Vec<String> = matches
+            .values_of("PORT_FORWARD")
+            .into_iter()
+            .flatten()
+            .chain(matches.values_of("forward").into_iter().flatten())
+            .map(String::from)
+            .collect();
         for n in 1.. {
             if let Ok(env) = std::env::var(format!("ONETUN_PORT_FORWARD_{}", n)) {
-                port_forward_strings.insert(env);
+                port_forward_strings.push(env);
             } else {
                 break;
             }
         }
+        let mut seen = HashSet::new();
+        port_forward_strings.retain(|s| seen.insert(s.clone()));
 
         // Parse `PORT_FORWARD` strings into `PortForwardConfig`
         let port_forwards: anyhow::Result<Vec<Vec<PortForwardConfig>>> = port_forward_strings
@@ -182,10 +726,37 @@ impl Config {
             .into_iter()
             .flatten()
             .collect();
+        if port_forwards.iter().any(|pf| !pf.peer_routes.is_empty()) {
+            return Err(anyhow::anyhow!(
+                "Routes are only supported on remote port forwards."
+            ));
+        }
 
-        // Read source-peer-ip
-        let source_peer_ip = parse_ip(matches.value_of("source-peer-ip"))
-            .with_context(|| "Invalid source peer IP")?;
+        // Read source-peer-ip: the first one is the default
+        let mut source_peer_ips: Vec<IpAddr> = Vec::new();
+        for ip in matches.values_of("source-peer-ip").into_iter().flatten() {
+            let ip = parse_ip(Some(ip)).with_context(|| "Invalid source peer IP")?;
+            if !source_peer_ips.contains(&ip) {
+                source_peer_ips.push(ip);
+            }
+        }
+        let source_peer_ip = *source_peer_ips
+            .first()
+            .with_context(|| "Missing source peer IP")?;
+        let additional_source_peer_ips = source_peer_ips.split_off(1);
+
+        // Read endpoint-addr: the first one is used until they are probed
+        let mut endpoint_addrs: Vec<SocketAddr> = Vec::new();
+        for addr in matches.values_of("endpoint-addr").into_iter().flatten() {
+            let addr = parse_addr(Some(addr)).with_context(|| "Invalid endpoint address")?;
+            if !endpoint_addrs.contains(&addr) {
+                endpoint_addrs.push(addr);
+            }
+        }
+        let endpoint_addr = *endpoint_addrs
+            .first()
+            .with_context(|| "Missing endpoint address")?;
+        let additional_endpoint_addrs = endpoint_addrs.split_off(1);
 
         // Combined `remote` arg and `ONETUN_REMOTE_PORT_FORWARD_#` envs
         let mut port_forward_strings = HashSet::new();
@@ -205,12 +776,7 @@ impl Config {
         let remote_port_forwards: anyhow::Result<Vec<Vec<PortForwardConfig>>> =
             port_forward_strings
                 .into_iter()
-                .map(|s| {
-                    PortForwardConfig::from_notation(
-                        &s,
-                        matches.value_of("source-peer-ip").unwrap(),
-                    )
-                })
+                .map(|s| PortForwardConfig::from_notation(&s, &source_peer_ip.to_string()))
                 .collect();
         let mut remote_port_forwards: Vec<PortForwardConfig> = remote_port_forwards
             .with_context(|| "Failed to parse remote port forward config")?
@@ -218,16 +784,61 @@ impl Config {
             .flatten()
             .collect();
         for port_forward in remote_port_forwards.iter_mut() {
-            if port_forward.source.ip() != source_peer_ip {
+            if port_forward.pipe.is_some() {
+                return Err(anyhow::anyhow!(
+                    "Named pipes are not supported on remote port forwards."
+                ));
+            }
+            if port_forward.interface.is_some() {
+                return Err(anyhow::anyhow!(
+                    "Interface names are not supported on remote port forwards."
+                ));
+            }
+            if port_forward.source.ip() != source_peer_ip
+                && !additional_source_peer_ips.contains(&port_forward.source.ip())
+            {
                 return Err(anyhow::anyhow!("Remote port forward config <src_host> must match --source-peer-ip ({}), or be omitted.", source_peer_ip));
             }
-            port_forward.source = SocketAddr::from((source_peer_ip, port_forward.source.port()));
+            if port_forward.tls.is_some() {
+                return Err(anyhow::anyhow!(
+                    "TLS is not supported on remote port forwards."
+                ));
+            }
             port_forward.remote = true;
         }
 
-        if port_forwards.is_empty() && remote_port_forwards.is_empty() {
-            return Err(anyhow::anyhow!("No port forward configurations given."));
-        }
+        // Each reconnection attempt is a round of handshakes, until the session expires again
+        let exit_on_tunnel_failure = if matches.is_present("exit-on-tunnel-failure") {
+            let attempts = matches
+                .value_of("reconnect-attempts")
+                .map(str::parse)
+                .transpose()
+                .with_context(|| "Invalid number of reconnection attempts")?
+                .unwrap_or(DEFAULT_RECONNECT_ATTEMPTS);
+            if attempts == 0 {
+                return Err(anyhow::anyhow!(
+                    "Invalid number of reconnection attempts: must be at least 1"
+                ));
+            }
+            Some(attempts)
+        } else {
+            None
+        };
+
+        // Read the control token from a file or the environment, never from the arguments
+        let control_token = match matches.value_of("control-token-file") {
+            Some(path) => {
+                if let Some((_, true)) = is_file_insecurely_readable(path) {
+                    warnings.push(ConfigWarning::ControlTokenWorldReadable);
+                }
+                Some(ControlToken::from_file(path.as_ref())?)
+            }
+            None => std::env::var("ONETUN_CONTROL_TOKEN")
+                .ok()
+                .map(|token| ControlToken::new(&token))
+                .transpose()
+                .with_context(|| "Invalid ONETUN_CONTROL_TOKEN")?,
+        };
 
         // Read private key from file or CLI argument
         let (group_readable, world_readable) = matches
@@ -235,471 +846,4393 @@ impl Config {
             .and_then(is_file_insecurely_readable)
             .unwrap_or_default();
         if group_readable {
-            warnings.push("Private key file is group-readable. This is insecure.".into());
+            warnings.push(ConfigWarning::PrivateKeyGroupReadable);
         }
         if world_readable {
-            warnings.push("Private key file is world-readable. This is insecure.".into());
+            warnings.push(ConfigWarning::PrivateKeyWorldReadable);
         }
 
-        let private_key = if let Some(private_key_file) = matches.value_of("private-key-file") {
-            read_to_string(private_key_file)
-                .map(|s| s.trim().to_string())
+        let private_key = if let Some(private_key) = config_private_key {
+            Ok(private_key)
+        } else if let Some(fd) = matches.value_of("private-key-fd") {
+            read_secret_fd(fd).with_context(|| "Failed to read private key file descriptor")
+        } else if let Some(private_key_file) = matches.value_of("private-key-file") {
+            File::open(private_key_file)
+                .and_then(read_secret)
                 .with_context(|| "Failed to read private key file")
         } else {
             if std::env::var("ONETUN_PRIVATE_KEY").is_err() {
-                warnings.push("Private key was passed using CLI. This is insecure. \
-                Use \"--private-key-file <file containing private key>\", or the \"ONETUN_PRIVATE_KEY\" env variable instead.".into());
+                warnings.push(ConfigWarning::PrivateKeyOnCommandLine);
             }
             matches
                 .value_of("private-key")
-                .map(String::from)
+                .map(|key| SecretBuffer(key.as_bytes().to_vec()))
                 .with_context(|| "Missing private key")
         }?;
 
-        Ok(Self {
+        let mut config = Self {
             port_forwards,
             remote_port_forwards,
             private_key: Arc::new(
-                parse_private_key(&private_key).with_context(|| "Invalid private key")?,
+                parse_private_key(private_key.as_str()?.trim())
+                    .with_context(|| "Invalid private key")?,
             ),
             endpoint_public_key: Arc::new(
                 parse_public_key(matches.value_of("endpoint-public-key"))
                     .with_context(|| "Invalid endpoint public key")?,
             ),
-            endpoint_addr: parse_addr(matches.value_of("endpoint-addr"))
-                .with_context(|| "Invalid endpoint address")?,
+            endpoint_addr,
+            additional_endpoint_addrs,
+            listen_port: matches
+                .value_of("listen-port")
+                .map(str::parse)
+                .transpose()
+                .with_context(|| "Invalid listen port")?,
+            endpoint_reuse_port: matches.is_present("endpoint-reuse-port"),
             source_peer_ip,
+            additional_source_peer_ips,
             keepalive_seconds: parse_keep_alive(matches.value_of("keep-alive"))
                 .with_context(|| "Invalid keep-alive value")?,
             max_transmission_unit: parse_mtu(matches.value_of("max-transmission-unit"))
                 .with_context(|| "Invalid max-transmission-unit value")?,
             log: matches.value_of("log").unwrap_or_default().into(),
+            skip_logger_init: false,
             pcap_file: matches.value_of("pcap").map(String::from),
+            pcap_index_file: matches.value_of("pcap-index").map(String::from),
+            capture_buffer: matches
+                .value_of("capture-buffer")
+                .map(str::parse)
+                .transpose()
+                .with_context(|| "Invalid capture buffer size")?
+                .unwrap_or(DEFAULT_CAPTURE_BUFFER),
+            pcap_on_error: matches.value_of("pcap-on-error").map(PathBuf::from),
+            replay: matches.value_of("replay").map(PathBuf::from),
+            handshake_timeout: matches
+                .value_of("handshake-timeout")
+                .map(parse_duration)
+                .transpose()
+                .with_context(|| "Invalid handshake timeout")?
+                // Checks measure the destinations, not the handshake
+                .or(match &command {
+                    Some(Command::Check(options)) => Some(options.timeout),
+                    _ => None,
+                }),
+            connect_timeout: matches
+                .value_of("connect-timeout")
+                .map(parse_duration)
+                .transpose()
+                .with_context(|| "Invalid connect timeout")?,
+            require_handshake: matches.is_present("require-handshake"),
+            exit_on_tunnel_failure,
+            allow_roaming: matches.is_present("allow-roaming"),
+            ignore_system_proxy: matches.is_present("ignore-system-proxy"),
+            ignore_network_changes: matches.is_present("ignore-network-changes"),
+            handshake_rate_limit: matches
+                .value_of("handshake-rate-limit")
+                .map(str::parse)
+                .transpose()
+                .with_context(|| "Invalid handshake rate limit")?,
+            timer_policy: TimerPolicy {
+                rekey_after_messages: matches
+                    .value_of("rekey-after-messages")
+                    .map(str::parse)
+                    .transpose()
+                    .with_context(|| "Invalid number of messages to rekey after")?,
+                rekey_after_time: matches
+                    .value_of("rekey-after-time")
+                    .map(parse_duration)
+                    .transpose()
+                    .with_context(|| "Invalid time to rekey after")?,
+                reject_margin: matches
+                    .value_of("reject-margin")
+                    .map(parse_duration)
+                    .transpose()
+                    .with_context(|| "Invalid reject margin")?,
+                handshake_retry_interval: matches
+                    .value_of("handshake-retry-interval")
+                    .map(parse_duration)
+                    .transpose()
+                    .with_context(|| "Invalid handshake retry interval")?,
+            },
+            crypto_workers: matches
+                .value_of("crypto-workers")
+                .unwrap_or_default()
+                .parse()
+                .with_context(|| "Invalid number of crypto workers")?,
+            priority_lanes: matches
+                .value_of("priority-lanes")
+                .map(parse_priority_lanes)
+                .transpose()?,
+            data_path_threads: matches
+                .value_of("data-path-threads")
+                .unwrap_or_default()
+                .parse()
+                .with_context(|| "Invalid number of data path threads")?,
+            data_path_priority: matches
+                .value_of("data-path-priority")
+                .map(str::parse)
+                .transpose()
+                .with_context(|| "Invalid data path priority")?,
+            runtime_workers: matches
+                .value_of("workers")
+                .map(str::parse)
+                .transpose()
+                .with_context(|| "Invalid number of workers")?,
+            blocking_threads: matches
+                .value_of("blocking-threads")
+                .map(str::parse)
+                .transpose()
+                .with_context(|| "Invalid number of blocking threads")?,
+            cpu_affinity: matches
+                .value_of("cpu-affinity")
+                .map(parse_cpu_list)
+                .transpose()
+                .with_context(|| "Invalid CPU affinity")?
+                .unwrap_or_default(),
+            #[cfg(unix)]
+            tun_fd: None,
+            packet_flow: false,
+            obfuscator: parse_obfuscator(matches)?,
+            allowed_ips: matches
+                .values_of("allowed-ips")
+                .into_iter()
+                .flatten()
+                .map(AllowedIp::from_str)
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| "Invalid allowed IPs")?,
+            routes: matches
+                .values_of("route")
+                .into_iter()
+                .flatten()
+                .map(AllowedIp::from_str)
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| "Invalid routes")?,
+            packet_filter: None,
+            connection_authorizer: None,
+            protocol_engine: None,
+            clock: None,
+            network_simulation: matches
+                .value_of("simulate")
+                .map(NetworkSimulation::from_str)
+                .transpose()
+                .with_context(|| "Invalid network simulation")?,
+            mdns_reflector: matches.is_present("mdns-reflector"),
+            in_process_connections: false,
+            lock_dir: matches.value_of("lock-dir").map(PathBuf::from),
+            state_file: matches.value_of("state-file").map(PathBuf::from),
+            audit_log: matches.value_of("audit-log").map(PathBuf::from),
+            initiator: Initiator::Cli,
+            connection_log: matches
+                .value_of("connection-log")
+                .map(ClientPrivacy::from_str)
+                .transpose()
+                .with_context(|| "Invalid connection log")?,
+            control_token,
+            remote_control: matches.is_present("control-allow-remote"),
+            control_socket: matches
+                .value_of("control-socket")
+                .map(ControlAddress::from_str)
+                .transpose()
+                .with_context(|| "Invalid control socket")?,
+            grpc_listen: matches
+                .value_of("grpc-listen")
+                .map(SocketAddr::from_str)
+                .transpose()
+                .with_context(|| "Invalid gRPC listen address")?,
+            tunnel_dns: matches
+                .value_of("tunnel-dns")
+                .map(parse_dns_server)
+                .transpose()
+                .with_context(|| "Invalid tunnel DNS server")?,
+            nat64: matches
+                .value_of("nat64")
+                .map(Nat64Prefix::from_str)
+                .transpose()?,
+            bind_policy: matches
+                .value_of("bind-policy")
+                .map(BindPolicy::from_str)
+                .transpose()
+                .with_context(|| "Invalid bind policy")?
+                .unwrap_or_default(),
+            udp_port_exhaustion: matches
+                .value_of("udp-port-exhaustion")
+                .map(PortPoolExhaustion::from_str)
+                .transpose()
+                .with_context(|| "Invalid UDP port exhaustion policy")?
+                .unwrap_or_default(),
+            sequential_ports: matches.is_present("sequential-ports"),
             warnings,
-        })
+            validation_warnings: vec![],
+            command,
+        };
+        // Fail early on invalid arguments; the warnings are reported when the tunnel starts
+        config.validation_warnings = config.validate()?;
+        Ok(config)
     }
-}
 
-fn parse_addr(s: Option<&str>) -> anyhow::Result<SocketAddr> {
-    s.with_context(|| "Missing address")?
-        .to_socket_addrs()
-        .with_context(|| "Invalid address")?
-        .next()
-        .with_context(|| "Could not lookup address")
-}
+    /// The first cargo feature left out of the build that the configuration requires, if any.
+    fn missing_feature(&self) -> Option<&'static str> {
+        let uses = |protocol| {
+            self.port_forwards
+                .iter()
+                .chain(self.remote_port_forwards.iter())
+                .any(|pf| pf.protocol == protocol)
+        };
+        if !cfg!(feature = "pcap")
+            && (self.pcap_file.is_some() || self.pcap_on_error.is_some() || self.replay.is_some())
+        {
+            Some("pcap")
+        } else if !cfg!(feature = "tcp") && uses(PortProtocol::Tcp) {
+            Some("tcp")
+        } else if !cfg!(feature = "udp")
+            && (uses(PortProtocol::Udp) || self.mdns_reflector || self.tunnel_dns.is_some())
+        {
+            Some("udp")
+        } else if !cfg!(feature = "control") && self.control_socket.is_some() {
+            Some("control")
+        } else if !cfg!(feature = "grpc") && self.grpc_listen.is_some() {
+            Some("grpc")
+        } else {
+            None
+        }
+    }
 
-fn parse_ip(s: Option<&str>) -> anyhow::Result<IpAddr> {
-    s.with_context(|| "Missing IP")?
-        .parse::<IpAddr>()
-        .with_context(|| "Invalid IP address")
+    /// The suspicious settings found when the configuration was built. `validate` checks the configuration
+    /// again, with the settings made since.
+    pub fn warnings(&self) -> &[ConfigWarning] {
+        &self.validation_warnings
+    }
+
+    /// Validates the configuration. Returns the suspicious settings as warnings,
+    /// or the first invalid setting as an error.
+    pub fn validate(&self) -> Result<Vec<ConfigWarning>, ConfigError> {
+        let no_port_forwards = self.port_forwards.is_empty()
+            && self.remote_port_forwards.is_empty()
+            && !self.mdns_reflector
+            && !self.in_process_connections;
+        if self.uses_tun() && !no_port_forwards {
+            return Err(ConfigError::PortForwardsWithTun);
+        }
+        if !self.uses_tun() && no_port_forwards {
+            return Err(ConfigError::NoPortForwards);
+        }
+        let mut warnings = self.warnings.clone();
+        if self.network_simulation.is_some() {
+            warnings.push(ConfigWarning::NetworkSimulated);
+        }
+        if self.pcap_on_error.is_some() && self.capture_buffer == 0 {
+            return Err(ConfigError::PcapOnErrorWithoutCaptureBuffer);
+        }
+        if let Some(feature) = self.missing_feature() {
+            return Err(ConfigError::FeatureDisabled(feature));
+        }
+        if self.data_path_priority.is_some() && self.data_path_threads == 0 {
+            warnings.push(ConfigWarning::PriorityWithoutDataPathThreads);
+        }
+        if self.runtime_workers == Some(0) || self.blocking_threads == Some(0) {
+            return Err(ConfigError::NoRuntimeThreads);
+        }
+        if let Some(reason) = self.timer_policy.invalid() {
+            return Err(ConfigError::InvalidTimerPolicy(reason));
+        }
+        if self.remote_control && self.control_token.is_none() {
+            return Err(ConfigError::RemoteControlWithoutToken);
+        }
+        match &self.control_socket {
+            Some(ControlAddress::Tcp(addr)) if !addr.ip().is_loopback() && !self.remote_control => {
+                return Err(ConfigError::ControlSocketNotLoopback(*addr));
+            }
+            Some(ControlAddress::Unix(path)) if cfg!(not(unix)) => {
+                return Err(ConfigError::UnixSocketsUnsupported(path.clone()));
+            }
+            _ => {}
+        }
+        match self.grpc_listen {
+            Some(addr) if !addr.ip().is_loopback() && !self.remote_control => {
+                return Err(ConfigError::GrpcNotLoopback(addr));
+            }
+            _ => {}
+        }
+
+        // The datagrams to every address of the endpoint go through the same socket
+        if self
+            .additional_endpoint_addrs
+            .iter()
+            .any(|addr| addr.is_ipv4() != self.endpoint_addr.is_ipv4())
+        {
+            return Err(ConfigError::MixedEndpointFamilies);
+        }
+
+        // The routes go through a source peer IP of their IP version
+        let source_peer_ips = self.source_peer_ips();
+        if let Some(route) = self.routes.iter().find(|route| {
+            !source_peer_ips
+                .iter()
+                .any(|ip| ip.is_ipv4() == route.addr().is_ipv4())
+        }) {
+            return Err(ConfigError::RouteWithoutSourcePeerIp(*route));
+        }
+
+        let all_forwards: Vec<&PortForwardConfig> = self
+            .port_forwards
+            .iter()
+            .chain(self.remote_port_forwards.iter())
+            .collect();
+
+        let mut names = HashSet::new();
+        let mut sources = HashSet::new();
+        for pf in &all_forwards {
+            // A definition with multiple protocols gives its name to each of them
+            if let Some(name) = &pf.name {
+                if !names.insert((name.clone(), pf.protocol)) {
+                    return Err(ConfigError::DuplicateName(name.clone()));
+                }
+            }
+            // Port 0 listens on a port chosen by the system
+            if pf.source.port() != 0
+                && !sources.insert((pf.source, pf.interface.clone(), pf.protocol, pf.remote))
+            {
+                return Err(ConfigError::DuplicateListenAddress(pf.source, pf.protocol));
+            }
+        }
+
+        // The socket of the endpoint listens on all interfaces
+        if let Some(pf) = self.port_forwards.iter().find(|pf| {
+            pf.protocol == PortProtocol::Udp
+                && pf.pipe.is_none()
+                && self
+                    .listen_port
+                    .map_or(false, |port| port != 0 && port == pf.source.port())
+        }) {
+            return Err(ConfigError::EndpointPortConflict(pf.source));
+        }
+
+        for pf in &self.port_forwards {
+            if let Some(pipe) = &pf.pipe {
+                if cfg!(not(windows)) {
+                    return Err(ConfigError::NamedPipesUnsupported(pipe.clone()));
+                }
+            }
+            if pf.relay.is_some() && pf.source.ip() != IpAddr::V4(Ipv4Addr::UNSPECIFIED) {
+                warnings.push(ConfigWarning::RelayOnSpecificAddress(pf.clone()));
+            }
+        }
+
+        for (i, a) in self.port_forwards.iter().enumerate() {
+            for b in &self.port_forwards[i + 1..] {
+                // Listening on an unspecified address and a specific address on the same port may conflict
+                if a.protocol == b.protocol
+                    && a.source.port() != 0
+                    && a.source.port() == b.source.port()
+                    && a.source.is_ipv4() == b.source.is_ipv4()
+                    && (a.source.ip().is_unspecified() || b.source.ip().is_unspecified())
+                {
+                    warnings.push(ConfigWarning::OverlappingListenAddresses(
+                        a.clone(),
+                        b.clone(),
+                    ));
+                }
+            }
+        }
+
+        let source_peer_ips = self.source_peer_ips();
+        for pf in &all_forwards {
+            let source_peer_ip = pf.source_peer_ip.unwrap_or(self.source_peer_ip);
+            if !source_peer_ips.contains(&source_peer_ip) {
+                return Err(ConfigError::UnknownSourcePeerIp(source_peer_ip));
+            }
+            if let Some(hostname) = &pf.hostname {
+                if self.tunnel_dns.is_some() && !pf.remote {
+                    // The address is only known once resolved through the tunnel
+                    continue;
+                }
+                if pf.destination.ip().is_unspecified() {
+                    return Err(ConfigError::UnresolvedDestination(hostname.clone()));
+                }
+            }
+            // A port forward may reach a remote port forward of this peer, through the virtual interface
+            let hairpin = !pf.remote
+                && self.remote_port_forwards.iter().any(|remote| {
+                    remote.protocol == pf.protocol && remote.source == pf.destination
+                });
+            if source_peer_ips.contains(&pf.destination.ip()) {
+                if hairpin {
+                    continue;
+                }
+                warnings.push(ConfigWarning::DestinationIsSourcePeer((*pf).clone()));
+            } else if pf.destination.is_ipv4() && source_peer_ip.is_ipv6() && self.nat64.is_some() {
+                // Reached through the NAT64 gateway
+            } else if pf.destination.is_ipv4() != source_peer_ip.is_ipv4() {
+                warnings.push(ConfigWarning::AddressFamilyMismatch((*pf).clone()));
+            }
+        }
+
+        if self.max_transmission_unit < MIN_MTU || self.max_transmission_unit > MAX_MTU {
+            return Err(ConfigError::InvalidMtu(self.max_transmission_unit));
+        }
+        let uses_ipv6 = source_peer_ips.iter().any(IpAddr::is_ipv6)
+            || all_forwards.iter().any(|pf| pf.destination.is_ipv6());
+        if uses_ipv6 && self.max_transmission_unit < IPV6_MIN_MTU {
+            warnings.push(ConfigWarning::MtuBelowIpv6Minimum(
+                self.max_transmission_unit,
+            ));
+        }
+        if self.max_transmission_unit > DEFAULT_MTU {
+            warnings.push(ConfigWarning::MtuMayFragment(self.max_transmission_unit));
+        }
+
+        Ok(warnings)
+    }
 }
 
-fn parse_private_key(s: &str) -> anyhow::Result<X25519SecretKey> {
-    s.parse::<X25519SecretKey>()
-        .map_err(|e| anyhow::anyhow!("{}", e))
+/// What to do when a port forward can't listen on its source address, such as when its port is busy.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BindPolicy {
+    /// Starting the tunnel fails with `OnetunError::BindFailed`.
+    Fail,
+    /// Tries again with an exponential backoff for up to the given time, then fails.
+    Retry(Duration),
+    /// Listens on a port chosen by the system instead, on the same IP. See `Handle::listeners`.
+    Ephemeral,
 }
 
-fn parse_public_key(s: Option<&str>) -> anyhow::Result<X25519PublicKey> {
-    s.with_context(|| "Missing public key")?
-        .parse::<X25519PublicKey>()
-        .map_err(|e| anyhow::anyhow!("{}", e))
-        .with_context(|| "Invalid public key")
+impl Default for BindPolicy {
+    fn default() -> Self {
+        Self::Fail
+    }
 }
 
-fn parse_keep_alive(s: Option<&str>) -> anyhow::Result<Option<u16>> {
-    if let Some(s) = s {
-        let parsed: u16 = s.parse().with_context(|| {
-            format!(
-                "Keep-alive must be a number between 0 and {} seconds",
-                u16::MAX
-            )
-        })?;
-        Ok(Some(parsed))
-    } else {
-        Ok(None)
+/// How long `BindPolicy::Retry` tries again, when not given.
+const DEFAULT_BIND_RETRY: Duration = Duration::from_secs(30);
+
+impl FromStr for BindPolicy {
+    type Err = anyhow::Error;
+
+    /// Parses `fail`, `retry`, `retry=<duration>` or `ephemeral`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.split_once('=') {
+            None if s == "fail" => Ok(Self::Fail),
+            None if s == "retry" => Ok(Self::Retry(DEFAULT_BIND_RETRY)),
+            None if s == "ephemeral" => Ok(Self::Ephemeral),
+            Some(("retry", duration)) => Ok(Self::Retry(parse_duration(duration)?)),
+            _ => Err(anyhow::anyhow!("Invalid bind policy: '{}'", s)),
+        }
     }
 }
 
-fn parse_mtu(s: Option<&str>) -> anyhow::Result<usize> {
-    s.with_context(|| "Missing MTU")?
-        .parse()
-        .with_context(|| "Invalid MTU")
+/// What to do when a client needs a virtual port, and all of them are assigned to active clients.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PortPoolExhaustion {
+    /// The new client is turned away.
+    Reject,
+    /// The virtual port of the least recently active session is taken from its client.
+    EvictOldest,
 }
 
-#[cfg(unix)]
+impl Default for PortPoolExhaustion {
+    fn default() -> Self {
+        Self::Reject
+    }
+}
+
+impl FromStr for PortPoolExhaustion {
+    type Err = anyhow::Error;
+
+    /// Parses `reject` or `evict-oldest`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "evict-oldest" => Ok(Self::EvictOldest),
+            _ => Err(anyhow::anyhow!("Invalid port exhaustion policy: '{}'", s)),
+        }
+    }
+}
+
+/// How many of the last IP packets are kept in memory by default.
+const DEFAULT_CAPTURE_BUFFER: usize = 1000;
+
+/// The default MTU of the tunnel: a 1500 bytes Ethernet MTU, minus the WireGuard, UDP and IPv6 overheads.
+const DEFAULT_MTU: usize = 1420;
+
+/// The smallest MTU accepted. IPv4 hosts must accept datagrams of this size.
+const MIN_MTU: usize = 576;
+
+/// The largest MTU accepted, so that IP packets fit in the packet buffers.
+const MAX_MTU: usize = 65535 - 80;
+
+/// The minimum MTU of IPv6 links.
+const IPV6_MIN_MTU: usize = 1280;
+
+/// A configuration setting that is usable, but likely a mistake or insecure.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ConfigWarning {
+    /// The private key file can be read by the users of its group.
+    PrivateKeyGroupReadable,
+    /// The private key file can be read by any user.
+    PrivateKeyWorldReadable,
+    /// The private key was passed as a command-line argument, which other users may see.
+    PrivateKeyOnCommandLine,
+    /// Two port forwards listen on the same port, one of them on an unspecified address.
+    OverlappingListenAddresses(PortForwardConfig, PortForwardConfig),
+    /// The port forward's destination is onetun's own peer IP, without a remote port forward listening there.
+    DestinationIsSourcePeer(PortForwardConfig),
+    /// The port forward's destination is not in the same IP family as onetun's peer IP.
+    AddressFamilyMismatch(PortForwardConfig),
+    /// The MTU is smaller than the minimum required by IPv6.
+    MtuBelowIpv6Minimum(usize),
+    /// The MTU is larger than the default, and encrypted packets may be fragmented.
+    MtuMayFragment(usize),
+    /// Poor network conditions are simulated.
+    NetworkSimulated,
+    /// The port forward relays broadcast or multicast datagrams, but doesn't listen on `0.0.0.0`.
+    RelayOnSpecificAddress(PortForwardConfig),
+    /// A data path priority is set, but the data path has no threads of its own.
+    PriorityWithoutDataPathThreads,
+    /// The control token file can be read by any user.
+    ControlTokenWorldReadable,
+}
+
+impl Display for ConfigWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PrivateKeyGroupReadable => {
+                write!(f, "Private key file is group-readable. This is insecure.")
+            }
+            Self::PrivateKeyWorldReadable => {
+                write!(f, "Private key file is world-readable. This is insecure.")
+            }
+            Self::PrivateKeyOnCommandLine => write!(
+                f,
+                "Private key was passed using CLI. This is insecure. \
+                Use \"--private-key-file <file containing private key>\", or the \"ONETUN_PRIVATE_KEY\" env variable instead."
+            ),
+            Self::OverlappingListenAddresses(a, b) => write!(
+                f,
+                "Port forwards {} and {} listen on overlapping addresses, and may fail to bind.",
+                a, b
+            ),
+            Self::DestinationIsSourcePeer(pf) => write!(
+                f,
+                "Port forward {} points to onetun's own peer IP, which is not reachable through the tunnel.",
+                pf
+            ),
+            Self::AddressFamilyMismatch(pf) => write!(
+                f,
+                "Port forward {} points to a different IP family than the source peer IP, and may not be routable.",
+                pf
+            ),
+            Self::MtuBelowIpv6Minimum(mtu) => write!(
+                f,
+                "MTU {} is below the IPv6 minimum of {}. IPv6 packets may be dropped.",
+                mtu, IPV6_MIN_MTU
+            ),
+            Self::MtuMayFragment(mtu) => write!(
+                f,
+                "MTU {} is above {}. Encrypted packets may be fragmented or dropped on common networks.",
+                mtu, DEFAULT_MTU
+            ),
+            Self::NetworkSimulated => write!(
+                f,
+                "Datagrams sent to the WireGuard endpoint are delayed and dropped to simulate a poor link. \
+                Don't use --simulate in production."
+            ),
+            Self::RelayOnSpecificAddress(pf) => write!(
+                f,
+                "Port forward {} relays broadcast or multicast datagrams, but only listens on {}. \
+                Listen on 0.0.0.0 to receive them from the local network.",
+                pf,
+                pf.source.ip()
+            ),
+            Self::PriorityWithoutDataPathThreads => write!(
+                f,
+                "The data path priority is ignored, since the data path shares the runtime that started the tunnel. \
+                Set --data-path-threads to give it threads of its own."
+            ),
+            Self::ControlTokenWorldReadable => {
+                write!(f, "Control token file is world-readable. This is insecure.")
+            }
+        }
+    }
+}
+
+/// Builds a `Config` from named settings. The keys, the endpoint address and the source peer IP are
+/// required; the other settings have defaults.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    port_forwards: Vec<PortForwardConfig>,
+    remote_port_forwards: Vec<PortForwardConfig>,
+    private_key: Option<String>,
+    endpoint_public_key: Option<String>,
+    endpoint_addr: Option<SocketAddr>,
+    source_peer_ip: Option<IpAddr>,
+    keepalive_seconds: Option<u16>,
+    max_transmission_unit: Option<usize>,
+    log_level: Option<String>,
+    skip_logger_init: bool,
+    pcap_file: Option<String>,
+}
+
+impl ConfigBuilder {
+    /// Sets the base64-encoded private key of this peer.
+    pub fn private_key(mut self, private_key: impl Into<String>) -> Self {
+        self.private_key = Some(private_key.into());
+        self
+    }
+
+    /// Sets the base64-encoded public key of the WireGuard endpoint.
+    pub fn endpoint_public_key(mut self, public_key: impl Into<String>) -> Self {
+        self.endpoint_public_key = Some(public_key.into());
+        self
+    }
+
+    /// Sets the address of the WireGuard endpoint.
+    pub fn endpoint_addr(mut self, endpoint_addr: SocketAddr) -> Self {
+        self.endpoint_addr = Some(endpoint_addr);
+        self
+    }
+
+    /// Sets the IP assigned to this peer in the WireGuard network.
+    pub fn source_peer_ip(mut self, source_peer_ip: IpAddr) -> Self {
+        self.source_peer_ip = Some(source_peer_ip);
+        self
+    }
+
+    /// Adds a local port forward.
+    pub fn port_forward(mut self, port_forward: PortForwardConfig) -> Self {
+        self.port_forwards.push(port_forward);
+        self
+    }
+
+    /// Adds local port forwards.
+    pub fn port_forwards(
+        mut self,
+        port_forwards: impl IntoIterator<Item = PortForwardConfig>,
+    ) -> Self {
+        self.port_forwards.extend(port_forwards);
+        self
+    }
+
+    /// Adds a remote port forward, listening on the source peer IP for the given source port.
+    pub fn remote_port_forward(mut self, mut port_forward: PortForwardConfig) -> Self {
+        port_forward.remote = true;
+        self.remote_port_forwards.push(port_forward);
+        self
+    }
+
+    /// Sends a keep-alive packet to the endpoint at the given interval, in seconds.
+    pub fn keepalive(mut self, seconds: u16) -> Self {
+        self.keepalive_seconds = Some(seconds);
+        self
+    }
+
+    /// Sets the MTU of the tunnel (1420 by default).
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.max_transmission_unit = Some(mtu);
+        self
+    }
+
+    /// Sets the log filter, such as `info` (the default) or `onetun=debug`.
+    pub fn log_level(mut self, level: impl Into<String>) -> Self {
+        self.log_level = Some(level.into());
+        self
+    }
+
+    /// Leaves the logging to the application: onetun logs through the `log` facade to the logger the
+    /// application installs, and doesn't install its own, which would otherwise print to stderr.
+    pub fn skip_logger_init(mut self) -> Self {
+        self.skip_logger_init = true;
+        self
+    }
+
+    /// Captures the decrypted IP packets in the given pcap file.
+    pub fn pcap_file(mut self, pcap_file: impl Into<String>) -> Self {
+        self.pcap_file = Some(pcap_file.into());
+        self
+    }
+
+    /// Checks the required settings and the keys, validates the configuration and builds it, keeping the
+    /// warnings (see `Config::warnings`). The errors that the settings of `Config` can still resolve, such as
+    /// a port forward from an additional source peer IP, are left to the validation when the tunnel starts.
+    pub fn build(self) -> Result<Config, OnetunError> {
+        let required = |name: &str| OnetunError::Config(anyhow::anyhow!("Missing {}", name));
+        let private_key = self.private_key.ok_or_else(|| required("private key"))?;
+        let endpoint_public_key = self
+            .endpoint_public_key
+            .ok_or_else(|| required("endpoint public key"))?;
+        let endpoint_addr = self
+            .endpoint_addr
+            .ok_or_else(|| required("endpoint address"))?;
+        let source_peer_ip = self
+            .source_peer_ip
+            .ok_or_else(|| required("source peer IP"))?;
+
+        let mut config = Config {
+            port_forwards: self.port_forwards,
+            remote_port_forwards: self.remote_port_forwards,
+            private_key: Arc::new(
+                parse_private_key(&private_key)
+                    .with_context(|| "Invalid private key")
+                    .map_err(OnetunError::Config)?,
+            ),
+            endpoint_public_key: Arc::new(
+                parse_public_key(Some(&endpoint_public_key))
+                    .with_context(|| "Invalid public key")
+                    .map_err(OnetunError::Config)?,
+            ),
+            endpoint_addr,
+            additional_endpoint_addrs: vec![],
+            listen_port: None,
+            endpoint_reuse_port: false,
+            source_peer_ip,
+            additional_source_peer_ips: vec![],
+            keepalive_seconds: self.keepalive_seconds,
+            max_transmission_unit: self.max_transmission_unit.unwrap_or(DEFAULT_MTU),
+            log: self.log_level.unwrap_or_else(|| "info".to_string()),
+            skip_logger_init: self.skip_logger_init,
+            pcap_file: self.pcap_file,
+            pcap_index_file: None,
+            capture_buffer: DEFAULT_CAPTURE_BUFFER,
+            pcap_on_error: None,
+            replay: None,
+            warnings: vec![],
+            validation_warnings: vec![],
+            command: None,
+            exit_on_tunnel_failure: None,
+            handshake_timeout: None,
+            connect_timeout: None,
+            require_handshake: false,
+            allow_roaming: false,
+            ignore_system_proxy: false,
+            ignore_network_changes: false,
+            handshake_rate_limit: None,
+            timer_policy: TimerPolicy::default(),
+            crypto_workers: 1,
+            priority_lanes: None,
+            data_path_threads: 0,
+            data_path_priority: None,
+            runtime_workers: None,
+            blocking_threads: None,
+            cpu_affinity: vec![],
+            #[cfg(unix)]
+            tun_fd: None,
+            packet_flow: false,
+            obfuscator: None,
+            allowed_ips: AllowedIp::any(),
+            routes: vec![],
+            packet_filter: None,
+            connection_authorizer: None,
+            protocol_engine: None,
+            clock: None,
+            network_simulation: None,
+            mdns_reflector: false,
+            in_process_connections: false,
+            lock_dir: None,
+            state_file: None,
+            audit_log: None,
+            initiator: Initiator::Library,
+            connection_log: None,
+            control_token: None,
+            remote_control: false,
+            control_socket: None,
+            grpc_listen: None,
+            tunnel_dns: None,
+            nat64: None,
+            bind_policy: BindPolicy::Fail,
+            udp_port_exhaustion: PortPoolExhaustion::Reject,
+            sequential_ports: false,
+        };
+        match config.validate() {
+            Ok(warnings) => config.validation_warnings = warnings,
+            Err(e) if e.is_resolvable() => {}
+            Err(e) => return Err(OnetunError::Config(e.into())),
+        }
+        Ok(config)
+    }
+}
+
+/// A configuration setting that prevents onetun from starting.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ConfigError {
+    /// No local or remote port forward was given.
+    NoPortForwards,
+    /// Port forwards were given along with a tun device, which receives all the traffic instead.
+    PortForwardsWithTun,
+    /// Two port forwards listen on the same address and protocol.
+    DuplicateListenAddress(SocketAddr, PortProtocol),
+    /// Two port forward definitions share a name.
+    DuplicateName(Arc<str>),
+    /// The MTU is outside of the supported range.
+    InvalidMtu(usize),
+    /// A port forward connects from an IP that isn't assigned to this peer.
+    UnknownSourcePeerIp(IpAddr),
+    /// The host name of a destination couldn't be resolved by the system, and there is no tunnel DNS server.
+    UnresolvedDestination(Arc<str>),
+    /// A port forward listens on a named pipe, which only exists on Windows.
+    NamedPipesUnsupported(Arc<str>),
+    /// The addresses of the endpoint aren't all IPv4 or all IPv6.
+    MixedEndpointFamilies,
+    /// Support bundles are written on errors, but no recent traffic is kept to capture.
+    PcapOnErrorWithoutCaptureBuffer,
+    /// The runtime was given no worker or blocking threads.
+    NoRuntimeThreads,
+    /// A route's IP version has no source peer IP to send its packets from.
+    RouteWithoutSourcePeerIp(AllowedIp),
+    /// A setting requires a cargo feature that onetun was built without.
+    FeatureDisabled(&'static str),
+    /// The control interfaces may listen on non-loopback addresses, without a control token.
+    RemoteControlWithoutToken,
+    /// The control socket listens on a non-loopback address, but remote control isn't allowed.
+    ControlSocketNotLoopback(SocketAddr),
+    /// The control socket is a Unix socket, which only exists on Unix.
+    UnixSocketsUnsupported(PathBuf),
+    /// The gRPC control service listens on a non-loopback address, but remote control isn't allowed.
+    GrpcNotLoopback(SocketAddr),
+    /// A UDP port forward listens on the local port of the socket exchanging datagrams with the endpoint.
+    EndpointPortConflict(SocketAddr),
+    /// A knob of the timer policy is out of its range, with the reason.
+    InvalidTimerPolicy(&'static str),
+}
+
+impl ConfigError {
+    /// Whether a setting made after `ConfigBuilder::build` may resolve the error: the in-process connections
+    /// or a tun device without port forwards, the additional source peer IPs, or the tunnel DNS server.
+    fn is_resolvable(&self) -> bool {
+        matches!(
+            self,
+            Self::NoPortForwards | Self::UnknownSourcePeerIp(_) | Self::UnresolvedDestination(_)
+        )
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoPortForwards => write!(f, "No port forward configurations given."),
+            Self::PortForwardsWithTun => {
+                write!(f, "Port forwards can't be used along with a tun device.")
+            }
+            Self::DuplicateListenAddress(addr, protocol) => {
+                write!(f, "Multiple {} port forwards listen on {}.", protocol, addr)
+            }
+            Self::DuplicateName(name) => {
+                write!(f, "Port forward name '{}' is used more than once.", name)
+            }
+            Self::InvalidMtu(mtu) => write!(
+                f,
+                "MTU {} is not supported. It must be between {} and {}.",
+                mtu, MIN_MTU, MAX_MTU
+            ),
+            Self::UnknownSourcePeerIp(ip) => write!(
+                f,
+                "Peer IP {} of a port forward is not one of the source peer IPs.",
+                ip
+            ),
+            Self::UnresolvedDestination(hostname) => write!(
+                f,
+                "Destination {} could not be resolved. Give a DNS server reachable through the tunnel with --tunnel-dns.",
+                hostname
+            ),
+            Self::NamedPipesUnsupported(pipe) => write!(
+                f,
+                "Named pipe {} can't be used: named pipes are only supported on Windows.",
+                pipe
+            ),
+            Self::MixedEndpointFamilies => write!(
+                f,
+                "The endpoint addresses must all be IPv4, or all IPv6."
+            ),
+            Self::PcapOnErrorWithoutCaptureBuffer => write!(
+                f,
+                "--pcap-on-error requires a capture buffer. Set --capture-buffer to a number of packets above 0."
+            ),
+            Self::NoRuntimeThreads => write!(
+                f,
+                "The runtime needs at least one thread. Set --workers and --blocking-threads above 0."
+            ),
+            Self::RouteWithoutSourcePeerIp(route) => write!(
+                f,
+                "Route {} can't be used: no source peer IP has its IP version.",
+                route
+            ),
+            Self::FeatureDisabled(feature) => write!(
+                f,
+                "This configuration requires the '{}' feature, which onetun was built without.",
+                feature
+            ),
+            Self::RemoteControlWithoutToken => write!(
+                f,
+                "Remote control requires a control token (--control-token-file or ONETUN_CONTROL_TOKEN)."
+            ),
+            Self::ControlSocketNotLoopback(addr) => write!(
+                f,
+                "Control socket {} is not on a loopback address, which requires --control-allow-remote.",
+                addr
+            ),
+            Self::UnixSocketsUnsupported(path) => write!(
+                f,
+                "Control socket {} can't be used: Unix sockets are not supported on this platform. Give an ip:port address.",
+                path.display()
+            ),
+            Self::GrpcNotLoopback(addr) => write!(
+                f,
+                "gRPC control service {} is not on a loopback address, which requires --control-allow-remote.",
+                addr
+            ),
+            Self::EndpointPortConflict(addr) => write!(
+                f,
+                "UDP port forward on {} conflicts with the local port of the WireGuard endpoint (--listen-port).",
+                addr
+            ),
+            Self::InvalidTimerPolicy(reason) => write!(f, "Invalid timer policy: {}.", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The obfuscation of the datagrams exchanged with the WireGuard endpoint, if any.
+#[cfg(feature = "bin")]
+fn parse_obfuscator(matches: &clap::ArgMatches) -> anyhow::Result<Option<Arc<dyn Obfuscator>>> {
+    if let Some(key) = matches.value_of("obfuscation-key") {
+        let padding = matches
+            .value_of("obfuscation-padding")
+            .unwrap_or("0")
+            .parse()
+            .with_context(|| "Invalid obfuscation padding")?;
+        return Ok(Some(Arc::new(XorObfuscator::new(key, padding)?)));
+    }
+    if let Some(params) = matches.value_of("amneziawg") {
+        let obfuscator: AmneziaObfuscator = params
+            .parse()
+            .with_context(|| "Invalid AmneziaWG parameters")?;
+        return Ok(Some(Arc::new(obfuscator)));
+    }
+    Ok(None)
+}
+
+/// The `echo-server` sub-command, which runs TCP and UDP echo services without a tunnel.
+#[cfg(feature = "bin")]
+fn echo_server_command<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("echo-server")
+        .about("Runs TCP and UDP echo services on the given address, without a tunnel, to validate a pair of onetun \
+        tunnels end to end: the other peer reaches it through a remote port forward, and measures it with `onetun bench`. \
+        The log level is read from ONETUN_LOG.")
+        .arg(
+            Arg::with_name("ADDR")
+                .required(true)
+                .index(1)
+                .help("The address to listen on with TCP and UDP. Example: 0.0.0.0:7"),
+        )
+}
+
+/// The arguments configuring the tunnel and its port forwards, shared by the sub-commands running one.
+#[cfg(feature = "bin")]
+fn tunnel_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("PORT_FORWARD")
+            .required(false)
+            .multiple(true)
+            .takes_value(true)
+            .help("Port forward configurations. The format of each argument is [src_host:]<src_port>:<dst_host>:<dst_port>[:TCP,UDP,...], \
+            where [src_host] is the local IP to listen on, <src_port> is the local port to listen on, <dst_host> is the remote peer IP to forward to, and <dst_port> is the remote port to forward to. \
+            Environment variables of the form 'ONETUN_PORT_FORWARD_[#]' are also accepted, where [#] starts at 1.\n\
+            Examples:\n\
+            \t127.0.0.1:8080:192.168.4.1:8081:TCP,UDP\n\
+            \t127.0.0.1:8080:192.168.4.1:8081:TCP\n\
+            \t0.0.0.0:8080:192.168.4.1:8081\n\
+            \t[::1]:8080:192.168.4.1:8081\n\
+            \t8080:192.168.4.1:8081\n\
+            \t8080:192.168.4.1:8081:TCP\n\
+            \tlocalhost:8080:192.168.4.1:8081:TCP\n\
+            \tlocalhost:8080:peer.intranet:8081:TCP\n\
+            Options can follow the definition after a ';', separated by commas. 'name=<name>' gives the port forward a stable \
+            identifier, used in logs and statistics:\n\
+            \t'8080:192.168.4.1:8081;name=web'\
+            "),
+        Arg::with_name("forward")
+            .required(false)
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .long("forward")
+            .short("f")
+            .help("Port forward configuration, in the same format as PORT_FORWARD. Can be repeated."),
+        Arg::with_name("config")
+            .required(false)
+            .takes_value(true)
+            .long("config")
+            .help("Reads options from this file, or from stdin with '-'. Each line holds an option without its leading '--', \
+            followed by '=' and its value if it takes one, such as 'endpoint-addr = 1.2.3.4:51820'. Empty lines and lines \
+            starting with '#' are ignored. The options must not be given again on the command line, except repeatable ones.\n\
+            A 'private-key' line is read directly, without being added to the arguments."),
+        Arg::with_name("private-key")
+            .required_unless_one(&["private-key-file", "private-key-fd", "config"])
+            .takes_value(true)
+            .long("private-key")
+            .env("ONETUN_PRIVATE_KEY")
+            .help("The private key of this peer. The corresponding public key should be registered in the WireGuard endpoint. \
+            You can also use '--private-key-file' to specify a file containing the key instead."),
+        Arg::with_name("private-key-file")
+            .takes_value(true)
+            .long("private-key-file")
+            .env("ONETUN_PRIVATE_KEY_FILE")
+            .help("The path to a file containing the private key of this peer. The corresponding public key should be registered in the WireGuard endpoint."),
+        Arg::with_name("private-key-fd")
+            .takes_value(true)
+            .long("private-key-fd")
+            .env("ONETUN_PRIVATE_KEY_FD")
+            .conflicts_with("private-key-file")
+            .help("Reads the private key of this peer from this file descriptor, such as a pipe opened by a supervisor, and closes it. \
+            Only supported on Unix."),
+        Arg::with_name("endpoint-public-key")
+            .required(true)
+            .takes_value(true)
+            .long("endpoint-public-key")
+            .env("ONETUN_ENDPOINT_PUBLIC_KEY")
+            .help("The public key of the WireGuard endpoint (remote)."),
+        Arg::with_name("endpoint-addr")
+            .required(true)
+            .takes_value(true)
+            .long("endpoint-addr")
+            .env("ONETUN_ENDPOINT_ADDR")
+            .multiple(true)
+            .use_delimiter(true)
+            .number_of_values(1)
+            .help("The address (IP + port) of the WireGuard endpoint (remote). Example: 1.2.3.4:51820\n\
+            When the endpoint is reachable at several addresses, give each of them (comma-separated, or repeating the option): \
+            the handshake round-trip time to each one is measured at startup and every 5 minutes, and the fastest one is used."),
+        Arg::with_name("listen-port")
+            .required(false)
+            .takes_value(true)
+            .long("listen-port")
+            .visible_alias("endpoint-local-port")
+            .env("ONETUN_LISTEN_PORT")
+            .help("The local UDP port to exchange datagrams with the WireGuard endpoint from, such as for a firewall rule or a NAT \
+            pinhole. A random port is used by default."),
+        Arg::with_name("endpoint-reuse-port")
+            .required(false)
+            .long("endpoint-reuse-port")
+            .requires("listen-port")
+            .help("Shares the --listen-port with SO_REUSEADDR and SO_REUSEPORT (Unix), so that it can be bound again while a \
+            previous socket lingers, such as after a restart or a network change."),
+        Arg::with_name("source-peer-ip")
+            .required(true)
+            .takes_value(true)
+            .long("source-peer-ip")
+            .env("ONETUN_SOURCE_PEER_IP")
+            .multiple(true)
+            .use_delimiter(true)
+            .number_of_values(1)
+            .help("The source IP to identify this peer as (local). Example: 192.168.4.3\n\
+            When the peer is assigned several IPs, give each of them (comma-separated, or repeating the option): the first one \
+            is used by default, and port forwards can use another one with the 'peer-ip=<ip>' option."),
+        Arg::with_name("allowed-ips")
+            .required(false)
+            .takes_value(true)
+            .long("allowed-ips")
+            .env("ONETUN_ALLOWED_IPS")
+            .multiple(true)
+            .use_delimiter(true)
+            .number_of_values(1)
+            .default_value("0.0.0.0/0,::/0")
+            .help("The IP ranges the WireGuard endpoint may send packets from, like WireGuard's AllowedIPs (comma-separated). \
+            Decrypted packets from other source IPs are dropped. Example: 192.168.4.0/24,fd00::/64"),
+        Arg::with_name("route")
+            .required(false)
+            .takes_value(true)
+            .long("route")
+            .env("ONETUN_ROUTES")
+            .multiple(true)
+            .use_delimiter(true)
+            .number_of_values(1)
+            .help("IP ranges reached through the peer acting as a router (comma-separated, or repeating the option), so that \
+            hosts in them are reachable besides the destinations of the port forwards. Use 0.0.0.0/0,::/0 for a default route. \
+            Example: 10.20.0.0/16"),
+        Arg::with_name("simulate")
+            .required(false)
+            .takes_value(true)
+            .long("simulate")
+            .env("ONETUN_SIMULATE")
+            .help("Developer mode: simulates a poor link by delaying and dropping the datagrams sent to the WireGuard endpoint. \
+            Example: latency=100ms,jitter=20ms,loss=1%"),
+        Arg::with_name("keep-alive")
+            .required(false)
+            .takes_value(true)
+            .long("keep-alive")
+            .env("ONETUN_KEEP_ALIVE")
+            .help("Configures a persistent keep-alive for the WireGuard tunnel, in seconds."),
+        Arg::with_name("handshake-timeout")
+            .required(false)
+            .takes_value(true)
+            .long("handshake-timeout")
+            .env("ONETUN_HANDSHAKE_TIMEOUT")
+            .help("Waits for a handshake with the WireGuard endpoint before starting the port forwards, and exits if it \
+            doesn't complete within this delay. Accepts a number of seconds, or a duration like '10s', '500ms' or '1m'."),
+        Arg::with_name("connect-timeout")
+            .required(false)
+            .takes_value(true)
+            .long("connect-timeout")
+            .env("ONETUN_CONNECT_TIMEOUT")
+            .help("Aborts the TCP connections whose destination doesn't answer through the tunnel within this delay, \
+            resetting the local connection. Accepts a number of seconds, or a duration like '10s' or '500ms'. By default, \
+            the local client waits for its own timeout."),
+        Arg::with_name("allow-roaming")
+            .required(false)
+            .long("allow-roaming")
+            .help("Follows the WireGuard endpoint when it sends authenticated packets from a new address (e.g. after a NAT rebinding), \
+            like WireGuard does. By default, packets are always sent to --endpoint-addr."),
+        Arg::with_name("ignore-system-proxy")
+            .required(false)
+            .long("ignore-system-proxy")
+            .help("Sends the datagrams to the WireGuard endpoint directly, even when a SOCKS proxy is set in the system \
+            settings (macOS, Windows). By default, they are relayed through it, as the network may not allow UDP otherwise."),
+        Arg::with_name("ignore-network-changes")
+            .required(false)
+            .long("ignore-network-changes")
+            .help("Stops watching the route to the WireGuard endpoint. By default, when the network changes (e.g. from Wi-Fi to \
+            cellular), the UDP socket is rebound and a handshake initiated right away, instead of waiting minutes for the \
+            stale session to expire."),
+        Arg::with_name("exit-on-tunnel-failure")
+            .required(false)
+            .long("exit-on-tunnel-failure")
+            .env("ONETUN_EXIT_ON_TUNNEL_FAILURE")
+            .help("Exits with code 9 when the tunnel dropped and failed to reconnect --reconnect-attempts times in a row, or with \
+            code 2 if no handshake ever completed, so that a supervisor restarts onetun instead of it running in a broken state."),
+        Arg::with_name("reconnect-attempts")
+            .required(false)
+            .takes_value(true)
+            .long("reconnect-attempts")
+            .env("ONETUN_RECONNECT_ATTEMPTS")
+            .requires("exit-on-tunnel-failure")
+            .help("How many reconnection attempts in a row may fail with --exit-on-tunnel-failure, each one a round of \
+            handshakes for 90 seconds. Defaults to 3."),
+        Arg::with_name("require-handshake")
+            .required(false)
+            .long("require-handshake")
+            .help("Turns the clients of the port forwards away (closing their connections, dropping their datagrams) until \
+            the first handshake with the WireGuard endpoint completed, so that their traffic isn't silently lost while it is unreachable."),
+        Arg::with_name("handshake-rate-limit")
+            .required(false)
+            .takes_value(true)
+            .long("handshake-rate-limit")
+            .env("ONETUN_HANDSHAKE_RATE_LIMIT")
+            .help("How many handshake messages per second are accepted from the WireGuard endpoint before it must answer a cookie \
+            reply, as WireGuard does under load. 0 requires a cookie for every handshake. Defaults to 10."),
+        Arg::with_name("rekey-after-messages")
+            .required(false)
+            .takes_value(true)
+            .long("rekey-after-messages")
+            .env("ONETUN_REKEY_AFTER_MESSAGES")
+            .help("Advanced: starts a new handshake once this many data messages were sent in the WireGuard session, for \
+            extremely bursty traffic. By default, WireGuard's limit of 2^60 applies."),
+        Arg::with_name("rekey-after-time")
+            .required(false)
+            .takes_value(true)
+            .long("rekey-after-time")
+            .env("ONETUN_REKEY_AFTER_TIME")
+            .help("Advanced: starts a new handshake once the WireGuard session is this old, below 180 seconds, even when \
+            idle or when the endpoint initiated it. By default, onetun rekeys after 120 seconds when sending data."),
+        Arg::with_name("reject-margin")
+            .required(false)
+            .takes_value(true)
+            .long("reject-margin")
+            .env("ONETUN_REJECT_MARGIN")
+            .help("Advanced: starts a new handshake at the latest this long before the WireGuard session is rejected, \
+            180 seconds after its handshake, so that long-lived sessions never stall on an expired session."),
+        Arg::with_name("handshake-retry-interval")
+            .required(false)
+            .takes_value(true)
+            .long("handshake-retry-interval")
+            .env("ONETUN_HANDSHAKE_RETRY_INTERVAL")
+            .help("Advanced: resends unanswered handshake initiations at this interval, below WireGuard's 5 seconds, \
+            such as '1s' or '500ms', for lossy paths."),
+        Arg::with_name("mdns-reflector")
+            .required(false)
+            .long("mdns-reflector")
+            .help("Reflects mDNS (port 5353) between the local network and the peer network, so that services like AirPlay \
+            or printers advertised on either side can be discovered on the other."),
+        Arg::with_name("state-file")
+            .required(false)
+            .takes_value(true)
+            .long("state-file")
+            .env("ONETUN_STATE_FILE")
+            .help("Saves the virtual ports of the UDP clients to this file, periodically and on exit, and restores them on start, \
+            so that the destinations keep seeing the same source ports across a restart."),
+        Arg::with_name("control-token-file")
+            .required(false)
+            .takes_value(true)
+            .long("control-token-file")
+            .env("ONETUN_CONTROL_TOKEN_FILE")
+            .help("Requires the token in the first line of this file in each request to the control interfaces, so that other \
+            users of the machine can't control the tunnel. The token can also be passed with the \"ONETUN_CONTROL_TOKEN\" env variable. \
+            It must be at least 16 characters long."),
+        Arg::with_name("control-allow-remote")
+            .required(false)
+            .long("control-allow-remote")
+            .env("ONETUN_CONTROL_ALLOW_REMOTE")
+            .help("Lets the control interfaces listen on other addresses than loopback ones, which requires a control token."),
+        Arg::with_name("control-socket")
+            .required(false)
+            .takes_value(true)
+            .long("control-socket")
+            .env("ONETUN_CONTROL_SOCKET")
+            .help("Serves the JSON-RPC control protocol on this Unix socket path, or on this ip:port address (on loopback unless \
+            --control-allow-remote is given). Only ip:port addresses are supported on Windows. Example: /run/onetun.sock"),
+        Arg::with_name("grpc-listen")
+            .required(false)
+            .takes_value(true)
+            .long("grpc-listen")
+            .env("ONETUN_GRPC_LISTEN")
+            .help("Serves the gRPC control service on this ip:port address (on loopback unless --control-allow-remote is given), \
+            to start and stop tunnels, manage their port forwards and watch their statistics and health. Requires the grpc \
+            feature. Example: 127.0.0.1:50051"),
+        Arg::with_name("audit-log")
+            .required(false)
+            .takes_value(true)
+            .long("audit-log")
+            .env("ONETUN_AUDIT_LOG")
+            .help("Appends a record of the configuration (without the keys), and of the requests to stop the tunnel, to this file \
+            as JSON lines, with their time and origin. Example: /var/log/onetun/audit.jsonl"),
+        Arg::with_name("connection-log")
+            .required(false)
+            .takes_value(true)
+            .long("connection-log")
+            .env("ONETUN_CONNECTION_LOG")
+            .help("Logs each TCP connection when it closes, with its client, port forward, bytes and duration, under the \
+            onetun::connections log target. The value decides how the client IPs are written: full, truncate (to their /24 or /48 \
+            network), or hash (with a key that changes on each start)."),
+        Arg::with_name("lock-dir")
+            .required(false)
+            .takes_value(true)
+            .long("lock-dir")
+            .env("ONETUN_LOCK_DIR")
+            .help("Registers this instance in the given directory, shared by the onetun instances of the host. \
+            Fails fast if another registered instance uses the same listen addresses or source peer IPs. Example: /run/onetun"),
+        Arg::with_name("bind-policy")
+            .required(false)
+            .takes_value(true)
+            .long("bind-policy")
+            .env("ONETUN_BIND_POLICY")
+            .help("What to do when a port forward can't listen on its source address, such as when its port is busy: \
+            'fail' to exit (default), 'retry' to try again for up to 30 seconds ('retry=2m' for another delay), or \
+            'ephemeral' to listen on a port chosen by the system instead."),
+        Arg::with_name("udp-port-exhaustion")
+            .required(false)
+            .takes_value(true)
+            .long("udp-port-exhaustion")
+            .env("ONETUN_UDP_PORT_EXHAUSTION")
+            .help("What to do when a new UDP client needs a virtual port, and all of them are assigned to active clients: \
+            'reject' to drop its datagrams (default), or 'evict-oldest' to take the port of the least recently active session."),
+        Arg::with_name("sequential-ports")
+            .required(false)
+            .long("sequential-ports")
+            .help("Assigns the virtual ports of the clients (their source ports on the peer network) in order, instead of \
+            at random. Ports released recently are not reused right away either way."),
+        Arg::with_name("tunnel-dns")
+            .required(false)
+            .takes_value(true)
+            .long("tunnel-dns")
+            .env("ONETUN_TUNNEL_DNS")
+            .help("Resolves the destination host names with this DNS server, reachable through the tunnel, instead of \
+            the system's resolver. They are resolved again when their record expires. Example: 192.168.4.1 or 192.168.4.1:53"),
+        Arg::with_name("nat64")
+            .required(false)
+            .takes_value(true)
+            .long("nat64")
+            .env("ONETUN_NAT64")
+            .help("The /96 prefix of the NAT64 gateway of the peer network, such as 64:ff9b::/96. Port forwards connecting \
+            from an IPv6 peer IP reach their IPv4 destinations through it, and host names resolved through the tunnel \
+            without an IPv6 address are reached at their IPv4 address through it (DNS64)."),
+        Arg::with_name("crypto-workers")
+            .required(false)
+            .takes_value(true)
+            .long("crypto-workers")
+            .env("ONETUN_CRYPTO_WORKERS")
+            .default_value("1")
+            .help("How many packets can be encrypted or decrypted concurrently. Values above 1 spread the WireGuard crypto \
+            over multiple cores, while preserving packet order. Useful for high-throughput forwards."),
+        Arg::with_name("priority-lanes")
+            .required(false)
+            .takes_value(true)
+            .long("priority-lanes")
+            .env("ONETUN_PRIORITY_LANES")
+            .help("Sends the IP packets of at most this many bytes, such as SSH keystrokes or the acknowledgements of a download, \
+            ahead of larger ones, so that interactive traffic doesn't wait behind the bulk data of another port forward. Example: 256"),
+        Arg::with_name("data-path-threads")
+            .required(false)
+            .takes_value(true)
+            .long("data-path-threads")
+            .env("ONETUN_DATA_PATH_THREADS")
+            .default_value("0")
+            .help("Runs the WireGuard tasks and the virtual interfaces on this many dedicated threads, so that other work doesn't \
+            delay the packets. 0 runs them with the rest of onetun."),
+        Arg::with_name("data-path-priority")
+            .required(false)
+            .takes_value(true)
+            .long("data-path-priority")
+            .env("ONETUN_DATA_PATH_PRIORITY")
+            .allow_hyphen_values(true)
+            .help("The nice value of the dedicated data path threads, from -20 (highest priority) to 19. Negative values require \
+            CAP_SYS_NICE. Only supported on Linux."),
+        Arg::with_name("workers")
+            .required(false)
+            .takes_value(true)
+            .long("workers")
+            .env("ONETUN_WORKERS")
+            .help("The worker threads of the runtime, one per core by default. Lower it on small machines to save memory and CPU."),
+        Arg::with_name("blocking-threads")
+            .required(false)
+            .takes_value(true)
+            .long("blocking-threads")
+            .env("ONETUN_BLOCKING_THREADS")
+            .help("The most threads the runtime spawns for blocking work, such as resolving host names with the system. 512 by default."),
+        Arg::with_name("cpu-affinity")
+            .required(false)
+            .takes_value(true)
+            .long("cpu-affinity")
+            .env("ONETUN_CPU_AFFINITY")
+            .help("Restricts the threads of onetun to these cores, as a list of core numbers and ranges (e.g. '0,2-3'). \
+            Only supported on Linux."),
+        Arg::with_name("obfuscation-key")
+            .required(false)
+            .takes_value(true)
+            .long("obfuscation-key")
+            .env("ONETUN_OBFUSCATION_KEY")
+            .help("Obfuscates the datagrams exchanged with the WireGuard endpoint by XOR-ing them with this key, for networks that \
+            fingerprint and drop WireGuard. The endpoint must reverse the obfuscation, typically with a proxy in front of it."),
+        Arg::with_name("obfuscation-padding")
+            .required(false)
+            .takes_value(true)
+            .long("obfuscation-padding")
+            .env("ONETUN_OBFUSCATION_PADDING")
+            .requires("obfuscation-key")
+            .help("Appends up to this many random bytes (at most 255) to each obfuscated datagram, so that their sizes don't match WireGuard messages."),
+        Arg::with_name("amneziawg")
+            .required(false)
+            .takes_value(true)
+            .long("amneziawg")
+            .env("ONETUN_AMNEZIAWG")
+            .conflicts_with("obfuscation-key")
+            .help("Connects to an AmneziaWG server, with the obfuscation parameters of its peer configuration, such as \
+            'jc=4,jmin=40,jmax=70,s1=0,s2=0,h1=1,h2=2,h3=3,h4=4'. Missing parameters keep the values of standard WireGuard."),
+        Arg::with_name("max-transmission-unit")
+            .required(false)
+            .takes_value(true)
+            .long("max-transmission-unit")
+            .env("ONETUN_MTU")
+            .default_value("1420")
+            .help("Configures the max-transmission-unit (MTU) of the WireGuard tunnel."),
+        Arg::with_name("log")
+            .required(false)
+            .takes_value(true)
+            .long("log")
+            .env("ONETUN_LOG")
+            .default_value("info")
+            .help("Configures the log level and format."),
+        Arg::with_name("pcap")
+            .required(false)
+            .takes_value(true)
+            .long("pcap")
+            .env("ONETUN_PCAP")
+            .help("Decrypts and captures IP packets on the WireGuard tunnel to a given output file."),
+        Arg::with_name("pcap-index")
+            .required(false)
+            .takes_value(true)
+            .long("pcap-index")
+            .env("ONETUN_PCAP_INDEX")
+            .requires("pcap")
+            .help("Writes a sidecar index of the packet capture to a given output file. Each line maps a virtual port \
+            to its port forward, with the wall-clock range of the session and the bytes it transferred."),
+        Arg::with_name("capture-buffer")
+            .required(false)
+            .takes_value(true)
+            .long("capture-buffer")
+            .env("ONETUN_CAPTURE_BUFFER")
+            .help("How many of the last IP packets on the WireGuard tunnel to keep in memory, to dump them to a pcap file \
+            on demand (with SIGUSR2). 0 disables it. [default: 1000]"),
+        Arg::with_name("pcap-on-error")
+            .required(false)
+            .takes_value(true)
+            .long("pcap-on-error")
+            .env("ONETUN_PCAP_ON_ERROR")
+            .help("Writes a support bundle to this directory when the session with the WireGuard endpoint expires, or when \
+            the connections of a port forward keep failing: the capture buffer as a pcap file, and a report with a summary \
+            of the configuration, the last events and the handshake timeline."),
+        Arg::with_name("replay")
+            .required(false)
+            .takes_value(true)
+            .long("replay")
+            .env("ONETUN_REPLAY")
+            .help("Developer mode: feeds the IP packets that the peer sent in a given pcap file (as written by --pcap) to \
+            the virtual interfaces at their original timing, instead of exchanging packets with the peer. Reproduces the \
+            bugs of the virtual interfaces without a live peer."),
+        Arg::with_name("remote")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .long("remote")
+            .short("r")
+            .help("Remote port forward configurations. The format of each argument is <src_port>:<dst_host>:<dst_port>[:TCP,UDP,...], \
+            where <src_port> is the port the other peers will reach the server with, <dst_host> is the IP to forward to, and <dst_port> is the port to forward to. \
+            The <src_port> will be bound on onetun's peer IP, as specified by --source-peer-ip. If you pass a different value for <src_host> here, it will be rejected.\n\
+            Note: <dst_host>:<dst_port> must be reachable by onetun. If referring to another WireGuard peer, use --bridge instead (not supported yet).\n\
+            Environment variables of the form 'ONETUN_REMOTE_PORT_FORWARD_[#]' are also accepted, where [#] starts at 1.\n\
+            Examples:\n\
+            \t--remote 8080:localhost:8081:TCP,UDP\n\
+            \t--remote 8080:[::1]:8081:TCP\n\
+            \t--remote 8080:google.com:80\
+            "),
+    ]
+}
+
+/// The largest configuration or private key read, so that secrets are read into a single buffer.
+#[cfg(feature = "bin")]
+const MAX_SECRET_SIZE: usize = 64 * 1024;
+
+/// A buffer holding secrets, such as the private key, that is wiped when dropped.
+#[cfg(feature = "bin")]
+struct SecretBuffer(Vec<u8>);
+
+#[cfg(feature = "bin")]
+impl SecretBuffer {
+    fn as_str(&self) -> anyhow::Result<&str> {
+        std::str::from_utf8(&self.0).with_context(|| "Secret is not valid UTF-8")
+    }
+}
+
+#[cfg(feature = "bin")]
+impl Drop for SecretBuffer {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+/// Overwrites the buffer with zeros, in a way the compiler can't optimize away.
+#[cfg(feature = "bin")]
+fn wipe(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        // SAFETY: the pointer comes from a mutable reference
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Reads the whole reader into a buffer allocated once, so that no copy of its content is left behind
+/// when it grows.
+#[cfg(feature = "bin")]
+fn read_secret(mut reader: impl Read) -> std::io::Result<SecretBuffer> {
+    let mut secret = SecretBuffer(Vec::with_capacity(MAX_SECRET_SIZE));
+    let mut chunk = [0u8; 1024];
+    let result = loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => break Ok(()),
+            Ok(n) if secret.0.len() + n > MAX_SECRET_SIZE => {
+                break Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Secret is larger than {} bytes", MAX_SECRET_SIZE),
+                ))
+            }
+            Ok(n) => secret.0.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        }
+    };
+    wipe(&mut chunk);
+    result.map(|_| secret)
+}
+
+/// Reads a secret from the given file descriptor, which is closed afterwards.
+#[cfg(all(feature = "bin", unix))]
+fn read_secret_fd(fd: &str) -> anyhow::Result<SecretBuffer> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd: RawFd = fd
+        .parse()
+        .with_context(|| format!("Invalid file descriptor: '{}'", fd))?;
+    if fd < 0 {
+        return Err(anyhow::anyhow!("Invalid file descriptor: {}", fd));
+    }
+    // SAFETY: the supervisor hands the descriptor over to onetun, which owns it from now on
+    let file = unsafe { File::from_raw_fd(fd) };
+    Ok(read_secret(file)?)
+}
+
+#[cfg(all(feature = "bin", not(unix)))]
+fn read_secret_fd(_fd: &str) -> anyhow::Result<SecretBuffer> {
+    Err(anyhow::anyhow!(
+        "Reading from file descriptors is only supported on Unix"
+    ))
+}
+
+/// Inserts the options of the configuration given with `--config` after it, in the command-line arguments.
+/// Its private key is returned separately, so that it isn't copied into the arguments.
+#[cfg(feature = "bin")]
+fn expand_config_args(
+    args: Vec<OsString>,
+) -> anyhow::Result<(Vec<OsString>, Option<SecretBuffer>)> {
+    let mut expanded = Vec::with_capacity(args.len());
+    let mut private_key = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // The path follows `--config`, or is inlined as `--config=<path>`
+        let (inlined, follows) = match arg.to_str() {
+            Some("--config") => (None, true),
+            Some(arg) => (arg.strip_prefix("--config=").map(OsString::from), false),
+            None => (None, false),
+        };
+        expanded.push(arg);
+        let path = match (inlined, follows) {
+            (Some(path), _) => path,
+            (None, true) => match args.next() {
+                Some(path) => {
+                    expanded.push(path.clone());
+                    path
+                }
+                None => continue,
+            },
+            (None, false) => continue,
+        };
+
+        let content = if path == "-" {
+            read_secret(std::io::stdin().lock())
+        } else {
+            File::open(&path).and_then(read_secret)
+        }
+        .with_context(|| format!("Failed to read configuration {:?}", path))?;
+        let (options, key) = config_options(content.as_str()?)?;
+        expanded.extend(options.into_iter().map(OsString::from));
+        if key.is_some() {
+            private_key = key;
+        }
+    }
+    Ok((expanded, private_key))
+}
+
+/// Converts the lines of a configuration into command-line options, except for its private key.
+#[cfg(feature = "bin")]
+fn config_options(content: &str) -> anyhow::Result<(Vec<String>, Option<SecretBuffer>)> {
+    let mut options = Vec::new();
+    let mut private_key = None;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (line, None),
+        };
+        if name.is_empty() || name.starts_with('-') || name == "config" {
+            return Err(anyhow::anyhow!(
+                "Invalid option on line {} of the configuration: '{}'",
+                i + 1,
+                name
+            ));
+        }
+        match value {
+            Some(value) if name == "private-key" => {
+                private_key = Some(SecretBuffer(value.as_bytes().to_vec()));
+            }
+            Some(value) => options.push(format!("--{}={}", name, value)),
+            None => options.push(format!("--{}", name)),
+        }
+    }
+    Ok((options, private_key))
+}
+
+#[cfg(feature = "bin")]
+fn parse_addr(s: Option<&str>) -> anyhow::Result<SocketAddr> {
+    s.with_context(|| "Missing address")?
+        .to_socket_addrs()
+        .with_context(|| "Invalid address")?
+        .next()
+        .with_context(|| "Could not lookup address")
+}
+
+#[cfg(feature = "bin")]
+fn parse_ip(s: Option<&str>) -> anyhow::Result<IpAddr> {
+    s.with_context(|| "Missing IP")?
+        .parse::<IpAddr>()
+        .with_context(|| "Invalid IP address")
+}
+
+/// Parses a DNS server address, whose port defaults to 53.
+#[cfg(any(feature = "bin", test))]
+fn parse_dns_server(s: &str) -> anyhow::Result<SocketAddr> {
+    match s.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+        Err(_) => s
+            .parse::<SocketAddr>()
+            .with_context(|| format!("Invalid DNS server address: '{}'", s)),
+    }
+}
+
+fn parse_private_key(s: &str) -> anyhow::Result<PrivateKey> {
+    s.parse::<PrivateKey>()
+}
+
+fn parse_public_key(s: Option<&str>) -> anyhow::Result<PublicKey> {
+    s.with_context(|| "Missing public key")?
+        .parse::<PublicKey>()
+        .with_context(|| "Invalid public key")
+}
+
+#[cfg(feature = "bin")]
+fn parse_keep_alive(s: Option<&str>) -> anyhow::Result<Option<u16>> {
+    if let Some(s) = s {
+        let parsed: u16 = s.parse().with_context(|| {
+            format!(
+                "Keep-alive must be a number between 0 and {} seconds",
+                u16::MAX
+            )
+        })?;
+        Ok(Some(parsed))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Parses a list of core numbers and ranges, such as `0,2-3`.
+#[cfg(any(feature = "bin", test))]
+fn parse_cpu_list(s: &str) -> anyhow::Result<Vec<usize>> {
+    let mut cores = Vec::new();
+    for item in s.split(',').map(str::trim) {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.parse().with_context(|| "Invalid core number")?;
+                let last: usize = last.parse().with_context(|| "Invalid core number")?;
+                if first > last {
+                    return Err(anyhow::anyhow!("Invalid core range: {}", item));
+                }
+                cores.extend(first..=last);
+            }
+            None => cores.push(item.parse().with_context(|| "Invalid core number")?),
+        }
+    }
+    Ok(cores)
+}
+
+/// Parses a duration given in seconds (`10`), or with a unit suffix (`500ms`, `10s`, `1m`).
+pub(crate) fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = value
+        .parse()
+        .with_context(|| format!("Invalid duration: {}", s))?;
+    match unit {
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "ms" => Ok(Duration::from_millis(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        _ => Err(anyhow::anyhow!("Invalid duration unit: {}", unit)),
+    }
+}
+
+#[cfg(feature = "bin")]
+fn parse_mtu(s: Option<&str>) -> anyhow::Result<usize> {
+    s.with_context(|| "Missing MTU")?
+        .parse()
+        .with_context(|| "Invalid MTU")
+}
+
+/// Parses the size of the largest packet of the interactive lane, in bytes.
+#[cfg(feature = "bin")]
+fn parse_priority_lanes(s: &str) -> anyhow::Result<usize> {
+    match s.parse() {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(anyhow::anyhow!("Invalid priority lane size: {}", s)),
+    }
+}
+
+#[cfg(all(feature = "bin", unix))]
 fn is_file_insecurely_readable(path: &str) -> Option<(bool, bool)> {
     use std::fs::File;
     use std::os::unix::fs::MetadataExt;
 
-    let mode = File::open(&path).ok()?.metadata().ok()?.mode();
+    let mode = File::open(path).ok()?.metadata().ok()?.mode();
     Some((mode & 0o40 > 0, mode & 0o4 > 0))
 }
 
-#[cfg(not(unix))]
+#[cfg(all(feature = "bin", not(unix)))]
 fn is_file_insecurely_readable(_path: &str) -> Option<(bool, bool)> {
     // No good way to determine permissions on non-Unix target
     None
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub struct PortForwardConfig {
-    /// The source IP and port where the local server will run.
-    pub source: SocketAddr,
-    /// The destination IP and port to which traffic will be forwarded.
-    pub destination: SocketAddr,
-    /// The transport protocol to use for the port (Layer 4).
-    pub protocol: PortProtocol,
-    /// Whether this is a remote port forward.
-    pub remote: bool,
-}
+/// A sub-command given on the command line. Without one, onetun runs the port forwards until killed.
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Measures throughput and latency through each TCP port forward.
+    Bench(BenchmarkOptions),
+    /// Checks that the destination of each port forward is reachable, without listening locally.
+    Check(CheckOptions),
+    /// Checks the environment (see `diagnostics`), without starting the tunnel.
+    Doctor,
+    /// Prints the interface and its peer in the layout of `wg show`, without listening locally.
+    Show,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PortForwardConfig {
+    /// The source IP and port where the local server will run.
+    pub source: SocketAddr,
+    /// The destination IP and port to which traffic will be forwarded.
+    pub destination: SocketAddr,
+    /// The transport protocol to use for the port (Layer 4).
+    pub protocol: PortProtocol,
+    /// Whether this is a remote port forward.
+    pub remote: bool,
+    /// The TLS layer to add on the local side of a TCP port forward, if any.
+    pub tls: Option<Arc<TlsOptions>>,
+    /// The name given to the port forward, if any.
+    pub name: Option<Arc<str>>,
+    /// The IP of this peer to connect from, if not the default `source_peer_ip`.
+    pub source_peer_ip: Option<IpAddr>,
+    /// The destinations to fail over to when `destination` can't be reached (TCP only).
+    pub failover_destinations: Vec<SocketAddr>,
+    /// How the destination of each connection is picked, when there are failover destinations.
+    pub selection: DestinationSelection,
+    /// Relaying of broadcast or multicast datagrams, on UDP port forwards.
+    pub relay: Option<DatagramRelay>,
+    /// How long the virtual port of an idle client is kept, on UDP port forwards.
+    pub session_mode: UdpSessionMode,
+    /// The host name of the destination, if it was given by name. With a tunnel DNS server (see
+    /// `Config::with_tunnel_dns`), it is resolved through the tunnel, and `destination` is only its port.
+    pub hostname: Option<Arc<str>>,
+    /// The Windows named pipe to listen on instead of `source`, such as `\\.\pipe\mydb` (TCP only).
+    /// Its connections get virtual ports as if they came from `source`.
+    pub pipe: Option<Arc<str>>,
+    /// The network interface to listen on, on each of its addresses, such as `eth0`. The IP of `source` is
+    /// unspecified, and only its port is used.
+    pub interface: Option<Arc<str>>,
+    /// The codec applied to the data of a TCP port forward through the tunnel, if any. The peer on the other
+    /// side must apply the same codec, such as another onetun with a remote port forward.
+    pub compression: Option<StreamCodec>,
+    /// Which sources the datagrams sent back to a client may come from, on UDP port forwards.
+    pub response_filter: ResponseFilter,
+    /// The bytes of datagrams each virtual UDP client can hold in each direction, if not 64 KiB.
+    pub udp_buffer_size: Option<usize>,
+    /// How many datagrams each virtual UDP client can hold in each direction, if not 10.
+    pub udp_packet_slots: Option<usize>,
+    /// The largest message a local client may send through the tunnel, if limited: the datagrams of a UDP port
+    /// forward above it are dropped, and the data of a TCP connection is sent in chunks of at most this size.
+    /// UDP datagrams are limited anyway to what the virtual interface can send (see `tunnel::DatagramLimit`).
+    pub max_message_size: Option<usize>,
+    /// How many connections can wait to be accepted by a TCP port forward, if not 1024. The system may
+    /// lower it (`net.core.somaxconn` on Linux).
+    pub listen_backlog: Option<u32>,
+    /// The length of the TCP Fast Open queue of a TCP port forward, if it accepts data in the SYN of its
+    /// clients (Linux, Android, macOS and iOS; the latter two ignore the length).
+    pub fast_open: Option<u32>,
+    /// Whether the socket of a TCP port forward sets `SO_REUSEPORT`, so that other sockets can listen on
+    /// the same port (Unix only).
+    pub reuse_port: bool,
+    /// How many sockets a TCP port forward listens on, each accepting on its own task, for connection-heavy
+    /// workloads. More than one shares the port with `SO_REUSEPORT` (Linux and Android only).
+    pub listeners: usize,
+    /// Another destination receiving a copy of the data of each connection of a TCP port forward.
+    pub mirror: Option<Mirror>,
+    /// The bytes the virtual socket of each connection of a TCP port forward buffers in each direction, and the
+    /// largest window it advertises, if not 64 KiB. Larger windows are scaled (RFC 7323), for links with a high
+    /// bandwidth-delay product.
+    pub tcp_window: Option<usize>,
+    /// How long the virtual TCP sockets delay their acknowledgements, if not smoltcp's 10 ms. Zero acknowledges
+    /// each segment right away.
+    pub ack_delay: Option<Duration>,
+    /// Whether the virtual TCP sockets hold small segments back while data is unacknowledged (Nagle's algorithm).
+    pub nagle: bool,
+    /// The local destinations of the datagrams from some peers, on remote UDP port forwards, instead of
+    /// `destination`. The first route containing the peer applies.
+    pub peer_routes: Vec<PeerRoute>,
+}
+
+/// How a port forward with failover destinations picks the destination of each connection.
+/// Destinations that recently failed are only tried after the others.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum DestinationSelection {
+    /// Tries the destinations in the order they were given.
+    InOrder,
+    /// Starts from the next destination on each connection.
+    RoundRobin,
+    /// Starts from the destination of the last successful connection.
+    Sticky,
+}
+
+impl Default for DestinationSelection {
+    fn default() -> Self {
+        Self::InOrder
+    }
+}
+
+/// Broadcast or multicast datagrams relayed by a UDP port forward, in both directions: from the local
+/// network to the destination through the tunnel, and from the tunnel to the local network.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum DatagramRelay {
+    /// Broadcast datagrams. The destination is the broadcast address to relay local datagrams to.
+    Broadcast,
+    /// Multicast datagrams. The destination is the IPv4 multicast group joined on both sides.
+    Multicast,
+}
+
+/// The kind of sessions carried by a UDP port forward, which decides how long the virtual port of an
+/// idle client is kept before it can be assigned to another client.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum UdpSessionMode {
+    /// Any protocol: the virtual port is kept for a minute after the last datagram.
+    Generic,
+    /// QUIC connections, which may stay idle for minutes. Clients whose address changes are kept on
+    /// the virtual port of their connection, recognized by its connection ID.
+    Quic,
+    /// DNS exchanges, which are over after the response: the virtual port is recycled within seconds.
+    Dns,
+}
+
+impl Default for UdpSessionMode {
+    fn default() -> Self {
+        Self::Generic
+    }
+}
+
+impl UdpSessionMode {
+    /// How long the virtual port of an idle client is kept.
+    pub fn idle_timeout(&self) -> Duration {
+        match self {
+            Self::Generic => Duration::from_secs(60),
+            Self::Quic => Duration::from_secs(600),
+            Self::Dns => Duration::from_secs(5),
+        }
+    }
+}
+
+impl FromStr for UdpSessionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "generic" => Ok(Self::Generic),
+            "quic" => Ok(Self::Quic),
+            "dns" => Ok(Self::Dns),
+            _ => Err(anyhow::anyhow!("Invalid session mode: '{}'", s)),
+        }
+    }
+}
+
+impl Display for UdpSessionMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Generic => "generic",
+                Self::Quic => "quic",
+                Self::Dns => "dns",
+            }
+        )
+    }
+}
+
+/// Which sources of the peer network may answer the clients of a UDP port forward, like Linux's `rp_filter`.
+/// Datagrams from other sources are dropped, so that a misbehaving peer can't inject datagrams into the
+/// sessions of unrelated clients. Port forwards relaying broadcast or multicast datagrams accept any source,
+/// since the hosts that received them answer.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ResponseFilter {
+    /// Only the exact addresses (IP and port) the client's virtual port sent datagrams to.
+    Strict,
+    /// Any port of the IPs the client's virtual port sent datagrams to.
+    Loose,
+    /// Any source.
+    Off,
+}
+
+impl Default for ResponseFilter {
+    fn default() -> Self {
+        Self::Loose
+    }
+}
+
+impl FromStr for ResponseFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "loose" => Ok(Self::Loose),
+            "off" => Ok(Self::Off),
+            _ => Err(anyhow::anyhow!("Invalid response filter: '{}'", s)),
+        }
+    }
+}
+
+impl Display for ResponseFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Strict => "strict",
+                Self::Loose => "loose",
+                Self::Off => "off",
+            }
+        )
+    }
+}
+
+/// A codec applied to the data of TCP connections through the tunnel (see `tunnel::transform`).
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum StreamCodec {
+    /// LZ4 block compression, in frames of up to 64 KiB.
+    Lz4,
+}
+
+impl FromStr for StreamCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(anyhow::anyhow!("Invalid compression codec: '{}'", s)),
+        }
+    }
+}
+
+impl Display for StreamCodec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+/// A copy of the data of the connections of a TCP port forward, sent through the tunnel to another destination,
+/// such as a traffic recorder or an IDS. Each connection is copied on a virtual connection of its own, which is
+/// best-effort: when the mirror can't be reached or falls behind, the connection goes on without it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Mirror {
+    /// Where the copy is sent.
+    pub destination: SocketAddr,
+    /// Which data of the connections is copied.
+    pub direction: MirrorDirection,
+}
+
+/// Which data of the connections of a TCP port forward is copied to its mirror.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum MirrorDirection {
+    /// The data of both sides, in the order it is relayed.
+    Both,
+    /// The data sent by the local clients.
+    Outbound,
+    /// The data sent back by the destination.
+    Inbound,
+}
+
+impl Default for MirrorDirection {
+    fn default() -> Self {
+        Self::Both
+    }
+}
+
+impl FromStr for MirrorDirection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "both" => Ok(Self::Both),
+            "outbound" => Ok(Self::Outbound),
+            "inbound" => Ok(Self::Inbound),
+            _ => Err(anyhow::anyhow!("Invalid mirror direction: '{}'", s)),
+        }
+    }
+}
+
+impl Display for MirrorDirection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Both => "both",
+                Self::Outbound => "outbound",
+                Self::Inbound => "inbound",
+            }
+        )
+    }
+}
+
+/// The local destination of the datagrams a remote UDP port forward receives from the peers in a range. The
+/// replies it sends go back to the peer that last reached it, so each route needs its own destination.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct PeerRoute {
+    /// The IPs of the peers taking the route.
+    pub peers: AllowedIp,
+    /// Where their datagrams are sent, and their replies come from.
+    pub destination: SocketAddr,
+}
+
+impl FromStr for PeerRoute {
+    type Err = anyhow::Error;
+
+    /// Parses `<ip>[/<prefix>]=<ip:port>`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (peers, destination) = s
+            .split_once('=')
+            .with_context(|| format!("Invalid route '{}': expected <peers>=<ip:port>", s))?;
+        Ok(Self {
+            peers: peers.parse()?,
+            destination: destination
+                .parse()
+                .with_context(|| format!("Invalid route destination: '{}'", destination))?,
+        })
+    }
+}
+
+impl PortForwardConfig {
+    /// Creates a new PortForwardConfig
+    pub fn new(source: SocketAddr, destination: SocketAddr, protocol: PortProtocol) -> Self {
+        Self {
+            source,
+            destination,
+            protocol,
+            remote: false,
+            tls: None,
+            name: None,
+            source_peer_ip: None,
+            failover_destinations: vec![],
+            selection: DestinationSelection::InOrder,
+            relay: None,
+            session_mode: UdpSessionMode::Generic,
+            hostname: None,
+            pipe: None,
+            interface: None,
+            compression: None,
+            response_filter: ResponseFilter::Loose,
+            udp_buffer_size: None,
+            udp_packet_slots: None,
+            max_message_size: None,
+            listen_backlog: None,
+            fast_open: None,
+            reuse_port: false,
+            listeners: 1,
+            mirror: None,
+            tcp_window: None,
+            ack_delay: None,
+            nagle: true,
+            peer_routes: vec![],
+        }
+    }
+
+    /// The source as given: its named pipe, its interface and port, or its address.
+    pub fn source_name(&self) -> String {
+        match (&self.pipe, &self.interface) {
+            (Some(pipe), _) => format!("npipe://{}", pipe.replace('\\', "/")),
+            (None, Some(interface)) => format!("{}:{}", interface, self.source.port()),
+            (None, None) => self.source.to_string(),
+        }
+    }
+
+    /// The destination as given: its host name and port, or its address.
+    pub fn destination_name(&self) -> String {
+        match &self.hostname {
+            Some(hostname) => format!("{}:{}", hostname, self.destination.port()),
+            None => self.destination.to_string(),
+        }
+    }
+
+    /// The local destination of the datagrams from the peer, on remote UDP port forwards: the one of the first
+    /// peer route containing it, or else `destination`.
+    pub fn peer_destination(&self, peer: IpAddr) -> SocketAddr {
+        self.peer_routes
+            .iter()
+            .find(|route| route.peers.contains(&peer))
+            .map_or(self.destination, |route| route.destination)
+    }
+
+    /// The destination, followed by the failover destinations.
+    pub fn destinations(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        std::iter::once(self.destination).chain(self.failover_destinations.iter().copied())
+    }
+
+    /// The stable identifier of the port forward at the given position in the configuration.
+    pub fn id(&self, index: usize) -> ForwardId {
+        match &self.name {
+            Some(name) => ForwardId::Name(name.clone()),
+            None => ForwardId::Index(index),
+        }
+    }
+
+    /// Converts a string representation into `PortForwardConfig`.
+    ///
+    /// Sample formats:
+    ///  - `127.0.0.1:8080:192.168.4.1:8081:TCP,UDP`
+    ///  - `127.0.0.1:8080:192.168.4.1:8081:TCP`
+    ///  - `0.0.0.0:8080:192.168.4.1:8081`
+    ///  - `[::1]:8080:192.168.4.1:8081`
+    ///  - `8080:192.168.4.1:8081`
+    ///  - `8080:192.168.4.1:8081:TCP`
+    ///  - `localhost:8080:192.168.4.1:8081:TCP`
+    ///  - `localhost:8080:peer.intranet:8081:TCP`
+    ///  - `8443:192.168.4.1:8081;tls-terminate,cert=cert.pem,key=key.pem`
+    ///  - `8080:peer.intranet:443;tls-originate`
+    ///  - `8080:192.168.4.1:8081;name=web`
+    ///  - `0.0.0.0:1900:239.255.255.250:1900:UDP;multicast`
+    ///  - `npipe:////./pipe/mydb:192.168.4.1:5432:TCP`
+    ///  - `[fe80::1%eth0]:8080:192.168.4.1:8081:TCP+UDP`
+    ///
+    /// Implementation Notes:
+    ///  - The format is formalized as `[src_host:]<src_port>:<dst_host>:<dst_port>[:PROTO1,PROTO2,...]`
+    ///  - `src_host` is optional and defaults to `127.0.0.1`.
+    ///  - `src_host` and `dst_host` may be specified as IPv4, IPv6, or a FQDN to be resolved by DNS. A single
+    ///    `dst_host` that the system can't resolve is left to the tunnel DNS server (see `Config::with_tunnel_dns`).
+    ///  - IPv6 addresses must be prefixed with `[` and suffixed with `]`. Example: `[::1]`. The IPv6 `src_host` may
+    ///    name its network interface after a `%`, by name or index: `[fe80::1%eth0]`.
+    ///  - Any `u16` is accepted as `src_port` and `dst_port`
+    ///  - Specifying protocols (`PROTO1,PROTO2,...`) is optional and defaults to `TCP`. Values must be separated by commas
+    ///    or `+` (`TCP+UDP`).
+    ///  - Syntax errors give the column they were found at.
+    ///  - Options may follow a `;`, separated by commas. See `ForwardOptions` for the accepted options.
+    ///  - `npipe://<path>` replaces `[src_host:]<src_port>` with a Windows named pipe, written with `/` instead
+    ///    of `\`. It listens on `\\.\pipe\mydb` in the example above.
+    pub fn from_notation(s: &str, default_source: &str) -> anyhow::Result<Vec<PortForwardConfig>> {
+        if let Some(pipe) = s.strip_prefix("npipe://") {
+            let (path, rest) = pipe
+                .split_once(':')
+                .with_context(|| "Invalid port-forward definition: missing destination")?;
+            if !path.starts_with("//") || !path[2..].contains("/pipe/") {
+                return Err(anyhow::anyhow!(
+                    "Invalid named pipe {}: expected //<server>/pipe/<name>",
+                    path
+                ));
+            }
+            let pipe = Arc::<str>::from(path.replace('/', "\\"));
+            // The placeholder source only keys the virtual ports of the pipe connections
+            let port_forwards = Self::from_notation(&format!("0:{}", rest), "127.0.0.1")?;
+            if port_forwards
+                .iter()
+                .any(|pf| pf.protocol != PortProtocol::Tcp || pf.tls.is_some())
+            {
+                return Err(anyhow::anyhow!(
+                    "Named pipes are only supported on TCP port forwards without TLS"
+                ));
+            }
+            return Ok(port_forwards
+                .into_iter()
+                .map(|pf| Self {
+                    pipe: Some(pipe.clone()),
+                    ..pf
+                })
+                .collect());
+        }
+
+        let (src_addr, dst_addrs, protocols, options) = parse_notation(s)?;
+        let options = match options {
+            Some(options) => ForwardOptions::parse(options, dst_addrs[0].0)
+                .with_context(|| "Invalid port-forward options")?,
+            None => ForwardOptions::default(),
+        };
+
+        let source_port = src_addr
+            .1
+            .parse::<u16>()
+            .with_context(|| "Invalid source port")?;
+        // A source host naming a network interface listens on each of its addresses
+        let interface = src_addr
+            .0
+            .filter(|host| is_interface(host))
+            .map(Arc::<str>::from);
+        let source = match interface {
+            Some(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, source_port)),
+            None => resolve_source(src_addr.0.unwrap_or(default_source), source_port)?,
+        };
+
+        // A single destination may be a host name that only the tunnel DNS server knows
+        let hostname = match dst_addrs[..] {
+            [(host, _)] if host.parse::<IpAddr>().is_err() => Some(Arc::<str>::from(host)),
+            _ => None,
+        };
+        let mut destinations = dst_addrs
+            .into_iter()
+            .map(|dst_addr| {
+                if dst_addr.0.contains('%') {
+                    return Err(anyhow::anyhow!(
+                        "Interface zones are only supported on the listen side"
+                    ));
+                }
+                let port = dst_addr
+                    .1
+                    .parse::<u16>()
+                    .with_context(|| "Invalid destination port")?;
+                let resolved = (dst_addr.0, port)
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next());
+                match resolved {
+                    Some(addr) => Ok(addr),
+                    None if hostname.is_some() => {
+                        Ok(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+                    }
+                    None => Err(anyhow::anyhow!("Could not resolve destination address")),
+                }
+            })
+            .collect::<anyhow::Result<Vec<SocketAddr>>>()?;
+        let destination = destinations.remove(0);
+        let failover_destinations = destinations;
+
+        // Parse protocols
+        let protocols = if let Some(protocols) = protocols {
+            let protocols: anyhow::Result<Vec<PortProtocol>> =
+                protocols.into_iter().map(PortProtocol::try_from).collect();
+            protocols
+        } else {
+            Ok(vec![PortProtocol::Tcp])
+        }
+        .with_context(|| "Failed to parse protocols")?;
+
+        if options.tls.is_some() && protocols.iter().any(|p| *p != PortProtocol::Tcp) {
+            return Err(anyhow::anyhow!(
+                "TLS is only supported on TCP port forwards"
+            ));
+        }
+        if !failover_destinations.is_empty() && protocols.iter().any(|p| *p != PortProtocol::Tcp) {
+            return Err(anyhow::anyhow!(
+                "Failover destinations are only supported on TCP port forwards"
+            ));
+        }
+        if options.relay.is_some() && protocols.iter().any(|p| *p != PortProtocol::Udp) {
+            return Err(anyhow::anyhow!(
+                "Broadcast and multicast relays are only supported on UDP port forwards"
+            ));
+        }
+        if options.compression.is_some() && protocols.iter().any(|p| *p != PortProtocol::Tcp) {
+            return Err(anyhow::anyhow!(
+                "Compression is only supported on TCP port forwards"
+            ));
+        }
+        if options.session_mode != UdpSessionMode::Generic
+            && protocols.iter().any(|p| *p != PortProtocol::Udp)
+        {
+            return Err(anyhow::anyhow!(
+                "Session modes are only supported on UDP port forwards"
+            ));
+        }
+        if options.response_filter != ResponseFilter::Loose
+            && protocols.iter().any(|p| *p != PortProtocol::Udp)
+        {
+            return Err(anyhow::anyhow!(
+                "Response filters are only supported on UDP port forwards"
+            ));
+        }
+        if (options.udp_buffer_size.is_some() || options.udp_packet_slots.is_some())
+            && protocols.iter().any(|p| *p != PortProtocol::Udp)
+        {
+            return Err(anyhow::anyhow!(
+                "UDP buffer sizes are only supported on UDP port forwards"
+            ));
+        }
+        if (options.listen_backlog.is_some()
+            || options.fast_open.is_some()
+            || options.reuse_port
+            || options.listeners.is_some())
+            && protocols.iter().any(|p| *p != PortProtocol::Tcp)
+        {
+            return Err(anyhow::anyhow!(
+                "Listener options are only supported on TCP port forwards"
+            ));
+        }
+        if (options.tcp_window.is_some() || options.ack_delay.is_some() || options.nagle.is_some())
+            && protocols.iter().any(|p| *p != PortProtocol::Tcp)
+        {
+            return Err(anyhow::anyhow!(
+                "TCP window, ack-delay and nagle options are only supported on TCP port forwards"
+            ));
+        }
+        if options.mirror.is_some() && protocols.iter().any(|p| *p != PortProtocol::Tcp) {
+            return Err(anyhow::anyhow!(
+                "Mirrors are only supported on TCP port forwards"
+            ));
+        }
+        if !options.peer_routes.is_empty() {
+            if protocols.iter().any(|p| *p != PortProtocol::Udp) {
+                return Err(anyhow::anyhow!(
+                    "Peer routes are only supported on UDP port forwards"
+                ));
+            }
+            // The replies are told apart by the destination they come from
+            let mut destinations: HashSet<SocketAddr> = std::iter::once(destination).collect();
+            for route in options.peer_routes.iter() {
+                if route.destination.is_ipv4() != destination.is_ipv4() {
+                    return Err(anyhow::anyhow!(
+                        "Route destination {} must be of the same IP version as {}",
+                        route.destination,
+                        destination
+                    ));
+                }
+                if !destinations.insert(route.destination) {
+                    return Err(anyhow::anyhow!(
+                        "Each route needs a destination of its own: {} is used twice",
+                        route.destination
+                    ));
+                }
+            }
+        }
+        if options.response_filter == ResponseFilter::Strict && options.relay.is_some() {
+            return Err(anyhow::anyhow!(
+                "Broadcast and multicast relays are answered by other hosts, and can't use the strict response filter"
+            ));
+        }
+        match (options.relay, destination.ip()) {
+            (Some(DatagramRelay::Multicast), IpAddr::V4(ip)) if ip.is_multicast() => {}
+            (Some(DatagramRelay::Multicast), _) => {
+                return Err(anyhow::anyhow!(
+                    "The destination of a multicast relay must be an IPv4 multicast group"
+                ))
+            }
+            (Some(DatagramRelay::Broadcast), IpAddr::V6(_)) => {
+                return Err(anyhow::anyhow!("IPv6 has no broadcast addresses"))
+            }
+            (None, ip) if ip.is_multicast() || ip == IpAddr::V4(Ipv4Addr::BROADCAST) => {
+                return Err(anyhow::anyhow!(
+                    "Forwarding to {} requires the broadcast or multicast option",
+                    ip
+                ))
+            }
+            _ => {}
+        }
+
+        // Returns an config for each protocol
+        Ok(protocols
+            .into_iter()
+            .map(|protocol| Self {
+                source,
+                destination,
+                protocol,
+                remote: false,
+                tls: options.tls.clone(),
+                name: options.name.clone(),
+                source_peer_ip: options.source_peer_ip,
+                failover_destinations: failover_destinations.clone(),
+                selection: options.selection,
+                relay: options.relay,
+                session_mode: options.session_mode,
+                hostname: hostname.clone(),
+                pipe: None,
+                interface: interface.clone(),
+                compression: options.compression,
+                response_filter: options.response_filter,
+                udp_buffer_size: options.udp_buffer_size,
+                udp_packet_slots: options.udp_packet_slots,
+                max_message_size: options.max_message_size,
+                listen_backlog: options.listen_backlog,
+                fast_open: options.fast_open,
+                reuse_port: options.reuse_port,
+                listeners: options.listeners.unwrap_or(1),
+                mirror: options.mirror,
+                tcp_window: options.tcp_window,
+                ack_delay: options.ack_delay,
+                nagle: options.nagle.unwrap_or(true),
+                peer_routes: options.peer_routes.clone(),
+            })
+            .collect())
+    }
+}
+
+/// The parts of a port forward definition: the source host and port, the destination hosts and ports, the
+/// protocols, and the options after the `;`.
+type NotationParts<'a> = (
+    (Option<&'a str>, &'a str),
+    Vec<(&'a str, &'a str)>,
+    Option<Vec<&'a str>>,
+    Option<&'a str>,
+);
+
+/// Splits a port forward definition into its parts, without resolving them (see `PortForwardConfig::from_notation`).
+/// Syntax errors give the column they were found at.
+fn parse_notation(s: &str) -> anyhow::Result<NotationParts<'_>> {
+    mod parsers {
+        use nom::branch::alt;
+        use nom::bytes::complete::is_not;
+        use nom::character::complete::{alpha1, char, digit1, one_of};
+        use nom::combinator::{map, opt, success};
+        use nom::error::{context, ErrorKind, VerboseError};
+        use nom::multi::separated_list1;
+        use nom::sequence::{delimited, preceded, separated_pair, tuple};
+
+        pub type IResult<I, O> = nom::IResult<I, O, VerboseError<I>>;
+
+        fn ipv6(s: &str) -> IResult<&str, &str> {
+            delimited(char('['), is_not("]"), context("']'", char(']')))(s)
+        }
+
+        fn ipv4_or_fqdn(s: &str) -> IResult<&str, &str> {
+            let s = is_not(":[],;")(s)?;
+            if s.1.chars().all(|c| c.is_ascii_digit()) {
+                // If ipv4 or fqdn is all digits, it's not valid.
+                Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
+                    s.1,
+                    ErrorKind::Fail,
+                )))
+            } else {
+                Ok(s)
+            }
+        }
+
+        fn port(s: &str) -> IResult<&str, &str> {
+            context("a port number", digit1)(s)
+        }
+
+        fn ip_or_fqdn(s: &str) -> IResult<&str, &str> {
+            context("a host", alt((ipv6, ipv4_or_fqdn)))(s)
+        }
+
+        fn no_ip(s: &str) -> IResult<&str, Option<&str>> {
+            success(None)(s)
+        }
+
+        fn src_addr(s: &str) -> IResult<&str, (Option<&str>, &str)> {
+            let with_ip = separated_pair(map(ip_or_fqdn, Some), char(':'), port);
+            let without_ip = tuple((no_ip, port));
+            alt((with_ip, without_ip))(s)
+        }
+
+        fn dst_addr(s: &str) -> IResult<&str, (&str, &str)> {
+            separated_pair(ip_or_fqdn, context("':'", char(':')), port)(s)
+        }
+
+        /// A single destination, or a bracketed list of failover destinations.
+        fn dst_addrs(s: &str) -> IResult<&str, Vec<(&str, &str)>> {
+            let failover = delimited(char('['), separated_list1(char(','), dst_addr), char(']'));
+            context(
+                "a destination",
+                alt((failover, map(dst_addr, |addr| vec![addr]))),
+            )(s)
+        }
+
+        fn protocol(s: &str) -> IResult<&str, &str> {
+            context("a protocol", alpha1)(s)
+        }
+
+        /// Protocols are separated by `,` or `+` (`TCP,UDP` or `TCP+UDP`).
+        fn protocols(s: &str) -> IResult<&str, Option<Vec<&str>>> {
+            opt(preceded(char(':'), separated_list1(one_of(",+"), protocol)))(s)
+        }
+
+        #[allow(clippy::type_complexity)]
+        pub fn port_forward(
+            s: &str,
+        ) -> IResult<
+            &str,
+            (
+                (Option<&str>, &str),
+                char,
+                Vec<(&str, &str)>,
+                Option<Vec<&str>>,
+            ),
+        > {
+            tuple((src_addr, context("':'", char(':')), dst_addrs, protocols))(s)
+        }
+    }
+
+    // The column of the remaining input, for error messages
+    let column = |rest: &str| s.len() - rest.len() + 1;
+    let (rest, (src_addr, _, dst_addrs, protocols)) =
+        parsers::port_forward(s).map_err(|e| match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => {
+                let expected = e
+                    .errors
+                    .iter()
+                    .find_map(|(_, kind)| match kind {
+                        nom::error::VerboseErrorKind::Context(expected) => Some(*expected),
+                        _ => None,
+                    })
+                    .unwrap_or("a port forward");
+                let at = e.errors.first().map_or("", |(rest, _)| *rest);
+                anyhow::anyhow!(
+                    "Invalid port-forward definition at column {}: expected {}",
+                    column(at),
+                    expected
+                )
+            }
+            nom::Err::Incomplete(_) => anyhow::anyhow!("Incomplete port-forward definition"),
+        })?;
+
+    let options = match rest.strip_prefix(';') {
+        Some(options) => Some(options),
+        None if rest.is_empty() => None,
+        None => {
+            return Err(anyhow::anyhow!(
+                "Invalid port-forward definition at column {}: unexpected '{}'",
+                column(rest),
+                rest
+            ))
+        }
+    };
+    Ok((src_addr, dst_addrs, protocols, options))
+}
+
+/// Resolves the listen address of a port forward. An IPv6 literal may name the interface it is on after a `%`,
+/// such as `fe80::1%eth0` for a link-local address.
+fn resolve_source(host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    if let Some((ip, zone)) = host.split_once('%') {
+        let ip: Ipv6Addr = ip
+            .parse()
+            .with_context(|| "Interface zones are only supported on IPv6 addresses")?;
+        return Ok(SocketAddrV6::new(ip, port, 0, interface_index(zone)?).into());
+    }
+    (host, port)
+        .to_socket_addrs()
+        .with_context(|| "Invalid source address")?
+        .next()
+        .with_context(|| "Could not resolve source address")
+}
+
+/// Whether the host is the name of a network interface of this system.
+#[cfg(unix)]
+fn is_interface(host: &str) -> bool {
+    std::ffi::CString::new(host).map_or(
+        false,
+        |name| unsafe { libc::if_nametoindex(name.as_ptr()) } != 0,
+    )
+}
+
+/// Listening on the addresses of an interface is only supported on Unix.
+#[cfg(not(unix))]
+fn is_interface(_host: &str) -> bool {
+    false
+}
+
+/// The index of the network interface with the given name or index.
+fn interface_index(interface: &str) -> anyhow::Result<u32> {
+    if let Ok(index) = interface.parse() {
+        return Ok(index);
+    }
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(interface)
+            .with_context(|| format!("Invalid interface name: {:?}", interface))?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index != 0 {
+            return Ok(index);
+        }
+    }
+    Err(anyhow::anyhow!("Unknown network interface: {}", interface))
+}
+
+impl Display for PortForwardConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.remote {
+            write!(f, "(remote)")?;
+        }
+        if self.failover_destinations.is_empty() {
+            write!(
+                f,
+                "{}:{}:{}",
+                self.source_name(),
+                self.destination_name(),
+                self.protocol
+            )?;
+        } else {
+            let destinations: Vec<String> = self.destinations().map(|d| d.to_string()).collect();
+            write!(
+                f,
+                "{}:[{}]:{}",
+                self.source_name(),
+                destinations.join(","),
+                self.protocol
+            )?;
+        }
+        if let Some(name) = &self.name {
+            write!(f, ";name={}", name)?;
+        }
+        if let Some(ip) = &self.source_peer_ip {
+            write!(f, ";peer-ip={}", ip)?;
+        }
+        match self.selection {
+            DestinationSelection::InOrder => {}
+            DestinationSelection::RoundRobin => write!(f, ";round-robin")?,
+            DestinationSelection::Sticky => write!(f, ";sticky")?,
+        }
+        match self.relay {
+            None => {}
+            Some(DatagramRelay::Broadcast) => write!(f, ";broadcast")?,
+            Some(DatagramRelay::Multicast) => write!(f, ";multicast")?,
+        }
+        if self.session_mode != UdpSessionMode::Generic {
+            write!(f, ";session-mode={}", self.session_mode)?;
+        }
+        if let Some(codec) = self.compression {
+            write!(f, ";compress={}", codec)?;
+        }
+        if self.response_filter != ResponseFilter::Loose {
+            write!(f, ";response-filter={}", self.response_filter)?;
+        }
+        if let Some(size) = self.udp_buffer_size {
+            write!(f, ";udp-buffer-size={}", size)?;
+        }
+        if let Some(slots) = self.udp_packet_slots {
+            write!(f, ";udp-packet-slots={}", slots)?;
+        }
+        if let Some(size) = self.max_message_size {
+            write!(f, ";max-message-size={}", size)?;
+        }
+        if let Some(backlog) = self.listen_backlog {
+            write!(f, ";backlog={}", backlog)?;
+        }
+        if let Some(queue) = self.fast_open {
+            write!(f, ";fast-open={}", queue)?;
+        }
+        if self.reuse_port {
+            write!(f, ";reuse-port")?;
+        }
+        if self.listeners > 1 {
+            write!(f, ";listeners={}", self.listeners)?;
+        }
+        if let Some(mirror) = self.mirror {
+            write!(f, ";mirror={}", mirror.destination)?;
+            if mirror.direction != MirrorDirection::Both {
+                write!(f, ";mirror-direction={}", mirror.direction)?;
+            }
+        }
+        if let Some(window) = self.tcp_window {
+            write!(f, ";tcp-window={}", window)?;
+        }
+        if let Some(delay) = self.ack_delay {
+            write!(f, ";ack-delay={}", delay.as_millis())?;
+        }
+        if !self.nagle {
+            write!(f, ";nagle=off")?;
+        }
+        for route in self.peer_routes.iter() {
+            write!(f, ";route={}={}", route.peers, route.destination)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stable identifier of a port forward: its name, or its position in the configuration if unnamed
+/// (local port forwards first, followed by remote port forwards).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ForwardId {
+    Name(Arc<str>),
+    Index(usize),
+}
+
+impl Display for ForwardId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{}", name),
+            Self::Index(index) => write!(f, "#{}", index),
+        }
+    }
+}
+
+impl FromStr for ForwardId {
+    type Err = anyhow::Error;
+
+    /// Parses a name, or `#<index>` like `Display`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('#') {
+            Some(index) => index
+                .parse()
+                .map(Self::Index)
+                .with_context(|| format!("Invalid port forward index: {}", s)),
+            None if s.is_empty() => Err(anyhow::anyhow!("Empty port forward name")),
+            None => Ok(Self::Name(s.into())),
+        }
+    }
+}
+
+/// Options given after the `;` of a port forward definition, as comma-separated `key[=value]` items.
+#[derive(Debug, Default)]
+struct ForwardOptions {
+    tls: Option<Arc<TlsOptions>>,
+    name: Option<Arc<str>>,
+    source_peer_ip: Option<IpAddr>,
+    selection: DestinationSelection,
+    relay: Option<DatagramRelay>,
+    session_mode: UdpSessionMode,
+    compression: Option<StreamCodec>,
+    response_filter: ResponseFilter,
+    udp_buffer_size: Option<usize>,
+    udp_packet_slots: Option<usize>,
+    max_message_size: Option<usize>,
+    listen_backlog: Option<u32>,
+    fast_open: Option<u32>,
+    reuse_port: bool,
+    listeners: Option<usize>,
+    mirror: Option<Mirror>,
+    tcp_window: Option<usize>,
+    ack_delay: Option<Duration>,
+    nagle: Option<bool>,
+    peer_routes: Vec<PeerRoute>,
+}
+
+impl ForwardOptions {
+    /// Parses the options of a port forward to the given destination host.
+    ///
+    /// Accepted options:
+    ///  - `name=<name>`: a stable identifier for the port forward. Letters, digits, `-`, `_` and `.` are allowed.
+    ///  - `tls-terminate`: accept TLS from local clients, and forward plaintext through the tunnel.
+    ///    Requires `cert=<path>` and `key=<path>` (PEM files).
+    ///  - `tls-originate`: accept plaintext from local clients, and wrap it in TLS through the tunnel.
+    ///    Accepts `server-name=<name>` (required when the destination is an IP address) and `ca=<path>`
+    ///    (PEM file of trusted roots, instead of the Mozilla root certificates).
+    ///  - `peer-ip=<ip>`: the IP of this peer to connect from, among those given to `--source-peer-ip`.
+    ///  - `round-robin` or `sticky`: with failover destinations, start from the next destination on each
+    ///    connection, or from the last one that accepted a connection, instead of the first one.
+    ///  - `broadcast` or `multicast`: relay broadcast datagrams, or the datagrams of the multicast group
+    ///    given as destination, between the local network and the tunnel.
+    ///  - `session-mode=<generic|quic|dns>`: how long the virtual port of an idle UDP client is kept.
+    ///  - `compress=lz4`: compress the data of TCP connections through the tunnel, for a peer that
+    ///    decompresses it with the same option.
+    ///  - `response-filter=<strict|loose|off>`: which sources may answer the clients of a UDP port forward.
+    ///  - `udp-buffer-size=<bytes>` and `udp-packet-slots=<count>`: how many bytes and datagrams each client of
+    ///    a UDP port forward can have queued in each direction, for bursty traffic.
+    ///  - `max-message-size=<bytes>`: the largest datagram a client of a UDP port forward may send, or the
+    ///    largest chunk of data of a TCP connection sent through the tunnel at once.
+    ///  - `backlog=<count>`, `fast-open[=<queue>]` and `reuse-port`: the options of the listening socket of a
+    ///    TCP port forward, for high accept rates.
+    ///  - `listeners=<count>`: how many sockets a TCP port forward listens on, sharing its port with
+    ///    `SO_REUSEPORT`, each with its own accept loop.
+    ///  - `mirror=<ip:port>`: copy the data of each connection of a TCP port forward to another destination
+    ///    through the tunnel. `mirror-direction=<both|outbound|inbound>` picks which side's data is copied.
+    ///  - `tcp-window=<bytes>`, `ack-delay=<ms>` and `nagle=<on|off>`: the window, acknowledgement delay and
+    ///    Nagle's algorithm of the virtual sockets of a TCP port forward, for links with a high bandwidth-delay
+    ///    product. smoltcp doesn't support TCP timestamps.
+    ///  - `route=<ip>[/<prefix>]=<ip:port>`: on remote UDP port forwards, send the datagrams of the peers in the
+    ///    range to another local destination, whose replies go back to them. Can be given more than once.
+    fn parse(s: &str, dst_host: &str) -> anyhow::Result<Self> {
+        let mut mode = None;
+        let mut cert = None;
+        let mut key = None;
+        let mut ca = None;
+        let mut server_name = None;
+        let mut forward_name = None;
+        let mut source_peer_ip = None;
+        let mut selection = None;
+        let mut relay = None;
+        let mut session_mode = UdpSessionMode::default();
+        let mut compression = None;
+        let mut response_filter = ResponseFilter::default();
+        let mut udp_buffer_size = None;
+        let mut udp_packet_slots = None;
+        let mut max_message_size = None;
+        let mut listen_backlog = None;
+        let mut fast_open = None;
+        let mut reuse_port = false;
+        let mut listeners = None;
+        let mut mirror_destination = None;
+        let mut mirror_direction = None;
+        let mut tcp_window = None;
+        let mut ack_delay = None;
+        let mut nagle = None;
+        let mut peer_routes = vec![];
+
+        for option in s.split(',').filter(|o| !o.is_empty()) {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (option, None),
+            };
+            let value = || value.with_context(|| format!("Option '{}' requires a value", name));
+            match name {
+                "tls-terminate" | "tls-originate" => {
+                    if mode.replace(name).is_some() {
+                        return Err(anyhow::anyhow!("Only one TLS mode can be given"));
+                    }
+                }
+                "cert" => cert = Some(PathBuf::from(value()?)),
+                "key" => key = Some(PathBuf::from(value()?)),
+                "ca" => ca = Some(PathBuf::from(value()?)),
+                "server-name" => server_name = Some(value()?.to_string()),
+                "name" => {
+                    let name = value()?;
+                    if name.is_empty()
+                        || !name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+                    {
+                        return Err(anyhow::anyhow!("Invalid port forward name: '{}'", name));
+                    }
+                    forward_name = Some(Arc::from(name));
+                }
+                "round-robin" | "sticky" => {
+                    let policy = if name == "sticky" {
+                        DestinationSelection::Sticky
+                    } else {
+                        DestinationSelection::RoundRobin
+                    };
+                    if selection.replace(policy).is_some() {
+                        return Err(anyhow::anyhow!(
+                            "Only one of round-robin or sticky can be given"
+                        ));
+                    }
+                }
+                "broadcast" | "multicast" => {
+                    let kind = if name == "multicast" {
+                        DatagramRelay::Multicast
+                    } else {
+                        DatagramRelay::Broadcast
+                    };
+                    if relay.replace(kind).is_some() {
+                        return Err(anyhow::anyhow!(
+                            "Only one of broadcast or multicast can be given"
+                        ));
+                    }
+                }
+                "session-mode" => session_mode = value()?.parse()?,
+                "compress" => compression = Some(value()?.parse()?),
+                "response-filter" => response_filter = value()?.parse()?,
+                "udp-buffer-size" | "udp-packet-slots" | "max-message-size" => {
+                    let count = value()?
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|count| *count > 0)
+                        .with_context(|| format!("Invalid {}: expected a positive number", name))?;
+                    match name {
+                        "udp-buffer-size" => udp_buffer_size = Some(count),
+                        "udp-packet-slots" => udp_packet_slots = Some(count),
+                        _ => max_message_size = Some(count),
+                    }
+                }
+                "backlog" => {
+                    listen_backlog = Some(
+                        value()?
+                            .parse::<u32>()
+                            .ok()
+                            .filter(|backlog| *backlog > 0)
+                            .with_context(|| "Invalid backlog: expected a positive number")?,
+                    );
+                }
+                "fast-open" if !option.contains('=') => fast_open = Some(DEFAULT_FAST_OPEN_QUEUE),
+                "fast-open" => {
+                    fast_open = Some(
+                        value()?
+                            .parse::<u32>()
+                            .ok()
+                            .filter(|queue| *queue > 0)
+                            .with_context(|| {
+                                "Invalid fast-open queue: expected a positive number"
+                            })?,
+                    );
+                }
+                "reuse-port" => reuse_port = true,
+                "listeners" => {
+                    listeners = Some(
+                        value()?
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|count| (1..=MAX_LISTENERS).contains(count))
+                            .with_context(|| {
+                                format!("Invalid listeners: expected 1 to {}", MAX_LISTENERS)
+                            })?,
+                    );
+                }
+                "mirror" => {
+                    let destination = value()?;
+                    mirror_destination = Some(
+                        destination
+                            .parse::<SocketAddr>()
+                            .with_context(|| format!("Invalid mirror: '{}'", destination))?,
+                    );
+                }
+                "mirror-direction" => mirror_direction = Some(value()?.parse()?),
+                "tcp-window" => {
+                    tcp_window = Some(
+                        value()?
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|window| (1..=MAX_TCP_WINDOW).contains(window))
+                            .with_context(|| {
+                                format!(
+                                    "Invalid tcp-window: expected 1 to {} bytes",
+                                    MAX_TCP_WINDOW
+                                )
+                            })?,
+                    );
+                }
+                "ack-delay" => {
+                    let millis = value()?
+                        .parse::<u64>()
+                        .with_context(|| "Invalid ack-delay: expected milliseconds")?;
+                    ack_delay = Some(Duration::from_millis(millis));
+                }
+                "nagle" => {
+                    nagle = Some(match value()? {
+                        "on" => true,
+                        "off" => false,
+                        other => {
+                            return Err(anyhow::anyhow!("Invalid nagle: '{}' (on or off)", other))
+                        }
+                    });
+                }
+                "route" => peer_routes.push(value()?.parse()?),
+                "peer-ip" => {
+                    let ip = value()?;
+                    source_peer_ip = Some(
+                        ip.parse::<IpAddr>()
+                            .with_context(|| format!("Invalid peer IP: '{}'", ip))?,
+                    );
+                }
+                _ => return Err(anyhow::anyhow!("Unknown option: {}", name)),
+            }
+        }
+
+        let tls = match mode {
+            Some("tls-terminate") => Some(TlsOptions::Terminate {
+                cert: cert.with_context(|| "tls-terminate requires cert=<path>")?,
+                key: key.with_context(|| "tls-terminate requires key=<path>")?,
+            }),
+            Some(_) => {
+                let server_name = match server_name {
+                    Some(server_name) => server_name,
+                    None if dst_host.parse::<IpAddr>().is_err() && !dst_host.starts_with('[') => {
+                        dst_host.to_string()
+                    }
+                    None => {
+                        return Err(anyhow::anyhow!(
+                            "tls-originate requires server-name=<name> when the destination is an IP address"
+                        ))
+                    }
+                };
+                Some(TlsOptions::Originate { server_name, ca })
+            }
+            None => {
+                if cert.is_some() || key.is_some() || ca.is_some() || server_name.is_some() {
+                    return Err(anyhow::anyhow!(
+                        "TLS options require tls-terminate or tls-originate"
+                    ));
+                }
+                None
+            }
+        };
+
+        let mirror = match (mirror_destination, mirror_direction) {
+            (Some(destination), direction) => Some(Mirror {
+                destination,
+                direction: direction.unwrap_or_default(),
+            }),
+            (None, Some(_)) => {
+                return Err(anyhow::anyhow!(
+                    "mirror-direction requires mirror=<ip:port>"
+                ))
+            }
+            (None, None) => None,
+        };
+
+        if tls.is_some() && cfg!(not(feature = "tls")) {
+            return Err(anyhow::anyhow!(
+                "onetun was built without TLS support (feature `tls`)"
+            ));
+        }
+
+        Ok(Self {
+            tls: tls.map(Arc::new),
+            name: forward_name,
+            source_peer_ip,
+            selection: selection.unwrap_or_default(),
+            relay,
+            session_mode,
+            compression,
+            response_filter,
+            udp_buffer_size,
+            udp_packet_slots,
+            max_message_size,
+            listen_backlog,
+            fast_open,
+            reuse_port,
+            listeners,
+            mirror,
+            tcp_window,
+            ack_delay,
+            nagle,
+            peer_routes,
+        })
+    }
+}
+
+/// The TLS layer of a TCP port forward.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TlsOptions {
+    /// Accept TLS from local clients, and forward the plaintext through the tunnel.
+    Terminate {
+        /// PEM file of the certificate chain presented to local clients.
+        cert: PathBuf,
+        /// PEM file of the certificate's private key.
+        key: PathBuf,
+    },
+    /// Accept plaintext from local clients, and wrap it in TLS through the tunnel.
+    Originate {
+        /// The name the destination's certificate is verified against.
+        server_name: String,
+        /// PEM file of the trusted root certificates. Defaults to the Mozilla root certificates.
+        ca: Option<PathBuf>,
+    },
+}
+
+/// Layer 7 protocols for ports.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum PortProtocol {
+    /// TCP
+    Tcp,
+    /// UDP
+    Udp,
+}
+
+impl TryFrom<&str> for PortProtocol {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> anyhow::Result<Self> {
+        match value.to_uppercase().as_str() {
+            "TCP" => Ok(Self::Tcp),
+            "UDP" => Ok(Self::Udp),
+            _ => Err(anyhow::anyhow!("Invalid protocol specifier: {}", value)),
+        }
+    }
+}
+
+impl Display for PortProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Tcp => "TCP",
+                Self::Udp => "UDP",
+            }
+        )
+    }
+}
+
+/// An IP range, in CIDR notation. A single IP is a range of one.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct AllowedIp {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl AllowedIp {
+    /// Creates the range of the IPs sharing the first `prefix_len` bits of `addr`.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> anyhow::Result<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return Err(anyhow::anyhow!(
+                "Prefix length {} is longer than {} bits",
+                prefix_len,
+                max
+            ));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// The ranges containing every IPv4 and IPv6 address.
+    pub fn any() -> Vec<Self> {
+        vec![
+            Self {
+                addr: Ipv4Addr::UNSPECIFIED.into(),
+                prefix_len: 0,
+            },
+            Self {
+                addr: Ipv6Addr::UNSPECIFIED.into(),
+                prefix_len: 0,
+            },
+        ]
+    }
+
+    /// The first IP of the range.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// How many leading bits the IPs of the range share.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether the IP is in this range.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(addr) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(addr) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for AllowedIp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid IP range: '{}'", s))?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid prefix length: '{}'", s))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl Display for AllowedIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// The /96 prefix a NAT64 gateway of the peer network translates to IPv4 (RFC 6052), such as the well-known
+/// `64:ff9b::/96`: the IPv4 address is embedded in its last 32 bits.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Nat64Prefix(Ipv6Addr);
+
+impl Nat64Prefix {
+    /// The well-known prefix, `64:ff9b::/96`.
+    pub const WELL_KNOWN: Self = Self(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0));
+
+    /// The prefix of the given address, whose last 32 bits must be zero.
+    pub fn new(prefix: Ipv6Addr) -> anyhow::Result<Self> {
+        if u128::from(prefix) as u32 != 0 {
+            return Err(anyhow::anyhow!(
+                "NAT64 prefix {} has bits set beyond its first 96",
+                prefix
+            ));
+        }
+        Ok(Self(prefix))
+    }
+
+    /// The IPv6 address of the IPv4 address through the gateway.
+    pub fn embed(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.0) | u32::from(ip) as u128)
+    }
+}
+
+impl FromStr for Nat64Prefix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let range = AllowedIp::from_str(s)?;
+        match range.addr() {
+            IpAddr::V6(prefix) if range.prefix_len() == 96 || !s.contains('/') => Self::new(prefix),
+            _ => Err(anyhow::anyhow!(
+                "Invalid NAT64 prefix: '{}', expected an IPv6 /96 such as 64:ff9b::/96",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Nat64Prefix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/96", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("0").unwrap(), vec![0]);
+        assert_eq!(parse_cpu_list("0, 2-4").unwrap(), vec![0, 2, 3, 4]);
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    /// Tests the parsing of durations.
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("10s").unwrap(), Duration::from_secs(10));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10h").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
+    /// Tests the conversion of a configuration into command-line options.
+    #[test]
+    #[cfg(feature = "bin")]
+    fn test_config_options() {
+        let content = "# Tunnel\n\
+            endpoint-addr = 1.2.3.4:51820\n\
+            private-key = 52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I=\n\
+            \n\
+            forward=8080:192.168.4.1:8081\n\
+            allow-roaming\n";
+        let (options, private_key) = config_options(content).unwrap();
+        assert_eq!(
+            options,
+            vec![
+                "--endpoint-addr=1.2.3.4:51820",
+                "--forward=8080:192.168.4.1:8081",
+                "--allow-roaming"
+            ]
+        );
+        assert_eq!(
+            private_key.unwrap().as_str().unwrap(),
+            "52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I="
+        );
+        assert!(config_options("--allow-roaming").is_err());
+        assert!(config_options("config = other.conf").is_err());
+
+        let path = std::env::temp_dir().join(format!("onetun-config-{}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let args = vec![
+            OsString::from("onetun"),
+            OsString::from("--config"),
+            path.clone().into_os_string(),
+            OsString::from("--log=debug"),
+        ];
+        let (expanded, private_key) = expand_config_args(args).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(expanded.len(), 7);
+        assert_eq!(expanded[3], "--endpoint-addr=1.2.3.4:51820");
+        assert_eq!(expanded[6], "--log=debug");
+        assert!(private_key.is_some());
+
+        let secret = read_secret(&b"key\n"[..]).unwrap();
+        assert_eq!(secret.as_str().unwrap(), "key\n");
+        assert!(read_secret(&vec![0u8; MAX_SECRET_SIZE + 1][..]).is_err());
+    }
+
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_1() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "192.168.0.1:8080:192.168.4.1:8081:TCP,UDP",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![
+                PortForwardConfig::new(
+                    SocketAddr::from_str("192.168.0.1:8080").unwrap(),
+                    SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                    PortProtocol::Tcp
+                ),
+                PortForwardConfig::new(
+                    SocketAddr::from_str("192.168.0.1:8080").unwrap(),
+                    SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                    PortProtocol::Udp
+                )
+            ]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_2() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "192.168.0.1:8080:192.168.4.1:8081:TCP",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig::new(
+                SocketAddr::from_str("192.168.0.1:8080").unwrap(),
+                SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                PortProtocol::Tcp
+            )]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_3() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "0.0.0.0:8080:192.168.4.1:8081",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig::new(
+                SocketAddr::from_str("0.0.0.0:8080").unwrap(),
+                SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                PortProtocol::Tcp
+            )]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_4() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "[::1]:8080:192.168.4.1:8081",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig::new(
+                SocketAddr::from_str("[::1]:8080").unwrap(),
+                SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                PortProtocol::Tcp
+            )]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_5() {
+        assert_eq!(
+            PortForwardConfig::from_notation("8080:192.168.4.1:8081", DEFAULT_PORT_FORWARD_SOURCE)
+                .expect("Failed to parse"),
+            vec![PortForwardConfig::new(
+                SocketAddr::from_str("127.0.0.1:8080").unwrap(),
+                SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                PortProtocol::Tcp
+            )]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_6() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "8080:192.168.4.1:8081:TCP",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig::new(
+                SocketAddr::from_str("127.0.0.1:8080").unwrap(),
+                SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                PortProtocol::Tcp
+            )]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_7() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "localhost:8080:192.168.4.1:8081",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig::new(
+                "localhost:8080".to_socket_addrs().unwrap().next().unwrap(),
+                SocketAddr::from_str("192.168.4.1:8081").unwrap(),
+                PortProtocol::Tcp
+            )]
+        );
+    }
+    /// Tests the parsing of `PortForwardConfig`.
+    #[test]
+    fn test_parse_port_forward_config_8() {
+        assert_eq!(
+            PortForwardConfig::from_notation(
+                "localhost:8080:localhost:8081:TCP",
+                DEFAULT_PORT_FORWARD_SOURCE
+            )
+            .expect("Failed to parse"),
+            vec![PortForwardConfig::new(
+                "localhost:8080".to_socket_addrs().unwrap().next().unwrap(),
+                "localhost:8081".to_socket_addrs().unwrap().next().unwrap(),
+                PortProtocol::Tcp
+            )]
+        );
+    }
+
+    /// Tests the parsing of port forward options.
+    #[test]
+    fn test_parse_port_forward_options() {
+        let options = ForwardOptions::parse("", "192.168.4.1").expect("Failed to parse");
+        assert!(options.tls.is_none());
+        assert!(ForwardOptions::parse("unknown", "192.168.4.1").is_err());
+        assert!(ForwardOptions::parse("cert=cert.pem", "192.168.4.1").is_err());
+        assert!(ForwardOptions::parse("tls-terminate,cert=cert.pem", "192.168.4.1").is_err());
+        assert!(ForwardOptions::parse("tls-originate", "192.168.4.1").is_err());
+        assert!(
+            ForwardOptions::parse("tls-terminate,tls-originate,server-name=a", "a.internal")
+                .is_err()
+        );
+        assert!(PortForwardConfig::from_notation(
+            "8080:192.168.4.1:8081:UDP;tls-originate,server-name=peer.intranet",
+            DEFAULT_PORT_FORWARD_SOURCE
+        )
+        .is_err());
+    }
+
+    /// Tests the parsing of named port forwards.
+    #[test]
+    fn test_parse_port_forward_config_name() {
+        let pf = PortForwardConfig::from_notation(
+            "8080:192.168.4.1:8081;name=web",
+            DEFAULT_PORT_FORWARD_SOURCE,
+        )
+        .expect("Failed to parse");
+        assert_eq!(pf[0].name.as_deref(), Some("web"));
+        assert_eq!(pf[0].id(3), ForwardId::Name("web".into()));
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:8080:192.168.4.1:8081:TCP;name=web"
+        );
+        assert!(PortForwardConfig::from_notation(
+            "8080:192.168.4.1:8081;name=",
+            DEFAULT_PORT_FORWARD_SOURCE
+        )
+        .is_err());
+        assert!(PortForwardConfig::from_notation(
+            "8080:192.168.4.1:8081;name=a b",
+            DEFAULT_PORT_FORWARD_SOURCE
+        )
+        .is_err());
+    }
+
+    /// Tests the parsing of port forwards with failover destinations.
+    #[test]
+    fn test_parse_port_forward_config_failover() {
+        let pf = PortForwardConfig::from_notation(
+            "127.0.0.1:8080:[10.0.0.2:80,10.0.0.3:80]:TCP;round-robin",
+            DEFAULT_PORT_FORWARD_SOURCE,
+        )
+        .expect("Failed to parse");
+        assert_eq!(pf.len(), 1);
+        assert_eq!(pf[0].destination, SocketAddr::from(([10, 0, 0, 2], 80)));
+        assert_eq!(
+            pf[0].failover_destinations,
+            vec![SocketAddr::from(([10, 0, 0, 3], 80))]
+        );
+        assert_eq!(pf[0].selection, DestinationSelection::RoundRobin);
+        assert_eq!(
+            pf[0].destinations().collect::<Vec<_>>(),
+            vec![
+                SocketAddr::from(([10, 0, 0, 2], 80)),
+                SocketAddr::from(([10, 0, 0, 3], 80))
+            ]
+        );
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:8080:[10.0.0.2:80,10.0.0.3:80]:TCP;round-robin"
+        );
+        assert!(PortForwardConfig::from_notation(
+            "8080:[10.0.0.2:80,10.0.0.3:80]:UDP",
+            DEFAULT_PORT_FORWARD_SOURCE
+        )
+        .is_err());
+        assert!(PortForwardConfig::from_notation(
+            "8080:[10.0.0.2:80,10.0.0.3:80];round-robin,sticky",
+            DEFAULT_PORT_FORWARD_SOURCE
+        )
+        .is_err());
+    }
+
+    /// Tests the parsing of broadcast and multicast relays.
+    #[test]
+    fn test_parse_port_forward_config_relay() {
+        let pf = PortForwardConfig::from_notation(
+            "0.0.0.0:1900:239.255.255.250:1900:UDP;multicast",
+            DEFAULT_PORT_FORWARD_SOURCE,
+        )
+        .expect("Failed to parse");
+        assert_eq!(pf[0].relay, Some(DatagramRelay::Multicast));
+        assert_eq!(
+            pf[0].to_string(),
+            "0.0.0.0:1900:239.255.255.250:1900:UDP;multicast"
+        );
+        let pf = PortForwardConfig::from_notation(
+            "0.0.0.0:27015:192.168.4.255:27015:UDP;broadcast",
+            DEFAULT_PORT_FORWARD_SOURCE,
+        )
+        .expect("Failed to parse");
+        assert_eq!(pf[0].relay, Some(DatagramRelay::Broadcast));
+
+        for invalid in [
+            "0.0.0.0:1900:239.255.255.250:1900:TCP;multicast",
+            "0.0.0.0:1900:239.255.255.250:1900:UDP",
+            "0.0.0.0:1900:192.168.4.1:1900:UDP;multicast",
+            "0.0.0.0:1900:255.255.255.255:1900:UDP",
+            "0.0.0.0:1900:[ff02::c]:1900:UDP;broadcast",
+            "0.0.0.0:1900:239.255.255.250:1900:UDP;multicast,broadcast",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_port_forward_config_session_mode() {
+        let pf = PortForwardConfig::from_notation(
+            "127.0.0.1:443:192.168.4.1:443:UDP;session-mode=quic",
+            DEFAULT_PORT_FORWARD_SOURCE,
+        )
+        .expect("Failed to parse");
+        assert_eq!(pf[0].session_mode, UdpSessionMode::Quic);
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:443:192.168.4.1:443:UDP;session-mode=quic"
+        );
+        assert_eq!(
+            forwards("53:192.168.4.1:53:UDP;session-mode=dns")[0].session_mode,
+            UdpSessionMode::Dns
+        );
+        assert_eq!(
+            forwards("53:192.168.4.1:53:UDP")[0].session_mode,
+            UdpSessionMode::Generic
+        );
+
+        for invalid in [
+            "443:192.168.4.1:443:TCP;session-mode=quic",
+            "443:192.168.4.1:443:UDP;session-mode=http",
+            "443:192.168.4.1:443:UDP;session-mode",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_port_forward_config_listener_options() {
+        let pf = forwards("8080:192.168.4.2:8080;backlog=4096,fast-open,reuse-port");
+        assert_eq!(pf[0].listen_backlog, Some(4096));
+        assert_eq!(pf[0].fast_open, Some(DEFAULT_FAST_OPEN_QUEUE));
+        assert!(pf[0].reuse_port);
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:8080:192.168.4.2:8080:TCP;backlog=4096;fast-open=256;reuse-port"
+        );
+        assert_eq!(
+            forwards("8080:192.168.4.2:8080;fast-open=16")[0].fast_open,
+            Some(16)
+        );
+
+        let pf = forwards("8080:192.168.4.2:8080;listeners=4");
+        assert_eq!(pf[0].listeners, 4);
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:8080:192.168.4.2:8080:TCP;listeners=4"
+        );
+        assert_eq!(forwards("8080:192.168.4.2:8080")[0].listeners, 1);
+
+        for invalid in [
+            "53:192.168.4.1:53:UDP;reuse-port",
+            "8080:192.168.4.2:8080;backlog=0",
+            "8080:192.168.4.2:8080;fast-open=",
+            "8080:192.168.4.2:8080;listeners=0",
+            "8080:192.168.4.2:8080;listeners=1000",
+            "53:192.168.4.1:53:UDP;listeners=2",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_port_forward_config_mirror() {
+        let pf = forwards("8080:192.168.4.2:8080;mirror=192.168.4.9:9000");
+        assert_eq!(
+            pf[0].mirror,
+            Some(Mirror {
+                destination: SocketAddr::from(([192, 168, 4, 9], 9000)),
+                direction: MirrorDirection::Both,
+            })
+        );
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:8080:192.168.4.2:8080:TCP;mirror=192.168.4.9:9000"
+        );
+
+        let pf = forwards("8080:192.168.4.2:8080;mirror=[fd00::9]:9000,mirror-direction=outbound");
+        assert_eq!(
+            pf[0].mirror.map(|mirror| mirror.direction),
+            Some(MirrorDirection::Outbound)
+        );
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:8080:192.168.4.2:8080:TCP;mirror=[fd00::9]:9000;mirror-direction=outbound"
+        );
+
+        for invalid in [
+            "8080:192.168.4.2:8080;mirror=ids.internal:9000",
+            "8080:192.168.4.2:8080;mirror-direction=inbound",
+            "8080:192.168.4.2:8080;mirror=192.168.4.9:9000,mirror-direction=sideways",
+            "53:192.168.4.1:53:UDP;mirror=192.168.4.9:9000",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_forward_id() {
+        assert_eq!(ForwardId::from_str("#3").unwrap(), ForwardId::Index(3));
+        assert_eq!(
+            ForwardId::from_str("web").unwrap(),
+            ForwardId::Name("web".into())
+        );
+        assert!(ForwardId::from_str("#web").is_err());
+        assert!(ForwardId::from_str("").is_err());
+        for id in [ForwardId::Index(0), ForwardId::Name("db".into())] {
+            assert_eq!(ForwardId::from_str(&id.to_string()).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn test_parse_port_forward_config_udp_buffers() {
+        let pf = forwards(
+            "127.0.0.1:51820:192.168.4.1:51820:UDP;udp-buffer-size=1048576,udp-packet-slots=256",
+        );
+        assert_eq!(pf[0].udp_buffer_size, Some(1048576));
+        assert_eq!(pf[0].udp_packet_slots, Some(256));
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:51820:192.168.4.1:51820:UDP;udp-buffer-size=1048576;udp-packet-slots=256"
+        );
+        assert_eq!(forwards("53:192.168.4.1:53:UDP")[0].udp_buffer_size, None);
+
+        for invalid in [
+            "53:192.168.4.1:53:TCP;udp-buffer-size=1024",
+            "53:192.168.4.1:53:UDP;udp-packet-slots=0",
+            "53:192.168.4.1:53:UDP;udp-buffer-size=big",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_port_forward_config_max_message_size() {
+        let pf = forwards("5353:192.168.4.1:53:TCP,UDP;max-message-size=1200");
+        assert_eq!(pf.len(), 2);
+        assert!(pf.iter().all(|pf| pf.max_message_size == Some(1200)));
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:5353:192.168.4.1:53:TCP;max-message-size=1200"
+        );
+        assert_eq!(forwards("53:192.168.4.1:53:UDP")[0].max_message_size, None);
+        assert!(PortForwardConfig::from_notation(
+            "53:192.168.4.1:53:UDP;max-message-size=0",
+            DEFAULT_PORT_FORWARD_SOURCE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_port_forward_config_tcp_tuning() {
+        let pf = forwards("8080:192.168.4.1:80;tcp-window=4194304,ack-delay=0,nagle=off");
+        assert_eq!(pf[0].tcp_window, Some(4194304));
+        assert_eq!(pf[0].ack_delay, Some(Duration::ZERO));
+        assert!(!pf[0].nagle);
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:8080:192.168.4.1:80:TCP;tcp-window=4194304;ack-delay=0;nagle=off"
+        );
+        assert!(forwards("8080:192.168.4.1:80")[0].nagle);
+
+        for invalid in [
+            "53:192.168.4.1:53:UDP;nagle=off",
+            "8080:192.168.4.1:80;tcp-window=0",
+            "8080:192.168.4.1:80;tcp-window=2147483648",
+            "8080:192.168.4.1:80;nagle=maybe",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_port_forward_config_peer_routes() {
+        let pf = forwards(
+            "5000:127.0.0.1:5000:UDP;route=192.168.4.5=127.0.0.1:5001,route=192.168.5.0/24=127.0.0.1:5002",
+        );
+        assert_eq!(pf[0].peer_routes.len(), 2);
+        assert_eq!(
+            pf[0].peer_destination("192.168.4.5".parse().unwrap()),
+            "127.0.0.1:5001".parse().unwrap()
+        );
+        assert_eq!(
+            pf[0].peer_destination("192.168.5.9".parse().unwrap()),
+            "127.0.0.1:5002".parse().unwrap()
+        );
+        assert_eq!(
+            pf[0].peer_destination("192.168.4.6".parse().unwrap()),
+            "127.0.0.1:5000".parse().unwrap()
+        );
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:5000:127.0.0.1:5000:UDP;route=192.168.4.5/32=127.0.0.1:5001;route=192.168.5.0/24=127.0.0.1:5002"
+        );
+
+        for invalid in [
+            "5000:127.0.0.1:5000:TCP;route=192.168.4.5=127.0.0.1:5001",
+            "5000:127.0.0.1:5000:UDP;route=192.168.4.5",
+            "5000:127.0.0.1:5000:UDP;route=192.168.4.5=127.0.0.1:5000",
+            "5000:127.0.0.1:5000:UDP;route=192.168.4.5=[::1]:5001",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_port_forward_config_response_filter() {
+        let pf = forwards("127.0.0.1:53:192.168.4.1:53:UDP;response-filter=strict");
+        assert_eq!(pf[0].response_filter, ResponseFilter::Strict);
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:53:192.168.4.1:53:UDP;response-filter=strict"
+        );
+        assert_eq!(
+            forwards("53:192.168.4.1:53:UDP;response-filter=off")[0].response_filter,
+            ResponseFilter::Off
+        );
+        assert_eq!(
+            forwards("53:192.168.4.1:53:UDP")[0].response_filter,
+            ResponseFilter::Loose
+        );
+
+        for invalid in [
+            "53:192.168.4.1:53:TCP;response-filter=strict",
+            "53:192.168.4.1:53:UDP;response-filter=reverse",
+            "0.0.0.0:1900:239.255.255.250:1900:UDP;multicast,response-filter=strict",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_port_forward_config_compression() {
+        let pf = forwards("8080:192.168.4.1:80;compress=lz4");
+        assert_eq!(pf[0].compression, Some(StreamCodec::Lz4));
+        assert_eq!(
+            pf[0].to_string(),
+            "127.0.0.1:8080:192.168.4.1:80:TCP;compress=lz4"
+        );
+
+        for invalid in [
+            "53:192.168.4.1:53:UDP;compress=lz4",
+            "8080:192.168.4.1:80;compress=zstd",
+            "8080:192.168.4.1:80;compress",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
 
-impl PortForwardConfig {
-    /// Creates a new PortForwardConfig
-    pub fn new(source: SocketAddr, destination: SocketAddr, protocol: PortProtocol) -> Self {
-        Self {
-            source,
-            destination,
-            protocol,
-            remote: false,
+    #[test]
+    fn test_parse_port_forward_config_syntax() {
+        for (notation, expected) in [
+            ("[::1]:8080:[::2]:80", &["[::1]:8080:[::2]:80:TCP"][..]),
+            (
+                "8080:192.168.4.1:80:TCP+UDP",
+                &[
+                    "127.0.0.1:8080:192.168.4.1:80:TCP",
+                    "127.0.0.1:8080:192.168.4.1:80:UDP",
+                ],
+            ),
+            (
+                "8080:192.168.4.1:80:udp,tcp",
+                &[
+                    "127.0.0.1:8080:192.168.4.1:80:UDP",
+                    "127.0.0.1:8080:192.168.4.1:80:TCP",
+                ],
+            ),
+            (
+                "[fe80::1%3]:80:192.168.4.1:80",
+                &["[fe80::1%3]:80:192.168.4.1:80:TCP"],
+            ),
+            (
+                "8080:192.168.4.1:80:TCP;name=web",
+                &["127.0.0.1:8080:192.168.4.1:80:TCP;name=web"],
+            ),
+        ] {
+            let parsed: Vec<String> = forwards(notation).iter().map(|pf| pf.to_string()).collect();
+            assert_eq!(parsed, expected, "{}", notation);
+        }
+        #[cfg(target_os = "linux")]
+        assert_ne!(
+            forwards("[fe80::1%lo]:80:192.168.4.1:80")[0]
+                .source
+                .to_string(),
+            "[fe80::1%0]:80"
+        );
+
+        for (notation, column) in [
+            ("", 1),
+            ("8080:192.168.4.1", 17),
+            ("8080:192.168.4.1:x", 18),
+            ("8080:[::1:80", 6),
+            ("8080:192.168.4.1:80 TCP", 20),
+            ("8080:192.168.4.1:80:", 20),
+            ("8080:192.168.4.1:80:TCP+", 24),
+        ] {
+            let e = PortForwardConfig::from_notation(notation, DEFAULT_PORT_FORWARD_SOURCE)
+                .expect_err(notation)
+                .to_string();
+            assert!(
+                e.contains(&format!("at column {}:", column)),
+                "{}: {}",
+                notation,
+                e
+            );
+        }
+        for invalid in [
+            "[fe80::1%1]:80:[fe80::2%1]:80",
+            "127.0.0.1%1:80:192.168.4.1:80",
+            "[::1%unknown0]:80:192.168.4.1:80",
+            "8080:192.168.4.1:99999",
+            "8080:192.168.4.1:80:SCTP",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
         }
     }
 
-    /// Converts a string representation into `PortForwardConfig`.
-    ///
-    /// Sample formats:
-    ///  - `127.0.0.1:8080:192.168.4.1:8081:TCP,UDP`
-    ///  - `127.0.0.1:8080:192.168.4.1:8081:TCP`
-    ///  - `0.0.0.0:8080:192.168.4.1:8081`
-    ///  - `[::1]:8080:192.168.4.1:8081`
-    ///  - `8080:192.168.4.1:8081`
-    ///  - `8080:192.168.4.1:8081:TCP`
-    ///  - `localhost:8080:192.168.4.1:8081:TCP`
-    ///  - `localhost:8080:peer.intranet:8081:TCP`
-    ///
-    /// Implementation Notes:
-    ///  - The format is formalized as `[src_host:]<src_port>:<dst_host>:<dst_port>[:PROTO1,PROTO2,...]`
-    ///  - `src_host` is optional and defaults to `127.0.0.1`.
-    ///  - `src_host` and `dst_host` may be specified as IPv4, IPv6, or a FQDN to be resolved by DNS.
-    ///  - IPv6 addresses must be prefixed with `[` and suffixed with `]`. Example: `[::1]`.
-    ///  - Any `u16` is accepted as `src_port` and `dst_port`
-    ///  - Specifying protocols (`PROTO1,PROTO2,...`) is optional and defaults to `TCP`. Values must be separated by commas.
-    pub fn from_notation(s: &str, default_source: &str) -> anyhow::Result<Vec<PortForwardConfig>> {
-        mod parsers {
-            use nom::branch::alt;
-            use nom::bytes::complete::is_not;
-            use nom::character::complete::{alpha1, char, digit1};
-            use nom::combinator::{complete, map, opt, success};
-            use nom::error::ErrorKind;
-            use nom::multi::separated_list1;
-            use nom::sequence::{delimited, preceded, separated_pair, tuple};
-            use nom::IResult;
-
-            fn ipv6(s: &str) -> IResult<&str, &str> {
-                delimited(char('['), is_not("]"), char(']'))(s)
-            }
-
-            fn ipv4_or_fqdn(s: &str) -> IResult<&str, &str> {
-                let s = is_not(":")(s)?;
-                if s.1.chars().all(|c| c.is_ascii_digit()) {
-                    // If ipv4 or fqdn is all digits, it's not valid.
-                    Err(nom::Err::Error(nom::error::ParseError::from_error_kind(
-                        s.1,
-                        ErrorKind::Fail,
-                    )))
-                } else {
-                    Ok(s)
+    /// Mutations of valid definitions are parsed or rejected with an error, never with a panic.
+    #[test]
+    fn test_parse_notation_mutations() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let valid = [
+            "127.0.0.1:8080:192.168.4.1:8081:TCP,UDP",
+            "[::1]:8080:[192.168.4.1:80,peer.intranet:80]:TCP;round-robin",
+            "0.0.0.0:1900:239.255.255.250:1900:UDP;multicast",
+            "[fe80::1%eth0]:53:192.168.4.1:53:UDP+TCP;session-mode=dns,name=dns",
+            "8443:192.168.4.1:8081;tls-terminate,cert=cert.pem,key=key.pem",
+        ];
+        let alphabet: Vec<char> = "0123456789:.[],;=+%/TCPUDtcpu -".chars().collect();
+        let mut rng = StdRng::seed_from_u64(1854);
+        for _ in 0..20000 {
+            let mut notation: Vec<char> = valid[rng.gen_range(0..valid.len())].chars().collect();
+            for _ in 0..rng.gen_range(1..4) {
+                if notation.is_empty() {
+                    break;
+                }
+                let i = rng.gen_range(0..notation.len());
+                match rng.gen_range(0..3) {
+                    0 => {
+                        notation.remove(i);
+                    }
+                    1 => notation.insert(i, alphabet[rng.gen_range(0..alphabet.len())]),
+                    _ => notation.truncate(i),
                 }
             }
-
-            fn port(s: &str) -> IResult<&str, &str> {
-                digit1(s)
+            let notation: String = notation.into_iter().collect();
+            if let Ok((_, dst_addrs, _, Some(options))) = parse_notation(&notation) {
+                // The certificates are only loaded when both are given
+                if !options.contains("cert=") || !options.contains("key=") {
+                    ForwardOptions::parse(options, dst_addrs[0].0).ok();
+                }
             }
+        }
+    }
 
-            fn ip_or_fqdn(s: &str) -> IResult<&str, &str> {
-                alt((ipv6, ipv4_or_fqdn))(s)
-            }
+    #[test]
+    fn test_parse_port_forward_config_named_pipe() {
+        let pf = forwards("npipe:////./pipe/mydb:192.168.4.1:5432:TCP");
+        assert_eq!(pf.len(), 1);
+        assert_eq!(pf[0].pipe.as_deref(), Some(r"\\.\pipe\mydb"));
+        assert_eq!(pf[0].destination, "192.168.4.1:5432".parse().unwrap());
+        assert_eq!(
+            pf[0].to_string(),
+            "npipe:////./pipe/mydb:192.168.4.1:5432:TCP"
+        );
+        if cfg!(not(windows)) {
+            assert_eq!(
+                validated(pf, "192.168.4.3", 1420),
+                Err(ConfigError::NamedPipesUnsupported(r"\\.\pipe\mydb".into()))
+            );
+        }
 
-            fn no_ip(s: &str) -> IResult<&str, Option<&str>> {
-                success(None)(s)
-            }
+        for invalid in [
+            "npipe:////./pipe/mydb:192.168.4.1:53:UDP",
+            "npipe:////./pipe/mydb:192.168.4.1:443;tls-originate",
+            "npipe://./mydb:192.168.4.1:5432",
+            "npipe:mydb:192.168.4.1:5432",
+        ] {
+            assert!(
+                PortForwardConfig::from_notation(invalid, DEFAULT_PORT_FORWARD_SOURCE).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
 
-            fn src_addr(s: &str) -> IResult<&str, (Option<&str>, &str)> {
-                let with_ip = separated_pair(map(ip_or_fqdn, Some), char(':'), port);
-                let without_ip = tuple((no_ip, port));
-                alt((with_ip, without_ip))(s)
-            }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_port_forward_config_interface() {
+        let pf = forwards("lo:8080:192.168.4.1:80");
+        assert_eq!(pf.len(), 1);
+        assert_eq!(pf[0].interface.as_deref(), Some("lo"));
+        assert_eq!(pf[0].source, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(pf[0].to_string(), "lo:8080:192.168.4.1:80:TCP");
+    }
 
-            fn dst_addr(s: &str) -> IResult<&str, (&str, &str)> {
-                separated_pair(ip_or_fqdn, char(':'), port)(s)
-            }
+    fn validated(
+        port_forwards: Vec<PortForwardConfig>,
+        source_peer_ip: &str,
+        mtu: usize,
+    ) -> Result<Vec<ConfigWarning>, ConfigError> {
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let config = Config::builder()
+            .port_forwards(port_forwards)
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr(SocketAddr::from_str("127.0.0.1:51820").unwrap())
+            .source_peer_ip(IpAddr::from_str(source_peer_ip).unwrap())
+            .mtu(mtu)
+            .build()
+            .map_err(|e| match e {
+                OnetunError::Config(e) => e.downcast::<ConfigError>().unwrap(),
+                e => panic!("{:?}", e),
+            })?;
+        config.validate()
+    }
 
-            fn protocol(s: &str) -> IResult<&str, &str> {
-                alpha1(s)
-            }
+    fn forwards(notation: &str) -> Vec<PortForwardConfig> {
+        PortForwardConfig::from_notation(notation, DEFAULT_PORT_FORWARD_SOURCE).unwrap()
+    }
 
-            fn protocols(s: &str) -> IResult<&str, Option<Vec<&str>>> {
-                opt(preceded(char(':'), separated_list1(char(','), protocol)))(s)
-            }
+    /// Tests the required settings and defaults of the configuration builder.
+    #[test]
+    fn test_config_builder() {
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let builder = Config::builder()
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr(SocketAddr::from_str("127.0.0.1:51820").unwrap());
+        assert!(builder.clone().build().is_err());
+        assert!(builder
+            .clone()
+            .source_peer_ip(IpAddr::from_str("192.168.4.3").unwrap())
+            .private_key("invalid")
+            .build()
+            .is_err());
 
-            #[allow(clippy::type_complexity)]
-            pub fn port_forward(
-                s: &str,
-            ) -> IResult<&str, ((Option<&str>, &str), (), (&str, &str), Option<Vec<&str>>)>
-            {
-                complete(tuple((
-                    src_addr,
-                    map(char(':'), |_| ()),
-                    dst_addr,
-                    protocols,
-                )))(s)
-            }
-        }
-
-        // TODO: Could improve error management with custom errors, so that the messages are more helpful.
-        let (src_addr, _, dst_addr, protocols) = parsers::port_forward(s)
-            .map_err(|e| anyhow::anyhow!("Invalid port-forward definition: {}", e))?
-            .1;
-
-        let source = (
-            src_addr.0.unwrap_or(default_source),
-            src_addr
-                .1
-                .parse::<u16>()
-                .with_context(|| "Invalid source port")?,
-        )
-            .to_socket_addrs()
-            .with_context(|| "Invalid source address")?
-            .next()
-            .with_context(|| "Could not resolve source address")?;
-
-        let destination = (
-            dst_addr.0,
-            dst_addr
-                .1
-                .parse::<u16>()
-                .with_context(|| "Invalid source port")?,
-        )
-            .to_socket_addrs() // TODO: Pass this as given and use DNS config instead (issue #15)
-            .with_context(|| "Invalid destination address")?
-            .next()
-            .with_context(|| "Could not resolve destination address")?;
+        let config = builder
+            .clone()
+            .source_peer_ip(IpAddr::from_str("192.168.4.3").unwrap())
+            .port_forwards(forwards("8080:192.168.4.1:8081"))
+            .keepalive(25)
+            .build()
+            .unwrap();
+        assert_eq!(config.port_forwards.len(), 1);
+        assert_eq!(config.keepalive_seconds, Some(25));
+        assert_eq!(config.max_transmission_unit, DEFAULT_MTU);
+        assert_eq!(config.log, "info");
+        assert!(!config.skip_logger_init);
+        assert!(config.warnings().is_empty());
 
-        // Parse protocols
-        let protocols = if let Some(protocols) = protocols {
-            let protocols: anyhow::Result<Vec<PortProtocol>> =
-                protocols.into_iter().map(PortProtocol::try_from).collect();
-            protocols
-        } else {
-            Ok(vec![PortProtocol::Tcp])
-        }
-        .with_context(|| "Failed to parse protocols")?;
+        // The configuration is validated when it is built, keeping the warnings
+        let builder = builder.source_peer_ip(IpAddr::from_str("192.168.4.3").unwrap());
+        assert!(builder
+            .clone()
+            .port_forwards(forwards("8080:192.168.4.1:8081"))
+            .mtu(100)
+            .build()
+            .is_err());
+        let mut duplicate = forwards("8080:192.168.4.1:8081");
+        duplicate.extend(forwards("8080:192.168.4.2:8081"));
+        assert!(builder.clone().port_forwards(duplicate).build().is_err());
+        let to_ipv6 = forwards("8080:[fd00::1]:8081");
+        assert_eq!(
+            builder
+                .clone()
+                .port_forwards(to_ipv6.clone())
+                .build()
+                .unwrap()
+                .warnings(),
+            [ConfigWarning::AddressFamilyMismatch(to_ipv6[0].clone())]
+        );
+        // Unless the settings of the configuration may still resolve the error
+        let in_process = builder.build().unwrap().with_in_process_connections();
+        assert_eq!(in_process.validate(), Ok(vec![]));
 
-        // Returns an config for each protocol
-        Ok(protocols
-            .into_iter()
-            .map(|protocol| Self {
-                source,
-                destination,
-                protocol,
-                remote: false,
-            })
-            .collect())
-    }
-}
+        // Remote control requires a control token
+        let config = config.with_remote_control();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::RemoteControlWithoutToken)
+        ));
+        let token = ControlToken::new("0123456789abcdef").unwrap();
+        let config = config.with_control_token(token);
+        assert!(config.validate().is_ok());
 
-impl Display for PortForwardConfig {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        if self.remote {
-            write!(
-                f,
-                "(remote){}:{}:{}",
-                self.source, self.destination, self.protocol
-            )
+        // The control socket stays on loopback unless remote control is allowed
+        let mut config = config.with_control_socket("0.0.0.0:9000".parse().unwrap());
+        assert!(config.validate().is_ok());
+        config.remote_control = false;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::ControlSocketNotLoopback(_))
+        ));
+        let config = config.with_control_socket("127.0.0.1:9000".parse().unwrap());
+        assert!(config.validate().is_ok());
+
+        // So does the gRPC control service
+        let grpc = config
+            .clone()
+            .with_grpc_listen("0.0.0.0:9001".parse().unwrap());
+        if cfg!(feature = "grpc") {
+            assert!(matches!(
+                grpc.validate(),
+                Err(ConfigError::GrpcNotLoopback(_))
+            ));
+            assert!(config
+                .with_grpc_listen("[::1]:9001".parse().unwrap())
+                .validate()
+                .is_ok());
         } else {
-            write!(f, "{}:{}:{}", self.source, self.destination, self.protocol)
+            assert!(matches!(
+                grpc.validate(),
+                Err(ConfigError::FeatureDisabled("grpc"))
+            ));
         }
     }
-}
 
-/// Layer 7 protocols for ports.
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
-pub enum PortProtocol {
-    /// TCP
-    Tcp,
-    /// UDP
-    Udp,
-}
+    /// Tests the validation of the configuration.
+    #[test]
+    fn test_validate_config() {
+        let warnings = validated(forwards("8080:192.168.4.1:8081"), "192.168.4.3", 1420).unwrap();
+        assert!(warnings.is_empty());
 
-impl TryFrom<&str> for PortProtocol {
-    type Error = anyhow::Error;
+        assert!(validated(vec![], "192.168.4.3", 1420).is_err());
+        assert!(validated(forwards("8080:192.168.4.1:8081"), "192.168.4.3", 100).is_err());
 
-    fn try_from(value: &str) -> anyhow::Result<Self> {
-        match value.to_uppercase().as_str() {
-            "TCP" => Ok(Self::Tcp),
-            "UDP" => Ok(Self::Udp),
-            _ => Err(anyhow::anyhow!("Invalid protocol specifier: {}", value)),
-        }
-    }
-}
+        let mut duplicate = forwards("8080:192.168.4.1:8081");
+        duplicate.extend(forwards("8080:192.168.4.2:8081"));
+        assert!(validated(duplicate, "192.168.4.3", 1420).is_err());
 
-impl Display for PortProtocol {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Self::Tcp => "TCP",
-                Self::Udp => "UDP",
-            }
-        )
-    }
-}
+        let mut same_name = forwards("8080:192.168.4.1:8081;name=web");
+        same_name.extend(forwards("8081:192.168.4.1:8081;name=web"));
+        assert!(validated(same_name, "192.168.4.3", 1420).is_err());
+        let both = forwards("8080:192.168.4.1:8081:TCP,UDP;name=web");
+        assert!(validated(both, "192.168.4.3", 1420).is_ok());
 
-#[cfg(test)]
-mod tests {
-    use std::str::FromStr;
+        let mut overlapping = forwards("0.0.0.0:8080:192.168.4.1:8081");
+        overlapping.extend(forwards("127.0.0.1:8080:192.168.4.2:8081"));
+        let warnings = validated(overlapping.clone(), "192.168.4.3", 1420).unwrap();
+        assert_eq!(
+            warnings,
+            vec![ConfigWarning::OverlappingListenAddresses(
+                overlapping[0].clone(),
+                overlapping[1].clone()
+            )]
+        );
 
-    use super::*;
+        let to_self = forwards("8080:192.168.4.3:8081");
+        let warnings = validated(to_self.clone(), "192.168.4.3", 1420).unwrap();
+        assert_eq!(
+            warnings,
+            vec![ConfigWarning::DestinationIsSourcePeer(to_self[0].clone())]
+        );
 
-    /// Tests the parsing of `PortForwardConfig`.
-    #[test]
-    fn test_parse_port_forward_config_1() {
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let remote = PortForwardConfig::from_notation("8081:127.0.0.1:8082", "192.168.4.3")
+            .unwrap()
+            .remove(0);
+        let hairpin = Config::builder()
+            .port_forwards(to_self)
+            .remote_port_forward(remote)
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr(SocketAddr::from_str("127.0.0.1:51820").unwrap())
+            .source_peer_ip(IpAddr::from_str("192.168.4.3").unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(hairpin.validate(), Ok(vec![]));
+
+        let to_ipv6 = forwards("8080:[fd00::1]:8081");
+        let warnings = validated(to_ipv6.clone(), "192.168.4.3", 1500).unwrap();
         assert_eq!(
-            PortForwardConfig::from_notation(
-                "192.168.0.1:8080:192.168.4.1:8081:TCP,UDP",
-                DEFAULT_PORT_FORWARD_SOURCE
-            )
-            .expect("Failed to parse"),
+            warnings,
             vec![
-                PortForwardConfig {
-                    source: SocketAddr::from_str("192.168.0.1:8080").unwrap(),
-                    destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
-                    protocol: PortProtocol::Tcp,
-                    remote: false,
-                },
-                PortForwardConfig {
-                    source: SocketAddr::from_str("192.168.0.1:8080").unwrap(),
-                    destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
-                    protocol: PortProtocol::Udp,
-                    remote: false,
-                }
+                ConfigWarning::AddressFamilyMismatch(to_ipv6[0].clone()),
+                ConfigWarning::MtuMayFragment(1500)
             ]
         );
-    }
-    /// Tests the parsing of `PortForwardConfig`.
-    #[test]
-    fn test_parse_port_forward_config_2() {
+
+        // Host names unknown to the system are resolved through the tunnel
+        let to_hostname = forwards("5432:db.internal.invalid:5432");
         assert_eq!(
-            PortForwardConfig::from_notation(
-                "192.168.0.1:8080:192.168.4.1:8081:TCP",
-                DEFAULT_PORT_FORWARD_SOURCE
-            )
-            .expect("Failed to parse"),
-            vec![PortForwardConfig {
-                source: SocketAddr::from_str("192.168.0.1:8080").unwrap(),
-                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
-                protocol: PortProtocol::Tcp,
-                remote: false,
-            }]
+            to_hostname[0].hostname.as_deref(),
+            Some("db.internal.invalid")
+        );
+        assert!(to_hostname[0].destination.ip().is_unspecified());
+        assert_eq!(
+            validated(to_hostname.clone(), "192.168.4.3", 1420),
+            Err(ConfigError::UnresolvedDestination(
+                "db.internal.invalid".into()
+            ))
+        );
+        let with_tunnel_dns = Config::builder()
+            .port_forwards(to_hostname)
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr(SocketAddr::from_str("127.0.0.1:51820").unwrap())
+            .source_peer_ip(IpAddr::from_str("192.168.4.3").unwrap())
+            .build()
+            .unwrap()
+            .with_tunnel_dns(parse_dns_server("192.168.4.1").unwrap());
+        assert_eq!(with_tunnel_dns.validate(), Ok(vec![]));
+        assert_eq!(
+            with_tunnel_dns.tunnel_dns,
+            Some(SocketAddr::from_str("192.168.4.1:53").unwrap())
         );
     }
-    /// Tests the parsing of `PortForwardConfig`.
+
+    /// Tests the parsing and matching of allowed IP ranges.
     #[test]
-    fn test_parse_port_forward_config_3() {
+    fn test_allowed_ip() {
+        let range = AllowedIp::from_str("192.168.4.0/24").unwrap();
+        assert!(range.contains(&"192.168.4.200".parse().unwrap()));
+        assert!(!range.contains(&"192.168.5.1".parse().unwrap()));
+        assert!(!range.contains(&"fd00::1".parse().unwrap()));
+        assert_eq!(range.to_string(), "192.168.4.0/24");
+
+        let host = AllowedIp::from_str("fd00::1").unwrap();
+        assert_eq!(host.to_string(), "fd00::1/128");
+        assert!(host.contains(&"fd00::1".parse().unwrap()));
+        assert!(!host.contains(&"fd00::2".parse().unwrap()));
+
+        let any = AllowedIp::any();
+        assert!(any.iter().any(|r| r.contains(&"10.0.0.1".parse().unwrap())));
+        assert!(any.iter().any(|r| r.contains(&"::1".parse().unwrap())));
+        assert!(AllowedIp::from_str("192.168.4.0/33").is_err());
+        assert!(AllowedIp::from_str("192.168.4.0/").is_err());
+        assert!(AllowedIp::from_str("peer/24").is_err());
+    }
+
+    #[test]
+    fn test_nat64_prefix() {
+        let prefix = Nat64Prefix::from_str("64:ff9b::/96").unwrap();
+        assert_eq!(prefix, Nat64Prefix::WELL_KNOWN);
+        assert_eq!(prefix.to_string(), "64:ff9b::/96");
         assert_eq!(
-            PortForwardConfig::from_notation(
-                "0.0.0.0:8080:192.168.4.1:8081",
-                DEFAULT_PORT_FORWARD_SOURCE
-            )
-            .expect("Failed to parse"),
-            vec![PortForwardConfig {
-                source: SocketAddr::from_str("0.0.0.0:8080").unwrap(),
-                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
-                protocol: PortProtocol::Tcp,
-                remote: false,
-            }]
+            prefix.embed(Ipv4Addr::new(192, 0, 2, 33)),
+            "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(
+            Nat64Prefix::from_str("2001:db8:64::").unwrap().to_string(),
+            "2001:db8:64::/96"
         );
+        assert!(Nat64Prefix::from_str("64:ff9b::/64").is_err());
+        assert!(Nat64Prefix::from_str("64:ff9b::1:2/96").is_err());
+        assert!(Nat64Prefix::from_str("192.168.4.0/24").is_err());
+
+        // IPv4 destinations of an IPv6-only peer are reached through the gateway
+        let to_ipv4 = forwards("8080:192.168.4.2:8080");
+        assert_eq!(
+            validated(to_ipv4.clone(), "fd00::3", 1420),
+            Ok(vec![ConfigWarning::AddressFamilyMismatch(
+                to_ipv4[0].clone()
+            )])
+        );
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let with_nat64 = Config::builder()
+            .port_forwards(to_ipv4)
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr(SocketAddr::from_str("127.0.0.1:51820").unwrap())
+            .source_peer_ip(IpAddr::from_str("fd00::3").unwrap())
+            .build()
+            .unwrap()
+            .with_nat64(prefix);
+        assert_eq!(with_nat64.validate(), Ok(vec![]));
     }
-    /// Tests the parsing of `PortForwardConfig`.
+
+    /// Tests port forwards connecting from additional source peer IPs.
     #[test]
-    fn test_parse_port_forward_config_4() {
+    fn test_validate_config_source_peer_ips() {
+        let pf = forwards("8080:192.168.4.1:8081;peer-ip=192.168.4.4");
+        assert_eq!(pf[0].source_peer_ip, Some("192.168.4.4".parse().unwrap()));
         assert_eq!(
-            PortForwardConfig::from_notation(
-                "[::1]:8080:192.168.4.1:8081",
-                DEFAULT_PORT_FORWARD_SOURCE
-            )
-            .expect("Failed to parse"),
-            vec![PortForwardConfig {
-                source: SocketAddr::from_str("[::1]:8080").unwrap(),
-                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
-                protocol: PortProtocol::Tcp,
-                remote: false,
-            }]
+            pf[0].to_string(),
+            "127.0.0.1:8080:192.168.4.1:8081:TCP;peer-ip=192.168.4.4"
+        );
+        assert!(PortForwardConfig::from_notation(
+            "8080:192.168.4.1:8081;peer-ip=peer",
+            DEFAULT_PORT_FORWARD_SOURCE
+        )
+        .is_err());
+
+        assert_eq!(
+            validated(pf.clone(), "192.168.4.3", 1420),
+            Err(ConfigError::UnknownSourcePeerIp(
+                "192.168.4.4".parse().unwrap()
+            ))
+        );
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let config = Config::builder()
+            .port_forwards(pf)
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr(SocketAddr::from_str("127.0.0.1:51820").unwrap())
+            .source_peer_ip(IpAddr::from_str("192.168.4.3").unwrap())
+            .build()
+            .unwrap()
+            .with_additional_source_peer_ips(vec!["192.168.4.4".parse().unwrap()]);
+        assert_eq!(config.validate(), Ok(vec![]));
+        assert_eq!(
+            config.source_peer_ips(),
+            vec![
+                "192.168.4.3".parse::<IpAddr>().unwrap(),
+                "192.168.4.4".parse().unwrap()
+            ]
         );
     }
-    /// Tests the parsing of `PortForwardConfig`.
+
+    /// Tests the additional addresses of the endpoint, which must be of the same IP version.
     #[test]
-    fn test_parse_port_forward_config_5() {
+    fn test_validate_config_endpoint_addrs() {
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let config = Config::builder()
+            .port_forwards(forwards("8080:192.168.4.1:8081"))
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr(SocketAddr::from_str("127.0.0.1:51820").unwrap())
+            .source_peer_ip(IpAddr::from_str("192.168.4.3").unwrap())
+            .build()
+            .unwrap();
+        let v4 = config
+            .clone()
+            .with_additional_endpoint_addrs(vec!["127.0.0.2:51820".parse().unwrap()]);
+        assert_eq!(v4.validate(), Ok(vec![]));
         assert_eq!(
-            PortForwardConfig::from_notation("8080:192.168.4.1:8081", DEFAULT_PORT_FORWARD_SOURCE)
-                .expect("Failed to parse"),
-            vec![PortForwardConfig {
-                source: SocketAddr::from_str("127.0.0.1:8080").unwrap(),
-                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
-                protocol: PortProtocol::Tcp,
-                remote: false,
-            }]
+            v4.endpoint_addrs(),
+            vec![
+                "127.0.0.1:51820".parse::<SocketAddr>().unwrap(),
+                "127.0.0.2:51820".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            config
+                .with_additional_endpoint_addrs(vec!["[::1]:51820".parse().unwrap()])
+                .validate(),
+            Err(ConfigError::MixedEndpointFamilies)
         );
     }
-    /// Tests the parsing of `PortForwardConfig`.
+
+    /// Tests that no UDP port forward listens on the local port of the endpoint.
     #[test]
-    fn test_parse_port_forward_config_6() {
+    fn test_validate_endpoint_port() {
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let config = Config::builder()
+            .port_forwards(forwards("51820:192.168.4.1:53:UDP,TCP"))
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr(SocketAddr::from_str("127.0.0.1:51820").unwrap())
+            .source_peer_ip(IpAddr::from_str("192.168.4.3").unwrap())
+            .build()
+            .unwrap();
         assert_eq!(
-            PortForwardConfig::from_notation(
-                "8080:192.168.4.1:8081:TCP",
-                DEFAULT_PORT_FORWARD_SOURCE
-            )
-            .expect("Failed to parse"),
-            vec![PortForwardConfig {
-                source: SocketAddr::from_str("127.0.0.1:8080").unwrap(),
-                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
-                protocol: PortProtocol::Tcp,
-                remote: false,
-            }]
+            config.clone().with_listen_port(51821).validate(),
+            Ok(vec![])
+        );
+        assert_eq!(
+            config.with_listen_port(51820).validate(),
+            Err(ConfigError::EndpointPortConflict(
+                "127.0.0.1:51820".parse().unwrap()
+            ))
         );
     }
-    /// Tests the parsing of `PortForwardConfig`.
+
+    /// Tests the routes of the virtual interfaces, which need a source peer IP of their IP version.
     #[test]
-    fn test_parse_port_forward_config_7() {
+    fn test_validate_config_routes() {
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let config = Config::builder()
+            .port_forwards(forwards("8080:192.168.4.1:8081"))
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr(SocketAddr::from_str("127.0.0.1:51820").unwrap())
+            .source_peer_ip(IpAddr::from_str("192.168.4.3").unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(config.interface_routes(), vec![]);
         assert_eq!(
-            PortForwardConfig::from_notation(
-                "localhost:8080:192.168.4.1:8081",
-                DEFAULT_PORT_FORWARD_SOURCE
-            )
-            .expect("Failed to parse"),
-            vec![PortForwardConfig {
-                source: "localhost:8080".to_socket_addrs().unwrap().next().unwrap(),
-                destination: SocketAddr::from_str("192.168.4.1:8081").unwrap(),
-                protocol: PortProtocol::Tcp,
-                remote: false,
-            }]
+            config
+                .clone()
+                .with_in_process_connections()
+                .interface_routes(),
+            vec![AllowedIp::from_str("0.0.0.0/0").unwrap()]
+        );
+
+        let subnet = AllowedIp::from_str("10.20.0.0/16").unwrap();
+        let routed = config.clone().with_routes(vec![subnet]);
+        assert_eq!(routed.validate(), Ok(vec![]));
+        assert_eq!(routed.interface_routes(), vec![subnet]);
+
+        let ipv6 = AllowedIp::from_str("fd00::/64").unwrap();
+        assert_eq!(
+            config.with_routes(vec![subnet, ipv6]).validate(),
+            Err(ConfigError::RouteWithoutSourcePeerIp(ipv6))
         );
     }
-    /// Tests the parsing of `PortForwardConfig`.
+
+    /// Tests the parsing of TLS options on port forwards.
+    #[cfg(feature = "tls")]
     #[test]
-    fn test_parse_port_forward_config_8() {
+    fn test_parse_port_forward_config_tls() {
         assert_eq!(
             PortForwardConfig::from_notation(
-                "localhost:8080:localhost:8081:TCP",
+                "8443:192.168.4.1:8081;tls-terminate,cert=cert.pem,key=key.pem",
                 DEFAULT_PORT_FORWARD_SOURCE
             )
-            .expect("Failed to parse"),
-            vec![PortForwardConfig {
-                source: "localhost:8080".to_socket_addrs().unwrap().next().unwrap(),
-                destination: "localhost:8081".to_socket_addrs().unwrap().next().unwrap(),
-                protocol: PortProtocol::Tcp,
-                remote: false,
-            }]
+            .expect("Failed to parse")[0]
+                .tls
+                .as_deref(),
+            Some(&TlsOptions::Terminate {
+                cert: PathBuf::from("cert.pem"),
+                key: PathBuf::from("key.pem"),
+            })
+        );
+        assert_eq!(
+            ForwardOptions::parse("tls-originate", "peer.intranet")
+                .expect("Failed to parse")
+                .tls
+                .as_deref(),
+            Some(&TlsOptions::Originate {
+                server_name: "peer.intranet".into(),
+                ca: None,
+            })
         );
     }
 }
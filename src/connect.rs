@@ -0,0 +1,238 @@
+//! Connections through the tunnel opened in-process, without binding local listeners (see `Handle::open_tcp`
+//! and `Handle::open_udp`), and the connector of hyper clients built on them (see `Handle::http_connector`).
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+use crate::config::{PortForwardConfig, PortProtocol, UdpSessionMode};
+use crate::events::{Bus, BusEndpoint, Event};
+use crate::tunnel::tcp::TcpPortPool;
+use crate::tunnel::udp::UdpPortPool;
+use crate::virtual_iface::VirtualPort;
+
+/// How long to wait for the destination to accept a TCP connection.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The placeholder port of the next UDP socket, which assigns it its own virtual port in the pool.
+static NEXT_UDP_SOCKET: AtomicU16 = AtomicU16::new(1);
+
+/// A TCP connection to a destination through the tunnel. The virtual connection is closed when dropped or
+/// shut down.
+pub struct VirtualTcpStream {
+    stream: DuplexStream,
+    virtual_port: VirtualPort,
+    destination: SocketAddr,
+}
+
+impl VirtualTcpStream {
+    pub(crate) async fn open(
+        destination: SocketAddr,
+        port_pool: TcpPortPool,
+        bus: Bus,
+    ) -> anyhow::Result<Self> {
+        let (virtual_port, stream) =
+            crate::tunnel::tcp::open_virtual_connection(destination, port_pool, bus, OPEN_TIMEOUT)
+                .await?;
+        Ok(Self {
+            stream,
+            virtual_port,
+            destination,
+        })
+    }
+
+    /// The virtual port of the connection, as it appears in the logs and events.
+    pub fn virtual_port(&self) -> VirtualPort {
+        self.virtual_port
+    }
+
+    /// The address the connection goes to.
+    pub fn destination(&self) -> SocketAddr {
+        self.destination
+    }
+}
+
+impl AsyncRead for VirtualTcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for VirtualTcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// A UDP socket that exchanges datagrams with a destination through the tunnel. Like the sessions of UDP port
+/// forwards, its virtual port is recycled once it has been inactive for a while.
+pub struct VirtualUdpSocket {
+    port_forward: Arc<PortForwardConfig>,
+    virtual_port: VirtualPort,
+    endpoint: BusEndpoint,
+    port_pool: UdpPortPool,
+}
+
+impl VirtualUdpSocket {
+    pub(crate) async fn open(
+        destination: SocketAddr,
+        port_pool: UdpPortPool,
+        bus: &Bus,
+    ) -> anyhow::Result<Self> {
+        // Each socket has its own placeholder source, so that it is assigned its own virtual port
+        let source = SocketAddr::from((
+            Ipv4Addr::UNSPECIFIED,
+            NEXT_UDP_SOCKET.fetch_add(1, Ordering::Relaxed),
+        ));
+        let virtual_port = port_pool.next(source, UdpSessionMode::Generic).await?;
+        Ok(Self {
+            port_forward: Arc::new(PortForwardConfig::new(
+                source,
+                destination,
+                PortProtocol::Udp,
+            )),
+            virtual_port,
+            endpoint: bus.new_endpoint(),
+            port_pool,
+        })
+    }
+
+    /// The virtual port of the socket, as it appears in the logs and events.
+    pub fn virtual_port(&self) -> VirtualPort {
+        self.virtual_port
+    }
+
+    /// The address the datagrams are sent to.
+    pub fn destination(&self) -> SocketAddr {
+        self.port_forward.destination
+    }
+
+    /// Sends a datagram to the destination.
+    pub async fn send(&self, data: &[u8]) {
+        self.port_pool.update_last_transmit(self.virtual_port).await;
+        self.endpoint.send(Event::LocalData(
+            self.port_forward.clone(),
+            self.virtual_port,
+            data.to_vec(),
+            Instant::now(),
+        ));
+    }
+
+    /// Receives the next datagram from the destination.
+    pub async fn recv(&mut self) -> Vec<u8> {
+        loop {
+            if let Event::RemoteData(e_vp, data, _) = self.endpoint.recv().await {
+                if e_vp == self.virtual_port {
+                    self.port_pool.update_last_transmit(self.virtual_port).await;
+                    return data;
+                }
+            }
+        }
+    }
+}
+
+/// Connects hyper clients through the tunnel, without listeners. Host names are resolved through the tunnel DNS
+/// server if there is one (see `Config::with_tunnel_dns`), by the system otherwise. For HTTPS, wrap it in a TLS
+/// connector such as `hyper-rustls`'s.
+#[cfg(feature = "http")]
+#[derive(Clone)]
+pub struct HttpConnector {
+    tcp_port_pool: TcpPortPool,
+    udp_port_pool: UdpPortPool,
+    bus: Bus,
+    tunnel_dns: Option<SocketAddr>,
+    /// Whether host names are resolved to IPv6 addresses, for an IPv6 source peer IP.
+    ipv6: bool,
+}
+
+#[cfg(feature = "http")]
+impl HttpConnector {
+    pub(crate) fn new(
+        tcp_port_pool: TcpPortPool,
+        udp_port_pool: UdpPortPool,
+        bus: Bus,
+        tunnel_dns: Option<SocketAddr>,
+        ipv6: bool,
+    ) -> Self {
+        Self {
+            tcp_port_pool,
+            udp_port_pool,
+            bus,
+            tunnel_dns,
+            ipv6,
+        }
+    }
+
+    async fn connect(self, uri: hyper::Uri) -> anyhow::Result<VirtualTcpStream> {
+        use anyhow::Context as _;
+
+        let host = uri
+            .host()
+            .with_context(|| format!("No host in {}", uri))?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+        let ip = match (host.parse::<std::net::IpAddr>(), self.tunnel_dns) {
+            (Ok(ip), _) => ip,
+            (Err(_), Some(server)) => {
+                crate::tunnel::dns::lookup(server, host, self.ipv6, &self.udp_port_pool, &self.bus)
+                    .await?
+            }
+            (Err(_), None) => tokio::net::lookup_host((host, port))
+                .await
+                .with_context(|| format!("Failed to resolve {}", host))?
+                .map(|addr| addr.ip())
+                .find(|ip| ip.is_ipv6() == self.ipv6)
+                .with_context(|| format!("No address for {}", host))?,
+        };
+        VirtualTcpStream::open(SocketAddr::new(ip, port), self.tcp_port_pool, self.bus).await
+    }
+}
+
+#[cfg(feature = "http")]
+impl hyper::service::Service<hyper::Uri> for HttpConnector {
+    type Response = VirtualTcpStream;
+    type Error = anyhow::Error;
+    type Future =
+        Pin<Box<dyn std::future::Future<Output = anyhow::Result<VirtualTcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<anyhow::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        Box::pin(self.clone().connect(uri))
+    }
+}
+
+#[cfg(feature = "http")]
+impl hyper::client::connect::Connection for VirtualTcpStream {
+    fn connected(&self) -> hyper::client::connect::Connected {
+        hyper::client::connect::Connected::new()
+    }
+}
@@ -0,0 +1,51 @@
+//! Hooks to accept or reject the local clients of the port forwards.
+//!
+//! A `ConnectionAuthorizer` registered with `Config::with_connection_authorizer` is asked about each
+//! client before a virtual port is assigned to it, which lets embedders apply their own policy, such as
+//! the pairing state of an app, without changing onetun itself.
+
+use std::fmt::Debug;
+use std::net::SocketAddr;
+
+use crate::config::PortForwardConfig;
+
+/// Decides whether the local clients may use the port forwards.
+pub trait ConnectionAuthorizer: Debug + Send + Sync {
+    /// Called with each accepted TCP connection, and with the first datagram of each new UDP client, from
+    /// the port forward's task, so it must not block. Returns whether to serve the client: a rejected TCP
+    /// connection is closed, and the datagrams of a rejected UDP client are dropped until it is authorized.
+    fn authorize(&self, port_forward: &PortForwardConfig, client: SocketAddr) -> bool;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortProtocol;
+    use crate::tunnel::ForwardStats;
+    use std::sync::Arc;
+
+    /// Only authorizes the clients on port 1000.
+    #[derive(Debug)]
+    struct PortAuthorizer;
+
+    impl ConnectionAuthorizer for PortAuthorizer {
+        fn authorize(&self, _port_forward: &PortForwardConfig, client: SocketAddr) -> bool {
+            client.port() == 1000
+        }
+    }
+
+    #[test]
+    fn test_forward_stats_authorizer() {
+        let port_forward = PortForwardConfig::new(
+            "127.0.0.1:8080".parse().unwrap(),
+            "192.168.4.2:80".parse().unwrap(),
+            PortProtocol::Tcp,
+        );
+        let open = ForwardStats::default();
+        assert!(open.authorizes(&port_forward, "127.0.0.1:2000".parse().unwrap()));
+
+        let stats = ForwardStats::with_authorizer(Some(Arc::new(PortAuthorizer)));
+        assert!(stats.authorizes(&port_forward, "127.0.0.1:1000".parse().unwrap()));
+        assert!(!stats.authorizes(&port_forward, "127.0.0.1:2000".parse().unwrap()));
+    }
+}
@@ -0,0 +1,254 @@
+//! Connection logs (see `Config::with_connection_log`): a line for each TCP connection of the port forwards when
+//! it closes, with its client, port forward, bytes and duration, such as
+//! `client=192.168.1.0/24 forward=127.0.0.1:8080->192.168.4.2:80 tx=512 rx=20480 duration_ms=1532 reason="local close"`.
+//!
+//! The lines are logged at the info level under their own target, `onetun::connections`, so that they can be
+//! kept apart from the other logs. The clients are written as the `ClientPrivacy` setting allows.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Instant;
+
+use tokio::sync::broadcast;
+
+use crate::config::PortForwardConfig;
+use crate::events::{Bus, DropReason, Event};
+use crate::tunnel::tcp::TcpPortPool;
+use crate::virtual_iface::VirtualPort;
+
+/// The log target of the connection logs.
+pub const CONNECTION_LOG_TARGET: &str = "onetun::connections";
+
+/// The leading bits of the IPv4 clients kept by `ClientPrivacy::Truncate`.
+const TRUNCATED_IPV4_PREFIX: u32 = 24;
+/// The leading bits of the IPv6 clients kept by `ClientPrivacy::Truncate`.
+const TRUNCATED_IPV6_PREFIX: u32 = 48;
+
+/// How the connection logs write the address of each client.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ClientPrivacy {
+    /// The IP and port of the client.
+    Full,
+    /// The /24 network of IPv4 clients, or the /48 network of IPv6 ones, without the port.
+    Truncate,
+    /// A keyed hash of the IP, without the port. The connections of a client can be told apart from the others',
+    /// but the key is random and changes when the tunnel starts again, so the IP can't be found by hashing
+    /// candidates.
+    Hash,
+}
+
+impl Default for ClientPrivacy {
+    fn default() -> Self {
+        Self::Full
+    }
+}
+
+impl FromStr for ClientPrivacy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "full" => Ok(Self::Full),
+            "truncate" => Ok(Self::Truncate),
+            "hash" => Ok(Self::Hash),
+            _ => Err(anyhow::anyhow!(
+                "Invalid client privacy: '{}' (full, truncate or hash)",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ClientPrivacy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Full => "full",
+                Self::Truncate => "truncate",
+                Self::Hash => "hash",
+            }
+        )
+    }
+}
+
+/// Writes the addresses of the clients as the privacy setting allows.
+struct ClientFormatter {
+    privacy: ClientPrivacy,
+    /// The key of the hashes of `ClientPrivacy::Hash`.
+    key: RandomState,
+}
+
+impl ClientFormatter {
+    fn new(privacy: ClientPrivacy) -> Self {
+        Self {
+            privacy,
+            key: RandomState::new(),
+        }
+    }
+
+    fn format(&self, client: SocketAddr) -> String {
+        match (self.privacy, client.ip()) {
+            (ClientPrivacy::Full, _) => client.to_string(),
+            (ClientPrivacy::Truncate, IpAddr::V4(ip)) => {
+                let mask = u32::MAX << (32 - TRUNCATED_IPV4_PREFIX);
+                let network = Ipv4Addr::from(u32::from(ip) & mask);
+                format!("{}/{}", network, TRUNCATED_IPV4_PREFIX)
+            }
+            (ClientPrivacy::Truncate, IpAddr::V6(ip)) => {
+                let mask = u128::MAX << (128 - TRUNCATED_IPV6_PREFIX);
+                let network = Ipv6Addr::from(u128::from(ip) & mask);
+                format!("{}/{}", network, TRUNCATED_IPV6_PREFIX)
+            }
+            (ClientPrivacy::Hash, ip) => {
+                let mut hasher = self.key.build_hasher();
+                ip.hash(&mut hasher);
+                format!("{:016x}", hasher.finish())
+            }
+        }
+    }
+}
+
+/// An open connection, until it is logged.
+struct LoggedConnection {
+    client: String,
+    forward: String,
+    started: Instant,
+    /// Bytes from the client, sent through the tunnel.
+    tx: usize,
+    /// Bytes received through the tunnel, for the client.
+    rx: usize,
+}
+
+impl LoggedConnection {
+    fn new(client: String, port_forward: &PortForwardConfig, started: Instant) -> Self {
+        let forward = match &port_forward.name {
+            Some(name) => name.to_string(),
+            None => format!(
+                "{}->{}",
+                port_forward.source_name(),
+                port_forward.destination_name()
+            ),
+        };
+        Self {
+            client,
+            forward,
+            started,
+            tx: 0,
+            rx: 0,
+        }
+    }
+
+    /// The log line of the connection, closed at the given time.
+    fn line(&self, reason: DropReason, closed: Instant) -> String {
+        format!(
+            "client={} forward={} tx={} rx={} duration_ms={} reason={:?}",
+            self.client,
+            self.forward,
+            self.tx,
+            self.rx,
+            closed.saturating_duration_since(self.started).as_millis(),
+            reason.to_string()
+        )
+    }
+}
+
+/// Logs the TCP connections on the bus as they close, until the tunnel is killed. The clients are the addresses
+/// the virtual ports of the pool were assigned to.
+pub async fn log_connections(
+    privacy: ClientPrivacy,
+    port_pool: TcpPortPool,
+    bus: Bus,
+    mut kill_switch: broadcast::Receiver<()>,
+) {
+    let formatter = ClientFormatter::new(privacy);
+    let mut endpoint = bus.new_endpoint();
+    let mut connections: HashMap<VirtualPort, LoggedConnection> = HashMap::new();
+    loop {
+        let event = tokio::select! {
+            event = endpoint.recv() => event,
+            _ = kill_switch.recv() => return,
+        };
+        match event {
+            Event::ClientConnectionInitiated(pf, vp, at) if !connections.contains_key(&vp) => {
+                let client = match (&pf.pipe, port_pool.get_peer_addr(vp).await) {
+                    // Named pipe clients have no address
+                    (Some(_), _) => pf.source_name(),
+                    // In-process connections (see `Handle::open_tcp`) have no client either
+                    (None, Some(client)) if client.port() != 0 => formatter.format(client),
+                    _ => continue,
+                };
+                connections.insert(vp, LoggedConnection::new(client, &pf, at));
+            }
+            Event::LocalData(_, vp, data, _) => {
+                if let Some(connection) = connections.get_mut(&vp) {
+                    connection.tx += data.len();
+                }
+            }
+            Event::RemoteData(vp, data, _) => {
+                if let Some(connection) = connections.get_mut(&vp) {
+                    connection.rx += data.len();
+                }
+            }
+            Event::ClientConnectionDropped(vp, reason, at) => {
+                if let Some(connection) = connections.remove(&vp) {
+                    info!(target: CONNECTION_LOG_TARGET, "{}", connection.line(reason, at));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortProtocol;
+    use std::time::Duration;
+
+    #[test]
+    fn test_client_privacy() {
+        let v4: SocketAddr = "192.168.1.23:51234".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8:1:2::7]:51234".parse().unwrap();
+
+        let full = ClientFormatter::new(ClientPrivacy::Full);
+        assert_eq!(full.format(v4), "192.168.1.23:51234");
+
+        let truncate = ClientFormatter::new("truncate".parse().unwrap());
+        assert_eq!(truncate.format(v4), "192.168.1.0/24");
+        assert_eq!(truncate.format(v6), "2001:db8:1::/48");
+
+        let hash = ClientFormatter::new(ClientPrivacy::Hash);
+        let hashed = hash.format(v4);
+        assert_eq!(hashed.len(), 16);
+        assert!(!hashed.contains("192.168"));
+        // The same IP on another port is the same client
+        assert_eq!(hash.format("192.168.1.23:40000".parse().unwrap()), hashed);
+        assert_ne!(hash.format("192.168.1.24:51234".parse().unwrap()), hashed);
+
+        assert!("none".parse::<ClientPrivacy>().is_err());
+    }
+
+    #[test]
+    fn test_connection_line() {
+        let port_forward = PortForwardConfig::new(
+            "127.0.0.1:8080".parse().unwrap(),
+            "192.168.4.2:80".parse().unwrap(),
+            PortProtocol::Tcp,
+        );
+        let started = Instant::now();
+        let mut connection =
+            LoggedConnection::new("192.168.1.0/24".to_string(), &port_forward, started);
+        connection.tx = 512;
+        connection.rx = 20480;
+        assert_eq!(
+            connection.line(DropReason::LocalClose, started + Duration::from_millis(1532)),
+            "client=192.168.1.0/24 forward=127.0.0.1:8080->192.168.4.2:80 tx=512 rx=20480 duration_ms=1532 reason=\"local close\""
+        );
+    }
+}
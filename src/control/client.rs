@@ -0,0 +1,163 @@
+//! A client of the control socket, for the Rust programs that manage onetun instances.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use super::protocol::{
+    self, Forward, ForwardChanges, Request, Response, TunnelStats, TunnelStatus,
+};
+use super::{Connection, ControlAddress, ControlToken};
+use crate::config::ForwardId;
+
+/// A connection to the control socket of a tunnel (see `Config::with_control_socket`). The requests fail with
+/// the `protocol::RpcError` of their response, if the tunnel rejected them.
+pub struct ControlClient {
+    reader: BufReader<ReadHalf<Box<dyn Connection>>>,
+    writer: WriteHalf<Box<dyn Connection>>,
+    token: Option<ControlToken>,
+    /// The identifier of the next request.
+    next_id: u64,
+}
+
+impl ControlClient {
+    /// Connects to the control socket at the address.
+    pub async fn connect(address: &ControlAddress) -> anyhow::Result<Self> {
+        let connection: Box<dyn Connection> = match address {
+            ControlAddress::Tcp(addr) => Box::new(TcpStream::connect(addr).await?),
+            #[cfg(unix)]
+            ControlAddress::Unix(path) => Box::new(UnixStream::connect(path).await?),
+            #[cfg(not(unix))]
+            ControlAddress::Unix(path) => {
+                return Err(anyhow::anyhow!(
+                    "Unix sockets are not supported on this platform: {}",
+                    path.display()
+                ))
+            }
+        };
+        let (reader, writer) = tokio::io::split(connection);
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer,
+            token: None,
+            next_id: 1,
+        })
+    }
+
+    /// Presents the token with each request, as required by tunnels with a control token (see
+    /// `Config::with_control_token`).
+    pub fn with_token(mut self, token: ControlToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    pub async fn status(&mut self) -> anyhow::Result<TunnelStatus> {
+        self.call(protocol::STATUS, Value::Null).await
+    }
+
+    /// The running local port forwards.
+    pub async fn forwards(&mut self) -> anyhow::Result<Vec<Forward>> {
+        self.call(protocol::FORWARDS_LIST, Value::Null).await
+    }
+
+    /// Adds the port forwards of the definition, in the command-line notation (e.g. `8080:192.168.4.2:80:TCP`).
+    pub async fn add_forward(&mut self, notation: &str) -> anyhow::Result<Vec<Forward>> {
+        let params = protocol::AddForwardParams {
+            notation: notation.to_string(),
+        };
+        self.call(protocol::FORWARDS_ADD, params).await
+    }
+
+    /// Removes the port forward, letting its sessions finish within the grace period.
+    pub async fn remove_forward(&mut self, id: &ForwardId, grace: Duration) -> anyhow::Result<()> {
+        let params = protocol::RemoveForwardParams {
+            id: id.to_string(),
+            grace_secs: grace.as_secs(),
+        };
+        let _: Value = self.call(protocol::FORWARDS_REMOVE, params).await?;
+        Ok(())
+    }
+
+    pub async fn stats(&mut self) -> anyhow::Result<TunnelStats> {
+        self.call(protocol::STATS, Value::Null).await
+    }
+
+    /// Replaces the local port forwards with those of the definitions, in the command-line notation. The
+    /// removed ones finish their sessions within the grace period.
+    pub async fn reload(
+        &mut self,
+        notations: &[&str],
+        grace: Duration,
+    ) -> anyhow::Result<ForwardChanges> {
+        let params = protocol::ReloadParams {
+            forwards: notations
+                .iter()
+                .map(|notation| notation.to_string())
+                .collect(),
+            grace_secs: grace.as_secs(),
+        };
+        self.call(protocol::RELOAD, params).await
+    }
+
+    /// Kills the tunnel. The control socket closes afterwards.
+    pub async fn kill(&mut self) -> anyhow::Result<()> {
+        let _: Value = self.call(protocol::KILL, Value::Null).await?;
+        Ok(())
+    }
+
+    /// Calls the method with the parameters, which must serialize to an object or null, and waits for its result.
+    pub async fn call<T: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: impl Serialize,
+    ) -> anyhow::Result<T> {
+        let mut params = serde_json::to_value(params)?;
+        if let Some(token) = &self.token {
+            if params.is_null() {
+                params = Value::Object(Default::default());
+            }
+            if let Value::Object(params) = &mut params {
+                params.insert("token".to_string(), token.expose().into());
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = Request {
+            jsonrpc: protocol::VERSION.to_string(),
+            id: Some(id.into()),
+            method: method.to_string(),
+            params,
+        };
+        let mut message = serde_json::to_vec(&request)?;
+        message.push(b'\n');
+        self.writer.write_all(&message).await?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow::anyhow!("The control socket closed the connection"));
+        }
+        let response: Response = serde_json::from_str(&line)
+            .with_context(|| "Invalid response from the control socket")?;
+        if response.id != Value::from(id) {
+            return Err(anyhow::anyhow!(
+                "Unexpected response from the control socket, for request {}",
+                response.id
+            ));
+        }
+        match (response.result, response.error) {
+            (_, Some(error)) => Err(error.into()),
+            (Some(result), None) => Ok(serde_json::from_value(result)
+                .with_context(|| format!("Invalid result of {}", method))?),
+            (None, None) => Err(anyhow::anyhow!(
+                "Response without result from the control socket"
+            )),
+        }
+    }
+}
@@ -0,0 +1,549 @@
+//! Serves the gRPC control service of `proto/onetun/control/v1/control.proto` (see `Config::with_grpc_listen`),
+//! for the orchestration systems managing fleets of onetun instances: it starts and stops tunnels, adds and
+//! removes their port forwards, streams their statistics and reports their health.
+//!
+//! The service manages the tunnel it was bound with, and those it started. Like the control socket, it
+//! listens on loopback unless remote control is allowed, and checks the control token of the tunnel, carried
+//! in the `authorization` metadata of each call as `Bearer <token>`.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+use super::{check_listen_addr, ControlToken};
+use crate::audit::Initiator;
+use crate::config::{Config, ForwardId, PortForwardConfig, DEFAULT_PORT_FORWARD_SOURCE};
+use crate::events::Event;
+use crate::Handle;
+
+/// The messages and the client and server of the service, generated from its definition.
+pub mod proto {
+    tonic::include_proto!("onetun.control.v1");
+}
+
+use proto::control_server::{Control, ControlServer};
+
+/// A tunnel whose latest handshake is older than this is not serving: its session expired, like a WireGuard
+/// session does after 3 minutes.
+const HEALTHY_HANDSHAKE_AGE: Duration = Duration::from_secs(180);
+/// The statistics waiting to be sent to a slow `WatchStats` client. The next ones are dropped until it
+/// catches up.
+const STATS_BUFFER: usize = 64;
+
+/// The tunnels managed by the service, by identifier.
+type Tunnels = Arc<Mutex<BTreeMap<u32, Arc<Mutex<Handle>>>>>;
+
+/// The gRPC control service of a tunnel, listening.
+pub struct GrpcServer {
+    listener: TcpListener,
+    token: Option<ControlToken>,
+    kill_switch: broadcast::Receiver<()>,
+}
+
+impl GrpcServer {
+    /// Listens for the gRPC control service of the tunnel (see `Config::with_grpc_listen`). None if it has
+    /// none.
+    pub async fn bind(handle: &Handle) -> anyhow::Result<Option<Self>> {
+        let addr = match handle.grpc_listen {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        check_listen_addr(addr, handle.control_token.as_ref(), handle.remote_control)?;
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind the gRPC control service on {}", addr))?;
+        info!(
+            "[tunnel {}] gRPC control service listening on {}",
+            handle.id(),
+            addr
+        );
+        Ok(Some(Self {
+            listener,
+            token: handle.control_token.clone(),
+            kill_switch: handle.get_killer(),
+        }))
+    }
+
+    /// The address the service listens on, with the port chosen by the system if it was 0.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves the calls until the tunnel it was bound with is killed, then kills the tunnels it started.
+    pub async fn run(self, handle: Arc<Mutex<Handle>>) {
+        let id = handle.lock().await.id();
+        let mut managed = BTreeMap::new();
+        managed.insert(id, handle);
+        let tunnels: Tunnels = Arc::new(Mutex::new(managed));
+        let service = ControlService {
+            tunnels: tunnels.clone(),
+        };
+        let token = self.token;
+        let authorize = move |request: Request<()>| match &token {
+            Some(token) if !presented_token(&request).map_or(false, |t| token.verify(t)) => {
+                warn!("Rejected a gRPC control call without the control token");
+                Err(Status::unauthenticated("Missing or invalid control token"))
+            }
+            _ => Ok(request),
+        };
+        let mut kill_switch = self.kill_switch;
+        let served = tonic::transport::Server::builder()
+            .add_service(ControlServer::with_interceptor(service, authorize))
+            .serve_with_incoming_shutdown(TcpListenerStream::new(self.listener), async move {
+                kill_switch.recv().await.ok();
+            })
+            .await;
+        if let Err(e) = served {
+            error!("gRPC control service failed: {}", e);
+        }
+        for (started, handle) in tunnels.lock().await.iter() {
+            if *started != id {
+                handle.lock().await.kill();
+            }
+        }
+    }
+}
+
+/// The token in the `authorization` metadata of the call, if any.
+fn presented_token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get("authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+struct ControlService {
+    tunnels: Tunnels,
+}
+
+impl ControlService {
+    async fn tunnel(&self, id: u32) -> Result<Arc<Mutex<Handle>>, Status> {
+        self.tunnels
+            .lock()
+            .await
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("No tunnel {}", id)))
+    }
+}
+
+#[tonic::async_trait]
+impl Control for ControlService {
+    async fn start_tunnel(
+        &self,
+        request: Request<proto::StartTunnelRequest>,
+    ) -> Result<Response<proto::Tunnel>, Status> {
+        let config = start_config(request.get_ref())?;
+        let handle = crate::start(config)
+            .await
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        let tunnel = tunnel(&handle);
+        self.tunnels
+            .lock()
+            .await
+            .insert(handle.id(), Arc::new(Mutex::new(handle)));
+        Ok(Response::new(tunnel))
+    }
+
+    async fn stop_tunnel(
+        &self,
+        request: Request<proto::StopTunnelRequest>,
+    ) -> Result<Response<proto::StopTunnelResponse>, Status> {
+        let id = request.get_ref().tunnel_id;
+        let handle = self
+            .tunnels
+            .lock()
+            .await
+            .remove(&id)
+            .ok_or_else(|| Status::not_found(format!("No tunnel {}", id)))?;
+        handle.lock().await.kill_by(Initiator::Grpc);
+        Ok(Response::new(proto::StopTunnelResponse {}))
+    }
+
+    async fn list_tunnels(
+        &self,
+        _request: Request<proto::ListTunnelsRequest>,
+    ) -> Result<Response<proto::ListTunnelsResponse>, Status> {
+        let handles: Vec<Arc<Mutex<Handle>>> =
+            self.tunnels.lock().await.values().cloned().collect();
+        let mut tunnels = Vec::new();
+        for handle in handles {
+            tunnels.push(tunnel(&*handle.lock().await));
+        }
+        Ok(Response::new(proto::ListTunnelsResponse { tunnels }))
+    }
+
+    async fn list_forwards(
+        &self,
+        request: Request<proto::ListForwardsRequest>,
+    ) -> Result<Response<proto::ListForwardsResponse>, Status> {
+        let handle = self.tunnel(request.get_ref().tunnel_id).await?;
+        let handle = handle.lock().await;
+        Ok(Response::new(proto::ListForwardsResponse {
+            forwards: forwards(&handle),
+        }))
+    }
+
+    async fn create_forward(
+        &self,
+        request: Request<proto::CreateForwardRequest>,
+    ) -> Result<Response<proto::Forward>, Status> {
+        let request = request.into_inner();
+        let port_forward = match parse_forwards(&request.notation)?.as_slice() {
+            [port_forward] => port_forward.clone(),
+            _ => {
+                return Err(Status::invalid_argument(
+                    "Create the port forwards of each protocol separately",
+                ))
+            }
+        };
+        let handle = self.tunnel(request.tunnel_id).await?;
+        let mut handle = handle.lock().await;
+        let bind_policy = handle.bind_policy;
+        let id = handle
+            .add_forward_by(Initiator::Grpc, port_forward.clone(), bind_policy)
+            .await
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        Ok(Response::new(forward(&handle, &id, &port_forward)))
+    }
+
+    async fn delete_forward(
+        &self,
+        request: Request<proto::DeleteForwardRequest>,
+    ) -> Result<Response<proto::DeleteForwardResponse>, Status> {
+        let request = request.into_inner();
+        let id = ForwardId::from_str(&request.forward_id)
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+        let handle = self.tunnel(request.tunnel_id).await?;
+        let grace = Duration::from_secs(request.grace_secs);
+        if handle
+            .lock()
+            .await
+            .remove_forward_by(Initiator::Grpc, &id, grace)
+        {
+            Ok(Response::new(proto::DeleteForwardResponse {}))
+        } else {
+            Err(Status::not_found(format!("No port forward {}", id)))
+        }
+    }
+
+    type WatchStatsStream = ReceiverStream<Result<proto::ForwardStats, Status>>;
+
+    async fn watch_stats(
+        &self,
+        request: Request<proto::WatchStatsRequest>,
+    ) -> Result<Response<Self::WatchStatsStream>, Status> {
+        let handle = self.tunnel(request.get_ref().tunnel_id).await?;
+        let (mut endpoint, mut kill_switch) = {
+            let handle = handle.lock().await;
+            (handle.subscribe(), handle.get_killer())
+        };
+        let (stats, stream) = mpsc::channel(STATS_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = endpoint.recv() => event,
+                    _ = stats.closed() => break,
+                    _ = kill_switch.recv() => break,
+                };
+                if let Event::ForwardStats(id, tx, rx) = event {
+                    let reported = proto::ForwardStats {
+                        forward_id: id.to_string(),
+                        bytes_in: rx,
+                        bytes_out: tx,
+                    };
+                    // A client slower than the reports misses some
+                    if let Err(mpsc::error::TrySendError::Closed(_)) = stats.try_send(Ok(reported))
+                    {
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(stream)))
+    }
+
+    async fn list_connections(
+        &self,
+        request: Request<proto::ListConnectionsRequest>,
+    ) -> Result<Response<proto::ListConnectionsResponse>, Status> {
+        let handle = self.tunnel(request.get_ref().tunnel_id).await?;
+        let connections = handle.lock().await.connections().await;
+        Ok(Response::new(proto::ListConnectionsResponse {
+            connections: connections
+                .into_iter()
+                .map(|connection| proto::Connection {
+                    virtual_port: connection.virtual_port.num() as u32,
+                    protocol: connection.virtual_port.proto().to_string(),
+                    peer_addr: connection
+                        .peer_addr
+                        .map(|addr| addr.to_string())
+                        .unwrap_or_default(),
+                    destination: connection.destination.to_string(),
+                    bytes_in: connection.bytes_in as u64,
+                    bytes_out: connection.bytes_out as u64,
+                    age_ms: connection.age.as_millis() as u64,
+                    state: connection.state.to_string(),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn health(
+        &self,
+        request: Request<proto::HealthRequest>,
+    ) -> Result<Response<proto::HealthResponse>, Status> {
+        let handles = match request.get_ref().tunnel_id {
+            Some(id) => vec![self.tunnel(id).await?],
+            None => self.tunnels.lock().await.values().cloned().collect(),
+        };
+        let mut serving = !handles.is_empty();
+        for handle in handles {
+            let latest_handshake = handle.lock().await.interface_status().latest_handshake;
+            serving &= latest_handshake.map_or(false, |ago| ago < HEALTHY_HANDSHAKE_AGE);
+        }
+        let status = if serving {
+            proto::health_response::Status::Serving
+        } else {
+            proto::health_response::Status::NotServing
+        };
+        Ok(Response::new(proto::HealthResponse {
+            status: status as i32,
+        }))
+    }
+}
+
+/// The configuration of a tunnel started by the service. It logs with the logger of the process.
+fn start_config(request: &proto::StartTunnelRequest) -> Result<Config, Status> {
+    let endpoint_addr = SocketAddr::from_str(&request.endpoint_addr)
+        .map_err(|e| Status::invalid_argument(format!("Invalid endpoint address: {}", e)))?;
+    let source_peer_ip = IpAddr::from_str(&request.source_peer_ip)
+        .map_err(|e| Status::invalid_argument(format!("Invalid source peer IP: {}", e)))?;
+    let mut builder = Config::builder()
+        .private_key(request.private_key.as_str())
+        .endpoint_public_key(request.endpoint_public_key.as_str())
+        .endpoint_addr(endpoint_addr)
+        .source_peer_ip(source_peer_ip)
+        .skip_logger_init();
+    if request.keepalive_seconds > 0 {
+        let keepalive = u16::try_from(request.keepalive_seconds)
+            .map_err(|_| Status::invalid_argument("Invalid keepalive interval"))?;
+        builder = builder.keepalive(keepalive);
+    }
+    for notation in &request.port_forwards {
+        builder = builder.port_forwards(parse_forwards(notation)?);
+    }
+    let source_peer_ip = source_peer_ip.to_string();
+    for notation in &request.remote_port_forwards {
+        let port_forwards = PortForwardConfig::from_notation(notation, &source_peer_ip)
+            .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+        for port_forward in port_forwards {
+            builder = builder.remote_port_forward(port_forward);
+        }
+    }
+    let config = builder
+        .build()
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    Ok(config.with_initiator(Initiator::Grpc))
+}
+
+/// Parses a port forward definition in the command-line notation.
+fn parse_forwards(notation: &str) -> Result<Vec<PortForwardConfig>, Status> {
+    PortForwardConfig::from_notation(notation, DEFAULT_PORT_FORWARD_SOURCE)
+        .map_err(|e| Status::invalid_argument(format!("{:#}", e)))
+}
+
+fn tunnel(handle: &Handle) -> proto::Tunnel {
+    proto::Tunnel {
+        id: handle.id(),
+        endpoint: handle.current_endpoint().to_string(),
+        forwards: forwards(handle),
+        ready: *handle.ready.borrow(),
+    }
+}
+
+fn forwards(handle: &Handle) -> Vec<proto::Forward> {
+    handle
+        .forwards()
+        .iter()
+        .map(|(id, pf)| forward(handle, id, pf))
+        .collect()
+}
+
+fn forward(handle: &Handle, id: &ForwardId, pf: &PortForwardConfig) -> proto::Forward {
+    proto::Forward {
+        id: id.to_string(),
+        notation: pf.to_string(),
+        listen_addrs: handle
+            .listeners()
+            .iter()
+            .filter(|(listening, _)| listening == pf)
+            .map(|(_, addr)| addr.to_string())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::control_client::ControlClient;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn config() -> Config {
+        Config::builder()
+            .private_key("52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I=")
+            .endpoint_public_key("0JSV/PhWC6sd9tl/KHlJk8gTLvf+zQul7oSrjSwRxRQ=")
+            .endpoint_addr("127.0.0.1:51820".parse().unwrap())
+            .source_peer_ip("192.168.4.3".parse().unwrap())
+            .port_forwards(
+                PortForwardConfig::from_notation("0:192.168.4.1:80;name=web", "127.0.0.1").unwrap(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    fn authorized<T>(message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        let bearer = format!("Bearer {}", TOKEN);
+        request
+            .metadata_mut()
+            .insert("authorization", bearer.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_grpc_control() {
+        let config = config()
+            .with_grpc_listen("127.0.0.1:0".parse().unwrap())
+            .with_control_token(ControlToken::new(TOKEN).unwrap());
+        let handle = crate::start(config).await.unwrap();
+        let id = handle.id();
+        let server = GrpcServer::bind(&handle).await.unwrap().unwrap();
+        let addr = server.local_addr().unwrap();
+        let server = tokio::spawn(server.run(Arc::new(Mutex::new(handle))));
+
+        let mut client = ControlClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+
+        // Calls without the token are rejected
+        let status = client
+            .list_tunnels(proto::ListTunnelsRequest {})
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let tunnels = client
+            .list_tunnels(authorized(proto::ListTunnelsRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .tunnels;
+        assert_eq!(tunnels.len(), 1);
+        assert_eq!(tunnels[0].id, id);
+        assert_eq!(tunnels[0].endpoint, "127.0.0.1:51820");
+        assert!(!tunnels[0].ready);
+        assert_eq!(tunnels[0].forwards[0].id, "web");
+
+        // Port forwards are created and deleted while the tunnel runs
+        let created = client
+            .create_forward(authorized(proto::CreateForwardRequest {
+                tunnel_id: id,
+                notation: "0:192.168.4.2:80".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(created.id, "#1");
+        assert_eq!(created.listen_addrs.len(), 1);
+        let forwards = client
+            .list_forwards(authorized(proto::ListForwardsRequest { tunnel_id: id }))
+            .await
+            .unwrap()
+            .into_inner()
+            .forwards;
+        assert_eq!(forwards.len(), 2);
+        let delete = |forward_id: &str| {
+            authorized(proto::DeleteForwardRequest {
+                tunnel_id: id,
+                forward_id: forward_id.to_string(),
+                grace_secs: 0,
+            })
+        };
+        client.delete_forward(delete("#1")).await.unwrap();
+        let status = client.delete_forward(delete("#1")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        // The endpoint never answered
+        let health = client
+            .health(authorized(proto::HealthRequest {
+                tunnel_id: Some(id),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            health.status,
+            proto::health_response::Status::NotServing as i32
+        );
+
+        // The statistics of the port forwards are streamed as they are reported
+        let mut stats = client
+            .watch_stats(authorized(proto::WatchStatsRequest { tunnel_id: id }))
+            .await
+            .unwrap()
+            .into_inner();
+        let reported = stats.message().await.unwrap().unwrap();
+        assert_eq!(reported.forward_id, "web");
+        assert_eq!(reported.bytes_in, 0);
+
+        // Other tunnels are started and stopped through the service
+        let started = client
+            .start_tunnel(authorized(proto::StartTunnelRequest {
+                private_key: "52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I=".to_string(),
+                endpoint_public_key: "0JSV/PhWC6sd9tl/KHlJk8gTLvf+zQul7oSrjSwRxRQ=".to_string(),
+                endpoint_addr: "127.0.0.1:51821".to_string(),
+                source_peer_ip: "192.168.4.4".to_string(),
+                keepalive_seconds: 0,
+                port_forwards: vec!["127.0.0.1:0:192.168.4.1:443".to_string()],
+                remote_port_forwards: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_ne!(started.id, id);
+        assert_eq!(started.forwards.len(), 1);
+        client
+            .stop_tunnel(authorized(proto::StopTunnelRequest {
+                tunnel_id: started.id,
+            }))
+            .await
+            .unwrap();
+        let tunnels = client
+            .list_tunnels(authorized(proto::ListTunnelsRequest {}))
+            .await
+            .unwrap()
+            .into_inner()
+            .tunnels;
+        assert_eq!(tunnels.len(), 1);
+
+        // Stopping the tunnel the service was bound with stops the service
+        client
+            .stop_tunnel(authorized(proto::StopTunnelRequest { tunnel_id: id }))
+            .await
+            .unwrap();
+        server.await.unwrap();
+    }
+}
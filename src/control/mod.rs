@@ -0,0 +1,196 @@
+//! The runtime control interfaces of a tunnel, and their access control: they only listen on loopback
+//! addresses unless remote control is allowed, and each request must carry the token configured with
+//! `Config::with_control_token`.
+//!
+//! The control socket (see `Config::with_control_socket`) serves the JSON-RPC protocol described in
+//! `protocol`, on a Unix socket or a TCP address. `ControlClient` calls it from other Rust programs. With the
+//! `grpc` feature, `grpc` serves the gRPC control service (see `Config::with_grpc_listen`).
+
+use std::fmt::{Debug, Display, Formatter};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+
+#[cfg(feature = "control")]
+mod client;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "control")]
+pub mod protocol;
+#[cfg(feature = "control")]
+mod server;
+
+#[cfg(feature = "control")]
+pub use client::ControlClient;
+#[cfg(feature = "control")]
+pub use server::ControlServer;
+
+/// A connection to the control socket, on a Unix socket or over TCP.
+#[cfg(feature = "control")]
+trait Connection: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+
+#[cfg(feature = "control")]
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send> Connection for T {}
+
+/// The shortest token accepted, so that it can't be guessed by a local user trying them out.
+pub const MIN_TOKEN_LENGTH: usize = 16;
+
+/// The token authenticating the requests to the control interfaces.
+#[derive(Clone)]
+pub struct ControlToken(Arc<[u8]>);
+
+impl ControlToken {
+    /// A token, without its surrounding whitespace. Fails if it is shorter than `MIN_TOKEN_LENGTH`.
+    pub fn new(token: &str) -> anyhow::Result<Self> {
+        let token = token.trim();
+        if token.len() < MIN_TOKEN_LENGTH {
+            return Err(anyhow::anyhow!(
+                "Control token must be at least {} characters long",
+                MIN_TOKEN_LENGTH
+            ));
+        }
+        Ok(Self(token.as_bytes().into()))
+    }
+
+    /// Reads the token from the first line of a file.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read control token file {:?}", path))?;
+        Self::new(content.lines().next().unwrap_or_default())
+    }
+
+    /// Whether the token presented with a request is this one. The comparison takes the same time
+    /// whatever the first differing character, so that the token can't be guessed from the timing.
+    pub fn verify(&self, presented: &str) -> bool {
+        let presented = presented.as_bytes();
+        if presented.len() != self.0.len() {
+            return false;
+        }
+        presented
+            .iter()
+            .zip(self.0.iter())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+
+    /// The token, as presented with the requests.
+    #[cfg_attr(not(feature = "control"), allow(dead_code))]
+    pub(crate) fn expose(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("Tokens are created from strings")
+    }
+}
+
+impl Debug for ControlToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ControlToken(<redacted>)")
+    }
+}
+
+/// Where the control socket listens.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ControlAddress {
+    /// A Unix socket at this path, which only exists on Unix. Only the user running onetun can connect.
+    Unix(PathBuf),
+    /// A TCP address, on loopback unless remote control is allowed (see `check_listen_addr`).
+    Tcp(SocketAddr),
+}
+
+impl FromStr for ControlAddress {
+    type Err = anyhow::Error;
+
+    /// An `ip:port` address, or the path of a Unix socket.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = SocketAddr::from_str(s) {
+            return Ok(Self::Tcp(addr));
+        }
+        if s.is_empty() {
+            return Err(anyhow::anyhow!("Empty control socket address"));
+        }
+        Ok(Self::Unix(PathBuf::from(s)))
+    }
+}
+
+impl Display for ControlAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "{}", path.display()),
+            Self::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// Checks that a control interface may listen on the address. Loopback addresses are always allowed; other
+/// addresses require remote control to be allowed (see `Config::with_remote_control`), and a token.
+pub fn check_listen_addr(
+    addr: SocketAddr,
+    token: Option<&ControlToken>,
+    allow_remote: bool,
+) -> anyhow::Result<()> {
+    if addr.ip().is_loopback() {
+        return Ok(());
+    }
+    if !allow_remote {
+        return Err(anyhow::anyhow!(
+            "Control interfaces only listen on loopback addresses, unless remote control is allowed: {}",
+            addr
+        ));
+    }
+    if token.is_none() {
+        return Err(anyhow::anyhow!(
+            "A control token is required to listen on a non-loopback address: {}",
+            addr
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_token() {
+        assert!(ControlToken::new("short").is_err());
+        let token = ControlToken::new(" 0123456789abcdef\n").unwrap();
+        assert!(token.verify("0123456789abcdef"));
+        assert!(!token.verify("0123456789abcdeF"));
+        assert!(!token.verify("0123456789abcdef0"));
+        assert!(!token.verify(""));
+        assert_eq!(format!("{:?}", token), "ControlToken(<redacted>)");
+
+        let path = std::env::temp_dir().join(format!("onetun-token-{}", std::process::id()));
+        std::fs::write(&path, "0123456789abcdef\nignored\n").unwrap();
+        assert!(ControlToken::from_file(&path)
+            .unwrap()
+            .verify("0123456789abcdef"));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_check_listen_addr() {
+        let token = ControlToken::new("0123456789abcdef").unwrap();
+        let loopback: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let any: SocketAddr = "0.0.0.0:9000".parse().unwrap();
+        assert!(check_listen_addr(loopback, None, false).is_ok());
+        assert!(check_listen_addr("[::1]:9000".parse().unwrap(), None, false).is_ok());
+        assert!(check_listen_addr(any, Some(&token), false).is_err());
+        assert!(check_listen_addr(any, None, true).is_err());
+        assert!(check_listen_addr(any, Some(&token), true).is_ok());
+    }
+
+    #[test]
+    fn test_parse_control_address() {
+        assert_eq!(
+            ControlAddress::from_str("127.0.0.1:9000").unwrap(),
+            ControlAddress::Tcp("127.0.0.1:9000".parse().unwrap())
+        );
+        assert_eq!(
+            ControlAddress::from_str("/run/onetun.sock").unwrap(),
+            ControlAddress::Unix(PathBuf::from("/run/onetun.sock"))
+        );
+        assert!(ControlAddress::from_str("").is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! The JSON-RPC 2.0 protocol of the control socket.
+//!
+//! Each request and each response is a JSON object on its own line, at most `MAX_MESSAGE_LENGTH` bytes long.
+//! Requests are answered in order; notifications (requests without an `id`) are executed without an answer.
+//! With a control token, the `params` object of each request carries it as `token`:
+//!
+//! ```text
+//! --> {"jsonrpc":"2.0","id":1,"method":"forwards.remove","params":{"id":"web","grace_secs":30,"token":"..."}}
+//! <-- {"jsonrpc":"2.0","id":1,"result":{}}
+//! ```
+//!
+//! Methods, with their parameters and result:
+//!  - `status`: the tunnel's `TunnelStatus`.
+//!  - `forwards.list`: the running local port forwards, as `Forward`s.
+//!  - `forwards.add`, `{"notation": "8080:192.168.4.2:80:TCP"}`: adds the port forwards of the definition,
+//!    like `Handle::add_forward`. Returns them as `Forward`s.
+//!  - `forwards.remove`, `{"id": "web", "grace_secs": 30}`: removes a port forward by its name or `#<index>`,
+//!    like `Handle::remove_forward`. The grace period defaults to 0.
+//!  - `stats`: the tunnel's `TunnelStats`.
+//!  - `reload`, `{"forwards": ["8080:192.168.4.2:80:TCP"], "grace_secs": 30}`: replaces the local port
+//!    forwards, like `Handle::reload_forwards`. Returns the `ForwardChanges`.
+//!  - `kill`: kills the tunnel, after answering.
+//!
+//! Errors use the standard JSON-RPC codes, and the codes below for the failures of the methods.
+
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::virtual_device::DeviceStats;
+use crate::wg::ProtocolErrors;
+
+/// The value of the `jsonrpc` member of the messages.
+pub const VERSION: &str = "2.0";
+/// The longest message accepted, newline included. Longer requests close the connection.
+pub const MAX_MESSAGE_LENGTH: usize = 64 * 1024;
+
+pub const STATUS: &str = "status";
+pub const FORWARDS_LIST: &str = "forwards.list";
+pub const FORWARDS_ADD: &str = "forwards.add";
+pub const FORWARDS_REMOVE: &str = "forwards.remove";
+pub const STATS: &str = "stats";
+pub const RELOAD: &str = "reload";
+pub const KILL: &str = "kill";
+
+/// The message isn't valid JSON.
+pub const PARSE_ERROR: i64 = -32700;
+/// The message isn't a JSON-RPC 2.0 request.
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The method failed, such as a port forward that couldn't listen.
+pub const FAILED: i64 = -32000;
+/// The request didn't carry the control token.
+pub const UNAUTHORIZED: i64 = -32001;
+/// There is no port forward with the given identifier.
+pub const FORWARD_NOT_FOUND: i64 = -32002;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    /// Absent for notifications, which aren't answered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    /// The identifier of the request, or null if it couldn't be read.
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl Response {
+    pub fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: VERSION.to_string(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+/// The error of a failed request.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// The result of `status`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    /// The identifier of the tunnel in its process, like `Handle::id`.
+    pub id: u32,
+    /// Whether the first handshake completed, like `Handle::ready`.
+    pub ready: bool,
+    /// The address the datagrams are sent to, like `Handle::current_endpoint`.
+    pub endpoint: SocketAddr,
+    /// The public key of the endpoint, once verified by a handshake.
+    pub verified_peer: Option<String>,
+    /// How long ago the latest handshake completed, if any.
+    pub latest_handshake_secs: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// A running local port forward.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Forward {
+    /// The name of the port forward, or `#<index>` if unnamed, like `ForwardId`.
+    pub id: String,
+    /// The port forward in the command-line notation.
+    pub notation: String,
+    /// The addresses it listens on, like `Handle::listeners`.
+    pub listen_addrs: Vec<SocketAddr>,
+}
+
+/// The result of `reload`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ForwardChanges {
+    pub added: Vec<Forward>,
+    /// The identifiers of the removed port forwards, which drain their sessions.
+    pub removed: Vec<String>,
+}
+
+/// The result of `stats`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TunnelStats {
+    /// The drop counters of the virtual device of each protocol in use, like `Handle::stats`.
+    pub devices: Vec<DeviceReport>,
+    pub protocol_errors: ProtocolErrors,
+    pub disallowed_packets: u64,
+    pub cookie_replies: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DeviceReport {
+    /// `TCP` or `UDP`.
+    pub protocol: String,
+    #[serde(flatten)]
+    pub stats: DeviceStats,
+}
+
+/// The parameters of `forwards.add`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddForwardParams {
+    pub notation: String,
+}
+
+/// The parameters of `forwards.remove`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoveForwardParams {
+    pub id: String,
+    #[serde(default)]
+    pub grace_secs: u64,
+}
+
+/// The parameters of `reload`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReloadParams {
+    pub forwards: Vec<String>,
+    #[serde(default)]
+    pub grace_secs: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let request: Request =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","id":7,"method":"status"}"#).unwrap();
+        assert_eq!(request.id, Some(Value::from(7)));
+        assert_eq!(request.params, Value::Null);
+
+        let notification: Request =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","method":"kill","params":{}}"#).unwrap();
+        assert_eq!(notification.id, None);
+
+        let response = Response::new(Value::from(7), Ok(Value::from(true)));
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"jsonrpc":"2.0","id":7,"result":true}"#
+        );
+        let response = Response::new(Value::Null, Err(RpcError::new(PARSE_ERROR, "Invalid JSON")));
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32700,"message":"Invalid JSON"}}"#
+        );
+
+        let params: RemoveForwardParams =
+            serde_json::from_str(r#"{"id":"web","token":"ignored"}"#).unwrap();
+        assert_eq!(params.grace_secs, 0);
+    }
+}
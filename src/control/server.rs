@@ -0,0 +1,477 @@
+//! Serves the control socket of a tunnel: a Unix socket, or a TCP address on loopback unless remote control
+//! is allowed. See `protocol` for the requests.
+
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{broadcast, Mutex};
+
+use super::protocol::{self, Forward, ForwardChanges, Request, Response, RpcError};
+use super::{check_listen_addr, Connection, ControlAddress, ControlToken};
+use crate::audit::Initiator;
+use crate::config::{ForwardId, PortForwardConfig, DEFAULT_PORT_FORWARD_SOURCE};
+use crate::Handle;
+
+/// The control socket of a tunnel, listening.
+pub struct ControlServer {
+    listener: ControlListener,
+    token: Option<ControlToken>,
+    kill_switch: broadcast::Receiver<()>,
+}
+
+enum ControlListener {
+    /// Removed when the server stops.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    Tcp(TcpListener),
+}
+
+impl ControlServer {
+    /// Listens on the control socket of the tunnel (see `Config::with_control_socket`). None if it has none.
+    pub async fn bind(handle: &Handle) -> anyhow::Result<Option<Self>> {
+        let address = match &handle.control_socket {
+            Some(address) => address,
+            None => return Ok(None),
+        };
+        let listener = match address {
+            ControlAddress::Tcp(addr) => {
+                check_listen_addr(*addr, handle.control_token.as_ref(), handle.remote_control)?;
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Failed to bind the control socket on {}", addr))?;
+                ControlListener::Tcp(listener)
+            }
+            #[cfg(unix)]
+            ControlAddress::Unix(path) => ControlListener::Unix(bind_unix(path)?, path.clone()),
+            #[cfg(not(unix))]
+            ControlAddress::Unix(path) => {
+                return Err(anyhow::anyhow!(
+                    "Unix sockets are not supported on this platform: {}",
+                    path.display()
+                ))
+            }
+        };
+        info!(
+            "[tunnel {}] Control socket listening on {}",
+            handle.id(),
+            address
+        );
+        Ok(Some(Self {
+            listener,
+            token: handle.control_token.clone(),
+            kill_switch: handle.get_killer(),
+        }))
+    }
+
+    /// The TCP address the control socket listens on, with the port chosen by the system if it was 0. None
+    /// for a Unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            ControlListener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            ControlListener::Unix(..) => None,
+        }
+    }
+
+    /// Serves the requests of each client until the tunnel is killed.
+    pub async fn run(mut self, handle: Arc<Mutex<Handle>>) {
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = self.kill_switch.recv() => break,
+            };
+            let connection = match accepted {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to accept a control socket client: {}", e);
+                    continue;
+                }
+            };
+            let kill_switch = handle.lock().await.get_killer();
+            let handle = handle.clone();
+            let token = self.token.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(connection, &handle, token.as_ref(), kill_switch).await {
+                    debug!("Control socket client failed: {:#}", e);
+                }
+            });
+        }
+        #[cfg(unix)]
+        if let ControlListener::Unix(_, path) = &self.listener {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+impl ControlListener {
+    async fn accept(&self) -> std::io::Result<Box<dyn Connection>> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(listener, _) => Ok(Box::new(listener.accept().await?.0)),
+            Self::Tcp(listener) => Ok(Box::new(listener.accept().await?.0)),
+        }
+    }
+}
+
+/// Listens on the Unix socket, replacing the file left by an instance that didn't stop cleanly. Only the
+/// user running onetun can connect.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow::anyhow!(
+                "Another process serves the control socket {:?}",
+                path
+            ));
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove the stale control socket {:?}", path))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind the control socket on {:?}", path))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict the control socket {:?}", path))?;
+    Ok(listener)
+}
+
+/// Answers the requests of the client, one per line, until it disconnects or the tunnel is killed. The
+/// request killing the tunnel is answered.
+async fn serve(
+    connection: Box<dyn Connection>,
+    handle: &Mutex<Handle>,
+    token: Option<&ControlToken>,
+    mut kill_switch: broadcast::Receiver<()>,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = tokio::io::split(connection);
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let mut limited = (&mut reader).take(protocol::MAX_MESSAGE_LENGTH as u64);
+        let read = tokio::select! {
+            read = limited.read_line(&mut line) => read?,
+            _ = kill_switch.recv() => return Ok(()),
+        };
+        if read == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && read == protocol::MAX_MESSAGE_LENGTH {
+            let error = RpcError::new(protocol::INVALID_REQUEST, "Request too long");
+            write_response(&mut writer, &Response::new(Value::Null, Err(error))).await?;
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = answer(line.trim(), handle, token).await {
+            write_response(&mut writer, &response).await?;
+        }
+    }
+}
+
+async fn write_response(
+    writer: &mut (impl AsyncWrite + Unpin),
+    response: &Response,
+) -> anyhow::Result<()> {
+    let mut message = serde_json::to_vec(response)?;
+    message.push(b'\n');
+    writer.write_all(&message).await?;
+    Ok(())
+}
+
+/// Executes the request. Returns the response, unless the request is a notification.
+async fn answer(
+    message: &str,
+    handle: &Mutex<Handle>,
+    token: Option<&ControlToken>,
+) -> Option<Response> {
+    let value: Value = match serde_json::from_str(message) {
+        Ok(value) => value,
+        Err(e) => {
+            let error = RpcError::new(protocol::PARSE_ERROR, e);
+            return Some(Response::new(Value::Null, Err(error)));
+        }
+    };
+    let request: Request = match serde_json::from_value(value) {
+        Ok(request) if request_is_valid(&request) => request,
+        _ => {
+            let error = RpcError::new(protocol::INVALID_REQUEST, "Not a JSON-RPC 2.0 request");
+            return Some(Response::new(Value::Null, Err(error)));
+        }
+    };
+    let outcome = execute(&request, handle, token).await;
+    request.id.map(|id| Response::new(id, outcome))
+}
+
+fn request_is_valid(request: &Request) -> bool {
+    request.jsonrpc == protocol::VERSION && (request.params.is_object() || request.params.is_null())
+}
+
+async fn execute(
+    request: &Request,
+    handle: &Mutex<Handle>,
+    token: Option<&ControlToken>,
+) -> Result<Value, RpcError> {
+    if let Some(token) = token {
+        let presented = request.params.get("token").and_then(Value::as_str);
+        if !presented.map_or(false, |presented| token.verify(presented)) {
+            warn!(
+                "Rejected a control socket request without the control token: {}",
+                request.method
+            );
+            return Err(RpcError::new(
+                protocol::UNAUTHORIZED,
+                "Missing or invalid control token",
+            ));
+        }
+    }
+
+    let mut handle = handle.lock().await;
+    let initiator = Initiator::ControlSocket;
+    match request.method.as_str() {
+        protocol::STATUS => result(status(&handle)),
+        protocol::FORWARDS_LIST => {
+            let forwards: Vec<Forward> = handle
+                .forwards()
+                .iter()
+                .map(|(id, pf)| forward(&handle, id, pf))
+                .collect();
+            result(forwards)
+        }
+        protocol::FORWARDS_ADD => {
+            let params: protocol::AddForwardParams = params(request)?;
+            let port_forwards = parse_forwards(&[params.notation])?;
+            let bind_policy = handle.bind_policy;
+            let mut added = Vec::new();
+            for pf in port_forwards {
+                match handle
+                    .add_forward_by(initiator, pf.clone(), bind_policy)
+                    .await
+                {
+                    Ok(id) => added.push((id, pf)),
+                    Err(e) => {
+                        // The protocols of a definition are added together, or not at all
+                        for (id, _) in added {
+                            handle.remove_forward_by(initiator, &id, Duration::ZERO);
+                        }
+                        return Err(RpcError::new(protocol::FAILED, e));
+                    }
+                }
+            }
+            let added: Vec<Forward> = added
+                .iter()
+                .map(|(id, pf)| forward(&handle, id, pf))
+                .collect();
+            result(added)
+        }
+        protocol::FORWARDS_REMOVE => {
+            let params: protocol::RemoveForwardParams = params(request)?;
+            let id = ForwardId::from_str(&params.id)
+                .map_err(|e| RpcError::new(protocol::INVALID_PARAMS, format!("{:#}", e)))?;
+            let grace = Duration::from_secs(params.grace_secs);
+            if handle.remove_forward_by(initiator, &id, grace) {
+                Ok(Value::Object(Default::default()))
+            } else {
+                Err(RpcError::new(
+                    protocol::FORWARD_NOT_FOUND,
+                    format!("No port forward {}", id),
+                ))
+            }
+        }
+        protocol::STATS => result(stats(&handle)),
+        protocol::RELOAD => {
+            let params: protocol::ReloadParams = params(request)?;
+            let port_forwards = parse_forwards(&params.forwards)?;
+            let grace = Duration::from_secs(params.grace_secs);
+            let (added, removed) = handle
+                .reload_forwards_by(initiator, port_forwards, grace)
+                .await
+                .map_err(|e| RpcError::new(protocol::FAILED, e))?;
+            let running = handle.forwards();
+            let changes = ForwardChanges {
+                added: running
+                    .iter()
+                    .filter(|(id, _)| added.contains(id))
+                    .map(|(id, pf)| forward(&handle, id, pf))
+                    .collect(),
+                removed: removed.iter().map(ForwardId::to_string).collect(),
+            };
+            result(changes)
+        }
+        protocol::KILL => {
+            handle.kill_by(initiator);
+            Ok(Value::Object(Default::default()))
+        }
+        method => Err(RpcError::new(
+            protocol::METHOD_NOT_FOUND,
+            format!("Unknown method {}", method),
+        )),
+    }
+}
+
+fn result(result: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(result).map_err(|e| RpcError::new(protocol::FAILED, e))
+}
+
+fn params<P: DeserializeOwned>(request: &Request) -> Result<P, RpcError> {
+    serde_json::from_value(request.params.clone())
+        .map_err(|e| RpcError::new(protocol::INVALID_PARAMS, e))
+}
+
+/// Parses port forward definitions in the command-line notation.
+fn parse_forwards(notations: &[String]) -> Result<Vec<PortForwardConfig>, RpcError> {
+    let mut port_forwards = Vec::new();
+    for notation in notations {
+        let parsed = PortForwardConfig::from_notation(notation, DEFAULT_PORT_FORWARD_SOURCE)
+            .map_err(|e| RpcError::new(protocol::INVALID_PARAMS, format!("{:#}", e)))?;
+        port_forwards.extend(parsed);
+    }
+    Ok(port_forwards)
+}
+
+fn forward(handle: &Handle, id: &ForwardId, pf: &PortForwardConfig) -> Forward {
+    Forward {
+        id: id.to_string(),
+        notation: pf.to_string(),
+        listen_addrs: handle
+            .listeners()
+            .iter()
+            .filter(|(listening, _)| listening == pf)
+            .map(|(_, addr)| *addr)
+            .collect(),
+    }
+}
+
+fn status(handle: &Handle) -> protocol::TunnelStatus {
+    let interface = handle.interface_status();
+    protocol::TunnelStatus {
+        id: handle.id(),
+        ready: *handle.ready.borrow(),
+        endpoint: handle.current_endpoint(),
+        verified_peer: handle.verified_peer(),
+        latest_handshake_secs: interface.latest_handshake.map(|ago| ago.as_secs()),
+        rx_bytes: interface.rx_bytes,
+        tx_bytes: interface.tx_bytes,
+    }
+}
+
+fn stats(handle: &Handle) -> protocol::TunnelStats {
+    protocol::TunnelStats {
+        devices: handle
+            .stats()
+            .devices
+            .into_iter()
+            .map(|(proto, stats)| protocol::DeviceReport {
+                protocol: proto.to_string(),
+                stats,
+            })
+            .collect(),
+        protocol_errors: handle.protocol_errors(),
+        disallowed_packets: handle.disallowed_packets(),
+        cookie_replies: handle.cookie_replies(),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::control::ControlClient;
+
+    #[tokio::test]
+    async fn test_control_socket() {
+        let path = std::env::temp_dir().join(format!("onetun-control-{}.sock", std::process::id()));
+        let address = ControlAddress::Unix(path.clone());
+        let token = ControlToken::new("0123456789abcdef").unwrap();
+        let config = Config::builder()
+            .private_key("52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I=")
+            .endpoint_public_key("0JSV/PhWC6sd9tl/KHlJk8gTLvf+zQul7oSrjSwRxRQ=")
+            .endpoint_addr("127.0.0.1:51820".parse().unwrap())
+            .source_peer_ip("192.168.4.3".parse().unwrap())
+            .port_forwards(
+                PortForwardConfig::from_notation("0:192.168.4.1:80;name=web", "127.0.0.1").unwrap(),
+            )
+            .build()
+            .unwrap()
+            .with_control_socket(address.clone())
+            .with_control_token(token.clone());
+        let handle = crate::start(config).await.unwrap();
+        let server = ControlServer::bind(&handle).await.unwrap().unwrap();
+        let server = tokio::spawn(server.run(Arc::new(Mutex::new(handle))));
+
+        // Requests without the token are rejected
+        let client = ControlClient::connect(&address).await.unwrap();
+        let mut client = client.with_token(ControlToken::new("fedcba9876543210").unwrap());
+        let error = client.status().await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<RpcError>().unwrap().code,
+            protocol::UNAUTHORIZED
+        );
+
+        let client = ControlClient::connect(&address).await.unwrap();
+        let mut client = client.with_token(token);
+        let status = client.status().await.unwrap();
+        assert!(!status.ready);
+        assert_eq!(status.endpoint, "127.0.0.1:51820".parse().unwrap());
+        let forwards = client.forwards().await.unwrap();
+        assert_eq!(forwards.len(), 1);
+        assert_eq!(forwards[0].id, "web");
+        assert_ne!(forwards[0].listen_addrs[0].port(), 0);
+
+        // Unnamed port forwards are identified after those of the configuration
+        let added = client.add_forward("0:192.168.4.2:80").await.unwrap();
+        assert_eq!(added[0].id, "#1");
+        assert_eq!(client.forwards().await.unwrap().len(), 2);
+        // Without a UDP port forward, the UDP virtual interface isn't running
+        let error = client
+            .add_forward("0:192.168.4.2:53:UDP")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<RpcError>().unwrap().code,
+            protocol::FAILED
+        );
+        client
+            .remove_forward(&ForwardId::Index(1), Duration::ZERO)
+            .await
+            .unwrap();
+        let error = client
+            .remove_forward(&ForwardId::Index(1), Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<RpcError>().unwrap().code,
+            protocol::FORWARD_NOT_FOUND
+        );
+
+        let changes = client
+            .reload(&["0:192.168.4.3:80;name=api"], Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(changes.removed, vec!["web".to_string()]);
+        assert_eq!(changes.added.len(), 1);
+        assert_eq!(changes.added[0].id, "api");
+
+        let stats = client.stats().await.unwrap();
+        assert_eq!(stats.devices[0].protocol, "TCP");
+
+        // The server answers, then stops and removes its socket
+        client.kill().await.unwrap();
+        server.await.unwrap();
+        assert!(!path.exists());
+    }
+}
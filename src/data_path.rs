@@ -0,0 +1,185 @@
+//! The runtime of the data path: the WireGuard tasks and the poll loops of the virtual interfaces. It is the
+//! runtime that starts the tunnel, unless the data path has threads of its own (see
+//! `Config::with_data_path_threads`).
+
+use std::future::Future;
+
+use tokio::runtime::{self, Runtime};
+use tokio::task::JoinHandle;
+
+use crate::config::Config;
+
+/// Where the tasks of the data path are spawned. A dedicated runtime is shut down when dropped.
+pub(crate) struct DataPath {
+    runtime: Option<Runtime>,
+    handle: runtime::Handle,
+}
+
+impl DataPath {
+    /// The data path of the configuration. Must be called from a runtime.
+    pub fn new(config: &Config, tunnel_id: u32) -> std::io::Result<Self> {
+        if config.data_path_threads == 0 {
+            return Ok(Self {
+                runtime: None,
+                handle: runtime::Handle::current(),
+            });
+        }
+        let priority = config.data_path_priority;
+        let cores = config.cpu_affinity.clone();
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(config.data_path_threads)
+            .thread_name(format!("onetun-data-{}", tunnel_id))
+            .on_thread_start(move || {
+                if let Some(nice) = priority {
+                    if let Err(e) = set_thread_priority(nice) {
+                        warn!("Failed to set the priority of a data path thread: {}", e);
+                    }
+                }
+                apply_cpu_affinity(&cores);
+            })
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            handle: runtime.handle().clone(),
+            runtime: Some(runtime),
+        })
+    }
+
+    /// Spawns a task of the data path.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.handle.spawn(future)
+    }
+
+    /// Enters the runtime of the data path, so that the tasks spawned with `tokio::spawn` meanwhile run on it.
+    pub fn enter(&self) -> runtime::EnterGuard<'_> {
+        self.handle.enter()
+    }
+
+    /// Runs the future on the data path, so that the sockets and timers it creates are driven by it.
+    pub async fn run<F>(&self, future: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match self.runtime {
+            Some(_) => match self.spawn(future).await {
+                Ok(output) => output,
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            },
+            None => future.await,
+        }
+    }
+}
+
+impl Drop for DataPath {
+    fn drop(&mut self) {
+        // Blocking on the shutdown isn't allowed from the runtime that drops it
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Restricts the calling thread to the cores, if any, warning on failure.
+pub(crate) fn apply_cpu_affinity(cores: &[usize]) {
+    if cores.is_empty() {
+        return;
+    }
+    if let Err(e) = set_thread_affinity(cores) {
+        warn!("Failed to set the CPU affinity of a thread: {}", e);
+    }
+}
+
+/// Restricts the calling thread to the cores.
+#[cfg(target_os = "linux")]
+fn set_thread_affinity(cores: &[usize]) -> std::io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &core in cores {
+            if core >= libc::CPU_SETSIZE as usize {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("core {} is out of range", core),
+                ));
+            }
+            libc::CPU_SET(core, &mut set);
+        }
+        // A thread ID of 0 is the calling thread
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// CPU affinity is only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+fn set_thread_affinity(_cores: &[usize]) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+/// Sets the nice value of the calling thread.
+#[cfg(target_os = "linux")]
+fn set_thread_priority(nice: i32) -> std::io::Result<()> {
+    // On Linux, the nice value of a thread is set through its thread ID
+    let tid = unsafe { libc::gettid() };
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Thread priorities are only supported on Linux, where the nice value is per thread.
+#[cfg(not(target_os = "linux"))]
+fn set_thread_priority(_nice: i32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "thread priorities are only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dedicated_runtime() {
+        let config = Config::builder()
+            .private_key("52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I=")
+            .endpoint_public_key("0JSV/PhWC6sd9tl/KHlJk8gTLvf+zQul7oSrjSwRxRQ=")
+            .endpoint_addr("127.0.0.1:51820".parse().unwrap())
+            .source_peer_ip("192.168.4.3".parse().unwrap())
+            .build()
+            .unwrap()
+            .with_data_path_threads(2)
+            .with_data_path_priority(5)
+            .with_cpu_affinity(vec![0]);
+        let data_path = DataPath::new(&config, 1).unwrap();
+
+        let (name, nice, cores) = data_path
+            .run(async {
+                let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, libc::gettid() as _) };
+                let cores = unsafe {
+                    let mut set: libc::cpu_set_t = std::mem::zeroed();
+                    libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+                    libc::CPU_COUNT(&set)
+                };
+                (std::thread::current().name().map(String::from), nice, cores)
+            })
+            .await;
+        assert_eq!(name.as_deref(), Some("onetun-data-1"));
+        assert_eq!(nice, 5);
+        assert_eq!(cores, 1);
+
+        // Dropped from the runtime that started the tunnel
+        drop(data_path);
+    }
+}
@@ -0,0 +1,356 @@
+//! Checks of the environment onetun runs in, printed by `onetun doctor`: whether the endpoint answers a
+//! handshake, whether the local ports are free, whether the MTU fits the usual links, whether IPv6 is
+//! available, whether the system proxy settings let UDP through, and whether the clock is right. Each check returns findings with the action to take, if any.
+
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, PortProtocol};
+use crate::platform::{self, ProxySettings};
+use crate::wg::WireGuardTunnel;
+
+/// The MTU of the usual links (Ethernet, Wi-Fi), which the datagrams to the endpoint should fit in.
+const LINK_MTU: usize = 1500;
+/// What WireGuard adds to each IP packet: the data message header and authentication tag, then the UDP header.
+const WIREGUARD_OVERHEAD: usize = 16 + 16 + 8;
+/// An address on the IPv6 internet, to find out whether there is a route to it. Nothing is sent to it.
+const IPV6_PROBE: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111)),
+    53,
+);
+/// The SNTP server the clock is compared to.
+const TIME_SERVER: &str = "pool.ntp.org:123";
+const TIME_TIMEOUT: Duration = Duration::from_secs(3);
+/// The difference with the time server from which the clock is reported as skewed.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+/// The seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// How bad a finding is.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    Ok,
+    /// The tunnel may work, but something should be looked at.
+    Warning,
+    /// The tunnel won't work as configured.
+    Error,
+}
+
+/// The result of a check.
+#[derive(Clone, Debug)]
+pub struct Finding {
+    /// What was checked.
+    pub check: String,
+    pub severity: Severity,
+    /// What was found, and what to do about it.
+    pub message: String,
+}
+
+impl Finding {
+    fn new(check: impl Into<String>, severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            check: check.into(),
+            severity,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "[{}] {}: {}", severity, self.check, self.message)
+    }
+}
+
+/// Runs all the checks.
+pub async fn run(config: &Config) -> Vec<Finding> {
+    let mut findings = endpoint(config).await;
+    findings.extend(local_ports(config));
+    findings.push(mtu(config));
+    findings.push(ipv6(config));
+    let settings = tokio::task::spawn_blocking(platform::proxy_settings)
+        .await
+        .unwrap_or_default();
+    findings.push(system_proxy(config, &settings));
+    findings.push(clock(TIME_SERVER).await);
+    findings
+}
+
+/// Sends a handshake initiation to each address of the endpoint, which must answer it.
+pub async fn endpoint(config: &Config) -> Vec<Finding> {
+    let tunnel = match WireGuardTunnel::standalone(config).await {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            return vec![Finding::new(
+                "endpoint",
+                Severity::Error,
+                format!("Failed to start the WireGuard tunnel: {}", e),
+            )]
+        }
+    };
+    let mut findings = vec![];
+    for addr in config.endpoint_addrs() {
+        let check = format!("endpoint {}", addr);
+        findings.push(match tunnel.probe(addr).await {
+            Some(rtt) => Finding::new(check, Severity::Ok, format!("handshake in {:?}", rtt)),
+            None => Finding::new(
+                check,
+                Severity::Error,
+                "no handshake response: check that the endpoint address and UDP port are reachable from \
+                this network, and that the keys match the peer's configuration",
+            ),
+        });
+    }
+    tunnel.shutdown();
+    findings
+}
+
+/// Binds the source address of each local port forward, which must be free.
+pub fn local_ports(config: &Config) -> Vec<Finding> {
+    config
+        .port_forwards
+        .iter()
+        // Port 0 is chosen by the system, and named pipes and interfaces aren't bound to their source
+        .filter(|pf| pf.source.port() != 0 && pf.pipe.is_none() && pf.interface.is_none())
+        .map(|pf| {
+            let check = format!("{} port {}", pf.protocol, pf.source);
+            let bound = match pf.protocol {
+                PortProtocol::Tcp => TcpListener::bind(pf.source).map(drop),
+                PortProtocol::Udp => UdpSocket::bind(pf.source).map(drop),
+            };
+            match bound {
+                Ok(()) => Finding::new(check, Severity::Ok, "free"),
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Finding::new(
+                    check,
+                    Severity::Error,
+                    "already in use: stop the process listening on it, or choose another port",
+                ),
+                Err(e) => Finding::new(check, Severity::Error, format!("can't be bound: {}", e)),
+            }
+        })
+        .collect()
+}
+
+/// Checks that full IP packets fit in the usual links once encapsulated.
+pub fn mtu(config: &Config) -> Finding {
+    let outer_header = if config.endpoint_addr.is_ipv6() {
+        40
+    } else {
+        20
+    };
+    let datagram = config.max_transmission_unit + WIREGUARD_OVERHEAD + outer_header;
+    let check = format!("MTU {}", config.max_transmission_unit);
+    if datagram > LINK_MTU {
+        Finding::new(
+            check,
+            Severity::Warning,
+            format!(
+                "full packets become datagrams of {} bytes, larger than the usual {} byte links, and may be \
+                fragmented or dropped: lower the MTU to {} unless the path to the endpoint allows it",
+                datagram,
+                LINK_MTU,
+                LINK_MTU - WIREGUARD_OVERHEAD - outer_header
+            ),
+        )
+    } else {
+        Finding::new(
+            check,
+            Severity::Ok,
+            format!("full packets become datagrams of {} bytes", datagram),
+        )
+    }
+}
+
+/// Checks whether this host has a route to the IPv6 internet, required by IPv6 endpoint addresses.
+pub fn ipv6(config: &Config) -> Finding {
+    let available = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+        .and_then(|socket| socket.connect(IPV6_PROBE))
+        .is_ok();
+    let required = config.endpoint_addrs().iter().any(|addr| addr.is_ipv6());
+    match (available, required) {
+        (true, _) => Finding::new("IPv6", Severity::Ok, "available"),
+        (false, true) => Finding::new(
+            "IPv6",
+            Severity::Error,
+            "unavailable, but the endpoint has an IPv6 address: use its IPv4 address instead",
+        ),
+        (false, false) => Finding::new("IPv6", Severity::Ok, "unavailable, and not required"),
+    }
+}
+
+/// Checks the proxy and VPN settings of the operating system, which may keep the datagrams from reaching the
+/// endpoint: HTTP proxies don't carry UDP, and a VPN may route them into a tunnel that drops them.
+pub fn system_proxy(config: &Config, settings: &ProxySettings) -> Finding {
+    const CHECK: &str = "system proxy";
+    if let Some(vpn) = settings.vpns.first() {
+        return Finding::new(
+            CHECK,
+            Severity::Warning,
+            format!(
+                "VPN '{}' is connected: if the endpoint doesn't answer, exclude {} from it",
+                vpn, config.endpoint_addr
+            ),
+        );
+    }
+    match &settings.socks {
+        Some(proxy) if config.ignore_system_proxy => Finding::new(
+            CHECK,
+            Severity::Warning,
+            format!(
+                "SOCKS proxy {} is ignored: if the network only lets UDP through it, remove --ignore-system-proxy",
+                proxy
+            ),
+        ),
+        Some(proxy) => Finding::new(
+            CHECK,
+            Severity::Ok,
+            format!(
+                "datagrams are relayed through SOCKS proxy {}, which must support UDP",
+                proxy
+            ),
+        ),
+        None if settings.http.is_some() || settings.auto_config => Finding::new(
+            CHECK,
+            Severity::Warning,
+            "an HTTP proxy is set, which doesn't carry UDP: if the network only allows traffic through it, \
+            the endpoint is unreachable",
+        ),
+        None => Finding::new(CHECK, Severity::Ok, "none"),
+    }
+}
+
+/// Compares the clock with a time server. Handshake initiations carry a timestamp, which the peer requires
+/// to increase: after the clock goes back, handshakes are rejected until it catches up.
+pub async fn clock(server: &str) -> Finding {
+    match tokio::time::timeout(TIME_TIMEOUT, clock_offset(server)).await {
+        Ok(Ok(offset)) if offset.unsigned_abs() <= MAX_CLOCK_SKEW.as_secs() => Finding::new(
+            "clock",
+            Severity::Ok,
+            format!("within {}s of {}", offset.unsigned_abs(), server),
+        ),
+        Ok(Ok(offset)) => Finding::new(
+            "clock",
+            Severity::Warning,
+            format!(
+                "{}s {} {}: synchronize it, as the peer rejects handshakes if it is later set back",
+                offset.unsigned_abs(),
+                if offset > 0 { "ahead of" } else { "behind" },
+                server
+            ),
+        ),
+        Ok(Err(e)) => Finding::new(
+            "clock",
+            Severity::Warning,
+            format!("couldn't be compared with {}: {:#}", server, e),
+        ),
+        Err(_) => Finding::new(
+            "clock",
+            Severity::Warning,
+            format!("couldn't be compared with {}: no answer", server),
+        ),
+    }
+}
+
+/// The seconds the local clock is ahead of the SNTP server (negative when behind).
+async fn clock_offset(server: &str) -> anyhow::Result<i64> {
+    let socket = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(server).await?;
+    // Version 4, client mode
+    let mut request = [0u8; 48];
+    request[0] = 0x23;
+    socket.send(&request).await?;
+
+    let mut response = [0u8; 48];
+    let size = socket.recv(&mut response).await?;
+    let local = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    server_time(&response[..size]).map(|server| local - server)
+}
+
+/// The Unix time in the transmit timestamp of an SNTP response.
+fn server_time(response: &[u8]) -> anyhow::Result<i64> {
+    if response.len() < 48 {
+        return Err(anyhow::anyhow!("Truncated time server response"));
+    }
+    let seconds = u32::from_be_bytes([response[40], response[41], response[42], response[43]]);
+    if seconds == 0 {
+        return Err(anyhow::anyhow!("Time server did not send its time"));
+    }
+    Ok(seconds as i64 - NTP_UNIX_OFFSET as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortForwardConfig;
+
+    fn config(mtu: usize) -> Config {
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        Config::builder()
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr("127.0.0.1:51820".parse().unwrap())
+            .source_peer_ip("192.168.4.3".parse().unwrap())
+            .mtu(mtu)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_mtu() {
+        assert_eq!(mtu(&config(1420)).severity, Severity::Ok);
+        let finding = mtu(&config(1480));
+        assert_eq!(finding.severity, Severity::Warning);
+        assert!(finding.message.contains("lower the MTU to 1440"));
+    }
+
+    #[test]
+    fn test_local_ports() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let busy = listener.local_addr().unwrap();
+        let mut config = config(1420);
+        config.port_forwards = vec![PortForwardConfig::new(
+            busy,
+            "192.168.4.2:80".parse().unwrap(),
+            PortProtocol::Tcp,
+        )];
+        let findings = local_ports(&config);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_system_proxy() {
+        let config = config(1420);
+        assert_eq!(
+            system_proxy(&config, &ProxySettings::default()).severity,
+            Severity::Ok
+        );
+        let http = ProxySettings {
+            http: Some("proxy:3128".into()),
+            ..Default::default()
+        };
+        assert_eq!(system_proxy(&config, &http).severity, Severity::Warning);
+        let socks = ProxySettings {
+            socks: Some("127.0.0.1:1080".into()),
+            ..http
+        };
+        assert_eq!(system_proxy(&config, &socks).severity, Severity::Ok);
+        let finding = system_proxy(&config.with_ignore_system_proxy(), &socks);
+        assert_eq!(finding.severity, Severity::Warning);
+    }
+
+    #[test]
+    fn test_server_time() {
+        let mut response = [0u8; 48];
+        response[40..44].copy_from_slice(&((NTP_UNIX_OFFSET + 1_000) as u32).to_be_bytes());
+        assert_eq!(server_time(&response).unwrap(), 1_000);
+        assert!(server_time(&[0u8; 48]).is_err());
+        assert!(server_time(&[0u8; 12]).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+//! Echo services that send back what they receive, over TCP and UDP on the same port (like RFC 862), to
+//! validate a pair of onetun tunnels end to end without other tools: one peer runs the echo server behind a
+//! remote port forward, and the other forwards to it, then measures the path with `onetun bench`. See
+//! `onetun echo-server` and `Handle::spawn_echo_responder`.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// How long to wait before accepting again after an error, such as when out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// TCP and UDP echo services listening on the same address.
+pub struct EchoServer {
+    tcp: TcpListener,
+    udp: UdpSocket,
+}
+
+impl EchoServer {
+    /// Listens on the address with TCP and UDP. With port 0, the UDP socket binds the port the system chose
+    /// for TCP.
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let tcp = TcpListener::bind(addr).await?;
+        let udp = UdpSocket::bind(tcp.local_addr()?).await?;
+        Ok(Self { tcp, udp })
+    }
+
+    /// The address the services listen on.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
+    /// Serves the clients until the future is dropped. Each TCP connection runs on its own task, until the
+    /// client closes it.
+    pub async fn run(self) {
+        tokio::join!(serve_tcp(self.tcp), serve_udp(self.udp));
+    }
+}
+
+async fn serve_tcp(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer)) => {
+                tokio::spawn(echo_connection(socket, peer));
+            }
+            Err(e) => {
+                warn!("Echo server failed to accept a TCP connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Sends the data of the connection back until the client closes it, then logs how much was echoed and how
+/// fast.
+async fn echo_connection(mut socket: TcpStream, peer: SocketAddr) {
+    socket.set_nodelay(true).ok();
+    let started = Instant::now();
+    let (mut reader, mut writer) = socket.split();
+    match tokio::io::copy(&mut reader, &mut writer).await {
+        Ok(bytes) => {
+            writer.shutdown().await.ok();
+            let elapsed = started.elapsed();
+            info!(
+                "[{}] Echoed {} bytes over TCP in {:?} ({:.2} Mbps)",
+                peer,
+                bytes,
+                elapsed,
+                throughput_mbps(bytes, elapsed)
+            );
+        }
+        Err(e) => debug!("[{}] Echo connection failed: {}", peer, e),
+    }
+}
+
+async fn serve_udp(socket: UdpSocket) {
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        match socket.recv_from(&mut buffer).await {
+            Ok((size, peer)) => {
+                trace!("[{}] Echoing a datagram of {} bytes", peer, size);
+                if let Err(e) = socket.send_to(&buffer[..size], peer).await {
+                    debug!("[{}] Failed to echo a datagram: {}", peer, e);
+                }
+            }
+            // Such as an ICMP port unreachable from a client that left, reported by some systems
+            Err(e) => debug!("Echo server failed to receive a UDP datagram: {}", e),
+        }
+    }
+}
+
+/// Echoed bits per second, in megabits, counting the data in one direction.
+fn throughput_mbps(bytes: u64, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        return 0.0;
+    }
+    bytes as f64 * 8.0 / elapsed.as_secs_f64() / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_echo_server() {
+        let server = EchoServer::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echoed = vec![];
+        stream.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"ping");
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(b"pong", addr).await.unwrap();
+        let mut buffer = [0u8; 16];
+        let (size, from) = socket.recv_from(&mut buffer).await.unwrap();
+        assert_eq!((&buffer[..size], from), (&b"pong"[..], addr));
+    }
+}
@@ -0,0 +1,170 @@
+//! The Curve25519 keys of the WireGuard peers, in onetun's own types so that the protocol engines can be swapped.
+
+use std::fmt::{Debug, Formatter};
+use std::str::FromStr;
+
+use crate::engine::packet::encode_key;
+
+/// The size of a key, in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// A private key. Its bytes are wiped when it is dropped, and left out of its `Debug` output.
+#[derive(Clone)]
+pub struct PrivateKey([u8; KEY_SIZE]);
+
+impl PrivateKey {
+    /// Generates a random private key.
+    pub fn generate() -> Self {
+        Self(rand::random())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+
+    /// The public key of this private key, as boringtun derives it. The custom protocol engines derive it with
+    /// `ProtocolEngineFactory::public_key` instead.
+    #[cfg(feature = "boringtun")]
+    pub fn public_key(&self) -> PublicKey {
+        crate::engine::tunn::public_key(self)
+    }
+}
+
+impl From<[u8; KEY_SIZE]> for PrivateKey {
+    fn from(bytes: [u8; KEY_SIZE]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl FromStr for PrivateKey {
+    type Err = anyhow::Error;
+
+    /// Parses a key in base64, as in WireGuard configurations, or in hex.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        decode_key(s).map(Self)
+    }
+}
+
+impl Debug for PrivateKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PrivateKey(..)")
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        for byte in self.0.iter_mut() {
+            // SAFETY: the pointer comes from a mutable reference
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+        std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// A public key.
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+pub struct PublicKey([u8; KEY_SIZE]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+}
+
+impl From<[u8; KEY_SIZE]> for PublicKey {
+    fn from(bytes: [u8; KEY_SIZE]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    /// Parses a key in base64, as in WireGuard configurations, or in hex.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        decode_key(s).map(Self)
+    }
+}
+
+impl Debug for PublicKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PublicKey({})", encode_key(&self.0))
+    }
+}
+
+/// Decodes a key in base64, with or without its padding, or in hex.
+fn decode_key(s: &str) -> anyhow::Result<[u8; KEY_SIZE]> {
+    let mut key = [0u8; KEY_SIZE];
+    match s.len() {
+        64 => {
+            for (byte, digits) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+                let digits = std::str::from_utf8(digits).ok();
+                *byte = digits
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                    .ok_or_else(|| anyhow::anyhow!("Illegal character in key"))?;
+            }
+        }
+        43 | 44 => {
+            let s = s.strip_suffix('=').unwrap_or(s);
+            let mut bits = 0u32;
+            let mut pending = 0u32;
+            let mut decoded = 0;
+            for c in s.bytes() {
+                let value = match c {
+                    b'A'..=b'Z' => c - b'A',
+                    b'a'..=b'z' => c - b'a' + 26,
+                    b'0'..=b'9' => c - b'0' + 52,
+                    b'+' => 62,
+                    b'/' => 63,
+                    _ => return Err(anyhow::anyhow!("Illegal character in key")),
+                };
+                bits = (bits << 6) | u32::from(value);
+                pending += 6;
+                if pending >= 8 {
+                    pending -= 8;
+                    // 43 characters hold 258 bits: the last 2 are padding
+                    if decoded == KEY_SIZE {
+                        return Err(anyhow::anyhow!("Illegal character in key"));
+                    }
+                    key[decoded] = (bits >> pending) as u8;
+                    decoded += 1;
+                }
+            }
+            if bits & ((1 << pending) - 1) != 0 {
+                return Err(anyhow::anyhow!("Illegal character in key"));
+            }
+        }
+        _ => return Err(anyhow::anyhow!("Illegal key size")),
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key() {
+        let base64 = "52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I=";
+        let key: PublicKey = base64.parse().unwrap();
+        assert_eq!(encode_key(key.as_bytes()), base64);
+        assert_eq!(base64[..43].parse::<PublicKey>().unwrap(), key);
+
+        let hex: String = key
+            .as_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(hex.parse::<PublicKey>().unwrap(), key);
+        assert_eq!(
+            hex.parse::<PrivateKey>().unwrap().as_bytes(),
+            key.as_bytes()
+        );
+
+        assert!("52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4!="
+            .parse::<PublicKey>()
+            .is_err());
+        assert!("invalid".parse::<PrivateKey>().is_err());
+        assert_eq!(format!("{:?}", PrivateKey::generate()), "PrivateKey(..)");
+    }
+}
@@ -0,0 +1,83 @@
+//! The passive estimate of the quality of the path to the endpoint, from the handshakes.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How many of the last handshake initiations the loss is estimated from.
+const HANDSHAKE_OUTCOMES: usize = 16;
+
+/// Follows the handshake initiations and their responses. The times are those of a `Clock`.
+#[derive(Debug, Default)]
+pub struct LinkMonitor {
+    /// The smoothed round-trip time of the handshakes, once one completed.
+    pub rtt: Option<Duration>,
+    /// When the handshake initiation awaiting its response was sent, if any.
+    pub pending: Option<Duration>,
+    /// Whether each of the last handshake initiations was answered.
+    outcomes: VecDeque<bool>,
+}
+
+impl LinkMonitor {
+    pub fn initiation_sent(&mut self, now: Duration) {
+        // A new initiation while one is pending is a retransmission: the previous one was lost
+        if self.pending.replace(now).is_some() {
+            self.record(false);
+        }
+    }
+
+    pub fn response_received(&mut self, now: Duration) {
+        if let Some(sent) = self.pending.take() {
+            let sample = now.saturating_sub(sent);
+            // Weighted like TCP's smoothed RTT, but faster: handshakes are only every two minutes
+            self.rtt = Some(match self.rtt {
+                Some(rtt) => (rtt * 3 + sample) / 4,
+                None => sample,
+            });
+            self.record(true);
+        }
+    }
+
+    fn record(&mut self, answered: bool) {
+        if self.outcomes.len() == HANDSHAKE_OUTCOMES {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(answered);
+    }
+
+    /// The share of the last handshake initiations that went unanswered, once one was answered or retransmitted.
+    pub fn loss(&self) -> Option<f64> {
+        if self.outcomes.is_empty() {
+            return None;
+        }
+        let lost = self.outcomes.iter().filter(|answered| !**answered).count();
+        Some(lost as f64 / self.outcomes.len() as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_monitor() {
+        let start = Duration::from_secs(1);
+        let mut link = LinkMonitor::default();
+        assert_eq!(link.loss(), None);
+
+        // The first initiation is lost, and retransmitted
+        link.initiation_sent(start);
+        link.initiation_sent(start + Duration::from_secs(5));
+        link.response_received(start + Duration::from_millis(5100));
+        assert_eq!(link.rtt, Some(Duration::from_millis(100)));
+        assert_eq!(link.loss(), Some(0.5));
+
+        link.initiation_sent(start + Duration::from_secs(120));
+        link.response_received(start + Duration::from_millis(120_500));
+        assert_eq!(link.rtt, Some(Duration::from_millis(200)));
+        assert_eq!(link.loss(), Some(1.0 / 3.0));
+
+        // A response without a pending initiation (such as a probe's) is ignored
+        link.response_received(start + Duration::from_secs(130));
+        assert_eq!(link.outcomes.len(), 3);
+    }
+}
@@ -0,0 +1,280 @@
+//! The packet-processing core of onetun (the `onetun-core` layer): the WireGuard encapsulation of IP packets,
+//! the classification of the packets from the peer, and the bookkeeping of the handshakes. It doesn't depend
+//! on tokio, on the sockets of the operating system or on its clock: time, randomness and the datagram socket
+//! are injected through the `Clock`, `Entropy` and `DatagramSocket` traits, so that the tunnel logic can
+//! eventually run on embedded targets. The rest of the crate drives it from tokio tasks (see `wg`), with the
+//! socket to the endpoint as its `DatagramSocket` (see `udp_batch`).
+//!
+//! The WireGuard protocol itself is behind the `ProtocolEngine` trait, in onetun's own types: boringtun's `Tunn`
+//! by default (feature `boringtun`), or another implementation given with `Config::with_protocol_engine`
+//! (feature `custom-engine`).
+
+use std::fmt::{Debug, Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::Duration;
+
+pub mod key;
+pub mod link;
+pub mod packet;
+pub mod timers;
+#[cfg(feature = "boringtun")]
+mod tunn;
+
+pub use key::{PrivateKey, PublicKey};
+pub use timers::TimerPolicy;
+
+/// A monotonic clock.
+pub trait Clock: Debug + Send + Sync {
+    /// The time elapsed since a fixed origin, such as the start of the clock.
+    fn now(&self) -> Duration;
+
+    /// Wakes the task of the waker the next time the clock is advanced, for the clocks that don't follow the time
+    /// of the system, and returns whether it will. The timers of the other clocks wait the time left on the system's
+    /// own timers.
+    fn wake_on_advance(&self, _waker: &Waker) -> bool {
+        false
+    }
+}
+
+/// The monotonic clock of the operating system, counted from the creation of the `SystemClock`.
+#[derive(Copy, Clone, Debug)]
+pub struct SystemClock(std::time::Instant);
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self(std::time::Instant::now())
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+/// A clock that only moves when advanced, for tests and simulations. The timers waiting on it expire as it is
+/// advanced past their deadline.
+#[derive(Debug, Default)]
+pub struct ManualClock(std::sync::Mutex<ManualTime>);
+
+#[derive(Debug, Default)]
+struct ManualTime {
+    now: Duration,
+    /// The tasks waiting for the clock to move.
+    waiting: Vec<Waker>,
+}
+
+impl ManualClock {
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        let waiting = {
+            let mut time = self.0.lock().expect("Failed to acquire clock lock");
+            time.now += duration;
+            std::mem::take(&mut time.waiting)
+        };
+        waiting.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.0.lock().expect("Failed to acquire clock lock").now
+    }
+
+    fn wake_on_advance(&self, waker: &Waker) -> bool {
+        let mut time = self.0.lock().expect("Failed to acquire clock lock");
+        if !time.waiting.iter().any(|waiting| waiting.will_wake(waker)) {
+            time.waiting.push(waker.clone());
+        }
+        true
+    }
+}
+
+/// A source of random numbers.
+pub trait Entropy: Send + Sync {
+    /// Fills the buffer with random bytes.
+    fn fill(&self, buf: &mut [u8]);
+
+    fn next_u32(&self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill(&mut bytes);
+        u32::from_ne_bytes(bytes)
+    }
+}
+
+/// The random number generator of the thread, seeded by the operating system.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ThreadEntropy;
+
+impl Entropy for ThreadEntropy {
+    fn fill(&self, buf: &mut [u8]) {
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), buf);
+    }
+}
+
+/// A socket exchanging datagrams with the WireGuard endpoint.
+pub trait DatagramSocket {
+    /// Sends a datagram to the address.
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> std::io::Result<usize>;
+
+    /// Receives a datagram into the buffer, returning its size and the address it came from.
+    fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)>;
+}
+
+impl DatagramSocket for std::net::UdpSocket {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        std::net::UdpSocket::send_to(self, datagram, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        std::net::UdpSocket::recv_from(self, buf)
+    }
+}
+
+/// What a protocol engine made of a packet, datagram or timer tick.
+#[derive(Debug)]
+pub enum EngineResult<'a> {
+    /// Nothing to send.
+    Done,
+    Err(EngineError),
+    /// A datagram to send to the endpoint, written in `dst`.
+    WriteToNetwork(&'a mut [u8]),
+    /// An IP packet decrypted from the endpoint, written in `dst`, with its source IP.
+    WriteToTunnel(&'a mut [u8], IpAddr),
+}
+
+/// Why a protocol engine failed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EngineError {
+    /// The datagram failed authentication (its MAC, AEAD tag or static key).
+    InvalidMac,
+    /// The counter of the data packet is older than the replay window.
+    InvalidCounter,
+    /// The counter of the data packet was already received.
+    DuplicateCounter,
+    /// The receiver index matches no session.
+    WrongIndex,
+    /// No handshake completed in time: the session was cleared.
+    ConnectionExpired,
+    /// Any other error, such as a malformed datagram, no session or a buffer too small.
+    Other(String),
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidMac => write!(f, "invalid MAC"),
+            Self::InvalidCounter => write!(f, "invalid counter"),
+            Self::DuplicateCounter => write!(f, "duplicate counter"),
+            Self::WrongIndex => write!(f, "wrong index"),
+            Self::ConnectionExpired => write!(f, "connection expired"),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// An implementation of the WireGuard protocol: the handshakes, the session keys, the timers and the
+/// encryption of the data messages, for the session with one peer. The datagram or IP packet of a result is
+/// written in `dst`, which is sized for the MTU of the tunnel.
+pub trait ProtocolEngine: Send + Sync {
+    /// Encapsulates an IP packet into a datagram for the endpoint. Until a session is established, the
+    /// packet may be queued and a handshake initiation returned instead.
+    fn encapsulate<'a>(&self, packet: &[u8], dst: &'a mut [u8]) -> EngineResult<'a>;
+
+    /// Processes a datagram from the endpoint, whose address is given to verify the cookies of handshakes
+    /// under load. Called again with an empty datagram after `WriteToNetwork`, to flush the queued packets.
+    fn decapsulate<'a>(
+        &self,
+        source: Option<IpAddr>,
+        datagram: &[u8],
+        dst: &'a mut [u8],
+    ) -> EngineResult<'a>;
+
+    /// Formats a handshake initiation, even while one is in progress with `force_resend`.
+    fn format_handshake_initiation<'a>(
+        &self,
+        dst: &'a mut [u8],
+        force_resend: bool,
+    ) -> EngineResult<'a>;
+
+    /// Runs the timers, returning the handshake initiation or keep-alive to send when one is due, or
+    /// `EngineError::ConnectionExpired` once when no handshake completed in time.
+    fn update_timers<'a>(&self, dst: &'a mut [u8]) -> EngineResult<'a>;
+}
+
+/// What a protocol engine needs to establish the session with the peer.
+#[derive(Clone)]
+pub struct EngineParams {
+    pub private_key: Arc<PrivateKey>,
+    pub peer_public_key: Arc<PublicKey>,
+    pub keepalive_seconds: Option<u16>,
+    /// The local index of the session, random so that the sessions of several tunnels to the same peer can
+    /// be told apart. It fits in 24 bits, leaving room for a session number.
+    pub index: u32,
+}
+
+impl EngineParams {
+    pub fn new(
+        private_key: Arc<PrivateKey>,
+        peer_public_key: Arc<PublicKey>,
+        keepalive_seconds: Option<u16>,
+        entropy: &dyn Entropy,
+    ) -> Self {
+        Self {
+            private_key,
+            peer_public_key,
+            keepalive_seconds,
+            index: entropy.next_u32() >> 8,
+        }
+    }
+}
+
+/// Creates the protocol engine of each tunnel, in place of boringtun (see `Config::with_protocol_engine`).
+pub trait ProtocolEngineFactory: Debug + Send + Sync {
+    fn create(&self, params: &EngineParams) -> anyhow::Result<Box<dyn ProtocolEngine>>;
+
+    /// The public key of the private key, shown as onetun's own.
+    fn public_key(&self, private_key: &PrivateKey) -> anyhow::Result<PublicKey>;
+}
+
+/// Creates the protocol engine of a tunnel with the factory, if any, or with boringtun, which may limit the
+/// rate of the handshakes it answers.
+pub(crate) fn new_engine(
+    factory: Option<&dyn ProtocolEngineFactory>,
+    params: &EngineParams,
+    handshake_rate_limit: Option<u64>,
+) -> anyhow::Result<Box<dyn ProtocolEngine>> {
+    match factory {
+        Some(factory) => factory.create(params),
+        #[cfg(feature = "boringtun")]
+        None => tunn::boringtun_engine(params, handshake_rate_limit),
+        #[cfg(not(feature = "boringtun"))]
+        None => {
+            let _ = handshake_rate_limit;
+            Err(no_engine())
+        }
+    }
+}
+
+/// The public key of the private key, derived by the factory, if any, or by boringtun.
+pub(crate) fn public_key(
+    factory: Option<&dyn ProtocolEngineFactory>,
+    private_key: &PrivateKey,
+) -> anyhow::Result<PublicKey> {
+    match factory {
+        Some(factory) => factory.public_key(private_key),
+        #[cfg(feature = "boringtun")]
+        None => Ok(private_key.public_key()),
+        #[cfg(not(feature = "boringtun"))]
+        None => Err(no_engine()),
+    }
+}
+
+#[cfg(not(feature = "boringtun"))]
+fn no_engine() -> anyhow::Error {
+    anyhow::anyhow!(
+        "No WireGuard protocol engine: onetun was built without boringtun, give one with Config::with_protocol_engine"
+    )
+}
@@ -0,0 +1,385 @@
+//! The WireGuard messages and the IP packets they carry.
+
+use std::cell::RefCell;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use log::Level;
+use smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet};
+
+use crate::config::PortProtocol;
+use crate::engine::{EngineError, EngineResult, ProtocolEngine};
+
+/// The largest datagram or IP packet processed.
+pub const MAX_PACKET: usize = 65536;
+/// The bytes a data message adds to the IP packet it carries: its header and its authentication tag.
+pub const DATA_OVERHEAD: usize = 32;
+/// The size of a handshake initiation, the largest of the other messages.
+pub const HANDSHAKE_INIT_SIZE: usize = 148;
+/// The size of a handshake response.
+const HANDSHAKE_RESPONSE_SIZE: usize = 92;
+/// The size of a cookie reply.
+const COOKIE_REPLY_SIZE: usize = 64;
+
+thread_local! {
+    /// The buffer the IP packets and datagrams are written to on each thread, reused from one to the next: the
+    /// crypto workers each have their own.
+    static BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+}
+
+/// The largest datagram sent to the WireGuard endpoint for the IP packets of up to the MTU.
+pub fn max_datagram(mtu: usize) -> usize {
+    (mtu + DATA_OVERHEAD).max(HANDSHAKE_INIT_SIZE)
+}
+
+/// Calls `f` with the buffer of the thread, of the given size.
+fn with_buffer<T>(size: usize, f: impl FnOnce(&mut [u8]) -> T) -> T {
+    BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        if buffer.len() < size {
+            buffer.resize(size, 0);
+        }
+        f(&mut buffer[..size])
+    })
+}
+
+/// Encapsulates an IP packet, returning the datagram to send to the WireGuard endpoint, if any. The packets
+/// above the MTU are dropped: the protocol engine queues the packets sent before the handshake, and flushes them
+/// into buffers sized for the MTU (see `decapsulate`).
+pub fn encapsulate(peer: &dyn ProtocolEngine, packet: &[u8], mtu: usize) -> Option<Vec<u8>> {
+    trace_ip_packet("Sending IP packet", packet);
+    if packet.len() > mtu {
+        error!(
+            "Dropping IP packet of {} bytes, above the MTU of {}",
+            packet.len(),
+            mtu
+        );
+        return None;
+    }
+    with_buffer(max_datagram(mtu), |send_buf| {
+        match peer.encapsulate(packet, send_buf) {
+            EngineResult::WriteToNetwork(packet) => Some(packet.to_vec()),
+            EngineResult::Err(e) => {
+                error!("Failed to encapsulate IP packet: {:?}", e);
+                None
+            }
+            EngineResult::Done => {
+                // Ignored
+                None
+            }
+            other => {
+                error!(
+                    "Unexpected WireGuard state during encapsulation: {:?}",
+                    other
+                );
+                None
+            }
+        }
+    })
+}
+
+/// Decapsulates a datagram received from the WireGuard endpoint, on a tunnel with the given MTU.
+pub fn decapsulate(
+    peer: &dyn ProtocolEngine,
+    source: SocketAddr,
+    datagram: &[u8],
+    mtu: usize,
+) -> Decapsulated {
+    let kind = PacketKind::of(datagram);
+    // The IP packet of a data message is smaller than the datagram, and the queued packets are up to the MTU
+    let size = max_datagram(mtu).max(datagram.len());
+    let result = with_buffer(size, |send_buf| {
+        // The source IP is needed to verify the cookies of handshakes under load, and to send cookie replies
+        let mut result = match peer.decapsulate(Some(source.ip()), datagram, send_buf) {
+            EngineResult::WriteToNetwork(packet) => {
+                DecapsulateResult::WriteToNetwork(vec![packet.to_vec()])
+            }
+            EngineResult::WriteToTunnel(packet, _) => {
+                DecapsulateResult::WriteToTunnel(packet.to_vec())
+            }
+            EngineResult::Done => DecapsulateResult::Done,
+            EngineResult::Err(e) => {
+                debug!("Failed to decapsulate datagram from {}: {:?}", source, e);
+                DecapsulateResult::Err(ProtocolError::from(&e))
+            }
+        };
+        if let DecapsulateResult::WriteToNetwork(packets) = &mut result {
+            // Flush the packets that were queued while waiting for the handshake
+            while let EngineResult::WriteToNetwork(packet) = peer.decapsulate(None, &[], send_buf) {
+                packets.push(packet.to_vec());
+            }
+        }
+        result
+    });
+    Decapsulated {
+        source,
+        kind,
+        result,
+    }
+}
+
+/// A decapsulated datagram, owned so it can be passed between tasks.
+pub struct Decapsulated {
+    /// The address the datagram was received from.
+    pub source: SocketAddr,
+    pub kind: PacketKind,
+    pub result: DecapsulateResult,
+}
+
+pub enum DecapsulateResult {
+    /// Datagrams to send back to the WireGuard endpoint.
+    WriteToNetwork(Vec<Vec<u8>>),
+    /// A decrypted IP packet.
+    WriteToTunnel(Vec<u8>),
+    Done,
+    /// The datagram was rejected.
+    Err(ProtocolError),
+}
+
+/// Why the WireGuard protocol rejected a datagram.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProtocolError {
+    /// The datagram failed authentication (its MAC, AEAD tag or static key): the keys don't match, or it
+    /// was corrupted on the way.
+    InvalidMac,
+    /// The counter of the data packet is older than the replay window.
+    StaleCounter,
+    /// The receiver index matches no session, such as after either side restarted.
+    UnknownIndex,
+    /// The counter of the data packet was already received: the datagram was duplicated or replayed.
+    Duplicate,
+    /// Any other error, such as a malformed datagram or no session.
+    Other,
+}
+
+impl From<&EngineError> for ProtocolError {
+    fn from(e: &EngineError) -> Self {
+        match e {
+            EngineError::InvalidMac => Self::InvalidMac,
+            EngineError::InvalidCounter => Self::StaleCounter,
+            EngineError::WrongIndex => Self::UnknownIndex,
+            EngineError::DuplicateCounter => Self::Duplicate,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// The WireGuard message type of a datagram.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PacketKind {
+    HandshakeInit,
+    HandshakeResponse,
+    CookieReply,
+    Data,
+    Invalid,
+}
+
+impl PacketKind {
+    /// The kind of a datagram, from its message type and size: the type is the first byte, followed by 3 zeros.
+    pub fn of(datagram: &[u8]) -> Self {
+        if datagram.len() < 4 || datagram[1..4] != [0, 0, 0] {
+            return Self::Invalid;
+        }
+        match (datagram[0], datagram.len()) {
+            (1, HANDSHAKE_INIT_SIZE) => Self::HandshakeInit,
+            (2, HANDSHAKE_RESPONSE_SIZE) => Self::HandshakeResponse,
+            (3, COOKIE_REPLY_SIZE) => Self::CookieReply,
+            (4, len) if len >= DATA_OVERHEAD => Self::Data,
+            _ => Self::Invalid,
+        }
+    }
+
+    /// Whether a successfully processed packet of this kind proves it was sent by the peer.
+    pub fn is_authenticated(&self) -> bool {
+        matches!(
+            self,
+            Self::HandshakeInit | Self::HandshakeResponse | Self::Data
+        )
+    }
+}
+
+/// Encodes a key in base64, like WireGuard configurations and `wg show`.
+pub fn encode_key(key: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((key.len() + 2) / 3 * 4);
+    for chunk in key.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((bits >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// The source IP of an IP packet, if it is well-formed.
+pub fn source_ip(packet: &[u8]) -> Option<IpAddr> {
+    match IpVersion::of_packet(packet) {
+        Ok(IpVersion::Ipv4) => Ipv4Packet::new_checked(packet)
+            .ok()
+            .map(|packet| Ipv4Addr::from(packet.src_addr()).into()),
+        Ok(IpVersion::Ipv6) => Ipv6Packet::new_checked(packet)
+            .ok()
+            .map(|packet| Ipv6Addr::from(packet.src_addr()).into()),
+        _ => None,
+    }
+}
+
+/// The protocol of the virtual interface that receives an IP packet from the peer: TCP or UDP, if the packet
+/// is destined for one of the source peer IPs.
+pub fn inbound_protocol(packet: &[u8], source_peer_ips: &[IpAddr]) -> Option<PortProtocol> {
+    let (destination, protocol) = match IpVersion::of_packet(packet).ok()? {
+        IpVersion::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+            (
+                IpAddr::from(Ipv4Addr::from(packet.dst_addr())),
+                packet.protocol(),
+            )
+        }
+        IpVersion::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            (
+                IpAddr::from(Ipv6Addr::from(packet.dst_addr())),
+                packet.next_header(),
+            )
+        }
+        _ => return None,
+    };
+    // Only care if the packet is destined for this tunnel
+    if !source_peer_ips.contains(&destination) {
+        return None;
+    }
+    match protocol {
+        IpProtocol::Tcp => Some(PortProtocol::Tcp),
+        IpProtocol::Udp => Some(PortProtocol::Udp),
+        // Unrecognized protocol, so we cannot determine where to route
+        _ => None,
+    }
+}
+
+/// Logs the IP packet at the trace level.
+pub fn trace_ip_packet(message: &str, packet: &[u8]) {
+    if log_enabled!(Level::Trace) {
+        use smoltcp::wire::*;
+
+        match IpVersion::of_packet(packet) {
+            Ok(IpVersion::Ipv4) => trace!(
+                "{}: {}",
+                message,
+                PrettyPrinter::<Ipv4Packet<&mut [u8]>>::new("", &packet)
+            ),
+            Ok(IpVersion::Ipv6) => trace!(
+                "{}: {}",
+                message,
+                PrettyPrinter::<Ipv6Packet<&mut [u8]>>::new("", &packet)
+            ),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PublicKey;
+
+    /// Tests that the packets queued before the handshake are flushed into buffers sized for the MTU, and that the
+    /// packets above it are dropped.
+    #[test]
+    #[cfg(feature = "boringtun")]
+    fn test_buffer_size() {
+        use crate::engine::tunn::boringtun_engine;
+        use crate::engine::{EngineParams, PrivateKey, ThreadEntropy};
+        use std::sync::Arc;
+
+        let mtu = 1420;
+        assert_eq!(max_datagram(mtu), 1452);
+        assert_eq!(max_datagram(68), HANDSHAKE_INIT_SIZE);
+
+        let (key_a, key_b) = (
+            Arc::new(PrivateKey::generate()),
+            Arc::new(PrivateKey::generate()),
+        );
+        let engine = |key: &Arc<PrivateKey>, peer: &Arc<PrivateKey>| {
+            let params = EngineParams::new(
+                key.clone(),
+                Arc::new(peer.public_key()),
+                None,
+                &ThreadEntropy,
+            );
+            boringtun_engine(&params, None).unwrap()
+        };
+        let (a, b) = (engine(&key_a, &key_b), engine(&key_b, &key_a));
+        let source = SocketAddr::from(([127, 0, 0, 1], 51820));
+
+        assert!(encapsulate(&*a, &[0x45; 1421], mtu).is_none());
+        // Queued until the handshake completes
+        let initiation = encapsulate(&*a, &[0x45; 1420], mtu).unwrap();
+        assert_eq!(initiation.len(), HANDSHAKE_INIT_SIZE);
+        let response = match decapsulate(&*b, source, &initiation, mtu).result {
+            DecapsulateResult::WriteToNetwork(mut datagrams) => datagrams.remove(0),
+            _ => panic!("Expected a handshake response"),
+        };
+        match decapsulate(&*a, source, &response, mtu).result {
+            // After the keep-alive confirming the session
+            DecapsulateResult::WriteToNetwork(datagrams) => {
+                assert_eq!(datagrams.last().map(Vec::len), Some(max_datagram(mtu)));
+            }
+            _ => panic!("Expected the queued packet"),
+        }
+    }
+
+    #[test]
+    fn test_encode_key() {
+        assert_eq!(encode_key(b""), "");
+        assert_eq!(encode_key(b"f"), "Zg==");
+        assert_eq!(encode_key(b"fo"), "Zm8=");
+        assert_eq!(encode_key(b"foo"), "Zm9v");
+        let key = "52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I=";
+        let public_key: PublicKey = key.parse().unwrap();
+        assert_eq!(encode_key(public_key.as_bytes()), key);
+    }
+
+    #[test]
+    fn test_protocol_error() {
+        for (error, category) in [
+            (EngineError::InvalidMac, ProtocolError::InvalidMac),
+            (EngineError::InvalidCounter, ProtocolError::StaleCounter),
+            (EngineError::WrongIndex, ProtocolError::UnknownIndex),
+            (EngineError::DuplicateCounter, ProtocolError::Duplicate),
+            (EngineError::ConnectionExpired, ProtocolError::Other),
+        ] {
+            assert_eq!(ProtocolError::from(&error), category);
+        }
+    }
+
+    #[test]
+    fn test_packet_kind() {
+        let message = |kind: u8, len: usize| {
+            let mut message = vec![0u8; len];
+            message[0] = kind;
+            message
+        };
+        assert_eq!(PacketKind::of(&message(1, 148)), PacketKind::HandshakeInit);
+        assert_eq!(
+            PacketKind::of(&message(2, 92)),
+            PacketKind::HandshakeResponse
+        );
+        assert_eq!(PacketKind::of(&message(3, 64)), PacketKind::CookieReply);
+        assert_eq!(PacketKind::of(&message(4, 32)), PacketKind::Data);
+        assert_eq!(PacketKind::of(&message(4, 1452)), PacketKind::Data);
+        assert_eq!(PacketKind::of(&message(1, 149)), PacketKind::Invalid);
+        assert_eq!(PacketKind::of(&message(4, 31)), PacketKind::Invalid);
+        assert_eq!(PacketKind::of(&message(5, 64)), PacketKind::Invalid);
+        let mut reserved = message(4, 64);
+        reserved[2] = 1;
+        assert_eq!(PacketKind::of(&reserved), PacketKind::Invalid);
+    }
+}
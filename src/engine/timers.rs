@@ -0,0 +1,306 @@
+//! Stricter WireGuard timers than the protocol engine's own, for extremely long-lived or bursty sessions
+//! (see `Config::with_timer_policy`). boringtun's timers are constants, so the policy is applied around the
+//! engine: it counts the data messages and the age of each session, and forces handshake initiations when
+//! one is due earlier than the engine would send it. It can only make the timers shorter.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::packet::PacketKind;
+use super::{Clock, EngineError, EngineResult, ProtocolEngine};
+
+/// How old a session may get before its packets are rejected (WireGuard's `Reject-After-Time`).
+pub const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+/// How long the handshake initiations are resent before giving up (WireGuard's `Rekey-Attempt-Time`).
+pub const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
+/// How often the engine resends an unanswered handshake initiation (WireGuard's `Rekey-Timeout`).
+pub const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The size of a keep-alive: a data message without payload.
+const KEEPALIVE_SIZE: usize = 32;
+
+/// When to start a new handshake, in addition to the WireGuard timers. Each knob is off when not set.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct TimerPolicy {
+    /// Rekeys once this many data messages (keep-alives aside) were sent in the session, for bursty sessions
+    /// that would otherwise encrypt a great deal of data with the same keys (WireGuard rekeys after 2^60).
+    pub rekey_after_messages: Option<u64>,
+    /// Rekeys once the session is this old, whichever side initiated it. WireGuard only rekeys after 120
+    /// seconds as the initiator of a session, and when sending data.
+    pub rekey_after_time: Option<Duration>,
+    /// Rekeys at the latest this long before the session would be rejected, at `REJECT_AFTER_TIME`, so that a
+    /// long-lived session never waits for a handshake in the middle of a transfer.
+    pub reject_margin: Option<Duration>,
+    /// Resends an unanswered handshake initiation at this interval, shorter than `REKEY_TIMEOUT`, until
+    /// `REKEY_ATTEMPT_TIME`.
+    pub handshake_retry_interval: Option<Duration>,
+}
+
+impl TimerPolicy {
+    /// Whether no knob is set, leaving the timers of the engine as they are.
+    pub fn is_default(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// The age at which a session is rekeyed, if limited.
+    fn max_session_age(&self) -> Option<Duration> {
+        let margin = self
+            .reject_margin
+            .map(|margin| REJECT_AFTER_TIME.saturating_sub(margin));
+        match (self.rekey_after_time, margin) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Why the policy has no effect, if it doesn't.
+    pub(crate) fn invalid(&self) -> Option<&'static str> {
+        let out_of_range = |duration: Option<Duration>, max: Duration| {
+            duration.map_or(false, |duration| duration.is_zero() || duration >= max)
+        };
+        if self.rekey_after_messages == Some(0) {
+            Some("--rekey-after-messages must be at least 1")
+        } else if out_of_range(self.rekey_after_time, REJECT_AFTER_TIME) {
+            Some("--rekey-after-time must be above 0 and below 180 seconds")
+        } else if out_of_range(self.reject_margin, REJECT_AFTER_TIME) {
+            Some("--reject-margin must be above 0 and below 180 seconds")
+        } else if out_of_range(self.handshake_retry_interval, REKEY_TIMEOUT) {
+            Some("--handshake-retry-interval must be above 0 and below 5 seconds")
+        } else {
+            None
+        }
+    }
+}
+
+/// A protocol engine whose handshakes follow a `TimerPolicy` as well.
+pub struct PolicyEngine {
+    inner: Arc<dyn ProtocolEngine>,
+    policy: TimerPolicy,
+    clock: Arc<dyn Clock>,
+    state: Mutex<PolicyState>,
+}
+
+#[derive(Default)]
+struct PolicyState {
+    /// When the current session was established, if there is one.
+    session_start: Option<Duration>,
+    /// The data messages sent in the current session.
+    messages: u64,
+    /// When the first and the latest initiation of the handshake in progress were sent, if there is one.
+    handshake: Option<(Duration, Duration)>,
+}
+
+impl PolicyEngine {
+    pub fn new(inner: Arc<dyn ProtocolEngine>, policy: TimerPolicy, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner,
+            policy,
+            clock,
+            state: Mutex::new(PolicyState::default()),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, PolicyState> {
+        self.state
+            .lock()
+            .expect("Failed to acquire timer policy lock")
+    }
+
+    /// Notes a datagram sent to the peer.
+    fn sent(&self, result: &EngineResult<'_>) {
+        if let EngineResult::WriteToNetwork(datagram) = result {
+            let mut state = self.state();
+            match PacketKind::of(datagram) {
+                // Not the keep-alives
+                PacketKind::Data if datagram.len() > KEEPALIVE_SIZE => state.messages += 1,
+                PacketKind::HandshakeInit => {
+                    let now = self.clock.now();
+                    let started = state.handshake.map_or(now, |(started, _)| started);
+                    state.handshake = Some((started, now));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether a handshake initiation is due according to the policy.
+    fn due(&self) -> bool {
+        let now = self.clock.now();
+        let state = self.state();
+        if let Some((started, latest)) = state.handshake {
+            return match self.policy.handshake_retry_interval {
+                Some(interval) => {
+                    now.saturating_sub(latest) >= interval
+                        && now.saturating_sub(started) < REKEY_ATTEMPT_TIME
+                }
+                None => false,
+            };
+        }
+        let session_start = match state.session_start {
+            Some(start) => start,
+            None => return false,
+        };
+        let age = now.saturating_sub(session_start);
+        let too_many = self
+            .policy
+            .rekey_after_messages
+            .map_or(false, |max| state.messages >= max);
+        let too_old = self
+            .policy
+            .max_session_age()
+            .map_or(false, |max| age >= max);
+        if too_many || too_old {
+            debug!(
+                "Rekeying the WireGuard session after {} message(s) and {:?}",
+                state.messages, age
+            );
+        }
+        too_many || too_old
+    }
+}
+
+impl ProtocolEngine for PolicyEngine {
+    fn encapsulate<'a>(&self, packet: &[u8], dst: &'a mut [u8]) -> EngineResult<'a> {
+        let result = self.inner.encapsulate(packet, dst);
+        self.sent(&result);
+        result
+    }
+
+    fn decapsulate<'a>(
+        &self,
+        source: Option<std::net::IpAddr>,
+        datagram: &[u8],
+        dst: &'a mut [u8],
+    ) -> EngineResult<'a> {
+        let kind = PacketKind::of(datagram);
+        let result = self.inner.decapsulate(source, datagram, dst);
+        let processed = !matches!(result, EngineResult::Err(_));
+        if processed
+            && matches!(
+                kind,
+                PacketKind::HandshakeInit | PacketKind::HandshakeResponse
+            )
+        {
+            // A new session, as the responder or the initiator
+            *self.state() = PolicyState {
+                session_start: Some(self.clock.now()),
+                ..Default::default()
+            };
+        }
+        self.sent(&result);
+        result
+    }
+
+    fn format_handshake_initiation<'a>(
+        &self,
+        dst: &'a mut [u8],
+        force_resend: bool,
+    ) -> EngineResult<'a> {
+        let result = self.inner.format_handshake_initiation(dst, force_resend);
+        self.sent(&result);
+        result
+    }
+
+    fn update_timers<'a>(&self, dst: &'a mut [u8]) -> EngineResult<'a> {
+        // The engine's timers run on the next call
+        if self.due() {
+            return self.format_handshake_initiation(dst, true);
+        }
+        let result = self.inner.update_timers(dst);
+        if let EngineResult::Err(EngineError::ConnectionExpired) = result {
+            *self.state() = PolicyState::default();
+        }
+        self.sent(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "boringtun")]
+    use crate::engine::{EngineParams, ManualClock, PrivateKey, ThreadEntropy};
+
+    #[cfg(feature = "boringtun")]
+    fn engine(key: &Arc<PrivateKey>, peer: &Arc<PrivateKey>) -> Arc<dyn ProtocolEngine> {
+        let params = EngineParams::new(
+            key.clone(),
+            Arc::new(peer.public_key()),
+            None,
+            &ThreadEntropy,
+        );
+        Arc::from(crate::engine::tunn::boringtun_engine(&params, None).unwrap())
+    }
+
+    #[cfg(feature = "boringtun")]
+    fn kind(result: EngineResult<'_>) -> Option<PacketKind> {
+        match result {
+            EngineResult::WriteToNetwork(datagram) => Some(PacketKind::of(datagram)),
+            _ => None,
+        }
+    }
+
+    /// Tests that the policy rekeys after the given number of messages, and retries unanswered initiations.
+    #[test]
+    #[cfg(feature = "boringtun")]
+    fn test_timer_policy() {
+        let key_a = Arc::new(PrivateKey::generate());
+        let key_b = Arc::new(PrivateKey::generate());
+        let clock = Arc::new(ManualClock::default());
+        let policy = TimerPolicy {
+            rekey_after_messages: Some(2),
+            handshake_retry_interval: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let a = PolicyEngine::new(engine(&key_a, &key_b), policy, clock.clone());
+        let b = engine(&key_b, &key_a);
+        let (mut buf_a, mut buf_b) = (vec![0u8; 2048], vec![0u8; 2048]);
+
+        assert_eq!(
+            kind(a.format_handshake_initiation(&mut buf_a, false)),
+            Some(PacketKind::HandshakeInit)
+        );
+        // Unanswered: resent after the retry interval
+        assert_eq!(kind(a.update_timers(&mut buf_a)), None);
+        clock.advance(Duration::from_secs(1));
+        let initiation = match a.update_timers(&mut buf_a) {
+            EngineResult::WriteToNetwork(datagram) => datagram.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert_eq!(PacketKind::of(&initiation), PacketKind::HandshakeInit);
+
+        let response = match b.decapsulate(None, &initiation, &mut buf_b) {
+            EngineResult::WriteToNetwork(datagram) => datagram.to_vec(),
+            _ => panic!("Expected a handshake response"),
+        };
+        a.decapsulate(None, &response, &mut buf_a);
+        for _ in 0..2 {
+            assert_eq!(kind(a.update_timers(&mut buf_a)), None);
+            assert_eq!(
+                kind(a.encapsulate(&[0x45; 20], &mut buf_a)),
+                Some(PacketKind::Data)
+            );
+        }
+        assert_eq!(
+            kind(a.update_timers(&mut buf_a)),
+            Some(PacketKind::HandshakeInit)
+        );
+    }
+
+    #[test]
+    fn test_invalid_timer_policy() {
+        assert_eq!(TimerPolicy::default().invalid(), None);
+        let policy = TimerPolicy {
+            rekey_after_time: Some(Duration::from_secs(600)),
+            ..Default::default()
+        };
+        assert!(policy.invalid().is_some());
+        let policy = TimerPolicy {
+            rekey_after_time: Some(Duration::from_secs(150)),
+            reject_margin: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert_eq!(policy.invalid(), None);
+        assert_eq!(policy.max_session_age(), Some(Duration::from_secs(120)));
+    }
+}
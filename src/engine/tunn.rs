@@ -0,0 +1,165 @@
+//! The default protocol engine: boringtun's `Tunn`, with its results and keys translated to onetun's.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use boringtun::crypto::{X25519PublicKey, X25519SecretKey};
+use boringtun::noise::errors::WireGuardError;
+use boringtun::noise::rate_limiter::RateLimiter;
+use boringtun::noise::{Tunn, TunnResult};
+
+use super::packet::encode_key;
+use super::{EngineError, EngineParams, EngineResult, PrivateKey, ProtocolEngine, PublicKey};
+
+/// boringtun's session with the peer.
+struct Boringtun {
+    tunn: Box<Tunn>,
+    /// The limit of handshake messages from the endpoint, if not boringtun's default. Its count is reset with
+    /// the timers, as boringtun only resets its own.
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+/// Creates the boringtun session with the peer, which may limit the rate of the handshakes it answers.
+pub fn boringtun_engine(
+    params: &EngineParams,
+    handshake_rate_limit: Option<u64>,
+) -> anyhow::Result<Box<dyn ProtocolEngine>> {
+    let private_key = Arc::new(secret_key(&params.private_key));
+    let rate_limiter = handshake_rate_limit
+        .map(|limit| Arc::new(RateLimiter::new(&private_key.public_key(), limit)));
+    let tunn = Tunn::new(
+        private_key,
+        Arc::new(
+            encode_key(params.peer_public_key.as_bytes())
+                .parse::<X25519PublicKey>()
+                .map_err(|s| anyhow::anyhow!("{}", s))?,
+        ),
+        None,
+        params.keepalive_seconds,
+        params.index,
+        rate_limiter.clone(),
+    )
+    .map_err(|s| anyhow::anyhow!("{}", s))
+    .with_context(|| "Failed to initialize boringtun Tunn")?;
+    Ok(Box::new(Boringtun { tunn, rate_limiter }))
+}
+
+/// The public key of the private key.
+pub fn public_key(private_key: &PrivateKey) -> PublicKey {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(secret_key(private_key).public_key().as_bytes());
+    PublicKey::from(bytes)
+}
+
+/// boringtun's copy of the private key, which it wipes when dropped.
+fn secret_key(private_key: &PrivateKey) -> X25519SecretKey {
+    let mut encoded = encode_key(private_key.as_bytes()).into_bytes();
+    let key = std::str::from_utf8(&encoded)
+        .ok()
+        .and_then(|encoded| encoded.parse::<X25519SecretKey>().ok())
+        .expect("Failed to convert private key");
+    for byte in encoded.iter_mut() {
+        // SAFETY: the pointer comes from a mutable reference
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    key
+}
+
+fn engine_result(result: TunnResult<'_>) -> EngineResult<'_> {
+    match result {
+        TunnResult::Done => EngineResult::Done,
+        TunnResult::Err(e) => EngineResult::Err(EngineError::from(e)),
+        TunnResult::WriteToNetwork(datagram) => EngineResult::WriteToNetwork(datagram),
+        TunnResult::WriteToTunnelV4(packet, source) => {
+            EngineResult::WriteToTunnel(packet, source.into())
+        }
+        TunnResult::WriteToTunnelV6(packet, source) => {
+            EngineResult::WriteToTunnel(packet, source.into())
+        }
+    }
+}
+
+impl From<WireGuardError> for EngineError {
+    fn from(e: WireGuardError) -> Self {
+        match e {
+            WireGuardError::InvalidMac
+            | WireGuardError::InvalidAeadTag
+            | WireGuardError::WrongKey => Self::InvalidMac,
+            WireGuardError::InvalidCounter => Self::InvalidCounter,
+            WireGuardError::DuplicateCounter => Self::DuplicateCounter,
+            WireGuardError::WrongIndex => Self::WrongIndex,
+            WireGuardError::ConnectionExpired => Self::ConnectionExpired,
+            e => Self::Other(format!("{:?}", e)),
+        }
+    }
+}
+
+impl ProtocolEngine for Boringtun {
+    fn encapsulate<'a>(&self, packet: &[u8], dst: &'a mut [u8]) -> EngineResult<'a> {
+        engine_result(self.tunn.encapsulate(packet, dst))
+    }
+
+    fn decapsulate<'a>(
+        &self,
+        source: Option<IpAddr>,
+        datagram: &[u8],
+        dst: &'a mut [u8],
+    ) -> EngineResult<'a> {
+        engine_result(self.tunn.decapsulate(source, datagram, dst))
+    }
+
+    fn format_handshake_initiation<'a>(
+        &self,
+        dst: &'a mut [u8],
+        force_resend: bool,
+    ) -> EngineResult<'a> {
+        engine_result(self.tunn.format_handshake_initiation(dst, force_resend))
+    }
+
+    fn update_timers<'a>(&self, dst: &'a mut [u8]) -> EngineResult<'a> {
+        let result = self.tunn.update_timers(dst);
+        if let (TunnResult::Done, Some(rate_limiter)) = (&result, &self.rate_limiter) {
+            // Only resets the count once per second
+            rate_limiter.reset_count();
+        }
+        engine_result(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let key: PrivateKey = "52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I="
+            .parse()
+            .unwrap();
+        assert_eq!(secret_key(&key).as_bytes(), key.as_bytes());
+        assert_eq!(
+            public_key(&key).as_bytes(),
+            secret_key(&key).public_key().as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_engine_error() {
+        for (error, converted) in [
+            (WireGuardError::InvalidAeadTag, EngineError::InvalidMac),
+            (WireGuardError::WrongKey, EngineError::InvalidMac),
+            (WireGuardError::InvalidCounter, EngineError::InvalidCounter),
+            (WireGuardError::WrongIndex, EngineError::WrongIndex),
+            (
+                WireGuardError::ConnectionExpired,
+                EngineError::ConnectionExpired,
+            ),
+            (
+                WireGuardError::InvalidPacket,
+                EngineError::Other("InvalidPacket".to_string()),
+            ),
+        ] {
+            assert_eq!(EngineError::from(error), converted);
+        }
+    }
+}
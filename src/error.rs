@@ -0,0 +1,82 @@
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+
+/// Errors returned by the public onetun API.
+#[derive(Debug)]
+pub enum OnetunError {
+    /// The configuration is invalid (bad keys, addresses, etc.)
+    Config(anyhow::Error),
+    /// The WireGuard peer did not complete a handshake in time.
+    HandshakeTimeout,
+    /// A socket could not be bound on the given address.
+    BindFailed {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+    /// The WireGuard endpoint could not be reached.
+    EndpointUnreachable {
+        addr: SocketAddr,
+        source: std::io::Error,
+    },
+    /// The WireGuard tunnel could not be initialized.
+    Tunnel(anyhow::Error),
+    /// The async runtime could not be started.
+    Runtime(std::io::Error),
+    /// Another instance registered in the lock directory uses the same resource.
+    InstanceConflict { resource: String, owner: String },
+    /// The tunnel was killed before it was ready.
+    Killed,
+}
+
+impl OnetunError {
+    /// A stable numeric code for this kind of error, used by the FFI layer and as the exit status of the
+    /// onetun binary.
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::Config(_) => 1,
+            Self::HandshakeTimeout => 2,
+            Self::BindFailed { .. } => 3,
+            Self::EndpointUnreachable { .. } => 4,
+            Self::Tunnel(_) => 5,
+            Self::Runtime(_) => 6,
+            Self::InstanceConflict { .. } => 7,
+            Self::Killed => 8,
+        }
+    }
+}
+
+impl Display for OnetunError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "Invalid configuration: {:#}", e),
+            Self::HandshakeTimeout => write!(f, "Timed out waiting for WireGuard handshake"),
+            Self::BindFailed { addr, source } => {
+                write!(f, "Failed to bind on {}: {}", addr, source)
+            }
+            Self::EndpointUnreachable { addr, source } => {
+                write!(f, "WireGuard endpoint {} is unreachable: {}", addr, source)
+            }
+            Self::Tunnel(e) => write!(f, "Failed to initialize WireGuard tunnel: {:#}", e),
+            Self::Runtime(e) => write!(f, "Failed to start async runtime: {}", e),
+            Self::InstanceConflict { resource, owner } => write!(
+                f,
+                "Another onetun instance ({}) already uses the {}",
+                owner, resource
+            ),
+            Self::Killed => write!(f, "The tunnel was killed before it was ready"),
+        }
+    }
+}
+
+impl std::error::Error for OnetunError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Config(e) | Self::Tunnel(e) => Some(e.as_ref()),
+            Self::BindFailed { source, .. } | Self::EndpointUnreachable { source, .. } => {
+                Some(source)
+            }
+            Self::Runtime(e) => Some(e),
+            Self::HandshakeTimeout | Self::InstanceConflict { .. } | Self::Killed => None,
+        }
+    }
+}
@@ -1,30 +1,103 @@
 use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::config::PortForwardConfig;
-use crate::virtual_iface::VirtualPort;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::mpsc;
+
+use crate::config::{ForwardId, PortForwardConfig};
+use crate::virtual_iface::{ConnectionInfo, VirtualPort};
+use crate::wg::{LinkQuality, ProtocolErrors};
 use crate::PortProtocol;
 
+/// The most events a busy endpoint handles per wakeup, with `BusEndpoint::recv_many`.
+pub const EVENT_BATCH: usize = 64;
+/// The version of the serialized form of the events, in their `version` field. It changes when a field is
+/// removed or changes meaning; new events and new fields keep the version.
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
 /// Events that go on the bus between the local server, smoltcp, and WireGuard.
+///
+/// The connection and data events carry the (monotonic) time they happened at, from which consumers can
+/// compute the duration of the sessions.
+///
+/// Each endpoint of the bus receives its own clone of the events, so the port forwards they refer to are shared.
+///
+/// With the `control` feature, events serialize to objects with the schema version, their `type` in snake case,
+/// and their fields, such as `{"version":1,"type":"remote_data","virtual_port":{"port":80,"protocol":"TCP"},
+/// "size":512}`. The data and packets they carry are redacted to their size, and the monotonic times are left out.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum Event {
     /// Dumb event with no data.
     Dumb,
     /// A new connection with the local server was initiated, and the given virtual port was assigned.
-    ClientConnectionInitiated(PortForwardConfig, VirtualPort),
-    /// A connection was dropped from the pool and should be closed in all interfaces.
-    ClientConnectionDropped(VirtualPort),
+    ClientConnectionInitiated(Arc<PortForwardConfig>, VirtualPort, Instant),
+    /// A TCP connection from the tunnel was accepted by the remote port forward, and the given virtual port
+    /// was assigned. Its data is held until the local side sends `ClientConnectionInitiated`.
+    VirtualConnectionAccepted(Arc<PortForwardConfig>, VirtualPort, Instant),
+    /// The virtual TCP connection of the given virtual port was established with the destination.
+    ClientConnectionEstablished(VirtualPort),
+    /// The destination refused (or reset) the virtual TCP connection of the given virtual port, whose local
+    /// connection should be reset as well. `ClientConnectionDropped` follows.
+    ClientConnectionRefused(VirtualPort),
+    /// A connection was dropped from the pool for the given reason, and should be closed in all interfaces.
+    ClientConnectionDropped(VirtualPort, DropReason, Instant),
     /// Data received by the local server that should be sent to the virtual server.
-    LocalData(PortForwardConfig, VirtualPort, Vec<u8>),
+    LocalData(Arc<PortForwardConfig>, VirtualPort, Vec<u8>, Instant),
     /// Data received by the remote server that should be sent to the local client.
-    RemoteData(VirtualPort, Vec<u8>),
+    RemoteData(VirtualPort, Vec<u8>, Instant),
+    /// Datagram received through the tunnel from the given peer by a remote UDP port forward with peer routes,
+    /// that should be sent to the local destination of the peer's route (see `PortForwardConfig::peer_routes`).
+    RemotePeerData(VirtualPort, SocketAddr, Vec<u8>, Instant),
+    /// Broadcast or multicast datagram received through the tunnel by the relay of the port forward to the
+    /// given destination, that should be sent to the local network.
+    RemoteRelayData(SocketAddr, Vec<u8>),
     /// IP packet received from the WireGuard tunnel that should be passed through the corresponding virtual device.
     InboundInternetPacket(PortProtocol, Vec<u8>),
     /// IP packet to be sent through the WireGuard tunnel as crafted by the virtual device.
     OutboundInternetPacket(Vec<u8>),
+    /// IP packet received from the WireGuard tunnel that should be written to the tun device.
+    InboundTunPacket(Vec<u8>),
     /// Notifies that a virtual device read an IP packet.
     VirtualDeviceFed(PortProtocol),
+    /// A handshake with the WireGuard endpoint completed, and a new session is ready to use.
+    HandshakeCompleted,
+    /// The datagrams now go to another address of the endpoint (from, to), whose handshake was the fastest
+    /// with the given round-trip time.
+    EndpointSwitched(SocketAddr, SocketAddr, Duration),
+    /// Requests a snapshot of the active sessions. Each virtual interface replies once on the given channel.
+    QueryConnections(mpsc::UnboundedSender<Vec<ConnectionInfo>>),
+    /// A connection of the port forward failed, with the given error.
+    ConnectionFailed(Arc<PortForwardConfig>, VirtualPort, String),
+    /// The session with the WireGuard endpoint expired, as no handshake completed in time. Packets are
+    /// dropped until the next handshake.
+    TunnelExpired,
+    /// Periodic estimate of the quality of the path to the WireGuard endpoint.
+    LinkQuality(LinkQuality),
+    /// Many datagrams from the WireGuard endpoint were rejected by the protocol in the last 10 seconds: the
+    /// errors of that interval, by category. See `Handle::protocol_errors`.
+    ProtocolErrorSpike(ProtocolErrors),
+    /// Periodic byte counters of a port forward: total bytes sent into (tx) and received from (rx) the tunnel.
+    ForwardStats(ForwardId, u64, u64),
+    /// A client needed a virtual port of the protocol while all of them were in use. See `Handle::port_pool_stats`.
+    PortPoolExhausted(PortProtocol),
+    /// The local port forward is being removed: it turns new clients away, and the given number of sessions
+    /// are still open. Sent every second until they finish, or the grace period ends.
+    ForwardDraining(ForwardId, usize),
+    /// The local port forward was removed, closing the given number of sessions still open after the grace period.
+    ForwardRemoved(ForwardId, usize),
+    /// How long the local client of the given virtual port kept its connection waiting, with the data from the
+    /// tunnel on hold, over the last 5 seconds or until the connection closed. Only sent when it did.
+    ClientStalled(VirtualPort, Duration),
+    /// The local client of the given virtual port reads slower than the tunnel delivers: it kept its connection
+    /// waiting for at least half of the last 5 seconds. Sent again if it catches up, then falls behind again.
+    SlowConsumer(VirtualPort),
+    /// The local address of the route to the WireGuard endpoint changed (from, to), such as after switching from
+    /// Wi-Fi to cellular; `None` while there is no route. The socket is rebound and a handshake initiated.
+    NetworkChanged(Option<IpAddr>, Option<IpAddr>),
 }
 
 impl Display for Event {
@@ -33,20 +106,49 @@ impl Display for Event {
             Event::Dumb => {
                 write!(f, "Dumb{{}}")
             }
-            Event::ClientConnectionInitiated(pf, vp) => {
+            Event::ClientConnectionInitiated(pf, vp, _) => {
                 write!(f, "ClientConnectionInitiated{{ pf={} vp={} }}", pf, vp)
             }
-            Event::ClientConnectionDropped(vp) => {
-                write!(f, "ClientConnectionDropped{{ vp={} }}", vp)
+            Event::VirtualConnectionAccepted(pf, vp, _) => {
+                write!(f, "VirtualConnectionAccepted{{ pf={} vp={} }}", pf, vp)
+            }
+            Event::ClientConnectionEstablished(vp) => {
+                write!(f, "ClientConnectionEstablished{{ vp={} }}", vp)
+            }
+            Event::ClientConnectionRefused(vp) => {
+                write!(f, "ClientConnectionRefused{{ vp={} }}", vp)
             }
-            Event::LocalData(pf, vp, data) => {
+            Event::ClientConnectionDropped(vp, reason, _) => {
+                write!(
+                    f,
+                    "ClientConnectionDropped{{ vp={} reason={} }}",
+                    vp, reason
+                )
+            }
+            Event::LocalData(pf, vp, data, _) => {
                 let size = data.len();
                 write!(f, "LocalData{{ pf={} vp={} size={} }}", pf, vp, size)
             }
-            Event::RemoteData(vp, data) => {
+            Event::RemoteData(vp, data, _) => {
                 let size = data.len();
                 write!(f, "RemoteData{{ vp={} size={} }}", vp, size)
             }
+            Event::RemotePeerData(vp, peer, data, _) => {
+                let size = data.len();
+                write!(
+                    f,
+                    "RemotePeerData{{ vp={} peer={} size={} }}",
+                    vp, peer, size
+                )
+            }
+            Event::RemoteRelayData(destination, data) => {
+                let size = data.len();
+                write!(
+                    f,
+                    "RemoteRelayData{{ destination={} size={} }}",
+                    destination, size
+                )
+            }
             Event::InboundInternetPacket(proto, data) => {
                 let size = data.len();
                 write!(
@@ -59,10 +161,347 @@ impl Display for Event {
                 let size = data.len();
                 write!(f, "OutboundInternetPacket{{ size={} }}", size)
             }
+            Event::InboundTunPacket(data) => {
+                let size = data.len();
+                write!(f, "InboundTunPacket{{ size={} }}", size)
+            }
             Event::VirtualDeviceFed(proto) => {
                 write!(f, "VirtualDeviceFed{{ proto={} }}", proto)
             }
+            Event::HandshakeCompleted => {
+                write!(f, "HandshakeCompleted{{}}")
+            }
+            Event::EndpointSwitched(from, to, rtt) => {
+                write!(
+                    f,
+                    "EndpointSwitched{{ from={} to={} rtt={:?} }}",
+                    from, to, rtt
+                )
+            }
+            Event::QueryConnections(_) => {
+                write!(f, "QueryConnections{{}}")
+            }
+            Event::ConnectionFailed(pf, vp, error) => {
+                write!(
+                    f,
+                    "ConnectionFailed{{ pf={} vp={} error={} }}",
+                    pf, vp, error
+                )
+            }
+            Event::TunnelExpired => {
+                write!(f, "TunnelExpired{{}}")
+            }
+            Event::LinkQuality(quality) => {
+                write!(
+                    f,
+                    "LinkQuality{{ rtt={:?} loss={:?} since_last_received={:?} }}",
+                    quality.rtt, quality.loss, quality.since_last_received
+                )
+            }
+            Event::ProtocolErrorSpike(errors) => {
+                write!(f, "ProtocolErrorSpike{{ {} }}", errors)
+            }
+            Event::ForwardStats(id, tx, rx) => {
+                write!(f, "ForwardStats{{ id={} tx={} rx={} }}", id, tx, rx)
+            }
+            Event::PortPoolExhausted(proto) => {
+                write!(f, "PortPoolExhausted{{ proto={} }}", proto)
+            }
+            Event::ForwardDraining(id, sessions) => {
+                write!(f, "ForwardDraining{{ id={} sessions={} }}", id, sessions)
+            }
+            Event::ForwardRemoved(id, closed) => {
+                write!(f, "ForwardRemoved{{ id={} closed={} }}", id, closed)
+            }
+            Event::ClientStalled(vp, stalled) => {
+                write!(f, "ClientStalled{{ vp={} stalled={:?} }}", vp, stalled)
+            }
+            Event::SlowConsumer(vp) => {
+                write!(f, "SlowConsumer{{ vp={} }}", vp)
+            }
+            Event::NetworkChanged(from, to) => {
+                write!(f, "NetworkChanged{{ from={:?} to={:?} }}", from, to)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "control")]
+impl serde::Serialize for Event {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        EventRecord {
+            version: EVENT_SCHEMA_VERSION,
+            event: EventFields::from(self),
         }
+        .serialize(serializer)
+    }
+}
+
+/// The serialized form of an event: its fields, after the schema version.
+#[cfg(feature = "control")]
+#[derive(serde::Serialize)]
+struct EventRecord {
+    version: u32,
+    #[serde(flatten)]
+    event: EventFields,
+}
+
+/// The fields of each event, with the data redacted to its size.
+#[cfg(feature = "control")]
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum EventFields {
+    Dumb,
+    ClientConnectionInitiated {
+        forward: String,
+        virtual_port: PortRecord,
+    },
+    VirtualConnectionAccepted {
+        forward: String,
+        virtual_port: PortRecord,
+    },
+    ClientConnectionEstablished {
+        virtual_port: PortRecord,
+    },
+    ClientConnectionRefused {
+        virtual_port: PortRecord,
+    },
+    ClientConnectionDropped {
+        virtual_port: PortRecord,
+        reason: DropReason,
+    },
+    LocalData {
+        forward: String,
+        virtual_port: PortRecord,
+        size: usize,
+    },
+    RemoteData {
+        virtual_port: PortRecord,
+        size: usize,
+    },
+    RemotePeerData {
+        virtual_port: PortRecord,
+        peer: SocketAddr,
+        size: usize,
+    },
+    RemoteRelayData {
+        destination: SocketAddr,
+        size: usize,
+    },
+    InboundInternetPacket {
+        protocol: String,
+        size: usize,
+    },
+    OutboundInternetPacket {
+        size: usize,
+    },
+    InboundTunPacket {
+        size: usize,
+    },
+    VirtualDeviceFed {
+        protocol: String,
+    },
+    HandshakeCompleted,
+    EndpointSwitched {
+        from: SocketAddr,
+        to: SocketAddr,
+        rtt_ms: u64,
+    },
+    QueryConnections,
+    ConnectionFailed {
+        forward: String,
+        virtual_port: PortRecord,
+        error: String,
+    },
+    TunnelExpired,
+    LinkQuality {
+        rtt_ms: Option<u64>,
+        loss: Option<f64>,
+        since_last_received_ms: Option<u64>,
+    },
+    ProtocolErrorSpike {
+        errors: ProtocolErrors,
+    },
+    ForwardStats {
+        forward_id: String,
+        tx: u64,
+        rx: u64,
+    },
+    PortPoolExhausted {
+        protocol: String,
+    },
+    ForwardDraining {
+        forward_id: String,
+        sessions: usize,
+    },
+    ForwardRemoved {
+        forward_id: String,
+        closed: usize,
+    },
+    ClientStalled {
+        virtual_port: PortRecord,
+        stalled_ms: u64,
+    },
+    SlowConsumer {
+        virtual_port: PortRecord,
+    },
+    NetworkChanged {
+        from: Option<IpAddr>,
+        to: Option<IpAddr>,
+    },
+}
+
+/// A virtual port, in serialized events.
+#[cfg(feature = "control")]
+#[derive(serde::Serialize)]
+struct PortRecord {
+    port: u16,
+    protocol: String,
+}
+
+#[cfg(feature = "control")]
+impl From<VirtualPort> for PortRecord {
+    fn from(virtual_port: VirtualPort) -> Self {
+        Self {
+            port: virtual_port.num(),
+            protocol: virtual_port.proto().to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "control")]
+impl From<&Event> for EventFields {
+    fn from(event: &Event) -> Self {
+        let millis = |duration: Duration| duration.as_millis() as u64;
+        match event {
+            Event::Dumb => Self::Dumb,
+            Event::ClientConnectionInitiated(pf, vp, _) => Self::ClientConnectionInitiated {
+                forward: pf.to_string(),
+                virtual_port: (*vp).into(),
+            },
+            Event::VirtualConnectionAccepted(pf, vp, _) => Self::VirtualConnectionAccepted {
+                forward: pf.to_string(),
+                virtual_port: (*vp).into(),
+            },
+            Event::ClientConnectionEstablished(vp) => Self::ClientConnectionEstablished {
+                virtual_port: (*vp).into(),
+            },
+            Event::ClientConnectionRefused(vp) => Self::ClientConnectionRefused {
+                virtual_port: (*vp).into(),
+            },
+            Event::ClientConnectionDropped(vp, reason, _) => Self::ClientConnectionDropped {
+                virtual_port: (*vp).into(),
+                reason: *reason,
+            },
+            Event::LocalData(pf, vp, data, _) => Self::LocalData {
+                forward: pf.to_string(),
+                virtual_port: (*vp).into(),
+                size: data.len(),
+            },
+            Event::RemoteData(vp, data, _) => Self::RemoteData {
+                virtual_port: (*vp).into(),
+                size: data.len(),
+            },
+            Event::RemotePeerData(vp, peer, data, _) => Self::RemotePeerData {
+                virtual_port: (*vp).into(),
+                peer: *peer,
+                size: data.len(),
+            },
+            Event::RemoteRelayData(destination, data) => Self::RemoteRelayData {
+                destination: *destination,
+                size: data.len(),
+            },
+            Event::InboundInternetPacket(proto, data) => Self::InboundInternetPacket {
+                protocol: proto.to_string(),
+                size: data.len(),
+            },
+            Event::OutboundInternetPacket(data) => {
+                Self::OutboundInternetPacket { size: data.len() }
+            }
+            Event::InboundTunPacket(data) => Self::InboundTunPacket { size: data.len() },
+            Event::VirtualDeviceFed(proto) => Self::VirtualDeviceFed {
+                protocol: proto.to_string(),
+            },
+            Event::HandshakeCompleted => Self::HandshakeCompleted,
+            Event::EndpointSwitched(from, to, rtt) => Self::EndpointSwitched {
+                from: *from,
+                to: *to,
+                rtt_ms: millis(*rtt),
+            },
+            Event::QueryConnections(_) => Self::QueryConnections,
+            Event::ConnectionFailed(pf, vp, error) => Self::ConnectionFailed {
+                forward: pf.to_string(),
+                virtual_port: (*vp).into(),
+                error: error.clone(),
+            },
+            Event::TunnelExpired => Self::TunnelExpired,
+            Event::LinkQuality(quality) => Self::LinkQuality {
+                rtt_ms: quality.rtt.map(millis),
+                loss: quality.loss,
+                since_last_received_ms: quality.since_last_received.map(millis),
+            },
+            Event::ProtocolErrorSpike(errors) => Self::ProtocolErrorSpike { errors: *errors },
+            Event::ForwardStats(id, tx, rx) => Self::ForwardStats {
+                forward_id: id.to_string(),
+                tx: *tx,
+                rx: *rx,
+            },
+            Event::PortPoolExhausted(proto) => Self::PortPoolExhausted {
+                protocol: proto.to_string(),
+            },
+            Event::ForwardDraining(id, sessions) => Self::ForwardDraining {
+                forward_id: id.to_string(),
+                sessions: *sessions,
+            },
+            Event::ForwardRemoved(id, closed) => Self::ForwardRemoved {
+                forward_id: id.to_string(),
+                closed: *closed,
+            },
+            Event::ClientStalled(vp, stalled) => Self::ClientStalled {
+                virtual_port: (*vp).into(),
+                stalled_ms: millis(*stalled),
+            },
+            Event::SlowConsumer(vp) => Self::SlowConsumer {
+                virtual_port: (*vp).into(),
+            },
+            Event::NetworkChanged(from, to) => Self::NetworkChanged {
+                from: *from,
+                to: *to,
+            },
+        }
+    }
+}
+
+/// Why a connection was dropped.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "control", derive(serde::Serialize))]
+#[cfg_attr(feature = "control", serde(rename_all = "snake_case"))]
+pub enum DropReason {
+    /// The local client closed the connection.
+    LocalClose,
+    /// The destination reset or closed the connection.
+    PeerReset,
+    /// The destination didn't answer in time.
+    Timeout,
+    /// The tunnel was killed.
+    TunnelDown,
+    /// The port forward was removed before the connection finished.
+    ForwardRemoved,
+}
+
+impl Display for DropReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::LocalClose => "local close",
+                Self::PeerReset => "peer reset",
+                Self::Timeout => "timeout",
+                Self::TunnelDown => "tunnel down",
+                Self::ForwardRemoved => "forward removed",
+            }
+        )
     }
 }
 
@@ -88,7 +527,12 @@ impl Bus {
         let rx = self.bus.subscribe();
 
         let tx = BusSender { id, tx };
-        BusEndpoint { id, tx, rx }
+        BusEndpoint {
+            id,
+            tx,
+            rx,
+            missed: 0,
+        }
     }
 }
 
@@ -102,6 +546,8 @@ pub struct BusEndpoint {
     id: u32,
     tx: BusSender,
     rx: tokio::sync::broadcast::Receiver<(u32, Event)>,
+    /// How many events were lost because the endpoint lagged behind the bus.
+    missed: u64,
 }
 
 impl BusEndpoint {
@@ -127,7 +573,12 @@ impl BusEndpoint {
                         return event;
                     }
                 }
-                Err(_) => {
+                Err(RecvError::Lagged(missed)) => {
+                    // The oldest events were overwritten: carry on with the ones left
+                    self.missed += missed;
+                    continue;
+                }
+                Err(RecvError::Closed) => {
                     error!("Failed to read event bus from endpoint #{}", self.id);
                     return futures::future::pending().await;
                 }
@@ -135,6 +586,41 @@ impl BusEndpoint {
         }
     }
 
+    /// Awaits the next `Event` on the bus, and returns it with the events already queued after it, up to `max`
+    /// in total, so that a busy endpoint handles a burst of events in one wakeup. Like `recv`, no event is lost
+    /// when the future is dropped before it completes.
+    pub async fn recv_many(&mut self, max: usize) -> Vec<Event> {
+        let mut events = vec![self.recv().await];
+        while events.len() < max {
+            match self.try_recv() {
+                Some(event) => events.push(event),
+                None => break,
+            }
+        }
+        events
+    }
+
+    /// Returns the next `Event` on the bus, if one is immediately available.
+    pub fn try_recv(&mut self) -> Option<Event> {
+        loop {
+            match self.rx.try_recv() {
+                Ok((id, event)) => {
+                    if id != self.id {
+                        return Some(event);
+                    }
+                }
+                Err(TryRecvError::Lagged(missed)) => self.missed += missed,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// How many events were lost because the endpoint didn't keep up with the bus, whose capacity is
+    /// shared by every endpoint.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+
     /// Creates a new sender for this endpoint that can be cloned.
     pub fn sender(&self) -> BusSender {
         self.tx.clone()
@@ -186,4 +672,53 @@ mod tests {
         assert!(matches!(recv_1, Event::Dumb));
         assert!(matches!(recv_3, Event::Dumb));
     }
+
+    /// Tests that an endpoint lagging behind the bus counts the events it missed, and carries on.
+    #[tokio::test]
+    async fn test_missed_events() {
+        let bus = Bus::new();
+        let sender = bus.new_endpoint();
+        let mut lagging = bus.new_endpoint();
+        for _ in 0..1010 {
+            sender.send(Event::Dumb);
+        }
+        assert!(matches!(lagging.recv().await, Event::Dumb));
+        assert_eq!(lagging.missed(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_recv_many() {
+        let bus = Bus::new();
+        let endpoint_1 = bus.new_endpoint();
+        let mut endpoint_2 = bus.new_endpoint();
+
+        for _ in 0..3 {
+            endpoint_1.send(Event::Dumb);
+        }
+        assert_eq!(endpoint_2.recv_many(2).await.len(), 2);
+        assert_eq!(endpoint_2.recv_many(EVENT_BATCH).await.len(), 1);
+        assert!(endpoint_2.try_recv().is_none());
+    }
+
+    /// Tests that serialized events carry the schema version, and the size of their data instead of the data.
+    #[cfg(feature = "control")]
+    #[test]
+    fn test_serialize_event() {
+        let virtual_port = VirtualPort::new(1234, PortProtocol::Tcp);
+        let event = Event::RemoteData(virtual_port, b"secret".to_vec(), Instant::now());
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"version":1,"type":"remote_data","virtual_port":{"port":1234,"protocol":"TCP"},"size":6}"#
+        );
+        let event =
+            Event::ClientConnectionDropped(virtual_port, DropReason::PeerReset, Instant::now());
+        assert_eq!(
+            serde_json::to_value(&event).unwrap()["reason"],
+            serde_json::json!("peer_reset")
+        );
+        assert_eq!(
+            serde_json::to_string(&Event::HandshakeCompleted).unwrap(),
+            r#"{"version":1,"type":"handshake_completed"}"#
+        );
+    }
 }
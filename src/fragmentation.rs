@@ -0,0 +1,238 @@
+//! IPv4 fragmentation of the packets of the UDP virtual interface, so that datagrams larger than the MTU
+//! (DNS answers, padded QUIC initials) go through the tunnel. smoltcp 0.8 neither fragments nor reassembles:
+//! the device gives it an unlimited MTU, fragments the packets it sends, and reassembles the fragments it
+//! receives (see `VirtualIpDevice::with_fragmentation`).
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use smoltcp::wire::{IpVersion, Ipv4Packet};
+
+/// The largest IPv4 packet.
+pub const MAX_IPV4_PACKET: usize = 65535;
+/// How long the fragments of a packet are kept while waiting for the others.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// The most packets being reassembled at once; the fragments of further packets are dropped.
+const MAX_PENDING: usize = 64;
+
+/// The "more fragments" flag, in the flags and fragment offset field.
+const MORE_FRAGMENTS: u16 = 0x2000;
+/// The fragment offset, in 8-byte units, in the flags and fragment offset field.
+const OFFSET_MASK: u16 = 0x1fff;
+
+/// The parts of an IPv4 header that fragmentation deals with.
+struct Header {
+    len: usize,
+    total_len: usize,
+    more_fragments: bool,
+    /// The offset of the fragment's payload, in bytes.
+    offset: usize,
+}
+
+impl Header {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if IpVersion::of_packet(packet) != Ok(IpVersion::Ipv4) {
+            return None;
+        }
+        let packet = Ipv4Packet::new_checked(packet).ok()?;
+        let buffer = packet.into_inner();
+        let field = u16::from_be_bytes([buffer[6], buffer[7]]);
+        Some(Self {
+            len: ((buffer[0] & 0x0f) as usize) * 4,
+            total_len: u16::from_be_bytes([buffer[2], buffer[3]]) as usize,
+            more_fragments: field & MORE_FRAGMENTS != 0,
+            offset: ((field & OFFSET_MASK) as usize) * 8,
+        })
+    }
+}
+
+/// Splits an IPv4 packet larger than the MTU into fragments. Other packets are returned as they are.
+pub fn fragment(packet: Vec<u8>, mtu: usize) -> Vec<Vec<u8>> {
+    let header = match Header::parse(&packet) {
+        Some(header) if packet.len() > mtu && mtu >= header.len + 8 => header,
+        _ => return vec![packet],
+    };
+    let payload = &packet[header.len..header.total_len];
+    // Each fragment but the last carries a multiple of 8 bytes
+    let chunk = (mtu - header.len) / 8 * 8;
+    payload
+        .chunks(chunk)
+        .enumerate()
+        .map(|(i, data)| {
+            let offset = header.offset + i * chunk;
+            let more = header.more_fragments || offset + data.len() < header.offset + payload.len();
+            let mut fragment = Vec::with_capacity(header.len + data.len());
+            fragment.extend_from_slice(&packet[..header.len]);
+            fragment.extend_from_slice(data);
+            let total_len = fragment.len() as u16;
+            let field = (offset / 8) as u16 | if more { MORE_FRAGMENTS } else { 0 };
+            fragment[2..4].copy_from_slice(&total_len.to_be_bytes());
+            fragment[6..8].copy_from_slice(&field.to_be_bytes());
+            Ipv4Packet::new_unchecked(&mut fragment).fill_checksum();
+            fragment
+        })
+        .collect()
+}
+
+/// Identifies the fragments of a packet: its source, destination, identification and protocol.
+type FragmentKey = ([u8; 4], [u8; 4], u16, u8);
+
+/// A packet whose fragments are being received.
+struct Partial {
+    /// The header of the first fragment, once received.
+    header: Option<Vec<u8>>,
+    /// The payload of each fragment received, by offset.
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// The size of the payload, once the last fragment is received.
+    size: Option<usize>,
+    started: Instant,
+}
+
+impl Partial {
+    /// Whether the fragments cover the whole payload.
+    fn is_complete(&self) -> bool {
+        let size = match self.size {
+            Some(size) if self.header.is_some() => size,
+            _ => return false,
+        };
+        let mut covered = 0;
+        for (offset, data) in &self.fragments {
+            if *offset > covered {
+                return false;
+            }
+            covered = covered.max(offset + data.len());
+        }
+        covered >= size
+    }
+
+    fn assemble(self) -> Vec<u8> {
+        let mut packet = self.header.unwrap_or_default();
+        let header_len = packet.len();
+        packet.resize(header_len + self.size.unwrap_or_default(), 0);
+        for (offset, data) in self.fragments {
+            let end = (header_len + offset + data.len()).min(packet.len());
+            let start = header_len + offset;
+            packet[start..end].copy_from_slice(&data[..end - start]);
+        }
+        let total_len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        packet[6..8].copy_from_slice(&0u16.to_be_bytes());
+        Ipv4Packet::new_unchecked(&mut packet).fill_checksum();
+        packet
+    }
+}
+
+/// Reassembles the fragments of IPv4 packets.
+#[derive(Default)]
+pub struct Reassembler {
+    pending: HashMap<FragmentKey, Partial>,
+}
+
+impl Reassembler {
+    /// Takes a received packet. Returns it if it isn't a fragment, the reassembled packet if it was the
+    /// missing fragment, or `None` while fragments are missing.
+    pub fn push(&mut self, packet: Vec<u8>, now: Instant) -> Option<Vec<u8>> {
+        let header = match Header::parse(&packet) {
+            Some(header) if header.more_fragments || header.offset > 0 => header,
+            _ => return Some(packet),
+        };
+        self.pending
+            .retain(|_, partial| now.duration_since(partial.started) < REASSEMBLY_TIMEOUT);
+
+        let key = (
+            packet[12..16].try_into().ok()?,
+            packet[16..20].try_into().ok()?,
+            u16::from_be_bytes([packet[4], packet[5]]),
+            packet[9],
+        );
+        let data = packet[header.len..header.total_len].to_vec();
+        if header.offset + data.len() > MAX_IPV4_PACKET - header.len {
+            debug!("Dropped IPv4 fragment beyond the largest packet");
+            self.pending.remove(&key);
+            return None;
+        }
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING {
+            debug!("Dropped IPv4 fragment: too many packets being reassembled");
+            return None;
+        }
+        let partial = self.pending.entry(key).or_insert_with(|| Partial {
+            header: None,
+            fragments: BTreeMap::new(),
+            size: None,
+            started: now,
+        });
+        if header.offset == 0 {
+            partial.header = Some(packet[..header.len].to_vec());
+        }
+        if !header.more_fragments {
+            partial.size = Some(header.offset + data.len());
+        }
+        partial.fragments.insert(header.offset, data);
+
+        if !partial.is_complete() {
+            return None;
+        }
+        self.pending.remove(&key).map(Partial::assemble)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Repr};
+
+    fn packet(payload_len: usize) -> Vec<u8> {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(192, 168, 4, 3),
+            dst_addr: Ipv4Address::new(192, 168, 4, 2),
+            protocol: IpProtocol::Udp,
+            payload_len,
+            hop_limit: 64,
+        };
+        let mut packet = vec![0u8; repr.buffer_len() + payload_len];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut packet),
+            &Default::default(),
+        );
+        for (i, byte) in packet[20..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        // Reassembled packets have no flags
+        packet[6..8].copy_from_slice(&[0, 0]);
+        Ipv4Packet::new_unchecked(&mut packet).fill_checksum();
+        packet
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        let small = packet(100);
+        assert_eq!(fragment(small.clone(), 1420), vec![small]);
+
+        let large = packet(3000);
+        let fragments = fragment(large.clone(), 1420);
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|f| f.len() <= 1420));
+        assert!(fragments
+            .iter()
+            .all(|f| Ipv4Packet::new_checked(f.as_slice())
+                .unwrap()
+                .verify_checksum()));
+
+        // Out of order, with a duplicate
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.push(fragments[2].clone(), now), None);
+        assert_eq!(reassembler.push(fragments[0].clone(), now), None);
+        assert_eq!(reassembler.push(fragments[0].clone(), now), None);
+        assert_eq!(reassembler.push(fragments[1].clone(), now), Some(large));
+        assert!(reassembler.pending.is_empty());
+
+        // The fragments of an incomplete packet expire
+        assert_eq!(reassembler.push(fragments[0].clone(), now), None);
+        let later = now + REASSEMBLY_TIMEOUT;
+        assert_eq!(reassembler.push(fragments[1].clone(), later), None);
+        assert_eq!(reassembler.push(fragments[2].clone(), later), None);
+        assert_eq!(reassembler.pending.len(), 1);
+    }
+}
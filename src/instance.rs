@@ -0,0 +1,154 @@
+//! Registry of the running onetun instances, in a lock directory shared by the instances of a host.
+//!
+//! Each instance locks a file for every resource it can't share with another instance: its listen
+//! addresses, and its peer IPs (whose virtual ports would collide through the same WireGuard peer).
+//! An instance that finds one of them locked fails fast, naming the instance holding it.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::error::OnetunError;
+
+/// The resources locked by an instance, released when dropped.
+#[derive(Debug)]
+pub(crate) struct InstanceLock {
+    files: Vec<(PathBuf, File)>,
+}
+
+impl InstanceLock {
+    /// Locks the resources of the configuration in the given directory, on behalf of the given tunnel.
+    pub fn acquire(dir: &Path, config: &Config, tunnel_id: u32) -> Result<Self, OnetunError> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| OnetunError::Config(anyhow::anyhow!("Invalid lock directory: {}", e)))?;
+        let owner = format!("pid {}, tunnel {}", std::process::id(), tunnel_id);
+
+        let mut lock = Self { files: vec![] };
+        for resource in resources(config) {
+            let path = dir.join(format!("{}.lock", file_name(&resource)));
+            let mut file = lock_file(&path).map_err(|e| OnetunError::InstanceConflict {
+                resource: resource.clone(),
+                owner: e.unwrap_or_else(|| "unknown".into()),
+            })?;
+            file.set_len(0)
+                .and_then(|_| writeln!(file, "{}", owner))
+                .map_err(|e| {
+                    OnetunError::Config(anyhow::anyhow!("Failed to write lock file: {}", e))
+                })?;
+            lock.files.push((path, file));
+        }
+        Ok(lock)
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        for (path, file) in self.files.drain(..) {
+            // Closing the file releases its advisory lock. Without advisory locks, the file itself is the lock.
+            drop(file);
+            if cfg!(not(unix)) {
+                std::fs::remove_file(path).ok();
+            }
+        }
+    }
+}
+
+/// The resources of the configuration that can't be shared between instances.
+fn resources(config: &Config) -> Vec<String> {
+    let mut resources: Vec<String> = config
+        .source_peer_ips()
+        .iter()
+        .map(|ip| format!("peer IP {}", ip))
+        .collect();
+    for pf in config.port_forwards.iter() {
+        resources.push(format!(
+            "{} listen address {}",
+            pf.protocol,
+            pf.source_name()
+        ));
+    }
+    if config.mdns_reflector {
+        resources.push("mDNS reflector".into());
+    }
+    resources
+}
+
+/// A file name identifying the resource.
+fn file_name(resource: &str) -> String {
+    resource
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Opens and locks the file. On conflict, returns the owner written by the instance holding it, if any.
+#[cfg(unix)]
+fn lock_file(path: &Path) -> Result<File, Option<String>> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|_| None)?;
+    // The lock is released when the file is closed, including when the process dies
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(read_owner(&mut file));
+    }
+    Ok(file)
+}
+
+/// Creates the file, which must not exist. On conflict, returns the owner written in it, if any.
+#[cfg(not(unix))]
+fn lock_file(path: &Path) -> Result<File, Option<String>> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => Ok(file),
+        Err(_) => Err(File::open(path)
+            .ok()
+            .and_then(|mut file| read_owner(&mut file))),
+    }
+}
+
+fn read_owner(file: &mut File) -> Option<String> {
+    let mut owner = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut owner).ok()?;
+    Some(owner.trim().to_string()).filter(|owner| !owner.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortForwardConfig;
+
+    #[test]
+    fn test_instance_lock() {
+        let dir = std::env::temp_dir().join(format!("onetun-test-{}", std::process::id()));
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let config = Config::builder()
+            .port_forwards(
+                PortForwardConfig::from_notation("8080:192.168.4.1:8081", "127.0.0.1").unwrap(),
+            )
+            .private_key(key)
+            .endpoint_public_key(key)
+            .endpoint_addr("127.0.0.1:51820".parse().unwrap())
+            .source_peer_ip("192.168.4.3".parse().unwrap())
+            .build()
+            .unwrap();
+
+        let lock = InstanceLock::acquire(&dir, &config, 1).unwrap();
+        match InstanceLock::acquire(&dir, &config, 2) {
+            Err(OnetunError::InstanceConflict { resource, owner }) => {
+                assert_eq!(resource, "peer IP 192.168.4.3");
+                assert_eq!(owner, format!("pid {}, tunnel 1", std::process::id()));
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        drop(lock);
+        assert!(InstanceLock::acquire(&dir, &config, 3).is_ok());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
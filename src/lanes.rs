@@ -0,0 +1,111 @@
+//! Priority lanes for the IP packets sent through the tunnel (see `Config::with_priority_lanes`). Small packets,
+//! such as keystrokes and acknowledgements, go in an interactive lane that is emptied before the bulk lane, so
+//! that they don't wait behind the bulk data queued by other port forwards while the WireGuard socket is busy.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+/// The most packets waiting in the lanes. When they are full, bulk packets are dropped first.
+const MAX_QUEUED: usize = 4096;
+
+/// The lanes of the outbound IP packets, filled from the bus and emptied by the WireGuard production task.
+#[derive(Debug)]
+pub(crate) struct Lanes {
+    /// The largest packet, in bytes, that goes in the interactive lane.
+    max_interactive_size: usize,
+    queues: Mutex<Queues>,
+    /// Wakes the production task up when packets are queued.
+    queued: Notify,
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    interactive: VecDeque<Vec<u8>>,
+    bulk: VecDeque<Vec<u8>>,
+}
+
+impl Lanes {
+    pub(crate) fn new(max_interactive_size: usize) -> Self {
+        Self {
+            max_interactive_size,
+            queues: Mutex::new(Queues::default()),
+            queued: Notify::new(),
+        }
+    }
+
+    /// Queues the packets in their lane.
+    pub(crate) fn push(&self, packets: Vec<Vec<u8>>) {
+        let mut queues = self.queues.lock().expect("Failed to acquire lanes lock");
+        for packet in packets {
+            let interactive = packet.len() <= self.max_interactive_size;
+            if queues.interactive.len() + queues.bulk.len() >= MAX_QUEUED {
+                // Make room for an interactive packet at the expense of the bulk data, which is retransmitted
+                if !interactive || queues.bulk.pop_front().is_none() {
+                    debug!(
+                        "Dropped outbound IP packet of {} bytes: the priority lanes are full",
+                        packet.len()
+                    );
+                    continue;
+                }
+            }
+            if interactive {
+                queues.interactive.push_back(packet);
+            } else {
+                queues.bulk.push_back(packet);
+            }
+        }
+        drop(queues);
+        self.queued.notify_one();
+    }
+
+    /// Awaits queued packets, and returns up to `max` of them: the interactive ones first, then the bulk ones,
+    /// each lane in order.
+    pub(crate) async fn pop_batch(&self, max: usize) -> Vec<Vec<u8>> {
+        loop {
+            {
+                let mut queues = self.queues.lock().expect("Failed to acquire lanes lock");
+                let from_interactive = queues.interactive.len().min(max);
+                let mut batch: Vec<_> = queues.interactive.drain(..from_interactive).collect();
+                let from_bulk = queues.bulk.len().min(max - batch.len());
+                batch.extend(queues.bulk.drain(..from_bulk));
+                if !batch.is_empty() {
+                    return batch;
+                }
+            }
+            self.queued.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lanes() {
+        let lanes = Lanes::new(100);
+        let bulk = |i: u8| vec![i; 1400];
+        let keystroke = |i: u8| vec![i; 60];
+        lanes.push(vec![bulk(1), bulk(2), keystroke(3), bulk(4), keystroke(5)]);
+
+        assert_eq!(
+            lanes.pop_batch(3).await,
+            vec![keystroke(3), keystroke(5), bulk(1)]
+        );
+        lanes.push(vec![keystroke(6)]);
+        assert_eq!(
+            lanes.pop_batch(10).await,
+            vec![keystroke(6), bulk(2), bulk(4)]
+        );
+
+        // When full, interactive packets push the oldest bulk ones out
+        lanes.push((0..MAX_QUEUED).map(|_| bulk(7)).collect());
+        lanes.push(vec![bulk(8), keystroke(9)]);
+        let batch = lanes.pop_batch(MAX_QUEUED + 2).await;
+        assert_eq!(batch.len(), MAX_QUEUED);
+        assert_eq!(batch[0], keystroke(9));
+        assert!(batch[1..].iter().all(|packet| *packet == bulk(7)));
+    }
+}
@@ -3,189 +3,1193 @@
 #[macro_use]
 extern crate log;
 
-use std::sync::Arc;
+#[cfg(not(any(feature = "boringtun", feature = "custom-engine")))]
+compile_error!("onetun needs a WireGuard protocol engine: the `boringtun` feature, or `custom-engine` to give one with `Config::with_protocol_engine`");
 
-use anyhow::Context;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
 use tokio::runtime::{self};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc, watch};
 
-use crate::config::{Config, PortProtocol};
-use crate::events::Bus;
+use crate::audit::{AuditLog, Initiator};
+use crate::bench::{BenchmarkOptions, BenchmarkReport};
+use crate::check::{CheckOptions, CheckReport};
+use crate::config::{
+    BindPolicy, Command, Config, ConfigError, ConfigWarning, ForwardId, PortForwardConfig,
+    PortProtocol,
+};
+use crate::connect::{VirtualTcpStream, VirtualUdpSocket};
+use crate::connection_authorizer::ConnectionAuthorizer;
+use crate::control::{ControlAddress, ControlToken};
+use crate::data_path::DataPath;
+use crate::error::OnetunError;
+use crate::events::{Bus, BusEndpoint, BusSender, Event};
+use crate::instance::InstanceLock;
+use crate::packet_flow::{PacketCallback, SharedPacketCallback};
+#[cfg(feature = "pcap")]
+use crate::pcap::RecentTraffic;
+use crate::tunnel::dns::TunnelResolver;
 use crate::tunnel::tcp::TcpPortPool;
 use crate::tunnel::udp::UdpPortPool;
-use crate::virtual_device::VirtualIpDevice;
+use crate::virtual_device::{DeviceCounters, DeviceStats, VirtualIpDevice};
+#[cfg(feature = "tcp")]
 use crate::virtual_iface::tcp::TcpVirtualInterface;
+#[cfg(feature = "udp")]
 use crate::virtual_iface::udp::UdpVirtualInterface;
-use crate::virtual_iface::VirtualInterfacePoll;
+use crate::virtual_iface::{ConnectionInfo, VirtualInterfacePoll};
 use crate::wg::WireGuardTunnel;
 
+pub mod audit;
+pub mod bench;
+pub mod check;
 pub mod config;
+pub mod connect;
+pub mod connection_authorizer;
+pub mod connection_log;
+pub mod control;
+mod data_path;
+pub mod diagnostics;
+pub mod echo;
+pub mod engine;
+pub mod error;
 pub mod events;
+mod fragmentation;
+mod instance;
+mod lanes;
+pub mod obfuscation;
+pub mod packet_filter;
+pub mod packet_flow;
+#[cfg(feature = "pcap")]
 pub mod pcap;
+pub mod platform;
+mod show;
+pub mod simulation;
+mod state;
+#[cfg(feature = "pcap")]
+mod support;
+#[cfg(any(all(test, feature = "boringtun"), feature = "testing"))]
+pub mod testing;
+#[cfg(unix)]
+mod tun;
 pub mod tunnel;
+mod udp_batch;
 pub mod virtual_device;
 pub mod virtual_iface;
 pub mod wg;
 
+/// How long to wait for a virtual interface to answer a connection query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the UDP sessions are saved to the state file, if any (see `Config::with_state_file`).
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a reloaded port forward retries to listen on the address of the one it replaces, after its grace period.
+const REBIND_MARGIN: Duration = Duration::from_secs(2);
+
+/// The identifier of the next tunnel started in this process.
+static NEXT_TUNNEL_ID: AtomicU32 = AtomicU32::new(1);
+
+/// The log filter of the logger installed by the first tunnel, if onetun installed it.
+static LOG_FILTER: OnceCell<String> = OnceCell::new();
+
+/// The runtime shared by the tunnels started with `blocking_start`.
+static SHARED_RUNTIME: OnceCell<runtime::Runtime> = OnceCell::new();
+
+/// Counters of the tunnel that help diagnose stalls (see `Handle::stats`).
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// The drop counters of the virtual device of each protocol in use.
+    pub devices: Vec<(PortProtocol, DeviceStats)>,
+}
+
+/// How a tunnel started (see `Handle::startup_report`): the values that were resolved or chosen at startup,
+/// for embedders presenting the status of the tunnel without parsing the logs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartupReport {
+    /// The address of the WireGuard endpoint the datagrams were sent to at startup, the fastest of its
+    /// addresses if there are several.
+    pub endpoint: SocketAddr,
+    /// The local address of the UDP socket exchanging datagrams with the endpoint, with the port chosen by the
+    /// system unless it was fixed.
+    pub local_endpoint: Option<SocketAddr>,
+    /// The local port forwards, with the address each one listens on: the port chosen by the system when the
+    /// source port was 0 or busy.
+    pub listeners: Vec<(PortForwardConfig, SocketAddr)>,
+    /// The MTU of the virtual interfaces.
+    pub mtu: usize,
+    /// Whether the tunnel carries IPv4 and IPv6: this peer has a source IP of the version.
+    pub ipv4: bool,
+    pub ipv6: bool,
+    /// The suspicious settings, which were also logged as warnings.
+    pub warnings: Vec<ConfigWarning>,
+}
+
 pub struct Handle {
+    /// Identifies the tunnel among those started in this process.
+    id: u32,
     kill_switch: broadcast::Sender<()>,
     tcp_port_pool: TcpPortPool,
     udp_port_pool: UdpPortPool,
     wg: Arc<WireGuardTunnel>,
     bus: Bus,
+    /// The protocols of the virtual interfaces that were started (one per protocol in use).
+    virtual_interfaces: Vec<PortProtocol>,
+    /// The drop counters of the virtual device of each virtual interface.
+    devices: Vec<(PortProtocol, Arc<DeviceCounters>)>,
+    port_forwards: Vec<PortForwardConfig>,
+    /// Sends the IP packets written by the embedder in packet flow mode.
+    packet_sender: BusSender,
+    packet_callback: SharedPacketCallback,
+    /// The last IP packets of the tunnel, unless disabled.
+    #[cfg(feature = "pcap")]
+    recent_traffic: Option<Arc<RecentTraffic>>,
+    /// The addresses of the destination host names resolved through the tunnel.
+    resolver: Arc<TunnelResolver>,
+    /// The local port forwards, with the address each one listens on.
+    listeners: Vec<(PortForwardConfig, SocketAddr)>,
+    /// The file where the UDP sessions are saved, if any.
+    state_file: Option<PathBuf>,
+    /// Removes each local port forward still running, with the grace period of its sessions.
+    removals: Vec<(
+        ForwardId,
+        PortForwardConfig,
+        watch::Sender<Option<Duration>>,
+    )>,
+    /// What to do when a port forward added to the running tunnel can't listen on its source address.
+    bind_policy: BindPolicy,
+    /// The identifier of the next unnamed port forward added to the running tunnel.
+    next_forward_index: usize,
+    connection_authorizer: Option<Arc<dyn ConnectionAuthorizer>>,
+    /// The DNS server that resolves host names through the tunnel, if any.
+    #[cfg(feature = "http")]
+    tunnel_dns: Option<SocketAddr>,
+    /// The default source peer IP of the port forwards.
+    source_peer_ip: IpAddr,
+    /// Where the control socket listens, if anywhere (see `control::ControlServer`).
+    #[cfg_attr(not(feature = "control"), allow(dead_code))]
+    control_socket: Option<ControlAddress>,
+    /// Where the gRPC control service listens, if anywhere (see `control::grpc::GrpcServer`).
+    #[cfg_attr(not(feature = "grpc"), allow(dead_code))]
+    grpc_listen: Option<SocketAddr>,
+    #[cfg_attr(not(any(feature = "control", feature = "grpc")), allow(dead_code))]
+    control_token: Option<ControlToken>,
+    #[cfg_attr(not(any(feature = "control", feature = "grpc")), allow(dead_code))]
+    remote_control: bool,
+    /// Becomes `true` once the tunnel is usable (see `Handle::ready`).
+    ready: watch::Receiver<bool>,
+    /// Records the actions requested on the handle, if there is an audit log.
+    audit_log: Option<Arc<AuditLog>>,
+    /// Who the actions requested on the handle are attributed to.
+    initiator: Initiator,
+    /// Whether the tunnel was killed through the handle, so that it is only recorded once.
+    killed: AtomicBool,
+    startup_report: StartupReport,
 }
 
 impl Handle {
+    /// The identifier of the tunnel, unique among the tunnels started in this process. It prefixes the
+    /// tunnel's lifecycle logs, and identifies the instance in the lock directory (see `Config::with_lock_dir`).
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The local port forwards, with the address each one listens on: its source address, or the port chosen
+    /// by the system when the source port is 0 or busy (see `Config::with_bind_policy`). Named pipes are
+    /// not listed. Empty for checks.
+    pub fn listeners(&self) -> &[(PortForwardConfig, SocketAddr)] {
+        &self.listeners
+    }
+
+    /// How the tunnel started: the endpoint and local addresses that were resolved or chosen, the MTU, the IP
+    /// versions and the warnings about the configuration. It isn't updated afterwards, unlike `listeners` and
+    /// `current_endpoint`.
+    pub fn startup_report(&self) -> &StartupReport {
+        &self.startup_report
+    }
+
+    /// Resolves once the tunnel is usable: the first handshake with the WireGuard endpoint completed, and
+    /// the local port forwards listen. Embedders can await it before connecting their clients. Fails with
+    /// `OnetunError::Killed` if the tunnel is killed first.
+    pub fn ready(&self) -> impl Future<Output = Result<(), OnetunError>> + Send + 'static {
+        let mut ready = self.ready.clone();
+        async move {
+            while !*ready.borrow() {
+                ready.changed().await.map_err(|_| OnetunError::Killed)?;
+            }
+            Ok(())
+        }
+    }
     pub fn get_killer(&self) -> broadcast::Receiver<()> {
         self.kill_switch.subscribe()
     }
+    /// Subscribes to the events of the tunnel, such as handshakes and traffic statistics.
+    pub fn subscribe(&self) -> BusEndpoint {
+        self.bus.new_endpoint()
+    }
+    /// Kills the tunnel. Killing it again has no effect.
     pub fn kill(&self) {
-        self.kill_switch.send(()).unwrap();
+        self.kill_by(self.initiator)
+    }
+
+    fn kill_by(&self, initiator: Initiator) {
+        if !self.killed.swap(true, Ordering::Relaxed) {
+            self.audit(initiator, "kill", &[]);
+        }
+        // Once killed, the tasks are gone and the kill switch has no receiver left
+        self.kill_switch.send(()).ok();
+    }
+
+    /// Opens a TCP connection to the destination through the tunnel, without a local listener. Requires the TCP
+    /// virtual interface: a TCP port forward, or `Config::with_in_process_connections`.
+    pub async fn open_tcp(&self, destination: SocketAddr) -> anyhow::Result<VirtualTcpStream> {
+        self.require_interface(PortProtocol::Tcp)?;
+        VirtualTcpStream::open(destination, self.tcp_port_pool.clone(), self.bus.clone()).await
+    }
+
+    /// Opens a UDP socket to the destination through the tunnel, without a local listener. Requires the UDP
+    /// virtual interface: a UDP port forward, or `Config::with_in_process_connections`.
+    pub async fn open_udp(&self, destination: SocketAddr) -> anyhow::Result<VirtualUdpSocket> {
+        self.require_interface(PortProtocol::Udp)?;
+        VirtualUdpSocket::open(destination, self.udp_port_pool.clone(), &self.bus).await
+    }
+
+    /// A connector for hyper clients, whose connections go through the tunnel like `open_tcp`. Host names are
+    /// resolved through the tunnel DNS server if there is one, which requires the UDP virtual interface too.
+    #[cfg(feature = "http")]
+    pub fn http_connector(&self) -> anyhow::Result<connect::HttpConnector> {
+        self.require_interface(PortProtocol::Tcp)?;
+        if self.tunnel_dns.is_some() {
+            self.require_interface(PortProtocol::Udp)?;
+        }
+        Ok(connect::HttpConnector::new(
+            self.tcp_port_pool.clone(),
+            self.udp_port_pool.clone(),
+            self.bus.clone(),
+            self.tunnel_dns,
+            self.source_peer_ip.is_ipv6(),
+        ))
+    }
+
+    fn require_interface(&self, protocol: PortProtocol) -> anyhow::Result<()> {
+        if self.virtual_interfaces.contains(&protocol) {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "The {} virtual interface is not running: add a {} port forward, or enable in-process connections",
+                protocol,
+                protocol
+            ))
+        }
+    }
+
+    /// Returns a snapshot of the active TCP and UDP sessions going through the tunnel.
+    pub async fn connections(&self) -> Vec<ConnectionInfo> {
+        let (reply, mut replies) = mpsc::unbounded_channel();
+        self.bus.new_endpoint().send(Event::QueryConnections(reply));
+
+        let mut connections = Vec::new();
+        for _ in 0..self.virtual_interfaces.len() {
+            match tokio::time::timeout(QUERY_TIMEOUT, replies.recv()).await {
+                Ok(Some(mut batch)) => connections.append(&mut batch),
+                _ => {
+                    warn!("A virtual interface did not answer the connection query");
+                    break;
+                }
+            }
+        }
+
+        for connection in connections.iter_mut() {
+            connection.peer_addr = match connection.virtual_port.proto() {
+                PortProtocol::Tcp => {
+                    self.tcp_port_pool
+                        .get_peer_addr(connection.virtual_port)
+                        .await
+                }
+                PortProtocol::Udp => {
+                    self.udp_port_pool
+                        .get_peer_addr(connection.virtual_port)
+                        .await
+                }
+            };
+        }
+        connections.sort_by_key(|c| c.virtual_port);
+        connections
+    }
+
+    /// The packets dropped by the virtual devices, and the events they missed. Drops are also logged, at most
+    /// every 10 seconds per device.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            devices: self
+                .devices
+                .iter()
+                .map(|(protocol, counters)| (*protocol, counters.snapshot()))
+                .collect(),
+        }
+    }
+
+    /// The address of the WireGuard endpoint the datagrams are sent to: the fastest of its addresses (see
+    /// `Config::with_additional_endpoint_addrs`), or the one it roamed to.
+    pub fn current_endpoint(&self) -> SocketAddr {
+        self.wg.endpoint()
+    }
+
+    /// The local address of the UDP socket the datagrams to the WireGuard endpoint are sent from: its port is
+    /// the one chosen by the system, unless fixed with `Config::with_listen_port`. It changes when the socket is
+    /// rebound after a network change.
+    pub fn local_endpoint(&self) -> std::io::Result<SocketAddr> {
+        self.wg.local_addr()
+    }
+
+    /// The quality of the path to the WireGuard endpoint, estimated from the handshakes and the received
+    /// datagrams, without active probing. It is also reported every 10 seconds with `Event::LinkQuality`.
+    pub fn link_quality(&self) -> wg::LinkQuality {
+        self.wg.link_quality()
+    }
+
+    /// The WireGuard interface of the tunnel and its peer: keys, endpoint, allowed IPs, latest handshake
+    /// and transferred bytes.
+    pub fn interface_status(&self) -> wg::InterfaceStatus {
+        self.wg.status()
+    }
+
+    /// Initiates a handshake with the WireGuard endpoint right away, such as after the session expired
+    /// (`Event::TunnelExpired`), instead of waiting for the next packet to send. Completion is notified
+    /// with `Event::HandshakeCompleted`.
+    pub async fn reconnect(&self) -> Result<(), OnetunError> {
+        self.wg.initiate_handshake().await
+    }
+
+    /// Recovers the path to the WireGuard endpoint after a network change: rebinds the UDP socket, unless its
+    /// port is fixed, and initiates a handshake right away. onetun watches the route to the endpoint and does
+    /// so by itself, unless `Config::with_ignore_network_changes` is set, for embedders notified by the OS
+    /// (such as Android's `ConnectivityManager`).
+    pub async fn network_changed(&self) -> Result<(), OnetunError> {
+        self.wg.network_changed().await
+    }
+
+    /// The status of the tunnel in the layout of `wg show`, for the scripts monitoring WireGuard
+    /// interfaces. The interface is named `onetun` followed by the identifier of the tunnel.
+    pub fn format_wg_show(&self) -> String {
+        show::format(&format!("onetun{}", self.id), &self.wg.status())
+    }
+
+    /// The public key of the WireGuard endpoint (base64, as in WireGuard configurations), once it proved
+    /// it holds the private key by completing a handshake. Each handshake is also logged with it.
+    pub fn verified_peer(&self) -> Option<String> {
+        self.wg.verified_peer().map(String::from)
+    }
+
+    /// How many packets from the WireGuard endpoint were dropped because their source IP isn't allowed
+    /// (see `Config::with_allowed_ips`).
+    pub fn disallowed_packets(&self) -> u64 {
+        self.wg.disallowed_packets()
+    }
+
+    /// How many cookie replies were received from the WireGuard endpoint, which sends them instead of
+    /// handshake responses when it is under load (see `Config::with_handshake_rate_limit`).
+    pub fn cookie_replies(&self) -> u64 {
+        self.wg.cookie_replies()
+    }
+
+    /// How many datagrams from the WireGuard endpoint were rejected by the protocol, by category, to tell
+    /// mismatched keys from corruption or reordering on the path. Spikes are also reported with
+    /// `Event::ProtocolErrorSpike`.
+    pub fn protocol_errors(&self) -> wg::ProtocolErrors {
+        self.wg.protocol_errors()
+    }
+
+    /// Removes the local port forward: it stops accepting new clients right away, and its open sessions are
+    /// closed once the grace period is over, if they haven't finished by then. The progress is reported with
+    /// `Event::ForwardDraining` and `Event::ForwardRemoved`. Returns false if there is no such local port forward.
+    pub fn remove_forward(&mut self, id: &ForwardId, grace: Duration) -> bool {
+        self.remove_forward_by(self.initiator, id, grace)
+    }
+
+    fn remove_forward_by(&mut self, initiator: Initiator, id: &ForwardId, grace: Duration) -> bool {
+        let index = match self.removals.iter().position(|(i, _, _)| i == id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let (_, port_forward, removal) = self.removals.remove(index);
+        self.audit(
+            initiator,
+            "remove-forward",
+            &[
+                ("forward", port_forward.to_string()),
+                ("grace", format!("{}s", grace.as_secs())),
+            ],
+        );
+        removal.send(Some(grace)).ok();
+        self.listeners.retain(|(pf, _)| *pf != port_forward);
+        true
+    }
+
+    /// The local port forwards still running, with their identifier: those of the configuration that weren't
+    /// removed, and those added since.
+    pub fn forwards(&self) -> Vec<(ForwardId, PortForwardConfig)> {
+        self.removals
+            .iter()
+            .map(|(id, pf, _)| (id.clone(), pf.clone()))
+            .collect()
+    }
+
+    /// Adds a local port forward to the running tunnel, listening like those of the configuration (see
+    /// `Config::with_bind_policy`). Its protocol's virtual interface must be running, and its destination
+    /// can't be a host name resolved through the tunnel. Returns its identifier: its name, or the next index.
+    pub async fn add_forward(
+        &mut self,
+        port_forward: PortForwardConfig,
+    ) -> Result<ForwardId, OnetunError> {
+        self.add_forward_by(self.initiator, port_forward, self.bind_policy)
+            .await
+    }
+
+    async fn add_forward_by(
+        &mut self,
+        initiator: Initiator,
+        port_forward: PortForwardConfig,
+        bind_policy: BindPolicy,
+    ) -> Result<ForwardId, OnetunError> {
+        self.check_new_forward(&port_forward)
+            .map_err(OnetunError::Config)?;
+        let id = port_forward.id(self.next_forward_index);
+        let (listener, addrs) = bind_forward(&id, &port_forward, bind_policy).await?;
+        self.next_forward_index += 1;
+        self.audit(
+            initiator,
+            "add-forward",
+            &[("forward", port_forward.to_string())],
+        );
+        for addr in addrs {
+            self.listeners.push((port_forward.clone(), addr));
+        }
+        self.spawn_port_forward(id.clone(), port_forward, listener);
+        Ok(id)
+    }
+
+    /// Checks that the port forward can be added to the running tunnel.
+    fn check_new_forward(&self, port_forward: &PortForwardConfig) -> anyhow::Result<()> {
+        if port_forward.remote {
+            return Err(anyhow::anyhow!(
+                "Remote port forwards can't be added to a running tunnel"
+            ));
+        }
+        self.require_interface(port_forward.protocol)?;
+        if port_forward.destination.ip().is_unspecified() {
+            return Err(anyhow::anyhow!(
+                "Destinations resolved through the tunnel can't be added to a running tunnel: {}",
+                port_forward.destination_name()
+            ));
+        }
+        for (_, pf, _) in self.removals.iter() {
+            if port_forward.name.is_some()
+                && pf.name == port_forward.name
+                && pf.protocol == port_forward.protocol
+            {
+                return Err(ConfigError::DuplicateName(pf.name.clone().unwrap()).into());
+            }
+            if port_forward.source.port() != 0
+                && (pf.source, &pf.interface, pf.protocol)
+                    == (
+                        port_forward.source,
+                        &port_forward.interface,
+                        port_forward.protocol,
+                    )
+            {
+                return Err(ConfigError::DuplicateListenAddress(pf.source, pf.protocol).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the local port forwards with the given ones: those that aren't given anymore are removed with the
+    /// grace period (see `Handle::remove_forward`), and those that aren't running yet are added (see
+    /// `Handle::add_forward`). Stops at the first port forward that can't be added, leaving the changes made
+    /// before it. Returns the identifiers of the added and of the removed port forwards.
+    pub async fn reload_forwards(
+        &mut self,
+        port_forwards: Vec<PortForwardConfig>,
+        grace: Duration,
+    ) -> Result<(Vec<ForwardId>, Vec<ForwardId>), OnetunError> {
+        self.reload_forwards_by(self.initiator, port_forwards, grace)
+            .await
+    }
+
+    async fn reload_forwards_by(
+        &mut self,
+        initiator: Initiator,
+        port_forwards: Vec<PortForwardConfig>,
+        grace: Duration,
+    ) -> Result<(Vec<ForwardId>, Vec<ForwardId>), OnetunError> {
+        let stale: Vec<(ForwardId, PortForwardConfig)> = self
+            .forwards()
+            .into_iter()
+            .filter(|(_, pf)| !port_forwards.contains(pf))
+            .collect();
+        for (id, _) in stale.iter() {
+            self.remove_forward_by(initiator, id, grace);
+        }
+
+        let running: Vec<PortForwardConfig> =
+            self.forwards().into_iter().map(|(_, pf)| pf).collect();
+        let mut added = Vec::new();
+        for pf in port_forwards {
+            if running.contains(&pf) {
+                continue;
+            }
+            // The removed port forwards listen until their sessions drained
+            let bind_policy = if stale.iter().any(|(_, old)| old.source == pf.source) {
+                BindPolicy::Retry(grace + REBIND_MARGIN)
+            } else {
+                self.bind_policy
+            };
+            added.push(self.add_forward_by(initiator, pf, bind_policy).await?);
+        }
+        Ok((added, stale.into_iter().map(|(id, _)| id).collect()))
+    }
+
+    /// Serves the local port forward on its listener until it is removed, or the tunnel is killed.
+    fn spawn_port_forward(
+        &mut self,
+        id: ForwardId,
+        pf: PortForwardConfig,
+        listener: tunnel::Listener,
+    ) {
+        let (removal, removal_rx) = watch::channel(None);
+        self.removals.push((id.clone(), pf.clone(), removal));
+        let source_peer_ip = pf.source_peer_ip.unwrap_or(self.source_peer_ip);
+        let tcp_port_pool = self.tcp_port_pool.clone();
+        let udp_port_pool = self.udp_port_pool.clone();
+        let wg = self.wg.clone();
+        let resolver = self.resolver.clone();
+        let authorizer = self.connection_authorizer.clone();
+        let bus = self.bus.clone();
+        let kill_switch = self.get_killer();
+        tokio::spawn(async move {
+            tunnel::port_forward(
+                id,
+                pf.clone(),
+                listener,
+                source_peer_ip,
+                tcp_port_pool,
+                udp_port_pool,
+                wg,
+                resolver,
+                authorizer,
+                bus,
+                removal_rx,
+                kill_switch,
+            )
+            .await
+            .unwrap_or_else(|e| error!("Port-forward failed for {} : {}", pf, e))
+        });
+    }
+
+    /// Records the action in the audit log, if there is one.
+    fn audit(&self, initiator: Initiator, action: &str, details: &[(&str, String)]) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(initiator, action, details);
+        }
+    }
+
+    /// The occupancy and allocation counters of the virtual ports of the protocol. When they are exhausted,
+    /// `Event::PortPoolExhausted` is also sent (see `Config::with_udp_port_exhaustion`).
+    pub async fn port_pool_stats(&self, protocol: PortProtocol) -> tunnel::PortPoolStats {
+        match protocol {
+            PortProtocol::Tcp => self.tcp_port_pool.stats().await,
+            PortProtocol::Udp => self.udp_port_pool.stats().await,
+        }
+    }
+
+    /// The file where the UDP sessions are saved, if any (see `Config::with_state_file`).
+    pub fn state_file(&self) -> Option<&Path> {
+        self.state_file.as_deref()
+    }
+
+    /// Saves the UDP sessions of the local port forwards to the state file, and returns how many were saved
+    /// (see `Config::with_state_file`). They are also saved periodically and when the tunnel is killed.
+    pub async fn save_state(&self) -> anyhow::Result<usize> {
+        let path = self
+            .state_file
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No state file is configured"))?;
+        let sessions = self.udp_port_pool.sessions().await;
+        state::save(path, &sessions)?;
+        Ok(sessions.len())
+    }
+
+    /// Sends a raw IP packet through the tunnel, in packet flow mode (see `Config::with_packet_flow`).
+    pub fn write_ip_packet(&self, packet: &[u8]) {
+        self.packet_sender
+            .send(Event::OutboundInternetPacket(packet.to_vec()));
+    }
+
+    /// Registers the function receiving the raw IP packets from the tunnel, in packet flow mode,
+    /// replacing the previous one. It is called from the tunnel's runtime, one packet at a time,
+    /// and must not register another callback itself.
+    pub fn set_packet_callback(&self, callback: Option<PacketCallback>) {
+        *self.packet_callback.write().unwrap() = callback;
+    }
+
+    /// Writes the last IP packets sent to and received from the WireGuard tunnel to a new pcap file, to
+    /// investigate a failure after the fact. Returns how many packets were written (see
+    /// `Config::with_capture_buffer`).
+    #[cfg(feature = "pcap")]
+    pub async fn dump_recent_traffic(&self, path: impl AsRef<Path>) -> anyhow::Result<usize> {
+        match &self.recent_traffic {
+            Some(recent_traffic) => recent_traffic.dump(path.as_ref()).await,
+            None => Err(anyhow::anyhow!("The capture buffer is disabled")),
+        }
+    }
+
+    /// Captures the IP packets sent to and received from the WireGuard tunnel that match the filter, in
+    /// memory, for embedders to inspect the traffic without writing pcap files. The stream ends when the
+    /// tunnel is killed; packets are skipped if it is not polled fast enough.
+    #[cfg(feature = "pcap")]
+    pub fn start_capture(
+        &self,
+        filter: pcap::CaptureFilter,
+    ) -> impl futures::Stream<Item = pcap::CapturedPacket> {
+        pcap::capture_stream(filter, self.bus.new_endpoint(), self.get_killer())
+    }
+
+    /// Runs TCP and UDP echo services on the local address until the tunnel is killed, and returns the address
+    /// they listen on (see `echo`). With a remote port forward to it, the other peer of a pair of onetun tunnels
+    /// can check the forwarding end to end and benchmark it.
+    pub async fn spawn_echo_responder(&self, addr: SocketAddr) -> std::io::Result<SocketAddr> {
+        let server = echo::EchoServer::bind(addr).await?;
+        let local_addr = server.local_addr()?;
+        info!("Echo responder listening on {} (TCP and UDP)", local_addr);
+        let mut kill_switch = self.get_killer();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = server.run() => {}
+                _ = kill_switch.recv() => {}
+            }
+        });
+        Ok(local_addr)
+    }
+
+    /// Measures throughput and latency through each local TCP port forward, one after the other, except
+    /// those listening on named pipes or interfaces. An echo server must be listening on each destination.
+    pub async fn benchmark(
+        &self,
+        options: &BenchmarkOptions,
+    ) -> Vec<(PortForwardConfig, anyhow::Result<BenchmarkReport>)> {
+        let mut reports = Vec::new();
+        for pf in self.port_forwards.iter().filter(|pf| {
+            pf.protocol == PortProtocol::Tcp && pf.pipe.is_none() && pf.interface.is_none()
+        }) {
+            reports.push((pf.clone(), bench::run(pf.source, options).await));
+        }
+        reports
+    }
+
+    /// Checks that the destination of each local port forward is reachable through the tunnel, one after the other.
+    pub async fn check(
+        &self,
+        options: &CheckOptions,
+    ) -> Vec<(PortForwardConfig, anyhow::Result<CheckReport>)> {
+        let mut reports = Vec::new();
+        for pf in self.port_forwards.iter() {
+            let resolved = match self.resolver.resolve(&Arc::new(pf.clone())).await {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    reports.push((pf.clone(), Err(e)));
+                    continue;
+                }
+            };
+            let report = check::run(
+                &resolved,
+                &self.tcp_port_pool,
+                &self.udp_port_pool,
+                &self.bus,
+                options,
+            )
+            .await;
+            reports.push((pf.clone(), report));
+        }
+        reports
+    }
+}
+
+/// Gives back their virtual ports to the UDP clients saved in the state file, for the port forwards that still
+/// listen on the same address.
+async fn restore_state(
+    path: &Path,
+    port_forwards: &[PortForwardConfig],
+    udp_port_pool: &UdpPortPool,
+) {
+    let sessions = match state::load(path) {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            warn!("Failed to load the UDP sessions: {:?}", e);
+            return;
+        }
+    };
+    let sessions: Vec<_> = sessions
+        .into_iter()
+        .filter(|session| {
+            port_forwards
+                .iter()
+                .any(|pf| pf.protocol == PortProtocol::Udp && pf.source == session.forward)
+        })
+        .collect();
+    let restored = udp_port_pool.restore(&sessions).await;
+    if restored > 0 {
+        info!("Restored {} UDP sessions from {:?}", restored, path);
     }
 }
 
-pub async fn start(config: Config) {
-    init_logger(&config).unwrap();
+/// Starts the tunnel, the virtual interfaces and the port forwards, and returns a `Handle` to control them.
+pub async fn start(config: Config) -> Result<Handle, OnetunError> {
+    init_logger(&config);
 
-    for warning in &config.warnings {
+    let warnings = config
+        .validate()
+        .map_err(|e| OnetunError::Config(e.into()))?;
+    for warning in &warnings {
         warn!("{}", warning);
     }
 
-    // Initialize the port pool for each protocol
-    let tcp_port_pool = TcpPortPool::new();
-    let udp_port_pool = UdpPortPool::new();
+    let id = NEXT_TUNNEL_ID.fetch_add(1, Ordering::Relaxed);
+    let instance_lock = match &config.lock_dir {
+        Some(lock_dir) => Some(InstanceLock::acquire(lock_dir, &config, id)?),
+        None => None,
+    };
+    info!("[tunnel {}] Starting tunnel", id);
+
+    let audit_log = match &config.audit_log {
+        Some(path) => {
+            let audit_log = AuditLog::open(path, id).map_err(OnetunError::Config)?;
+            audit_log.record_start(&config);
+            Some(Arc::new(audit_log))
+        }
+        None => None,
+    };
 
     let bus = Bus::default();
 
-    let wg = WireGuardTunnel::new(&config, bus.clone())
-        .await
-        .with_context(|| "Failed to initialize WireGuard tunnel")
-        .unwrap();
+    // Initialize the port pool for each protocol
+    let tcp_port_pool = TcpPortPool::new()
+        .with_sequential_ports(config.sequential_ports)
+        .with_events(&bus);
+    let udp_port_pool = UdpPortPool::new()
+        .with_exhaustion(config.udp_port_exhaustion)
+        .with_sequential_ports(config.sequential_ports)
+        .with_events(&bus);
+
+    // Destination host names are only resolved through the tunnel with a tunnel DNS server
+    let resolver = match config.tunnel_dns {
+        Some(_) => TunnelResolver::new(&config.port_forwards, config.source_peer_ip),
+        None => TunnelResolver::default(),
+    };
+    let resolver = Arc::new(resolver.with_nat64(config.nat64, config.source_peer_ip));
+
+    // The WireGuard socket is created on the data path, whose runtime drives it
+    let data_path = DataPath::new(&config, id).map_err(|e| {
+        OnetunError::Config(anyhow::anyhow!(
+            "Failed to start the data path threads: {}",
+            e
+        ))
+    })?;
+    let wg = {
+        let (config, bus) = (config.clone(), bus.clone());
+        data_path
+            .run(async move { WireGuardTunnel::new(&config, bus).await })
+            .await?
+    };
     let wg = Arc::new(wg);
 
     let (kill_switch, _) = broadcast::channel(1);
-    let handle = Handle {
+    let (ready, ready_rx) = watch::channel(false);
+    let mut handle = Handle {
+        id,
         kill_switch,
         tcp_port_pool: tcp_port_pool.clone(),
         udp_port_pool: udp_port_pool.clone(),
         wg: wg.clone(),
         bus: bus.clone(),
+        virtual_interfaces: vec![],
+        devices: vec![],
+        port_forwards: config.port_forwards.clone(),
+        packet_sender: bus.new_endpoint().sender(),
+        packet_callback: Arc::new(RwLock::new(None)),
+        #[cfg(feature = "pcap")]
+        recent_traffic: None,
+        resolver: resolver.clone(),
+        listeners: vec![],
+        state_file: config.state_file.clone(),
+        removals: vec![],
+        bind_policy: config.bind_policy,
+        // Added port forwards are identified after those of the configuration
+        next_forward_index: config.port_forwards.len() + config.remote_port_forwards.len(),
+        connection_authorizer: config.connection_authorizer.clone(),
+        #[cfg(feature = "http")]
+        tunnel_dns: config.tunnel_dns,
+        source_peer_ip: config.source_peer_ip,
+        control_socket: config.control_socket.clone(),
+        grpc_listen: config.grpc_listen,
+        control_token: config.control_token.clone(),
+        remote_control: config.remote_control,
+        ready: ready_rx,
+        // Attached once started: failing to start isn't an action to record
+        audit_log: None,
+        initiator: config.initiator,
+        killed: AtomicBool::new(false),
+        startup_report: StartupReport {
+            endpoint: config.endpoint_addr,
+            local_endpoint: None,
+            listeners: vec![],
+            mtu: config.max_transmission_unit,
+            ipv4: config.source_peer_ips().iter().any(IpAddr::is_ipv4),
+            ipv6: config.source_peer_ips().iter().any(IpAddr::is_ipv6),
+            warnings,
+        },
     };
 
+    {
+        // Keep the instance registered until the tunnel is killed
+        let mut kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            kill_switch.recv().await.ok();
+            drop(instance_lock);
+            info!("[tunnel {}] Tunnel killed", id);
+        });
+    }
+
+    #[cfg(feature = "pcap")]
+    if config.capture_buffer > 0 {
+        // Keep the last packets in memory
+        let recent_traffic = Arc::new(RecentTraffic::new(config.capture_buffer));
+        handle.recent_traffic = Some(recent_traffic.clone());
+        let bus = bus.clone();
+        let kill_switch = handle.get_killer();
+        tokio::spawn(async move { pcap::record_recent(recent_traffic, bus, kill_switch).await });
+    }
+
+    #[cfg(feature = "pcap")]
+    if let (Some(dir), Some(recent_traffic)) = (&config.pcap_on_error, &handle.recent_traffic) {
+        // Write a support bundle when the tunnel fails
+        let summary = support::summary(&config);
+        let (dir, recent_traffic) = (dir.clone(), recent_traffic.clone());
+        let bus = bus.clone();
+        let kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            support::write_on_error(dir, summary, recent_traffic, bus, kill_switch).await
+        });
+    }
+
+    #[cfg(feature = "pcap")]
     if let Some(pcap_file) = config.pcap_file.clone() {
         // Start packet capture
+        let pcap_index_file = config.pcap_index_file.clone();
         let bus = bus.clone();
         let kill_switch = handle.get_killer();
-        tokio::spawn(async move { pcap::capture(pcap_file, bus, kill_switch).await });
+        tokio::spawn(async move {
+            pcap::capture(pcap_file, pcap_index_file, bus, kill_switch)
+                .await
+                .unwrap_or_else(|e| error!("Packet capture failed: {:?}", e))
+        });
     }
 
-    {
+    if let Some(privacy) = config.connection_log {
+        // Log the connections as they close
+        let port_pool = tcp_port_pool.clone();
+        let bus = bus.clone();
+        let kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            connection_log::log_connections(privacy, port_pool, bus, kill_switch).await
+        });
+    }
+
+    // Listen for the handshake before the consumption task starts
+    let mut handshake_endpoint = bus.new_endpoint();
+    let mut ready_endpoint = bus.new_endpoint();
+
+    // When replaying a capture, the packets come from the capture instead of the peer
+    let live = config.replay.is_none();
+
+    if live {
         // Start routine task for WireGuard
         let wg = wg.clone();
         let kill_switch = handle.get_killer();
-        tokio::spawn(async move { wg.routine_task(kill_switch).await });
+        data_path.spawn(async move { wg.routine_task(kill_switch).await });
     }
 
-    {
+    if live {
         // Start consumption task for WireGuard
         let wg = wg.clone();
         let kill_switch = handle.get_killer();
-        tokio::spawn(async move { wg.consume_task(kill_switch).await });
+        data_path.spawn(async move { wg.consume_task(kill_switch).await });
     }
 
-    {
+    if live {
         // Start production task for WireGuard
         let wg = wg.clone();
         let kill_switch = handle.get_killer();
-        tokio::spawn(async move { wg.produce_task(kill_switch).await });
+        data_path.spawn(async move { wg.produce_task(kill_switch).await });
+    }
+
+    if live && !config.ignore_network_changes {
+        // Rebind and reconnect when the network changes
+        let wg = wg.clone();
+        let kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            wg.network_task(platform::network::NETWORK_POLL_INTERVAL, kill_switch)
+                .await
+        });
     }
 
-    if config
-        .port_forwards
-        .iter()
-        .any(|pf| pf.protocol == PortProtocol::Tcp)
+    let endpoint_addrs = config.endpoint_addrs();
+    if live && endpoint_addrs.len() > 1 {
+        // Start with the fastest address of the endpoint, and follow the changes in latency
+        wg.select_endpoint(&endpoint_addrs).await;
+        let wg = wg.clone();
+        let mut kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(wg::PROBE_INTERVAL) => {
+                        wg.select_endpoint(&endpoint_addrs).await;
+                    }
+                    _ = kill_switch.recv() => break,
+                }
+            }
+        });
+    }
+
+    if let Some(timeout) = config.handshake_timeout.filter(|_| live) {
+        if let Err(e) = wait_for_handshake(&wg, &mut handshake_endpoint, timeout).await {
+            handle.kill();
+            return Err(e);
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(tun_fd) = config.tun_fd {
+        // Bridge the tun device, which replaces the port forwards
+        let bus = bus.clone();
+        let kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            tun::bridge(tun_fd, bus, kill_switch)
+                .await
+                .unwrap_or_else(|e| error!("Tun device bridge failed: {:?}", e))
+        });
+    }
+
+    if config.packet_flow {
+        // Deliver the packets from the tunnel to the embedder
+        let callback = handle.packet_callback.clone();
+        let bus = bus.clone();
+        let kill_switch = handle.get_killer();
+        tokio::spawn(async move { packet_flow::deliver(callback, bus, kill_switch).await });
+    }
+
+    #[cfg(feature = "tcp")]
+    if config.in_process_connections
+        || config
+            .port_forwards
+            .iter()
+            .chain(config.remote_port_forwards.iter())
+            .any(|pf| pf.protocol == PortProtocol::Tcp)
     {
-        // TCP device
+        // TCP device, whose feeding task runs on the data path
+        let _data_path = data_path.enter();
         let bus = bus.clone();
-        let device =
-            VirtualIpDevice::new(PortProtocol::Tcp, bus.clone(), config.max_transmission_unit);
+        let mut device =
+            VirtualIpDevice::new(PortProtocol::Tcp, bus.clone(), config.max_transmission_unit)
+                .with_hairpin(config.source_peer_ips());
+        if let Some(packet_filter) = config.packet_filter.clone() {
+            device = device.with_packet_filter(packet_filter);
+        }
 
         // Start TCP Virtual Interface
         let port_forwards = config.port_forwards.clone();
-        let iface = TcpVirtualInterface::new(port_forwards, bus, config.source_peer_ip);
+        let remote_port_forwards = config.remote_port_forwards.clone();
+        let iface = TcpVirtualInterface::new(
+            port_forwards,
+            remote_port_forwards,
+            tcp_port_pool.clone(),
+            bus,
+            config.source_peer_ips(),
+        )
+        .with_routes(config.interface_routes())
+        .with_connect_timeout(config.connect_timeout)
+        .with_clock(wg.clock());
         let kill_switch = handle.get_killer();
-        tokio::spawn(async move { iface.poll_loop(device, kill_switch).await });
+        handle.devices.push((PortProtocol::Tcp, device.counters()));
+        data_path.spawn(async move { iface.poll_loop(device, kill_switch).await });
+        handle.virtual_interfaces.push(PortProtocol::Tcp);
     }
 
-    if config
-        .port_forwards
-        .iter()
-        .any(|pf| pf.protocol == PortProtocol::Udp)
+    #[cfg(feature = "udp")]
+    if config.mdns_reflector
+        || config.in_process_connections
+        || !resolver.is_empty()
+        || config
+            .port_forwards
+            .iter()
+            .any(|pf| pf.protocol == PortProtocol::Udp)
         || config
             .remote_port_forwards
             .iter()
             .any(|pf| pf.protocol == PortProtocol::Udp)
     {
-        // UDP device
+        // UDP device, whose feeding task runs on the data path
+        let _data_path = data_path.enter();
         let bus = bus.clone();
-        let device =
-            VirtualIpDevice::new(PortProtocol::Udp, bus.clone(), config.max_transmission_unit);
+        let mut device =
+            VirtualIpDevice::new(PortProtocol::Udp, bus.clone(), config.max_transmission_unit)
+                .with_hairpin(config.source_peer_ips())
+                .with_fragmentation();
+        if let Some(packet_filter) = config.packet_filter.clone() {
+            device = device.with_packet_filter(packet_filter);
+        }
 
         // Start UDP Virtual Interface
-        let port_forwards = config.port_forwards.clone();
+        let mut port_forwards = config.port_forwards.clone();
+        if config.mdns_reflector {
+            port_forwards.push(tunnel::mdns::port_forward());
+        }
         let remote_port_forwards = config.remote_port_forwards.clone();
         let iface = UdpVirtualInterface::new(
             port_forwards,
             remote_port_forwards,
             bus,
-            config.source_peer_ip,
-        );
+            config.source_peer_ips(),
+        )
+        .with_routes(config.interface_routes())
+        .with_clock(wg.clock());
         let kill_switch = handle.get_killer();
-        tokio::spawn(async move { iface.poll_loop(device, kill_switch).await });
+        handle.devices.push((PortProtocol::Udp, device.counters()));
+        data_path.spawn(async move { iface.poll_loop(device, kill_switch).await });
+        handle.virtual_interfaces.push(PortProtocol::Udp);
     }
 
-    {
-        let port_forwards = config.port_forwards;
-        let source_peer_ip = config.source_peer_ip;
+    #[cfg(feature = "pcap")]
+    if let Some(path) = config.replay.clone() {
+        // Feed the packets the peer sent in the capture to the virtual interfaces
+        let source_peer_ips = config.source_peer_ips();
+        let bus = bus.clone();
+        let kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            pcap::replay::replay(path, source_peer_ips, bus, kill_switch)
+                .await
+                .unwrap_or_else(|e| error!("Packet replay failed: {:?}", e))
+        });
+    }
 
-        port_forwards
-            .into_iter()
-            .map(|pf| {
-                (
-                    pf,
-                    wg.clone(),
-                    tcp_port_pool.clone(),
-                    udp_port_pool.clone(),
-                    bus.clone(),
-                    handle.get_killer(),
-                )
-            })
-            .for_each(
-                move |(pf, wg, tcp_port_pool, udp_port_pool, bus, kill_switch)| {
-                    tokio::spawn(async move {
-                        tunnel::port_forward(
-                            pf,
-                            source_peer_ip,
-                            tcp_port_pool,
-                            udp_port_pool,
-                            wg,
-                            bus,
-                            kill_switch,
-                        )
-                        .await
-                        .unwrap_or_else(|e| error!("Port-forward failed for {} : {}", pf, e))
-                    });
-                },
-            );
+    let listens_locally = !matches!(config.command, Some(Command::Check(_) | Command::Show));
+    if config.mdns_reflector && listens_locally {
+        let udp_port_pool = udp_port_pool.clone();
+        let bus = bus.clone();
+        let mut kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = tunnel::mdns::mdns_reflector(udp_port_pool, bus) => {
+                    result.unwrap_or_else(|e| error!("mDNS reflector failed: {:?}", e))
+                }
+                _ = kill_switch.recv() => {}
+            }
+        });
+    }
+
+    if let Some(server) = config.tunnel_dns.filter(|_| !resolver.is_empty()) {
+        let resolver = resolver.clone();
+        let udp_port_pool = udp_port_pool.clone();
+        let bus = bus.clone();
+        let mut kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = resolver.run(server, udp_port_pool, bus) => {}
+                _ = kill_switch.recv() => {}
+            }
+        });
+    }
+
+    // Checks go through the virtual interfaces directly, and `show` only reads the status: no local listeners
+    if listens_locally {
+        if let Some(path) = &config.state_file {
+            restore_state(path, &config.port_forwards, &udp_port_pool).await;
+            let udp_port_pool = udp_port_pool.clone();
+            let path = path.clone();
+            let mut kill_switch = handle.get_killer();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(STATE_SAVE_INTERVAL);
+                interval.tick().await;
+                loop {
+                    let killed = tokio::select! {
+                        _ = interval.tick() => false,
+                        _ = kill_switch.recv() => true,
+                    };
+                    let sessions = udp_port_pool.sessions().await;
+                    if let Err(e) = state::save(&path, &sessions) {
+                        warn!("Failed to save the UDP sessions: {:?}", e);
+                    }
+                    if killed {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // The connections signed with TCP MD5 never reach the port forwards, only a system counter
+        let tcp_forwards: Vec<PortForwardConfig> = config
+            .port_forwards
+            .iter()
+            .filter(|pf| pf.protocol == PortProtocol::Tcp)
+            .cloned()
+            .collect();
+        if !tcp_forwards.is_empty() {
+            let mut kill_switch = handle.get_killer();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = tunnel::tcp_options::watch_md5_drops(tcp_forwards) => {}
+                    _ = kill_switch.recv() => {}
+                }
+            });
+        }
+
+        // Listen on every source address before serving any, so that a busy port fails the start
+        let mut listeners = Vec::new();
+        for (i, pf) in config.port_forwards.into_iter().enumerate() {
+            let id = pf.id(i);
+            match bind_forward(&id, &pf, config.bind_policy).await {
+                Ok((listener, addrs)) => {
+                    for addr in addrs {
+                        handle.listeners.push((pf.clone(), addr));
+                    }
+                    listeners.push((id, pf, listener));
+                }
+                Err(e) => {
+                    handle.kill();
+                    return Err(e);
+                }
+            }
+        }
+
+        for (id, pf, listener) in listeners {
+            handle.spawn_port_forward(id, pf, listener);
+        }
     }
 
     {
         let remote_port_forwards = config.remote_port_forwards;
+        // Remote port forwards are identified after the local ones
+        let first_id = handle.port_forwards.len();
 
         remote_port_forwards
             .into_iter()
-            .map(|pf| {
+            .enumerate()
+            .map(|(i, pf)| {
                 (
+                    pf.id(first_id + i),
                     pf,
                     wg.clone(),
                     tcp_port_pool.clone(),
@@ -195,10 +1199,11 @@ pub async fn start(config: Config) {
                 )
             })
             .for_each(
-                move |(pf, wg, tcp_port_pool, udp_port_pool, bus, kill_switch)| {
+                move |(id, pf, wg, tcp_port_pool, udp_port_pool, bus, kill_switch)| {
                     tokio::spawn(async move {
                         tunnel::remote_port_forward(
-                            pf,
+                            id,
+                            pf.clone(),
                             tcp_port_pool,
                             udp_port_pool,
                             wg,
@@ -211,26 +1216,237 @@ pub async fn start(config: Config) {
                 },
             );
     }
-    println!("Survived start");
 
-    futures::future::pending().await
-}
+    {
+        // Keep the data path running until the tunnel is killed
+        let mut kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            kill_switch.recv().await.ok();
+            drop(data_path);
+        });
+    }
 
-pub fn blocking_start(config: Config) -> anyhow::Result<(), anyhow::Error> {
-    let rt = runtime::Builder::new_multi_thread().enable_all().build()?;
-    std::thread::spawn(move || {
-        rt.block_on(async {
-            start(config).await;
+    {
+        // The local port forwards listen by now: the tunnel is ready after the first handshake, which
+        // replayed captures don't wait for
+        let mut kill_switch = handle.get_killer();
+        tokio::spawn(async move {
+            let handshake = async {
+                while live {
+                    if let Event::HandshakeCompleted = ready_endpoint.recv().await {
+                        break;
+                    }
+                }
+            };
+            tokio::select! {
+                _ = handshake => {
+                    debug!("[tunnel {}] Tunnel ready", id);
+                    ready.send(true).ok();
+                }
+                _ = kill_switch.recv() => {}
+            }
         });
-    });
+    }
+
+    handle.startup_report.endpoint = wg.endpoint();
+    handle.startup_report.local_endpoint = wg.local_addr().ok();
+    handle.startup_report.listeners = handle.listeners.clone();
+    handle.audit_log = audit_log;
+    Ok(handle)
+}
+
+/// Listens on the source address of the local port forward. Returns the listener, with the addresses it
+/// listens on.
+async fn bind_forward(
+    id: &ForwardId,
+    pf: &PortForwardConfig,
+    policy: BindPolicy,
+) -> Result<(tunnel::Listener, Vec<SocketAddr>), OnetunError> {
+    let listener = tunnel::bind(id, pf, policy).await?;
+    let addrs = listener
+        .local_addrs()
+        .map_err(|source| OnetunError::BindFailed {
+            addr: pf.source,
+            source,
+        })?;
+    Ok((listener, addrs))
+}
+
+/// Initiates a handshake with the WireGuard endpoint and waits for it to complete.
+async fn wait_for_handshake(
+    wg: &WireGuardTunnel,
+    endpoint: &mut BusEndpoint,
+    timeout: Duration,
+) -> Result<(), OnetunError> {
+    info!(
+        "Waiting for handshake with WireGuard endpoint [{}]",
+        wg.endpoint()
+    );
+    wg.initiate_handshake().await?;
+
+    let handshake = async {
+        loop {
+            if let Event::HandshakeCompleted = endpoint.recv().await {
+                return;
+            }
+        }
+    };
+    tokio::time::timeout(timeout, handshake)
+        .await
+        .map_err(|_| OnetunError::HandshakeTimeout)
+}
 
-    Ok(())
+/// Starts the tunnel on the runtime shared by the tunnels of the process, and returns once it is ready.
+/// It can be called concurrently, from threads outside of an async context, to run independent tunnels
+/// with different configurations; each one is controlled by its own `Handle`.
+pub fn blocking_start(config: Config) -> Result<Handle, OnetunError> {
+    shared_runtime(&config)?.block_on(start(config))
 }
 
-fn init_logger(config: &Config) -> anyhow::Result<()> {
+/// Builds a runtime sized by the configuration (see `Config::with_runtime_workers`), like the one of the
+/// onetun binary.
+pub fn build_runtime(config: &Config) -> Result<runtime::Runtime, OnetunError> {
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder.enable_all().thread_name("onetun");
+    if let Some(workers) = config.runtime_workers {
+        builder.worker_threads(workers);
+    }
+    if let Some(threads) = config.blocking_threads {
+        builder.max_blocking_threads(threads);
+    }
+    if !config.cpu_affinity.is_empty() {
+        let cores = config.cpu_affinity.clone();
+        builder.on_thread_start(move || data_path::apply_cpu_affinity(&cores));
+    }
+    builder.build().map_err(OnetunError::Runtime)
+}
+
+/// The runtime shared by the tunnels started with `blocking_start`, created on first use with the
+/// configuration of the first tunnel.
+fn shared_runtime(config: &Config) -> Result<&'static runtime::Runtime, OnetunError> {
+    if let Some(rt) = SHARED_RUNTIME.get() {
+        return Ok(rt);
+    }
+    let rt = build_runtime(config)?;
+    // Another thread may have created the runtime meanwhile, in which case this one is dropped
+    Ok(SHARED_RUNTIME.get_or_init(|| rt))
+}
+
+/// Installs the logger of the process, unless the application installed its own, in which case onetun
+/// logs through it. The logger is shared by the tunnels, so only the log filter of the first one applies.
+fn init_logger(config: &Config) {
+    if config.skip_logger_init {
+        return;
+    }
     let mut builder = pretty_env_logger::formatted_timed_builder();
     builder.parse_filters(&config.log);
-    builder
-        .try_init()
-        .with_context(|| "Failed to initialize logger")
+    if builder.try_init().is_ok() {
+        LOG_FILTER.set(config.log.clone()).ok();
+        return;
+    }
+    match LOG_FILTER.get() {
+        Some(filter) if *filter != config.log => warn!(
+            "Ignoring log filter '{}': the logger is shared with a tunnel using '{}'",
+            config.log, filter
+        ),
+        Some(_) => {}
+        None => debug!("Logging through the logger installed by the application"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BindPolicy;
+
+    fn config(source_peer_ip: &str, port_forward: &str) -> Config {
+        Config::builder()
+            .private_key("52fSYali/Gicn3ZcMmS8Wtz2Rsdh7A3byO4gwi7Lc4I=")
+            .endpoint_public_key("0JSV/PhWC6sd9tl/KHlJk8gTLvf+zQul7oSrjSwRxRQ=")
+            .endpoint_addr("127.0.0.1:51820".parse().unwrap())
+            .source_peer_ip(source_peer_ip.parse().unwrap())
+            .port_forwards(PortForwardConfig::from_notation(port_forward, "127.0.0.1").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_concurrent_tunnels() {
+        let starts: Vec<_> = ["192.168.4.3", "192.168.5.3"]
+            .iter()
+            .map(|ip| {
+                let config = config(ip, "0:192.168.4.1:80");
+                std::thread::spawn(move || blocking_start(config))
+            })
+            .collect();
+        let handles: Vec<Handle> = starts
+            .into_iter()
+            .map(|start| start.join().unwrap().expect("Failed to start tunnel"))
+            .collect();
+        assert_ne!(handles[0].id(), handles[1].id());
+        assert_ne!(handles[0].listeners()[0].1.port(), 0);
+
+        // Killing a tunnel leaves the other one running
+        handles[0].kill();
+        assert!(handles[1].kill_switch.receiver_count() > 0);
+        handles[1].kill();
+    }
+
+    #[test]
+    fn test_bind_policy() {
+        let busy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = busy.local_addr().unwrap().port();
+        let config = || config("192.168.4.3", &format!("{}:192.168.4.1:80", port));
+
+        match blocking_start(config()) {
+            Err(OnetunError::BindFailed { addr, .. }) => assert_eq!(addr.port(), port),
+            other => panic!("Unexpected result: {:?}", other.map(|handle| handle.id())),
+        }
+
+        let handle = blocking_start(config().with_bind_policy(BindPolicy::Ephemeral)).unwrap();
+        let listening = handle.listeners()[0].1;
+        assert_ne!(listening.port(), port);
+        assert_eq!(listening.ip(), busy.local_addr().unwrap().ip());
+        handle.kill();
+
+        // The port is released while retrying
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            drop(busy);
+        });
+        let handle =
+            blocking_start(config().with_bind_policy(BindPolicy::Retry(Duration::from_secs(5))))
+                .unwrap();
+        assert_eq!(handle.listeners()[0].1.port(), port);
+        handle.kill();
+        release.join().unwrap();
+    }
+    #[test]
+    fn test_ready() {
+        let handle = blocking_start(config("192.168.4.3", "0:192.168.4.1:80")).unwrap();
+        let runtime = SHARED_RUNTIME.get().unwrap();
+
+        // No endpoint completes the handshake
+        let ready = runtime.block_on(tokio::time::timeout(
+            Duration::from_millis(200),
+            handle.ready(),
+        ));
+        assert!(ready.is_err());
+
+        let ready = handle.ready();
+        handle.kill();
+        assert!(matches!(runtime.block_on(ready), Err(OnetunError::Killed)));
+    }
+
+    #[test]
+    fn test_startup_report() {
+        let handle = blocking_start(config("192.168.4.3", "0:192.168.4.1:80")).unwrap();
+        let report = handle.startup_report();
+        assert_eq!(report.endpoint, handle.current_endpoint());
+        assert_ne!(report.local_endpoint.unwrap().port(), 0);
+        assert_eq!(report.listeners, handle.listeners());
+        assert_ne!(report.listeners[0].1.port(), 0);
+        assert!(report.ipv4 && !report.ipv6);
+        handle.kill();
+    }
 }
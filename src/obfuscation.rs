@@ -0,0 +1,308 @@
+//! Obfuscation of the datagrams exchanged with the WireGuard endpoint.
+//!
+//! Some networks fingerprint and drop WireGuard traffic. An `Obfuscator` transforms every datagram
+//! between boringtun and the UDP socket, so that it no longer looks like WireGuard on the wire.
+//! The endpoint must apply the reverse transformation, typically with a proxy in front of it.
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use anyhow::Context;
+use rand::Rng;
+
+/// Transforms the datagrams sent to and received from the WireGuard endpoint.
+pub trait Obfuscator: Debug + Send + Sync {
+    /// Transforms a datagram before it is sent to the endpoint.
+    fn obfuscate(&self, datagram: &[u8]) -> Vec<u8>;
+
+    /// Reverses the transformation of a datagram received from the endpoint.
+    /// Returns `None` if the datagram is malformed, in which case it is dropped.
+    fn deobfuscate(&self, datagram: &[u8]) -> Option<Vec<u8>>;
+
+    /// Extra datagrams to send before the given one, such as junk packets. None by default.
+    fn junk(&self, _datagram: &[u8]) -> Vec<Vec<u8>> {
+        vec![]
+    }
+}
+
+/// XORs the datagrams with a repeating key, after appending up to `max_padding` random bytes
+/// so that their sizes don't match WireGuard messages.
+///
+/// On the wire, a datagram is `XOR(payload || padding || padding length as one byte)`.
+#[derive(Debug, Clone)]
+pub struct XorObfuscator {
+    key: Vec<u8>,
+    max_padding: u8,
+}
+
+impl XorObfuscator {
+    /// Creates an obfuscator with the given key, which must not be empty.
+    pub fn new(key: impl Into<Vec<u8>>, max_padding: u8) -> anyhow::Result<Self> {
+        let key = key.into();
+        if key.is_empty() {
+            return Err(anyhow::anyhow!("Obfuscation key must not be empty"));
+        }
+        Ok(Self { key, max_padding })
+    }
+
+    fn xor(&self, data: &mut [u8]) {
+        for (byte, key) in data.iter_mut().zip(self.key.iter().cycle()) {
+            *byte ^= key;
+        }
+    }
+}
+
+impl Obfuscator for XorObfuscator {
+    fn obfuscate(&self, datagram: &[u8]) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let padding = rng.gen_range(0..=self.max_padding);
+        let mut obfuscated = Vec::with_capacity(datagram.len() + padding as usize + 1);
+        obfuscated.extend_from_slice(datagram);
+        obfuscated.extend((0..padding).map(|_| rng.gen::<u8>()));
+        obfuscated.push(padding);
+        self.xor(&mut obfuscated);
+        obfuscated
+    }
+
+    fn deobfuscate(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let mut deobfuscated = datagram.to_vec();
+        self.xor(&mut deobfuscated);
+        let padding = *deobfuscated.last()? as usize;
+        let size = deobfuscated.len().checked_sub(padding + 1)?;
+        deobfuscated.truncate(size);
+        Some(deobfuscated)
+    }
+}
+
+/// The sizes of the WireGuard messages, by type.
+const HANDSHAKE_INIT_SIZE: usize = 148;
+const HANDSHAKE_RESPONSE_SIZE: usize = 92;
+const COOKIE_REPLY_SIZE: usize = 64;
+const MIN_DATA_SIZE: usize = 32;
+
+/// The largest junk packet or handshake prefix accepted by AmneziaWG.
+const MAX_AMNEZIA_JUNK: usize = 1280;
+
+/// The obfuscation of AmneziaWG servers: junk packets sent before each handshake initiation, random
+/// prefixes on the handshake messages, and custom message type values.
+///
+/// Parsed from the parameters of the AmneziaWG peer configuration, as `jc=4,jmin=40,jmax=70,s1=0,s2=0,h1=1,h2=2,h3=3,h4=4`.
+/// Missing parameters keep the values of standard WireGuard.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AmneziaObfuscator {
+    /// How many junk packets to send before each handshake initiation (`Jc`).
+    pub junk_count: usize,
+    /// The size range of the junk packets (`Jmin` and `Jmax`).
+    pub junk_min: usize,
+    pub junk_max: usize,
+    /// The size of the random prefix of handshake initiations (`S1`) and responses (`S2`).
+    pub init_prefix: usize,
+    pub response_prefix: usize,
+    /// The message type values of handshake initiations, handshake responses, cookie replies and
+    /// transport data (`H1` to `H4`).
+    pub headers: [u32; 4],
+}
+
+impl Default for AmneziaObfuscator {
+    fn default() -> Self {
+        Self {
+            junk_count: 0,
+            junk_min: 0,
+            junk_max: 0,
+            init_prefix: 0,
+            response_prefix: 0,
+            headers: [1, 2, 3, 4],
+        }
+    }
+}
+
+impl AmneziaObfuscator {
+    /// Checks the parameters like AmneziaWG does.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.junk_min > self.junk_max {
+            return Err(anyhow::anyhow!("jmin must not be greater than jmax"));
+        }
+        if self.junk_max > MAX_AMNEZIA_JUNK {
+            return Err(anyhow::anyhow!(
+                "jmax must not be greater than {}",
+                MAX_AMNEZIA_JUNK
+            ));
+        }
+        if self.init_prefix > MAX_AMNEZIA_JUNK - HANDSHAKE_INIT_SIZE
+            || self.response_prefix > MAX_AMNEZIA_JUNK - HANDSHAKE_RESPONSE_SIZE
+        {
+            return Err(anyhow::anyhow!("s1 or s2 is too large"));
+        }
+        // Otherwise, handshake initiations and responses can't be told apart by their size
+        if self.init_prefix + HANDSHAKE_INIT_SIZE == self.response_prefix + HANDSHAKE_RESPONSE_SIZE
+        {
+            return Err(anyhow::anyhow!("s2 must not be equal to s1 + 56"));
+        }
+        if self.headers.iter().collect::<HashSet<_>>().len() != self.headers.len() {
+            return Err(anyhow::anyhow!("h1, h2, h3 and h4 must be different"));
+        }
+        Ok(())
+    }
+
+    /// The type of a WireGuard message, from its first 4 bytes.
+    fn message_type(datagram: &[u8]) -> Option<u32> {
+        let header: [u8; 4] = datagram.get(..4)?.try_into().ok()?;
+        Some(u32::from_le_bytes(header))
+    }
+
+    /// Restores the standard type of a message found at the given offset.
+    fn restore(datagram: &[u8], offset: usize, message_type: u32) -> Vec<u8> {
+        let mut message = datagram[offset..].to_vec();
+        message[..4].copy_from_slice(&message_type.to_le_bytes());
+        message
+    }
+}
+
+impl FromStr for AmneziaObfuscator {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut obfuscator = Self::default();
+        for param in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = param
+                .split_once('=')
+                .with_context(|| format!("Invalid AmneziaWG parameter: {}", param))?;
+            let key = key.trim().to_lowercase();
+            let value = value.trim();
+            let invalid = || format!("Invalid value for AmneziaWG parameter {}: {}", key, value);
+            match key.as_str() {
+                "jc" => obfuscator.junk_count = value.parse().with_context(invalid)?,
+                "jmin" => obfuscator.junk_min = value.parse().with_context(invalid)?,
+                "jmax" => obfuscator.junk_max = value.parse().with_context(invalid)?,
+                "s1" => obfuscator.init_prefix = value.parse().with_context(invalid)?,
+                "s2" => obfuscator.response_prefix = value.parse().with_context(invalid)?,
+                "h1" => obfuscator.headers[0] = value.parse().with_context(invalid)?,
+                "h2" => obfuscator.headers[1] = value.parse().with_context(invalid)?,
+                "h3" => obfuscator.headers[2] = value.parse().with_context(invalid)?,
+                "h4" => obfuscator.headers[3] = value.parse().with_context(invalid)?,
+                _ => return Err(anyhow::anyhow!("Unknown AmneziaWG parameter: {}", key)),
+            }
+        }
+        obfuscator.validate()?;
+        Ok(obfuscator)
+    }
+}
+
+impl Obfuscator for AmneziaObfuscator {
+    fn obfuscate(&self, datagram: &[u8]) -> Vec<u8> {
+        let (prefix, header) = match Self::message_type(datagram) {
+            Some(1) if datagram.len() == HANDSHAKE_INIT_SIZE => (self.init_prefix, self.headers[0]),
+            Some(2) if datagram.len() == HANDSHAKE_RESPONSE_SIZE => {
+                (self.response_prefix, self.headers[1])
+            }
+            Some(3) if datagram.len() == COOKIE_REPLY_SIZE => (0, self.headers[2]),
+            Some(4) if datagram.len() >= MIN_DATA_SIZE => (0, self.headers[3]),
+            _ => return datagram.to_vec(),
+        };
+        let mut rng = rand::thread_rng();
+        let mut obfuscated = Vec::with_capacity(prefix + datagram.len());
+        obfuscated.extend((0..prefix).map(|_| rng.gen::<u8>()));
+        obfuscated.extend_from_slice(&header.to_le_bytes());
+        obfuscated.extend_from_slice(&datagram[4..]);
+        obfuscated
+    }
+
+    fn deobfuscate(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let header_at = |offset: usize| Self::message_type(datagram.get(offset..)?);
+        if datagram.len() == self.init_prefix + HANDSHAKE_INIT_SIZE
+            && header_at(self.init_prefix) == Some(self.headers[0])
+        {
+            Some(Self::restore(datagram, self.init_prefix, 1))
+        } else if datagram.len() == self.response_prefix + HANDSHAKE_RESPONSE_SIZE
+            && header_at(self.response_prefix) == Some(self.headers[1])
+        {
+            Some(Self::restore(datagram, self.response_prefix, 2))
+        } else if datagram.len() == COOKIE_REPLY_SIZE && header_at(0) == Some(self.headers[2]) {
+            Some(Self::restore(datagram, 0, 3))
+        } else if datagram.len() >= MIN_DATA_SIZE && header_at(0) == Some(self.headers[3]) {
+            Some(Self::restore(datagram, 0, 4))
+        } else {
+            None
+        }
+    }
+
+    fn junk(&self, datagram: &[u8]) -> Vec<Vec<u8>> {
+        if Self::message_type(datagram) != Some(1) {
+            return vec![];
+        }
+        let mut rng = rand::thread_rng();
+        (0..self.junk_count)
+            .map(|_| {
+                let size = rng.gen_range(self.junk_min..=self.junk_max);
+                (0..size).map(|_| rng.gen::<u8>()).collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that obfuscated datagrams are restored, and that malformed ones are rejected.
+    #[test]
+    fn test_xor_obfuscator() {
+        let obfuscator = XorObfuscator::new("secret", 16).unwrap();
+        let datagram: Vec<u8> = (0..148u8).collect();
+        for _ in 0..10 {
+            let obfuscated = obfuscator.obfuscate(&datagram);
+            assert!(obfuscated.len() > datagram.len());
+            assert_ne!(&obfuscated[..datagram.len()], &datagram[..]);
+            assert_eq!(obfuscator.deobfuscate(&obfuscated), Some(datagram.clone()));
+        }
+
+        assert_eq!(obfuscator.deobfuscate(&[]), None);
+        // The padding length is larger than the datagram
+        let mut malformed = vec![0, 0, 10];
+        obfuscator.xor(&mut malformed);
+        assert_eq!(obfuscator.deobfuscate(&malformed), None);
+
+        assert!(XorObfuscator::new("", 0).is_err());
+    }
+
+    /// Tests the AmneziaWG transformation of each WireGuard message type.
+    #[test]
+    fn test_amnezia_obfuscator() {
+        let obfuscator: AmneziaObfuscator =
+            "jc=3, jmin=10, jmax=20, s1=15, s2=18, h1=1020325451, h2=3288052141, h3=1766607858, h4=2528465083"
+                .parse()
+                .unwrap();
+
+        let mut init = vec![0u8; HANDSHAKE_INIT_SIZE];
+        init[0] = 1;
+        let obfuscated = obfuscator.obfuscate(&init);
+        assert_eq!(obfuscated.len(), 15 + HANDSHAKE_INIT_SIZE);
+        assert_eq!(&obfuscated[15..19], &1020325451u32.to_le_bytes());
+        assert_eq!(obfuscator.deobfuscate(&obfuscated), Some(init.clone()));
+
+        let junk = obfuscator.junk(&init);
+        assert_eq!(junk.len(), 3);
+        assert!(junk.iter().all(|j| (10..=20).contains(&j.len())));
+
+        let mut data = vec![0u8; 100];
+        data[0] = 4;
+        assert!(obfuscator.junk(&data).is_empty());
+        let obfuscated = obfuscator.obfuscate(&data);
+        assert_eq!(&obfuscated[..4], &2528465083u32.to_le_bytes());
+        assert_eq!(obfuscator.deobfuscate(&obfuscated), Some(data.clone()));
+
+        // Standard WireGuard messages are not accepted
+        assert_eq!(obfuscator.deobfuscate(&data), None);
+
+        assert!("jmin=20,jmax=10".parse::<AmneziaObfuscator>().is_err());
+        assert!("s1=0,s2=56".parse::<AmneziaObfuscator>().is_err());
+        assert!("h1=5,h2=5".parse::<AmneziaObfuscator>().is_err());
+        assert!("foo=1".parse::<AmneziaObfuscator>().is_err());
+        assert_eq!(
+            "".parse::<AmneziaObfuscator>().unwrap(),
+            AmneziaObfuscator::default()
+        );
+    }
+}
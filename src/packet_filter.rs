@@ -0,0 +1,98 @@
+//! Hooks to inspect, rewrite or drop the IP packets going through the virtual interfaces.
+//!
+//! A `PacketFilter` registered with `Config::with_packet_filter` sees every IP packet exchanged
+//! between the virtual interfaces and the WireGuard tunnel, which enables ad-blocking, policy
+//! enforcement or debugging without changing onetun itself.
+
+use std::fmt::{Debug, Display, Formatter};
+
+/// The direction of an IP packet going through a virtual interface.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum PacketDirection {
+    /// Received from the WireGuard tunnel, going to the virtual interface.
+    Inbound,
+    /// Crafted by the virtual interface, going to the WireGuard tunnel.
+    Outbound,
+}
+
+impl Display for PacketDirection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Inbound => "inbound",
+                Self::Outbound => "outbound",
+            }
+        )
+    }
+}
+
+/// Inspects the IP packets going through the virtual interfaces.
+pub trait PacketFilter: Debug + Send + Sync {
+    /// Called with each IP packet, from the virtual interface's task, so it must not block.
+    /// Returns the packet to pass on, possibly rewritten, or `None` to drop it.
+    fn filter(&self, direction: PacketDirection, packet: Vec<u8>) -> Option<Vec<u8>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortProtocol;
+    use crate::events::{Bus, Event};
+    use crate::virtual_device::VirtualIpDevice;
+    use smoltcp::phy::{Device, RxToken, TxToken};
+    use smoltcp::time::Instant;
+    use std::sync::Arc;
+
+    /// Drops empty packets, and reverses the others.
+    #[derive(Debug)]
+    struct ReverseFilter;
+
+    impl PacketFilter for ReverseFilter {
+        fn filter(&self, _direction: PacketDirection, mut packet: Vec<u8>) -> Option<Vec<u8>> {
+            packet.reverse();
+            Some(packet).filter(|packet| !packet.is_empty())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_virtual_device_filter() {
+        let bus = Bus::default();
+        let mut endpoint = bus.new_endpoint();
+        let mut device = VirtualIpDevice::new(PortProtocol::Tcp, bus.clone(), 1420)
+            .with_packet_filter(Arc::new(ReverseFilter));
+
+        endpoint.send(Event::InboundInternetPacket(PortProtocol::Tcp, vec![]));
+        endpoint.send(Event::InboundInternetPacket(
+            PortProtocol::Tcp,
+            vec![1, 2, 3],
+        ));
+        let mut fed = 0;
+        while fed < 2 {
+            if let Event::VirtualDeviceFed(_) = endpoint.recv().await {
+                fed += 1;
+            }
+        }
+
+        let (rx, _) = device.receive().expect("Missing inbound packet");
+        let inbound = rx
+            .consume(Instant::now(), |buffer| Ok(buffer.to_vec()))
+            .unwrap();
+        assert_eq!(inbound, vec![3, 2, 1]);
+        assert!(device.receive().is_none());
+
+        let tx = device.transmit().unwrap();
+        tx.consume(Instant::now(), 2, |buffer| {
+            buffer.copy_from_slice(&[4, 5]);
+            Ok(())
+        })
+        .unwrap();
+        loop {
+            if let Event::OutboundInternetPacket(packet) = endpoint.recv().await {
+                assert_eq!(packet, vec![5, 4]);
+                break;
+            }
+        }
+    }
+}
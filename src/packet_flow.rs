@@ -0,0 +1,67 @@
+//! Bridges the WireGuard tunnel to the embedder's own packet flow, such as an iOS `NEPacketTunnelFlow`.
+//!
+//! The embedder writes the IP packets to send through the tunnel with `Handle::write_ip_packet`, and
+//! receives the IP packets from the tunnel on the callback registered with `Handle::set_packet_callback`.
+
+use std::sync::{Arc, RwLock};
+
+use crate::events::{Bus, Event};
+
+/// A function receiving the IP packets from the tunnel.
+pub type PacketCallback = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+/// The callback currently registered, shared between the `Handle` and the delivery task.
+pub(crate) type SharedPacketCallback = Arc<RwLock<Option<PacketCallback>>>;
+
+/// Passes the IP packets received from the tunnel to the registered callback, until the tunnel is killed.
+/// Packets received while no callback is registered are dropped.
+pub(crate) async fn deliver(
+    callback: SharedPacketCallback,
+    bus: Bus,
+    mut kill_switch: tokio::sync::broadcast::Receiver<()>,
+) {
+    let mut endpoint = bus.new_endpoint();
+    loop {
+        tokio::select! {
+            event = endpoint.recv() => {
+                if let Event::InboundTunPacket(packet) = event {
+                    match callback.read().unwrap().as_ref() {
+                        Some(callback) => callback(&packet),
+                        None => debug!("Dropped IP packet of {} bytes: no packet callback registered", packet.len()),
+                    }
+                }
+            }
+            _ = kill_switch.recv() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Tests that the packets from the tunnel reach the registered callback.
+    #[tokio::test]
+    async fn test_deliver() {
+        let bus = Bus::default();
+        let endpoint = bus.new_endpoint();
+        let (kill, kill_switch) = tokio::sync::broadcast::channel(1);
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let callback: SharedPacketCallback =
+            Arc::new(RwLock::new(Some(Box::new(move |packet: &[u8]| {
+                tx.send(packet.to_vec()).unwrap()
+            }))));
+        let delivery = tokio::spawn(deliver(callback, bus, kill_switch));
+        tokio::task::yield_now().await;
+
+        endpoint.send(Event::InboundTunPacket(vec![1, 2, 3]));
+        let packet = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap();
+        assert_eq!(packet, Some(vec![1, 2, 3]));
+
+        kill.send(()).unwrap();
+        delivery.await.unwrap();
+    }
+}
@@ -0,0 +1,581 @@
+pub mod replay;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::config::{PortForwardConfig, PortProtocol};
+use crate::events::{BusEndpoint, Event};
+use crate::packet_filter::PacketDirection;
+use crate::virtual_iface::VirtualPort;
+use crate::Bus;
+use anyhow::Context;
+use futures::Stream;
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket};
+use tokio::fs::File;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::broadcast;
+
+struct Pcap {
+    writer: BufWriter<File>,
+}
+
+/// libpcap file writer
+/// This is mostly taken from `smoltcp`, but rewritten to be async.
+impl Pcap {
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer
+            .flush()
+            .await
+            .with_context(|| "Failed to flush pcap writer")
+    }
+
+    async fn write(&mut self, data: &[u8]) -> anyhow::Result<usize> {
+        self.writer
+            .write(data)
+            .await
+            .with_context(|| format!("Failed to write {} bytes to pcap writer", data.len()))
+    }
+
+    async fn write_u16(&mut self, value: u16) -> anyhow::Result<()> {
+        self.writer
+            .write_u16(value)
+            .await
+            .with_context(|| "Failed to write u16 to pcap writer")
+    }
+
+    async fn write_u32(&mut self, value: u32) -> anyhow::Result<()> {
+        self.writer
+            .write_u32(value)
+            .await
+            .with_context(|| "Failed to write u32 to pcap writer")
+    }
+
+    async fn global_header(&mut self) -> anyhow::Result<()> {
+        self.write_u32(0xa1b2c3d4).await?; // magic number
+        self.write_u16(2).await?; // major version
+        self.write_u16(4).await?; // minor version
+        self.write_u32(0).await?; // timezone (= UTC)
+        self.write_u32(0).await?; // accuracy (not used)
+        self.write_u32(65535).await?; // maximum packet length
+        self.write_u32(101).await?; // link-layer header type (101 = IP)
+        self.flush().await
+    }
+
+    async fn packet_header(&mut self, timestamp: Instant, length: usize) -> anyhow::Result<()> {
+        assert!(length <= 65535);
+
+        self.write_u32(timestamp.secs() as u32).await?; // timestamp seconds
+        self.write_u32(timestamp.micros() as u32).await?; // timestamp microseconds
+        self.write_u32(length as u32).await?; // captured length
+        self.write_u32(length as u32).await?; // original length
+        Ok(())
+    }
+
+    async fn packet(&mut self, timestamp: Instant, packet: &[u8]) -> anyhow::Result<()> {
+        self.packet_header(timestamp, packet.len())
+            .await
+            .with_context(|| "Failed to write packet header to pcap writer")?;
+        self.write(packet)
+            .await
+            .with_context(|| "Failed to write packet to pcap writer")?;
+        self.writer
+            .flush()
+            .await
+            .with_context(|| "Failed to flush pcap writer")?;
+        self.flush().await
+    }
+}
+
+/// Sidecar index of a capture, attributing the captured traffic to port forwards.
+///
+/// Each line describes a session, tab-separated: the virtual port, the port forward,
+/// the first and last activity timestamps (same clock as the capture), and the bytes sent and received.
+struct PcapIndex {
+    writer: BufWriter<File>,
+    sessions: HashMap<VirtualPort, IndexedSession>,
+}
+
+struct IndexedSession {
+    port_forward: Arc<PortForwardConfig>,
+    first_seen: Instant,
+    last_seen: Instant,
+    tx: usize,
+    rx: usize,
+}
+
+impl PcapIndex {
+    async fn create(path: &str) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .await
+            .with_context(|| "Failed to create pcap index file")?;
+        let mut index = Self {
+            writer: BufWriter::new(file),
+            sessions: HashMap::new(),
+        };
+        index
+            .writer
+            .write_all(b"# virtual_port\tport_forward\tstart\tend\ttx_bytes\trx_bytes\n")
+            .await
+            .with_context(|| "Failed to write pcap index header")?;
+        index.writer.flush().await?;
+        Ok(index)
+    }
+
+    /// Records the traffic of a session on the bus. Sessions are written to the index when they close.
+    async fn record(&mut self, event: &Event) -> anyhow::Result<()> {
+        let now = Instant::now();
+        match event {
+            Event::ClientConnectionInitiated(pf, vp, _) => {
+                self.session(pf.clone(), *vp, now);
+            }
+            Event::LocalData(pf, vp, data, _) => {
+                self.session(pf.clone(), *vp, now).tx += data.len();
+            }
+            Event::RemoteData(vp, data, _) | Event::RemotePeerData(vp, _, data, _) => {
+                if let Some(session) = self.sessions.get_mut(vp) {
+                    session.last_seen = now;
+                    session.rx += data.len();
+                }
+            }
+            Event::ClientConnectionDropped(vp, ..) => {
+                if let Some(session) = self.sessions.remove(vp) {
+                    self.write(*vp, &session).await?;
+                    self.writer.flush().await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn session(
+        &mut self,
+        port_forward: Arc<PortForwardConfig>,
+        virtual_port: VirtualPort,
+        now: Instant,
+    ) -> &mut IndexedSession {
+        let session = self
+            .sessions
+            .entry(virtual_port)
+            .or_insert_with(|| IndexedSession {
+                port_forward,
+                first_seen: now,
+                last_seen: now,
+                tx: 0,
+                rx: 0,
+            });
+        session.last_seen = now;
+        session
+    }
+
+    async fn write(
+        &mut self,
+        virtual_port: VirtualPort,
+        session: &IndexedSession,
+    ) -> anyhow::Result<()> {
+        let line = format!(
+            "{}:{}\t{}\t{}.{:06}\t{}.{:06}\t{}\t{}\n",
+            virtual_port.num(),
+            virtual_port.proto(),
+            session.port_forward,
+            session.first_seen.secs(),
+            session.first_seen.micros(),
+            session.last_seen.secs(),
+            session.last_seen.micros(),
+            session.tx,
+            session.rx
+        );
+        self.writer
+            .write_all(line.as_bytes())
+            .await
+            .with_context(|| "Failed to write to pcap index")
+    }
+
+    /// Writes the sessions that are still open.
+    async fn close(&mut self) -> anyhow::Result<()> {
+        let mut sessions: Vec<_> = self.sessions.drain().collect();
+        sessions.sort_by_key(|(_, session)| session.first_seen);
+        for (vp, session) in sessions {
+            self.write(vp, &session).await?;
+        }
+        self.writer
+            .flush()
+            .await
+            .with_context(|| "Failed to flush pcap index")
+    }
+}
+
+/// Listens on the event bus for IP packets sent from and to the WireGuard tunnel.
+pub async fn capture(
+    pcap_file: String,
+    pcap_index_file: Option<String>,
+    bus: Bus,
+    mut kill_switch: broadcast::Receiver<()>,
+) -> anyhow::Result<()> {
+    let mut endpoint = bus.new_endpoint();
+    let file = File::create(&pcap_file)
+        .await
+        .with_context(|| "Failed to create pcap file")?;
+    let writer = BufWriter::new(file);
+
+    let mut writer = Pcap { writer };
+    writer
+        .global_header()
+        .await
+        .with_context(|| "Failed to write global header to pcap writer")?;
+
+    let mut index = match pcap_index_file {
+        Some(path) => {
+            let index = PcapIndex::create(&path).await?;
+            info!("Indexing captured sessions to {}", &path);
+            Some(index)
+        }
+        None => None,
+    };
+
+    info!("Capturing WireGuard IP packets to {}", &pcap_file);
+    loop {
+        tokio::select! {
+            event = endpoint.recv() => {
+                if let Some(index) = index.as_mut() {
+                    index.record(&event).await?;
+                }
+                if let Some((_, ip)) = captured_packet(&event) {
+                    writer
+                        .packet(Instant::now(), ip)
+                        .await
+                        .with_context(|| "Failed to write IP packet to pcap writer")?;
+                }
+            }
+            _ = kill_switch.recv() => {
+                if let Some(index) = index.as_mut() {
+                    index.close().await?;
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The IP packet sent to or received from the WireGuard tunnel in the event, if any, with its direction.
+fn captured_packet(event: &Event) -> Option<(PacketDirection, &[u8])> {
+    match event {
+        Event::InboundInternetPacket(_, ip) | Event::InboundTunPacket(ip) => {
+            Some((PacketDirection::Inbound, ip))
+        }
+        Event::OutboundInternetPacket(ip) => Some((PacketDirection::Outbound, ip)),
+        _ => None,
+    }
+}
+
+/// An IP packet sent to or received from the WireGuard tunnel, captured in memory (see `Handle::start_capture`).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CapturedPacket {
+    /// When the packet was captured.
+    pub timestamp: SystemTime,
+    /// Whether the packet was received from the tunnel, or sent to it.
+    pub direction: PacketDirection,
+    /// The IP packet, from its IP header.
+    pub data: Vec<u8>,
+}
+
+/// Which IP packets an in-memory capture keeps: those matching all the criteria given. The default
+/// filter keeps every packet.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct CaptureFilter {
+    /// Only the packets in this direction.
+    pub direction: Option<PacketDirection>,
+    /// Only the packets from or to this IP.
+    pub host: Option<IpAddr>,
+    /// Only the TCP or UDP packets from or to this port.
+    pub port: Option<u16>,
+    /// Only the packets of this transport protocol.
+    pub protocol: Option<PortProtocol>,
+}
+
+impl CaptureFilter {
+    /// Whether the filter keeps the IP packet.
+    pub fn matches(&self, direction: PacketDirection, packet: &[u8]) -> bool {
+        if self.direction.map_or(false, |d| d != direction) {
+            return false;
+        }
+        if self.host.is_none() && self.port.is_none() && self.protocol.is_none() {
+            return true;
+        }
+        let (addresses, protocol, payload) = match ip_header(packet) {
+            Some(header) => header,
+            None => return false,
+        };
+        if self.host.map_or(false, |host| !addresses.contains(&host)) {
+            return false;
+        }
+        let transport = match protocol {
+            IpProtocol::Tcp => PortProtocol::Tcp,
+            IpProtocol::Udp => PortProtocol::Udp,
+            _ => return self.protocol.is_none() && self.port.is_none(),
+        };
+        if self.protocol.map_or(false, |p| p != transport) {
+            return false;
+        }
+        match self.port {
+            Some(port) => {
+                transport_ports(transport, payload).map_or(false, |ports| ports.contains(&port))
+            }
+            None => true,
+        }
+    }
+}
+
+/// The source and destination IPs of an IP packet, its transport protocol and its payload, if it is well-formed.
+fn ip_header(packet: &[u8]) -> Option<([IpAddr; 2], IpProtocol, &[u8])> {
+    match IpVersion::of_packet(packet).ok()? {
+        IpVersion::Ipv4 => {
+            let ip = Ipv4Packet::new_checked(packet).ok()?;
+            let header_len = usize::from(ip.header_len());
+            let total_len = usize::from(ip.total_len());
+            Some((
+                [
+                    Ipv4Addr::from(ip.src_addr()).into(),
+                    Ipv4Addr::from(ip.dst_addr()).into(),
+                ],
+                ip.protocol(),
+                &packet[header_len..total_len],
+            ))
+        }
+        IpVersion::Ipv6 => {
+            let ip = Ipv6Packet::new_checked(packet).ok()?;
+            let payload_len = usize::from(ip.payload_len());
+            Some((
+                [
+                    Ipv6Addr::from(ip.src_addr()).into(),
+                    Ipv6Addr::from(ip.dst_addr()).into(),
+                ],
+                ip.next_header(),
+                &packet[40..40 + payload_len],
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// The source and destination ports of a TCP or UDP payload, if it is well-formed.
+fn transport_ports(protocol: PortProtocol, payload: &[u8]) -> Option<[u16; 2]> {
+    match protocol {
+        PortProtocol::Tcp => {
+            let tcp = TcpPacket::new_checked(payload).ok()?;
+            Some([tcp.src_port(), tcp.dst_port()])
+        }
+        PortProtocol::Udp => {
+            let udp = UdpPacket::new_checked(payload).ok()?;
+            Some([udp.src_port(), udp.dst_port()])
+        }
+    }
+}
+
+/// The IP packets of the tunnel that match the filter, until the tunnel is killed. Packets are skipped when the
+/// stream is not polled fast enough to keep up with the event bus.
+pub(crate) fn capture_stream(
+    filter: CaptureFilter,
+    endpoint: BusEndpoint,
+    kill_switch: broadcast::Receiver<()>,
+) -> impl Stream<Item = CapturedPacket> {
+    futures::stream::unfold(
+        (endpoint, kill_switch, filter),
+        |(mut endpoint, mut kill_switch, filter)| async move {
+            loop {
+                let event = tokio::select! {
+                    event = endpoint.recv() => event,
+                    _ = kill_switch.recv() => return None,
+                };
+                let captured = match captured_packet(&event) {
+                    Some((direction, ip)) if filter.matches(direction, ip) => CapturedPacket {
+                        timestamp: SystemTime::now(),
+                        direction,
+                        data: ip.to_vec(),
+                    },
+                    _ => continue,
+                };
+                return Some((captured, (endpoint, kill_switch, filter)));
+            }
+        },
+    )
+}
+
+/// The last IP packets sent to and received from the WireGuard tunnel, kept in memory so the moments
+/// before a failure can be dumped to a pcap file, without capturing all the traffic.
+pub(crate) struct RecentTraffic {
+    capacity: usize,
+    packets: Mutex<VecDeque<(Instant, Vec<u8>)>>,
+}
+
+impl RecentTraffic {
+    /// Keeps up to the given number of packets.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            packets: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn push(&self, packet: Vec<u8>) {
+        let mut packets = self.packets.lock().unwrap();
+        if packets.len() == self.capacity {
+            packets.pop_front();
+        }
+        packets.push_back((Instant::now(), packet));
+    }
+
+    /// Writes the packets kept so far to a new pcap file, and returns how many they are.
+    pub async fn dump(&self, path: &Path) -> anyhow::Result<usize> {
+        let packets: Vec<(Instant, Vec<u8>)> =
+            self.packets.lock().unwrap().iter().cloned().collect();
+        let file = File::create(path)
+            .await
+            .with_context(|| "Failed to create pcap file")?;
+        let mut writer = Pcap {
+            writer: BufWriter::new(file),
+        };
+        writer
+            .global_header()
+            .await
+            .with_context(|| "Failed to write global header to pcap writer")?;
+        for (instant, packet) in packets.iter() {
+            writer.packet(*instant, packet).await?;
+        }
+        Ok(packets.len())
+    }
+}
+
+/// Keeps the last IP packets sent from and to the WireGuard tunnel, until the tunnel is killed.
+pub(crate) async fn record_recent(
+    recent: Arc<RecentTraffic>,
+    bus: Bus,
+    mut kill_switch: broadcast::Receiver<()>,
+) {
+    let mut endpoint = bus.new_endpoint();
+    loop {
+        tokio::select! {
+            event = endpoint.recv() => {
+                if let Some((_, ip)) = captured_packet(&event) {
+                    recent.push(ip.to_vec());
+                }
+            }
+            _ = kill_switch.recv() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recent_traffic() {
+        let recent = RecentTraffic::new(2);
+        recent.push(vec![1]);
+        recent.push(vec![2, 2]);
+        recent.push(vec![3, 3, 3]);
+
+        let path = std::env::temp_dir().join(format!("onetun-recent-{}.pcap", std::process::id()));
+        assert_eq!(recent.dump(&path).await.unwrap(), 2);
+        let dump = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        // Global header, then the last two packets with their headers
+        assert_eq!(dump.len(), 24 + (16 + 2) + (16 + 3));
+        assert_eq!(&dump[24 + 16..24 + 16 + 2], &[2, 2]);
+        assert_eq!(&dump[dump.len() - 3..], &[3, 3, 3]);
+    }
+
+    fn udp_packet(src_port: u16, dst_port: u16) -> Vec<u8> {
+        use smoltcp::phy::ChecksumCapabilities;
+        use smoltcp::wire::{IpAddress, Ipv4Address, Ipv4Repr, UdpRepr};
+
+        let (src_addr, dst_addr) = (
+            Ipv4Address::new(192, 168, 4, 3),
+            Ipv4Address::new(192, 168, 4, 1),
+        );
+        let udp = UdpRepr { src_port, dst_port };
+        let ip = Ipv4Repr {
+            src_addr,
+            dst_addr,
+            protocol: IpProtocol::Udp,
+            payload_len: udp.header_len() + 4,
+            hop_limit: 64,
+        };
+        let mut buffer = vec![0u8; ip.buffer_len() + ip.payload_len];
+        let mut packet = Ipv4Packet::new_unchecked(&mut buffer);
+        ip.emit(&mut packet, &ChecksumCapabilities::default());
+        udp.emit(
+            &mut UdpPacket::new_unchecked(packet.payload_mut()),
+            &IpAddress::Ipv4(src_addr),
+            &IpAddress::Ipv4(dst_addr),
+            4,
+            |payload| payload.copy_from_slice(b"ping"),
+            &ChecksumCapabilities::default(),
+        );
+        buffer
+    }
+
+    #[test]
+    fn test_capture_filter() {
+        let dns = udp_packet(40000, 53);
+        let inbound = PacketDirection::Inbound;
+        assert!(CaptureFilter::default().matches(inbound, &dns));
+        assert!(CaptureFilter::default().matches(inbound, &[0xff]));
+
+        let filter = CaptureFilter {
+            host: Some("192.168.4.1".parse().unwrap()),
+            port: Some(53),
+            protocol: Some(PortProtocol::Udp),
+            ..Default::default()
+        };
+        assert!(filter.matches(inbound, &dns));
+        assert!(!filter.matches(inbound, &udp_packet(40000, 54)));
+        assert!(!filter.matches(inbound, &[0xff]));
+        for unmatched in [
+            CaptureFilter {
+                direction: Some(PacketDirection::Outbound),
+                ..Default::default()
+            },
+            CaptureFilter {
+                host: Some("192.168.4.2".parse().unwrap()),
+                ..Default::default()
+            },
+            CaptureFilter {
+                protocol: Some(PortProtocol::Tcp),
+                ..Default::default()
+            },
+        ] {
+            assert!(!unmatched.matches(inbound, &dns), "{:?}", unmatched);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_stream() {
+        use futures::StreamExt;
+
+        let bus = Bus::default();
+        let (kill, kill_switch) = broadcast::channel(1);
+        let filter = CaptureFilter {
+            direction: Some(PacketDirection::Outbound),
+            ..Default::default()
+        };
+        let stream = capture_stream(filter, bus.new_endpoint(), kill_switch);
+        futures::pin_mut!(stream);
+
+        let sender = bus.new_endpoint();
+        sender.send(Event::InboundTunPacket(vec![1]));
+        sender.send(Event::OutboundInternetPacket(vec![2]));
+        let captured = stream.next().await.unwrap();
+        assert_eq!(captured.direction, PacketDirection::Outbound);
+        assert_eq!(captured.data, vec![2]);
+
+        kill.send(()).unwrap();
+        assert!(stream.next().await.is_none());
+    }
+}
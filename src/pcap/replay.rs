@@ -0,0 +1,177 @@
+//! Replay of a packet capture into the virtual interfaces (see `Config::with_replay`): the IP packets that the
+//! peer sent are fed to the virtual devices at their original timing, without a live peer, so that the bugs
+//! of the virtual interfaces can be reproduced deterministically.
+
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::sync::broadcast;
+
+use crate::engine::packet::inbound_protocol;
+use crate::events::Event;
+use crate::Bus;
+
+/// The link-layer header types of raw IP packets.
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+/// A packet of a capture, with its time since the first packet.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct CapturedPacket {
+    pub offset: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Reads the packets of a pcap file of raw IP packets, like onetun's captures.
+pub(crate) fn read(capture: &[u8]) -> anyhow::Result<Vec<CapturedPacket>> {
+    let header = capture
+        .get(..24)
+        .with_context(|| "Truncated pcap global header")?;
+    let magic = u32::from_be_bytes(header[..4].try_into().unwrap());
+    // The magic number tells the byte order, and whether the timestamps are in micro or nanoseconds
+    let (big_endian, nanos) = match magic {
+        0xa1b2c3d4 => (true, false),
+        0xd4c3b2a1 => (false, false),
+        0xa1b23c4d => (true, true),
+        0x4d3cb2a1 => (false, true),
+        _ => return Err(anyhow::anyhow!("Not a pcap file (magic {:#x})", magic)),
+    };
+    let u32_at = |data: &[u8], at: usize| {
+        let bytes: [u8; 4] = data[at..at + 4].try_into().unwrap();
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let link_type = u32_at(header, 20);
+    if !matches!(link_type, LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6) {
+        return Err(anyhow::anyhow!(
+            "Unsupported pcap link-layer type {}: only raw IP packets can be replayed",
+            link_type
+        ));
+    }
+
+    let mut packets = Vec::new();
+    let mut first = None;
+    let mut position = 24;
+    while position < capture.len() {
+        let header = capture
+            .get(position..position + 16)
+            .with_context(|| format!("Truncated pcap packet header at byte {}", position))?;
+        let fraction = u32_at(header, 4) as u64;
+        let timestamp = Duration::from_secs(u32_at(header, 0) as u64)
+            + if nanos {
+                Duration::from_nanos(fraction)
+            } else {
+                Duration::from_micros(fraction)
+            };
+        let length = u32_at(header, 8) as usize;
+        position += 16;
+        let data = capture
+            .get(position..position + length)
+            .with_context(|| format!("Truncated pcap packet at byte {}", position))?;
+        position += length;
+
+        let first = *first.get_or_insert(timestamp);
+        packets.push(CapturedPacket {
+            offset: timestamp.saturating_sub(first),
+            data: data.to_vec(),
+        });
+    }
+    Ok(packets)
+}
+
+/// Feeds the packets that the peer sent in the capture to the virtual interfaces, at their original timing.
+pub(crate) async fn replay(
+    path: PathBuf,
+    source_peer_ips: Vec<IpAddr>,
+    bus: Bus,
+    mut kill_switch: broadcast::Receiver<()>,
+) -> anyhow::Result<()> {
+    let capture = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("Failed to read capture {:?}", path))?;
+    let packets = read(&capture).with_context(|| format!("Failed to parse capture {:?}", path))?;
+
+    let endpoint = bus.new_endpoint();
+    let start = tokio::time::Instant::now();
+    let mut replayed = 0;
+    info!(
+        "Replaying {} captured packets from {:?}",
+        packets.len(),
+        path
+    );
+    for packet in packets {
+        // The captures also contain the packets sent to the peer
+        let protocol = match inbound_protocol(&packet.data, &source_peer_ips) {
+            Some(protocol) => protocol,
+            None => continue,
+        };
+        tokio::select! {
+            _ = tokio::time::sleep_until(start + packet.offset) => {}
+            _ = kill_switch.recv() => return Ok(()),
+        }
+        endpoint.send(Event::InboundInternetPacket(protocol, packet.data));
+        replayed += 1;
+    }
+    info!("Replayed {} packets from the peer", replayed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortProtocol;
+    use crate::pcap::RecentTraffic;
+    use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr};
+
+    fn ipv4_packet(src: [u8; 4], dst: [u8; 4], protocol: IpProtocol) -> Vec<u8> {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address(src),
+            dst_addr: Ipv4Address(dst),
+            protocol,
+            payload_len: 0,
+            hop_limit: 64,
+        };
+        let mut packet = vec![0u8; repr.buffer_len()];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut packet),
+            &Default::default(),
+        );
+        packet
+    }
+
+    #[tokio::test]
+    async fn test_read_capture() {
+        let inbound = ipv4_packet([192, 168, 4, 2], [192, 168, 4, 3], IpProtocol::Tcp);
+        let outbound = ipv4_packet([192, 168, 4, 3], [192, 168, 4, 2], IpProtocol::Udp);
+        let recent = RecentTraffic::new(2);
+        recent.push(outbound.clone());
+        recent.push(inbound.clone());
+
+        let path = std::env::temp_dir().join(format!("onetun-replay-{}.pcap", std::process::id()));
+        recent.dump(&path).await.unwrap();
+        let capture = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let packets = read(&capture).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].offset, Duration::ZERO);
+        assert_eq!(packets[1].data, inbound);
+
+        let source_peer_ips = ["192.168.4.3".parse().unwrap()];
+        assert_eq!(inbound_protocol(&outbound, &source_peer_ips), None);
+        assert_eq!(
+            inbound_protocol(&inbound, &source_peer_ips),
+            Some(PortProtocol::Tcp)
+        );
+
+        assert!(read(&capture[..capture.len() - 1]).is_err());
+        assert!(read(&[0u8; 24]).is_err());
+    }
+}
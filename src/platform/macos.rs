@@ -0,0 +1,85 @@
+//! The macOS backend, which reads the settings of the primary network service with `scutil`.
+
+use super::ProxySettings;
+
+#[cfg(target_os = "macos")]
+pub fn proxy_settings() -> ProxySettings {
+    let mut settings = super::command_output("scutil", &["--proxy"])
+        .map(|output| parse_proxy(&output))
+        .unwrap_or_default();
+    settings.vpns = super::command_output("scutil", &["--nc", "list"])
+        .map(|output| parse_vpns(&output))
+        .unwrap_or_default();
+    settings
+}
+
+/// Parses the dictionary printed by `scutil --proxy`.
+fn parse_proxy(output: &str) -> ProxySettings {
+    let value = |key: &str| {
+        output.lines().find_map(|line| {
+            let (k, v) = line.split_once(" : ")?;
+            (k.trim() == key).then(|| v.trim().to_string())
+        })
+    };
+    let enabled = |key: &str| value(key).as_deref() == Some("1");
+    let proxy = |kind: &str| {
+        if !enabled(&format!("{}Enable", kind)) {
+            return None;
+        }
+        let host = value(&format!("{}Proxy", kind))?;
+        let port = value(&format!("{}Port", kind))?;
+        Some(format!("{}:{}", host, port))
+    };
+    ProxySettings {
+        http: proxy("HTTPS").or_else(|| proxy("HTTP")),
+        socks: proxy("SOCKS"),
+        auto_config: enabled("ProxyAutoConfigEnable") || enabled("ProxyAutoDiscoveryEnable"),
+        vpns: vec![],
+    }
+}
+
+/// Parses the connected services listed by `scutil --nc list`, such as
+/// `* (Connected)   6F3A... IPSec   "Office" [IPSec]`.
+fn parse_vpns(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.contains("(Connected)"))
+        .filter_map(|line| line.split('"').nth(1).map(str::to_string))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scutil() {
+        let output = "<dictionary> {\n\
+              ExceptionsList : <array> {\n\
+                0 : *.local\n\
+              }\n\
+              HTTPEnable : 1\n\
+              HTTPPort : 3128\n\
+              HTTPProxy : proxy.example.com\n\
+              SOCKSEnable : 1\n\
+              SOCKSPort : 1080\n\
+              SOCKSProxy : 127.0.0.1\n\
+              ProxyAutoConfigEnable : 0\n\
+            }\n";
+        assert_eq!(
+            parse_proxy(output),
+            ProxySettings {
+                http: Some("proxy.example.com:3128".into()),
+                socks: Some("127.0.0.1:1080".into()),
+                auto_config: false,
+                vpns: vec![],
+            }
+        );
+        assert!(parse_proxy("<dictionary> {\n  HTTPEnable : 0\n}\n").is_empty());
+
+        let output = "Available network connection services in the current set (*=enabled):\n\
+            * (Connected)      6F3A8D1C-1F4B PPP --> L2TP       \"Office\"                    [PPP/L2TP]\n\
+            * (Disconnected)   0B1E22C4-9A7D IPSec              \"Home\"                      [IPSec]\n";
+        assert_eq!(parse_vpns(output), vec!["Office".to_string()]);
+    }
+}
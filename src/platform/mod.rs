@@ -0,0 +1,109 @@
+//! The network settings of the operating system that affect the path to the WireGuard endpoint.
+//!
+//! WireGuard runs over UDP, which HTTP proxies don't carry and which a VPN may route into a tunnel that
+//! drops it. On macOS and Windows, the system proxy and VPN settings are read at startup, with a backend
+//! per OS: the settings that could blackhole the datagrams to the endpoint are reported (see
+//! `diagnostics::system_proxy`), and when a SOCKS proxy is configured, the datagrams are relayed through
+//! it with a UDP association (see `socks`). Elsewhere, no settings are detected.
+//!
+//! On every OS, the route to the endpoint is watched for network changes (see `network`).
+
+use std::net::SocketAddr;
+
+#[cfg(any(target_os = "macos", test))]
+mod macos;
+pub mod network;
+pub mod socks;
+#[cfg(any(target_os = "windows", test))]
+mod windows;
+
+use socks::SocksRelay;
+
+/// The proxy and VPN settings of the operating system.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ProxySettings {
+    /// The HTTP or HTTPS proxy, as `host:port`. It only carries TCP, so the datagrams bypass it.
+    pub http: Option<String>,
+    /// The SOCKS proxy, as `host:port`.
+    pub socks: Option<String>,
+    /// Whether the proxies are picked by an automatic configuration script, which onetun doesn't evaluate.
+    pub auto_config: bool,
+    /// The names of the connected VPN services.
+    pub vpns: Vec<String>,
+}
+
+impl ProxySettings {
+    /// Whether no proxy or VPN is set.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Reads the proxy and VPN settings of the operating system, which runs external commands on macOS
+/// (`scutil`) and Windows (`reg` and `rasdial`). Always empty on the other operating systems.
+pub fn proxy_settings() -> ProxySettings {
+    #[cfg(target_os = "macos")]
+    return macos::proxy_settings();
+    #[cfg(target_os = "windows")]
+    return windows::proxy_settings();
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    ProxySettings::default()
+}
+
+/// Reads the system settings, warns about those that could blackhole the datagrams to the endpoint, and
+/// associates with the SOCKS proxy, if one is set, to relay them. Falls back to sending the datagrams
+/// directly when the proxy refuses.
+pub(crate) async fn endpoint_relay(endpoint: SocketAddr) -> Option<SocksRelay> {
+    let settings = tokio::task::spawn_blocking(proxy_settings)
+        .await
+        .unwrap_or_default();
+    for vpn in &settings.vpns {
+        warn!(
+            "VPN '{}' is connected: if it routes {}, the WireGuard datagrams may be dropped",
+            vpn, endpoint
+        );
+    }
+    let proxy = match &settings.socks {
+        Some(proxy) => proxy,
+        None => {
+            if settings.http.is_some() || settings.auto_config {
+                warn!(
+                    "A system HTTP proxy is set, which doesn't carry UDP: the WireGuard datagrams are sent to {} directly",
+                    endpoint
+                );
+            }
+            return None;
+        }
+    };
+    match socks::associate(proxy).await {
+        Ok(relay) => {
+            info!(
+                "Relaying the WireGuard datagrams through the system SOCKS proxy {} ({})",
+                proxy, relay.relay
+            );
+            Some(relay)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to relay UDP through the system SOCKS proxy {}, sending the WireGuard datagrams to {} directly: {:?}",
+                proxy, endpoint, e
+            );
+            None
+        }
+    }
+}
+
+/// Runs a command, returning its standard output if it succeeded.
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| debug!("Failed to run {}: {:?}", program, e))
+        .ok()?;
+    if !output.status.success() {
+        debug!("{} exited with {}", program, output.status);
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,38 @@
+//! Detects the changes of the network path to the WireGuard endpoint, such as switching from Wi-Fi to
+//! cellular or docking a laptop. Instead of a backend per OS, the routing table is queried the same way
+//! everywhere: a UDP socket connected to the endpoint gets the local address the operating system would send
+//! its datagrams from, without sending any. A new default route or interface changes that address.
+
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// How often the route to the endpoint is looked up. Polling instead of subscribing to route changes is
+/// deliberate, to keep one implementation on every platform: a lookup is a `connect` on a throwaway socket, cheap
+/// enough every 2 seconds, and a path change noticed that late costs less than the handshake it triggers.
+pub const NETWORK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The local address the operating system routes the datagrams to the destination from, or `None` if it has
+/// no route to it, such as while no network is connected.
+pub fn route_source(destination: SocketAddr) -> Option<IpAddr> {
+    let bind_addr: SocketAddr = match destination {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).ok()?;
+    socket.connect(destination).ok()?;
+    let source = socket.local_addr().ok()?.ip();
+    (!source.is_unspecified()).then(|| source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_source() {
+        assert_eq!(
+            route_source("127.0.0.1:51820".parse().unwrap()),
+            Some("127.0.0.1".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! UDP through a SOCKS5 proxy (RFC 1928). The proxy relays the datagrams of a UDP association, which lasts
+//! as long as the TCP connection that requested it. Each datagram carries a header with the address of
+//! the peer: its destination when sent to the relay, its source when received from it.
+
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+/// A UDP association with a SOCKS proxy.
+#[derive(Debug)]
+pub struct SocksRelay {
+    /// The address the proxy relays the datagrams at.
+    pub relay: SocketAddr,
+    /// The connection the association lives with.
+    _control: TcpStream,
+}
+
+/// Requests a UDP association from the proxy at `host:port`, without authentication.
+pub async fn associate(proxy: &str) -> anyhow::Result<SocksRelay> {
+    let mut control = TcpStream::connect(proxy)
+        .await
+        .with_context(|| "Failed to connect to SOCKS proxy")?;
+    let proxy_addr = control.peer_addr()?;
+    control.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+    let mut choice = [0u8; 2];
+    control.read_exact(&mut choice).await?;
+    if choice != [VERSION, NO_AUTHENTICATION] {
+        return Err(anyhow::anyhow!("SOCKS proxy requires authentication"));
+    }
+
+    // The datagrams may come from any address: the one of the socket is only known to the proxy
+    control
+        .write_all(&[VERSION, UDP_ASSOCIATE, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    let mut reply = [0u8; 4];
+    control.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(anyhow::anyhow!(
+            "SOCKS proxy refused the UDP association (reply {})",
+            reply[1]
+        ));
+    }
+    let ip = match reply[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            control.read_exact(&mut ip).await?;
+            IpAddr::from(ip)
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            control.read_exact(&mut ip).await?;
+            IpAddr::from(ip)
+        }
+        other => {
+            return Err(anyhow::anyhow!(
+                "Unsupported relay address type from SOCKS proxy: {}",
+                other
+            ))
+        }
+    };
+    let port = control.read_u16().await?;
+    // An unspecified relay address means the proxy's own
+    let ip = if ip.is_unspecified() {
+        proxy_addr.ip()
+    } else {
+        ip
+    };
+    Ok(SocksRelay {
+        relay: SocketAddr::new(ip, port),
+        _control: control,
+    })
+}
+
+/// Prepends the SOCKS header to a datagram sent to the destination through the relay.
+pub fn encapsulate(datagram: &[u8], destination: SocketAddr) -> Vec<u8> {
+    let mut relayed = Vec::with_capacity(datagram.len() + 22);
+    // Reserved, then the fragment number: datagrams are never fragmented
+    relayed.extend_from_slice(&[0, 0, 0]);
+    match destination.ip() {
+        IpAddr::V4(ip) => {
+            relayed.push(ATYP_IPV4);
+            relayed.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            relayed.push(ATYP_IPV6);
+            relayed.extend_from_slice(&ip.octets());
+        }
+    }
+    relayed.extend_from_slice(&destination.port().to_be_bytes());
+    relayed.extend_from_slice(datagram);
+    relayed
+}
+
+/// Strips the SOCKS header of a datagram received from the relay, returning its source. Fragments and
+/// malformed datagrams are dropped.
+pub fn decapsulate(relayed: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    if relayed.len() < 4 || relayed[2] != 0 {
+        return None;
+    }
+    let (ip, rest): (IpAddr, _) = match relayed[3] {
+        ATYP_IPV4 if relayed.len() >= 10 => {
+            let octets: [u8; 4] = relayed[4..8].try_into().ok()?;
+            (Ipv4Addr::from(octets).into(), &relayed[8..])
+        }
+        ATYP_IPV6 if relayed.len() >= 22 => {
+            let octets: [u8; 16] = relayed[4..20].try_into().ok()?;
+            (Ipv6Addr::from(octets).into(), &relayed[20..])
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([rest[0], rest[1]]);
+    Some((SocketAddr::new(ip, port), &rest[2..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_socks_header() {
+        for destination in ["140.30.3.182:51820", "[2001:db8::1]:51820"] {
+            let destination: SocketAddr = destination.parse().unwrap();
+            let relayed = encapsulate(b"datagram", destination);
+            assert_eq!(
+                decapsulate(&relayed),
+                Some((destination, b"datagram".as_slice()))
+            );
+        }
+        let mut fragment = encapsulate(b"datagram", "140.30.3.182:51820".parse().unwrap());
+        fragment[2] = 1;
+        assert_eq!(decapsulate(&fragment), None);
+        assert_eq!(decapsulate(&[0, 0, 0, ATYP_IPV4, 1]), None);
+    }
+}
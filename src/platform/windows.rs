@@ -0,0 +1,94 @@
+//! The Windows backend, which reads the Internet settings of the user from the registry with `reg`, and
+//! the connected dial-up and VPN connections with `rasdial`.
+
+use super::ProxySettings;
+
+/// The registry key of the proxy settings of the user.
+#[cfg(target_os = "windows")]
+const INTERNET_SETTINGS: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+
+#[cfg(target_os = "windows")]
+pub fn proxy_settings() -> ProxySettings {
+    let mut settings = super::command_output("reg", &["query", INTERNET_SETTINGS])
+        .map(|output| parse_registry(&output))
+        .unwrap_or_default();
+    settings.vpns = super::command_output("rasdial", &[])
+        .map(|output| parse_rasdial(&output))
+        .unwrap_or_default();
+    settings
+}
+
+/// Parses the values printed by `reg query`, such as `    ProxyServer    REG_SZ    socks=127.0.0.1:1080`.
+fn parse_registry(output: &str) -> ProxySettings {
+    let value = |name: &str| {
+        output.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            if fields.next()? != name {
+                return None;
+            }
+            let _kind = fields.next()?;
+            Some(fields.collect::<Vec<_>>().join(" "))
+        })
+    };
+    let mut settings = ProxySettings {
+        auto_config: value("AutoConfigURL").map_or(false, |url| !url.is_empty()),
+        ..Default::default()
+    };
+    if value("ProxyEnable").as_deref() != Some("0x1") {
+        return settings;
+    }
+    for server in value("ProxyServer").unwrap_or_default().split(';') {
+        // Either one proxy for all protocols (HTTP), or `protocol=host:port` entries
+        match server.split_once('=') {
+            Some(("socks", proxy)) => settings.socks = Some(proxy.to_string()),
+            Some(("http" | "https", proxy)) => {
+                settings.http.get_or_insert_with(|| proxy.to_string());
+            }
+            Some(_) => {}
+            None if !server.is_empty() => settings.http = Some(server.to_string()),
+            None => {}
+        }
+    }
+    settings
+}
+
+/// Parses the connections listed by `rasdial`, between `Connected to` and the final status line.
+fn parse_rasdial(output: &str) -> Vec<String> {
+    let mut lines = output.lines().map(str::trim);
+    if lines.next() != Some("Connected to") {
+        return vec![];
+    }
+    lines
+        .take_while(|line| !line.starts_with("Command completed"))
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_registry() {
+        let output = "\r\nHKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Internet Settings\r\n\
+            \x20   ProxyEnable    REG_DWORD    0x1\r\n\
+            \x20   ProxyServer    REG_SZ    http=proxy:3128;socks=127.0.0.1:1080\r\n";
+        assert_eq!(
+            parse_registry(output),
+            ProxySettings {
+                http: Some("proxy:3128".into()),
+                socks: Some("127.0.0.1:1080".into()),
+                auto_config: false,
+                vpns: vec![],
+            }
+        );
+        let output =
+            "    ProxyEnable    REG_DWORD    0x0\r\n    ProxyServer    REG_SZ    proxy:3128\r\n";
+        assert!(parse_registry(output).is_empty());
+
+        let output = "Connected to\r\nOffice VPN\r\nCommand completed successfully.\r\n";
+        assert_eq!(parse_rasdial(output), vec!["Office VPN".to_string()]);
+        assert!(parse_rasdial("No connections\r\nCommand completed successfully.\r\n").is_empty());
+    }
+}
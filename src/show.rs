@@ -0,0 +1,144 @@
+//! The status of a tunnel in the layout of `wg show` (see `Handle::format_wg_show`), so that the scripts
+//! monitoring WireGuard interfaces work against onetun unchanged.
+
+use std::time::Duration;
+
+use crate::wg::InterfaceStatus;
+
+/// Formats the interface and its peer as `wg show <interface>` does, without colors.
+pub(crate) fn format(interface: &str, status: &InterfaceStatus) -> String {
+    let mut lines = vec![
+        format!("interface: {}", interface),
+        format!("  public key: {}", status.public_key),
+        "  private key: (hidden)".to_string(),
+        format!("  listening port: {}", status.listen_port),
+        String::new(),
+        format!("peer: {}", status.peer_public_key),
+        format!("  endpoint: {}", status.endpoint),
+    ];
+    if status.allowed_ips.is_empty() {
+        lines.push("  allowed ips: (none)".to_string());
+    } else {
+        let allowed_ips: Vec<String> = status.allowed_ips.iter().map(|ip| ip.to_string()).collect();
+        lines.push(format!("  allowed ips: {}", allowed_ips.join(", ")));
+    }
+    if let Some(ago) = status.latest_handshake {
+        if ago.as_secs() == 0 {
+            lines.push("  latest handshake: Now".to_string());
+        } else {
+            lines.push(format!("  latest handshake: {} ago", pretty_time(ago)));
+        }
+    }
+    if status.rx_bytes > 0 || status.tx_bytes > 0 {
+        lines.push(format!(
+            "  transfer: {} received, {} sent",
+            pretty_bytes(status.rx_bytes),
+            pretty_bytes(status.tx_bytes)
+        ));
+    }
+    if let Some(seconds) = status.keepalive_seconds.filter(|seconds| *seconds > 0) {
+        lines.push(format!(
+            "  persistent keepalive: every {}",
+            pretty_time(Duration::from_secs(seconds.into()))
+        ));
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// A duration to the second, as `1 day, 2 hours, 5 seconds`.
+fn pretty_time(duration: Duration) -> String {
+    const UNITS: [(&str, u64); 5] = [
+        ("year", 365 * 24 * 3600),
+        ("day", 24 * 3600),
+        ("hour", 3600),
+        ("minute", 60),
+        ("second", 1),
+    ];
+    let mut left = duration.as_secs();
+    let mut parts = Vec::new();
+    for (unit, seconds) in UNITS {
+        let count = left / seconds;
+        left %= seconds;
+        if count > 0 {
+            parts.push(format!(
+                "{} {}{}",
+                count,
+                unit,
+                if count == 1 { "" } else { "s" }
+            ));
+        }
+    }
+    parts.join(", ")
+}
+
+/// A number of bytes, in the largest binary unit below it, as `1.50 KiB`.
+fn pretty_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AllowedIp;
+
+    #[test]
+    fn test_format() {
+        let mut status = InterfaceStatus {
+            public_key: "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=".to_string(),
+            listen_port: 40312,
+            peer_public_key: "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=".to_string(),
+            endpoint: "140.30.3.182:51820".parse().unwrap(),
+            allowed_ips: vec![
+                AllowedIp::new("192.168.4.0".parse().unwrap(), 24).unwrap(),
+                AllowedIp::new("fd00::".parse().unwrap(), 64).unwrap(),
+            ],
+            latest_handshake: Some(Duration::from_secs(62)),
+            rx_bytes: 1536,
+            tx_bytes: 5 * 1024 * 1024 + 300,
+            keepalive_seconds: Some(25),
+        };
+        assert_eq!(
+            format("onetun1", &status),
+            "interface: onetun1\n\
+            \x20 public key: HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=\n\
+            \x20 private key: (hidden)\n\
+            \x20 listening port: 40312\n\
+            \n\
+            peer: xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\n\
+            \x20 endpoint: 140.30.3.182:51820\n\
+            \x20 allowed ips: 192.168.4.0/24, fd00::/64\n\
+            \x20 latest handshake: 1 minute, 2 seconds ago\n\
+            \x20 transfer: 1.50 KiB received, 5.00 MiB sent\n\
+            \x20 persistent keepalive: every 25 seconds\n"
+        );
+
+        // Before the first handshake and datagram
+        status.latest_handshake = None;
+        status.rx_bytes = 0;
+        status.tx_bytes = 0;
+        status.keepalive_seconds = None;
+        assert!(format("onetun1", &status).ends_with("allowed ips: 192.168.4.0/24, fd00::/64\n"));
+    }
+
+    #[test]
+    fn test_pretty_time() {
+        assert_eq!(pretty_time(Duration::from_secs(1)), "1 second");
+        assert_eq!(
+            pretty_time(Duration::from_secs(366 * 24 * 3600 + 7200 + 5)),
+            "1 year, 1 day, 2 hours, 5 seconds"
+        );
+        assert_eq!(pretty_bytes(1023), "1023 B");
+        assert_eq!(pretty_bytes(3 * 1024 * 1024 * 1024), "3.00 GiB");
+    }
+}
@@ -0,0 +1,114 @@
+//! Simulation of poor network conditions, for developers to test their apps through the tunnel.
+//!
+//! Like `netem` on an egress interface, the simulation delays or drops the datagrams sent to the
+//! WireGuard endpoint, so the round-trip time of the tunnel grows by the configured latency.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use rand::Rng;
+
+use crate::config::parse_duration;
+
+/// Network conditions applied to the datagrams sent to the WireGuard endpoint.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkSimulation {
+    /// Delay added to each datagram.
+    pub latency: Duration,
+    /// Random variation of the delay, up to this much more or less than `latency`.
+    pub jitter: Duration,
+    /// Probability of dropping each datagram, between 0 and 1.
+    pub loss: f64,
+}
+
+impl NetworkSimulation {
+    /// Whether to drop the next datagram.
+    pub(crate) fn drops(&self) -> bool {
+        self.loss > 0.0 && rand::thread_rng().gen_bool(self.loss.min(1.0))
+    }
+
+    /// How long to delay the next datagram.
+    pub(crate) fn delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.latency;
+        }
+        let jitter = rand::thread_rng().gen_range(0..=self.jitter.as_micros() as u64 * 2);
+        (self.latency + Duration::from_micros(jitter)).saturating_sub(self.jitter)
+    }
+}
+
+impl FromStr for NetworkSimulation {
+    type Err = anyhow::Error;
+
+    /// Parses comma-separated conditions: `latency=<duration>`, `jitter=<duration>` and `loss=<percent>%`,
+    /// such as `latency=100ms,jitter=20ms,loss=1%`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut simulation = Self::default();
+        for condition in s.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            let (key, value) = condition
+                .split_once('=')
+                .with_context(|| format!("Invalid network condition: '{}'", condition))?;
+            match key.trim() {
+                "latency" => simulation.latency = parse_duration(value)?,
+                "jitter" => simulation.jitter = parse_duration(value)?,
+                "loss" => {
+                    let percent: f64 = value
+                        .trim()
+                        .trim_end_matches('%')
+                        .parse()
+                        .with_context(|| format!("Invalid loss: '{}'", value))?;
+                    if !(0.0..=100.0).contains(&percent) {
+                        return Err(anyhow::anyhow!(
+                            "Loss must be between 0% and 100%, not {}",
+                            value
+                        ));
+                    }
+                    simulation.loss = percent / 100.0;
+                }
+                _ => return Err(anyhow::anyhow!("Unknown network condition: '{}'", key)),
+            }
+        }
+        Ok(simulation)
+    }
+}
+
+impl Display for NetworkSimulation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "latency={:?},jitter={:?},loss={}%",
+            self.latency,
+            self.jitter,
+            self.loss * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_network_simulation() {
+        let simulation: NetworkSimulation = "latency=100ms,jitter=20ms,loss=1%".parse().unwrap();
+        assert_eq!(simulation.latency, Duration::from_millis(100));
+        assert_eq!(simulation.jitter, Duration::from_millis(20));
+        assert!((simulation.loss - 0.01).abs() < f64::EPSILON);
+        for _ in 0..100 {
+            let delay = simulation.delay();
+            assert!(delay >= Duration::from_millis(80) && delay <= Duration::from_millis(120));
+        }
+
+        let lossless: NetworkSimulation = "latency=1s".parse().unwrap();
+        assert_eq!(lossless.delay(), Duration::from_secs(1));
+        assert!(!lossless.drops());
+        let lossy: NetworkSimulation = "loss=100".parse().unwrap();
+        assert!(lossy.drops());
+
+        assert!("loss=101%".parse::<NetworkSimulation>().is_err());
+        assert!("latency=fast".parse::<NetworkSimulation>().is_err());
+        assert!("bandwidth=1mbps".parse::<NetworkSimulation>().is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! State saved across restarts (see `Config::with_state_file`): the virtual ports of the UDP clients, so that
+//! the destinations keep seeing the same source port after a short restart.
+//!
+//! The file is text, one session per line: `udp <listen address> <client address> <virtual port> <mode>`.
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::tunnel::udp::UdpSession;
+
+const HEADER: &str = "# onetun state v1";
+
+/// Writes the sessions to the file, replacing it at once so that it is never left half-written.
+pub(crate) fn save(path: &Path, sessions: &[UdpSession]) -> anyhow::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let mut file = std::fs::File::create(&tmp)
+        .with_context(|| format!("Failed to create state file {:?}", tmp))?;
+    writeln!(file, "{}", HEADER)?;
+    for session in sessions {
+        writeln!(
+            file,
+            "udp {} {} {} {}",
+            session.forward, session.peer_addr, session.virtual_port, session.session_mode
+        )?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to write state file {:?}", path))
+}
+
+/// Reads the sessions from the file. A missing file has none, and invalid lines are skipped.
+pub(crate) fn load(path: &Path) -> anyhow::Result<Vec<UdpSession>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read state file {:?}", path)),
+    };
+    let mut lines = content.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(anyhow::anyhow!("Unsupported state file {:?}", path));
+    }
+    Ok(lines
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let session = parse_session(line);
+            if session.is_none() {
+                warn!("Ignoring invalid line in state file: {}", line);
+            }
+            session
+        })
+        .collect())
+}
+
+fn parse_session(line: &str) -> Option<UdpSession> {
+    let mut fields = line.split_whitespace();
+    if fields.next()? != "udp" {
+        return None;
+    }
+    let session = UdpSession {
+        forward: fields.next()?.parse::<SocketAddr>().ok()?,
+        peer_addr: fields.next()?.parse().ok()?,
+        virtual_port: fields.next()?.parse().ok()?,
+        session_mode: fields.next()?.parse().ok()?,
+    };
+    match fields.next() {
+        None => Some(session),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UdpSessionMode;
+    use crate::tunnel::udp::UdpPortPool;
+
+    #[tokio::test]
+    async fn test_save_and_restore_sessions() {
+        let path = std::env::temp_dir().join(format!("onetun-state-{}", std::process::id()));
+        let forward: SocketAddr = "127.0.0.1:5353".parse().unwrap();
+        let client: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let quic_client: SocketAddr = "[::1]:40001".parse().unwrap();
+
+        let pool = UdpPortPool::new();
+        let port = pool.next(client, UdpSessionMode::Generic).await.unwrap();
+        pool.set_forward(port, forward).await;
+        let quic_port = pool.next(quic_client, UdpSessionMode::Quic).await.unwrap();
+        pool.set_forward(quic_port, forward).await;
+        // Clients of remote port forwards, or unknown to any port forward, aren't saved
+        pool.next("127.0.0.1:40002".parse().unwrap(), UdpSessionMode::Generic)
+            .await
+            .unwrap();
+
+        let sessions = pool.sessions().await;
+        assert_eq!(sessions.len(), 2);
+        save(&path, &sessions).unwrap();
+        assert_eq!(load(&path).unwrap(), sessions);
+
+        // After a restart, the clients get their virtual port back
+        let restarted = UdpPortPool::new();
+        assert_eq!(restarted.restore(&load(&path).unwrap()).await, 2);
+        assert_eq!(
+            restarted
+                .next(client, UdpSessionMode::Generic)
+                .await
+                .unwrap(),
+            port
+        );
+        assert_eq!(
+            restarted
+                .next(quic_client, UdpSessionMode::Quic)
+                .await
+                .unwrap(),
+            quic_port
+        );
+        let mut restored = restarted.ports_of_forward(forward).await;
+        restored.sort();
+        let mut expected = vec![port, quic_port];
+        expected.sort();
+        assert_eq!(restored, expected);
+        // Already taken
+        assert_eq!(restarted.restore(&sessions).await, 0);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(load(&path).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,318 @@
+//! Support bundles, written when the tunnel fails (see `Config::with_pcap_on_error`): the recent traffic as a
+//! pcap file, along with a report of the configuration, the last events and the handshakes.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use tokio::sync::broadcast;
+
+use crate::config::Config;
+use crate::events::Event;
+use crate::pcap::RecentTraffic;
+use crate::Bus;
+
+/// How many of the last events are kept for the report.
+const EVENT_HISTORY: usize = 100;
+/// How many of the last handshake events are kept for the report.
+const HANDSHAKE_HISTORY: usize = 20;
+/// How many connections of a port forward must fail within `FAILURE_WINDOW` for a bundle to be written.
+const FAILURE_THRESHOLD: usize = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// How long to wait after writing a bundle before writing another one.
+const BUNDLE_COOLDOWN: Duration = Duration::from_secs(300);
+
+/// Follows the events of the tunnel, and tells when a bundle should be written.
+struct Recorder {
+    started: Instant,
+    events: VecDeque<(Instant, String)>,
+    handshakes: VecDeque<(Instant, String)>,
+    /// The recent connection failures of each port forward.
+    failures: HashMap<String, VecDeque<Instant>>,
+    last_bundle: Option<Instant>,
+}
+
+impl Recorder {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            events: VecDeque::with_capacity(EVENT_HISTORY),
+            handshakes: VecDeque::with_capacity(HANDSHAKE_HISTORY),
+            failures: HashMap::new(),
+            last_bundle: None,
+        }
+    }
+
+    /// Records the event, and returns why a bundle should be written, if it should.
+    fn record(&mut self, event: &Event, now: Instant) -> Option<String> {
+        match event {
+            // The packets are in the pcap file, and the data is too frequent to be worth keeping
+            Event::InboundInternetPacket(..)
+            | Event::OutboundInternetPacket(_)
+            | Event::InboundTunPacket(_)
+            | Event::VirtualDeviceFed(_)
+            | Event::LocalData(..)
+            | Event::RemoteData(..)
+            | Event::RemotePeerData(..)
+            | Event::RemoteRelayData(..)
+            | Event::QueryConnections(_)
+            | Event::Dumb => return None,
+            _ => {}
+        }
+        push(&mut self.events, EVENT_HISTORY, (now, event.to_string()));
+
+        let reason = match event {
+            Event::HandshakeCompleted | Event::EndpointSwitched(..) => {
+                push(
+                    &mut self.handshakes,
+                    HANDSHAKE_HISTORY,
+                    (now, event.to_string()),
+                );
+                None
+            }
+            Event::TunnelExpired => {
+                push(
+                    &mut self.handshakes,
+                    HANDSHAKE_HISTORY,
+                    (now, event.to_string()),
+                );
+                Some("The session with the WireGuard endpoint expired".to_string())
+            }
+            Event::ConnectionFailed(pf, ..) => {
+                let failures = self.failures.entry(pf.to_string()).or_default();
+                failures.push_back(now);
+                while failures
+                    .front()
+                    .map_or(false, |failed| now.duration_since(*failed) > FAILURE_WINDOW)
+                {
+                    failures.pop_front();
+                }
+                if failures.len() >= FAILURE_THRESHOLD {
+                    failures.clear();
+                    Some(format!(
+                        "{} connections of port forward {} failed within {}s",
+                        FAILURE_THRESHOLD,
+                        pf,
+                        FAILURE_WINDOW.as_secs()
+                    ))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }?;
+
+        if let Some(last_bundle) = self.last_bundle {
+            if now.duration_since(last_bundle) < BUNDLE_COOLDOWN {
+                debug!(
+                    "Not writing a support bundle, one was just written: {}",
+                    reason
+                );
+                return None;
+            }
+        }
+        self.last_bundle = Some(now);
+        Some(reason)
+    }
+
+    /// The diagnostic report of the bundle.
+    fn report(&self, summary: &str, reason: &str, now: Instant) -> String {
+        let timestamp = |instant: &Instant| {
+            format!(
+                "+{:.3}s",
+                instant
+                    .saturating_duration_since(self.started)
+                    .as_secs_f64()
+            )
+        };
+        let mut report = vec![
+            format!("onetun {} support bundle", env!("CARGO_PKG_VERSION")),
+            format!("Reason: {}", reason),
+            format!("Time: {} ({} since start)", unix_time(), timestamp(&now)),
+            String::new(),
+            "== Configuration ==".to_string(),
+            summary.to_string(),
+            String::new(),
+            "== Handshakes ==".to_string(),
+        ];
+        report.extend(
+            self.handshakes
+                .iter()
+                .map(|(instant, event)| format!("{} {}", timestamp(instant), event)),
+        );
+        report.push(String::new());
+        report.push(format!("== Last {} events ==", self.events.len()));
+        report.extend(
+            self.events
+                .iter()
+                .map(|(instant, event)| format!("{} {}", timestamp(instant), event)),
+        );
+        report.push(String::new());
+        report.join("\n")
+    }
+}
+
+fn push(history: &mut VecDeque<(Instant, String)>, capacity: usize, entry: (Instant, String)) {
+    if history.len() == capacity {
+        history.pop_front();
+    }
+    history.push_back(entry);
+}
+
+/// The seconds since the Unix epoch, naming the files of a bundle.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A summary of the configuration for the report, without the keys.
+pub(crate) fn summary(config: &Config) -> String {
+    let mut lines = vec![
+        format!(
+            "Endpoint: {}",
+            config
+                .endpoint_addrs()
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        format!(
+            "Source peer IPs: {}",
+            config
+                .source_peer_ips()
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        format!(
+            "Allowed IPs: {}",
+            config
+                .allowed_ips
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        format!("MTU: {}", config.max_transmission_unit),
+        format!(
+            "Keep-alive: {}",
+            config
+                .keepalive_seconds
+                .map(|seconds| format!("{}s", seconds))
+                .unwrap_or_else(|| "off".to_string())
+        ),
+        format!("Roaming: {}", config.allow_roaming),
+        format!("Obfuscated: {}", config.obfuscator.is_some()),
+    ];
+    for pf in config.port_forwards.iter() {
+        lines.push(format!("Port forward: {}", pf));
+    }
+    for pf in config.remote_port_forwards.iter() {
+        lines.push(format!("Remote port forward: {}", pf));
+    }
+    lines.join("\n")
+}
+
+/// Writes the recent traffic and the report to new files in the directory, and returns the path of the report.
+async fn write_bundle(
+    dir: &Path,
+    report: &str,
+    recent_traffic: &RecentTraffic,
+) -> anyhow::Result<PathBuf> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create support bundle directory {:?}", dir))?;
+    let name = format!("onetun-error-{}", unix_time());
+    recent_traffic
+        .dump(&dir.join(format!("{}.pcap", name)))
+        .await?;
+    let path = dir.join(format!("{}.txt", name));
+    tokio::fs::write(&path, report)
+        .await
+        .with_context(|| "Failed to write support bundle report")?;
+    Ok(path)
+}
+
+/// Writes a support bundle to the directory whenever the tunnel fails, until it is killed.
+pub(crate) async fn write_on_error(
+    dir: PathBuf,
+    summary: String,
+    recent_traffic: Arc<RecentTraffic>,
+    bus: Bus,
+    mut kill_switch: broadcast::Receiver<()>,
+) {
+    let mut endpoint = bus.new_endpoint();
+    let mut recorder = Recorder::new(Instant::now());
+    loop {
+        tokio::select! {
+            event = endpoint.recv() => {
+                let now = Instant::now();
+                if let Some(reason) = recorder.record(&event, now) {
+                    let report = recorder.report(&summary, &reason, now);
+                    match write_bundle(&dir, &report, &recent_traffic).await {
+                        Ok(path) => warn!("{}: wrote support bundle {:?}", reason, path),
+                        Err(e) => error!("Failed to write support bundle: {:?}", e),
+                    }
+                }
+            }
+            _ = kill_switch.recv() => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortForwardConfig;
+    use crate::virtual_iface::VirtualPort;
+    use crate::PortProtocol;
+
+    #[test]
+    fn test_recorder() {
+        let started = Instant::now();
+        let mut recorder = Recorder::new(started);
+        let pf = Arc::new(
+            PortForwardConfig::from_notation("8080:192.168.4.1:8081", "127.0.0.1")
+                .unwrap()
+                .remove(0),
+        );
+        let failed = |n| {
+            Event::ConnectionFailed(
+                pf.clone(),
+                VirtualPort::new(n, PortProtocol::Tcp),
+                "refused".into(),
+            )
+        };
+
+        assert_eq!(recorder.record(&Event::HandshakeCompleted, started), None);
+        for n in 1..FAILURE_THRESHOLD as u16 {
+            assert_eq!(recorder.record(&failed(n), started), None);
+        }
+        // Failures outside of the window don't count
+        let later = started + FAILURE_WINDOW * 2;
+        assert_eq!(recorder.record(&failed(10), later), None);
+        for n in 11..10 + FAILURE_THRESHOLD as u16 {
+            assert_eq!(
+                recorder.record(&failed(n), later).is_some(),
+                n == 9 + FAILURE_THRESHOLD as u16
+            );
+        }
+
+        // Another failure is ignored until the cooldown is over
+        assert_eq!(recorder.record(&Event::TunnelExpired, later), None);
+        assert!(recorder
+            .record(&Event::TunnelExpired, later + BUNDLE_COOLDOWN)
+            .is_some());
+
+        let report = recorder.report("Endpoint: 127.0.0.1:51820", "test", later);
+        assert!(report.contains("Reason: test"));
+        assert!(report.contains("+0.000s HandshakeCompleted{}"));
+        assert_eq!(report.matches("TunnelExpired").count(), 4);
+    }
+}
@@ -0,0 +1,411 @@
+//! Harness for integration tests of port forward configurations, without a WireGuard server.
+//!
+//! `TestPeers` starts two onetun tunnels in the process, each one the WireGuard endpoint of the other over
+//! loopback UDP. The `local` peer listens for the clients of the port forwards, and reaches the `remote` peer on
+//! its IP (`REMOTE_PEER_IP`), where remote port forwards connect to the destinations. The echo servers are
+//! destinations that send back what they receive.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::config::{Config, PortForwardConfig, PortProtocol};
+use crate::engine::PrivateKey;
+use crate::Handle;
+
+/// The IP of the `local` peer in the WireGuard network.
+pub const LOCAL_PEER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 73, 0, 1));
+/// The IP of the `remote` peer in the WireGuard network, which the port forwards reach.
+pub const REMOTE_PEER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 73, 0, 2));
+
+/// How long the `local` peer waits for its first handshake with the `remote` peer.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Two onetun tunnels connected to each other. Both are killed when dropped.
+pub struct TestPeers {
+    /// The peer with the port forwards, which the clients connect to.
+    pub local: Handle,
+    /// The peer with the remote port forwards, which connect to the destinations.
+    pub remote: Handle,
+}
+
+impl TestPeers {
+    /// Starts the peers, with the given port forwards on the `local` peer and remote port forwards on the
+    /// `remote` peer. Returns once the peers completed a handshake.
+    pub async fn start(
+        port_forwards: Vec<PortForwardConfig>,
+        remote_port_forwards: Vec<PortForwardConfig>,
+    ) -> anyhow::Result<Self> {
+        Self::start_with(
+            port_forwards,
+            remote_port_forwards,
+            |config| config,
+            |config| config,
+        )
+        .await
+    }
+
+    /// Starts the peers like `start`, with the configuration of each peer adjusted by its function.
+    pub async fn start_with(
+        port_forwards: Vec<PortForwardConfig>,
+        remote_port_forwards: Vec<PortForwardConfig>,
+        configure_local: impl FnOnce(Config) -> Config,
+        configure_remote: impl FnOnce(Config) -> Config,
+    ) -> anyhow::Result<Self> {
+        let (local_key, remote_key) = (PrivateKey::generate(), PrivateKey::generate());
+        let (local_port, remote_port) = (free_udp_port()?, free_udp_port()?);
+
+        let mut builder = Config::builder()
+            .private_key(hex(remote_key.as_bytes()))
+            .endpoint_public_key(hex(local_key.public_key().as_bytes()))
+            .endpoint_addr(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), local_port))
+            .source_peer_ip(REMOTE_PEER_IP);
+        for port_forward in remote_port_forwards {
+            builder = builder.remote_port_forward(port_forward);
+        }
+        let remote = crate::start(configure_remote(
+            builder.build()?.with_listen_port(remote_port),
+        ))
+        .await
+        .with_context(|| "Failed to start the remote peer")?;
+
+        let mut local_config = Config::builder()
+            .private_key(hex(local_key.as_bytes()))
+            .endpoint_public_key(hex(remote_key.public_key().as_bytes()))
+            .endpoint_addr(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), remote_port))
+            .source_peer_ip(LOCAL_PEER_IP)
+            .port_forwards(port_forwards)
+            .build()?
+            .with_listen_port(local_port);
+        local_config.handshake_timeout = Some(HANDSHAKE_TIMEOUT);
+        let local = match crate::start(configure_local(local_config)).await {
+            Ok(local) => local,
+            Err(e) => {
+                remote.kill();
+                return Err(e).with_context(|| "Failed to start the local peer");
+            }
+        };
+
+        Ok(Self { local, remote })
+    }
+
+    /// Starts the peers with a single port forward to the destination, through a remote port forward on the
+    /// same port. Returns the address that the local peer listens on.
+    pub async fn forward(
+        protocol: PortProtocol,
+        destination: SocketAddr,
+    ) -> anyhow::Result<(Self, SocketAddr)> {
+        let remote_source = SocketAddr::new(REMOTE_PEER_IP, destination.port());
+        let peers = Self::start(
+            vec![PortForwardConfig::new(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+                remote_source,
+                protocol,
+            )],
+            vec![PortForwardConfig::new(remote_source, destination, protocol)],
+        )
+        .await?;
+        let addr = peers.local.listeners()[0].1;
+        Ok((peers, addr))
+    }
+}
+
+impl Drop for TestPeers {
+    fn drop(&mut self) {
+        self.local.kill();
+        self.remote.kill();
+    }
+}
+
+/// Starts a TCP server on loopback that sends back the data of each connection, and returns its address.
+pub async fn tcp_echo_server() -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                if tokio::io::copy(&mut reader, &mut writer).await.is_ok() {
+                    writer.shutdown().await.ok();
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Starts a UDP server on loopback that sends each datagram back to its sender, and returns its address.
+pub async fn udp_echo_server() -> std::io::Result<SocketAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = socket.local_addr()?;
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; 65536];
+        while let Ok((size, peer)) = socket.recv_from(&mut buffer).await {
+            if socket.send_to(&buffer[..size], peer).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(addr)
+}
+
+/// A UDP port that was free on loopback, for a peer to listen on.
+fn free_udp_port() -> std::io::Result<u16> {
+    Ok(std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+fn hex(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ForwardId, StreamCodec};
+    use crate::events::Event;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_tcp_round_trip() {
+        let echo = tcp_echo_server().await.unwrap();
+        let (peers, addr) = TestPeers::forward(PortProtocol::Tcp, echo).await.unwrap();
+
+        // Larger than the socket buffers, so that it takes several segments both ways
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        let mut received = vec![0u8; data.len()];
+        let read = tokio::time::timeout(Duration::from_secs(10), reader.read_exact(&mut received));
+        let (write, read) = tokio::join!(writer.write_all(&data), read);
+        write.unwrap();
+        read.expect("Timed out waiting for the echo").unwrap();
+        assert_eq!(received, data);
+
+        drop(peers);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_round_trip_compressed() {
+        let echo = tcp_echo_server().await.unwrap();
+        let remote_source = SocketAddr::new(REMOTE_PEER_IP, echo.port());
+        let mut local = PortForwardConfig::new(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            remote_source,
+            PortProtocol::Tcp,
+        );
+        local.compression = Some(StreamCodec::Lz4);
+        let mut remote = PortForwardConfig::new(remote_source, echo, PortProtocol::Tcp);
+        remote.compression = Some(StreamCodec::Lz4);
+        let peers = TestPeers::start(vec![local], vec![remote]).await.unwrap();
+
+        let data = b"onetun compresses this line\n".repeat(10_000);
+        let mut stream = TcpStream::connect(peers.local.listeners()[0].1)
+            .await
+            .unwrap();
+        let (mut reader, mut writer) = stream.split();
+        let mut received = vec![0u8; data.len()];
+        let read = tokio::time::timeout(Duration::from_secs(10), reader.read_exact(&mut received));
+        let (write, read) = tokio::join!(writer.write_all(&data), read);
+        write.unwrap();
+        read.expect("Timed out waiting for the echo").unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_round_trip_dedicated_data_path() {
+        let echo = tcp_echo_server().await.unwrap();
+        let remote_source = SocketAddr::new(REMOTE_PEER_IP, echo.port());
+        let peers = TestPeers::start_with(
+            vec![PortForwardConfig::new(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+                remote_source,
+                PortProtocol::Tcp,
+            )],
+            vec![PortForwardConfig::new(
+                remote_source,
+                echo,
+                PortProtocol::Tcp,
+            )],
+            |config| config.with_data_path_threads(1),
+            |config| config,
+        )
+        .await
+        .unwrap();
+
+        let mut stream = TcpStream::connect(peers.local.listeners()[0].1)
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(10), stream.read_exact(&mut received))
+            .await
+            .expect("Timed out waiting for the echo")
+            .unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_selection() {
+        let echo = udp_echo_server().await.unwrap();
+        let remote_source = SocketAddr::new(REMOTE_PEER_IP, echo.port());
+        let unreachable = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), free_udp_port().unwrap());
+        let mut remote = None;
+        let peers = TestPeers::start_with(
+            vec![PortForwardConfig::new(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+                remote_source,
+                PortProtocol::Udp,
+            )],
+            vec![PortForwardConfig::new(
+                remote_source,
+                echo,
+                PortProtocol::Udp,
+            )],
+            |mut config| {
+                // The remote peer is only the second address, after one that never answers
+                let addr = config.endpoint_addr;
+                remote = Some(addr);
+                config.endpoint_addr = unreachable;
+                config.with_additional_endpoint_addrs(vec![addr])
+            },
+            |config| config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(peers.local.current_endpoint()), remote);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_cookie() {
+        let echo = udp_echo_server().await.unwrap();
+        let remote_source = SocketAddr::new(REMOTE_PEER_IP, echo.port());
+        // The remote peer answers every handshake initiation without a cookie with a cookie reply
+        let peers = TestPeers::start_with(
+            vec![PortForwardConfig::new(
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+                remote_source,
+                PortProtocol::Udp,
+            )],
+            vec![PortForwardConfig::new(
+                remote_source,
+                echo,
+                PortProtocol::Udp,
+            )],
+            |config| config,
+            |config| config.with_handshake_rate_limit(0),
+        )
+        .await
+        .unwrap();
+        assert!(peers.local.cookie_replies() > 0);
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = peers.local.listeners()[0].1;
+        socket.send_to(b"ping", addr).await.unwrap();
+        let mut buffer = [0u8; 16];
+        let (size, _) = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buffer))
+            .await
+            .expect("Timed out waiting for the echo")
+            .unwrap();
+        assert_eq!(&buffer[..size], b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_udp_round_trip() {
+        let echo = udp_echo_server().await.unwrap();
+        let (_peers, addr) = TestPeers::forward(PortProtocol::Udp, echo).await.unwrap();
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut buffer = [0u8; 1500];
+        for datagram in [&b"ping"[..], &[7u8; 1200][..]] {
+            socket.send_to(datagram, addr).await.unwrap();
+            let (size, from) =
+                tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buffer))
+                    .await
+                    .expect("Timed out waiting for the echo")
+                    .unwrap();
+            assert_eq!(from, addr);
+            assert_eq!(&buffer[..size], datagram);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_in_process() {
+        let (tcp_echo, udp_echo) = (
+            tcp_echo_server().await.unwrap(),
+            udp_echo_server().await.unwrap(),
+        );
+        let (tcp_source, udp_source) = (
+            SocketAddr::new(REMOTE_PEER_IP, tcp_echo.port()),
+            SocketAddr::new(REMOTE_PEER_IP, udp_echo.port()),
+        );
+        let peers = TestPeers::start_with(
+            vec![],
+            vec![
+                PortForwardConfig::new(tcp_source, tcp_echo, PortProtocol::Tcp),
+                PortForwardConfig::new(udp_source, udp_echo, PortProtocol::Udp),
+            ],
+            |config| config.with_in_process_connections(),
+            |config| config,
+        )
+        .await
+        .unwrap();
+
+        let mut stream = peers.local.open_tcp(tcp_source).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(10), stream.read_exact(&mut received))
+            .await
+            .expect("Timed out waiting for the echo")
+            .unwrap();
+        assert_eq!(&received, b"ping");
+
+        let mut socket = peers.local.open_udp(udp_source).await.unwrap();
+        socket.send(b"pong").await;
+        let datagram = tokio::time::timeout(Duration::from_secs(5), socket.recv())
+            .await
+            .expect("Timed out waiting for the echo");
+        assert_eq!(datagram, b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_remove_forward() {
+        let echo = tcp_echo_server().await.unwrap();
+        let (mut peers, addr) = TestPeers::forward(PortProtocol::Tcp, echo).await.unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(10), stream.read_exact(&mut received))
+            .await
+            .expect("Timed out waiting for the echo")
+            .unwrap();
+
+        let mut events = peers.local.subscribe();
+        let id = ForwardId::Index(0);
+        assert!(peers.local.remove_forward(&id, Duration::from_millis(500)));
+        assert!(!peers.local.remove_forward(&id, Duration::from_millis(500)));
+        assert!(peers.local.listeners().is_empty());
+
+        // The open connection is closed after the grace period
+        let removed = async {
+            loop {
+                if let Event::ForwardRemoved(e_id, closed) = events.recv().await {
+                    return (e_id, closed);
+                }
+            }
+        };
+        let (e_id, closed) = tokio::time::timeout(Duration::from_secs(5), removed)
+            .await
+            .expect("Timed out waiting for the removal");
+        assert_eq!((e_id, closed), (id, 1));
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut received))
+            .await
+            .expect("Timed out waiting for the connection to close");
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+}
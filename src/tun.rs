@@ -0,0 +1,157 @@
+//! Bridges the WireGuard tunnel to a tun device, such as the one created by Android's `VpnService`.
+//!
+//! Every IP packet read from the device is sent through the tunnel, and every IP packet received from
+//! the tunnel is written to the device. No virtual interface is involved.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use anyhow::Context;
+use tokio::io::unix::AsyncFd;
+
+use crate::events::{Bus, Event};
+
+const MAX_PACKET: usize = 65536;
+
+/// A tun device file descriptor, closed on drop.
+struct TunFd(RawFd);
+
+impl TunFd {
+    /// Takes ownership of the file descriptor and switches it to non-blocking mode.
+    fn new(fd: RawFd) -> io::Result<Self> {
+        let tun = Self(fd);
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(tun)
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = unsafe { libc::read(self.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if size < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(size as usize)
+        }
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let size = unsafe { libc::write(self.0, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if size < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(size as usize)
+        }
+    }
+}
+
+impl AsRawFd for TunFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for TunFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Moves IP packets between the tun device and the tunnel until the device is closed or the tunnel is killed.
+pub async fn bridge(
+    fd: RawFd,
+    bus: Bus,
+    mut kill_switch: tokio::sync::broadcast::Receiver<()>,
+) -> anyhow::Result<()> {
+    let tun = TunFd::new(fd).with_context(|| "Failed to configure tun device")?;
+    let tun = AsyncFd::new(tun).with_context(|| "Failed to register tun device")?;
+    let mut endpoint = bus.new_endpoint();
+    let mut buffer = vec![0u8; MAX_PACKET];
+
+    loop {
+        tokio::select! {
+            guard = tun.readable() => {
+                let mut guard = guard.with_context(|| "Failed to poll tun device")?;
+                match guard.try_io(|tun| tun.get_ref().read(&mut buffer)) {
+                    Ok(Ok(0)) => {
+                        info!("Tun device was closed");
+                        return Ok(());
+                    }
+                    Ok(Ok(size)) => {
+                        endpoint.send(Event::OutboundInternetPacket(buffer[..size].to_vec()));
+                    }
+                    Ok(Err(e)) => return Err(e).with_context(|| "Failed to read from tun device"),
+                    Err(_would_block) => {}
+                }
+            }
+            event = endpoint.recv() => {
+                if let Event::InboundTunPacket(packet) = event {
+                    if let Err(e) = write_packet(&tun, &packet).await {
+                        error!("Failed to write IP packet to tun device: {:?}", e);
+                    }
+                }
+            }
+            _ = kill_switch.recv() => return Ok(()),
+        }
+    }
+}
+
+/// Writes one IP packet to the tun device, which takes it whole.
+async fn write_packet(tun: &AsyncFd<TunFd>, packet: &[u8]) -> io::Result<()> {
+    loop {
+        let mut guard = tun.writable().await?;
+        match guard.try_io(|tun| tun.get_ref().write(packet)) {
+            Ok(result) => return result.map(|_| ()),
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::time::Duration;
+    use tokio::net::UnixDatagram;
+
+    /// Tests that packets are moved between the device and the bus, using a datagram socket as the device.
+    #[tokio::test]
+    async fn test_bridge() {
+        let (device, peer) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        let peer = UnixDatagram::from_std({
+            peer.set_nonblocking(true).unwrap();
+            peer
+        })
+        .unwrap();
+        let bus = Bus::default();
+        let mut endpoint = bus.new_endpoint();
+        let (kill, kill_switch) = tokio::sync::broadcast::channel(1);
+        let bridge = tokio::spawn(bridge(device.into_raw_fd(), bus, kill_switch));
+
+        peer.send(&[1, 2, 3]).await.unwrap();
+        let outbound = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Event::OutboundInternetPacket(packet) = endpoint.recv().await {
+                    return packet;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(outbound, vec![1, 2, 3]);
+
+        endpoint.send(Event::InboundTunPacket(vec![4, 5]));
+        let mut buf = [0u8; 16];
+        let size = tokio::time::timeout(Duration::from_secs(1), peer.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..size], &[4, 5]);
+
+        kill.send(()).unwrap();
+        bridge.await.unwrap().unwrap();
+    }
+}
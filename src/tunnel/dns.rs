@@ -0,0 +1,444 @@
+//! Resolution of the destination host names through a DNS server reachable via the tunnel, which knows
+//! the names of the peer network (such as `db.internal`) that the system's resolver doesn't.
+//!
+//! Each host name is resolved when the tunnel starts, and again when its record expires, so that the
+//! port forwards follow their destination when its IP changes.
+//!
+//! With a NAT64 prefix (see `Config::with_nat64`), the IPv4 destinations of the port forwards connecting from
+//! an IPv6 peer IP are embedded in it, and their host names without an IPv6 address are resolved to their IPv4
+//! address (DNS64).
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use tokio::sync::watch;
+
+use crate::config::{Nat64Prefix, PortForwardConfig, PortProtocol, UdpSessionMode};
+use crate::events::{Bus, BusEndpoint, Event};
+use crate::tunnel::udp::UdpPortPool;
+use crate::virtual_iface::VirtualPort;
+
+/// How long to wait for the response to a query, before sending it again.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// How many times a query is sent before the resolution fails.
+const QUERY_ATTEMPTS: usize = 3;
+/// The shortest time between two resolutions of a host name, whatever the TTL of its record.
+const MIN_TTL: Duration = Duration::from_secs(5);
+/// The longest time between two resolutions of a host name, whatever the TTL of its record.
+const MAX_TTL: Duration = Duration::from_secs(3600);
+/// How long to wait before resolving a host name again, after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a connection waits for the first resolution of its destination.
+const FIRST_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The local address the virtual port of the queries is assigned to. Local clients can't have it.
+const RESOLVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 53);
+
+const DNS_HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// The current addresses of the destination host names resolved through the tunnel.
+#[derive(Debug, Default)]
+pub struct TunnelResolver {
+    hosts: HashMap<Arc<str>, Host>,
+    /// The NAT64 prefix, with the default source peer IP of the port forwards.
+    nat64: Option<(Nat64Prefix, IpAddr)>,
+}
+
+#[derive(Debug)]
+struct Host {
+    /// Whether an IPv6 address is wanted, for port forwards connecting from an IPv6 peer IP.
+    ipv6: bool,
+    sender: watch::Sender<Option<IpAddr>>,
+    receiver: watch::Receiver<Option<IpAddr>>,
+}
+
+impl TunnelResolver {
+    /// A resolver for the host names of the given port forwards' destinations.
+    pub fn new(port_forwards: &[PortForwardConfig], source_peer_ip: IpAddr) -> Self {
+        let mut hosts = HashMap::new();
+        for pf in port_forwards.iter() {
+            if let Some(hostname) = &pf.hostname {
+                hosts.entry(hostname.clone()).or_insert_with(|| {
+                    let (sender, receiver) = watch::channel(None);
+                    Host {
+                        ipv6: pf.source_peer_ip.unwrap_or(source_peer_ip).is_ipv6(),
+                        sender,
+                        receiver,
+                    }
+                });
+            }
+        }
+        Self { hosts, nat64: None }
+    }
+
+    /// Embeds the IPv4 destinations of the port forwards connecting from an IPv6 peer IP in the NAT64 prefix.
+    pub fn with_nat64(mut self, prefix: Option<Nat64Prefix>, source_peer_ip: IpAddr) -> Self {
+        self.nat64 = prefix.map(|prefix| (prefix, source_peer_ip));
+        self
+    }
+
+    /// The address of the destination through the NAT64 gateway, for a port forward connecting from the
+    /// given peer IP. Other destinations are returned as they are.
+    fn translate(&self, destination: SocketAddr, source_peer_ip: Option<IpAddr>) -> SocketAddr {
+        match (self.nat64, destination.ip()) {
+            (Some((prefix, default)), IpAddr::V4(ip))
+                if source_peer_ip.unwrap_or(default).is_ipv6() =>
+            {
+                SocketAddr::new(prefix.embed(ip).into(), destination.port())
+            }
+            _ => destination,
+        }
+    }
+
+    /// Whether there is no host name to resolve.
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    /// The port forward, with the address its destination host name currently resolves to. Waits for the
+    /// first resolution of the host name. The port forward is shared as is if its destination is unchanged.
+    pub async fn resolve(
+        &self,
+        port_forward: &Arc<PortForwardConfig>,
+    ) -> anyhow::Result<Arc<PortForwardConfig>> {
+        let destination = self.destination(port_forward).await?;
+        if destination == port_forward.destination {
+            return Ok(port_forward.clone());
+        }
+        Ok(Arc::new(PortForwardConfig {
+            destination,
+            ..PortForwardConfig::clone(port_forward)
+        }))
+    }
+
+    /// The address the destination host name of the port forward currently resolves to. Waits for the first
+    /// resolution of the host name.
+    pub async fn destination(
+        &self,
+        port_forward: &PortForwardConfig,
+    ) -> anyhow::Result<SocketAddr> {
+        let host = match port_forward
+            .hostname
+            .as_ref()
+            .and_then(|hostname| self.hosts.get(hostname))
+        {
+            Some(host) => host,
+            None => {
+                return Ok(self.translate(port_forward.destination, port_forward.source_peer_ip))
+            }
+        };
+        let mut receiver = host.receiver.clone();
+        let ip = tokio::time::timeout(FIRST_RESOLUTION_TIMEOUT, async {
+            loop {
+                if let Some(ip) = *receiver.borrow() {
+                    return Ok(ip);
+                }
+                receiver.changed().await?;
+            }
+        })
+        .await
+        .with_context(|| {
+            format!(
+                "Timed out resolving {} through the tunnel",
+                port_forward.destination_name()
+            )
+        })?
+        .map_err(|e: watch::error::RecvError| anyhow::anyhow!(e))?;
+        let destination = SocketAddr::new(ip, port_forward.destination.port());
+        Ok(self.translate(destination, port_forward.source_peer_ip))
+    }
+
+    /// Resolves the host names through the given DNS server, and again when their record expires, until killed.
+    pub async fn run(&self, server: SocketAddr, port_pool: UdpPortPool, bus: Bus) {
+        let mut endpoint = bus.new_endpoint();
+        let server = self.translate(server, None);
+        let mut next_resolutions: HashMap<Arc<str>, Instant> = self
+            .hosts
+            .keys()
+            .map(|hostname| (hostname.clone(), Instant::now()))
+            .collect();
+
+        loop {
+            let (hostname, at) = match next_resolutions.iter().min_by_key(|(_, at)| **at) {
+                Some((hostname, at)) => (hostname.clone(), *at),
+                None => return,
+            };
+            tokio::time::sleep_until(at.into()).await;
+
+            let host = &self.hosts[&hostname];
+            let record_type = if host.ipv6 { TYPE_AAAA } else { TYPE_A };
+            let mut result = query(&mut endpoint, &port_pool, server, &hostname, record_type).await;
+            if host.ipv6 && self.nat64.is_some() && result.is_err() {
+                // DNS64: the IPv4 address is reached through the NAT64 gateway
+                result = query(&mut endpoint, &port_pool, server, &hostname, TYPE_A).await;
+            }
+            let delay = match result {
+                Ok((ip, ttl)) => {
+                    if *host.receiver.borrow() != Some(ip) {
+                        info!("Resolved {} to {} through the tunnel", hostname, ip);
+                        host.sender.send(Some(ip)).ok();
+                    }
+                    ttl.clamp(MIN_TTL, MAX_TTL)
+                }
+                Err(e) => {
+                    warn!("Failed to resolve {} through the tunnel: {:?}", hostname, e);
+                    RETRY_INTERVAL
+                }
+            };
+            next_resolutions.insert(hostname, Instant::now() + delay);
+        }
+    }
+}
+
+/// Resolves the host name through the DNS server once, for a connection opened in-process (see
+/// `Handle::http_connector`).
+#[cfg(feature = "http")]
+pub(crate) async fn lookup(
+    server: SocketAddr,
+    hostname: &str,
+    ipv6: bool,
+    port_pool: &UdpPortPool,
+    bus: &Bus,
+) -> anyhow::Result<IpAddr> {
+    let mut endpoint = bus.new_endpoint();
+    let record_type = if ipv6 { TYPE_AAAA } else { TYPE_A };
+    let (ip, _) = query(&mut endpoint, port_pool, server, hostname, record_type)
+        .await
+        .with_context(|| format!("Failed to resolve {} through the tunnel", hostname))?;
+    Ok(ip)
+}
+
+/// Sends a query for the host name to the DNS server, through the virtual UDP interface. Returns the first
+/// address of the response, with the TTL of its record.
+async fn query(
+    endpoint: &mut BusEndpoint,
+    port_pool: &UdpPortPool,
+    server: SocketAddr,
+    hostname: &str,
+    record_type: u16,
+) -> anyhow::Result<(IpAddr, Duration)> {
+    let virtual_port = port_pool
+        .next(RESOLVER_ADDR, UdpSessionMode::Generic)
+        .await
+        .with_context(|| "Failed to assign virtual port for DNS queries")?;
+    let port_forward = Arc::new(PortForwardConfig::new(
+        RESOLVER_ADDR,
+        server,
+        PortProtocol::Udp,
+    ));
+
+    for _ in 0..QUERY_ATTEMPTS {
+        let id: u16 = rand::random();
+        let query = encode_query(id, hostname, record_type)?;
+        trace!("[{}] Querying {} for {}", virtual_port, server, hostname);
+        port_pool.update_last_transmit(virtual_port).await;
+        endpoint.send(Event::LocalData(
+            port_forward.clone(),
+            virtual_port,
+            query,
+            Instant::now(),
+        ));
+        match tokio::time::timeout(QUERY_TIMEOUT, response(endpoint, virtual_port, id)).await {
+            Ok(response) => {
+                return decode_response(&response, record_type)?
+                    .with_context(|| format!("No address for {}", hostname))
+            }
+            Err(_) => debug!(
+                "[{}] No response from {} for {}",
+                virtual_port, server, hostname
+            ),
+        }
+    }
+    Err(anyhow::anyhow!("No response from {}", server))
+}
+
+/// Waits for the response with the given ID.
+async fn response(endpoint: &mut BusEndpoint, virtual_port: VirtualPort, id: u16) -> Vec<u8> {
+    loop {
+        if let Event::RemoteData(port, data, _) = endpoint.recv().await {
+            if port == virtual_port && data.len() >= DNS_HEADER_LEN && data[..2] == id.to_be_bytes()
+            {
+                return data;
+            }
+        }
+    }
+}
+
+/// Encodes a recursive query for the records of the given type of the host name.
+fn encode_query(id: u16, hostname: &str, record_type: u16) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(DNS_HEADER_LEN + hostname.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in hostname.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow::anyhow!("Invalid host name: '{}'", hostname));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Decodes the first address of the given type in the answers of the response, with the TTL of its record.
+fn decode_response(
+    response: &[u8],
+    record_type: u16,
+) -> anyhow::Result<Option<(IpAddr, Duration)>> {
+    let truncated = || anyhow::anyhow!("Truncated DNS response");
+    let flags = read_u16(response, 2).ok_or_else(truncated)?;
+    if flags & 0x8000 == 0 {
+        return Err(anyhow::anyhow!("Not a DNS response"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Ok(None),
+        rcode => return Err(anyhow::anyhow!("DNS server failed with code {}", rcode)),
+    }
+    let questions = read_u16(response, 4).ok_or_else(truncated)?;
+    let answers = read_u16(response, 6).ok_or_else(truncated)?;
+
+    let mut pos = DNS_HEADER_LEN;
+    for _ in 0..questions {
+        pos = skip_name(response, pos).ok_or_else(truncated)? + 4;
+    }
+    for _ in 0..answers {
+        pos = skip_name(response, pos).ok_or_else(truncated)?;
+        let rtype = read_u16(response, pos).ok_or_else(truncated)?;
+        let class = read_u16(response, pos + 2).ok_or_else(truncated)?;
+        let ttl = response
+            .get(pos + 4..pos + 8)
+            .map(|ttl| u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]))
+            .ok_or_else(truncated)?;
+        let len = read_u16(response, pos + 8).ok_or_else(truncated)? as usize;
+        let data = response
+            .get(pos + 10..pos + 10 + len)
+            .ok_or_else(truncated)?;
+        pos += 10 + len;
+
+        if class != CLASS_IN || rtype != record_type {
+            // Such as the CNAME records leading to the address
+            continue;
+        }
+        let ip = match data.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(data).unwrap()),
+            16 => IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(data).unwrap())),
+            _ => return Err(anyhow::anyhow!("Invalid address record")),
+        };
+        return Ok(Some((ip, Duration::from_secs(ttl as u64))));
+    }
+    Ok(None)
+}
+
+fn read_u16(buffer: &[u8], pos: usize) -> Option<u16> {
+    buffer
+        .get(pos..pos + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// The position following the (possibly compressed) name at the given position.
+fn skip_name(buffer: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buffer.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_response() {
+        let query = encode_query(0x1234, "db.internal.", TYPE_A).unwrap();
+        assert_eq!(&query[12..25], b"\x02db\x08internal\x00");
+
+        // A CNAME to the name of the question, followed by its address, with compressed names
+        let mut response = query.clone();
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[6..8].copy_from_slice(&[0, 2]);
+        response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+        response.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 192, 168, 4, 7]);
+        assert_eq!(
+            decode_response(&response, TYPE_A).unwrap(),
+            Some((IpAddr::from([192, 168, 4, 7]), Duration::from_secs(300)))
+        );
+        assert_eq!(decode_response(&response, TYPE_AAAA).unwrap(), None);
+
+        response.truncate(response.len() - 2);
+        assert!(decode_response(&response, TYPE_A).is_err());
+        assert!(decode_response(&query, TYPE_A).is_err());
+        assert!(encode_query(0, "db..internal", TYPE_A).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let pfs =
+            PortForwardConfig::from_notation("5432:db.internal.invalid:5432", "127.0.0.1").unwrap();
+        let pf = Arc::new(pfs[0].clone());
+        assert_eq!(
+            pf.to_string(),
+            "127.0.0.1:5432:db.internal.invalid:5432:TCP"
+        );
+        let resolver = TunnelResolver::new(&pfs, "192.168.4.3".parse().unwrap());
+        assert!(!resolver.is_empty());
+
+        let host = &resolver.hosts["db.internal.invalid"];
+        host.sender
+            .send(Some("192.168.4.7".parse().unwrap()))
+            .unwrap();
+        let resolved = resolver.resolve(&pf).await.unwrap();
+        assert_eq!(resolved.destination, "192.168.4.7:5432".parse().unwrap());
+
+        // Port forwards without a host name are shared as they are
+        let other = PortForwardConfig::from_notation("8080:192.168.4.1:80", "127.0.0.1").unwrap();
+        let other = Arc::new(other[0].clone());
+        assert!(Arc::ptr_eq(
+            &resolver.resolve(&other).await.unwrap(),
+            &other
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_nat64() {
+        let pfs = PortForwardConfig::from_notation(
+            "5432:db.internal.invalid:5432;peer-ip=fd00::3",
+            "127.0.0.1",
+        )
+        .unwrap();
+        let resolver = TunnelResolver::new(&pfs, "fd00::3".parse().unwrap())
+            .with_nat64(Some(Nat64Prefix::WELL_KNOWN), "fd00::3".parse().unwrap());
+        resolver.hosts["db.internal.invalid"]
+            .sender
+            .send(Some("192.168.4.7".parse().unwrap()))
+            .unwrap();
+        let resolved = resolver.resolve(&Arc::new(pfs[0].clone())).await.unwrap();
+        assert_eq!(
+            resolved.destination,
+            "[64:ff9b::c0a8:407]:5432".parse().unwrap()
+        );
+
+        let pfs = PortForwardConfig::from_notation("8080:192.168.4.1:80", "127.0.0.1").unwrap();
+        let resolved = resolver.destination(&pfs[0]).await.unwrap();
+        assert_eq!(resolved, "[64:ff9b::c0a8:401]:80".parse().unwrap());
+        let to_ipv6 = PortForwardConfig::from_notation("8080:[fd00::2]:80", "127.0.0.1").unwrap();
+        assert_eq!(
+            resolver.destination(&to_ipv6[0]).await.unwrap(),
+            to_ipv6[0].destination
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::config::{ForwardId, PortForwardConfig};
+use crate::events::Bus;
+use crate::tunnel::dns::TunnelResolver;
+use crate::tunnel::tcp::{self, TcpPortPool};
+use crate::tunnel::udp::{self, UdpPortPool};
+use crate::tunnel::{ForwardStats, Listener};
+
+/// How often the addresses of the interface are enumerated again, to follow DHCP leases and roaming.
+const ADDRESSES_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The addresses of the network interface, with the given port.
+#[cfg(unix)]
+pub fn addresses(interface: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut addrs = Vec::new();
+    let mut current = ifaddrs;
+    while !current.is_null() {
+        let ifaddr = unsafe { &*current };
+        current = ifaddr.ifa_next;
+        if ifaddr.ifa_addr.is_null()
+            || unsafe { std::ffi::CStr::from_ptr(ifaddr.ifa_name) }.to_bytes()
+                != interface.as_bytes()
+        {
+            continue;
+        }
+        match unsafe { (*ifaddr.ifa_addr).sa_family } as libc::c_int {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+                addrs.push(SocketAddr::new(ip.into(), port));
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+                addrs.push(SocketAddrV6::new(ip, port, 0, sin6.sin6_scope_id).into());
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+/// Listening on the addresses of an interface is only supported on Unix.
+#[cfg(not(unix))]
+pub fn addresses(interface: &str, _port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("Can't list the addresses of interface {}", interface),
+    ))
+}
+
+/// Serves the port forward on each address of its interface, binding the addresses that appear and closing
+/// the listeners of those that disappear.
+#[allow(clippy::too_many_arguments)]
+pub async fn interface_server(
+    forward_id: ForwardId,
+    port_forward: Arc<PortForwardConfig>,
+    listeners: Vec<Listener>,
+    tcp_port_pool: TcpPortPool,
+    udp_port_pool: UdpPortPool,
+    resolver: Arc<TunnelResolver>,
+    bus: Bus,
+    stats: Arc<ForwardStats>,
+) -> anyhow::Result<()> {
+    let interface = port_forward.interface.clone().unwrap_or_default();
+    let port = port_forward.source.port();
+    let serve = |listener: Listener| {
+        let port_forward = port_forward.clone();
+        let (resolver, bus, stats) = (resolver.clone(), bus.clone(), stats.clone());
+        match listener {
+            Listener::Tcp(listeners) => tokio::spawn(tcp::tcp_proxy_server(
+                port_forward,
+                listeners,
+                tcp_port_pool.clone(),
+                resolver,
+                bus,
+                stats,
+            )),
+            Listener::Udp(socket) => tokio::spawn(udp::udp_proxy_server(
+                port_forward,
+                socket,
+                udp_port_pool.clone(),
+                resolver,
+                bus,
+                stats,
+            )),
+            _ => unreachable!("interface addresses are bound to sockets"),
+        }
+    };
+
+    let mut servers = Servers::default();
+    for listener in listeners {
+        if let Some(&addr) = listener.local_addrs()?.first() {
+            servers.0.insert(addr.ip(), (addr, serve(listener)));
+        }
+    }
+
+    loop {
+        tokio::time::sleep(ADDRESSES_POLL_INTERVAL).await;
+        let addrs = match addresses(&interface, port) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!(
+                    "[{}] Failed to list the addresses of interface {}: {}",
+                    forward_id, interface, e
+                );
+                continue;
+            }
+        };
+
+        servers.0.retain(|ip, (addr, server)| {
+            let kept = addrs.iter().any(|a| a.ip() == *ip);
+            if !kept {
+                info!(
+                    "[{}] Address {} removed from interface {}, closing its listener",
+                    forward_id, addr, interface
+                );
+                server.abort();
+            }
+            kept
+        });
+        for addr in addrs {
+            if servers.0.contains_key(&addr.ip()) {
+                continue;
+            }
+            match Listener::bind(&port_forward, addr).await {
+                Ok(listener) => {
+                    info!(
+                        "[{}] Address {} added to interface {}, listening on it",
+                        forward_id, addr, interface
+                    );
+                    servers.0.insert(addr.ip(), (addr, serve(listener)));
+                }
+                Err(e) => {
+                    // Tried again on the next poll
+                    warn!(
+                        "[{}] Failed to bind on {} of interface {}: {}",
+                        forward_id, addr, interface, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The servers of the interface addresses, by IP. They are aborted when the port forward stops.
+#[derive(Default)]
+struct Servers(HashMap<IpAddr, (SocketAddr, JoinHandle<anyhow::Result<()>>)>);
+
+impl Drop for Servers {
+    fn drop(&mut self) {
+        for (_, server) in self.0.values() {
+            server.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_loopback_addresses() {
+        use std::net::Ipv4Addr;
+
+        let addrs = addresses("lo", 8080).unwrap();
+        assert!(addrs.contains(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080)));
+        assert!(addresses("nonexistent0", 8080).unwrap().is_empty());
+    }
+}
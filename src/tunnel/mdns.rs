@@ -0,0 +1,182 @@
+//! mDNS reflector: makes the services of the peer network (AirPlay, printers, ...) discoverable on the
+//! local network, and the other way around.
+//!
+//! The mDNS datagrams received locally are sent to the mDNS group of the peer network, from a virtual
+//! port. Since that port is not 5353, responders answer them with unicast "legacy" responses (RFC 6762,
+//! section 6.7), which are reflected back to the local mDNS group. Announcements multicast on the peer
+//! network are reflected as well, through the relay of the virtual UDP interface.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::config::{DatagramRelay, PortForwardConfig, PortProtocol};
+use crate::events::{Bus, Event};
+use crate::tunnel::udp::UdpPortPool;
+use crate::virtual_iface::VirtualPort;
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const MAX_PACKET: usize = 9000;
+const DNS_HEADER_LEN: usize = 12;
+
+/// How long a reflected datagram is recognized when it loops back to the reflector.
+const LOOPBACK_WINDOW: Duration = Duration::from_secs(1);
+
+/// The UDP port forward carrying the mDNS datagrams, whose multicast relay receives the announcements
+/// of the peer network.
+pub fn port_forward() -> PortForwardConfig {
+    let mut port_forward = PortForwardConfig::new(
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)),
+        SocketAddr::from((MDNS_GROUP, MDNS_PORT)),
+        PortProtocol::Udp,
+    );
+    port_forward.name = Some(Arc::from("mdns"));
+    port_forward.relay = Some(DatagramRelay::Multicast);
+    port_forward
+}
+
+/// Reflects mDNS datagrams between the local network and the peer network, until killed.
+pub async fn mdns_reflector(port_pool: UdpPortPool, bus: Bus) -> anyhow::Result<()> {
+    let port_forward = Arc::new(port_forward());
+    let socket = bind_mdns_socket().with_context(|| "Failed to bind on the mDNS port")?;
+    info!(
+        "[mdns] Reflecting mDNS between the local network and [{}]",
+        port_forward.destination
+    );
+
+    let mut endpoint = bus.new_endpoint();
+    // The virtual ports assigned to local mDNS senders
+    let mut virtual_ports: HashSet<VirtualPort> = HashSet::new();
+    let mut reflected = RecentPackets::default();
+    let mut buffer = [0u8; MAX_PACKET];
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                let (size, peer_addr) = received.with_context(|| "Failed to read mDNS datagram")?;
+                let data = &buffer[..size];
+                if size < DNS_HEADER_LEN || reflected.contains(data) {
+                    continue;
+                }
+                let virtual_port = match port_pool.next(peer_addr, port_forward.session_mode).await {
+                    Ok(port) => port,
+                    Err(e) => {
+                        error!("[mdns] Failed to assign virtual port for [{}]: {:?}", peer_addr, e);
+                        continue;
+                    }
+                };
+                port_pool.update_last_transmit(virtual_port).await;
+                virtual_ports.insert(virtual_port);
+                trace!("[mdns] Reflecting {} bytes from {} to the peer network", size, peer_addr);
+                endpoint.send(Event::LocalData(port_forward.clone(), virtual_port, data.to_vec(), Instant::now()));
+            }
+            event = endpoint.recv() => {
+                let (peer, mut data) = match event {
+                    Event::RemoteData(virtual_port, data, _) if virtual_ports.contains(&virtual_port) => {
+                        match port_pool.get_peer_addr(virtual_port).await {
+                            Some(peer) => (peer, data),
+                            None => continue,
+                        }
+                    }
+                    Event::RemoteRelayData(destination, data) if destination == port_forward.destination => {
+                        (SocketAddr::from((MDNS_GROUP, MDNS_PORT)), data)
+                    }
+                    _ => continue,
+                };
+                if data.len() < DNS_HEADER_LEN {
+                    continue;
+                }
+                // Local mDNS queriers get multicast responses, with a zero ID. Legacy queriers get the
+                // unicast response as is.
+                let target = if peer.port() == MDNS_PORT {
+                    data[..2].copy_from_slice(&[0, 0]);
+                    reflected.insert(&data);
+                    SocketAddr::from((MDNS_GROUP, MDNS_PORT))
+                } else {
+                    peer
+                };
+                trace!("[mdns] Reflecting {} bytes from the peer network to {}", data.len(), target);
+                if let Err(e) = socket.send_to(&data, target).await {
+                    error!("[mdns] Failed to reflect mDNS datagram to {}: {:?}", target, e);
+                }
+            }
+        }
+    }
+}
+
+/// Binds the mDNS port on all interfaces, sharing it with the system's mDNS responder (such as Avahi
+/// or mDNSResponder), and joins the mDNS group.
+fn bind_mdns_socket() -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SockAddr::from(SocketAddr::from((
+        IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        MDNS_PORT,
+    ))))?;
+    socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// The datagrams recently reflected to the local mDNS group, which loop back to the reflector and must
+/// not be reflected again.
+#[derive(Default)]
+struct RecentPackets {
+    packets: VecDeque<(Instant, u64)>,
+}
+
+impl RecentPackets {
+    fn insert(&mut self, data: &[u8]) {
+        self.expire();
+        self.packets.push_back((Instant::now(), Self::hash(data)));
+    }
+
+    fn contains(&mut self, data: &[u8]) -> bool {
+        self.expire();
+        let hash = Self::hash(data);
+        self.packets.iter().any(|(_, h)| *h == hash)
+    }
+
+    fn expire(&mut self) {
+        while let Some((at, _)) = self.packets.front() {
+            if at.elapsed() < LOOPBACK_WINDOW {
+                break;
+            }
+            self.packets.pop_front();
+        }
+    }
+
+    fn hash(data: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_packets() {
+        let mut recent = RecentPackets::default();
+        recent.insert(b"announcement");
+        assert!(recent.contains(b"announcement"));
+        assert!(!recent.contains(b"query"));
+
+        recent.packets[0].0 -= LOOPBACK_WINDOW;
+        assert!(!recent.contains(b"announcement"));
+        assert!(recent.packets.is_empty());
+    }
+}
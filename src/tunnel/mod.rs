@@ -1,80 +1,786 @@
-use std::net::IpAddr;
-use std::sync::Arc;
+use std::collections::{HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tokio::sync::broadcast;
+use anyhow::Context;
+use rand::{thread_rng, Rng};
+use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+use tokio::sync::{broadcast, watch};
 
-use crate::config::{PortForwardConfig, PortProtocol};
-use crate::events::Bus;
+use crate::config::{BindPolicy, ForwardId, PortForwardConfig, PortProtocol};
+use crate::connection_authorizer::ConnectionAuthorizer;
+use crate::error::OnetunError;
+use crate::events::{Bus, DropReason, Event};
+use crate::fragmentation::MAX_IPV4_PACKET;
+use crate::tunnel::dns::TunnelResolver;
 use crate::tunnel::tcp::TcpPortPool;
 use crate::tunnel::udp::UdpPortPool;
+use crate::virtual_iface::VirtualPort;
 use crate::wg::WireGuardTunnel;
 
+pub mod dns;
+mod interface;
+pub mod mdns;
 pub mod tcp;
+pub mod tcp_options;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod transform;
 pub mod udp;
 
+/// How often each port forward reports its byte counters on the bus.
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a port forward being removed reports its remaining sessions on the bus.
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The first delay before binding again, with `BindPolicy::Retry`. It doubles after each attempt.
+const BIND_RETRY_MIN_DELAY: Duration = Duration::from_millis(100);
+/// The longest delay before binding again, with `BindPolicy::Retry`.
+const BIND_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+/// How many connections can wait to be accepted by a TCP port forward, by default (as `TcpListener::bind`).
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+/// How often a port forward warns about the oversized datagrams of its clients, at most.
+const OVERSIZED_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// How many of the ports released last are skipped when assigning a random virtual port, so that the peers have
+/// forgotten the previous connection of a port (such as in TCP's TIME-WAIT) when it is assigned again.
+const RECENTLY_RELEASED_PORTS: usize = 1024;
+
+/// The socket a local port forward listens on.
+#[derive(Debug)]
+pub enum Listener {
+    /// The sockets of a TCP port forward, all on the same address: one per shard (see
+    /// `PortForwardConfig::listeners`).
+    Tcp(Vec<TcpListener>),
+    Udp(UdpSocket),
+    /// The first instance of the named pipe of the port forward (see `PortForwardConfig::pipe`).
+    #[cfg(windows)]
+    Pipe(tokio::net::windows::named_pipe::NamedPipeServer),
+    /// A socket on each address of the interface of the port forward (see `PortForwardConfig::interface`),
+    /// when it started. The addresses that appear later are bound by the port forward.
+    Interface(Vec<Listener>),
+}
+
+impl Listener {
+    /// The addresses listened on. None for a named pipe.
+    pub fn local_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+        match self {
+            Self::Tcp(listeners) => listeners
+                .iter()
+                .take(1)
+                .map(|listener| listener.local_addr())
+                .collect(),
+            Self::Udp(socket) => Ok(vec![socket.local_addr()?]),
+            #[cfg(windows)]
+            Self::Pipe(_) => Ok(vec![]),
+            Self::Interface(listeners) => {
+                let mut addrs = Vec::new();
+                for listener in listeners {
+                    addrs.extend(listener.local_addrs()?);
+                }
+                Ok(addrs)
+            }
+        }
+    }
+
+    async fn bind(port_forward: &PortForwardConfig, addr: SocketAddr) -> std::io::Result<Self> {
+        if let Some(pipe) = &port_forward.pipe {
+            return Self::bind_pipe(pipe);
+        }
+        match port_forward.protocol {
+            PortProtocol::Tcp => {
+                let mut listeners = vec![Self::bind_tcp(port_forward, addr)?];
+                // The other shards share the port of the first, which may be ephemeral
+                let addr = listeners[0].local_addr()?;
+                for _ in 1..port_forward.listeners {
+                    listeners.push(Self::bind_tcp(port_forward, addr)?);
+                }
+                Ok(Self::Tcp(listeners))
+            }
+            PortProtocol::Udp => Ok(Self::Udp(UdpSocket::bind(addr).await?)),
+        }
+    }
+
+    /// Binds a TCP socket with the listener options of the port forward.
+    fn bind_tcp(
+        port_forward: &PortForwardConfig,
+        addr: SocketAddr,
+    ) -> std::io::Result<TcpListener> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        // As `TcpListener::bind`, so that a restart doesn't wait for the connections in TIME_WAIT
+        #[cfg(unix)]
+        socket.set_reuseaddr(true)?;
+        let sharded = port_forward.listeners > 1;
+        if sharded && cfg!(not(any(target_os = "linux", target_os = "android"))) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "sharded listeners are only supported on Linux, whose SO_REUSEPORT spreads the connections",
+            ));
+        }
+        if port_forward.reuse_port || sharded {
+            #[cfg(unix)]
+            socket.set_reuseport(true)?;
+            #[cfg(not(unix))]
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "reuse-port is only supported on Unix",
+            ));
+        }
+        socket.bind(addr)?;
+        if let Some(queue) = port_forward.fast_open {
+            set_fast_open(&socket, queue)?;
+        }
+        let backlog = port_forward
+            .listen_backlog
+            .unwrap_or(DEFAULT_LISTEN_BACKLOG);
+        socket.listen(backlog)
+    }
+
+    #[cfg(windows)]
+    fn bind_pipe(pipe: &str) -> std::io::Result<Self> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        // Fails if another process already serves the pipe, like a busy port
+        let server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(pipe)?;
+        Ok(Self::Pipe(server))
+    }
+
+    #[cfg(not(windows))]
+    fn bind_pipe(_pipe: &str) -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "named pipes are only supported on Windows",
+        ))
+    }
+}
+
+/// Lets the listening socket accept data in the SYN of its clients, with TCP Fast Open.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn set_fast_open(socket: &TcpSocket, queue: u32) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Apple systems take a flag rather than the length of the queue
+    let value: libc::c_int = if cfg!(target_vendor = "apple") {
+        1
+    } else {
+        queue.min(libc::c_int::MAX as u32) as libc::c_int
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+fn set_fast_open(_socket: &TcpSocket, _queue: u32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "fast-open is only supported on Linux, Android, macOS and iOS",
+    ))
+}
+
+/// Binds the socket of the local port forward on its source address, or on each address of its interface,
+/// following the policy when it can't.
+pub async fn bind(
+    forward_id: &ForwardId,
+    port_forward: &PortForwardConfig,
+    policy: BindPolicy,
+) -> Result<Listener, OnetunError> {
+    let interface = match &port_forward.interface {
+        Some(interface) => interface,
+        None => return bind_addr(forward_id, port_forward, port_forward.source, policy).await,
+    };
+    let addrs = interface::addresses(interface, port_forward.source.port()).map_err(|source| {
+        OnetunError::BindFailed {
+            addr: port_forward.source,
+            source,
+        }
+    })?;
+    if addrs.is_empty() {
+        warn!(
+            "[{}] Interface {} has no address yet",
+            forward_id, interface
+        );
+    }
+    let mut listeners = Vec::new();
+    for addr in addrs {
+        listeners.push(bind_addr(forward_id, port_forward, addr, policy).await?);
+    }
+    Ok(Listener::Interface(listeners))
+}
+
+/// Binds the socket of the local port forward on the address, following the policy when it can't.
+async fn bind_addr(
+    forward_id: &ForwardId,
+    port_forward: &PortForwardConfig,
+    addr: SocketAddr,
+    policy: BindPolicy,
+) -> Result<Listener, OnetunError> {
+    let name = match port_forward.pipe {
+        Some(_) => port_forward.source_name(),
+        None => addr.to_string(),
+    };
+    let started = Instant::now();
+    let mut delay = BIND_RETRY_MIN_DELAY;
+    loop {
+        let e = match Listener::bind(port_forward, addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) => e,
+        };
+        match policy {
+            BindPolicy::Retry(timeout) if started.elapsed() + delay <= timeout => {
+                warn!(
+                    "[{}] Failed to bind on {}: {}. Retrying in {:?}",
+                    forward_id, name, e, delay
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(BIND_RETRY_MAX_DELAY);
+            }
+            BindPolicy::Ephemeral if addr.port() != 0 => {
+                let mut ephemeral = addr;
+                ephemeral.set_port(0);
+                let listener = Listener::bind(port_forward, ephemeral)
+                    .await
+                    .map_err(|source| OnetunError::BindFailed {
+                        addr: ephemeral,
+                        source,
+                    })?;
+                let listening =
+                    listener
+                        .local_addrs()
+                        .map_err(|source| OnetunError::BindFailed {
+                            addr: ephemeral,
+                            source,
+                        })?;
+                warn!(
+                    "[{}] Failed to bind on {}: {}. Listening on {} instead",
+                    forward_id,
+                    name,
+                    e,
+                    listening.first().unwrap_or(&ephemeral)
+                );
+                return Ok(listener);
+            }
+            _ => return Err(OnetunError::BindFailed { addr, source: e }),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn port_forward(
+    forward_id: ForwardId,
     port_forward: PortForwardConfig,
+    listener: Listener,
     source_peer_ip: IpAddr,
     tcp_port_pool: TcpPortPool,
     udp_port_pool: UdpPortPool,
     wg: Arc<WireGuardTunnel>,
+    resolver: Arc<TunnelResolver>,
+    authorizer: Option<Arc<dyn ConnectionAuthorizer>>,
     bus: Bus,
+    mut removal: watch::Receiver<Option<Duration>>,
     mut kill_switch: broadcast::Receiver<()>,
 ) -> anyhow::Result<()> {
     info!(
-        "Tunneling {} [{}]->[{}] (via [{}] as peer {})",
+        "[{}] Tunneling {} [{}]->[{}] (via [{}] as peer {})",
+        forward_id,
         port_forward.protocol,
-        port_forward.source,
-        port_forward.destination,
-        &wg.endpoint,
+        match listener.local_addrs()?[..] {
+            [addr] if port_forward.interface.is_none() => addr.to_string(),
+            _ => port_forward.source_name(),
+        },
+        port_forward.destination_name(),
+        wg.endpoint(),
         source_peer_ip
     );
 
-    match port_forward.protocol {
-        PortProtocol::Tcp => {
-            tokio::select! {
-                x = tcp::tcp_proxy_server(port_forward, tcp_port_pool, bus) => x,
-                _ = kill_switch.recv() => {
-                    info!("Port forwarder has been murdered");
-                    Ok(())
-                }
+    let port_forward = Arc::new(port_forward);
+    let source = port_forward.source;
+    let sessions_pool = udp_port_pool.clone();
+    let mut stats = ForwardStats::with_authorizer(authorizer);
+    if port_forward.protocol == PortProtocol::Udp {
+        stats = stats.with_datagram_limit(DatagramLimit::new(
+            forward_id.clone(),
+            &port_forward,
+            wg.mtu(),
+        ));
+    }
+    let stats = Arc::new(stats);
+    stats.set_awaiting_handshake(wg.awaits_handshake());
+    let server = async {
+        match listener {
+            Listener::Tcp(listeners) => {
+                tcp::tcp_proxy_server(
+                    port_forward,
+                    listeners,
+                    tcp_port_pool,
+                    resolver,
+                    bus.clone(),
+                    stats.clone(),
+                )
+                .await
+            }
+            Listener::Udp(socket) => {
+                udp::udp_proxy_server(
+                    port_forward,
+                    socket,
+                    udp_port_pool,
+                    resolver,
+                    bus.clone(),
+                    stats.clone(),
+                )
+                .await
+            }
+            Listener::Interface(listeners) => {
+                interface::interface_server(
+                    forward_id.clone(),
+                    port_forward,
+                    listeners,
+                    tcp_port_pool,
+                    udp_port_pool,
+                    resolver,
+                    bus.clone(),
+                    stats.clone(),
+                )
+                .await
+            }
+            #[cfg(windows)]
+            Listener::Pipe(server) => {
+                tcp::pipe_proxy_server(
+                    port_forward,
+                    server,
+                    tcp_port_pool,
+                    resolver,
+                    bus.clone(),
+                    stats.clone(),
+                )
+                .await
             }
         }
-        PortProtocol::Udp => {
-            tokio::select! {
-                x = udp::udp_proxy_server(port_forward, udp_port_pool, bus) => x,
-                _ = kill_switch.recv() => {
-                    info!("Port forwarder has been murdered");
-                    Ok(())
-                }
+    };
+
+    tokio::pin!(server);
+    let reporting = {
+        let reporting = report_stats(forward_id.clone(), stats.clone(), bus.clone());
+        let (wg, stats) = (wg.clone(), stats.clone());
+        let verification = async move {
+            if wg.awaits_handshake() {
+                wg.verification().await;
+            }
+            stats.set_awaiting_handshake(false);
+        };
+        async move { tokio::join!(reporting, verification) }
+    };
+    tokio::pin!(reporting);
+
+    let grace = tokio::select! {
+        x = &mut server => return x,
+        _ = &mut reporting => return Ok(()),
+        grace = removed(&mut removal) => grace,
+        _ = kill_switch.recv() => {
+            info!("Port forwarder has been murdered");
+            return Ok(());
+        }
+    };
+
+    // Turn the new clients away, and let the open sessions finish within the grace period
+    info!(
+        "[{}] Removing port forward, draining its sessions for up to {:?}",
+        forward_id, grace
+    );
+    stats.start_draining();
+    let sender = bus.new_endpoint().sender();
+    let drain = async {
+        loop {
+            let sessions =
+                stats.connections().len() + sessions_pool.active_sessions_of_forward(source).await;
+            sender.send(Event::ForwardDraining(forward_id.clone(), sessions));
+            if sessions == 0 {
+                return;
             }
+            tokio::time::sleep(DRAIN_INTERVAL).await;
+        }
+    };
+    tokio::select! {
+        x = &mut server => x?,
+        _ = &mut reporting => {}
+        _ = tokio::time::timeout(grace, drain) => {}
+        _ = kill_switch.recv() => return Ok(()),
+    }
+
+    // Close the sessions still open. The UDP sessions end with the server
+    let connections = stats.connections();
+    for virtual_port in connections.iter() {
+        sender.send(Event::ClientConnectionDropped(
+            *virtual_port,
+            DropReason::ForwardRemoved,
+            Instant::now(),
+        ));
+    }
+    let closed = connections.len() + sessions_pool.active_sessions_of_forward(source).await;
+    if closed > 0 {
+        warn!(
+            "[{}] Port forward removed, closing {} session(s) still open",
+            forward_id, closed
+        );
+    } else {
+        info!("[{}] Port forward removed", forward_id);
+    }
+    sender.send(Event::ForwardRemoved(forward_id, closed));
+    Ok(())
+}
+
+/// Waits for the removal of the port forward, and returns the grace period of its sessions.
+async fn removed(removal: &mut watch::Receiver<Option<Duration>>) -> Duration {
+    loop {
+        if let Some(grace) = *removal.borrow() {
+            return grace;
+        }
+        if removal.changed().await.is_err() {
+            // The port forward can no longer be removed
+            std::future::pending::<()>().await;
         }
     }
 }
 
 pub async fn remote_port_forward(
+    forward_id: ForwardId,
     port_forward: PortForwardConfig,
-    _tcp_port_pool: TcpPortPool,
+    tcp_port_pool: TcpPortPool,
     udp_port_pool: UdpPortPool,
     wg: Arc<WireGuardTunnel>,
     bus: Bus,
     mut kill_switch: broadcast::Receiver<()>,
 ) -> anyhow::Result<()> {
     info!(
-        "Remote Tunneling {} [{}]<-[{}] (via [{}])",
-        port_forward.protocol, port_forward.destination, port_forward.source, &wg.endpoint,
+        "[{}] Remote Tunneling {} [{}]<-[{}] (via [{}])",
+        forward_id,
+        port_forward.protocol,
+        port_forward.destination,
+        port_forward.source,
+        wg.endpoint(),
     );
 
+    let port_forward = Arc::new(port_forward);
+    let mut stats = ForwardStats::default();
+    if port_forward.protocol == PortProtocol::Udp {
+        stats = stats.with_datagram_limit(DatagramLimit::new(
+            forward_id.clone(),
+            &port_forward,
+            wg.mtu(),
+        ));
+    }
+    let stats = Arc::new(stats);
     match port_forward.protocol {
-        PortProtocol::Tcp => Ok(()), // TODO: Remote TCP forwarding
+        PortProtocol::Tcp => {
+            tokio::select! {
+                x = tcp::remote_tcp_proxy_server(port_forward, tcp_port_pool, bus.clone(), stats.clone()) => x,
+                _ = report_stats(forward_id.clone(), stats.clone(), bus.clone()) => Ok(()),
+                _ = kill_switch.recv() => {
+                    info!("[{}] Port forwarder has been murdered", forward_id);
+                    Ok(())
+                }
+            }
+        }
         PortProtocol::Udp => {
+            // Remote port forwards send datagrams to their destination from any local port
+            let bind = match port_forward.source.ip() {
+                IpAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                IpAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+            };
+            let socket = UdpSocket::bind(bind)
+                .await
+                .with_context(|| "Failed to bind on UDP proxy address")?;
             tokio::select! {
-                x = udp::udp_proxy_server(port_forward, udp_port_pool, bus) => x,
+                x = udp::udp_proxy_server(port_forward, socket, udp_port_pool, Arc::default(), bus.clone(), stats.clone()) => x,
+                _ = report_stats(forward_id.clone(), stats.clone(), bus.clone()) => Ok(()),
                 _ = kill_switch.recv() => {
-                    info!("Port forwarder has been murdered");
+                    info!("[{}] Port forwarder has been murdered", forward_id);
                     Ok(())
                 }
             }
         }
     }
 }
+
+/// The occupancy and allocation counters of a pool of virtual ports (see `Handle::port_pool_stats`).
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct PortPoolStats {
+    /// How many virtual ports the pool has.
+    pub capacity: usize,
+    /// How many virtual ports are assigned to clients.
+    pub in_use: usize,
+    /// How many virtual ports were assigned since the tunnel started.
+    pub allocations: u64,
+    /// How many times a client needed a virtual port while all of them were in use.
+    pub exhaustions: u64,
+    /// How many active UDP sessions lost their virtual port to a new client (see `PortPoolExhaustion`).
+    pub evictions: u64,
+}
+
+/// Takes a free port out of the queue of a pool of virtual ports, where the released ports are put back at the end:
+/// the first one if the ports are sequential, or else a random one among those not released recently.
+fn take_free_port(queue: &mut VecDeque<u16>, sequential: bool) -> Option<u16> {
+    if sequential || queue.is_empty() {
+        return queue.pop_front();
+    }
+    let candidates = queue.len().saturating_sub(RECENTLY_RELEASED_PORTS).max(1);
+    // The port taken is replaced with the first one, leaving the ports released recently at the end
+    queue.swap_remove_front(thread_rng().gen_range(0..candidates))
+}
+
+/// Byte counters of a port forward, and the state its servers share.
+#[derive(Debug, Default)]
+pub struct ForwardStats {
+    /// Bytes received from local clients and sent through the tunnel.
+    tx: AtomicU64,
+    /// Bytes received through the tunnel and sent to local clients.
+    rx: AtomicU64,
+    /// The virtual ports of the open TCP connections.
+    connections: Mutex<HashSet<VirtualPort>>,
+    /// Whether the port forward is being removed, and turns new clients away.
+    draining: AtomicBool,
+    /// Whether the port forward turns clients away until the first handshake (see `Config::with_require_handshake`).
+    awaiting_handshake: AtomicBool,
+    /// Asked whether to serve each new client, if any (see `Config::with_connection_authorizer`).
+    authorizer: Option<Arc<dyn ConnectionAuthorizer>>,
+    /// The largest datagram the clients may send through the tunnel, on UDP port forwards.
+    datagram_limit: Option<DatagramLimit>,
+    /// How many datagrams of the clients were dropped for exceeding `datagram_limit`.
+    oversized: AtomicU64,
+    /// When the oversized datagrams were last reported, and how many were dropped since.
+    oversized_report: Mutex<(Option<Instant>, u64)>,
+}
+
+impl ForwardStats {
+    pub fn with_authorizer(authorizer: Option<Arc<dyn ConnectionAuthorizer>>) -> Self {
+        Self {
+            authorizer,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_datagram_limit(self, limit: DatagramLimit) -> Self {
+        Self {
+            datagram_limit: Some(limit),
+            ..self
+        }
+    }
+
+    pub fn record_tx(&self, bytes: usize) {
+        self.tx.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_rx(&self, bytes: usize) {
+        self.rx.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn tx(&self) -> u64 {
+        self.tx.load(Ordering::Relaxed)
+    }
+
+    pub fn rx(&self) -> u64 {
+        self.rx.load(Ordering::Relaxed)
+    }
+
+    pub fn open_connection(&self, virtual_port: VirtualPort) {
+        self.connections.lock().unwrap().insert(virtual_port);
+    }
+
+    pub fn close_connection(&self, virtual_port: VirtualPort) {
+        self.connections.lock().unwrap().remove(&virtual_port);
+    }
+
+    pub fn connections(&self) -> Vec<VirtualPort> {
+        self.connections.lock().unwrap().iter().copied().collect()
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_awaiting_handshake(&self, awaiting: bool) {
+        self.awaiting_handshake.store(awaiting, Ordering::Relaxed);
+    }
+
+    /// Whether the client may use the port forward.
+    pub fn authorizes(&self, port_forward: &PortForwardConfig, client: SocketAddr) -> bool {
+        self.authorizer.as_ref().map_or(true, |authorizer| {
+            authorizer.authorize(port_forward, client)
+        })
+    }
+
+    /// Whether a datagram of the given size may be sent through the tunnel. Oversized datagrams are counted,
+    /// and reported at most every `OVERSIZED_LOG_INTERVAL`, instead of failing silently in the virtual interface.
+    pub fn admits_datagram(&self, size: usize) -> bool {
+        let limit = match &self.datagram_limit {
+            Some(limit) if size > limit.max => limit,
+            _ => return true,
+        };
+        self.oversized.fetch_add(1, Ordering::Relaxed);
+        let mut report = self.oversized_report.lock().unwrap();
+        report.1 += 1;
+        let now = Instant::now();
+        if report.0.map_or(true, |last| {
+            now.duration_since(last) >= OVERSIZED_LOG_INTERVAL
+        }) {
+            warn!(
+                "[{}] Dropped {} datagram(s) of local clients larger than {} bytes (the last one of {} bytes). \
+                Lower the MTU or the datagram size of the clients, or raise the max-message-size or \
+                udp-buffer-size of the port forward, or --mtu (now {}) for IPv6, which isn't fragmented",
+                limit.forward_id, report.1, limit.max, size, limit.mtu
+            );
+            *report = (Some(now), 0);
+        }
+        false
+    }
+
+    /// How many datagrams of the clients were dropped for exceeding the size limit of the port forward.
+    pub fn oversized(&self) -> u64 {
+        self.oversized.load(Ordering::Relaxed)
+    }
+
+    /// Why new clients are turned away, if they are.
+    pub fn refusal(&self) -> Option<&'static str> {
+        if self.is_draining() {
+            Some("the port forward is being removed")
+        } else if self.awaiting_handshake.load(Ordering::Relaxed) {
+            Some("no handshake with the WireGuard endpoint yet")
+        } else {
+            None
+        }
+    }
+}
+
+/// The size limit of the datagrams sent through the tunnel by the clients of a UDP port forward.
+#[derive(Debug)]
+pub struct DatagramLimit {
+    /// The port forward, named in the warnings.
+    forward_id: ForwardId,
+    /// The largest payload, in bytes.
+    max: usize,
+    /// The MTU of the virtual interface, which limits the IPv6 datagrams.
+    mtu: usize,
+}
+
+impl DatagramLimit {
+    /// The limit of the port forward: the largest datagram the virtual interface can send, or its
+    /// `max-message-size` or UDP buffer size if lower. IPv4 datagrams are fragmented up to the largest IPv4
+    /// packet, while IPv6 ones must fit in an IP packet of the MTU.
+    pub fn new(forward_id: ForwardId, port_forward: &PortForwardConfig, mtu: usize) -> Self {
+        // Remote port forwards send from their source
+        let ip = if port_forward.remote {
+            port_forward.source.ip()
+        } else {
+            port_forward.destination.ip()
+        };
+        let largest = match ip {
+            IpAddr::V4(_) => MAX_IPV4_PACKET - 20 - 8,
+            IpAddr::V6(_) => mtu.saturating_sub(40 + 8),
+        };
+        let max = [port_forward.max_message_size, port_forward.udp_buffer_size]
+            .iter()
+            .flatten()
+            .fold(largest, |max, limit| max.min(*limit));
+        Self {
+            forward_id,
+            max,
+            mtu,
+        }
+    }
+}
+
+/// Periodically sends the byte counters of a port forward on the bus.
+async fn report_stats(forward_id: ForwardId, stats: Arc<ForwardStats>, bus: Bus) {
+    let sender = bus.new_endpoint().sender();
+    let mut interval = tokio::time::interval(STATS_INTERVAL);
+    loop {
+        interval.tick().await;
+        sender.send(Event::ForwardStats(
+            forward_id.clone(),
+            stats.tx(),
+            stats.rx(),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datagram_limit() {
+        let limit = |notation: &str| {
+            let port_forward = PortForwardConfig::from_notation(notation, "127.0.0.1")
+                .unwrap()
+                .remove(0);
+            DatagramLimit::new(ForwardId::Index(0), &port_forward, 1420)
+        };
+        assert_eq!(limit("53:192.168.4.1:53:UDP").max, 65507);
+        assert_eq!(limit("53:[fd00::1]:53:UDP").max, 1372);
+        assert_eq!(limit("53:192.168.4.1:53:UDP;max-message-size=512").max, 512);
+        assert_eq!(
+            limit("53:192.168.4.1:53:UDP;udp-buffer-size=4096").max,
+            4096
+        );
+        assert_eq!(limit("53:[fd00::1]:53:UDP;max-message-size=9000").max, 1372);
+
+        let stats = ForwardStats::default()
+            .with_datagram_limit(limit("53:192.168.4.1:53:UDP;max-message-size=1200"));
+        assert!(stats.admits_datagram(1200));
+        assert!(!stats.admits_datagram(1201));
+        assert!(!stats.admits_datagram(4096));
+        assert_eq!(stats.oversized(), 2);
+        assert!(ForwardStats::default().admits_datagram(65507));
+    }
+
+    #[test]
+    fn test_take_free_port() {
+        let mut queue: VecDeque<u16> = (1000..3000).collect();
+        assert_eq!(take_free_port(&mut queue, true), Some(1000));
+        assert_eq!(take_free_port(&mut queue, true), Some(1001));
+
+        // The ports released last are not taken while others are free
+        let taken: HashSet<u16> = (0..500)
+            .filter_map(|_| take_free_port(&mut queue, false))
+            .collect();
+        assert_eq!(taken.len(), 500);
+        assert!(taken
+            .iter()
+            .all(|port| *port < 3000 - RECENTLY_RELEASED_PORTS as u16));
+        assert_ne!(taken, (1002..1502).collect());
+
+        let mut queue = VecDeque::from(vec![42]);
+        assert_eq!(take_free_port(&mut queue, false), Some(42));
+        assert_eq!(take_free_port(&mut queue, false), None);
+    }
+}
@@ -1,131 +1,660 @@
-use crate::config::{PortForwardConfig, PortProtocol};
+use crate::config::{DestinationSelection, MirrorDirection, PortForwardConfig, PortProtocol};
 use crate::virtual_iface::VirtualPort;
 use anyhow::Context;
-use std::collections::VecDeque;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::net::{TcpListener, TcpStream};
 
 use std::ops::Range;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::events::{Bus, Event};
+use crate::events::{Bus, BusEndpoint, BusSender, DropReason, Event};
+use crate::tunnel::dns::TunnelResolver;
+use crate::tunnel::tcp_options::{DiagnosedStream, TcpOptionDiagnostics};
+#[cfg(feature = "tls")]
+use crate::tunnel::tls::TlsLayer;
+use crate::tunnel::transform::new_transform;
+use crate::tunnel::{take_free_port, ForwardStats, PortPoolStats};
+use futures::FutureExt;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 
 const MAX_PACKET: usize = 65536;
+/// The most data from the local client coalesced into one `LocalData` event: the reads that complete right
+/// away after one are appended to it, so that a bulk transfer takes fewer events and wakeups.
+const COALESCE_LIMIT: usize = MAX_PACKET;
 const MIN_PORT: u16 = 1000;
 const MAX_PORT: u16 = 60999;
 const PORT_RANGE: Range<u16> = MIN_PORT..MAX_PORT;
 
-/// Starts the server that listens on TCP connections.
+/// How long to wait for a virtual connection to a destination with failover to be established.
+const FAILOVER_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a destination that failed is only tried after the others.
+const FAILOVER_PENALTY: Duration = Duration::from_secs(30);
+
+/// How long to wait for the mirror connection of a connection to be established.
+const MIRROR_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many chunks of data can wait to be copied to the mirror of a connection. A mirror that falls this far
+/// behind is closed, rather than slowing down the connection it copies.
+const MIRROR_QUEUE: usize = 256;
+
+/// The interval over which the time a local client keeps its connection waiting is measured.
+const DRAIN_WINDOW: Duration = Duration::from_secs(5);
+/// The share of a window, in percent, a local client must keep its connection waiting to be a slow consumer.
+const SLOW_CONSUMER_PERCENT: u32 = 50;
+
+/// Starts the server that listens on TCP connections. The listeners after the first are the shards of the
+/// port forward (see `PortForwardConfig::listeners`), which accept on their own tasks.
 pub async fn tcp_proxy_server(
-    port_forward: PortForwardConfig,
+    port_forward: Arc<PortForwardConfig>,
+    listeners: Vec<TcpListener>,
     port_pool: TcpPortPool,
+    resolver: Arc<TunnelResolver>,
     bus: Bus,
+    stats: Arc<ForwardStats>,
 ) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(port_forward.source)
-        .await
-        .with_context(|| "Failed to listen on TCP proxy server")?;
+    let acceptor = TcpAcceptor {
+        #[cfg(feature = "tls")]
+        tls: port_forward
+            .tls
+            .as_deref()
+            .map(TlsLayer::new)
+            .transpose()?
+            .map(Arc::new),
+        destinations: Arc::new(DestinationSelector::new(&port_forward)),
+        diagnostics: Arc::new(TcpOptionDiagnostics::new(&port_forward)),
+        port_forward,
+        port_pool,
+        resolver,
+        bus,
+        stats,
+    };
+
+    let mut listeners = listeners.into_iter();
+    let first = listeners
+        .next()
+        .with_context(|| "TCP port forward has no listener")?;
+    let mut shards = Shards(
+        listeners
+            .map(|listener| tokio::spawn(acceptor.clone().accept(listener)))
+            .collect(),
+    );
+    tokio::select! {
+        x = acceptor.accept(first) => x,
+        x = shards.first() => x,
+    }
+}
+
+/// The accept loops of the shards of a TCP port forward, aborted when the port forward stops.
+struct Shards(Vec<tokio::task::JoinHandle<anyhow::Result<()>>>);
+
+impl Shards {
+    /// Waits for the first accept loop to fail. Never completes without shards.
+    async fn first(&mut self) -> anyhow::Result<()> {
+        if self.0.is_empty() {
+            return futures::future::pending().await;
+        }
+        let (result, _, _) = futures::future::select_all(self.0.iter_mut()).await;
+        result.with_context(|| "TCP listener shard panicked")?
+    }
+}
+
+impl Drop for Shards {
+    fn drop(&mut self) {
+        for shard in &self.0 {
+            shard.abort();
+        }
+    }
+}
+
+/// What the accept loops of a TCP port forward share.
+#[derive(Clone)]
+struct TcpAcceptor {
+    port_forward: Arc<PortForwardConfig>,
+    port_pool: TcpPortPool,
+    resolver: Arc<TunnelResolver>,
+    bus: Bus,
+    stats: Arc<ForwardStats>,
+    destinations: Arc<DestinationSelector>,
+    /// Warns about the TCP features of the local clients that the virtual path can't honor.
+    diagnostics: Arc<TcpOptionDiagnostics>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<TlsLayer>>,
+}
+
+impl TcpAcceptor {
+    /// Accepts the connections of the listener, and proxies each of them on its own task.
+    async fn accept(self, listener: TcpListener) -> anyhow::Result<()> {
+        let Self {
+            port_forward,
+            port_pool,
+            resolver,
+            bus,
+            stats,
+            destinations,
+            diagnostics,
+            #[cfg(feature = "tls")]
+            tls,
+        } = self;
+
+        loop {
+            let port_pool = port_pool.clone();
+            let (socket, peer_addr) = listener
+                .accept()
+                .await
+                .with_context(|| "Failed to accept connection on TCP proxy server")?;
+            if let Some(reason) = stats.refusal() {
+                debug!("Turning away connection from {}: {}", peer_addr, reason);
+                continue;
+            }
+            if !stats.authorizes(&port_forward, peer_addr) {
+                info!("Rejected connection from {}: not authorized", peer_addr);
+                continue;
+            }
+
+            // Assign a 'virtual port': this is a unique port number used to route IP packets
+            // received from the WireGuard tunnel. It is the port number that the virtual client will
+            // listen on.
+            let virtual_port = match port_pool.next(peer_addr).await {
+                Ok(port) => port,
+                Err(e) => {
+                    error!(
+                        "Failed to assign virtual port number for connection [{}]: {:?}",
+                        peer_addr, e
+                    );
+                    continue;
+                }
+            };
+
+            info!("[{}] Incoming connection from {}", virtual_port, peer_addr);
+            diagnostics.check_accepted(&socket, virtual_port);
+
+            let port_forward = port_forward.clone();
+            let bus = bus.clone();
+            let stats = stats.clone();
+            let destinations = destinations.clone();
+            let diagnostics = diagnostics.clone();
+            let resolver = resolver.clone();
+            #[cfg(feature = "tls")]
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                let port_pool = port_pool.clone();
+                // Failures are reported on the bus, for the port forward as configured
+                let (configured, events) = (port_forward.clone(), bus.clone());
+                let port_forward = match resolver.resolve(&port_forward).await {
+                    Ok(port_forward) => port_forward,
+                    Err(e) => {
+                        return finish_connection(
+                            Err(e),
+                            virtual_port,
+                            port_pool,
+                            &configured,
+                            &events,
+                        )
+                        .await
+                    }
+                };
+                let tracked = stats.clone();
+                tracked.open_connection(virtual_port);
+                let mirror = MirrorFeed::open(&port_forward, virtual_port, &port_pool, &bus);
+                #[cfg(feature = "tls")]
+                let result = match tls {
+                    Some(tls) => {
+                        tls.handle_connection(
+                            socket,
+                            virtual_port,
+                            port_forward,
+                            destinations,
+                            bus,
+                            stats,
+                            mirror,
+                        )
+                        .await
+                    }
+                    None => {
+                        let mut socket = DiagnosedStream::new(socket, &diagnostics, virtual_port);
+                        let result = handle_tcp_proxy_connection(
+                            &mut socket,
+                            virtual_port,
+                            port_forward,
+                            destinations,
+                            bus,
+                            stats,
+                            mirror,
+                        )
+                        .await;
+                        reset_on_failure(socket.get_ref(), &result);
+                        result
+                    }
+                };
+                #[cfg(not(feature = "tls"))]
+                let mut socket = DiagnosedStream::new(socket, &diagnostics, virtual_port);
+                #[cfg(not(feature = "tls"))]
+                let result = handle_tcp_proxy_connection(
+                    &mut socket,
+                    virtual_port,
+                    port_forward,
+                    destinations,
+                    bus,
+                    stats,
+                    mirror,
+                )
+                .await;
+                #[cfg(not(feature = "tls"))]
+                reset_on_failure(socket.get_ref(), &result);
+                tracked.close_connection(virtual_port);
+
+                finish_connection(result, virtual_port, port_pool, &configured, &events).await;
+            });
+        }
+    }
+}
+
+/// Starts the server that accepts connections on a Windows named pipe, from its first instance.
+#[cfg(windows)]
+pub async fn pipe_proxy_server(
+    port_forward: Arc<PortForwardConfig>,
+    mut server: tokio::net::windows::named_pipe::NamedPipeServer,
+    port_pool: TcpPortPool,
+    resolver: Arc<TunnelResolver>,
+    bus: Bus,
+    stats: Arc<ForwardStats>,
+) -> anyhow::Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let pipe = port_forward
+        .pipe
+        .clone()
+        .with_context(|| "Port forward has no named pipe")?;
+    let destinations = Arc::new(DestinationSelector::new(&port_forward));
 
     loop {
-        let port_pool = port_pool.clone();
-        let (socket, peer_addr) = listener
-            .accept()
+        server
+            .connect()
             .await
-            .with_context(|| "Failed to accept connection on TCP proxy server")?;
+            .with_context(|| "Failed to accept connection on named pipe")?;
+        // The next client connects to a new instance of the pipe
+        let next_server = ServerOptions::new()
+            .create(&*pipe)
+            .with_context(|| "Failed to create named pipe instance")?;
+        let client = std::mem::replace(&mut server, next_server);
+        if let Some(reason) = stats.refusal() {
+            debug!("Turning away connection on {}: {}", pipe, reason);
+            continue;
+        }
+        if !stats.authorizes(&port_forward, port_forward.source) {
+            info!("Rejected connection on {}: not authorized", pipe);
+            continue;
+        }
 
-        // Assign a 'virtual port': this is a unique port number used to route IP packets
-        // received from the WireGuard tunnel. It is the port number that the virtual client will
-        // listen on.
-        let virtual_port = match port_pool.next().await {
+        // Pipe clients have no address: the virtual port is assigned to the placeholder source
+        let virtual_port = match port_pool.next(port_forward.source).await {
             Ok(port) => port,
             Err(e) => {
                 error!(
-                    "Failed to assign virtual port number for connection [{}]: {:?}",
-                    peer_addr, e
+                    "Failed to assign virtual port number for connection on {}: {:?}",
+                    pipe, e
                 );
                 continue;
             }
         };
 
-        info!("[{}] Incoming connection from {}", virtual_port, peer_addr);
+        info!("[{}] Incoming connection on {}", virtual_port, pipe);
 
+        let port_pool = port_pool.clone();
+        let port_forward = port_forward.clone();
         let bus = bus.clone();
+        let stats = stats.clone();
+        let destinations = destinations.clone();
+        let resolver = resolver.clone();
         tokio::spawn(async move {
-            let port_pool = port_pool.clone();
-            let result = handle_tcp_proxy_connection(socket, virtual_port, port_forward, bus).await;
+            // Failures are reported on the bus, for the port forward as configured
+            let (configured, events) = (port_forward.clone(), bus.clone());
+            let port_forward = match resolver.resolve(&port_forward).await {
+                Ok(port_forward) => port_forward,
+                Err(e) => {
+                    return finish_connection(Err(e), virtual_port, port_pool, &configured, &events)
+                        .await
+                }
+            };
+            let tracked = stats.clone();
+            tracked.open_connection(virtual_port);
+            let mirror = MirrorFeed::open(&port_forward, virtual_port, &port_pool, &bus);
+            let result = handle_tcp_proxy_connection(
+                client,
+                virtual_port,
+                port_forward,
+                destinations,
+                bus,
+                stats,
+                mirror,
+            )
+            .await;
+            tracked.close_connection(virtual_port);
+            finish_connection(result, virtual_port, port_pool, &configured, &events).await;
+        });
+    }
+}
 
-            if let Err(e) = result {
-                error!(
-                    "[{}] Connection dropped un-gracefully: {:?}",
-                    virtual_port, e
-                );
-            } else {
-                info!("[{}] Connection closed by client", virtual_port);
+/// Why a virtual connection failed, in which case its local connection is reset.
+#[derive(Debug)]
+enum VirtualConnectionError {
+    /// The destination didn't answer within the connect timeout.
+    Timeout,
+    /// The destination refused or reset the connection.
+    Refused,
+}
+
+impl Display for VirtualConnectionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => {
+                f.write_str("No answer from the destination within the connect timeout")
             }
+            Self::Refused => f.write_str("The destination refused or reset the connection"),
+        }
+    }
+}
+
+impl std::error::Error for VirtualConnectionError {}
+
+/// Resets the local connection when its virtual connection failed, instead of closing it: the client sees
+/// the failure right away, rather than a connection that ended normally.
+fn reset_on_failure(socket: &TcpStream, result: &anyhow::Result<()>) {
+    if result
+        .as_ref()
+        .err()
+        .map_or(false, |e| e.is::<VirtualConnectionError>())
+    {
+        if let Err(e) = socket.set_linger(Some(Duration::ZERO)) {
+            debug!("Failed to reset the local connection: {:?}", e);
+        }
+    }
+}
+
+/// Logs how the connection ended, reports its failure on the bus, and releases its virtual port.
+async fn finish_connection(
+    result: anyhow::Result<()>,
+    virtual_port: VirtualPort,
+    port_pool: TcpPortPool,
+    port_forward: &Arc<PortForwardConfig>,
+    bus: &Bus,
+) {
+    if let Err(e) = result {
+        error!(
+            "[{}] Connection dropped un-gracefully: {:?}",
+            virtual_port, e
+        );
+        bus.new_endpoint().send(Event::ConnectionFailed(
+            port_forward.clone(),
+            virtual_port,
+            format!("{:#}", e),
+        ));
+    } else {
+        info!("[{}] Connection closed by client", virtual_port);
+    }
 
-            tokio::time::sleep(Duration::from_millis(100)).await; // Make sure the other tasks have time to process the event
-            port_pool.release(virtual_port).await;
+    tokio::time::sleep(Duration::from_millis(100)).await; // Make sure the other tasks have time to process the event
+    port_pool.release(virtual_port).await;
+}
+
+/// Starts the server of a remote port forward, which connects to its destination for each connection accepted
+/// by the virtual interface on its source address.
+pub async fn remote_tcp_proxy_server(
+    port_forward: Arc<PortForwardConfig>,
+    port_pool: TcpPortPool,
+    bus: Bus,
+    stats: Arc<ForwardStats>,
+) -> anyhow::Result<()> {
+    let mut endpoint = bus.new_endpoint();
+    loop {
+        let virtual_port = match endpoint.recv().await {
+            Event::VirtualConnectionAccepted(pf, virtual_port, _)
+                if pf.source == port_forward.source =>
+            {
+                virtual_port
+            }
+            _ => continue,
+        };
+
+        info!(
+            "[{}] Incoming connection from the tunnel to {}",
+            virtual_port, port_forward.source
+        );
+
+        // Subscribed before the virtual connection releases its data
+        let endpoint = bus.new_endpoint();
+        let port_forward = port_forward.clone();
+        let port_pool = port_pool.clone();
+        let stats = stats.clone();
+        let bus = bus.clone();
+        tokio::spawn(async move {
+            let result = match TcpStream::connect(port_forward.destination).await {
+                Ok(socket) => {
+                    endpoint.send(Event::ClientConnectionInitiated(
+                        port_forward.clone(),
+                        virtual_port,
+                        Instant::now(),
+                    ));
+                    let mirror = MirrorFeed::open(&port_forward, virtual_port, &port_pool, &bus);
+                    relay_tcp_connection(
+                        socket,
+                        endpoint,
+                        virtual_port,
+                        port_forward.clone(),
+                        stats,
+                        mirror,
+                    )
+                    .await
+                }
+                Err(e) => {
+                    endpoint.send(Event::ClientConnectionDropped(
+                        virtual_port,
+                        DropReason::PeerReset,
+                        Instant::now(),
+                    ));
+                    Err(e).with_context(|| {
+                        format!("Failed to connect to {}", port_forward.destination)
+                    })
+                }
+            };
+            finish_connection(result, virtual_port, port_pool, &port_forward, &bus).await;
         });
     }
 }
 
+/// Opens a virtual connection to the destination, without a local client: the returned stream is relayed to it
+/// until either side closes (see `Handle::open_tcp`).
+pub(crate) async fn open_virtual_connection(
+    destination: SocketAddr,
+    port_pool: TcpPortPool,
+    bus: Bus,
+    timeout: Duration,
+) -> anyhow::Result<(VirtualPort, DuplexStream)> {
+    // In-process connections have no address: the virtual port is assigned to a placeholder source
+    let source = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+    let port_forward = Arc::new(PortForwardConfig::new(
+        source,
+        destination,
+        PortProtocol::Tcp,
+    ));
+    let virtual_port = port_pool.next(source).await?;
+
+    let mut endpoint = bus.new_endpoint();
+    endpoint.send(Event::ClientConnectionInitiated(
+        port_forward.clone(),
+        virtual_port,
+        Instant::now(),
+    ));
+    let established = async {
+        loop {
+            match endpoint.recv().await {
+                Event::ClientConnectionEstablished(e_vp) if e_vp == virtual_port => return true,
+                Event::ClientConnectionDropped(e_vp, ..) if e_vp == virtual_port => return false,
+                _ => {}
+            }
+        }
+    };
+    let error = match tokio::time::timeout(timeout, established).await {
+        Ok(true) => None,
+        Ok(false) => Some(anyhow::anyhow!("Connection refused by {}", destination)),
+        Err(_) => {
+            endpoint.send(Event::ClientConnectionDropped(
+                virtual_port,
+                DropReason::Timeout,
+                Instant::now(),
+            ));
+            Some(anyhow::anyhow!(
+                "No answer from {} within {:?}",
+                destination,
+                timeout
+            ))
+        }
+    };
+    if let Some(e) = error {
+        let message = e.to_string();
+        finish_connection(Err(e), virtual_port, port_pool, &port_forward, &bus).await;
+        return Err(anyhow::anyhow!(message));
+    }
+
+    let (stream, relayed) = tokio::io::duplex(MAX_PACKET);
+    tokio::spawn(async move {
+        let result = relay_tcp_connection(
+            relayed,
+            endpoint,
+            virtual_port,
+            port_forward.clone(),
+            Arc::default(),
+            None,
+        )
+        .await;
+        finish_connection(result, virtual_port, port_pool, &port_forward, &bus).await;
+    });
+    Ok((virtual_port, stream))
+}
+
 /// Handles a new TCP connection with its assigned virtual port.
-async fn handle_tcp_proxy_connection(
-    mut socket: TcpStream,
+pub(super) async fn handle_tcp_proxy_connection<S>(
+    socket: S,
     virtual_port: VirtualPort,
-    port_forward: PortForwardConfig,
+    port_forward: Arc<PortForwardConfig>,
+    destinations: Arc<DestinationSelector>,
     bus: Bus,
-) -> anyhow::Result<()> {
+    stats: Arc<ForwardStats>,
+    mirror: Option<MirrorFeed>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let mut endpoint = bus.new_endpoint();
-    endpoint.send(Event::ClientConnectionInitiated(port_forward, virtual_port));
+    let port_forward = if port_forward.failover_destinations.is_empty() {
+        endpoint.send(Event::ClientConnectionInitiated(
+            port_forward.clone(),
+            virtual_port,
+            Instant::now(),
+        ));
+        port_forward
+    } else {
+        connect_with_failover(&mut endpoint, virtual_port, port_forward, &destinations).await?
+    };
+
+    relay_tcp_connection(socket, endpoint, virtual_port, port_forward, stats, mirror).await
+}
 
+/// Relays the data of the connection between the local socket and its virtual connection, until either closes.
+async fn relay_tcp_connection<S>(
+    mut socket: S,
+    mut endpoint: BusEndpoint,
+    virtual_port: VirtualPort,
+    port_forward: Arc<PortForwardConfig>,
+    stats: Arc<ForwardStats>,
+    mut mirror: Option<MirrorFeed>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let mut buffer = Vec::with_capacity(MAX_PACKET);
+    let mut reason = DropReason::LocalClose;
+    let mut refused = false;
+    let mut transform = port_forward.compression.map(new_transform);
+    let mut drain = DrainMeter::new(Instant::now());
+    let chunk_size = port_forward
+        .max_message_size
+        .map_or(COALESCE_LIMIT, |max| max.min(COALESCE_LIMIT));
     loop {
         tokio::select! {
-            readable_result = socket.readable() => {
-                match readable_result {
-                    Ok(_) => {
-                        match socket.try_read_buf(&mut buffer) {
-                            Ok(size) if size > 0 => {
-                                let data = Vec::from(&buffer[..size]);
-                                endpoint.send(Event::LocalData(port_forward, virtual_port, data));
-                                // Reset buffer
-                                buffer.clear();
-                            }
-                            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                                continue;
-                            }
-                            Err(e) => {
-                                error!(
-                                    "[{}] Failed to read from client TCP socket: {:?}",
-                                    virtual_port, e
-                                );
-                                break;
-                            }
-                            _ => {
-                                break;
+            read_result = socket.read_buf(&mut buffer) => {
+                match read_result {
+                    Ok(size) if size > 0 => {
+                        // An error or the end of the stream is seen again by the next read
+                        while buffer.len() < COALESCE_LIMIT {
+                            match socket.read_buf(&mut buffer).now_or_never() {
+                                Some(Ok(size)) if size > 0 => {}
+                                _ => break,
                             }
                         }
+                        let size = buffer.len();
+                        if let Some(mirror) = &mut mirror {
+                            mirror.copy(MirrorDirection::Outbound, &buffer);
+                        }
+                        stats.record_tx(size);
+                        // Sent in chunks of at most the size limit of the port forward
+                        for chunk in buffer.chunks(chunk_size) {
+                            let data = match &mut transform {
+                                Some(transform) => transform.encode(chunk),
+                                None => chunk.to_vec(),
+                            };
+                            endpoint.send(Event::LocalData(port_forward.clone(), virtual_port, data, Instant::now()));
+                        }
+                        // Reset buffer
+                        buffer.clear();
                     }
                     Err(e) => {
-                        error!("[{}] Failed to check if readable: {:?}", virtual_port, e);
+                        error!(
+                            "[{}] Failed to read from client TCP socket: {:?}",
+                            virtual_port, e
+                        );
+                        break;
+                    }
+                    _ => {
                         break;
                     }
                 }
             }
             event = endpoint.recv() => {
                 match event {
-                    Event::ClientConnectionDropped(e_vp) if e_vp == virtual_port => {
+                    Event::ClientConnectionRefused(e_vp) if e_vp == virtual_port => {
+                        reason = DropReason::PeerReset;
+                        refused = true;
+                        break;
+                    }
+                    Event::ClientConnectionDropped(e_vp, e_reason, _) if e_vp == virtual_port => {
                         // This connection is supposed to be closed, stop the task.
+                        reason = e_reason;
                         break;
                     }
-                    Event::RemoteData(e_vp, data) if e_vp == virtual_port => {
+                    Event::RemoteData(e_vp, data, _) if e_vp == virtual_port => {
                         // Have remote data to send to the local client
-                        if let Err(e) = socket.writable().await {
-                            error!("[{}] Failed to check if writable: {:?}", virtual_port, e);
+                        let data = match &mut transform {
+                            Some(transform) => match transform.decode(&data) {
+                                Ok(data) => data,
+                                Err(e) => {
+                                    error!("[{}] Failed to decode data from the tunnel: {:?}", virtual_port, e);
+                                    break;
+                                }
+                            },
+                            None => data,
+                        };
+                        if let Some(mirror) = &mut mirror {
+                            mirror.copy(MirrorDirection::Inbound, &data);
                         }
+                        let writing = Instant::now();
                         let expected = data.len();
                         let mut sent = 0;
                         loop {
@@ -133,9 +662,14 @@ async fn handle_tcp_proxy_connection(
                                 break;
                             }
                             match socket.write(&data[sent..expected]).await {
+                                Ok(0) => {
+                                    error!("[{}] Local client closed before receiving {} bytes", virtual_port, expected - sent);
+                                    break;
+                                }
                                 Ok(written) => {
                                     debug!("[{}] Sent {} (expected {}) bytes to local client", virtual_port, written, expected);
                                     sent += written;
+                                    stats.record_rx(written);
                                     if sent < expected {
                                         debug!("[{}] Will try to resend remaining {} bytes to local client", virtual_port, (expected - written));
                                     }
@@ -146,6 +680,13 @@ async fn handle_tcp_proxy_connection(
                                 }
                             }
                         }
+                        if let Some((stalled, became_slow)) = drain.record(writing.elapsed(), Instant::now()) {
+                            endpoint.send(Event::ClientStalled(virtual_port, stalled));
+                            if became_slow {
+                                warn!("[{}] Local client reads slower than the tunnel delivers", virtual_port);
+                                endpoint.send(Event::SlowConsumer(virtual_port));
+                            }
+                        }
                     }
                     _ => {}
                 }
@@ -153,16 +694,292 @@ async fn handle_tcp_proxy_connection(
         }
     }
 
+    if let Some(stalled) = drain.finish() {
+        endpoint.send(Event::ClientStalled(virtual_port, stalled));
+    }
     // Notify other endpoints that this task has closed and no more data is to be sent to the local client
-    endpoint.send(Event::ClientConnectionDropped(virtual_port));
+    endpoint.send(Event::ClientConnectionDropped(
+        virtual_port,
+        reason,
+        Instant::now(),
+    ));
 
+    if refused {
+        return Err(VirtualConnectionError::Refused.into());
+    }
+    if reason == DropReason::Timeout {
+        return Err(VirtualConnectionError::Timeout.into());
+    }
     Ok(())
 }
 
+/// Measures how long the local client of a connection keeps it waiting to write the data from the tunnel, as its
+/// socket's send buffer is full: the client reads slower than the tunnel delivers.
+struct DrainMeter {
+    window_start: Instant,
+    /// The time spent writing to the local client since the start of the window.
+    stalled: Duration,
+    /// Whether the local client was a slow consumer in the last window.
+    slow: bool,
+}
+
+impl DrainMeter {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            stalled: Duration::ZERO,
+            slow: false,
+        }
+    }
+
+    /// Records the time a write to the local client took. At the end of a window in which the client stalled,
+    /// returns how long it did, and whether it just became a slow consumer.
+    fn record(&mut self, waited: Duration, now: Instant) -> Option<(Duration, bool)> {
+        self.stalled += waited;
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < DRAIN_WINDOW {
+            return None;
+        }
+        self.window_start = now;
+        let stalled = std::mem::take(&mut self.stalled);
+        let slow = stalled * 100 >= elapsed * SLOW_CONSUMER_PERCENT;
+        let became_slow = slow && !self.slow;
+        self.slow = slow;
+        (!stalled.is_zero()).then(|| (stalled, became_slow))
+    }
+
+    /// The time the local client stalled in the last, unfinished window, if it did.
+    fn finish(self) -> Option<Duration> {
+        (!self.stalled.is_zero()).then(|| self.stalled)
+    }
+}
+
+/// Copies the data of a connection to the mirror of its port forward (see `PortForwardConfig::mirror`), on a
+/// virtual connection of its own, fed from the data relayed by the connection. The mirror is closed with it.
+pub(super) struct MirrorFeed {
+    virtual_port: VirtualPort,
+    direction: MirrorDirection,
+    /// The data waiting to be written to the mirror connection, until it fails or falls behind.
+    sender: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+impl MirrorFeed {
+    /// Opens the mirror connection of a connection, if its port forward has a mirror.
+    fn open(
+        port_forward: &PortForwardConfig,
+        virtual_port: VirtualPort,
+        port_pool: &TcpPortPool,
+        bus: &Bus,
+    ) -> Option<Self> {
+        let mirror = port_forward.mirror?;
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(MIRROR_QUEUE);
+        let (port_pool, bus) = (port_pool.clone(), bus.clone());
+        tokio::spawn(async move {
+            // The data relayed in the meantime waits in the queue
+            let (mirror_port, stream) = match open_virtual_connection(
+                mirror.destination,
+                port_pool,
+                bus,
+                MIRROR_CONNECT_TIMEOUT,
+            )
+            .await
+            {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!(
+                        "[{}] Failed to open mirror connection: {:#}",
+                        virtual_port, e
+                    );
+                    return;
+                }
+            };
+            debug!(
+                "[{}] Mirroring to {} from virtual port {}",
+                virtual_port, mirror.destination, mirror_port
+            );
+
+            let (mut replies, mut copies) = tokio::io::split(stream);
+            let feed = async {
+                while let Some(data) = receiver.recv().await {
+                    copies.write_all(&data).await?;
+                }
+                copies.shutdown().await
+            };
+            // What the mirror sends back is discarded, so that it can't stall its virtual connection
+            let discard = tokio::io::copy(&mut replies, &mut tokio::io::sink());
+            tokio::select! {
+                result = feed => {
+                    if let Err(e) = result {
+                        warn!("[{}] Failed to write to mirror connection: {:?}", virtual_port, e);
+                    }
+                }
+                _ = discard => {
+                    debug!("[{}] Mirror connection closed by {}", virtual_port, mirror.destination);
+                }
+            }
+        });
+        Some(Self {
+            virtual_port,
+            direction: mirror.direction,
+            sender: Some(sender),
+        })
+    }
+
+    /// Copies data relayed in the given direction, if the mirror takes it.
+    fn copy(&mut self, direction: MirrorDirection, data: &[u8]) {
+        if self.direction != MirrorDirection::Both && self.direction != direction {
+            return;
+        }
+        if let Some(sender) = &self.sender {
+            match sender.try_send(data.to_vec()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "[{}] Mirror connection fell behind, no longer mirroring",
+                        self.virtual_port
+                    );
+                    self.sender = None;
+                }
+                Err(TrySendError::Closed(_)) => self.sender = None,
+            }
+        }
+    }
+}
+
+/// Opens the virtual connection of a port forward with failover destinations, trying each of them
+/// until one accepts it. Returns the port forward to the destination that was connected.
+async fn connect_with_failover(
+    endpoint: &mut BusEndpoint,
+    virtual_port: VirtualPort,
+    port_forward: Arc<PortForwardConfig>,
+    destinations: &DestinationSelector,
+) -> anyhow::Result<Arc<PortForwardConfig>> {
+    for destination in destinations.candidates() {
+        let attempt = Arc::new(PortForwardConfig {
+            destination,
+            ..PortForwardConfig::clone(&port_forward)
+        });
+        endpoint.send(Event::ClientConnectionInitiated(
+            attempt.clone(),
+            virtual_port,
+            Instant::now(),
+        ));
+
+        let connected = async {
+            loop {
+                match endpoint.recv().await {
+                    Event::ClientConnectionEstablished(e_vp) if e_vp == virtual_port => {
+                        return true
+                    }
+                    Event::ClientConnectionDropped(e_vp, ..) if e_vp == virtual_port => {
+                        return false
+                    }
+                    _ => {}
+                }
+            }
+        };
+        match tokio::time::timeout(FAILOVER_CONNECT_TIMEOUT, connected).await {
+            Ok(true) => {
+                destinations.report(destination, true);
+                return Ok(attempt);
+            }
+            Ok(false) => {}
+            Err(_) => {
+                // Close the pending connection, and wait for the virtual interface to release the virtual port
+                endpoint.send(Event::ClientConnectionDropped(
+                    virtual_port,
+                    DropReason::Timeout,
+                    Instant::now(),
+                ));
+                let released = async {
+                    loop {
+                        if let Event::ClientConnectionDropped(e_vp, ..) = endpoint.recv().await {
+                            if e_vp == virtual_port {
+                                return;
+                            }
+                        }
+                    }
+                };
+                tokio::time::timeout(Duration::from_secs(1), released)
+                    .await
+                    .ok();
+            }
+        }
+        destinations.report(destination, false);
+        warn!(
+            "[{}] Failed to connect to destination {}, trying the next one",
+            virtual_port, destination
+        );
+    }
+    Err(anyhow::anyhow!(
+        "All the destinations of {} are unreachable",
+        port_forward
+    ))
+}
+
+/// Picks the destination of each connection of a port forward with failover destinations.
+pub(super) struct DestinationSelector {
+    destinations: Vec<SocketAddr>,
+    selection: DestinationSelection,
+    /// The position of the destination to try first on the next connection.
+    next: AtomicUsize,
+    /// The destinations that recently failed, until when they are tried last.
+    penalties: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl DestinationSelector {
+    pub fn new(port_forward: &PortForwardConfig) -> Self {
+        Self {
+            destinations: port_forward.destinations().collect(),
+            selection: port_forward.selection,
+            next: AtomicUsize::new(0),
+            penalties: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The destinations to try for a new connection, in order: the healthy ones following the
+    /// selection policy, then the ones that recently failed.
+    fn candidates(&self) -> Vec<SocketAddr> {
+        let start = match self.selection {
+            DestinationSelection::InOrder => 0,
+            DestinationSelection::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            DestinationSelection::Sticky => self.next.load(Ordering::Relaxed),
+        } % self.destinations.len();
+        let mut candidates = self.destinations.clone();
+        candidates.rotate_left(start);
+
+        let now = Instant::now();
+        let mut penalties = self.penalties.lock().unwrap();
+        penalties.retain(|_, until| *until > now);
+        // Stable sort: the healthy destinations keep their order, before the others
+        candidates.sort_by_key(|destination| penalties.contains_key(destination));
+        candidates
+    }
+
+    /// Records whether a connection to the destination succeeded.
+    fn report(&self, destination: SocketAddr, success: bool) {
+        let mut penalties = self.penalties.lock().unwrap();
+        if success {
+            penalties.remove(&destination);
+            if self.selection == DestinationSelection::Sticky {
+                if let Some(position) = self.destinations.iter().position(|d| *d == destination) {
+                    self.next.store(position, Ordering::Relaxed);
+                }
+            }
+        } else {
+            penalties.insert(destination, Instant::now() + FAILOVER_PENALTY);
+        }
+    }
+}
+
 /// A pool of virtual ports available for TCP connections.
 #[derive(Clone)]
 pub struct TcpPortPool {
     inner: Arc<tokio::sync::RwLock<TcpPortPoolInner>>,
+    /// Where `Event::PortPoolExhausted` is reported, if anywhere.
+    events: Option<BusSender>,
+    /// Whether the ports are assigned in order instead of at random.
+    sequential: bool,
 }
 
 impl Default for TcpPortPool {
@@ -182,24 +999,72 @@ impl TcpPortPool {
             .for_each(|p| inner.queue.push_back(p) as ());
         Self {
             inner: Arc::new(tokio::sync::RwLock::new(inner)),
+            events: None,
+            sequential: false,
+        }
+    }
+
+    /// Reports the exhaustion of the pool on the bus.
+    pub(crate) fn with_events(mut self, bus: &Bus) -> Self {
+        self.events = Some(bus.new_endpoint().sender());
+        self
+    }
+
+    /// Assigns the ports in order instead of at random (see `Config::with_sequential_ports`).
+    pub(crate) fn with_sequential_ports(mut self, sequential: bool) -> Self {
+        if sequential {
+            self.inner
+                .try_write()
+                .expect("The pool is not shared yet")
+                .queue = PORT_RANGE.collect();
         }
+        self.sequential = sequential;
+        self
     }
 
-    /// Requests a free port from the pool. An error is returned if none is available (exhaused max capacity).
-    pub async fn next(&self) -> anyhow::Result<VirtualPort> {
+    /// Requests a free port from the pool, assigned to the given local peer address.
+    /// An error is returned if none is available (exhaused max capacity).
+    pub async fn next(&self, peer_addr: SocketAddr) -> anyhow::Result<VirtualPort> {
         let mut inner = self.inner.write().await;
-        let port = inner
-            .queue
-            .pop_front()
-            .with_context(|| "TCP virtual port pool is exhausted")?;
+        let port = match take_free_port(&mut inner.queue, self.sequential) {
+            Some(port) => port,
+            None => {
+                inner.exhaustions += 1;
+                if let Some(events) = &self.events {
+                    events.send(Event::PortPoolExhausted(PortProtocol::Tcp));
+                }
+                return Err(anyhow::anyhow!("TCP virtual port pool is exhausted"));
+            }
+        };
+        inner.allocations += 1;
+        inner.peer_addr_by_port.insert(port, peer_addr);
         Ok(VirtualPort::new(port, PortProtocol::Tcp))
     }
 
     /// Releases a port back into the pool.
     pub async fn release(&self, port: VirtualPort) {
         let mut inner = self.inner.write().await;
+        inner.peer_addr_by_port.remove(&port.num());
         inner.queue.push_back(port.num());
     }
+
+    /// The local peer address the given virtual port is assigned to, if any.
+    pub async fn get_peer_addr(&self, port: VirtualPort) -> Option<SocketAddr> {
+        let inner = self.inner.read().await;
+        inner.peer_addr_by_port.get(&port.num()).copied()
+    }
+
+    /// The occupancy and allocation counters of the pool.
+    pub async fn stats(&self) -> PortPoolStats {
+        let inner = self.inner.read().await;
+        PortPoolStats {
+            capacity: PORT_RANGE.len(),
+            in_use: inner.peer_addr_by_port.len(),
+            allocations: inner.allocations,
+            exhaustions: inner.exhaustions,
+            evictions: 0,
+        }
+    }
 }
 
 /// Non thread-safe inner logic for TCP port pool.
@@ -207,4 +1072,96 @@ impl TcpPortPool {
 struct TcpPortPoolInner {
     /// Remaining ports in the pool.
     queue: VecDeque<u16>,
+    /// The local peer address assigned to each port in use.
+    peer_addr_by_port: HashMap<u16, SocketAddr>,
+    /// How many ports were assigned.
+    allocations: u64,
+    /// How many times no port was left.
+    exhaustions: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector(selection: DestinationSelection) -> DestinationSelector {
+        let mut port_forward = PortForwardConfig::new(
+            SocketAddr::from(([127, 0, 0, 1], 8080)),
+            SocketAddr::from(([10, 0, 0, 1], 80)),
+            PortProtocol::Tcp,
+        );
+        port_forward.failover_destinations = vec![
+            SocketAddr::from(([10, 0, 0, 2], 80)),
+            SocketAddr::from(([10, 0, 0, 3], 80)),
+        ];
+        port_forward.selection = selection;
+        DestinationSelector::new(&port_forward)
+    }
+
+    fn last_octets(candidates: Vec<SocketAddr>) -> Vec<u8> {
+        candidates
+            .iter()
+            .map(|destination| match destination.ip() {
+                std::net::IpAddr::V4(ip) => ip.octets()[3],
+                std::net::IpAddr::V6(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_drain_meter() {
+        let start = Instant::now();
+        let mut drain = DrainMeter::new(start);
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // Writes that complete right away are no stall
+        assert_eq!(drain.record(Duration::ZERO, at(100)), None);
+        assert_eq!(drain.record(Duration::ZERO, at(5000)), None);
+
+        // Waiting a third of the window is reported, without being a slow consumer
+        assert_eq!(drain.record(Duration::from_millis(1000), at(6000)), None);
+        assert_eq!(
+            drain.record(Duration::from_millis(1000), at(11000)),
+            Some((Duration::from_millis(2000), false))
+        );
+
+        // Waiting most of the window is, once until the client catches up
+        assert_eq!(
+            drain.record(Duration::from_millis(4000), at(16000)),
+            Some((Duration::from_millis(4000), true))
+        );
+        assert_eq!(
+            drain.record(Duration::from_millis(4000), at(21000)),
+            Some((Duration::from_millis(4000), false))
+        );
+        assert_eq!(drain.record(Duration::ZERO, at(26000)), None);
+        assert_eq!(
+            drain.record(Duration::from_millis(3000), at(31000)),
+            Some((Duration::from_millis(3000), true))
+        );
+
+        drain.record(Duration::from_millis(200), at(32000));
+        assert_eq!(drain.finish(), Some(Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn test_destination_selection() {
+        let in_order = selector(DestinationSelection::InOrder);
+        assert_eq!(last_octets(in_order.candidates()), vec![1, 2, 3]);
+        in_order.report(SocketAddr::from(([10, 0, 0, 1], 80)), false);
+        assert_eq!(last_octets(in_order.candidates()), vec![2, 3, 1]);
+        in_order.report(SocketAddr::from(([10, 0, 0, 1], 80)), true);
+        assert_eq!(last_octets(in_order.candidates()), vec![1, 2, 3]);
+
+        let round_robin = selector(DestinationSelection::RoundRobin);
+        assert_eq!(last_octets(round_robin.candidates()), vec![1, 2, 3]);
+        assert_eq!(last_octets(round_robin.candidates()), vec![2, 3, 1]);
+        assert_eq!(last_octets(round_robin.candidates()), vec![3, 1, 2]);
+
+        let sticky = selector(DestinationSelection::Sticky);
+        sticky.report(SocketAddr::from(([10, 0, 0, 1], 80)), false);
+        sticky.report(SocketAddr::from(([10, 0, 0, 2], 80)), true);
+        assert_eq!(last_octets(sticky.candidates()), vec![2, 3, 1]);
+        assert_eq!(last_octets(sticky.candidates()), vec![2, 3, 1]);
+    }
 }
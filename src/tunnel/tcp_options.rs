@@ -0,0 +1,362 @@
+//! Diagnostics of the TCP features local clients use that the virtual path can't honor: the connection goes
+//! on without them, so onetun warns about each of them once per port forward, instead of letting the
+//! application misbehave silently.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::config::PortForwardConfig;
+use crate::virtual_iface::VirtualPort;
+
+/// How often the system counter of the connections dropped for their TCP MD5 signatures is read.
+#[cfg(target_os = "linux")]
+const MD5_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A TCP feature of a local client that the virtual path can't honor.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum UnsupportedOption {
+    /// TCP MD5 signatures (`TCP_MD5SIG`, RFC 2385), used by BGP sessions.
+    Md5Signature,
+    /// Urgent (out-of-band) data, sent with `MSG_OOB`, such as Telnet interrupts.
+    UrgentData,
+    /// Selective acknowledgements (SACK), negotiated with the local system.
+    SelectiveAck,
+}
+
+impl UnsupportedOption {
+    /// What happens to the connections using the feature.
+    fn consequence(&self) -> &'static str {
+        match self {
+            Self::Md5Signature => {
+                "the system drops the signed connections, as onetun can't sign the virtual path"
+            }
+            Self::UrgentData => {
+                "the urgent byte is not forwarded, and the destination only receives the normal data"
+            }
+            Self::SelectiveAck => {
+                "losses on the virtual path are recovered without selective acknowledgements, which is \
+                slower on lossy links"
+            }
+        }
+    }
+}
+
+impl Display for UnsupportedOption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Md5Signature => write!(f, "TCP MD5 signatures"),
+            Self::UrgentData => write!(f, "urgent data"),
+            Self::SelectiveAck => write!(f, "selective acknowledgements"),
+        }
+    }
+}
+
+/// The unsupported features reported for a TCP port forward.
+#[derive(Debug)]
+pub(super) struct TcpOptionDiagnostics {
+    port_forward: String,
+    reported: Mutex<Vec<UnsupportedOption>>,
+}
+
+impl TcpOptionDiagnostics {
+    pub(super) fn new(port_forward: &PortForwardConfig) -> Self {
+        Self {
+            port_forward: port_forward.to_string(),
+            reported: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Warns that a connection of the port forward uses the feature, unless one already did.
+    pub(super) fn report(&self, option: UnsupportedOption, virtual_port: VirtualPort) {
+        let mut reported = self
+            .reported
+            .lock()
+            .expect("Failed to acquire diagnostics lock");
+        if reported.contains(&option) {
+            debug!("[{}] Local client uses {}", virtual_port, option);
+            return;
+        }
+        reported.push(option);
+        warn!(
+            "[{}] Local client of port forward {} uses {}, which the virtual path can't honor: {}",
+            virtual_port,
+            self.port_forward,
+            option,
+            option.consequence()
+        );
+    }
+
+    /// Checks the options the local system negotiated with an accepted client.
+    pub(super) fn check_accepted(&self, socket: &TcpStream, virtual_port: VirtualPort) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            if sys::negotiated_sack(socket) {
+                self.report(UnsupportedOption::SelectiveAck, virtual_port);
+            }
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let _ = (socket, virtual_port);
+    }
+}
+
+/// A local TCP connection whose reads are checked for the urgent data of the client.
+pub(super) struct DiagnosedStream<'a> {
+    socket: TcpStream,
+    diagnostics: &'a TcpOptionDiagnostics,
+    virtual_port: VirtualPort,
+    /// Whether urgent data was seen, after which the connection is no longer checked.
+    urgent: bool,
+}
+
+impl<'a> DiagnosedStream<'a> {
+    pub(super) fn new(
+        socket: TcpStream,
+        diagnostics: &'a TcpOptionDiagnostics,
+        virtual_port: VirtualPort,
+    ) -> Self {
+        Self {
+            socket,
+            diagnostics,
+            virtual_port,
+            urgent: false,
+        }
+    }
+
+    pub(super) fn get_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+}
+
+impl AsyncRead for DiagnosedStream<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.socket).poll_read(cx, buf);
+        // Reads stop at the urgent mark, which is where the pending urgent byte is found
+        #[cfg(unix)]
+        {
+            if !self.urgent && buf.filled().len() > filled && sys::has_urgent_data(&self.socket) {
+                self.urgent = true;
+                self.diagnostics
+                    .report(UnsupportedOption::UrgentData, self.virtual_port);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = filled;
+        poll
+    }
+}
+
+impl AsyncWrite for DiagnosedStream<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.socket).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket).poll_shutdown(cx)
+    }
+}
+
+/// Warns when the system drops connections for their TCP MD5 signatures, which never reach the port forwards.
+/// The counter is system-wide: the TCP port forwards are listed as those possibly affected.
+#[cfg(target_os = "linux")]
+pub(crate) async fn watch_md5_drops(port_forwards: Vec<PortForwardConfig>) {
+    let sources: Vec<String> = port_forwards
+        .iter()
+        .map(PortForwardConfig::source_name)
+        .collect();
+    let mut last = match sys::md5_unexpected() {
+        Some(count) => count,
+        None => return,
+    };
+    let mut interval = tokio::time::interval(MD5_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let count = match sys::md5_unexpected() {
+            Some(count) => count,
+            None => return,
+        };
+        if count > last {
+            warn!(
+                "The system dropped {} connection(s) using {} since the last check, possibly to the TCP port \
+                forwards on {}: {}",
+                count - last,
+                UnsupportedOption::Md5Signature,
+                sources.join(", "),
+                UnsupportedOption::Md5Signature.consequence()
+            );
+        }
+        last = count;
+    }
+}
+
+/// Never warns: only Linux counts the connections dropped for their TCP MD5 signatures.
+#[cfg(not(target_os = "linux"))]
+pub(crate) async fn watch_md5_drops(_port_forwards: Vec<PortForwardConfig>) {}
+
+#[cfg(unix)]
+mod sys {
+    use std::os::unix::io::AsRawFd;
+
+    use tokio::net::TcpStream;
+
+    /// The `tcpi_options` bit of a connection that negotiated selective acknowledgements.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const TCPI_OPT_SACK: u8 = 2;
+
+    /// Whether urgent data is pending on the socket: it is kept out of the normal data, since
+    /// `SO_OOBINLINE` is not set, until read with `MSG_OOB`.
+    pub fn has_urgent_data(socket: &TcpStream) -> bool {
+        let mut byte = 0u8;
+        let read = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_OOB | libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        read == 1
+    }
+
+    /// Whether the local system negotiated selective acknowledgements with the client.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn negotiated_sack(socket: &TcpStream) -> bool {
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        result == 0 && info.tcpi_options & TCPI_OPT_SACK != 0
+    }
+
+    /// The number of connections the system dropped for an unexpected TCP MD5 signature, from the
+    /// `TCPMD5Unexpected` counter of `/proc/net/netstat`.
+    #[cfg(target_os = "linux")]
+    pub fn md5_unexpected() -> Option<u64> {
+        let netstat = std::fs::read_to_string("/proc/net/netstat").ok()?;
+        super::parse_netstat_counter(&netstat, "TcpExt", "TCPMD5Unexpected")
+    }
+}
+
+/// Reads a counter of the `/proc/net/netstat` format: for each group, a line of names followed by a line of
+/// values, both prefixed with the group.
+#[cfg(target_os = "linux")]
+fn parse_netstat_counter(netstat: &str, group: &str, name: &str) -> Option<u64> {
+    let prefix = format!("{}:", group);
+    let mut lines = netstat.lines().filter(|line| line.starts_with(&prefix));
+    let names = lines.next()?.split_whitespace();
+    let values = lines.next()?.split_whitespace();
+    names
+        .zip(values)
+        .find(|(n, _)| *n == name)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PortProtocol;
+
+    #[test]
+    fn test_report_once() {
+        let pf = PortForwardConfig::new(
+            "127.0.0.1:2323".parse().unwrap(),
+            "192.168.4.2:23".parse().unwrap(),
+            PortProtocol::Tcp,
+        );
+        let diagnostics = TcpOptionDiagnostics::new(&pf);
+        let vp = VirtualPort::new(1000, PortProtocol::Tcp);
+        diagnostics.report(UnsupportedOption::UrgentData, vp);
+        diagnostics.report(UnsupportedOption::UrgentData, vp);
+        diagnostics.report(UnsupportedOption::SelectiveAck, vp);
+        assert_eq!(
+            *diagnostics.reported.lock().unwrap(),
+            vec![
+                UnsupportedOption::UrgentData,
+                UnsupportedOption::SelectiveAck
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_urgent_data() {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::AsyncReadExt;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let pf = PortForwardConfig::new(
+            listener.local_addr().unwrap(),
+            "192.168.4.2:23".parse().unwrap(),
+            PortProtocol::Tcp,
+        );
+        let diagnostics = TcpOptionDiagnostics::new(&pf);
+        let mut stream = DiagnosedStream::new(
+            accepted,
+            &diagnostics,
+            VirtualPort::new(1000, PortProtocol::Tcp),
+        );
+
+        let sent = unsafe {
+            libc::send(
+                client.as_raw_fd(),
+                b"ab".as_ptr() as *const libc::c_void,
+                2,
+                libc::MSG_OOB,
+            )
+        };
+        assert_eq!(sent, 2);
+        let mut buf = [0u8; 8];
+        // The last byte sent with MSG_OOB is the urgent one
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 1);
+        assert!(stream.urgent);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_netstat_counter() {
+        let netstat = "TcpExt: SyncookiesSent TCPMD5NotFound TCPMD5Unexpected\n\
+            TcpExt: 0 3 17\n\
+            IpExt: InNoRoutes\n\
+            IpExt: 0\n";
+        assert_eq!(
+            parse_netstat_counter(netstat, "TcpExt", "TCPMD5Unexpected"),
+            Some(17)
+        );
+        assert_eq!(parse_netstat_counter(netstat, "TcpExt", "Missing"), None);
+        assert_eq!(
+            parse_netstat_counter(netstat, "IpExt", "InNoRoutes"),
+            Some(0)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! TLS termination and origination on the local side of TCP port forwards.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+    ServerName,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::{PortForwardConfig, TlsOptions};
+use crate::events::Bus;
+use crate::tunnel::tcp::{handle_tcp_proxy_connection, DestinationSelector, MirrorFeed};
+use crate::tunnel::ForwardStats;
+use crate::virtual_iface::VirtualPort;
+
+/// Size of the in-memory pipe between the TLS client and the virtual connection.
+const PIPE_CAPACITY: usize = 65536;
+
+/// The TLS layer of a TCP port forward, ready to wrap its connections.
+pub enum TlsLayer {
+    Terminate(TlsAcceptor),
+    Originate(TlsConnector, ServerName),
+}
+
+impl TlsLayer {
+    /// Loads the certificates and keys of the TLS layer.
+    pub fn new(options: &TlsOptions) -> anyhow::Result<Self> {
+        match options {
+            TlsOptions::Terminate { cert, key } => {
+                let config = ServerConfig::builder()
+                    .with_safe_defaults()
+                    .with_no_client_auth()
+                    .with_single_cert(load_certs(cert)?, load_key(key)?)
+                    .with_context(|| "Invalid TLS certificate or key")?;
+                Ok(Self::Terminate(TlsAcceptor::from(Arc::new(config))))
+            }
+            TlsOptions::Originate { server_name, ca } => {
+                let mut roots = RootCertStore::empty();
+                match ca {
+                    Some(ca) => {
+                        for cert in load_certs(ca)? {
+                            roots
+                                .add(&cert)
+                                .with_context(|| format!("Invalid CA certificate in {:?}", ca))?;
+                        }
+                    }
+                    None => {
+                        roots.add_server_trust_anchors(
+                            webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                                    ta.subject,
+                                    ta.spki,
+                                    ta.name_constraints,
+                                )
+                            }),
+                        );
+                    }
+                }
+                let config = ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                let server_name = ServerName::try_from(server_name.as_str())
+                    .with_context(|| format!("Invalid TLS server name: {}", server_name))?;
+                Ok(Self::Originate(
+                    TlsConnector::from(Arc::new(config)),
+                    server_name,
+                ))
+            }
+        }
+    }
+
+    /// Handles a new TCP connection through the TLS layer.
+    pub async fn handle_connection(
+        &self,
+        mut socket: TcpStream,
+        virtual_port: VirtualPort,
+        port_forward: Arc<PortForwardConfig>,
+        destinations: Arc<DestinationSelector>,
+        bus: Bus,
+        stats: Arc<ForwardStats>,
+        mirror: Option<MirrorFeed>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Terminate(acceptor) => {
+                let stream = acceptor
+                    .accept(socket)
+                    .await
+                    .with_context(|| "TLS handshake with local client failed")?;
+                handle_tcp_proxy_connection(
+                    stream,
+                    virtual_port,
+                    port_forward,
+                    destinations,
+                    bus,
+                    stats,
+                    mirror,
+                )
+                .await
+            }
+            Self::Originate(connector, server_name) => {
+                // The TLS client writes to one end of the pipe, and the virtual connection carries the other end
+                let (tls_io, tunnel_io) = tokio::io::duplex(PIPE_CAPACITY);
+                let tls = async {
+                    let mut stream = connector
+                        .connect(server_name.clone(), tls_io)
+                        .await
+                        .with_context(|| "TLS handshake with destination failed")?;
+                    tokio::io::copy_bidirectional(&mut socket, &mut stream)
+                        .await
+                        .with_context(|| "TLS connection failed")?;
+                    Ok::<_, anyhow::Error>(())
+                };
+                let tunnel = handle_tcp_proxy_connection(
+                    tunnel_io,
+                    virtual_port,
+                    port_forward,
+                    destinations,
+                    bus,
+                    stats,
+                    mirror,
+                );
+                let (tls, tunnel) = tokio::join!(tls, tunnel);
+                tunnel.and(tls)
+            }
+        }
+    }
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read certificates from {:?}", path))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificate found in {:?}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("Failed to read private key from {:?}", path))?
+        {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => {}
+            None => return Err(anyhow::anyhow!("No private key found in {:?}", path)),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Transforms applied to the data of TCP connections before it enters the tunnel, and undone on the data that
+//! comes out of it. The peer on the other side applies the same transform in the opposite direction.
+
+use std::convert::TryInto;
+
+use anyhow::Context;
+
+use crate::config::StreamCodec;
+
+/// The largest frame of the LZ4 codec, before compression. Larger reads are split.
+const LZ4_MAX_FRAME: usize = 65536;
+/// Size of the header of an LZ4 frame: its size before compression, then its compressed size.
+const LZ4_HEADER: usize = 8;
+
+/// A reversible transform of the byte stream of a TCP connection.
+pub trait StreamTransform: Send {
+    /// Transforms data read from the local socket, before it is sent through the tunnel.
+    fn encode(&mut self, data: &[u8]) -> Vec<u8>;
+
+    /// Restores data received through the tunnel, before it is written to the local socket. The data may
+    /// end in the middle of a frame, which is kept until the rest arrives.
+    fn decode(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Creates the transform of a connection, for the codec of its port forward.
+pub fn new_transform(codec: StreamCodec) -> Box<dyn StreamTransform> {
+    match codec {
+        StreamCodec::Lz4 => Box::new(Lz4Transform::default()),
+    }
+}
+
+/// LZ4 block compression of each read, in frames that are stored uncompressed when they don't shrink.
+/// A frame is its size before compression (u32, little endian), its compressed size (u32, 0 if stored), then
+/// its data.
+#[derive(Default)]
+struct Lz4Transform {
+    /// Received data that doesn't make a complete frame yet.
+    pending: Vec<u8>,
+}
+
+impl StreamTransform for Lz4Transform {
+    fn encode(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(data.len() + LZ4_HEADER);
+        for chunk in data.chunks(LZ4_MAX_FRAME) {
+            let header = encoded.len();
+            encoded.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            encoded.extend_from_slice(&[0; 4]);
+            lz4_compress(chunk, &mut encoded);
+            let compressed = encoded.len() - header - LZ4_HEADER;
+            if compressed < chunk.len() {
+                encoded[header + 4..header + LZ4_HEADER]
+                    .copy_from_slice(&(compressed as u32).to_le_bytes());
+            } else {
+                encoded.truncate(header + LZ4_HEADER);
+                encoded.extend_from_slice(chunk);
+            }
+        }
+        encoded
+    }
+
+    fn decode(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        let mut decoded = Vec::new();
+        let mut offset = 0;
+        while let Some(header) = self.pending.get(offset..offset + LZ4_HEADER) {
+            let size = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+            let compressed = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+            if size > LZ4_MAX_FRAME || compressed >= LZ4_MAX_FRAME {
+                return Err(anyhow::anyhow!("Invalid LZ4 frame of {} bytes", size));
+            }
+            let start = offset + LZ4_HEADER;
+            let frame = match compressed {
+                0 => self.pending.get(start..start + size),
+                _ => self.pending.get(start..start + compressed),
+            };
+            let frame = match frame {
+                Some(frame) => frame,
+                None => break,
+            };
+            if compressed == 0 {
+                decoded.extend_from_slice(frame);
+            } else {
+                lz4_decompress(frame, size, &mut decoded)?;
+            }
+            offset = start + frame.len();
+        }
+        self.pending.drain(..offset);
+        Ok(decoded)
+    }
+}
+
+/// Compresses the data as an LZ4 block, appended to `out`.
+fn lz4_compress(input: &[u8], out: &mut Vec<u8>) {
+    const HASH_LOG: u32 = 12;
+    // The last match starts at least 12 bytes before the end, and the last 5 bytes are literals
+    let match_limit = input.len().saturating_sub(12);
+    let end_limit = input.len().saturating_sub(5);
+
+    // The position after the last occurrence of each hashed 4-byte sequence, 0 if none
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos < match_limit {
+        let sequence = u32::from_le_bytes(input[pos..pos + 4].try_into().unwrap());
+        let hash = (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize;
+        let candidate = std::mem::replace(&mut table[hash], pos + 1);
+        if candidate > 0 {
+            let candidate = candidate - 1;
+            if pos - candidate <= u16::MAX as usize
+                && input[candidate..candidate + 4] == input[pos..pos + 4]
+            {
+                let mut length = 4;
+                while pos + length < end_limit && input[candidate + length] == input[pos + length] {
+                    length += 1;
+                }
+                lz4_write_sequence(out, &input[anchor..pos], Some((pos - candidate, length)));
+                pos += length;
+                anchor = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+    lz4_write_sequence(out, &input[anchor..], None);
+}
+
+/// Appends an LZ4 sequence: its literals, then its match (offset and length), except for the last one.
+fn lz4_write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_length = matched.map_or(0, |(_, length)| length - 4);
+    out.push(((literals.len().min(15) as u8) << 4) | match_length.min(15) as u8);
+    lz4_write_length(out, literals.len());
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        lz4_write_length(out, match_length);
+    }
+}
+
+/// Appends the rest of a length that doesn't fit in the 4 bits of the token.
+fn lz4_write_length(out: &mut Vec<u8>, length: usize) {
+    if length < 15 {
+        return;
+    }
+    let mut rest = length - 15;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+/// Decompresses an LZ4 block of `size` bytes, appended to `out`.
+fn lz4_decompress(input: &[u8], size: usize, out: &mut Vec<u8>) -> anyhow::Result<()> {
+    let start = out.len();
+    let end = start + size;
+    let mut pos = 0;
+    loop {
+        let token = *input.get(pos).with_context(|| "Truncated LZ4 block")?;
+        pos += 1;
+        let literals = lz4_read_length(input, &mut pos, (token >> 4) as usize)?;
+        let literals = pos
+            .checked_add(literals)
+            .and_then(|literals_end| input.get(pos..literals_end))
+            .filter(|literals| out.len() + literals.len() <= end)
+            .with_context(|| "Invalid LZ4 literals")?;
+        out.extend_from_slice(literals);
+        pos += literals.len();
+        if pos == input.len() {
+            break;
+        }
+
+        let offset = input
+            .get(pos..pos + 2)
+            .map(|offset| u16::from_le_bytes([offset[0], offset[1]]) as usize)
+            .with_context(|| "Truncated LZ4 block")?;
+        pos += 2;
+        let length = lz4_read_length(input, &mut pos, (token & 0xf) as usize)? + 4;
+        if offset == 0 || offset > out.len() - start || out.len() + length > end {
+            return Err(anyhow::anyhow!("Invalid LZ4 match"));
+        }
+        // The match may overlap the data it produces
+        let from = out.len() - offset;
+        for i in 0..length {
+            out.push(out[from + i]);
+        }
+    }
+    if out.len() != end {
+        return Err(anyhow::anyhow!(
+            "LZ4 block decompressed to {} bytes instead of {}",
+            out.len() - start,
+            size
+        ));
+    }
+    Ok(())
+}
+
+/// Reads a length from the 4 bits of the token and the bytes that extend it.
+fn lz4_read_length(input: &[u8], pos: &mut usize, token_length: usize) -> anyhow::Result<usize> {
+    let mut length = token_length;
+    if token_length == 15 {
+        loop {
+            let byte = *input.get(*pos).with_context(|| "Truncated LZ4 block")?;
+            *pos += 1;
+            length += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn test_lz4_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1856);
+        let random: Vec<u8> = (0..100_000).map(|_| rng.gen()).collect();
+        let text = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(3000);
+        let runs: Vec<u8> = (0..200_000).map(|i| (i / 1000) as u8).collect();
+
+        for data in [&b""[..], b"a", b"abcdabcdabcdabcd", &random, &text, &runs] {
+            let mut encoder = new_transform(StreamCodec::Lz4);
+            let mut decoder = new_transform(StreamCodec::Lz4);
+            let encoded = encoder.encode(data);
+            if data == &text[..] {
+                assert!(encoded.len() < data.len() / 10);
+            }
+
+            // Received in pieces that split the frames
+            let mut decoded = Vec::new();
+            for piece in encoded.chunks(1000) {
+                decoded.extend(decoder.decode(piece).unwrap());
+            }
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[test]
+    fn test_lz4_invalid_frames() {
+        for frame in [
+            &[5, 0, 0, 0, 2, 0, 0, 0, 0x50, b'a'][..],
+            &[4, 0, 0, 0, 3, 0, 0, 0, 0x00, 5, 0][..],
+            &[0, 0, 2, 0, 0, 0, 0, 0][..],
+        ] {
+            let mut decoder = new_transform(StreamCodec::Lz4);
+            assert!(decoder.decode(frame).is_err(), "{:?}", frame);
+        }
+    }
+}
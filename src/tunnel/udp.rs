@@ -1,18 +1,22 @@
-use std::collections::{HashMap, VecDeque};
-use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ops::Range;
-use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Instant;
 
-use crate::events::{Bus, Event};
+use crate::events::{Bus, BusSender, DropReason, Event};
+use crate::tunnel::dns::TunnelResolver;
+use crate::tunnel::{take_free_port, ForwardStats, PortPoolStats};
 use anyhow::Context;
 use priority_queue::double_priority_queue::DoublePriorityQueue;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use tokio::net::UdpSocket;
 
-use crate::config::{PortForwardConfig, PortProtocol};
+use crate::config::{
+    DatagramRelay, PortForwardConfig, PortPoolExhaustion, PortProtocol, ResponseFilter,
+    UdpSessionMode,
+};
 use crate::virtual_iface::VirtualPort;
 
 const MAX_PACKET: usize = 65536;
@@ -20,47 +24,110 @@ const MIN_PORT: u16 = 1000;
 const MAX_PORT: u16 = 60999;
 const PORT_RANGE: Range<u16> = MIN_PORT..MAX_PORT;
 
-/// How long to keep the UDP peer address assigned to its virtual specified port, in seconds.
-/// TODO: Make this configurable by the CLI
-const UDP_TIMEOUT_SECONDS: u64 = 60;
+/// The longest QUIC connection ID (RFC 9000, section 17.2).
+const MAX_QUIC_CID_LEN: usize = 20;
 
 /// To prevent port-flooding, we set a limit on the amount of open ports per IP address.
 /// TODO: Make this configurable by the CLI
 const PORTS_PER_IP: usize = 100;
 
-/// Starts the server that listens on UDP datagrams.
+/// Starts the server that listens on UDP datagrams, on the given socket.
 pub async fn udp_proxy_server(
-    port_forward: PortForwardConfig,
+    port_forward: Arc<PortForwardConfig>,
+    socket: UdpSocket,
     port_pool: UdpPortPool,
+    resolver: Arc<TunnelResolver>,
     bus: Bus,
+    stats: Arc<ForwardStats>,
 ) -> anyhow::Result<()> {
     let mut endpoint = bus.new_endpoint();
 
-    // Remote port forwards bind on localhost. Regular port forwards bind on the given source.
-    let bind = if port_forward.remote {
-        port_pool
+    // The virtual ports of this port forward's clients. The pool is shared with the other port forwards,
+    // which may reach this one through the virtual interface.
+    let mut virtual_ports: HashSet<VirtualPort> = HashSet::new();
+
+    // The clients restored from the state file of a previous run (see `Config::with_state_file`)
+    virtual_ports.extend(port_pool.ports_of_forward(port_forward.source).await);
+
+    // Remote port forwards receive on the virtual port of their source
+    if port_forward.remote {
+        let port = port_pool
             .reserve(port_forward.source.port(), port_forward.destination)
             .await
             .with_context(|| "Failed to assign virtual port for remote UDP port forward")?;
-        match port_forward.source.ip() {
-            IpAddr::V4(_) => SocketAddr::from((IpAddr::from_str("0.0.0.0").unwrap(), 0)),
-            IpAddr::V6(_) => SocketAddr::from((IpAddr::from_str("[::]").unwrap(), 0)),
+        // The replies from the destinations of the peer routes are sent from it too
+        for route in port_forward.peer_routes.iter() {
+            port_pool.alias(port, route.destination).await;
         }
-    } else {
-        port_forward.source
+        virtual_ports.insert(port);
+    }
+
+    // The port forward back to the peer that last reached each destination of a remote port forward with peer
+    // routes, which its replies go back to
+    let mut reply_peers: HashMap<SocketAddr, Arc<PortForwardConfig>> = HashMap::new();
+    // The port forward to the address its destination last resolved to, shared by its datagrams until it changes
+    let mut resolved = port_forward.clone();
+
+    // Relayed datagrams are sent from another socket, so they can be told apart when they loop back
+    let relay = match port_forward.relay {
+        Some(relay) => Some(
+            bind_relay(&socket, &port_forward, relay)
+                .await
+                .with_context(|| "Failed to set up UDP relay")?,
+        ),
+        None => None,
+    };
+    let relay_port = match &relay {
+        Some((relay_socket, _)) => Some(relay_socket.local_addr()?.port()),
+        None => None,
     };
 
-    let socket = UdpSocket::bind(bind)
-        .await
-        .with_context(|| "Failed to bind on UDP proxy address")?;
+    // The connections of QUIC clients, to keep them on their virtual port when their address changes
+    let mut quic_ids = match port_forward.session_mode {
+        UdpSessionMode::Quic => Some(QuicConnectionIds::default()),
+        _ => None,
+    };
 
     let mut buffer = [0u8; MAX_PACKET];
     loop {
         tokio::select! {
-            to_send_result = next_udp_datagram(&socket, &mut buffer, port_pool.clone()) => {
+            to_send_result = next_udp_datagram(&socket, &mut buffer, port_pool.clone(), relay_port, port_forward.session_mode, quic_ids.as_mut(), |client| stats.authorizes(&port_forward, client)) => {
                 match to_send_result {
-                    Ok(Some((port, data))) => {
-                        endpoint.send(Event::LocalData(port_forward, port, data));
+                    Ok(Some((port, peer_addr, data))) => {
+                        if let Some(reason) = stats.refusal() {
+                            // While the port forward drains, the clients already served keep their sessions
+                            if !(stats.is_draining() && virtual_ports.contains(&port)) {
+                                trace!("[{}] Dropping datagram: {}", port, reason);
+                                continue;
+                            }
+                        }
+                        if !stats.admits_datagram(data.len()) {
+                            trace!("[{}] Dropping oversized datagram of {} bytes", port, data.len());
+                            continue;
+                        }
+                        if virtual_ports.insert(port) {
+                            port_pool.set_forward(port, port_forward.source).await;
+                        }
+                        if !port_forward.peer_routes.is_empty() {
+                            match reply_peers.get(&peer_addr) {
+                                Some(reply) => {
+                                    stats.record_tx(data.len());
+                                    endpoint.send(Event::LocalData(reply.clone(), port, data, Instant::now()));
+                                }
+                                None => trace!("[{}] Dropping datagram from {}: no peer reached it yet", port, peer_addr),
+                            }
+                            continue;
+                        }
+                        match resolver.destination(&port_forward).await {
+                            Ok(destination) => {
+                                if destination != resolved.destination {
+                                    resolved = Arc::new(PortForwardConfig { destination, ..PortForwardConfig::clone(&port_forward) });
+                                }
+                                stats.record_tx(data.len());
+                                endpoint.send(Event::LocalData(resolved.clone(), port, data, Instant::now()));
+                            }
+                            Err(e) => warn!("[{}] Dropping datagram: {:?}", port, e),
+                        }
                     }
                     Ok(None) => {
                         continue;
@@ -75,16 +142,58 @@ pub async fn udp_proxy_server(
                 }
             }
             event = endpoint.recv() => {
-                if let Event::RemoteData(port, data) = event {
+                if let Event::RemoteRelayData(destination, data) = &event {
+                    if let Some((relay_socket, local_target)) = &relay {
+                        if *destination == port_forward.destination {
+                            trace!("Relaying {} bytes to local network ({})", data.len(), local_target);
+                            match relay_socket.send_to(data, local_target).await {
+                                Ok(sent) => stats.record_rx(sent),
+                                Err(e) => error!(
+                                    "Failed to relay UDP datagram to local network ({}): {:?}",
+                                    local_target,
+                                    e,
+                                ),
+                            }
+                        }
+                    }
+                }
+                if let Event::RemotePeerData(port, peer, data, _) = &event {
+                    if virtual_ports.contains(port) {
+                        let destination = port_forward.peer_destination(peer.ip());
+                        if reply_peers.get(&destination).map_or(true, |reply| reply.destination != *peer) {
+                            let reply = PortForwardConfig { destination: *peer, ..PortForwardConfig::clone(&port_forward) };
+                            reply_peers.insert(destination, Arc::new(reply));
+                        }
+                        trace!("[{}] Sending {} bytes from {} to {}", port, data.len(), peer, destination);
+                        match socket.send_to(data, destination).await {
+                            Ok(sent) => stats.record_rx(sent),
+                            Err(e) => error!(
+                                "[{}] Failed to send UDP datagram to {}: {:?}",
+                                port,
+                                destination,
+                                e,
+                            ),
+                        }
+                        port_pool.update_last_transmit(*port).await;
+                    }
+                }
+                if let Event::RemoteData(port, data, _) = event {
+                    if !virtual_ports.contains(&port) {
+                        continue;
+                    }
+                    if let Some(quic_ids) = quic_ids.as_mut() {
+                        quic_ids.learn(port, &data);
+                    }
                     if let Some(peer) = port_pool.get_peer_addr(port).await {
                         trace!("Sending {} bytes to real client ({}->{})", data.len(), socket.local_addr().unwrap(), peer);
-                        if let Err(e) = socket.send_to(&data, peer).await {
-                            error!(
+                        match socket.send_to(&data, peer).await {
+                            Ok(sent) => stats.record_rx(sent),
+                            Err(e) => error!(
                                 "[{}] Failed to send UDP datagram to real client ({}): {:?}",
                                 port,
                                 peer,
                                 e,
-                            );
+                            ),
                         }
                         port_pool.update_last_transmit(port).await;
                     }
@@ -95,20 +204,67 @@ pub async fn udp_proxy_server(
     Ok(())
 }
 
+/// Prepares the listener of a relaying port forward to receive broadcast or multicast datagrams, and binds the
+/// socket relaying datagrams from the tunnel. Returns it with the local address to relay to.
+async fn bind_relay(
+    socket: &UdpSocket,
+    port_forward: &PortForwardConfig,
+    relay: DatagramRelay,
+) -> anyhow::Result<(UdpSocket, SocketAddr)> {
+    let relay_socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    let local_target = match (relay, port_forward.destination.ip()) {
+        (DatagramRelay::Multicast, IpAddr::V4(group)) => {
+            let interface = match port_forward.source.ip() {
+                IpAddr::V4(ip) => ip,
+                IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+            };
+            socket.join_multicast_v4(group, interface)?;
+            SocketAddr::from((group, port_forward.source.port()))
+        }
+        _ => {
+            socket.set_broadcast(true)?;
+            relay_socket.set_broadcast(true)?;
+            SocketAddr::from((Ipv4Addr::BROADCAST, port_forward.source.port()))
+        }
+    };
+    Ok((relay_socket, local_target))
+}
+
 async fn next_udp_datagram(
     socket: &UdpSocket,
     buffer: &mut [u8],
     port_pool: UdpPortPool,
-) -> anyhow::Result<Option<(VirtualPort, Vec<u8>)>> {
+    relay_port: Option<u16>,
+    session_mode: UdpSessionMode,
+    quic_ids: Option<&mut QuicConnectionIds>,
+    authorize: impl Fn(SocketAddr) -> bool,
+) -> anyhow::Result<Option<(VirtualPort, SocketAddr, Vec<u8>)>> {
     let (size, peer_addr) = socket
         .recv_from(buffer)
         .await
         .with_context(|| "Failed to accept incoming UDP datagram")?;
 
+    if relay_port == Some(peer_addr.port()) {
+        trace!(
+            "Ignoring datagram relayed to the local network ({})",
+            peer_addr
+        );
+        return Ok(None);
+    }
+
     // Assign a 'virtual port': this is a unique port number used to route IP packets
     // received from the WireGuard tunnel. It is the port number that the virtual client will
-    // listen on.
-    let port = match port_pool.next(peer_addr).await {
+    // listen on. QUIC clients whose address changed keep the virtual port of their connection, and
+    // the other new clients only get one once authorized.
+    let port = match quic_ids.and_then(|ids| ids.port_of(&buffer[..size])) {
+        Some(port) if port_pool.migrate(port, peer_addr).await => Ok(port),
+        _ if !port_pool.has_peer(peer_addr).await && !authorize(peer_addr) => {
+            debug!("Dropping datagram from {}: not authorized", peer_addr);
+            return Ok(None);
+        }
+        _ => port_pool.next(peer_addr, session_mode).await,
+    };
+    let port = match port {
         Ok(port) => port,
         Err(e) => {
             error!(
@@ -127,13 +283,19 @@ async fn next_udp_datagram(
     port_pool.update_last_transmit(port).await;
 
     let data = buffer[..size].to_vec();
-    Ok(Some((port, data)))
+    Ok(Some((port, peer_addr, data)))
 }
 
 /// A pool of virtual ports available for TCP connections.
 #[derive(Clone)]
 pub struct UdpPortPool {
     inner: Arc<tokio::sync::RwLock<UdpPortPoolInner>>,
+    /// What to do when all the ports are assigned to active clients.
+    exhaustion: PortPoolExhaustion,
+    /// Where `Event::PortPoolExhausted` and the released ports are reported, if anywhere.
+    events: Option<BusSender>,
+    /// Whether the ports are assigned in order instead of at random.
+    sequential: bool,
 }
 
 impl Default for UdpPortPool {
@@ -153,9 +315,36 @@ impl UdpPortPool {
             .for_each(|p| inner.queue.push_back(p) as ());
         Self {
             inner: Arc::new(tokio::sync::RwLock::new(inner)),
+            exhaustion: PortPoolExhaustion::default(),
+            events: None,
+            sequential: false,
         }
     }
 
+    /// Sets what to do when all the ports are assigned to active clients.
+    pub(crate) fn with_exhaustion(mut self, exhaustion: PortPoolExhaustion) -> Self {
+        self.exhaustion = exhaustion;
+        self
+    }
+
+    /// Reports the exhaustion of the pool, and the ports taken from their clients, on the bus.
+    pub(crate) fn with_events(mut self, bus: &Bus) -> Self {
+        self.events = Some(bus.new_endpoint().sender());
+        self
+    }
+
+    /// Assigns the ports in order instead of at random (see `Config::with_sequential_ports`).
+    pub(crate) fn with_sequential_ports(mut self, sequential: bool) -> Self {
+        if sequential {
+            self.inner
+                .try_write()
+                .expect("The pool is not shared yet")
+                .queue = PORT_RANGE.collect();
+        }
+        self.sequential = sequential;
+        self
+    }
+
     /// Takes the given port out of the pool, marking it with the given peer address, for an unlimited amount of time.
     pub async fn reserve(&self, port: u16, peer_addr: SocketAddr) -> anyhow::Result<VirtualPort> {
         let mut inner = self.inner.write().await;
@@ -164,69 +353,183 @@ impl UdpPortPool {
         Ok(VirtualPort::new(port, PortProtocol::Udp))
     }
 
-    /// Requests a free port from the pool. An error is returned if none is available (exhausted max capacity).
-    pub async fn next(&self, peer_addr: SocketAddr) -> anyhow::Result<VirtualPort> {
-        // A port found to be reused. This is outside of the block because the read lock cannot be upgraded to a write lock.
-        let mut port_reuse: Option<u16> = None;
+    /// Gives the datagrams from another peer address to the client of the reserved port, without changing the
+    /// address the port sends to.
+    pub async fn alias(&self, port: VirtualPort, peer_addr: SocketAddr) {
+        let mut inner = self.inner.write().await;
+        inner.port_by_peer_addr.insert(peer_addr, port.num());
+    }
 
+    /// Requests a free port from the pool, for a client of the given session mode. An error is returned if none is
+    /// available (exhausted max capacity).
+    pub async fn next(
+        &self,
+        peer_addr: SocketAddr,
+        session_mode: UdpSessionMode,
+    ) -> anyhow::Result<VirtualPort> {
         {
             let inner = self.inner.read().await;
             if let Some(port) = inner.port_by_peer_addr.get(&peer_addr) {
                 return Ok(VirtualPort::new(*port, PortProtocol::Udp));
             }
+        }
 
-            // Count how many ports are being used by the peer IP
-            let peer_ip = peer_addr.ip();
-            let peer_port_count = inner
-                .peer_port_usage
-                .get(&peer_ip)
-                .map(|v| v.len())
-                .unwrap_or_default();
-
-            if peer_port_count >= PORTS_PER_IP {
-                // Return least recently used port in this IP's pool
-                port_reuse = Some(
-                    *(inner
-                        .peer_port_usage
-                        .get(&peer_ip)
-                        .unwrap()
-                        .peek_min()
-                        .unwrap()
-                        .0),
+        let mut inner = self.inner.write().await;
+        let peer_ip = peer_addr.ip();
+        for port in inner.recycle_dns_ports(peer_ip) {
+            self.report_released(port);
+        }
+
+        // Count how many ports are being used by the peer IP
+        let peer_port_count = inner
+            .peer_port_usage
+            .get(&peer_ip)
+            .map(|v| v.len())
+            .unwrap_or_default();
+
+        let port_reuse = if peer_port_count >= PORTS_PER_IP {
+            // Reuse a port in this IP's pool: preferably an inactive one, otherwise the least recently used one, sparing
+            // idle QUIC connections if possible.
+            let (port, inactive) = inner.peer_port_usage[&peer_ip]
+                .iter()
+                .map(|(port, last)| (*port, inner.is_inactive(*port, *last), *last))
+                .min_by_key(|(port, inactive, last)| {
+                    (
+                        !inactive,
+                        inner.session_mode(*port) == UdpSessionMode::Quic,
+                        *last,
+                    )
+                })
+                .map(|(port, inactive, _)| (port, inactive))
+                .unwrap();
+            if inactive {
+                debug!(
+                    "Peer [{}] is re-using inactive virtual port {} due to self-exhaustion.",
+                    peer_addr, port
                 );
+            } else {
                 warn!(
                     "Peer [{}] is re-using active virtual port {} due to self-exhaustion.",
-                    peer_addr,
-                    port_reuse.unwrap()
+                    peer_addr, port
                 );
             }
-        }
-
-        let mut inner = self.inner.write().await;
+            Some(port)
+        } else {
+            None
+        };
 
+        let evict = self.exhaustion == PortPoolExhaustion::EvictOldest;
         let port = port_reuse
-            .or_else(|| inner.queue.pop_front())
+            .or_else(|| take_free_port(&mut inner.queue, self.sequential))
             .or_else(|| {
                 // If there is no port to reuse, and the port pool is exhausted, take the last recently used port overall,
-                // as long as the last transmission exceeds the deadline
-                let last: (&u16, &Instant) = inner.port_usage.peek_min().unwrap();
-                if Instant::now().duration_since(*last.1).as_secs() > UDP_TIMEOUT_SECONDS {
+                // as long as it is inactive, unless active sessions may be evicted
+                let (port, inactive) = inner
+                    .port_usage
+                    .iter()
+                    .map(|(port, last)| (*port, inner.is_inactive(*port, *last), *last))
+                    .filter(|(_, inactive, _)| *inactive || evict)
+                    .min_by_key(|(_, inactive, last)| (!inactive, *last))
+                    .map(|(port, inactive, _)| (port, inactive))?;
+                if inactive {
                     warn!(
                         "Peer [{}] is re-using inactive virtual port {} due to global exhaustion.",
-                        peer_addr, last.0
+                        peer_addr, port
                     );
-                    Some(*last.0)
                 } else {
-                    None
+                    warn!(
+                        "Peer [{}] is evicting the session of active virtual port {} due to global exhaustion.",
+                        peer_addr, port
+                    );
+                    inner.exhaustions += 1;
+                    inner.evictions += 1;
+                    self.report_exhaustion();
                 }
-            })
-            .with_context(|| "virtual port pool is exhausted")?;
+                Some(port)
+            });
+        let port = match port {
+            Some(port) => port,
+            None => {
+                inner.exhaustions += 1;
+                self.report_exhaustion();
+                return Err(anyhow::anyhow!("virtual port pool is exhausted"));
+            }
+        };
 
-        inner.port_by_peer_addr.insert(peer_addr, port);
-        inner.peer_addr_by_port.insert(port, peer_addr);
+        inner.allocations += 1;
+        if inner.unassign(port) {
+            self.report_released(port);
+        }
+        inner.assign(port, peer_addr, session_mode);
         Ok(VirtualPort::new(port, PortProtocol::Udp))
     }
 
+    fn report_exhaustion(&self) {
+        if let Some(events) = &self.events {
+            events.send(Event::PortPoolExhausted(PortProtocol::Udp));
+        }
+    }
+
+    /// Reports that the port was taken from its client, so that the state of its session is forgotten before the
+    /// port is given to another one.
+    fn report_released(&self, port: u16) {
+        if let Some(events) = &self.events {
+            events.send(Event::ClientConnectionDropped(
+                VirtualPort::new(port, PortProtocol::Udp),
+                DropReason::Timeout,
+                Instant::now(),
+            ));
+        }
+    }
+
+    /// Releases a port back into the pool, forgetting the state of its session.
+    pub async fn release(&self, port: VirtualPort) {
+        let mut inner = self.inner.write().await;
+        if inner.unassign(port.num()) {
+            inner.queue.push_back(port.num());
+            self.report_released(port.num());
+        }
+    }
+
+    /// Whether a virtual port is assigned to the client.
+    pub async fn has_peer(&self, peer_addr: SocketAddr) -> bool {
+        self.inner
+            .read()
+            .await
+            .port_by_peer_addr
+            .contains_key(&peer_addr)
+    }
+
+    /// Moves the given QUIC client's virtual port to its new peer address. Returns whether it was moved, or already
+    /// assigned to that address.
+    pub async fn migrate(&self, port: VirtualPort, peer_addr: SocketAddr) -> bool {
+        let mut inner = self.inner.write().await;
+        match inner.peer_addr_by_port.get(&port.num()) {
+            Some(peer) if *peer == peer_addr => return true,
+            Some(_) if inner.session_mode(port.num()) == UdpSessionMode::Quic => {}
+            _ => return false,
+        }
+        debug!(
+            "[{}] QUIC connection moved from {} to {}",
+            port,
+            inner.peer_addr_by_port[&port.num()],
+            peer_addr
+        );
+        // The port given to the new address before the connection was recognized goes back to the pool
+        if let Some(previous) = inner.port_by_peer_addr.get(&peer_addr).copied() {
+            inner.unassign(previous);
+            inner.queue.push_back(previous);
+            self.report_released(previous);
+        }
+        let forward = inner.forward_by_port.get(&port.num()).copied();
+        inner.unassign(port.num());
+        inner.assign(port.num(), peer_addr, UdpSessionMode::Quic);
+        if let Some(forward) = forward {
+            inner.forward_by_port.insert(port.num(), forward);
+        }
+        true
+    }
+
     /// Notify that the given virtual port has received or transmitted a UDP datagram.
     pub async fn update_last_transmit(&self, port: VirtualPort) {
         let mut inner = self.inner.write().await;
@@ -245,6 +548,118 @@ impl UdpPortPool {
         let inner = self.inner.read().await;
         inner.peer_addr_by_port.get(&port.num()).copied()
     }
+
+    /// Records the listen address of the local port forward whose client got the virtual port.
+    pub async fn set_forward(&self, port: VirtualPort, forward: SocketAddr) {
+        let mut inner = self.inner.write().await;
+        if inner.peer_addr_by_port.contains_key(&port.num()) {
+            inner.forward_by_port.insert(port.num(), forward);
+        }
+    }
+
+    /// The virtual ports assigned to the clients of the local port forward listening on the given address.
+    pub async fn ports_of_forward(&self, forward: SocketAddr) -> Vec<VirtualPort> {
+        let inner = self.inner.read().await;
+        inner
+            .forward_by_port
+            .iter()
+            .filter(|(_, f)| **f == forward)
+            .map(|(port, _)| VirtualPort::new(*port, PortProtocol::Udp))
+            .collect()
+    }
+
+    /// The occupancy and allocation counters of the pool.
+    pub async fn stats(&self) -> PortPoolStats {
+        let inner = self.inner.read().await;
+        PortPoolStats {
+            capacity: PORT_RANGE.len(),
+            in_use: inner.peer_addr_by_port.len(),
+            allocations: inner.allocations,
+            exhaustions: inner.exhaustions,
+            evictions: inner.evictions,
+        }
+    }
+
+    /// How many clients of the local port forward listening on the given address are still active, given
+    /// the idle timeout of their session mode.
+    pub async fn active_sessions_of_forward(&self, forward: SocketAddr) -> usize {
+        let inner = self.inner.read().await;
+        inner
+            .forward_by_port
+            .iter()
+            .filter(|(port, f)| {
+                **f == forward
+                    && inner
+                        .port_usage
+                        .get_priority(port)
+                        .map_or(false, |last| !inner.is_inactive(**port, *last))
+            })
+            .count()
+    }
+
+    /// The clients of the local port forwards, with their virtual port.
+    pub async fn sessions(&self) -> Vec<UdpSession> {
+        let inner = self.inner.read().await;
+        let mut sessions: Vec<UdpSession> = inner
+            .forward_by_port
+            .iter()
+            .filter_map(|(port, forward)| {
+                Some(UdpSession {
+                    forward: *forward,
+                    peer_addr: *inner.peer_addr_by_port.get(port)?,
+                    virtual_port: *port,
+                    session_mode: inner.session_mode(*port),
+                })
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.virtual_port);
+        sessions
+    }
+
+    /// Assigns the virtual ports of the sessions to their clients again, unless they were taken meanwhile.
+    /// Returns how many were restored.
+    pub async fn restore(&self, sessions: &[UdpSession]) -> usize {
+        let mut inner = self.inner.write().await;
+        let mut restored = 0;
+        for session in sessions {
+            let port = session.virtual_port;
+            match inner.queue.iter().position(|p| *p == port) {
+                Some(index) if !inner.port_by_peer_addr.contains_key(&session.peer_addr) => {
+                    inner.queue.remove(index);
+                }
+                _ => {
+                    debug!(
+                        "Virtual port {} of {} is no longer available",
+                        port, session.peer_addr
+                    );
+                    continue;
+                }
+            }
+            inner.assign(port, session.peer_addr, session.session_mode);
+            inner.forward_by_port.insert(port, session.forward);
+            // The idle timeout of the session starts over
+            let now = Instant::now();
+            inner.port_usage.push(port, now);
+            inner
+                .peer_port_usage
+                .entry(session.peer_addr.ip())
+                .or_insert_with(Default::default)
+                .push(port, now);
+            restored += 1;
+        }
+        restored
+    }
+}
+
+/// The client of a local UDP port forward and its virtual port, saved across restarts.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UdpSession {
+    /// The listen address of the port forward.
+    pub forward: SocketAddr,
+    /// The address of the client.
+    pub peer_addr: SocketAddr,
+    pub virtual_port: u16,
+    pub session_mode: UdpSessionMode,
 }
 
 /// Non thread-safe inner logic for UDP port pool.
@@ -262,4 +677,385 @@ struct UdpPortPoolInner {
     peer_port_usage: HashMap<IpAddr, DoublePriorityQueue<u16, Instant>>,
     /// Keeps an ordered map of the most recently used virtual ports in general.
     port_usage: DoublePriorityQueue<u16, Instant>,
+    /// The session mode of the clients assigned to virtual ports, if not generic.
+    session_modes: HashMap<u16, UdpSessionMode>,
+    /// The listen address of the local port forward of the clients assigned to virtual ports.
+    forward_by_port: HashMap<u16, SocketAddr>,
+    /// How many ports were assigned.
+    allocations: u64,
+    /// How many times no port was free or inactive.
+    exhaustions: u64,
+    /// How many active sessions lost their port.
+    evictions: u64,
+}
+
+impl UdpPortPoolInner {
+    fn session_mode(&self, port: u16) -> UdpSessionMode {
+        self.session_modes.get(&port).copied().unwrap_or_default()
+    }
+
+    /// Whether the client of the port has been idle for longer than its session mode allows.
+    fn is_inactive(&self, port: u16, last_transmit: Instant) -> bool {
+        last_transmit.elapsed() > self.session_mode(port).idle_timeout()
+    }
+
+    fn assign(&mut self, port: u16, peer_addr: SocketAddr, session_mode: UdpSessionMode) {
+        self.port_by_peer_addr.insert(peer_addr, port);
+        self.peer_addr_by_port.insert(port, peer_addr);
+        if session_mode != UdpSessionMode::Generic {
+            self.session_modes.insert(port, session_mode);
+        }
+    }
+
+    /// Forgets the client assigned to the port, if any. Returns whether there was one.
+    fn unassign(&mut self, port: u16) -> bool {
+        self.session_modes.remove(&port);
+        self.forward_by_port.remove(&port);
+        self.port_usage.remove(&port);
+        match self.peer_addr_by_port.remove(&port) {
+            Some(peer) => {
+                if self.port_by_peer_addr.get(&peer) == Some(&port) {
+                    self.port_by_peer_addr.remove(&peer);
+                }
+                if let Some(usage) = self.peer_port_usage.get_mut(&peer.ip()) {
+                    usage.remove(&port);
+                    if usage.is_empty() {
+                        self.peer_port_usage.remove(&peer.ip());
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Puts the ports of the peer IP's finished DNS exchanges back in the pool. Returns the recycled ports.
+    fn recycle_dns_ports(&mut self, peer_ip: IpAddr) -> Vec<u16> {
+        let finished: Vec<u16> = match self.peer_port_usage.get(&peer_ip) {
+            Some(usage) => usage
+                .iter()
+                .filter(|(port, last)| {
+                    self.session_mode(**port) == UdpSessionMode::Dns
+                        && self.is_inactive(**port, **last)
+                })
+                .map(|(port, _)| *port)
+                .collect(),
+            None => return Vec::new(),
+        };
+        for port in &finished {
+            trace!("Recycling virtual port {} of finished DNS exchange", port);
+            self.unassign(*port);
+            self.queue.push_back(*port);
+        }
+        finished
+    }
+}
+
+/// The destinations each virtual port of the local UDP port forwards sent datagrams to, which decide the
+/// sources allowed to answer it (see `ResponseFilter`). Kept by the virtual interface, which knows the source
+/// of each datagram before it is sent back to the client.
+#[derive(Debug, Default)]
+pub struct ResponseGuard {
+    destinations: HashMap<VirtualPort, (ResponseFilter, HashSet<SocketAddr>)>,
+}
+
+impl ResponseGuard {
+    /// Records a datagram of the port forward, sent from the virtual port to the destination.
+    pub fn record_sent(
+        &mut self,
+        port_forward: &PortForwardConfig,
+        virtual_port: VirtualPort,
+        destination: SocketAddr,
+    ) {
+        // Remote port forwards are servers, which any peer may reach
+        if port_forward.remote {
+            return;
+        }
+        // Broadcast and multicast datagrams are answered by the hosts that received them
+        let filter = match port_forward.relay {
+            Some(_) => ResponseFilter::Off,
+            None => port_forward.response_filter,
+        };
+        let (current, destinations) = self
+            .destinations
+            .entry(virtual_port)
+            .or_insert_with(|| (filter, HashSet::new()));
+        *current = filter;
+        destinations.insert(destination);
+    }
+
+    /// Forgets the destinations of the virtual port, given to another client.
+    pub fn forget(&mut self, virtual_port: VirtualPort) {
+        self.destinations.remove(&virtual_port);
+    }
+
+    /// Whether the virtual port may receive a datagram from the source. Virtual ports that didn't send any
+    /// datagram receive from any source.
+    pub fn allows(&self, virtual_port: VirtualPort, source: SocketAddr) -> bool {
+        let (filter, destinations) = match self.destinations.get(&virtual_port) {
+            Some(sent) => sent,
+            None => return true,
+        };
+        match filter {
+            ResponseFilter::Strict => destinations.contains(&source),
+            ResponseFilter::Loose => destinations
+                .iter()
+                .any(|destination| destination.ip() == source.ip()),
+            ResponseFilter::Off => true,
+        }
+    }
+}
+
+/// The QUIC connection IDs chosen by the servers of a port forward, which clients send in the short header of
+/// their packets (RFC 9000, section 17.3). Servers give them in the long header of their handshake packets.
+#[derive(Debug, Default)]
+struct QuicConnectionIds {
+    ports: HashMap<Vec<u8>, (VirtualPort, Instant)>,
+}
+
+impl QuicConnectionIds {
+    /// Learns the connection ID chosen by the server, from a datagram it sent to the given virtual port.
+    fn learn(&mut self, port: VirtualPort, datagram: &[u8]) {
+        if let Some(id) = long_header_source_id(datagram) {
+            let timeout = UdpSessionMode::Quic.idle_timeout();
+            self.ports.retain(|_, (_, seen)| seen.elapsed() < timeout);
+            self.ports.insert(id.to_vec(), (port, Instant::now()));
+        }
+    }
+
+    /// The virtual port of the connection of a short header packet sent by a client.
+    fn port_of(&mut self, datagram: &[u8]) -> Option<VirtualPort> {
+        if datagram.first()? & 0xc0 != 0x40 {
+            return None;
+        }
+        let (port, seen) = self
+            .ports
+            .iter_mut()
+            .find(|(id, _)| datagram.get(1..1 + id.len()) == Some(id.as_slice()))?
+            .1;
+        *seen = Instant::now();
+        Some(*port)
+    }
+}
+
+/// The source connection ID of a QUIC long header packet (RFC 9000, section 17.2), if not empty.
+fn long_header_source_id(datagram: &[u8]) -> Option<&[u8]> {
+    // Version negotiation packets have a zero version
+    if datagram.first()? & 0x80 == 0 || datagram.get(1..5)? == [0, 0, 0, 0] {
+        return None;
+    }
+    let dcid_len = *datagram.get(5)? as usize;
+    let scid_len = *datagram.get(6 + dcid_len)? as usize;
+    if dcid_len > MAX_QUIC_CID_LEN || scid_len == 0 || scid_len > MAX_QUIC_CID_LEN {
+        return None;
+    }
+    datagram.get(7 + dcid_len..7 + dcid_len + scid_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_session_modes() {
+        let pool = UdpPortPool::new();
+        let resolver: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let dns_port = pool.next(resolver, UdpSessionMode::Dns).await.unwrap();
+        pool.update_last_transmit(dns_port).await;
+        let quic_client: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+        let quic_port = pool.next(quic_client, UdpSessionMode::Quic).await.unwrap();
+        pool.update_last_transmit(quic_port).await;
+
+        // Both clients have been idle for 10 seconds: the DNS exchange is over, the QUIC connection isn't
+        {
+            let mut inner = pool.inner.write().await;
+            let idle = Instant::now() - Duration::from_secs(10);
+            for port in [dns_port.num(), quic_port.num()] {
+                inner.port_usage.push(port, idle);
+                inner
+                    .peer_port_usage
+                    .get_mut(&resolver.ip())
+                    .unwrap()
+                    .push(port, idle);
+            }
+        }
+        let other: SocketAddr = "127.0.0.1:40002".parse().unwrap();
+        pool.next(other, UdpSessionMode::Generic).await.unwrap();
+        assert_eq!(pool.get_peer_addr(dns_port).await, None);
+        assert_eq!(pool.get_peer_addr(quic_port).await, Some(quic_client));
+
+        // The client moves to another address, with the connection ID chosen by the server
+        let mut ids = QuicConnectionIds::default();
+        let mut initial = vec![0xc0, 0, 0, 0, 1, 4, 1, 2, 3, 4, 8];
+        initial.extend_from_slice(b"serverid");
+        ids.learn(quic_port, &initial);
+        let mut short = vec![0x40];
+        short.extend_from_slice(b"serverid");
+        short.extend_from_slice(b"payload");
+        assert_eq!(ids.port_of(&short), Some(quic_port));
+        assert_eq!(ids.port_of(b"\x40unknown-connection"), None);
+
+        let moved: SocketAddr = "127.0.0.1:40003".parse().unwrap();
+        assert!(pool.migrate(quic_port, moved).await);
+        assert_eq!(pool.get_peer_addr(quic_port).await, Some(moved));
+        assert_eq!(
+            pool.next(moved, UdpSessionMode::Quic).await.unwrap(),
+            quic_port
+        );
+        let generic_port = pool.next(other, UdpSessionMode::Generic).await.unwrap();
+        assert!(!pool.migrate(generic_port, moved).await);
+    }
+
+    /// Tests that the replies of the destinations of peer routes get the port of their remote port forward.
+    #[tokio::test]
+    async fn test_alias() {
+        let pool = UdpPortPool::new();
+        let destination: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let route: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        let port = pool.reserve(5000, destination).await.unwrap();
+        pool.alias(port, route).await;
+        assert_eq!(
+            pool.next(route, UdpSessionMode::Generic).await.unwrap(),
+            port
+        );
+        assert_eq!(pool.get_peer_addr(port).await, Some(destination));
+    }
+
+    #[tokio::test]
+    async fn test_released_ports() {
+        let bus = Bus::new();
+        let mut endpoint = bus.new_endpoint();
+        let pool = UdpPortPool::new().with_events(&bus);
+        for i in 0..PORTS_PER_IP as u16 {
+            let port = pool
+                .next(
+                    SocketAddr::from(([127, 0, 0, 1], 40000 + i)),
+                    UdpSessionMode::Generic,
+                )
+                .await
+                .unwrap();
+            pool.update_last_transmit(port).await;
+        }
+
+        // The session of a client is over when its port is given to the next client of the same IP
+        let client: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let port = pool.next(client, UdpSessionMode::Generic).await.unwrap();
+        assert!(matches!(
+            endpoint.recv().await,
+            Event::ClientConnectionDropped(vp, DropReason::Timeout, _) if vp == port
+        ));
+        assert_eq!(pool.get_peer_addr(port).await, Some(client));
+
+        let mut guard = ResponseGuard::default();
+        let port_forward = PortForwardConfig::new(
+            "127.0.0.1:53".parse().unwrap(),
+            "192.168.4.2:53".parse().unwrap(),
+            PortProtocol::Udp,
+        );
+        guard.record_sent(&port_forward, port, port_forward.destination);
+        assert!(!guard.allows(port, "192.168.4.3:53".parse().unwrap()));
+        guard.forget(port);
+        assert!(guard.allows(port, "192.168.4.3:53".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_exhaustion() {
+        let bus = Bus::new();
+        let mut endpoint = bus.new_endpoint();
+        let first: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let second: SocketAddr = "127.0.0.2:40000".parse().unwrap();
+        for exhaustion in [PortPoolExhaustion::Reject, PortPoolExhaustion::EvictOldest] {
+            let pool = UdpPortPool::new()
+                .with_exhaustion(exhaustion)
+                .with_events(&bus);
+            // A single port is left, taken by an active client
+            pool.inner.write().await.queue.truncate(1);
+            let port = pool.next(first, UdpSessionMode::Generic).await.unwrap();
+            pool.update_last_transmit(port).await;
+
+            let result = pool.next(second, UdpSessionMode::Generic).await;
+            assert!(matches!(
+                endpoint.recv().await,
+                Event::PortPoolExhausted(PortProtocol::Udp)
+            ));
+            let stats = pool.stats().await;
+            assert_eq!(stats.in_use, 1);
+            assert_eq!(stats.allocations, 1 + result.is_ok() as u64);
+            assert_eq!(stats.exhaustions, 1);
+            if exhaustion == PortPoolExhaustion::Reject {
+                assert!(result.is_err());
+                assert_eq!(stats.evictions, 0);
+            } else {
+                assert_eq!(result.unwrap(), port);
+                assert_eq!(pool.get_peer_addr(port).await, Some(second));
+                assert_eq!(stats.evictions, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_response_guard() {
+        let destination: SocketAddr = "192.168.4.2:53".parse().unwrap();
+        let mut port_forward = PortForwardConfig::new(
+            "127.0.0.1:53".parse().unwrap(),
+            destination,
+            PortProtocol::Udp,
+        );
+        let (strict, loose, off, remote, group) = (
+            VirtualPort::new(1001, PortProtocol::Udp),
+            VirtualPort::new(1002, PortProtocol::Udp),
+            VirtualPort::new(1003, PortProtocol::Udp),
+            VirtualPort::new(1004, PortProtocol::Udp),
+            VirtualPort::new(1005, PortProtocol::Udp),
+        );
+        let other_port: SocketAddr = "192.168.4.2:5353".parse().unwrap();
+        let other_ip: SocketAddr = "192.168.4.3:53".parse().unwrap();
+
+        let mut guard = ResponseGuard::default();
+        port_forward.response_filter = ResponseFilter::Strict;
+        guard.record_sent(&port_forward, strict, destination);
+        assert!(guard.allows(strict, destination));
+        assert!(!guard.allows(strict, other_port));
+        assert!(!guard.allows(strict, other_ip));
+
+        port_forward.response_filter = ResponseFilter::Loose;
+        guard.record_sent(&port_forward, loose, destination);
+        assert!(guard.allows(loose, other_port));
+        assert!(!guard.allows(loose, other_ip));
+        port_forward.relay = Some(DatagramRelay::Broadcast);
+        guard.record_sent(&port_forward, group, "192.168.4.255:53".parse().unwrap());
+        assert!(guard.allows(group, other_ip));
+        port_forward.relay = None;
+
+        port_forward.response_filter = ResponseFilter::Off;
+        guard.record_sent(&port_forward, off, destination);
+        assert!(guard.allows(off, other_ip));
+
+        port_forward.remote = true;
+        port_forward.response_filter = ResponseFilter::Strict;
+        guard.record_sent(&port_forward, remote, destination);
+        assert!(guard.allows(remote, other_ip));
+    }
+
+    #[tokio::test]
+    async fn test_release() {
+        let bus = Bus::new();
+        let mut endpoint = bus.new_endpoint();
+        let pool = UdpPortPool::new().with_events(&bus);
+        let client: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let port = pool.next(client, UdpSessionMode::Generic).await.unwrap();
+        pool.update_last_transmit(port).await;
+
+        pool.release(port).await;
+        assert!(matches!(
+            endpoint.recv().await,
+            Event::ClientConnectionDropped(vp, DropReason::Timeout, _) if vp == port
+        ));
+        assert_eq!(pool.get_peer_addr(port).await, None);
+        assert_ne!(
+            pool.next(client, UdpSessionMode::Generic).await.unwrap(),
+            port
+        );
+    }
 }
@@ -0,0 +1,399 @@
+//! Batched I/O on the UDP socket connected to the WireGuard endpoint.
+//!
+//! On Linux, `sendmmsg(2)` and `recvmmsg(2)` are used to move a whole batch of datagrams per syscall,
+//! which matters at high packet rates. The runs of datagrams of the same size, such as the full packets of a bulk
+//! transfer, are sent as a single buffer that the kernel or the network card splits (UDP segmentation offload,
+//! `UDP_SEGMENT`), when the kernel supports it (Linux 4.18): it is probed once, and turned off for good if a
+//! segmented send fails. Other platforms fall back to one syscall per datagram, through the `DatagramSocket` of
+//! the engine.
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+
+use crate::engine::DatagramSocket;
+
+/// The maximum number of datagrams sent or received at once.
+pub const BATCH_SIZE: usize = 32;
+
+/// The most datagrams in one segmented send (the kernel's `UDP_MAX_SEGMENTS`).
+#[cfg(target_os = "linux")]
+const MAX_SEGMENTS: usize = 64;
+/// The most bytes in one segmented send: the payload of the largest IPv4 UDP datagram.
+#[cfg(target_os = "linux")]
+const MAX_SEGMENTED_BYTES: usize = u16::MAX as usize - 28;
+
+/// The socket to the endpoint, as the engine sees it. It doesn't block: each call fails with `WouldBlock` until
+/// the socket is ready, which the functions of this module wait for.
+impl DatagramSocket for UdpSocket {
+    fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.try_send_to(datagram, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.try_recv_from(buf)
+    }
+}
+
+/// Sends all the datagrams to the destination, in order.
+pub async fn send_batch(udp: &UdpSocket, packets: &[Vec<u8>], dst: SocketAddr) -> io::Result<()> {
+    let mut sent = 0;
+    while sent < packets.len() {
+        sent += send_some(udp, &packets[sent..], dst).await?;
+    }
+    Ok(())
+}
+
+/// Waits for at least one datagram, then reads as many as are immediately available
+/// (up to one per buffer). Returns the size and source of each datagram, in the buffer order.
+pub async fn recv_batch(
+    udp: &UdpSocket,
+    buffers: &mut [Vec<u8>],
+) -> io::Result<Vec<(usize, SocketAddr)>> {
+    recv_some(udp, buffers).await
+}
+
+#[cfg(target_os = "linux")]
+async fn send_some(udp: &UdpSocket, packets: &[Vec<u8>], dst: SocketAddr) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+    use tokio::io::Interest;
+
+    loop {
+        udp.writable().await?;
+        let segmented = sys::segmentation_enabled();
+        let run = if segmented { segment_run(packets) } else { 1 };
+        let result = udp.try_io(Interest::WRITABLE, || {
+            if run > 1 {
+                return sys::send_segments(udp.as_raw_fd(), &packets[..run], dst).map(|_| run);
+            }
+            // The datagrams up to the next run to segment
+            let end = (1..packets.len())
+                .find(|&start| segmented && segment_run(&packets[start..]) > 1)
+                .unwrap_or(packets.len());
+            sys::sendmmsg(udp.as_raw_fd(), &packets[..end], dst)
+        });
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) if run > 1 && sys::is_segmentation_error(&e) => {
+                warn!(
+                    "UDP segmentation offload failed, sending the datagrams one by one: {}",
+                    e
+                );
+                sys::disable_segmentation();
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How many of the leading datagrams are sent together by a segmented send: the datagrams of the size of the
+/// first, and a smaller one ending the run, within the limits of the kernel.
+#[cfg(target_os = "linux")]
+fn segment_run(packets: &[Vec<u8>]) -> usize {
+    let size = match packets.first() {
+        Some(packet) if !packet.is_empty() => packet.len(),
+        _ => return packets.len().min(1),
+    };
+    let (mut count, mut total) = (0, 0);
+    for packet in packets.iter().take(MAX_SEGMENTS) {
+        if packet.is_empty() || packet.len() > size || total + packet.len() > MAX_SEGMENTED_BYTES {
+            break;
+        }
+        count += 1;
+        total += packet.len();
+        if packet.len() < size {
+            break;
+        }
+    }
+    count
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn send_some(udp: &UdpSocket, packets: &[Vec<u8>], dst: SocketAddr) -> io::Result<usize> {
+    loop {
+        udp.writable().await?;
+        match DatagramSocket::send_to(udp, &packets[0], dst) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result.map(|_| 1),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn recv_some(
+    udp: &UdpSocket,
+    buffers: &mut [Vec<u8>],
+) -> io::Result<Vec<(usize, SocketAddr)>> {
+    use std::os::unix::io::AsRawFd;
+    use tokio::io::Interest;
+
+    loop {
+        udp.readable().await?;
+        match udp.try_io(Interest::READABLE, || {
+            sys::recvmmsg(udp.as_raw_fd(), buffers)
+        }) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn recv_some(
+    udp: &UdpSocket,
+    buffers: &mut [Vec<u8>],
+) -> io::Result<Vec<(usize, SocketAddr)>> {
+    let mut received = loop {
+        udp.readable().await?;
+        match DatagramSocket::recv_from(udp, &mut buffers[0]) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => break vec![result?],
+        }
+    };
+    for buffer in buffers.iter_mut().skip(1) {
+        match DatagramSocket::recv_from(udp, buffer) {
+            Ok(datagram) => received.push(datagram),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(received)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Once;
+
+    use socket2::SockAddr;
+
+    /// Whether the runs of datagrams of the same size are segmented by the kernel: it supports `UDP_SEGMENT`,
+    /// and no segmented send failed yet.
+    static SEGMENTATION: AtomicBool = AtomicBool::new(false);
+    /// Probes the support of `UDP_SEGMENT` on the first send.
+    static PROBE: Once = Once::new();
+
+    /// Whether the kernel supports `UDP_SEGMENT`. The older kernels ignore the control message, and would send
+    /// the run as a single datagram, so the socket option is tried first.
+    fn probe_segmentation() -> bool {
+        let socket = match std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)) {
+            Ok(socket) => socket,
+            Err(_) => return false,
+        };
+        let size: libc::c_int = 1280;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                &size as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        result == 0
+    }
+
+    pub fn segmentation_enabled() -> bool {
+        PROBE.call_once(|| SEGMENTATION.store(probe_segmentation(), Ordering::Relaxed));
+        SEGMENTATION.load(Ordering::Relaxed)
+    }
+
+    pub fn disable_segmentation() {
+        SEGMENTATION.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether a segmented send failed because the kernel or the network card can't segment the datagrams, such
+    /// as without checksum offload.
+    pub fn is_segmentation_error(e: &io::Error) -> bool {
+        matches!(
+            e.raw_os_error(),
+            Some(libc::EIO) | Some(libc::EINVAL) | Some(libc::ENOPROTOOPT) | Some(libc::EOPNOTSUPP)
+        )
+    }
+
+    /// Sends a run of datagrams (see `segment_run`) with a single `sendmsg(2)` call, which the kernel or the
+    /// network card splits into datagrams of the size of the first.
+    pub fn send_segments(fd: RawFd, packets: &[Vec<u8>], dst: SocketAddr) -> io::Result<()> {
+        let dst = SockAddr::from(dst);
+        let mut iovecs: Vec<libc::iovec> = packets
+            .iter()
+            .map(|packet| libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            })
+            .collect();
+        // Aligned for the header of the control message
+        let mut control = [0u64; 4];
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_name = dst.as_ptr() as *mut libc::c_void;
+        message.msg_namelen = dst.len();
+        message.msg_iov = iovecs.as_mut_ptr();
+        message.msg_iovlen = iovecs.len() as _;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as _;
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_UDP;
+            (*header).cmsg_type = libc::UDP_SEGMENT;
+            (*header).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(header) as *mut u16, packets[0].len() as u16);
+        }
+
+        let sent = unsafe { libc::sendmsg(fd, &message, 0) };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends the datagrams with a single `sendmmsg(2)` call, returning how many were sent.
+    pub fn sendmmsg(fd: RawFd, packets: &[Vec<u8>], dst: SocketAddr) -> io::Result<usize> {
+        let dst = SockAddr::from(dst);
+        let mut iovecs: Vec<libc::iovec> = packets
+            .iter()
+            .take(super::BATCH_SIZE)
+            .map(|packet| libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            })
+            .collect();
+        let mut messages: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iovec| {
+                let mut message: libc::mmsghdr = unsafe { mem::zeroed() };
+                message.msg_hdr.msg_name = dst.as_ptr() as *mut libc::c_void;
+                message.msg_hdr.msg_namelen = dst.len();
+                message.msg_hdr.msg_iov = iovec;
+                message.msg_hdr.msg_iovlen = 1;
+                message
+            })
+            .collect();
+
+        let sent = unsafe { libc::sendmmsg(fd, messages.as_mut_ptr(), messages.len() as _, 0) };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(sent as usize)
+        }
+    }
+
+    /// Receives datagrams with a single `recvmmsg(2)` call, one per buffer at most.
+    pub fn recvmmsg(fd: RawFd, buffers: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let mut addresses: Vec<libc::sockaddr_storage> =
+            vec![unsafe { mem::zeroed() }; buffers.len()];
+        let mut iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buffer| libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            })
+            .collect();
+        let mut messages: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addresses.iter_mut())
+            .map(|(iovec, address)| {
+                let mut message: libc::mmsghdr = unsafe { mem::zeroed() };
+                message.msg_hdr.msg_name = address as *mut _ as *mut libc::c_void;
+                message.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+                message.msg_hdr.msg_iov = iovec;
+                message.msg_hdr.msg_iovlen = 1;
+                message
+            })
+            .collect();
+
+        let received = unsafe {
+            libc::recvmmsg(
+                fd,
+                messages.as_mut_ptr(),
+                messages.len() as _,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut datagrams = Vec::with_capacity(received as usize);
+        for (message, address) in messages.iter().zip(addresses).take(received as usize) {
+            let source = unsafe { SockAddr::new(address, message.msg_hdr.msg_namelen) };
+            let source = source.as_socket().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Unsupported address family")
+            })?;
+            datagrams.push((message.msg_len as usize, source));
+        }
+        Ok(datagrams)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_and_recv_batch() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let packets: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 100 + i as usize]).collect();
+        send_batch(&sender, &packets, receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut buffers = vec![vec![0u8; 1500]; BATCH_SIZE];
+        let mut received = Vec::new();
+        while received.len() < packets.len() {
+            let datagrams = recv_batch(&receiver, &mut buffers).await.unwrap();
+            for (buffer, (size, source)) in buffers.iter().zip(datagrams) {
+                assert_eq!(source, sender.local_addr().unwrap());
+                received.push(buffer[..size].to_vec());
+            }
+        }
+        assert_eq!(received, packets);
+    }
+
+    /// Tests that the runs of datagrams of the same size arrive as separate datagrams, whether the kernel
+    /// segments them or not.
+    #[tokio::test]
+    async fn test_send_segments() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut packets: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 1200]).collect();
+        packets.push(vec![5; 100]);
+        packets.push(vec![6; 1200]);
+        send_batch(&sender, &packets, receiver.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut buffers = vec![vec![0u8; 1500]; BATCH_SIZE];
+        let mut received = Vec::new();
+        while received.len() < packets.len() {
+            let datagrams = recv_batch(&receiver, &mut buffers).await.unwrap();
+            for (buffer, (size, _)) in buffers.iter().zip(datagrams) {
+                received.push(buffer[..size].to_vec());
+            }
+        }
+        assert_eq!(received, packets);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_segment_run() {
+        let sizes = |sizes: &[usize]| -> Vec<Vec<u8>> {
+            sizes.iter().map(|size| vec![0u8; *size]).collect()
+        };
+        assert_eq!(segment_run(&sizes(&[1200, 1200, 1200, 100, 1200])), 4);
+        assert_eq!(segment_run(&sizes(&[1200, 1300])), 1);
+        assert_eq!(segment_run(&sizes(&[100, 1200])), 1);
+        assert_eq!(segment_run(&sizes(&[0, 0])), 1);
+        assert_eq!(segment_run(&[]), 0);
+        // Within the limits of the kernel
+        assert_eq!(segment_run(&sizes(&[100; 80])), MAX_SEGMENTS);
+        assert_eq!(segment_run(&sizes(&[1500; 64])), MAX_SEGMENTED_BYTES / 1500);
+    }
+}
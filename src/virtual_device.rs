@@ -1,19 +1,74 @@
 use crate::config::PortProtocol;
 use crate::events::{BusSender, Event};
+use crate::fragmentation::{self, Reassembler, MAX_IPV4_PACKET};
+use crate::packet_filter::{PacketDirection, PacketFilter};
 use crate::Bus;
 use smoltcp::phy::{Device, DeviceCapabilities, Medium};
 use smoltcp::time::Instant;
+use smoltcp::wire::{IpVersion, Ipv4Packet, Ipv6Packet};
 use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The most packets waiting for the virtual interface to process them. Further packets are dropped, so that a
+/// stalled virtual interface doesn't grow the queue without bounds.
+const MAX_QUEUED_PACKETS: usize = 4096;
+/// The shortest interval between the warnings about dropped packets, so that they don't flood the logs.
+const DROP_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many packets a virtual device dropped, and how many events it missed (see `Handle::stats`).
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "control", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceStats {
+    /// Packets received from the tunnel that were dropped: rejected by the packet filter, or arriving while the
+    /// queue of the virtual interface is full.
+    pub inbound_dropped: u64,
+    /// Packets sent by the virtual interface that were dropped: rejected by the packet filter, or larger than
+    /// the MTU on a device that doesn't fragment them.
+    pub outbound_dropped: u64,
+    /// Events the device missed because it didn't keep up with the event bus, including packets from the tunnel.
+    pub overruns: u64,
+}
+
+/// The counters behind `DeviceStats`, shared by the device, its tokens and its feeding task.
+#[derive(Debug, Default)]
+pub(crate) struct DeviceCounters {
+    inbound_dropped: AtomicU64,
+    outbound_dropped: AtomicU64,
+    overruns: AtomicU64,
+}
+
+impl DeviceCounters {
+    pub(crate) fn snapshot(&self) -> DeviceStats {
+        DeviceStats {
+            inbound_dropped: self.inbound_dropped.load(Ordering::Relaxed),
+            outbound_dropped: self.outbound_dropped.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+        }
+    }
+}
 
 /// A virtual device that processes IP packets through smoltcp and WireGuard.
 pub struct VirtualIpDevice {
+    /// The protocol of the packets going through the device.
+    protocol: PortProtocol,
     /// Max transmission unit (bytes)
     max_transmission_unit: usize,
     /// Channel receiver for received IP packets.
     bus_sender: BusSender,
     /// Local queue for packets received from the bus that need to go through the smoltcp interface.
     process_queue: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    /// Inspects the packets going through the device, if any.
+    packet_filter: Option<Arc<dyn PacketFilter>>,
+    /// The IPs of this peer. The packets sent to them are looped back to the device.
+    local_ips: Arc<Vec<IpAddr>>,
+    /// Feeds the looped back packets to the device, from another endpoint than the device's own.
+    hairpin_sender: BusSender,
+    /// Reassembles the IPv4 fragments received, when the device fragments packets larger than the MTU.
+    reassembler: Option<Reassembler>,
+    counters: Arc<DeviceCounters>,
 }
 
 impl VirtualIpDevice {
@@ -21,17 +76,36 @@ impl VirtualIpDevice {
     pub fn new(protocol: PortProtocol, bus: Bus, max_transmission_unit: usize) -> Self {
         let mut bus_endpoint = bus.new_endpoint();
         let bus_sender = bus_endpoint.sender();
+        let hairpin_sender = bus.new_endpoint().sender();
         let process_queue = Arc::new(Mutex::new(VecDeque::new()));
+        let counters = Arc::new(DeviceCounters::default());
 
         {
             let process_queue = process_queue.clone();
+            let counters = counters.clone();
             tokio::spawn(async move {
+                let mut warnings = tokio::time::interval(DROP_WARNING_INTERVAL);
+                let mut reported = DeviceStats::default();
                 loop {
-                    match bus_endpoint.recv().await {
+                    let event = tokio::select! {
+                        event = bus_endpoint.recv() => event,
+                        _ = warnings.tick() => {
+                            reported = warn_drops(protocol, &counters, reported);
+                            continue;
+                        }
+                    };
+                    counters
+                        .overruns
+                        .store(bus_endpoint.missed(), Ordering::Relaxed);
+                    match event {
                         Event::InboundInternetPacket(ip_proto, data) if ip_proto == protocol => {
                             let mut queue = process_queue
                                 .lock()
                                 .expect("Failed to acquire process queue lock");
+                            if queue.len() >= MAX_QUEUED_PACKETS {
+                                counters.inbound_dropped.fetch_add(1, Ordering::Relaxed);
+                                continue;
+                            }
                             queue.push_back(data);
                             bus_endpoint.send(Event::VirtualDeviceFed(ip_proto));
                         }
@@ -42,9 +116,57 @@ impl VirtualIpDevice {
         }
 
         Self {
+            protocol,
             bus_sender,
             process_queue,
             max_transmission_unit,
+            packet_filter: None,
+            local_ips: Arc::new(vec![]),
+            hairpin_sender,
+            reassembler: None,
+            counters,
+        }
+    }
+
+    /// Passes every packet going through the device to the filter, which may rewrite or drop it.
+    pub fn with_packet_filter(mut self, packet_filter: Arc<dyn PacketFilter>) -> Self {
+        self.packet_filter = Some(packet_filter);
+        self
+    }
+
+    /// Loops the packets sent to the given IPs of this peer back to the device, instead of sending them
+    /// to the WireGuard endpoint, which would only route them back. This lets a port forward reach
+    /// another one listening on the virtual interface (such as a remote port forward).
+    pub fn with_hairpin(mut self, local_ips: Vec<IpAddr>) -> Self {
+        self.local_ips = Arc::new(local_ips);
+        self
+    }
+
+    /// Fragments the IPv4 packets larger than the MTU before sending them, and reassembles the fragments
+    /// received, so that smoltcp, which does neither, can send and receive datagrams of up to 64 KiB.
+    pub fn with_fragmentation(mut self) -> Self {
+        self.reassembler = Some(Reassembler::default());
+        self
+    }
+
+    /// The drop counters of the device, which outlive it.
+    pub(crate) fn counters(&self) -> Arc<DeviceCounters> {
+        self.counters.clone()
+    }
+
+    fn tx_token(&self) -> TxToken {
+        TxToken {
+            protocol: self.protocol,
+            sender: self.bus_sender.clone(),
+            packet_filter: self.packet_filter.clone(),
+            local_ips: self.local_ips.clone(),
+            hairpin_sender: self.hairpin_sender.clone(),
+            fragment_mtu: self
+                .reassembler
+                .is_some()
+                .then(|| self.max_transmission_unit),
+            max_transmission_unit: self.max_transmission_unit,
+            counters: self.counters.clone(),
         }
     }
 }
@@ -54,34 +176,49 @@ impl<'a> Device<'a> for VirtualIpDevice {
     type TxToken = TxToken;
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        let next = {
-            let mut queue = self
-                .process_queue
-                .lock()
-                .expect("Failed to acquire process queue lock");
-            queue.pop_front()
-        };
-        match next {
-            Some(buffer) => Some((
-                Self::RxToken { buffer },
-                Self::TxToken {
-                    sender: self.bus_sender.clone(),
+        let buffer = loop {
+            let next = {
+                let mut queue = self
+                    .process_queue
+                    .lock()
+                    .expect("Failed to acquire process queue lock");
+                queue.pop_front()?
+            };
+            let next = match &mut self.reassembler {
+                Some(reassembler) => match reassembler.push(next, std::time::Instant::now()) {
+                    Some(packet) => packet,
+                    None => continue,
                 },
-            )),
-            None => None,
-        }
+                None => next,
+            };
+            match &self.packet_filter {
+                Some(filter) => match filter.filter(PacketDirection::Inbound, next) {
+                    Some(buffer) => break buffer,
+                    None => {
+                        self.counters
+                            .inbound_dropped
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                },
+                None => break next,
+            }
+        };
+        Some((Self::RxToken { buffer }, self.tx_token()))
     }
 
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
-        Some(TxToken {
-            sender: self.bus_sender.clone(),
-        })
+        Some(self.tx_token())
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut cap = DeviceCapabilities::default();
         cap.medium = Medium::Ip;
-        cap.max_transmission_unit = self.max_transmission_unit;
+        // The packets larger than the MTU are fragmented by the device
+        cap.max_transmission_unit = if self.reassembler.is_some() {
+            MAX_IPV4_PACKET
+        } else {
+            self.max_transmission_unit
+        };
         cap
     }
 }
@@ -102,7 +239,15 @@ impl smoltcp::phy::RxToken for RxToken {
 
 #[doc(hidden)]
 pub struct TxToken {
+    protocol: PortProtocol,
     sender: BusSender,
+    packet_filter: Option<Arc<dyn PacketFilter>>,
+    local_ips: Arc<Vec<IpAddr>>,
+    hairpin_sender: BusSender,
+    /// The MTU to fragment the packets to, if the device fragments them.
+    fragment_mtu: Option<usize>,
+    max_transmission_unit: usize,
+    counters: Arc<DeviceCounters>,
 }
 
 impl smoltcp::phy::TxToken for TxToken {
@@ -110,10 +255,173 @@ impl smoltcp::phy::TxToken for TxToken {
     where
         F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
     {
-        let mut buffer = Vec::new();
-        buffer.resize(len, 0);
+        let mut buffer = vec![0; len];
         let result = f(&mut buffer);
-        self.sender.send(Event::OutboundInternetPacket(buffer));
+        let buffer = match &self.packet_filter {
+            Some(filter) => filter.filter(PacketDirection::Outbound, buffer),
+            None => Some(buffer),
+        };
+        let buffer = match buffer {
+            Some(buffer)
+                if self.fragment_mtu.is_none() && buffer.len() > self.max_transmission_unit =>
+            {
+                // Rewritten by the packet filter beyond what the tunnel carries
+                debug!(
+                    "Dropped IP packet of {} bytes, larger than the MTU",
+                    buffer.len()
+                );
+                None
+            }
+            buffer => buffer,
+        };
+        if buffer.is_none() {
+            self.counters
+                .outbound_dropped
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(buffer) = buffer {
+            if destination_ip(&buffer).map_or(false, |ip| self.local_ips.contains(&ip)) {
+                trace!("Looping back IP packet of {} bytes", buffer.len());
+                self.hairpin_sender
+                    .send(Event::InboundInternetPacket(self.protocol, buffer));
+            } else if let Some(mtu) = self.fragment_mtu {
+                for fragment in fragmentation::fragment(buffer, mtu) {
+                    self.sender.send(Event::OutboundInternetPacket(fragment));
+                }
+            } else {
+                self.sender.send(Event::OutboundInternetPacket(buffer));
+            }
+        }
         result
     }
 }
+
+/// Warns about the packets dropped since the last report, if any, and returns the counters reported.
+fn warn_drops(
+    protocol: PortProtocol,
+    counters: &DeviceCounters,
+    reported: DeviceStats,
+) -> DeviceStats {
+    let stats = counters.snapshot();
+    if stats != reported {
+        warn!(
+            "{} virtual device dropped {} inbound and {} outbound packets, and missed {} events, in the last {}s",
+            protocol,
+            stats.inbound_dropped - reported.inbound_dropped,
+            stats.outbound_dropped - reported.outbound_dropped,
+            stats.overruns - reported.overruns,
+            DROP_WARNING_INTERVAL.as_secs()
+        );
+    }
+    stats
+}
+
+/// The destination IP of an IP packet, if it is well-formed.
+fn destination_ip(packet: &[u8]) -> Option<IpAddr> {
+    match IpVersion::of_packet(packet) {
+        Ok(IpVersion::Ipv4) => Ipv4Packet::new_checked(packet)
+            .ok()
+            .map(|packet| Ipv4Addr::from(packet.dst_addr()).into()),
+        Ok(IpVersion::Ipv6) => Ipv6Packet::new_checked(packet)
+            .ok()
+            .map(|packet| Ipv6Addr::from(packet.dst_addr()).into()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::TxToken as _;
+    use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Repr};
+
+    fn packet(dst_addr: Ipv4Address) -> Vec<u8> {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(192, 168, 4, 3),
+            dst_addr,
+            protocol: IpProtocol::Udp,
+            payload_len: 0,
+            hop_limit: 64,
+        };
+        let mut packet = vec![0u8; repr.buffer_len()];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut packet),
+            &Default::default(),
+        );
+        packet
+    }
+
+    /// Tests that the packets sent to this peer are looped back to the device.
+    #[tokio::test]
+    async fn test_hairpin() {
+        let bus = Bus::default();
+        let mut endpoint = bus.new_endpoint();
+        let mut device = VirtualIpDevice::new(PortProtocol::Udp, bus.clone(), 1420)
+            .with_hairpin(vec![IpAddr::from([192, 168, 4, 3])]);
+
+        for dst_addr in [
+            Ipv4Address::new(192, 168, 4, 2),
+            Ipv4Address::new(192, 168, 4, 3),
+        ] {
+            let packet = packet(dst_addr);
+            let tx = device.transmit().unwrap();
+            tx.consume(Instant::now(), packet.len(), |buffer| {
+                buffer.copy_from_slice(&packet);
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        let mut outbound = vec![];
+        loop {
+            match endpoint.recv().await {
+                Event::OutboundInternetPacket(packet) => outbound.push(packet),
+                Event::VirtualDeviceFed(PortProtocol::Udp) => break,
+                _ => {}
+            }
+        }
+        assert_eq!(outbound, vec![packet(Ipv4Address::new(192, 168, 4, 2))]);
+        let (rx, _) = device.receive().expect("Missing looped back packet");
+        let looped_back =
+            smoltcp::phy::RxToken::consume(rx, Instant::now(), |buffer| Ok(buffer.to_vec()))
+                .unwrap();
+        assert_eq!(looped_back, packet(Ipv4Address::new(192, 168, 4, 3)));
+    }
+
+    #[derive(Debug)]
+    struct DropAll;
+
+    impl PacketFilter for DropAll {
+        fn filter(&self, _direction: PacketDirection, _packet: Vec<u8>) -> Option<Vec<u8>> {
+            None
+        }
+    }
+
+    /// Tests that the packets dropped by the device are counted.
+    #[tokio::test]
+    async fn test_drop_counters() {
+        let bus = Bus::default();
+        let mut device = VirtualIpDevice::new(PortProtocol::Udp, bus, 1420)
+            .with_packet_filter(Arc::new(DropAll));
+        let counters = device.counters();
+
+        let packet = packet(Ipv4Address::new(192, 168, 4, 2));
+        let tx = device.transmit().unwrap();
+        tx.consume(Instant::now(), packet.len(), |buffer| {
+            buffer.copy_from_slice(&packet);
+            Ok(())
+        })
+        .unwrap();
+        device.process_queue.lock().unwrap().push_back(packet);
+        assert!(device.receive().is_none());
+
+        assert_eq!(
+            counters.snapshot(),
+            DeviceStats {
+                inbound_dropped: 1,
+                outbound_dropped: 1,
+                overruns: 0,
+            }
+        );
+    }
+}
@@ -1,10 +1,19 @@
+#[cfg(feature = "tcp")]
 pub mod tcp;
+#[cfg(feature = "udp")]
 pub mod udp;
 
-use crate::config::PortProtocol;
+use crate::config::{AllowedIp, PortProtocol};
+use crate::engine::Clock;
 use crate::VirtualIpDevice;
 use async_trait::async_trait;
+use smoltcp::iface::{Route, Routes};
+use smoltcp::socket::TcpState;
+use smoltcp::wire::{IpAddress, IpCidr};
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
 
 #[async_trait]
@@ -18,6 +27,34 @@ pub trait VirtualInterfacePoll {
     ) -> anyhow::Result<()>;
 }
 
+/// The routes of a virtual interface, to the destinations that aren't its own addresses: each range goes
+/// through the WireGuard peer, acting as a router. smoltcp needs a gateway for them, but the virtual device
+/// sends every packet into the tunnel, so the gateway is just the source peer IP of the same IP version.
+pub(crate) fn routes(ranges: &[AllowedIp], source_peer_ips: &[IpAddr]) -> Routes<'static> {
+    let mut routes = Routes::new(BTreeMap::new());
+    routes.update(|storage| {
+        for range in ranges {
+            let gateway = source_peer_ips
+                .iter()
+                .find(|ip| ip.is_ipv4() == range.addr().is_ipv4());
+            let route = match gateway {
+                Some(IpAddr::V4(ip)) => Route::new_ipv4_gateway((*ip).into()),
+                Some(IpAddr::V6(ip)) => Route::new_ipv6_gateway((*ip).into()),
+                // Rejected by the validation of the configuration
+                None => continue,
+            };
+            let cidr = IpCidr::new(IpAddress::from(range.addr()), range.prefix_len());
+            storage.insert(cidr, route).ok();
+        }
+    });
+    routes
+}
+
+/// The time of the clock, as the timestamp of smoltcp's timers.
+pub(crate) fn timestamp(clock: &dyn Clock) -> smoltcp::time::Instant {
+    smoltcp::time::Instant::from_micros(clock.now().as_micros() as i64)
+}
+
 /// Virtual port.
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
 pub struct VirtualPort(u16, PortProtocol);
@@ -68,3 +105,120 @@ impl Display for VirtualPort {
         write!(f, "[{}:{}]", self.num(), self.proto())
     }
 }
+
+/// A snapshot of an active session going through a virtual interface.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// The virtual port assigned to the session.
+    pub virtual_port: VirtualPort,
+    /// The address of the local client, if it is still assigned in the port pool.
+    pub peer_addr: Option<SocketAddr>,
+    /// The address the session is forwarded to.
+    pub destination: SocketAddr,
+    /// Bytes received from the destination and sent to the local client.
+    pub bytes_in: usize,
+    /// Bytes received from the local client and sent to the destination.
+    pub bytes_out: usize,
+    /// How long ago the session was opened.
+    pub age: Duration,
+    /// How long the local client kept the session waiting, reading slower than the tunnel delivers (TCP only).
+    pub stalled: Duration,
+    /// The state of the session in the virtual interface.
+    pub state: ConnectionState,
+}
+
+/// The state of a session in the virtual interface.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConnectionState {
+    /// The virtual TCP connection is being opened.
+    Connecting,
+    /// The virtual TCP connection is open.
+    Established,
+    /// The virtual TCP connection is being closed.
+    Closing,
+    /// UDP sessions have no connection state.
+    Stateless,
+}
+
+impl From<TcpState> for ConnectionState {
+    fn from(state: TcpState) -> Self {
+        match state {
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent | TcpState::SynReceived => {
+                Self::Connecting
+            }
+            TcpState::Established => Self::Established,
+            _ => Self::Closing,
+        }
+    }
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Connecting => "CONNECTING",
+                Self::Established => "ESTABLISHED",
+                Self::Closing => "CLOSING",
+                Self::Stateless => "STATELESS",
+            }
+        )
+    }
+}
+
+/// Bookkeeping kept by the virtual interfaces for each session, used to answer connection queries.
+pub(crate) struct SessionMeta {
+    destination: SocketAddr,
+    started: Instant,
+    bytes_in: usize,
+    bytes_out: usize,
+    stalled: Duration,
+    established: bool,
+}
+
+impl SessionMeta {
+    pub(crate) fn new(destination: SocketAddr) -> Self {
+        Self {
+            destination,
+            started: Instant::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            stalled: Duration::ZERO,
+            established: false,
+        }
+    }
+
+    /// Marks the session as established, returning whether it wasn't already.
+    pub(crate) fn establish(&mut self) -> bool {
+        !std::mem::replace(&mut self.established, true)
+    }
+
+    /// Records data sent by the destination to the local client.
+    pub(crate) fn record_in(&mut self, size: usize) {
+        self.bytes_in += size;
+    }
+
+    /// Records data sent by the local client to the destination.
+    pub(crate) fn record_out(&mut self, size: usize) {
+        self.bytes_out += size;
+    }
+
+    /// Records time the local client kept the session waiting.
+    pub(crate) fn record_stall(&mut self, stalled: Duration) {
+        self.stalled += stalled;
+    }
+
+    pub(crate) fn info(&self, virtual_port: VirtualPort, state: ConnectionState) -> ConnectionInfo {
+        ConnectionInfo {
+            virtual_port,
+            peer_addr: None,
+            destination: self.destination,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            age: self.started.elapsed(),
+            stalled: self.stalled,
+            state,
+        }
+    }
+}
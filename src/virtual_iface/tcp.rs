@@ -1,42 +1,179 @@
-use crate::config::{PortForwardConfig, PortProtocol};
-use crate::events::Event;
+use crate::config::{AllowedIp, PortForwardConfig, PortProtocol};
+use crate::engine::Clock;
+use crate::events::{BusEndpoint, DropReason, Event, EVENT_BATCH};
+use crate::tunnel::tcp::TcpPortPool;
 use crate::virtual_device::VirtualIpDevice;
-use crate::virtual_iface::{VirtualInterfacePoll, VirtualPort};
+use crate::virtual_iface::{
+    routes, timestamp, ConnectionInfo, SessionMeta, VirtualInterfacePoll, VirtualPort,
+};
+use crate::wg::{sleep_until, TokioClock};
 use crate::Bus;
 use anyhow::Context;
 use async_trait::async_trait;
+use futures::task::ArcWake;
 use smoltcp::iface::{InterfaceBuilder, SocketHandle};
 use smoltcp::socket::{TcpSocket, TcpSocketBuffer, TcpState};
 use smoltcp::wire::{IpAddress, IpCidr};
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::net::IpAddr;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
 
 const MAX_PACKET: usize = 65536;
+/// The most bytes each connection sends and receives per poll, so that bulk transfers take turns with the
+/// interactive sessions instead of starving them.
+const CONNECTION_QUOTA: usize = 16 * 1024;
+
+/// The connections whose sockets need attention from the poll loop.
+type ReadySet = Arc<Mutex<HashSet<VirtualPort>>>;
+
+/// Marks a connection as ready when smoltcp wakes its socket: data or buffer space became available, or its
+/// state changed. smoltcp wakes a registered waker once, so it is registered again each time the socket is
+/// processed.
+struct ReadinessWaker {
+    virtual_port: VirtualPort,
+    ready: ReadySet,
+}
+
+impl ArcWake for ReadinessWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self
+            .ready
+            .lock()
+            .expect("Failed to acquire ready lock")
+            .insert(arc_self.virtual_port);
+    }
+}
+
+/// The order the connections are served in after each poll: the ones woken since the last poll, then the ones
+/// that used up their quota with work left, in turn.
+#[derive(Default)]
+struct Schedule {
+    /// The connections woken by smoltcp or by the bus, which are the only ones processed after a poll besides
+    /// the backlog.
+    ready: ReadySet,
+    /// The connections that used up their quota with work left, served again on the next poll.
+    backlog: Vec<VirtualPort>,
+}
+
+impl Schedule {
+    /// Creates the waker of a connection, which starts ready.
+    fn waker(&self, virtual_port: VirtualPort) -> Waker {
+        self.wake(virtual_port);
+        futures::task::waker(Arc::new(ReadinessWaker {
+            virtual_port,
+            ready: self.ready.clone(),
+        }))
+    }
+
+    /// Marks a connection as needing attention after the next poll.
+    fn wake(&self, virtual_port: VirtualPort) {
+        self.ready
+            .lock()
+            .expect("Failed to acquire ready lock")
+            .insert(virtual_port);
+    }
+
+    /// Serves the connection again after the next poll, without waiting for a wake.
+    fn carry(&mut self, virtual_port: VirtualPort) {
+        self.backlog.push(virtual_port);
+    }
+
+    /// Whether connections were carried over, so that the next poll is due right away.
+    fn has_backlog(&self) -> bool {
+        !self.backlog.is_empty()
+    }
+
+    /// The connections to serve after this poll, each once: the newly woken ones, then the backlog.
+    fn next(&mut self) -> Vec<VirtualPort> {
+        let woken = std::mem::take(&mut *self.ready.lock().expect("Failed to acquire ready lock"));
+        let carried: HashSet<VirtualPort> = self.backlog.iter().copied().collect();
+        let mut schedule: Vec<VirtualPort> = woken
+            .into_iter()
+            .filter(|port| !carried.contains(port))
+            .collect();
+        schedule.append(&mut self.backlog);
+        schedule
+    }
+}
 
 /// A virtual interface for proxying Layer 7 data to Layer 3 packets, and vice-versa.
 pub struct TcpVirtualInterface {
-    source_peer_ip: IpAddr,
+    /// The IPs of this peer; the first one is used by default.
+    source_peer_ips: Vec<IpAddr>,
     port_forwards: Vec<PortForwardConfig>,
-    bus: Bus,
+    remote_port_forwards: Vec<PortForwardConfig>,
+    /// Assigns the virtual ports of the connections accepted by remote port forwards.
+    port_pool: TcpPortPool,
+    /// The IP ranges reached through the peer, besides the destinations of the port forwards.
+    routes: Vec<AllowedIp>,
+    /// How long a connection may wait for the destination to answer, if limited.
+    connect_timeout: Option<Duration>,
+    /// The clock of the timers of the sockets.
+    clock: Arc<dyn Clock>,
+    /// Subscribed on creation, so that the poll loop misses no event even when it starts later, on another
+    /// runtime. Taken by the poll loop.
+    endpoint: Option<BusEndpoint>,
 }
 
 impl TcpVirtualInterface {
     /// Initialize the parameters for a new virtual interface.
     /// Use the `poll_loop()` future to start the virtual interface poll loop.
-    pub fn new(port_forwards: Vec<PortForwardConfig>, bus: Bus, source_peer_ip: IpAddr) -> Self {
+    pub fn new(
+        port_forwards: Vec<PortForwardConfig>,
+        remote_port_forwards: Vec<PortForwardConfig>,
+        port_pool: TcpPortPool,
+        bus: Bus,
+        source_peer_ips: Vec<IpAddr>,
+    ) -> Self {
         Self {
             port_forwards: port_forwards
                 .into_iter()
                 .filter(|f| matches!(f.protocol, PortProtocol::Tcp))
                 .collect(),
-            source_peer_ip,
-            bus,
+            remote_port_forwards: remote_port_forwards
+                .into_iter()
+                .filter(|f| matches!(f.protocol, PortProtocol::Tcp))
+                .collect(),
+            port_pool,
+            source_peer_ips,
+            routes: vec![],
+            connect_timeout: None,
+            clock: Arc::new(TokioClock::default()),
+            endpoint: Some(bus.new_endpoint()),
         }
     }
 
-    fn new_server_socket(port_forward: PortForwardConfig) -> anyhow::Result<TcpSocket<'static>> {
+    /// Routes the given IP ranges through the peer, so that destinations in them are reachable even when
+    /// they aren't addresses of the interface.
+    pub fn with_routes(mut self, routes: Vec<AllowedIp>) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Runs the timers of the sockets on the given clock, such as the one of the tunnel (see
+    /// `WireGuardTunnel::clock`), instead of one of its own.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Aborts the connections that aren't established within the timeout, with `DropReason::Timeout`.
+    pub fn with_connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// The IP of this peer to connect from for the given port forward.
+    fn source_peer_ip(&self, port_forward: &PortForwardConfig) -> IpAddr {
+        port_forward
+            .source_peer_ip
+            .unwrap_or(self.source_peer_ips[0])
+    }
+
+    fn new_server_socket(destination: SocketAddr) -> anyhow::Result<TcpSocket<'static>> {
         static mut TCP_SERVER_RX_DATA: [u8; 0] = [];
         static mut TCP_SERVER_TX_DATA: [u8; 0] = [];
 
@@ -45,29 +182,60 @@ impl TcpVirtualInterface {
         let mut socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
 
         socket
-            .listen((
-                IpAddress::from(port_forward.destination.ip()),
-                port_forward.destination.port(),
-            ))
+            .listen((IpAddress::from(destination.ip()), destination.port()))
             .with_context(|| "Virtual server socket failed to listen")?;
 
         Ok(socket)
     }
 
-    fn new_client_socket() -> anyhow::Result<TcpSocket<'static>> {
-        let rx_data = vec![0u8; MAX_PACKET];
-        let tx_data = vec![0u8; MAX_PACKET];
+    /// A socket accepting the next connection of a remote port forward, on its source address.
+    fn new_listener_socket(port_forward: &PortForwardConfig) -> anyhow::Result<TcpSocket<'static>> {
+        let mut socket = TcpVirtualInterface::new_client_socket(port_forward)?;
+        socket
+            .listen((
+                IpAddress::from(port_forward.source.ip()),
+                port_forward.source.port(),
+            ))
+            .with_context(|| "Virtual listener socket failed to listen")?;
+        Ok(socket)
+    }
+
+    /// Whether the destination is the source of a remote port forward, which listens on it instead.
+    fn is_remote_source(&self, destination: SocketAddr) -> bool {
+        self.remote_port_forwards
+            .iter()
+            .any(|remote| remote.source == destination)
+    }
+
+    /// A virtual socket for a connection of the port forward, with the window and timers it configures. smoltcp
+    /// scales the window it advertises when its receive buffer is larger than 64 KiB.
+    fn new_client_socket(port_forward: &PortForwardConfig) -> anyhow::Result<TcpSocket<'static>> {
+        let window = port_forward.tcp_window.unwrap_or(MAX_PACKET);
+        let rx_data = vec![0u8; window];
+        let tx_data = vec![0u8; window];
         let tcp_rx_buffer = TcpSocketBuffer::new(rx_data);
         let tcp_tx_buffer = TcpSocketBuffer::new(tx_data);
-        let socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
+        let mut socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
+        if let Some(delay) = port_forward.ack_delay {
+            socket.set_ack_delay(
+                (!delay.is_zero())
+                    .then(|| smoltcp::time::Duration::from_millis(delay.as_millis() as u64)),
+            );
+        }
+        socket.set_nagle_enabled(port_forward.nagle);
         Ok(socket)
     }
 
     fn addresses(&self) -> Vec<IpCidr> {
         let mut addresses = HashSet::new();
-        addresses.insert(IpAddress::from(self.source_peer_ip));
+        for ip in self.source_peer_ips.iter() {
+            addresses.insert(IpAddress::from(*ip));
+        }
         for config in self.port_forwards.iter() {
-            addresses.insert(IpAddress::from(config.destination.ip()));
+            // Destinations resolved through the tunnel are unknown until then
+            for destination in config.destinations().filter(|d| !d.ip().is_unspecified()) {
+                addresses.insert(IpAddress::from(destination.ip()));
+            }
         }
         addresses
             .into_iter()
@@ -79,7 +247,7 @@ impl TcpVirtualInterface {
 #[async_trait]
 impl VirtualInterfacePoll for TcpVirtualInterface {
     async fn poll_loop(
-        self,
+        mut self,
         device: VirtualIpDevice,
         mut kill_switch: broadcast::Receiver<()>,
     ) -> anyhow::Result<()> {
@@ -89,19 +257,39 @@ impl VirtualInterfacePoll for TcpVirtualInterface {
         // Create virtual interface (contains smoltcp state machine)
         let mut iface = InterfaceBuilder::new(device, vec![])
             .ip_addrs(addresses)
+            .routes(routes(&self.routes, &self.source_peer_ips))
             .finalize();
 
         // Create virtual server for each port forward
         for port_forward in self.port_forwards.iter() {
-            let server_socket = TcpVirtualInterface::new_server_socket(*port_forward)?;
-            iface.add_socket(server_socket);
+            for destination in port_forward
+                .destinations()
+                .filter(|d| !d.ip().is_unspecified() && !self.is_remote_source(*d))
+            {
+                let server_socket = TcpVirtualInterface::new_server_socket(destination)?;
+                iface.add_socket(server_socket);
+            }
         }
 
-        // The next time to poll the interface. Can be None for instant poll.
-        let mut next_poll: Option<tokio::time::Instant> = None;
+        // Create the listener of each remote port forward, replaced after each accepted connection
+        let mut listeners: Vec<(Arc<PortForwardConfig>, SocketHandle)> = Vec::new();
+        for port_forward in self.remote_port_forwards.iter() {
+            let listener_socket = TcpVirtualInterface::new_listener_socket(port_forward)?;
+            listeners.push((
+                Arc::new(port_forward.clone()),
+                iface.add_socket(listener_socket),
+            ));
+        }
+
+        // Whether to poll the interface right away: a packet was fed to the device, or a socket has work to do
+        let mut poll_now = true;
+
+        // The next time to poll the interface for the timers of the sockets, if any, on the clock of the interface
+        let mut next_poll: Option<Duration> = None;
+        let clock = self.clock.clone();
 
         // Bus endpoint to read events
-        let mut endpoint = self.bus.new_endpoint();
+        let mut endpoint = self.endpoint.take().expect("The poll loop runs once");
 
         // Maps virtual port to its client socket handle
         let mut port_client_handle_map: HashMap<VirtualPort, SocketHandle> = HashMap::new();
@@ -109,27 +297,53 @@ impl VirtualInterfacePoll for TcpVirtualInterface {
         // Data packets to send from a virtual client
         let mut send_queue: HashMap<VirtualPort, VecDeque<Vec<u8>>> = HashMap::new();
 
+        // Introspection data for each virtual client
+        let mut sessions: HashMap<VirtualPort, SessionMeta> = HashMap::new();
+
+        // Why the connections being closed were dropped. The others were closed by the destination.
+        let mut close_reasons: HashMap<VirtualPort, DropReason> = HashMap::new();
+
+        // The connections the destination closed gracefully. The others it closed were refused or reset.
+        let mut peer_closed: HashSet<VirtualPort> = HashSet::new();
+
+        // Accepted connections whose local side isn't connected yet: their data is held in the socket until then
+        let mut pending_accepts: HashSet<VirtualPort> = HashSet::new();
+
+        // The connections waiting for the destination to answer, with the time they are aborted at
+        let mut connect_deadlines: HashMap<VirtualPort, Duration> = HashMap::new();
+
+        // The connections to serve after each poll, and the waker registered on the socket of each connection
+        let mut schedule = Schedule::default();
+        let mut wakers: HashMap<VirtualPort, Waker> = HashMap::new();
+
+        // Data read from the sockets, reused across polls: one read takes up to the quota, across both halves of
+        // a socket's ring buffer
+        let mut recv_buf = vec![0u8; CONNECTION_QUOTA];
+
         loop {
             tokio::select! {
-                _ = match (next_poll, port_client_handle_map.len()) {
-                    (None, 0) => tokio::time::sleep(Duration::MAX),
-                    (None, _) => tokio::time::sleep(Duration::ZERO),
-                    (Some(until), _) => tokio::time::sleep_until(until),
+                _ = async {
+                    match (poll_now, next_poll) {
+                        (true, _) => {}
+                        (false, Some(until)) => sleep_until(&*clock, until).await,
+                        (false, None) => futures::future::pending::<()>().await,
+                    }
                 } => {
-                    let loop_start = smoltcp::time::Instant::now();
-
-                    // Find closed sockets
-                    port_client_handle_map.retain(|virtual_port, client_handle| {
-                        let client_socket = iface.get_socket::<TcpSocket>(*client_handle);
-                        if client_socket.state() == TcpState::Closed {
-                            endpoint.send(Event::ClientConnectionDropped(*virtual_port));
-                            send_queue.remove(virtual_port);
-                            iface.remove_socket(*client_handle);
-                            false
-                        } else {
-                            // Not closed, retain
-                            true
+                    let loop_start = timestamp(&*clock);
+
+                    // Abort the connections the destination didn't answer in time, before the poll sends their reset
+                    let now = clock.now();
+                    connect_deadlines.retain(|virtual_port, deadline| {
+                        if *deadline > now {
+                            return true;
                         }
+                        if let Some(client_handle) = port_client_handle_map.get(virtual_port) {
+                            info!("[{}] No answer from the destination, aborting the connection", virtual_port);
+                            iface.get_socket::<TcpSocket>(*client_handle).abort();
+                            close_reasons.insert(*virtual_port, DropReason::Timeout);
+                            schedule.wake(*virtual_port);
+                        }
+                        false
                     });
 
                     match iface.poll(loop_start) {
@@ -140,38 +354,114 @@ impl VirtualInterfacePoll for TcpVirtualInterface {
                         _ => {}
                     }
 
-                    for (virtual_port, client_handle) in port_client_handle_map.iter() {
-                        let client_socket = iface.get_socket::<TcpSocket>(*client_handle);
+                    for (port_forward, listener_handle) in listeners.iter_mut() {
+                        let listener_socket = iface.get_socket::<TcpSocket>(*listener_handle);
+                        if listener_socket.state() != TcpState::Established {
+                            continue;
+                        }
+                        let remote = listener_socket.remote_endpoint();
+                        let peer_addr = SocketAddr::new(remote.addr.into(), remote.port);
+                        let accepted = *listener_handle;
+                        *listener_handle = iface.add_socket(TcpVirtualInterface::new_listener_socket(port_forward)?);
+
+                        let virtual_port = match self.port_pool.next(peer_addr).await {
+                            Ok(virtual_port) => virtual_port,
+                            Err(e) => {
+                                error!("Failed to assign virtual port number for connection from {}: {:?}", peer_addr, e);
+                                iface.remove_socket(accepted);
+                                continue;
+                            }
+                        };
+                        debug!("[{}] Virtual connection accepted from {}", virtual_port, peer_addr);
+                        wakers.insert(virtual_port, schedule.waker(virtual_port));
+                        port_client_handle_map.insert(virtual_port, accepted);
+                        send_queue.insert(virtual_port, VecDeque::new());
+                        sessions.insert(virtual_port, SessionMeta::new(port_forward.destination));
+                        pending_accepts.insert(virtual_port);
+                        endpoint.send(Event::VirtualConnectionAccepted(port_forward.clone(), virtual_port, Instant::now()));
+                    }
+
+                    // Only the sockets woken since the last poll need attention. Each gets one quota, the newly woken
+                    // ones first, then the backlog in turn
+                    for virtual_port in schedule.next() {
+                        let client_handle = match port_client_handle_map.get(&virtual_port) {
+                            Some(client_handle) => *client_handle,
+                            None => continue,
+                        };
+                        if iface.get_socket::<TcpSocket>(client_handle).state() == TcpState::Closed {
+                            let reason = close_reasons.remove(&virtual_port);
+                            if reason.is_none() && !peer_closed.remove(&virtual_port) {
+                                debug!("[{}] Virtual connection refused or reset by the destination", virtual_port);
+                                endpoint.send(Event::ClientConnectionRefused(virtual_port));
+                            }
+                            let reason = reason.unwrap_or(DropReason::PeerReset);
+                            debug!("[{}] Virtual connection closed ({})", virtual_port, reason);
+                            endpoint.send(Event::ClientConnectionDropped(virtual_port, reason, Instant::now()));
+                            port_client_handle_map.remove(&virtual_port);
+                            send_queue.remove(&virtual_port);
+                            sessions.remove(&virtual_port);
+                            pending_accepts.remove(&virtual_port);
+                            connect_deadlines.remove(&virtual_port);
+                            peer_closed.remove(&virtual_port);
+                            wakers.remove(&virtual_port);
+                            iface.remove_socket(client_handle);
+                            continue;
+                        }
+
+                        let client_socket = iface.get_socket::<TcpSocket>(client_handle);
+                        if let Some(waker) = wakers.get(&virtual_port) {
+                            client_socket.register_recv_waker(waker);
+                            client_socket.register_send_waker(waker);
+                        }
+                        if client_socket.state() == TcpState::Established
+                            && sessions.get_mut(&virtual_port).map_or(false, SessionMeta::establish)
+                        {
+                            connect_deadlines.remove(&virtual_port);
+                            endpoint.send(Event::ClientConnectionEstablished(virtual_port));
+                        }
+                        let mut exhausted = false;
                         if client_socket.can_send() {
-                            if let Some(send_queue) = send_queue.get_mut(virtual_port) {
-                                let to_transfer = send_queue.pop_front();
-                                if let Some(to_transfer_slice) = to_transfer.as_deref() {
-                                    let total = to_transfer_slice.len();
-                                    match client_socket.send_slice(to_transfer_slice) {
+                            if let Some(send_queue) = send_queue.get_mut(&virtual_port) {
+                                // Fill the send buffer up to the quota; the rest is sent once the socket wakes up with
+                                // room for it, or on the next poll
+                                let mut quota = CONNECTION_QUOTA;
+                                while let Some(to_transfer) = send_queue.front_mut() {
+                                    let size = to_transfer.len().min(quota);
+                                    match client_socket.send_slice(&to_transfer[..size]) {
+                                        Ok(sent) if sent == to_transfer.len() => {
+                                            quota -= sent;
+                                            send_queue.pop_front();
+                                        }
                                         Ok(sent) => {
-                                            if sent < total {
-                                                // Sometimes only a subset is sent, so the rest needs to be sent on the next poll
-                                                let tx_extra = Vec::from(&to_transfer_slice[sent..total]);
-                                                send_queue.push_front(tx_extra);
-                                            }
+                                            quota -= sent;
+                                            to_transfer.drain(..sent);
+                                            exhausted = quota == 0 && client_socket.can_send();
+                                            break;
                                         }
                                         Err(e) => {
                                             error!(
                                                 "Failed to send slice via virtual client socket: {:?}", e
                                             );
+                                            break;
                                         }
                                     }
-                                } else if client_socket.state() == TcpState::CloseWait {
+                                }
+                                if send_queue.is_empty() && client_socket.state() == TcpState::CloseWait {
+                                    peer_closed.insert(virtual_port);
                                     client_socket.close();
                                 }
                             }
                         }
-                        if client_socket.can_recv() {
-                            match client_socket.recv(|buffer| (buffer.len(), buffer.to_vec())) {
-                                Ok(data) => {
-                                    debug!("[{}] Received {} bytes from virtual server", virtual_port, data.len());
-                                    if !data.is_empty() {
-                                        endpoint.send(Event::RemoteData(*virtual_port, data));
+                        if client_socket.can_recv() && !pending_accepts.contains(&virtual_port) {
+                            match client_socket.recv_slice(&mut recv_buf) {
+                                Ok(size) => {
+                                    debug!("[{}] Received {} bytes from virtual server", virtual_port, size);
+                                    exhausted |= size == recv_buf.len() && client_socket.can_recv();
+                                    if size > 0 {
+                                        if let Some(session) = sessions.get_mut(&virtual_port) {
+                                            session.record_in(size);
+                                        }
+                                        endpoint.send(Event::RemoteData(virtual_port, recv_buf[..size].to_vec(), Instant::now()));
                                     }
                                 }
                                 Err(e) => {
@@ -181,66 +471,301 @@ impl VirtualInterfacePoll for TcpVirtualInterface {
                                 }
                             }
                         }
+                        if exhausted {
+                            schedule.carry(virtual_port);
+                        }
                     }
 
                     // The virtual interface determines the next time to poll (this is to reduce unnecessary polls)
+                    poll_now = schedule.has_backlog();
                     next_poll = match iface.poll_delay(loop_start) {
-                        Some(smoltcp::time::Duration::ZERO) => None,
+                        Some(smoltcp::time::Duration::ZERO) => {
+                            poll_now = true;
+                            None
+                        }
                         Some(delay) => {
                             trace!("TCP Virtual interface delayed next poll by {}", delay);
-                            Some(tokio::time::Instant::now() + Duration::from_millis(delay.total_millis()))
+                            Some(clock.now() + Duration::from_millis(delay.total_millis()))
                         },
                         None => None,
                     };
+                    // Poll again when the next connection attempt times out
+                    if let Some(deadline) = connect_deadlines.values().min() {
+                        poll_now |= *deadline <= clock.now();
+                        next_poll = Some(next_poll.map_or(*deadline, |until| until.min(*deadline)));
+                    }
                 }
-                event = endpoint.recv() => {
-                    match event {
-                        Event::ClientConnectionInitiated(port_forward, virtual_port) => {
-                            let client_socket = TcpVirtualInterface::new_client_socket()?;
-                            let client_handle = iface.add_socket(client_socket);
-
-                            // Add handle to map
-                            port_client_handle_map.insert(virtual_port, client_handle);
-                            send_queue.insert(virtual_port, VecDeque::new());
-
-                            let (client_socket, context) = iface.get_socket_and_context::<TcpSocket>(client_handle);
-
-                            client_socket
-                                .connect(
-                                    context,
-                                    (
-                                        IpAddress::from(port_forward.destination.ip()),
-                                        port_forward.destination.port(),
-                                    ),
-                                    (IpAddress::from(self.source_peer_ip), virtual_port.num()),
-                                )
-                                .with_context(|| "Virtual server socket failed to listen")?;
-
-                            next_poll = None;
-                        }
-                        Event::ClientConnectionDropped(virtual_port) => {
-                            if let Some(client_handle) = port_client_handle_map.get(&virtual_port) {
-                                let client_socket = iface.get_socket::<TcpSocket>(*client_handle);
-                                client_socket.close();
-                                next_poll = None;
+                events = endpoint.recv_many(EVENT_BATCH) => {
+                    for event in events {
+                        match event {
+                            Event::ClientConnectionInitiated(port_forward, virtual_port, _) if port_forward.remote => {
+                                // The local side of an accepted connection is ready for its data
+                                if pending_accepts.remove(&virtual_port) {
+                                    schedule.wake(virtual_port);
+                                } else {
+                                    // The connection was closed before its local side connected
+                                    endpoint.send(Event::ClientConnectionDropped(virtual_port, DropReason::PeerReset, Instant::now()));
+                                }
+                                poll_now = true;
                             }
-                        }
-                        Event::LocalData(_, virtual_port, data) if send_queue.contains_key(&virtual_port) => {
-                            if let Some(send_queue) = send_queue.get_mut(&virtual_port) {
-                                send_queue.push_back(data);
-                                next_poll = None;
+                            Event::ClientConnectionInitiated(port_forward, virtual_port, _) => {
+                                let client_socket = TcpVirtualInterface::new_client_socket(&port_forward)?;
+                                let client_handle = iface.add_socket(client_socket);
+
+                                // Add handle to map
+                                wakers.insert(virtual_port, schedule.waker(virtual_port));
+                                port_client_handle_map.insert(virtual_port, client_handle);
+                                send_queue.insert(virtual_port, VecDeque::new());
+                                sessions.insert(virtual_port, SessionMeta::new(port_forward.destination));
+
+                                let (client_socket, context) = iface.get_socket_and_context::<TcpSocket>(client_handle);
+
+                                client_socket
+                                    .connect(
+                                        context,
+                                        (
+                                            IpAddress::from(port_forward.destination.ip()),
+                                            port_forward.destination.port(),
+                                        ),
+                                        (IpAddress::from(self.source_peer_ip(&port_forward)), virtual_port.num()),
+                                    )
+                                    .with_context(|| "Virtual server socket failed to listen")?;
+                                if let Some(timeout) = self.connect_timeout {
+                                    connect_deadlines.insert(virtual_port, clock.now() + timeout);
+                                }
+
+                                poll_now = true;
                             }
+                            Event::ClientConnectionDropped(virtual_port, reason, _) => {
+                                if let Some(client_handle) = port_client_handle_map.get(&virtual_port) {
+                                    close_reasons.entry(virtual_port).or_insert(reason);
+                                    let client_socket = iface.get_socket::<TcpSocket>(*client_handle);
+                                    client_socket.close();
+                                    schedule.wake(virtual_port);
+                                    poll_now = true;
+                                }
+                            }
+                            Event::LocalData(_, virtual_port, data, _) if send_queue.contains_key(&virtual_port) => {
+                                if let Some(send_queue) = send_queue.get_mut(&virtual_port) {
+                                    if let Some(session) = sessions.get_mut(&virtual_port) {
+                                        session.record_out(data.len());
+                                    }
+                                    send_queue.push_back(data);
+                                    schedule.wake(virtual_port);
+                                    poll_now = true;
+                                }
+                            }
+                            Event::VirtualDeviceFed(PortProtocol::Tcp) => {
+                                poll_now = true;
+                            }
+                            Event::ClientStalled(virtual_port, stalled) => {
+                                if let Some(session) = sessions.get_mut(&virtual_port) {
+                                    session.record_stall(stalled);
+                                }
+                            }
+                            Event::QueryConnections(reply) => {
+                                let connections: Vec<ConnectionInfo> = port_client_handle_map
+                                    .iter()
+                                    .filter_map(|(virtual_port, client_handle)| {
+                                        let state = iface.get_socket::<TcpSocket>(*client_handle).state();
+                                        sessions
+                                            .get(virtual_port)
+                                            .map(|session| session.info(*virtual_port, state.into()))
+                                    })
+                                    .collect();
+                                reply.send(connections).ok();
+                            }
+                            _ => {}
                         }
-                        Event::VirtualDeviceFed(protocol) if protocol == PortProtocol::Tcp => {
-                            next_poll = None;
-                        }
-                        _ => {}
                     }
                 }
                 _ = kill_switch.recv() => {
+                    // Close the local connections as well
+                    let now = Instant::now();
+                    for virtual_port in port_client_handle_map.keys() {
+                        endpoint.send(Event::ClientConnectionDropped(*virtual_port, DropReason::TunnelDown, now));
+                    }
                     return Ok(())
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The IP of onetun in the peer network.
+    const SOURCE_PEER_IP: [u8; 4] = [192, 168, 4, 3];
+    /// The IP of the destinations, on the peer.
+    const DESTINATION_IP: [u8; 4] = [192, 168, 4, 2];
+
+    /// Feeds the IP packets sent on a bus to the virtual device of the other, like a WireGuard tunnel would.
+    fn forward(from: &Bus, to: &Bus) {
+        let mut from = from.new_endpoint();
+        let to = to.new_endpoint().sender();
+        tokio::spawn(async move {
+            loop {
+                if let Event::OutboundInternetPacket(packet) = from.recv().await {
+                    to.send(Event::InboundInternetPacket(PortProtocol::Tcp, packet));
+                }
+            }
+        });
+    }
+
+    /// A destination socket on the peer.
+    fn destination_socket(port: u16) -> TcpSocket<'static> {
+        let mut socket = TcpSocket::new(
+            TcpSocketBuffer::new(vec![0u8; MAX_PACKET]),
+            TcpSocketBuffer::new(vec![0u8; MAX_PACKET]),
+        );
+        socket
+            .listen((IpAddress::from(IpAddr::from(DESTINATION_IP)), port))
+            .unwrap();
+        socket
+    }
+
+    /// A connection of a port forward to the destination on the given port.
+    fn connection(port: u16) -> (Arc<PortForwardConfig>, VirtualPort) {
+        let port_forward = PortForwardConfig::new(
+            SocketAddr::from(([127, 0, 0, 1], port)),
+            SocketAddr::from((DESTINATION_IP, port)),
+            PortProtocol::Tcp,
+        );
+        (
+            Arc::new(port_forward),
+            VirtualPort::new(port + 1000, PortProtocol::Tcp),
+        )
+    }
+
+    /// Tests that an interactive connection gets its data through while a bulk one has more queued than the
+    /// destination takes, and more coming back than fits in its socket: each connection gets one quota per poll.
+    #[tokio::test]
+    async fn test_connection_quota() {
+        const BULK: usize = 16 * 1024 * 1024;
+        let (bus, peer_bus) = (Bus::default(), Bus::default());
+        forward(&bus, &peer_bus);
+        forward(&peer_bus, &bus);
+        let mut endpoint = bus.new_endpoint();
+        let (kill_switch, kill_switch_rx) = broadcast::channel(1);
+        let interface = TcpVirtualInterface::new(
+            vec![],
+            vec![],
+            TcpPortPool::new(),
+            bus.clone(),
+            vec![IpAddr::from(SOURCE_PEER_IP)],
+        );
+        let device = VirtualIpDevice::new(PortProtocol::Tcp, bus.clone(), 1420);
+        tokio::spawn(interface.poll_loop(device, kill_switch_rx));
+
+        // The destinations: the bulk one sends without reading, the interactive one echoes
+        let peer_device = VirtualIpDevice::new(PortProtocol::Tcp, peer_bus.clone(), 1420);
+        let mut peer = InterfaceBuilder::new(peer_device, vec![])
+            .ip_addrs(vec![IpCidr::new(
+                IpAddress::from(IpAddr::from(DESTINATION_IP)),
+                24,
+            )])
+            .finalize();
+        let bulk_destination = peer.add_socket(destination_socket(80));
+        let interactive_destination = peer.add_socket(destination_socket(22));
+
+        let (bulk_forward, bulk) = connection(80);
+        let (interactive_forward, interactive) = connection(22);
+        let now = Instant::now();
+        endpoint.send(Event::ClientConnectionInitiated(
+            bulk_forward.clone(),
+            bulk,
+            now,
+        ));
+        endpoint.send(Event::LocalData(bulk_forward, bulk, vec![0u8; BULK], now));
+
+        let zeros = vec![0u8; MAX_PACKET];
+        let mut buffer = vec![0u8; MAX_PACKET];
+        let (mut bulk_sent, mut bulk_received) = (0, 0);
+        let mut echoed = Vec::new();
+        let mut interactive_started = false;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while echoed.len() < 4 {
+            assert!(
+                Instant::now() < deadline,
+                "The interactive connection starved"
+            );
+            peer.poll(smoltcp::time::Instant::now()).ok();
+            let socket = peer.get_socket::<TcpSocket>(bulk_destination);
+            if socket.can_send() && bulk_sent < BULK {
+                bulk_sent += socket
+                    .send_slice(&zeros[..(BULK - bulk_sent).min(MAX_PACKET)])
+                    .unwrap();
+            }
+            let socket = peer.get_socket::<TcpSocket>(interactive_destination);
+            if socket.can_recv() {
+                let size = socket.recv_slice(&mut buffer).unwrap();
+                socket.send_slice(&buffer[..size]).unwrap();
+            }
+            peer.poll(smoltcp::time::Instant::now()).ok();
+
+            while let Some(event) = endpoint.try_recv() {
+                match event {
+                    Event::RemoteData(virtual_port, data, _) if virtual_port == bulk => {
+                        bulk_received += data.len();
+                    }
+                    Event::RemoteData(virtual_port, data, _) if virtual_port == interactive => {
+                        echoed.extend(data);
+                    }
+                    _ => {}
+                }
+            }
+            // Once the bulk connection fills its socket both ways
+            if !interactive_started && bulk_received >= 4 * CONNECTION_QUOTA {
+                interactive_started = true;
+                let now = Instant::now();
+                endpoint.send(Event::ClientConnectionInitiated(
+                    interactive_forward.clone(),
+                    interactive,
+                    now,
+                ));
+                endpoint.send(Event::LocalData(
+                    interactive_forward.clone(),
+                    interactive,
+                    b"ping".to_vec(),
+                    now,
+                ));
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(echoed, b"ping");
+        // The destination never read the bulk data, and sent more than was received in the meantime
+        assert!(peer.get_socket::<TcpSocket>(bulk_destination).recv_queue() > 0);
+        assert!(bulk_received < BULK);
+        kill_switch.send(()).ok();
+    }
+
+    /// Tests that the connections that used up their quota are served after the next poll without being woken
+    /// again, after the ones woken since, and once even when woken too.
+    #[test]
+    fn test_backlog() {
+        let (bulk, interactive) = (
+            VirtualPort::new(1080, PortProtocol::Tcp),
+            VirtualPort::new(1022, PortProtocol::Tcp),
+        );
+        let mut schedule = Schedule::default();
+        let bulk_waker = schedule.waker(bulk);
+        assert_eq!(schedule.next(), vec![bulk]);
+        assert!(schedule.next().is_empty());
+
+        schedule.carry(bulk);
+        assert!(schedule.has_backlog());
+        assert_eq!(schedule.next(), vec![bulk]);
+        assert!(!schedule.has_backlog());
+
+        schedule.carry(bulk);
+        let _interactive_waker = schedule.waker(interactive);
+        assert_eq!(schedule.next(), vec![interactive, bulk]);
+
+        schedule.carry(bulk);
+        bulk_waker.wake_by_ref();
+        assert_eq!(schedule.next(), vec![bulk]);
+        assert!(schedule.next().is_empty());
+    }
+}
@@ -1,27 +1,43 @@
 use anyhow::Context;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use tokio::sync::broadcast;
 
-use crate::events::Event;
+use crate::events::{BusEndpoint, Event, EVENT_BATCH};
 use crate::{Bus, PortProtocol};
 use async_trait::async_trait;
 use smoltcp::iface::{InterfaceBuilder, SocketHandle};
 use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
 use smoltcp::wire::{IpAddress, IpCidr};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::config::PortForwardConfig;
+use crate::config::{AllowedIp, DatagramRelay, PortForwardConfig};
+use crate::engine::Clock;
+use crate::tunnel::udp::ResponseGuard;
 use crate::virtual_device::VirtualIpDevice;
-use crate::virtual_iface::{VirtualInterfacePoll, VirtualPort};
+use crate::virtual_iface::{
+    routes, timestamp, ConnectionInfo, ConnectionState, SessionMeta, VirtualInterfacePoll,
+    VirtualPort,
+};
+use crate::wg::{sleep_until, TokioClock};
 
 const MAX_PACKET: usize = 65536;
+/// How many datagrams a virtual client holds in each direction, unless its port forward sets `udp-packet-slots`.
+const DEFAULT_PACKET_SLOTS: usize = 10;
 
 pub struct UdpVirtualInterface {
-    source_peer_ip: IpAddr,
+    /// The IPs of this peer; the first one is used by default.
+    source_peer_ips: Vec<IpAddr>,
     port_forwards: Vec<PortForwardConfig>,
     remote_port_forwards: Vec<PortForwardConfig>,
-    bus: Bus,
+    /// The IP ranges reached through the peer, besides the destinations of the port forwards.
+    routes: Vec<AllowedIp>,
+    /// The clock of the timers of the sockets.
+    clock: Arc<dyn Clock>,
+    /// Subscribed on creation, so that the poll loop misses no event even when it starts later, on another
+    /// runtime. Taken by the poll loop.
+    endpoint: Option<BusEndpoint>,
 }
 
 impl UdpVirtualInterface {
@@ -31,7 +47,7 @@ impl UdpVirtualInterface {
         port_forwards: Vec<PortForwardConfig>,
         remote_port_forwards: Vec<PortForwardConfig>,
         bus: Bus,
-        source_peer_ip: IpAddr,
+        source_peer_ips: Vec<IpAddr>,
     ) -> Self {
         Self {
             port_forwards: port_forwards
@@ -42,12 +58,35 @@ impl UdpVirtualInterface {
                 .into_iter()
                 .filter(|f| matches!(f.protocol, PortProtocol::Udp))
                 .collect(),
-            source_peer_ip,
-            bus,
+            source_peer_ips,
+            routes: vec![],
+            clock: Arc::new(TokioClock::default()),
+            endpoint: Some(bus.new_endpoint()),
         }
     }
 
-    fn new_server_socket(port_forward: PortForwardConfig) -> anyhow::Result<UdpSocket<'static>> {
+    /// Routes the given IP ranges through the peer, so that destinations in them are reachable even when
+    /// they aren't addresses of the interface.
+    pub fn with_routes(mut self, routes: Vec<AllowedIp>) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Runs the timers of the sockets on the given clock, such as the one of the tunnel (see
+    /// `WireGuardTunnel::clock`), instead of one of its own.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// The IP of this peer to connect from for the given port forward.
+    fn source_peer_ip(&self, port_forward: &PortForwardConfig) -> IpAddr {
+        port_forward
+            .source_peer_ip
+            .unwrap_or(self.source_peer_ips[0])
+    }
+
+    fn new_server_socket(port_forward: &PortForwardConfig) -> anyhow::Result<UdpSocket<'static>> {
         static mut UDP_SERVER_RX_META: [UdpPacketMetadata; 0] = [];
         static mut UDP_SERVER_RX_DATA: [u8; 0] = [];
         static mut UDP_SERVER_TX_META: [UdpPacketMetadata; 0] = [];
@@ -68,14 +107,36 @@ impl UdpVirtualInterface {
         Ok(socket)
     }
 
+    /// A server socket that receives the broadcast or multicast datagrams to relay to the local network.
+    fn new_relay_socket(port_forward: &PortForwardConfig) -> anyhow::Result<UdpSocket<'static>> {
+        let rx_meta = vec![UdpPacketMetadata::EMPTY; 10];
+        let rx_data = vec![0u8; MAX_PACKET];
+        let udp_rx_buffer = UdpSocketBuffer::new(rx_meta, rx_data);
+        let udp_tx_buffer = UdpSocketBuffer::new(vec![], vec![]);
+        let mut socket = UdpSocket::new(udp_rx_buffer, udp_tx_buffer);
+        socket
+            .bind((
+                IpAddress::from(port_forward.destination.ip()),
+                port_forward.destination.port(),
+            ))
+            .with_context(|| "UDP virtual relay socket failed to bind")?;
+        Ok(socket)
+    }
+
+    /// A virtual client of the port forward, with the buffers it configures.
     fn new_client_socket(
         source_peer_ip: IpAddr,
         client_port: VirtualPort,
+        port_forward: &PortForwardConfig,
     ) -> anyhow::Result<UdpSocket<'static>> {
-        let rx_meta = vec![UdpPacketMetadata::EMPTY; 10];
-        let tx_meta = vec![UdpPacketMetadata::EMPTY; 10];
-        let rx_data = vec![0u8; MAX_PACKET];
-        let tx_data = vec![0u8; MAX_PACKET];
+        let slots = port_forward
+            .udp_packet_slots
+            .unwrap_or(DEFAULT_PACKET_SLOTS);
+        let size = port_forward.udp_buffer_size.unwrap_or(MAX_PACKET);
+        let rx_meta = vec![UdpPacketMetadata::EMPTY; slots];
+        let tx_meta = vec![UdpPacketMetadata::EMPTY; slots];
+        let rx_data = vec![0u8; size];
+        let tx_data = vec![0u8; size];
         let udp_rx_buffer = UdpSocketBuffer::new(rx_meta, rx_data);
         let udp_tx_buffer = UdpSocketBuffer::new(tx_meta, tx_data);
         let mut socket = UdpSocket::new(udp_rx_buffer, udp_tx_buffer);
@@ -85,11 +146,25 @@ impl UdpVirtualInterface {
         Ok(socket)
     }
 
+    /// Whether the port forward's destination is a remote port forward of this peer, which its
+    /// datagrams reach without going through the WireGuard endpoint.
+    fn is_hairpin(&self, port_forward: &PortForwardConfig) -> bool {
+        self.remote_port_forwards
+            .iter()
+            .any(|remote| remote.source == port_forward.destination)
+    }
+
     fn addresses(&self) -> Vec<IpCidr> {
         let mut addresses = HashSet::new();
-        addresses.insert(IpAddress::from(self.source_peer_ip));
+        for ip in self.source_peer_ips.iter() {
+            addresses.insert(IpAddress::from(*ip));
+        }
         for config in self.port_forwards.iter() {
-            addresses.insert(IpAddress::from(config.destination.ip()));
+            // Broadcast and multicast destinations are not addresses of the interface
+            let destination = IpAddress::from(config.destination.ip());
+            if destination.is_unicast() {
+                addresses.insert(destination);
+            }
         }
         addresses
             .into_iter()
@@ -101,7 +176,7 @@ impl UdpVirtualInterface {
 #[async_trait]
 impl VirtualInterfacePoll for UdpVirtualInterface {
     async fn poll_loop(
-        self,
+        mut self,
         device: VirtualIpDevice,
         mut kill_switch: broadcast::Receiver<()>,
     ) -> anyhow::Result<()> {
@@ -111,19 +186,51 @@ impl VirtualInterfacePoll for UdpVirtualInterface {
         // Create virtual interface (contains smoltcp state machine)
         let mut iface = InterfaceBuilder::new(device, vec![])
             .ip_addrs(addresses)
+            .routes(routes(&self.routes, &self.source_peer_ips))
+            .ipv4_multicast_groups(BTreeMap::new())
             .finalize();
 
+        // Relay sockets, with the destination of their port forward
+        let mut relay_handles: Vec<(SocketAddr, SocketHandle)> = Vec::new();
+
         // Create virtual server for each port forward
         for port_forward in self.port_forwards.iter() {
-            let server_socket = UdpVirtualInterface::new_server_socket(*port_forward)?;
-            iface.add_socket(server_socket);
+            if self.is_hairpin(port_forward) {
+                // The remote port forward's socket receives the datagrams, looped back by the device
+                continue;
+            }
+            if port_forward.relay.is_none() {
+                if port_forward.destination.ip().is_unspecified() {
+                    // Resolved through the tunnel, and unknown until then
+                    continue;
+                }
+                let server_socket = UdpVirtualInterface::new_server_socket(port_forward)?;
+                iface.add_socket(server_socket);
+                continue;
+            }
+            if port_forward.relay == Some(DatagramRelay::Multicast) {
+                iface
+                    .join_multicast_group(
+                        IpAddress::from(port_forward.destination.ip()),
+                        timestamp(&*self.clock),
+                    )
+                    .with_context(|| {
+                        format!(
+                            "Failed to join multicast group {}",
+                            port_forward.destination
+                        )
+                    })?;
+            }
+            let relay_socket = UdpVirtualInterface::new_relay_socket(port_forward)?;
+            relay_handles.push((port_forward.destination, iface.add_socket(relay_socket)));
         }
 
-        // The next time to poll the interface. Can be None for instant poll.
-        let mut next_poll: Option<tokio::time::Instant> = None;
+        // The next time to poll the interface, on the clock of the interface. Can be None for instant poll.
+        let mut next_poll: Option<Duration> = None;
+        let clock = self.clock.clone();
 
         // Bus endpoint to read events
-        let mut endpoint = self.bus.new_endpoint();
+        let mut endpoint = self.endpoint.take().expect("The poll loop runs once");
 
         // Maps virtual port to its client socket handle
         let mut port_client_handle_map: HashMap<VirtualPort, SocketHandle> = HashMap::new();
@@ -131,6 +238,19 @@ impl VirtualInterfacePoll for UdpVirtualInterface {
         // Data packets to send from a virtual client
         let mut send_queue: HashMap<VirtualPort, VecDeque<(SocketAddr, Vec<u8>)>> = HashMap::new();
 
+        // Introspection data for each virtual client
+        let mut sessions: HashMap<VirtualPort, SessionMeta> = HashMap::new();
+
+        // The sources allowed to answer the clients of the local port forwards
+        let mut response_guard = ResponseGuard::default();
+
+        // The last peer that sent a datagram to each remote port forward, which its replies go to
+        let mut remote_peers: HashMap<VirtualPort, SocketAddr> = HashMap::new();
+
+        // The remote port forwards with peer routes, whose datagrams are sent with their peer, which the
+        // replies are addressed to
+        let mut routed_ports: HashSet<VirtualPort> = HashSet::new();
+
         // Create sockets for remote port forwards
         for remote_port_forward in self.remote_port_forwards.iter() {
             let virtual_port =
@@ -138,22 +258,33 @@ impl VirtualInterfacePoll for UdpVirtualInterface {
             let client_socket = UdpVirtualInterface::new_client_socket(
                 remote_port_forward.source.ip(),
                 virtual_port,
+                remote_port_forward,
             )?;
             let client_handle = iface.add_socket(client_socket);
             port_client_handle_map.insert(virtual_port, client_handle);
             send_queue.insert(virtual_port, VecDeque::new());
+            remote_peers.insert(virtual_port, remote_port_forward.destination);
+            if !remote_port_forward.peer_routes.is_empty() {
+                routed_ports.insert(virtual_port);
+            }
+            sessions.insert(
+                virtual_port,
+                SessionMeta::new(remote_port_forward.destination),
+            );
         }
 
         let mut wake = false;
 
         loop {
             tokio::select! {
-                _ = match (next_poll, wake) {
-                    (None, false) => tokio::time::sleep(Duration::MAX),
-                    (None, true) => tokio::time::sleep(Duration::ZERO),
-                    (Some(until), _) => tokio::time::sleep_until(until),
+                _ = async {
+                    match (next_poll, wake) {
+                        (None, false) => futures::future::pending::<()>().await,
+                        (None, true) => {}
+                        (Some(until), _) => sleep_until(&*clock, until).await,
+                    }
                 } => {
-                    let loop_start = smoltcp::time::Instant::now();
+                    let loop_start = timestamp(&*clock);
 
                     match iface.poll(loop_start) {
                         Ok(processed) if processed => {
@@ -186,9 +317,24 @@ impl VirtualInterfacePoll for UdpVirtualInterface {
                         if client_socket.can_recv() {
                             match client_socket.recv() {
                                 Ok((data, peer)) => {
+                                    let peer = SocketAddr::new(peer.addr.into(), peer.port);
+                                    if !response_guard.allows(*virtual_port, peer) {
+                                        debug!("[{}] Dropped datagram from unexpected source {}", virtual_port, peer);
+                                        continue;
+                                    }
+                                    if let Some(remote_peer) = remote_peers.get_mut(virtual_port) {
+                                        *remote_peer = peer;
+                                    }
                                     if !data.is_empty() {
                                         trace!("notifying remote data from peer: {}", peer);
-                                        endpoint.send(Event::RemoteData(*virtual_port, data.to_vec()));
+                                        if let Some(session) = sessions.get_mut(virtual_port) {
+                                            session.record_in(data.len());
+                                        }
+                                        if routed_ports.contains(virtual_port) {
+                                            endpoint.send(Event::RemotePeerData(*virtual_port, peer, data.to_vec(), Instant::now()));
+                                        } else {
+                                            endpoint.send(Event::RemoteData(*virtual_port, data.to_vec(), Instant::now()));
+                                        }
                                     }
                                 }
                                 Err(e) => {
@@ -200,41 +346,85 @@ impl VirtualInterfacePoll for UdpVirtualInterface {
                         }
                     }
 
+                    for (destination, relay_handle) in relay_handles.iter() {
+                        let relay_socket = iface.get_socket::<UdpSocket>(*relay_handle);
+                        while relay_socket.can_recv() {
+                            match relay_socket.recv() {
+                                Ok((data, peer)) => {
+                                    trace!("notifying relayed data from peer: {}", peer);
+                                    endpoint.send(Event::RemoteRelayData(*destination, data.to_vec()));
+                                }
+                                Err(e) => {
+                                    error!("Failed to read from virtual relay socket: {:?}", e);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+
                     // The virtual interface determines the next time to poll (this is to reduce unnecessary polls)
                     next_poll = match iface.poll_delay(loop_start) {
                         Some(smoltcp::time::Duration::ZERO) => None,
                         Some(delay) => {
                             trace!("UDP Virtual interface delayed next poll by {}", delay);
-                            Some(tokio::time::Instant::now() + Duration::from_millis(delay.total_millis()))
+                            Some(clock.now() + Duration::from_millis(delay.total_millis()))
                         },
                         None => None,
                     };
                 }
-                event = endpoint.recv() => {
-                    match event {
-                        Event::LocalData(port_forward, virtual_port, data) => {
-                            let destination = port_forward.destination;
-
-                            if let Some(send_queue) = send_queue.get_mut(&virtual_port) {
-                                // Client socket already exists
-                                send_queue.push_back((destination, data));
-                            } else {
-                                // Client socket does not exist
-                                let client_socket = UdpVirtualInterface::new_client_socket(self.source_peer_ip, virtual_port)?;
-                                let client_handle = iface.add_socket(client_socket);
-
-                                // Add handle to map
-                                port_client_handle_map.insert(virtual_port, client_handle);
-                                send_queue.insert(virtual_port, VecDeque::from(vec![(destination, data)]));
+                events = endpoint.recv_many(EVENT_BATCH) => {
+                    for event in events {
+                        match event {
+                            Event::LocalData(port_forward, virtual_port, data, _) if virtual_port.proto() == PortProtocol::Udp => {
+                                // Remote port forwards reply to the peer that reached them, unless their peer routes
+                                // addressed the reply already
+                                let destination = match remote_peers.get(&virtual_port) {
+                                    Some(peer) if port_forward.remote && !routed_ports.contains(&virtual_port) => *peer,
+                                    _ => port_forward.destination,
+                                };
+                                sessions
+                                    .entry(virtual_port)
+                                    .or_insert_with(|| SessionMeta::new(destination))
+                                    .record_out(data.len());
+                                response_guard.record_sent(&port_forward, virtual_port, destination);
+
+                                if let Some(send_queue) = send_queue.get_mut(&virtual_port) {
+                                    // Client socket already exists
+                                    send_queue.push_back((destination, data));
+                                } else {
+                                    // Client socket does not exist
+                                    let client_socket = UdpVirtualInterface::new_client_socket(self.source_peer_ip(&port_forward), virtual_port, &port_forward)?;
+                                    let client_handle = iface.add_socket(client_socket);
+
+                                    // Add handle to map
+                                    port_client_handle_map.insert(virtual_port, client_handle);
+                                    send_queue.insert(virtual_port, VecDeque::from(vec![(destination, data)]));
+                                }
+                                next_poll = None;
+                                wake = true;
                             }
-                            next_poll = None;
-                            wake = true;
-                        }
-                        Event::VirtualDeviceFed(protocol) if protocol == PortProtocol::Udp => {
-                            next_poll = None;
-                            wake = true;
+                            Event::VirtualDeviceFed(PortProtocol::Udp) => {
+                                next_poll = None;
+                                wake = true;
+                            }
+                            Event::ClientConnectionDropped(virtual_port, ..) if virtual_port.proto() == PortProtocol::Udp => {
+                                // The pool gave the port to another client, whose session starts over
+                                if !remote_peers.contains_key(&virtual_port) {
+                                    sessions.remove(&virtual_port);
+                                    response_guard.forget(virtual_port);
+                                }
+                            }
+                            Event::QueryConnections(reply) => {
+                                let connections: Vec<ConnectionInfo> = sessions
+                                    .iter()
+                                    .map(|(virtual_port, session)| {
+                                        session.info(*virtual_port, ConnectionState::Stateless)
+                                    })
+                                    .collect();
+                                reply.send(connections).ok();
+                            }
+                            _ => {}
                         }
-                        _ => {}
                     }
                 }
                 _ = kill_switch.recv() => {
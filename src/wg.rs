@@ -1,114 +1,943 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::sync::Arc;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::Poll;
 use std::time::Duration;
 
 use crate::Bus;
 use anyhow::Context;
-use boringtun::noise::{Tunn, TunnResult};
-use log::Level;
-use smoltcp::wire::{IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tokio::net::UdpSocket;
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex, Notify};
+use tokio::task::JoinHandle;
 
-use crate::config::{Config, PortProtocol};
-use crate::events::Event;
+use crate::config::{AllowedIp, Config};
+use crate::engine::link::LinkMonitor;
+use crate::engine::packet::{
+    decapsulate, encapsulate, encode_key, inbound_protocol, max_datagram, source_ip,
+    trace_ip_packet, DecapsulateResult, Decapsulated, PacketKind, ProtocolError,
+    HANDSHAKE_INIT_SIZE, MAX_PACKET,
+};
+use crate::engine::timers::PolicyEngine;
+use crate::engine::{new_engine, public_key, ThreadEntropy};
+use crate::error::OnetunError;
+use crate::events::{BusEndpoint, Event};
+use crate::lanes::Lanes;
+use crate::obfuscation::Obfuscator;
+use crate::platform::{self, network, socks, socks::SocksRelay};
+use crate::simulation::NetworkSimulation;
+use crate::udp_batch;
+
+pub use crate::engine::{
+    Clock, EngineError, EngineParams, EngineResult, ManualClock, PrivateKey, ProtocolEngine,
+    ProtocolEngineFactory, PublicKey,
+};
 
 /// The capacity of the channel for received IP packets.
 pub const DISPATCH_CAPACITY: usize = 1_000;
-const MAX_PACKET: usize = 65536;
+
+/// How long to wait for the handshake response of an endpoint address being probed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often the addresses of the endpoint are probed again, after startup.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(300);
+/// How often the routine task runs the timers of the protocol engine, on the clock of the tunnel.
+const TIMER_TICK: Duration = Duration::from_millis(1);
+/// How often the quality of the link is reported on the bus.
+const LINK_QUALITY_INTERVAL: Duration = Duration::from_secs(10);
+/// The rejected datagrams per `LINK_QUALITY_INTERVAL` from which `Event::ProtocolErrorSpike` is sent.
+const PROTOCOL_ERROR_SPIKE: u64 = 50;
+
+/// The clock of the tokio runtime, counted from the creation of the `TokioClock`. It follows the monotonic
+/// clock of the system, unless the time of the runtime is paused (`tokio::time::pause`), as in tests that
+/// advance it to elapse the timers instantly.
+#[derive(Copy, Clone, Debug)]
+pub struct TokioClock(tokio::time::Instant);
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self(tokio::time::Instant::now())
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+/// Waits until the clock reaches the deadline: the timers of the tunnel and of the virtual interfaces expire on
+/// their clock, whether it follows the time of the system or is advanced by hand (see `Clock::wake_on_advance`).
+pub(crate) async fn sleep_until(clock: &dyn Clock, deadline: Duration) {
+    loop {
+        let now = clock.now();
+        if now >= deadline {
+            return;
+        }
+        // Registered before the clock is read again, so that an advance in between isn't missed
+        let mut registered = false;
+        let manual = futures::future::poll_fn(|cx| {
+            if registered {
+                return Poll::Ready(true);
+            }
+            if !clock.wake_on_advance(cx.waker()) {
+                return Poll::Ready(false);
+            }
+            if clock.now() >= deadline {
+                return Poll::Ready(true);
+            }
+            registered = true;
+            Poll::Pending
+        })
+        .await;
+        if !manual {
+            tokio::time::sleep(deadline - now).await;
+        }
+    }
+}
 
 /// A WireGuard tunnel. Encapsulates and decapsulates IP packets
 /// to be sent to and received from a remote UDP endpoint.
-/// This tunnel supports at most 1 peer IP at a time, but supports simultaneous ports.
+/// This tunnel supports the peer IPs assigned in the config, and simultaneous ports.
 pub struct WireGuardTunnel {
-    pub(crate) source_peer_ip: IpAddr,
-    /// `boringtun` peer/tunnel implementation, used for crypto & WG protocol.
-    peer: Box<Tunn>,
-    /// The UDP socket for the public WireGuard endpoint to connect to.
-    udp: Arc<Mutex<UdpSocket>>,
-    /// The address of the public WireGuard endpoint (UDP).
-    pub(crate) endpoint: SocketAddr,
+    pub(crate) source_peer_ips: Vec<IpAddr>,
+    /// The WireGuard protocol implementation: `boringtun`, unless the config gives another one.
+    peer: Arc<dyn ProtocolEngine>,
+    /// The UDP socket for the public WireGuard endpoint to connect to. Replaced when the network changes,
+    /// unless its port is fixed. The consumption task watches it to receive on the new socket.
+    udp: watch::Receiver<Arc<UdpSocket>>,
+    /// Replaces the UDP socket. Sending never fails, since `udp` keeps a receiver.
+    rebind: watch::Sender<Arc<UdpSocket>>,
+    /// The fixed port of the UDP socket (`Config::with_listen_port`), or 0 for a random one.
+    listen_port: u16,
+    /// Whether the UDP socket shares its port with `SO_REUSEADDR` and `SO_REUSEPORT`, which lets it be rebound
+    /// on a fixed port.
+    reuse_port: bool,
+    /// The UDP association with the system SOCKS proxy the datagrams are relayed through, if any.
+    socks: Option<SocksRelay>,
+    /// The address of the public WireGuard endpoint (UDP). May change if roaming is allowed.
+    endpoint: RwLock<SocketAddr>,
+    /// Whether to follow the endpoint when it sends authenticated packets from a new address.
+    allow_roaming: bool,
+    /// How many cookie replies were received from the endpoint, which sends them when it is under load.
+    cookie_replies: AtomicU64,
+    /// The handshake initiation sent to an address of the endpoint to measure its latency, if any.
+    probe: std::sync::Mutex<Option<Probe>>,
+    /// The handshakes with the endpoint, from which the quality of the link is estimated.
+    link: std::sync::Mutex<LinkMonitor>,
+    /// The monotonic clock of the timers of the tunnel, shared with the virtual interfaces.
+    clock: Arc<dyn Clock>,
+    /// The milliseconds of `clock` at which the last authenticated datagram was received, plus one
+    /// (zero until one is received).
+    last_received: AtomicU64,
+    /// How many datagrams can be encapsulated or decapsulated concurrently.
+    crypto_workers: usize,
+    /// The largest outbound IP packet sent ahead of the bulk data, if there are priority lanes.
+    priority_lanes: Option<usize>,
+    /// The MTU of the virtual interfaces, which bounds the IP packets sent through the tunnel.
+    mtu: usize,
+    /// Transforms the datagrams on the wire, if any.
+    obfuscator: Option<Arc<dyn Obfuscator>>,
+    /// Simulated network conditions for the datagrams sent to the endpoint, if any.
+    simulation: Option<NetworkSimulation>,
+    /// Whether decapsulated IP packets go to a tun device instead of the virtual interfaces.
+    tun_mode: bool,
+    /// The source IPs the endpoint may send packets from (cryptokey routing).
+    allowed_ips: Vec<AllowedIp>,
+    /// How many decapsulated packets were dropped for coming from outside of `allowed_ips`.
+    disallowed_packets: AtomicU64,
+    /// How many datagrams from the endpoint the protocol rejected, by category.
+    protocol_errors: std::sync::Mutex<ProtocolErrors>,
+    /// The public key of the endpoint, in the base64 encoding of WireGuard configurations.
+    peer_public_key: String,
+    /// The public key of onetun, in the base64 encoding of WireGuard configurations.
+    public_key: String,
+    /// The persistent keep-alive interval, if any.
+    keepalive_seconds: Option<u16>,
+    /// The milliseconds of `clock` at which the last handshake completed, plus one (zero until one does).
+    last_handshake: AtomicU64,
+    /// Bytes of the datagrams received from the endpoint, and sent to it, before obfuscation.
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+    /// Whether the port forwards turn clients away until the first handshake.
+    require_handshake: bool,
+    /// Whether the endpoint proved its identity: a handshake response or a data packet from it was authenticated.
+    verified: AtomicBool,
+    /// Wakes up the tasks waiting for `verified`.
+    verification: Notify,
     /// Event bus
     bus: Bus,
+    /// The IP packets from the endpoint and the shutdown switch, if the tunnel runs standalone.
+    standalone: Option<Standalone>,
+}
+
+/// A handshake initiation sent to an address of the endpoint, awaiting its response.
+struct Probe {
+    addr: SocketAddr,
+    sent: Duration,
+    /// Receives the round-trip time of the handshake.
+    done: oneshot::Sender<Duration>,
+}
+
+/// The quality of the path to the WireGuard endpoint, estimated passively from the WireGuard timers: the
+/// handshakes, and the datagrams received from the endpoint (see `Handle::link_quality`).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LinkQuality {
+    /// The smoothed round-trip time of the handshakes, once one completed.
+    pub rtt: Option<Duration>,
+    /// The share of the last handshake initiations that went unanswered (retransmitted by the timers), from
+    /// 0 to 1, once one was answered or retransmitted.
+    pub loss: Option<f64>,
+    /// How long ago the last datagram was received from the endpoint, if any was. With keep-alives, a gap much
+    /// longer than their interval means the path is down.
+    pub since_last_received: Option<Duration>,
+}
+
+/// How many datagrams from the WireGuard endpoint the protocol rejected, by category (see
+/// `Handle::protocol_errors`). Failed authentication points to mismatched keys or corruption, while
+/// duplicate and stale counters come from datagrams duplicated or delayed on the path.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "control", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProtocolErrors {
+    /// Failed authentication: the keys don't match, or the datagrams were corrupted.
+    pub invalid_mac: u64,
+    /// Data packets older than the replay window, such as after heavy reordering.
+    pub stale_counter: u64,
+    /// Datagrams for no known session, such as after the endpoint restarted.
+    pub unknown_index: u64,
+    /// Data packets received twice, duplicated on the path or replayed.
+    pub duplicate: u64,
+    /// Malformed datagrams, and the other errors.
+    pub other: u64,
+}
+
+impl ProtocolErrors {
+    /// The number of rejected datagrams.
+    pub fn total(&self) -> u64 {
+        self.invalid_mac + self.stale_counter + self.unknown_index + self.duplicate + self.other
+    }
+
+    fn record(&mut self, error: ProtocolError) {
+        let count = match error {
+            ProtocolError::InvalidMac => &mut self.invalid_mac,
+            ProtocolError::StaleCounter => &mut self.stale_counter,
+            ProtocolError::UnknownIndex => &mut self.unknown_index,
+            ProtocolError::Duplicate => &mut self.duplicate,
+            ProtocolError::Other => &mut self.other,
+        };
+        *count += 1;
+    }
+
+    /// The errors since an earlier snapshot.
+    fn since(&self, earlier: &Self) -> Self {
+        Self {
+            invalid_mac: self.invalid_mac - earlier.invalid_mac,
+            stale_counter: self.stale_counter - earlier.stale_counter,
+            unknown_index: self.unknown_index - earlier.unknown_index,
+            duplicate: self.duplicate - earlier.duplicate,
+            other: self.other - earlier.other,
+        }
+    }
+}
+
+impl Display for ProtocolErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid_mac={} stale_counter={} unknown_index={} duplicate={} other={}",
+            self.invalid_mac, self.stale_counter, self.unknown_index, self.duplicate, self.other
+        )
+    }
+}
+
+/// The WireGuard interface of a tunnel and its peer, as listed by `wg show` (see `Handle::format_wg_show`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct InterfaceStatus {
+    /// The public key of onetun, in the base64 encoding of WireGuard configurations.
+    pub public_key: String,
+    /// The local UDP port the datagrams are exchanged on.
+    pub listen_port: u16,
+    /// The public key of the endpoint, in the base64 encoding of WireGuard configurations.
+    pub peer_public_key: String,
+    /// The current address of the endpoint.
+    pub endpoint: SocketAddr,
+    /// The source IPs the endpoint may send packets from.
+    pub allowed_ips: Vec<AllowedIp>,
+    /// How long ago the last handshake with the endpoint completed, if one did.
+    pub latest_handshake: Option<Duration>,
+    /// Bytes of the WireGuard datagrams received from the endpoint.
+    pub rx_bytes: u64,
+    /// Bytes of the WireGuard datagrams sent to the endpoint.
+    pub tx_bytes: u64,
+    /// The persistent keep-alive interval, if any.
+    pub keepalive_seconds: Option<u16>,
+}
+
+/// Binds the UDP socket to exchange datagrams with the target from, on all the interfaces of its IP version.
+fn bind_socket(target: SocketAddr, port: u16, reuse_port: bool) -> Result<UdpSocket, OnetunError> {
+    let bind_addr: SocketAddr = match target {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], port).into(),
+        SocketAddr::V6(_) => ([0u16; 8], port).into(),
+    };
+    let bind = || -> std::io::Result<UdpSocket> {
+        let socket = Socket::new(
+            Domain::for_address(bind_addr),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        if reuse_port {
+            socket.set_reuse_address(true)?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+        }
+        socket.bind(&SockAddr::from(bind_addr))?;
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())
+    };
+    bind().map_err(|source| OnetunError::BindFailed {
+        addr: bind_addr,
+        source,
+    })
+}
+
+/// Looks up the local address of the route to the target, without blocking the runtime.
+async fn route_source(target: SocketAddr) -> Option<IpAddr> {
+    tokio::task::spawn_blocking(move || network::route_source(target))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// The state of a tunnel started with `WireGuardTunnel::standalone`.
+struct Standalone {
+    /// Receives the IP packets from the endpoint, for `recv_ip_packet`.
+    inbound: Mutex<BusEndpoint>,
+    kill_switch: broadcast::Sender<()>,
+    stopped: AtomicBool,
 }
 
 impl WireGuardTunnel {
     /// Initialize a new WireGuard tunnel.
-    pub async fn new(config: &Config, bus: Bus) -> anyhow::Result<Self> {
-        let source_peer_ip = config.source_peer_ip;
-        let peer = Self::create_tunnel(config)?;
+    pub async fn new(config: &Config, bus: Bus) -> Result<Self, OnetunError> {
+        let source_peer_ips = config.source_peer_ips();
+        let params = EngineParams::new(
+            config.private_key.clone(),
+            config.endpoint_public_key.clone(),
+            config.keepalive_seconds,
+            &ThreadEntropy,
+        );
+        let factory = config.protocol_engine.as_deref();
+        let peer: Arc<dyn ProtocolEngine> = Arc::from(
+            new_engine(factory, &params, config.handshake_rate_limit)
+                .map_err(OnetunError::Tunnel)?,
+        );
+        let public_key = public_key(factory, &config.private_key).map_err(OnetunError::Tunnel)?;
+        let clock = config
+            .clock
+            .clone()
+            .unwrap_or_else(|| Arc::new(TokioClock::default()));
+        // Stricter timers than the engine's, if any
+        let peer = if config.timer_policy.is_default() {
+            peer
+        } else {
+            Arc::new(PolicyEngine::new(peer, config.timer_policy, clock.clone()))
+        };
         let endpoint = config.endpoint_addr;
-        let udp = UdpSocket::bind(match endpoint {
-            SocketAddr::V4(_) => "0.0.0.0:51820",
-            SocketAddr::V6(_) => "[::]:51820",
-        })
-        .await
-        .with_context(|| "Failed to create UDP socket for WireGuard connection")?;
+        let listen_port = config.listen_port.unwrap_or(0);
+        let socks = if config.ignore_system_proxy {
+            None
+        } else {
+            platform::endpoint_relay(endpoint).await
+        };
+        // The socket sends to the relay of the proxy instead of the endpoint
+        let target = socks.as_ref().map_or(endpoint, |socks| socks.relay);
+        let udp = bind_socket(target, listen_port, config.endpoint_reuse_port)?;
+        let (rebind, sockets) = watch::channel(Arc::new(udp));
 
         Ok(Self {
-            source_peer_ip,
+            source_peer_ips,
             peer,
-            udp: Arc::new(Mutex::new(udp)),
-            endpoint,
+            udp: sockets,
+            rebind,
+            listen_port,
+            reuse_port: config.endpoint_reuse_port,
+            socks,
+            endpoint: RwLock::new(endpoint),
+            allow_roaming: config.allow_roaming,
+            cookie_replies: AtomicU64::new(0),
+            probe: std::sync::Mutex::new(None),
+            link: std::sync::Mutex::new(LinkMonitor::default()),
+            clock,
+            last_received: AtomicU64::new(0),
+            crypto_workers: config.crypto_workers.max(1),
+            priority_lanes: config.priority_lanes,
+            mtu: config.max_transmission_unit,
+            tun_mode: config.uses_tun(),
+            obfuscator: config.obfuscator.clone(),
+            simulation: config.network_simulation.clone(),
+            allowed_ips: config.allowed_ips.clone(),
+            disallowed_packets: AtomicU64::new(0),
+            protocol_errors: Default::default(),
+            peer_public_key: encode_key(config.endpoint_public_key.as_bytes()),
+            public_key: encode_key(public_key.as_bytes()),
+            keepalive_seconds: config.keepalive_seconds,
+            last_handshake: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            require_handshake: config.require_handshake,
+            verified: AtomicBool::new(false),
+            verification: Notify::new(),
             bus,
+            standalone: None,
         })
     }
 
+    /// Starts a tunnel on its own, without virtual interfaces or port forwards, for applications
+    /// building their own logic on top of the WireGuard session: they send IP packets with
+    /// `send_ip_packet`, and receive every IP packet from the endpoint with `recv_ip_packet`.
+    /// The tunnel runs until `shutdown` is called.
+    pub async fn standalone(config: &Config) -> Result<Arc<Self>, OnetunError> {
+        let bus = Bus::default();
+        let mut tunnel = Self::new(config, bus.clone()).await?;
+        // Like with a tun device, the application gets the packets of every protocol
+        tunnel.tun_mode = true;
+        let (kill_switch, _) = broadcast::channel(1);
+        tunnel.standalone = Some(Standalone {
+            inbound: Mutex::new(bus.new_endpoint()),
+            kill_switch: kill_switch.clone(),
+            stopped: AtomicBool::new(false),
+        });
+        let tunnel = Arc::new(tunnel);
+
+        // The packets are encapsulated by `send_ip_packet`, so there is no production task
+        {
+            let wg = tunnel.clone();
+            let kill_switch = kill_switch.subscribe();
+            tokio::spawn(async move { wg.routine_task(kill_switch).await });
+        }
+        {
+            let wg = tunnel.clone();
+            let kill_switch = kill_switch.subscribe();
+            tokio::spawn(async move { wg.consume_task(kill_switch).await });
+        }
+        Ok(tunnel)
+    }
+
+    /// Awaits the next IP packet from the endpoint, on a standalone tunnel. Returns `None` once the
+    /// tunnel is shut down, and right away for the other tunnels, whose packets go to their virtual
+    /// interfaces.
+    pub async fn recv_ip_packet(&self) -> Option<Vec<u8>> {
+        let standalone = self.standalone.as_ref()?;
+        let mut kill_switch = standalone.kill_switch.subscribe();
+        if standalone.stopped.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut inbound = standalone.inbound.lock().await;
+        loop {
+            tokio::select! {
+                event = inbound.recv() => {
+                    if let Event::InboundTunPacket(packet) = event {
+                        return Some(packet);
+                    }
+                }
+                _ = kill_switch.recv() => return None,
+            }
+        }
+    }
+
+    /// Stops a standalone tunnel. Does nothing on the other tunnels, which are stopped by `Handle::kill`.
+    pub fn shutdown(&self) {
+        if let Some(standalone) = &self.standalone {
+            standalone.stopped.store(true, Ordering::SeqCst);
+            standalone.kill_switch.send(()).ok();
+        }
+    }
+
+    /// The clock of the timers of the tunnel, which the virtual interfaces share.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    /// The UDP socket the datagrams are exchanged on.
+    fn socket(&self) -> Arc<UdpSocket> {
+        self.udp.borrow().clone()
+    }
+
+    /// The address the datagrams are sent to: the endpoint, or the relay of the SOCKS proxy.
+    fn send_target(&self) -> SocketAddr {
+        self.socks
+            .as_ref()
+            .map_or_else(|| self.endpoint(), |socks| socks.relay)
+    }
+
+    /// The local address of the UDP socket the datagrams are exchanged on.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket().local_addr()
+    }
+
+    /// The MTU of the virtual interfaces, which bounds the IP packets sent through the tunnel.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// The current address of the WireGuard endpoint.
+    pub fn endpoint(&self) -> SocketAddr {
+        *self
+            .endpoint
+            .read()
+            .expect("Failed to acquire endpoint lock")
+    }
+
+    /// How many packets from the endpoint were dropped because their source IP isn't allowed.
+    pub fn disallowed_packets(&self) -> u64 {
+        self.disallowed_packets.load(Ordering::Relaxed)
+    }
+
+    /// How many cookie replies were received from the endpoint, which sends them instead of handshake
+    /// responses when it is under load.
+    pub fn cookie_replies(&self) -> u64 {
+        self.cookie_replies.load(Ordering::Relaxed)
+    }
+
+    /// How many datagrams from the endpoint the protocol rejected, by category.
+    pub fn protocol_errors(&self) -> ProtocolErrors {
+        *self
+            .protocol_errors
+            .lock()
+            .expect("Failed to acquire protocol errors lock")
+    }
+
+    /// The public key of the endpoint, once it proved it holds its private key by completing a handshake.
+    pub fn verified_peer(&self) -> Option<&str> {
+        self.verified
+            .load(Ordering::Relaxed)
+            .then(|| self.peer_public_key.as_str())
+    }
+
+    /// Whether the port forwards turn clients away, as a handshake is required and none completed yet.
+    pub(crate) fn awaits_handshake(&self) -> bool {
+        self.require_handshake && !self.verified.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the endpoint to prove its identity (see `verified_peer`).
+    pub(crate) async fn verification(&self) {
+        loop {
+            let notified = self.verification.notified();
+            if self.verified.load(Ordering::Relaxed) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// The current estimate of the quality of the path to the endpoint.
+    pub fn link_quality(&self) -> LinkQuality {
+        let link = self.link.lock().expect("Failed to acquire link lock");
+        let last_received = self.last_received.load(Ordering::Relaxed);
+        LinkQuality {
+            rtt: link.rtt,
+            loss: link.loss(),
+            since_last_received: (last_received > 0).then(|| {
+                self.clock
+                    .now()
+                    .saturating_sub(Duration::from_millis(last_received - 1))
+            }),
+        }
+    }
+
+    /// The interface and its peer, as listed by `wg show`.
+    pub fn status(&self) -> InterfaceStatus {
+        let last_handshake = self.last_handshake.load(Ordering::Relaxed);
+        InterfaceStatus {
+            public_key: self.public_key.clone(),
+            listen_port: self.local_addr().map_or(0, |addr| addr.port()),
+            peer_public_key: self.peer_public_key.clone(),
+            endpoint: self.endpoint(),
+            allowed_ips: self.allowed_ips.clone(),
+            latest_handshake: (last_handshake > 0).then(|| {
+                self.clock
+                    .now()
+                    .saturating_sub(Duration::from_millis(last_handshake - 1))
+            }),
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            keepalive_seconds: self.keepalive_seconds,
+        }
+    }
+
+    /// Notes the handshake initiation in the datagram, if it is one.
+    fn track_initiation(&self, datagram: &[u8]) {
+        if PacketKind::of(datagram) == PacketKind::HandshakeInit {
+            self.link
+                .lock()
+                .expect("Failed to acquire link lock")
+                .initiation_sent(self.clock.now());
+        }
+    }
+
+    /// Follows the endpoint to a new address, after receiving an authenticated packet from it.
+    fn roam(&self, addr: SocketAddr) {
+        let mut endpoint = self
+            .endpoint
+            .write()
+            .expect("Failed to acquire endpoint lock");
+        if *endpoint != addr {
+            info!("WireGuard endpoint roamed from {} to {}", *endpoint, addr);
+            *endpoint = addr;
+        }
+    }
+
+    /// Measures the handshake round-trip time to each address of the endpoint, and switches to the fastest
+    /// one, unless the current address is within 25% of it. Returns the address used afterwards.
+    pub async fn select_endpoint(&self, addrs: &[SocketAddr]) -> SocketAddr {
+        let current = self.endpoint();
+        let mut current_rtt = None;
+        let mut fastest: Option<(SocketAddr, Duration)> = None;
+        for &addr in addrs {
+            match self.probe(addr).await {
+                Some(rtt) => {
+                    debug!("Handshake with WireGuard endpoint {} took {:?}", addr, rtt);
+                    if addr == current {
+                        current_rtt = Some(rtt);
+                    }
+                    if fastest.map_or(true, |(_, fastest)| rtt < fastest) {
+                        fastest = Some((addr, rtt));
+                    }
+                }
+                None => debug!("WireGuard endpoint {} did not answer the probe", addr),
+            }
+        }
+
+        let (addr, rtt) = match fastest {
+            Some(fastest) => fastest,
+            None => {
+                warn!(
+                    "No address of the WireGuard endpoint answered, keeping {}",
+                    current
+                );
+                return current;
+            }
+        };
+        if addr == current || current_rtt.map_or(false, |current_rtt| current_rtt <= rtt * 5 / 4) {
+            return current;
+        }
+        info!(
+            "Switching WireGuard endpoint from {} to {} ({:?} handshake)",
+            current, addr, rtt
+        );
+        *self
+            .endpoint
+            .write()
+            .expect("Failed to acquire endpoint lock") = addr;
+        self.bus
+            .new_endpoint()
+            .send(Event::EndpointSwitched(current, addr, rtt));
+        addr
+    }
+
+    /// Sends a handshake initiation to the address, and returns the round-trip time of the handshake, if it
+    /// completes in time.
+    pub(crate) async fn probe(&self, addr: SocketAddr) -> Option<Duration> {
+        let mut send_buf = [0u8; HANDSHAKE_INIT_SIZE];
+        let packet = match self.peer.format_handshake_initiation(&mut send_buf, true) {
+            EngineResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => return None,
+        };
+        // The probe supersedes the pending initiation, which will not be answered
+        self.link
+            .lock()
+            .expect("Failed to acquire link lock")
+            .pending = None;
+        let (done, rtt) = oneshot::channel();
+        *self.probe.lock().expect("Failed to acquire probe lock") = Some(Probe {
+            addr,
+            sent: self.clock.now(),
+            done,
+        });
+        if let Err(e) = self.send_datagrams_to(&[packet], addr).await {
+            debug!("Failed to probe WireGuard endpoint {}: {:?}", addr, e);
+            return None;
+        }
+        tokio::time::timeout(PROBE_TIMEOUT, rtt).await.ok()?.ok()
+    }
+
+    /// Completes the probe of the address that sent a handshake response. Returns whether it was probed.
+    fn complete_probe(&self, source: SocketAddr) -> bool {
+        let mut probe = self.probe.lock().expect("Failed to acquire probe lock");
+        match probe.take() {
+            Some(p) if p.addr == source => {
+                p.done.send(self.clock.now().saturating_sub(p.sent)).ok();
+                true
+            }
+            other => {
+                *probe = other;
+                false
+            }
+        }
+    }
+
     /// Encapsulates and sends an IP packet through to the WireGuard endpoint.
     pub async fn send_ip_packet(&self, packet: &[u8]) -> anyhow::Result<()> {
-        trace_ip_packet("Sending IP packet", packet);
-        let mut send_buf = [0u8; MAX_PACKET];
-        match self.peer.encapsulate(packet, &mut send_buf) {
-            TunnResult::WriteToNetwork(packet) => {
-                self.udp
-                    .lock()
-                    .await
-                    .send_to(packet, self.endpoint)
-                    .await
-                    .with_context(|| "Failed to send encrypted IP packet to WireGuard endpoint.")?;
-                debug!(
-                    "Sent {} bytes to WireGuard endpoint (encrypted IP packet)",
-                    packet.len()
-                );
+        if let Some(packet) = encapsulate(&self.peer, packet, self.mtu) {
+            self.send_encapsulated(&[packet]).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends encapsulated packets to the WireGuard endpoint, batching them when possible.
+    async fn send_encapsulated(&self, packets: &[Vec<u8>]) -> anyhow::Result<()> {
+        if packets.is_empty() {
+            return Ok(());
+        }
+        self.send_datagrams(packets)
+            .await
+            .with_context(|| "Failed to send encrypted IP packets to WireGuard endpoint.")?;
+        debug!(
+            "Sent {} bytes to WireGuard endpoint ({} encrypted IP packets)",
+            packets.iter().map(Vec::len).sum::<usize>(),
+            packets.len()
+        );
+        Ok(())
+    }
+
+    /// Sends datagrams to the WireGuard endpoint, obfuscating them if configured.
+    async fn send_datagrams(&self, datagrams: &[Vec<u8>]) -> std::io::Result<()> {
+        self.send_datagrams_to(datagrams, self.endpoint()).await
+    }
+
+    /// Sends datagrams to the given address of the WireGuard endpoint, obfuscating them if configured, through
+    /// the SOCKS proxy if any.
+    async fn send_datagrams_to(
+        &self,
+        datagrams: &[Vec<u8>],
+        addr: SocketAddr,
+    ) -> std::io::Result<()> {
+        let size: usize = datagrams.iter().map(Vec::len).sum();
+        self.tx_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        let obfuscated: Vec<Vec<u8>>;
+        let datagrams = match &self.obfuscator {
+            Some(obfuscator) => {
+                obfuscated = datagrams
+                    .iter()
+                    .flat_map(|datagram| {
+                        let mut datagrams = obfuscator.junk(datagram);
+                        datagrams.push(obfuscator.obfuscate(datagram));
+                        datagrams
+                    })
+                    .collect();
+                &obfuscated
             }
-            TunnResult::Err(e) => {
-                error!("Failed to encapsulate IP packet: {:?}", e);
+            None => datagrams,
+        };
+        let relayed: Vec<Vec<u8>>;
+        let (datagrams, addr) = match &self.socks {
+            Some(socks) => {
+                relayed = datagrams
+                    .iter()
+                    .map(|datagram| socks::encapsulate(datagram, addr))
+                    .collect();
+                (&relayed, socks.relay)
             }
-            TunnResult::Done => {
-                // Ignored
+            None => (datagrams, addr),
+        };
+        match &self.simulation {
+            Some(simulation) => {
+                self.send_simulated(simulation, datagrams, addr);
+                Ok(())
             }
-            other => {
-                error!(
-                    "Unexpected WireGuard state during encapsulation: {:?}",
-                    other
-                );
+            None => udp_batch::send_batch(&self.socket(), datagrams, addr).await,
+        }
+    }
+
+    /// Sends datagrams in the background after the simulated network conditions, which may drop or delay
+    /// each of them. Send errors are only logged.
+    fn send_simulated(
+        &self,
+        simulation: &NetworkSimulation,
+        datagrams: &[Vec<u8>],
+        endpoint: SocketAddr,
+    ) {
+        for datagram in datagrams {
+            if simulation.drops() {
+                trace!("Simulated loss of a {} bytes datagram", datagram.len());
+                continue;
+            }
+            let delay = simulation.delay();
+            let udp = self.socket();
+            let datagram = datagram.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                if let Err(e) = udp.send_to(&datagram, endpoint).await {
+                    error!(
+                        "Failed to send delayed datagram to WireGuard endpoint: {:?}",
+                        e
+                    );
+                }
+            });
+        }
+    }
+
+    /// Sends a handshake initiation to the WireGuard endpoint, unless one is already in progress.
+    /// Completion is notified on the bus with `Event::HandshakeCompleted`.
+    pub async fn initiate_handshake(&self) -> Result<(), OnetunError> {
+        self.send_initiation(false).await
+    }
+
+    /// Sends a handshake initiation to the WireGuard endpoint, even if one is already in progress when forced.
+    async fn send_initiation(&self, force: bool) -> Result<(), OnetunError> {
+        let packet = {
+            let mut send_buf = [0u8; HANDSHAKE_INIT_SIZE];
+            match self.peer.format_handshake_initiation(&mut send_buf, force) {
+                EngineResult::WriteToNetwork(packet) => packet.to_vec(),
+                EngineResult::Err(e) => {
+                    error!("Failed to format handshake initiation: {:?}", e);
+                    return Ok(());
+                }
+                _ => return Ok(()),
             }
         };
+        self.track_initiation(&packet);
+        self.send_datagrams(&[packet]).await.map_err(|source| {
+            OnetunError::EndpointUnreachable {
+                addr: self.endpoint(),
+                source,
+            }
+        })?;
+        debug!("Sent handshake initiation to WireGuard endpoint");
         Ok(())
     }
 
+    /// WireGuard production task. Encapsulates IP packets crafted by the virtual interfaces
+    /// and sends them to the WireGuard endpoint.
+    ///
+    /// With more than one crypto worker, packets are encapsulated concurrently on the runtime's
+    /// threads, but still sent in the order they were produced.
     pub async fn produce_task(&self, mut kill_switch: broadcast::Receiver<()>) -> ! {
         trace!("Starting WireGuard production task");
-        let mut endpoint = self.bus.new_endpoint();
+        let (pipeline_tx, mut pipeline_rx) =
+            mpsc::channel::<JoinHandle<Option<Vec<u8>>>>(self.crypto_workers);
 
-        loop {
-            tokio::select! {
-                event = endpoint.recv() => {
-                    match event {
-                        Event::OutboundInternetPacket(data) => {
-                            match self.send_ip_packet(&data).await {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    error!("{:?}", e);
-                                }
-                            }
+        // With priority lanes, the packets are taken off the bus as soon as they are sent, and queued in their lane
+        let lanes = self.priority_lanes.map(|size| Arc::new(Lanes::new(size)));
+        let mut outbound = match &lanes {
+            Some(lanes) => Outbound::Lanes(lanes.clone()),
+            None => Outbound::Bus(self.bus.new_endpoint()),
+        };
+        let collector = lanes.map(|lanes| (lanes, self.bus.new_endpoint()));
+        let collect = async {
+            match collector {
+                Some((lanes, mut endpoint)) => loop {
+                    lanes.push(recv_outbound_batch(&mut endpoint).await);
+                },
+                None => futures::future::pending().await,
+            }
+        };
+
+        let produce = async {
+            loop {
+                let packets = outbound.next_batch().await;
+                if self.crypto_workers > 1 {
+                    for data in packets {
+                        let (peer, mtu) = (self.peer.clone(), self.mtu);
+                        let job = tokio::spawn(async move { encapsulate(&peer, &data, mtu) });
+                        if pipeline_tx.send(job).await.is_err() {
+                            error!("WireGuard encapsulation pipeline was closed");
                         }
-                        _ => {}
+                    }
+                } else {
+                    let packets: Vec<Vec<u8>> = packets
+                        .iter()
+                        .filter_map(|data| encapsulate(&self.peer, data, self.mtu))
+                        .collect();
+                    if let Err(e) = self.send_encapsulated(&packets).await {
+                        error!("{:?}", e);
+                    }
+                }
+            }
+        };
+
+        let send = async {
+            while let Some(job) = pipeline_rx.recv().await {
+                // Batch together the jobs that are already queued
+                let mut jobs = vec![job];
+                while jobs.len() < udp_batch::BATCH_SIZE {
+                    match pipeline_rx.try_recv() {
+                        Ok(job) => jobs.push(job),
+                        Err(_) => break,
                     }
                 }
-                _ = kill_switch.recv() => {
-                    // A panic isn't pretty, but it's the best way to shut down the task with this return type.
-                    panic!("We've been ordered to die");
+
+                let mut packets = Vec::with_capacity(jobs.len());
+                for job in jobs {
+                    match job.await {
+                        Ok(Some(packet)) => packets.push(packet),
+                        Ok(None) => {}
+                        Err(e) => error!("WireGuard encapsulation worker failed: {:?}", e),
+                    }
+                }
+                if let Err(e) = self.send_encapsulated(&packets).await {
+                    error!("{:?}", e);
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = collect => {}
+            _ = produce => {}
+            _ = send => {}
+            _ = kill_switch.recv() => {}
+        }
+        // A panic isn't pretty, but it's the best way to shut down the task with this return type.
+        panic!("We've been ordered to die");
+    }
+
+    /// Recovers the path to the endpoint after a network change: rebinds the UDP socket, so that the datagrams
+    /// leave from the new interface with a fresh NAT mapping, and initiates a handshake right away instead of
+    /// waiting for the timers of the stale session. The socket is kept if its port is fixed without port
+    /// reuse, or if the datagrams are relayed through a SOCKS proxy, whose association belongs to it.
+    pub async fn network_changed(&self) -> Result<(), OnetunError> {
+        if (self.listen_port == 0 || self.reuse_port) && self.socks.is_none() {
+            let udp = bind_socket(self.send_target(), self.listen_port, self.reuse_port)?;
+            debug!(
+                "Rebound the socket of the WireGuard endpoint to {:?}",
+                udp.local_addr()
+            );
+            let _ = self.rebind.send(Arc::new(udp));
+        }
+        self.send_initiation(true).await
+    }
+
+    /// Network watch task. Looks up the route to the endpoint every `interval`, and when the local address it
+    /// leaves from changes, sends `Event::NetworkChanged` and recovers the path with `network_changed`.
+    ///
+    /// The route is polled on purpose rather than watched (see `platform::network`): a change is noticed up to
+    /// `interval` late. The endpoint is read again for each lookup, since `--allow-roaming` may have moved it.
+    pub async fn network_task(&self, interval: Duration, mut kill_switch: broadcast::Receiver<()>) {
+        trace!("Starting network watch task");
+        let sender = self.bus.new_endpoint().sender();
+        let mut source = route_source(self.send_target()).await;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = kill_switch.recv() => break,
+            }
+            let current = route_source(self.send_target()).await;
+            if current == source {
+                continue;
+            }
+            match current {
+                Some(addr) => info!(
+                    "Network changed: the WireGuard endpoint is now reached from {}, reconnecting",
+                    addr
+                ),
+                None => warn!("Network changed: no route to the WireGuard endpoint"),
+            }
+            sender.send(Event::NetworkChanged(source, current));
+            source = current;
+            // Without a route, wait for the next network instead
+            if current.is_some() {
+                if let Err(e) = self.network_changed().await {
+                    error!("Failed to reconnect after a network change: {}", e);
                 }
             }
         }
@@ -117,17 +946,21 @@ impl WireGuardTunnel {
     /// WireGuard Routine task. Handles Handshake, keep-alive, etc.
     pub async fn routine_task(&self, mut kill_switch: broadcast::Receiver<()>) -> ! {
         trace!("Starting WireGuard routine task");
+        let sender = self.bus.new_endpoint().sender();
+        let mut next_link_quality = self.clock.now() + LINK_QUALITY_INTERVAL;
+        let mut last_errors = ProtocolErrors::default();
+        // Handshake initiations and keep-alives
+        let mut send_buf = vec![0u8; max_datagram(self.mtu)];
 
         loop {
-            let mut send_buf = [0u8; MAX_PACKET];
-
             match self.peer.update_timers(&mut send_buf) {
-                TunnResult::WriteToNetwork(packet) => {
+                EngineResult::WriteToNetwork(packet) => {
                     debug!(
                         "Sending routine packet of {} bytes to WireGuard endpoint",
                         packet.len()
                     );
-                    match self.udp.lock().await.send_to(packet, self.endpoint).await {
+                    self.track_initiation(packet);
+                    match self.send_datagrams(&[packet.to_vec()]).await {
                         Ok(_) => {}
                         Err(e) => {
                             error!(
@@ -137,20 +970,41 @@ impl WireGuardTunnel {
                         }
                     };
                 }
-                TunnResult::Err(e) => {
+                EngineResult::Err(EngineError::ConnectionExpired) => {
+                    // The engine clears the session, and reports the expiry once
+                    warn!(
+                        "Session with WireGuard endpoint expired: no handshake completed in time"
+                    );
+                    sender.send(Event::TunnelExpired);
+                }
+                EngineResult::Err(e) => {
                     error!(
                         "Failed to prepare routine packet for WireGuard endpoint: {:?}",
                         e
                     );
                 }
-                TunnResult::Done => {
-                    // Sleep for a bit
-                    tokio::time::sleep(Duration::from_millis(1)).await;
-                    match kill_switch.try_recv() {
-                        Ok(_) => {
-                            panic!("We've been ordered to die");
+                EngineResult::Done => {
+                    if self.clock.now() >= next_link_quality {
+                        sender.send(Event::LinkQuality(self.link_quality()));
+                        next_link_quality += LINK_QUALITY_INTERVAL;
+
+                        let errors = self.protocol_errors();
+                        let recent = errors.since(&last_errors);
+                        if recent.total() >= PROTOCOL_ERROR_SPIKE {
+                            warn!(
+                                "Rejected {} datagrams from WireGuard endpoint in {:?} ({})",
+                                recent.total(),
+                                LINK_QUALITY_INTERVAL,
+                                recent
+                            );
+                            sender.send(Event::ProtocolErrorSpike(recent));
                         }
-                        Err(_) => {}
+                        last_errors = errors;
+                    }
+                    // Sleep for a tick of the clock, which a manual clock only elapses when advanced
+                    tokio::select! {
+                        _ = sleep_until(&*self.clock, self.clock.now() + TIMER_TICK) => {}
+                        Ok(()) = kill_switch.recv() => panic!("We've been ordered to die"),
                     }
                 }
                 other => {
@@ -162,142 +1016,481 @@ impl WireGuardTunnel {
 
     /// WireGuard consumption task. Receives encrypted packets from the WireGuard endpoint,
     /// decapsulates them, and dispatches newly received IP packets.
+    ///
+    /// With more than one crypto worker, datagrams are decapsulated concurrently on the runtime's
+    /// threads, but dispatched in the order they were received.
     pub async fn consume_task(&self, mut kill_switch: broadcast::Receiver<()>) -> ! {
         trace!("Starting WireGuard consumption task");
         let endpoint = self.bus.new_endpoint();
+        let (pipeline_tx, mut pipeline_rx) =
+            mpsc::channel::<JoinHandle<Decapsulated>>(self.crypto_workers);
 
-        loop {
-            let mut recv_buf = [0u8; MAX_PACKET];
-            let mut send_buf = [0u8; MAX_PACKET];
-
-            let lock = self.udp.lock().await;
-
-            let size = tokio::select! {
-                size = lock.recv(&mut recv_buf) => {
-                    match size {
-                        Ok(size) => size,
-                        Err(e) => {
-                            error!("Failed to read from WireGuard endpoint: {:?}", e);
-                            // Sleep a little bit and try again
-                            tokio::time::sleep(Duration::from_millis(1)).await;
-                            continue;
-                        }
+        let receive = async {
+            let mut buffers = vec![vec![0u8; MAX_PACKET]; udp_batch::BATCH_SIZE];
+            // Borrowing marks the socket as seen, so a replacement made after the borrow is always noticed
+            let mut sockets = self.udp.clone();
+            loop {
+                let udp = sockets.borrow_and_update().clone();
+                let received = tokio::select! {
+                    received = udp_batch::recv_batch(&udp, &mut buffers) => received,
+                    // Receive on the new socket instead
+                    _ = sockets.changed() => continue,
+                };
+                let datagrams = match received {
+                    Ok(datagrams) => datagrams,
+                    Err(e) => {
+                        error!("Failed to read from WireGuard endpoint: {:?}", e);
+                        // Sleep a little bit and try again
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                        continue;
                     }
-                }
-                _ = kill_switch.recv() => {
-                    // A panic isn't pretty, but it's the best way to shut down the task with this return type.
-                    panic!("We've been ordered to die");
-                }
-            };
+                };
 
-            let data = &recv_buf[..size];
-            match self.peer.decapsulate(None, data, &mut send_buf) {
-                TunnResult::WriteToNetwork(packet) => {
-                    match self.udp.lock().await.send_to(packet, self.endpoint).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("Failed to send decapsulation-instructed packet to WireGuard endpoint: {:?}", e);
-                            continue;
-                        }
+                for (buffer, (size, source)) in buffers.iter().zip(datagrams) {
+                    let (source, datagram) = match self.unrelay(source, &buffer[..size]) {
+                        Some(relayed) => relayed,
+                        None => continue,
                     };
-                    loop {
-                        let mut send_buf = [0u8; MAX_PACKET];
-                        match self.peer.decapsulate(None, &[], &mut send_buf) {
-                            TunnResult::WriteToNetwork(packet) => {
-                                let endpoint = self.endpoint.clone();
-                                let udp = self.udp.clone();
-                                let packet = packet.to_vec();
-
-                                tokio::spawn(async move {
-                                    match udp.lock().await.send_to(&packet, endpoint).await {
-                                        Ok(_) => {}
-                                        Err(e) => {
-                                            error!("Failed to send decapsulation-instructed packet to WireGuard endpoint: {:?}", e);
-                                        }
-                                    };
-                                });
-                            }
-                            _ => {
-                                break;
+                    let datagram = match &self.obfuscator {
+                        Some(obfuscator) => match obfuscator.deobfuscate(datagram) {
+                            Some(datagram) => datagram,
+                            None => {
+                                debug!("Dropped malformed obfuscated datagram from {}", source);
+                                continue;
                             }
+                        },
+                        None => datagram.to_vec(),
+                    };
+                    self.rx_bytes
+                        .fetch_add(datagram.len() as u64, Ordering::Relaxed);
+                    if self.crypto_workers > 1 {
+                        let (peer, mtu) = (self.peer.clone(), self.mtu);
+                        let job =
+                            tokio::spawn(async move { decapsulate(&peer, source, &datagram, mtu) });
+                        if pipeline_tx.send(job).await.is_err() {
+                            error!("WireGuard decapsulation pipeline was closed");
                         }
+                    } else {
+                        let decapsulated = decapsulate(&self.peer, source, &datagram, self.mtu);
+                        self.dispatch(decapsulated, &endpoint).await;
                     }
                 }
-                TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
+            }
+        };
+
+        let dispatch = async {
+            while let Some(job) = pipeline_rx.recv().await {
+                match job.await {
+                    Ok(decapsulated) => self.dispatch(decapsulated, &endpoint).await,
+                    Err(e) => error!("WireGuard decapsulation worker failed: {:?}", e),
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = receive => {}
+            _ = dispatch => {}
+            _ = kill_switch.recv() => {}
+        }
+        // A panic isn't pretty, but it's the best way to shut down the task with this return type.
+        panic!("We've been ordered to die");
+    }
+
+    /// Strips the SOCKS header of a datagram relayed by the proxy, returning the address it was sent from.
+    /// Without a proxy, the datagram is returned as received.
+    fn unrelay<'a>(
+        &self,
+        source: SocketAddr,
+        datagram: &'a [u8],
+    ) -> Option<(SocketAddr, &'a [u8])> {
+        match &self.socks {
+            None => Some((source, datagram)),
+            Some(socks) if source == socks.relay => {
+                let relayed = socks::decapsulate(datagram);
+                if relayed.is_none() {
+                    debug!("Dropped malformed datagram from SOCKS relay {}", source);
+                }
+                relayed
+            }
+            Some(_) => {
+                debug!(
+                    "Dropped datagram from {}, which is not the SOCKS relay",
+                    source
+                );
+                None
+            }
+        }
+    }
+
+    /// Acts on a decapsulated datagram: replies to the endpoint, or dispatches the IP packet it contained.
+    async fn dispatch(&self, decapsulated: Decapsulated, endpoint: &BusEndpoint) {
+        if let DecapsulateResult::Err(error) = decapsulated.result {
+            self.protocol_errors
+                .lock()
+                .expect("Failed to acquire protocol errors lock")
+                .record(error);
+        } else {
+            let mut probed = false;
+            if decapsulated.kind == PacketKind::CookieReply {
+                // The engine keeps the cookie for the next handshake initiation, sent by the routine task
+                let replies = self.cookie_replies.fetch_add(1, Ordering::Relaxed) + 1;
+                if replies == 1 {
+                    warn!("WireGuard endpoint is under load, and requires a cookie for handshakes");
+                } else {
                     debug!(
-                        "WireGuard endpoint sent IP packet of {} bytes",
-                        packet.len()
+                        "Received cookie reply from WireGuard endpoint ({} so far)",
+                        replies
+                    );
+                }
+            }
+            if decapsulated.kind == PacketKind::HandshakeResponse {
+                info!(
+                    "Completed handshake with WireGuard peer {}",
+                    self.peer_public_key
+                );
+                endpoint.send(Event::HandshakeCompleted);
+                probed = self.complete_probe(decapsulated.source);
+                if !probed {
+                    self.link
+                        .lock()
+                        .expect("Failed to acquire link lock")
+                        .response_received(self.clock.now());
+                }
+            }
+            if decapsulated.kind.is_authenticated() {
+                let received = self.clock.now().as_millis() as u64 + 1;
+                self.last_received.store(received, Ordering::Relaxed);
+                // As responder, the handshake completes with the response to the endpoint's initiation
+                if decapsulated.kind != PacketKind::Data {
+                    self.last_handshake.store(received, Ordering::Relaxed);
+                }
+            }
+            // A handshake initiation can be replayed, so only the packets of a confirmed session count
+            if matches!(
+                decapsulated.kind,
+                PacketKind::HandshakeResponse | PacketKind::Data
+            ) && !self.verified.swap(true, Ordering::Relaxed)
+            {
+                if self.require_handshake {
+                    info!("WireGuard peer verified, the port forwards now accept clients");
+                }
+                self.verification.notify_waiters();
+            }
+            // Cookie replies are not authenticated by the peer's keys, so they can't trigger roaming.
+            // The responses to probes come from the addresses being compared, which don't roam either.
+            if self.allow_roaming && decapsulated.kind.is_authenticated() && !probed {
+                self.roam(decapsulated.source);
+            }
+        }
+
+        match decapsulated.result {
+            DecapsulateResult::WriteToNetwork(packets) => {
+                if let Err(e) = self.send_datagrams(&packets).await {
+                    error!(
+                        "Failed to send decapsulation-instructed packet to WireGuard endpoint: {:?}",
+                        e
                     );
+                }
+            }
+            DecapsulateResult::WriteToTunnel(packet) => {
+                debug!(
+                    "WireGuard endpoint sent IP packet of {} bytes",
+                    packet.len()
+                );
 
-                    // For debugging purposes: parse packet
-                    trace_ip_packet("Received IP packet", packet);
+                // For debugging purposes: parse packet
+                trace_ip_packet("Received IP packet", &packet);
 
-                    if let Some(proto) = self.route_protocol(packet) {
-                        endpoint.send(Event::InboundInternetPacket(proto, packet.into()));
+                // Cryptokey routing: the peer may only send packets from its allowed IPs
+                let source_ip = source_ip(&packet);
+                if !source_ip.map_or(false, |ip| self.allowed_ips.iter().any(|a| a.contains(&ip))) {
+                    let dropped = self.disallowed_packets.fetch_add(1, Ordering::Relaxed) + 1;
+                    let source = source_ip.map_or_else(|| "?".into(), |ip| ip.to_string());
+                    if dropped == 1 {
+                        warn!(
+                            "Dropped IP packet from {} outside of the allowed IPs",
+                            source
+                        );
+                    } else {
+                        debug!(
+                            "Dropped IP packet from {} outside of the allowed IPs ({} so far)",
+                            source, dropped
+                        );
                     }
+                    return;
+                }
+
+                if self.tun_mode {
+                    // The tun device owns the peer IP, so it gets every packet (ICMP included)
+                    endpoint.send(Event::InboundTunPacket(packet));
+                } else if let Some(proto) = inbound_protocol(&packet, &self.source_peer_ips) {
+                    endpoint.send(Event::InboundInternetPacket(proto, packet));
                 }
-                _ => {}
             }
+            DecapsulateResult::Done | DecapsulateResult::Err(_) => {}
         }
     }
+}
 
-    fn create_tunnel(config: &Config) -> anyhow::Result<Box<Tunn>> {
-        Tunn::new(
-            config.private_key.clone(),
-            config.endpoint_public_key.clone(),
-            None,
-            config.keepalive_seconds,
-            0,
-            None,
-        )
-        .map_err(|s| anyhow::anyhow!("{}", s))
-        .with_context(|| "Failed to initialize boringtun Tunn")
-    }
-
-    /// Determine the inner protocol of the incoming IP packet (TCP/UDP).
-    fn route_protocol(&self, packet: &[u8]) -> Option<PortProtocol> {
-        match IpVersion::of_packet(packet) {
-            Ok(IpVersion::Ipv4) => Ipv4Packet::new_checked(&packet)
-                .ok()
-                // Only care if the packet is destined for this tunnel
-                .filter(|packet| Ipv4Addr::from(packet.dst_addr()) == self.source_peer_ip)
-                .and_then(|packet| match packet.protocol() {
-                    IpProtocol::Tcp => Some(PortProtocol::Tcp),
-                    IpProtocol::Udp => Some(PortProtocol::Udp),
-                    // Unrecognized protocol, so we cannot determine where to route
-                    _ => None,
-                }),
-            Ok(IpVersion::Ipv6) => Ipv6Packet::new_checked(&packet)
-                .ok()
-                // Only care if the packet is destined for this tunnel
-                .filter(|packet| Ipv6Addr::from(packet.dst_addr()) == self.source_peer_ip)
-                .and_then(|packet| match packet.next_header() {
-                    IpProtocol::Tcp => Some(PortProtocol::Tcp),
-                    IpProtocol::Udp => Some(PortProtocol::Udp),
-                    // Unrecognized protocol, so we cannot determine where to route
-                    _ => None,
-                }),
-            _ => None,
+/// Where the production task takes the outbound IP packets from.
+enum Outbound {
+    /// The bus, in the order they were sent.
+    Bus(BusEndpoint),
+    /// The priority lanes, which the bus fills.
+    Lanes(Arc<Lanes>),
+}
+
+impl Outbound {
+    async fn next_batch(&mut self) -> Vec<Vec<u8>> {
+        match self {
+            Self::Bus(endpoint) => recv_outbound_batch(endpoint).await,
+            Self::Lanes(lanes) => lanes.pop_batch(udp_batch::BATCH_SIZE).await,
         }
     }
 }
 
-fn trace_ip_packet(message: &str, packet: &[u8]) {
-    if log_enabled!(Level::Trace) {
-        use smoltcp::wire::*;
-
-        match IpVersion::of_packet(packet) {
-            Ok(IpVersion::Ipv4) => trace!(
-                "{}: {}",
-                message,
-                PrettyPrinter::<Ipv4Packet<&mut [u8]>>::new("", &packet)
-            ),
-            Ok(IpVersion::Ipv6) => trace!(
-                "{}: {}",
-                message,
-                PrettyPrinter::<Ipv6Packet<&mut [u8]>>::new("", &packet)
-            ),
-            _ => {}
+/// Awaits the next outbound IP packet, along with those that are already queued on the bus.
+async fn recv_outbound_batch(endpoint: &mut BusEndpoint) -> Vec<Vec<u8>> {
+    loop {
+        let packets: Vec<Vec<u8>> = endpoint
+            .recv_many(udp_batch::BATCH_SIZE)
+            .await
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::OutboundInternetPacket(data) => Some(data),
+                _ => None,
+            })
+            .collect();
+        if !packets.is_empty() {
+            return packets;
         }
     }
 }
+
+// The tunnels of the tests run boringtun
+#[cfg(all(test, feature = "boringtun"))]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr};
+
+    fn hex(key: &[u8]) -> String {
+        key.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn config(key: &PrivateKey, peer: &PublicKey, port: u16, peer_port: u16) -> Config {
+        Config::builder()
+            .private_key(hex(key.as_bytes()))
+            .endpoint_public_key(hex(peer.as_bytes()))
+            .endpoint_addr(([127, 0, 0, 1], peer_port).into())
+            .source_peer_ip("192.168.4.3".parse().unwrap())
+            .build()
+            .unwrap()
+            .with_listen_port(port)
+    }
+
+    /// Tests that two standalone tunnels exchange IP packets through a WireGuard session.
+    #[tokio::test]
+    async fn test_standalone_tunnels() {
+        let (key_a, key_b) = (PrivateKey::generate(), PrivateKey::generate());
+        let a = WireGuardTunnel::standalone(&config(&key_a, &key_b.public_key(), 51871, 51872))
+            .await
+            .unwrap();
+        let b = WireGuardTunnel::standalone(&config(&key_b, &key_a.public_key(), 51872, 51871))
+            .await
+            .unwrap();
+
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(192, 168, 4, 3),
+            dst_addr: Ipv4Address::new(192, 168, 4, 4),
+            protocol: IpProtocol::Icmp,
+            payload_len: 0,
+            hop_limit: 64,
+        };
+        let mut packet = vec![0u8; repr.buffer_len()];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut packet),
+            &Default::default(),
+        );
+        // Queued until the handshake completes
+        a.send_ip_packet(&packet).await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(5), b.recv_ip_packet())
+            .await
+            .expect("Timed out waiting for the IP packet")
+            .unwrap();
+        assert_eq!(source_ip(&received), Some(IpAddr::from([192, 168, 4, 3])));
+        let peer_b = encode_key(key_b.public_key().as_bytes());
+        assert_eq!(a.verified_peer(), Some(peer_b.as_str()));
+
+        b.shutdown();
+        assert!(b.recv_ip_packet().await.is_none());
+        a.shutdown();
+    }
+
+    fn icmp_packet() -> Vec<u8> {
+        let repr = Ipv4Repr {
+            src_addr: Ipv4Address::new(192, 168, 4, 3),
+            dst_addr: Ipv4Address::new(192, 168, 4, 4),
+            protocol: IpProtocol::Icmp,
+            payload_len: 0,
+            hop_limit: 64,
+        };
+        let mut packet = vec![0u8; repr.buffer_len()];
+        repr.emit(
+            &mut Ipv4Packet::new_unchecked(&mut packet),
+            &Default::default(),
+        );
+        packet
+    }
+
+    /// Tests that the session continues on a new socket after a network change.
+    #[tokio::test]
+    async fn test_network_changed() {
+        let (key_a, key_b) = (PrivateKey::generate(), PrivateKey::generate());
+        let a = WireGuardTunnel::standalone(&config(&key_a, &key_b.public_key(), 0, 51874))
+            .await
+            .unwrap();
+        // Follows the new address of the other side
+        let mut config_b = config(&key_b, &key_a.public_key(), 51874, 51875);
+        config_b.allow_roaming = true;
+        let b = WireGuardTunnel::standalone(&config_b).await.unwrap();
+        let receive = |tunnel: &Arc<WireGuardTunnel>| {
+            let tunnel = tunnel.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), tunnel.recv_ip_packet())
+                    .await
+                    .expect("Timed out waiting for the IP packet")
+                    .unwrap()
+            }
+        };
+
+        a.send_ip_packet(&icmp_packet()).await.unwrap();
+        receive(&b).await;
+        let port = a.status().listen_port;
+
+        a.network_changed().await.unwrap();
+        assert_ne!(a.status().listen_port, port);
+        a.send_ip_packet(&icmp_packet()).await.unwrap();
+        receive(&b).await;
+        // Received on the new socket
+        b.send_ip_packet(&icmp_packet()).await.unwrap();
+        receive(&a).await;
+
+        a.shutdown();
+        b.shutdown();
+    }
+
+    /// Tests that the timers of the tunnel follow the injected clock, or tokio's paused time by default.
+    #[tokio::test(start_paused = true)]
+    async fn test_clock() {
+        let (key_a, key_b) = (PrivateKey::generate(), PrivateKey::generate());
+        let tunnel_config = config(&key_a, &key_b.public_key(), 0, 51873);
+        let default = WireGuardTunnel::standalone(&tunnel_config).await.unwrap();
+        tokio::time::advance(Duration::from_secs(300)).await;
+        assert!(default.clock().now() >= Duration::from_secs(300));
+        default.shutdown();
+
+        let clock = Arc::new(ManualClock::default());
+        let manual = WireGuardTunnel::standalone(&tunnel_config.with_clock(clock.clone()))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(25));
+        assert_eq!(manual.clock().now(), Duration::from_secs(25));
+        assert_eq!(
+            crate::virtual_iface::timestamp(&*manual.clock()),
+            smoltcp::time::Instant::from_secs(25)
+        );
+        manual.shutdown();
+    }
+
+    /// Tests that the timers waiting on a manual clock only expire once it is advanced past their deadline.
+    #[tokio::test]
+    async fn test_sleep_until() {
+        let clock = ManualClock::default();
+        let sleep = sleep_until(&clock, Duration::from_secs(1));
+        tokio::pin!(sleep);
+        // The clock stands still, however long it takes
+        let wait = Duration::from_millis(100);
+        assert!(tokio::time::timeout(wait, &mut sleep).await.is_err());
+        clock.advance(Duration::from_millis(500));
+        assert!(tokio::time::timeout(wait, &mut sleep).await.is_err());
+
+        clock.advance(Duration::from_millis(500));
+        tokio::time::timeout(Duration::from_secs(5), sleep)
+            .await
+            .expect("Timed out waiting for the clock");
+    }
+
+    /// The kind of the next datagram received by the socket within the given time, if any.
+    async fn next_datagram(socket: &UdpSocket, wait: Duration) -> Option<PacketKind> {
+        let mut buffer = [0u8; MAX_PACKET];
+        let (size, _) = tokio::time::timeout(wait, socket.recv_from(&mut buffer))
+            .await
+            .ok()?
+            .unwrap();
+        Some(PacketKind::of(&buffer[..size]))
+    }
+
+    /// Tests that the handshake timers wait on the injected clock: an unanswered handshake initiation is only
+    /// resent once the clock is advanced past the retry interval of the timer policy.
+    #[tokio::test]
+    async fn test_handshake_retry_clock() {
+        let (key_a, key_b) = (PrivateKey::generate(), PrivateKey::generate());
+        // Plays the endpoint, which never answers
+        let endpoint = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let clock = Arc::new(ManualClock::default());
+        let policy = crate::engine::TimerPolicy {
+            handshake_retry_interval: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let port = endpoint.local_addr().unwrap().port();
+        let tunnel_config = config(&key_a, &key_b.public_key(), 0, port)
+            .with_timer_policy(policy)
+            .with_clock(clock.clone());
+        let tunnel = WireGuardTunnel::standalone(&tunnel_config).await.unwrap();
+        assert_eq!(tunnel.clock().now(), Duration::ZERO);
+
+        tunnel.send_ip_packet(&icmp_packet()).await.unwrap();
+        assert_eq!(
+            next_datagram(&endpoint, Duration::from_secs(5)).await,
+            Some(PacketKind::HandshakeInit)
+        );
+        // The clock stands still, so the retry isn't due however long it takes
+        assert!(next_datagram(&endpoint, Duration::from_millis(500))
+            .await
+            .is_none());
+        clock.advance(Duration::from_millis(500));
+        assert!(next_datagram(&endpoint, Duration::from_millis(500))
+            .await
+            .is_none());
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(
+            next_datagram(&endpoint, Duration::from_secs(5)).await,
+            Some(PacketKind::HandshakeInit)
+        );
+        tunnel.shutdown();
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let mut errors = ProtocolErrors::default();
+        errors.record(ProtocolError::InvalidMac);
+        let earlier = errors;
+        errors.record(ProtocolError::InvalidMac);
+        errors.record(ProtocolError::Duplicate);
+        assert_eq!(errors.total(), 3);
+        assert_eq!(
+            errors.since(&earlier),
+            ProtocolErrors {
+                invalid_mac: 1,
+                duplicate: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            errors.to_string(),
+            "invalid_mac=2 stale_counter=0 unknown_index=0 duplicate=1 other=0"
+        );
+    }
+}
@@ -1,7 +1,26 @@
-use onetun::{config::Config, start};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[tokio::main]
-async fn main() {
+use onetun::config::{Command, Config};
+use onetun::control::ControlServer;
+use onetun::diagnostics::Severity;
+use onetun::echo::EchoServer;
+use onetun::error::OnetunError;
+use onetun::events::Event;
+use onetun::{start, Handle};
+use tokio::sync::Mutex;
+
+/// How long `onetun show` waits for the handshake before printing.
+const SHOW_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The exit status when the tunnel dropped and failed to reconnect, with `--exit-on-tunnel-failure`. It follows
+/// the codes of `OnetunError`.
+const EXIT_TUNNEL_FAILED: i32 = 9;
+
+fn main() {
+    if let Some(addr) = Config::echo_server_addr() {
+        run_echo_server(addr);
+    }
     let config = match Config::from_args() {
         Ok(config) => config,
         Err(e) => {
@@ -9,6 +28,280 @@ async fn main() {
             std::process::exit(1);
         }
     };
+    // Sized by --workers, --blocking-threads and --cpu-affinity
+    let runtime = match onetun::build_runtime(&config) {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.code());
+        }
+    };
+    runtime.block_on(run(config));
+}
+
+/// Runs `onetun echo-server` until killed, without a tunnel.
+fn run_echo_server(addr: anyhow::Result<SocketAddr>) -> ! {
+    let addr = match addr {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    pretty_env_logger::formatted_timed_builder()
+        .parse_filters(&std::env::var("ONETUN_LOG").unwrap_or_else(|_| "info".into()))
+        .init();
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start the runtime");
+    runtime.block_on(async {
+        let server = match EchoServer::bind(addr).await {
+            Ok(server) => server,
+            Err(e) => {
+                let e = OnetunError::BindFailed { addr, source: e };
+                eprintln!("{}", e);
+                std::process::exit(e.code());
+            }
+        };
+        if let Ok(addr) = server.local_addr() {
+            println!("Echoing TCP and UDP on {}", addr);
+        }
+        server.run().await;
+    });
+    std::process::exit(0);
+}
+
+async fn run(config: Config) {
+    let command = config.command().cloned();
+    if let Some(Command::Doctor) = command {
+        let findings = onetun::diagnostics::run(&config).await;
+        for finding in findings.iter() {
+            println!("{}", finding);
+        }
+        let failed = findings.iter().any(|f| f.severity == Severity::Error);
+        std::process::exit(if failed { 1 } else { 0 });
+    }
+
+    let exit_on_tunnel_failure = config.exit_on_tunnel_failure();
+    let handle = match start(config).await {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.code());
+        }
+    };
+
+    if let Some(Command::Bench(options)) = &command {
+        let mut failed = false;
+        for (pf, report) in handle.benchmark(options).await {
+            match report {
+                Ok(report) => println!("{}: {}", pf, report),
+                Err(e) => {
+                    failed = true;
+                    eprintln!("{}: benchmark failed: {:?}", pf, e);
+                }
+            }
+        }
+        std::process::exit(if failed { 1 } else { 0 });
+    }
+
+    if let Some(Command::Show) = command {
+        // Printed without a handshake as well, like `wg show` for a peer that never answered
+        tokio::time::timeout(SHOW_HANDSHAKE_TIMEOUT, handle.ready())
+            .await
+            .ok();
+        print!("{}", handle.format_wg_show());
+        std::process::exit(0);
+    }
+
+    if let Some(Command::Check(options)) = command {
+        let mut failed = false;
+        for (pf, report) in handle.check(&options).await {
+            match report {
+                Ok(report) => println!("{}: {}", pf, report),
+                Err(e) => {
+                    failed = true;
+                    println!("{}: unreachable: {}", pf, e);
+                }
+            }
+        }
+        std::process::exit(if failed { 1 } else { 0 });
+    }
+
+    // The control socket shares the handle with the signal handlers
+    let control = match ControlServer::bind(&handle).await {
+        Ok(control) => control,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            handle.kill();
+            std::process::exit(1);
+        }
+    };
+    #[cfg(feature = "grpc")]
+    let grpc = match onetun::control::grpc::GrpcServer::bind(&handle).await {
+        Ok(grpc) => grpc,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            handle.kill();
+            std::process::exit(1);
+        }
+    };
+    let mut kill_switch = handle.get_killer();
+    let handle = Arc::new(Mutex::new(handle));
+    let control = control.map(|control| tokio::spawn(control.run(handle.clone())));
+    // The gRPC control service stops the tunnels it started when the tunnel is killed
+    #[cfg(feature = "grpc")]
+    let grpc = grpc.map(|grpc| tokio::spawn(grpc.run(handle.clone())));
+
+    let mut exit_code = 0;
+    let tunnel_failure = async {
+        match exit_on_tunnel_failure {
+            Some(attempts) => watch_tunnel(handle.clone(), attempts).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(tunnel_failure);
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        // Print the status of the tunnel when receiving SIGUSR1
+        let mut status_signal =
+            signal(SignalKind::user_defined1()).expect("Failed to register SIGUSR1 handler");
+        // Dump the recent traffic when receiving SIGUSR2
+        let mut dump_signal =
+            signal(SignalKind::user_defined2()).expect("Failed to register SIGUSR2 handler");
+        // Save the state and stop when receiving SIGTERM or SIGINT
+        let mut terminate_signal =
+            signal(SignalKind::terminate()).expect("Failed to register SIGTERM handler");
+        let mut interrupt_signal =
+            signal(SignalKind::interrupt()).expect("Failed to register SIGINT handler");
+        loop {
+            tokio::select! {
+                _ = status_signal.recv() => print_status(&handle.lock().await).await,
+                _ = dump_signal.recv() => dump_recent_traffic(&handle.lock().await).await,
+                _ = terminate_signal.recv() => break shutdown(&handle.lock().await).await,
+                _ = interrupt_signal.recv() => break shutdown(&handle.lock().await).await,
+                _ = kill_switch.recv() => break,
+                code = &mut tunnel_failure => {
+                    exit_code = code;
+                    break shutdown(&handle.lock().await).await;
+                }
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::select! {
+            _ = kill_switch.recv() => {}
+            code = &mut tunnel_failure => {
+                exit_code = code;
+                handle.lock().await.kill();
+            }
+        }
+    }
+
+    // Let the control socket remove its file
+    if let Some(control) = control {
+        control.await.ok();
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = grpc {
+        grpc.await.ok();
+    }
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// Re-initiates the handshake each time the session with the WireGuard endpoint expires, and resolves with the
+/// exit status once it failed the given number of times in a row: `EXIT_TUNNEL_FAILED`, or the code of
+/// `OnetunError::HandshakeTimeout` if no handshake ever completed.
+async fn watch_tunnel(handle: Arc<Mutex<Handle>>, attempts: u32) -> i32 {
+    let (mut endpoint, mut connected) = {
+        let handle = handle.lock().await;
+        (
+            handle.subscribe(),
+            handle.interface_status().latest_handshake.is_some(),
+        )
+    };
+    let mut failures = 0;
+    loop {
+        match endpoint.recv().await {
+            Event::HandshakeCompleted => {
+                connected = true;
+                failures = 0;
+            }
+            Event::TunnelExpired => {
+                failures += 1;
+                if failures >= attempts {
+                    eprintln!(
+                        "The tunnel failed to reconnect {} time(s) in a row, exiting",
+                        failures
+                    );
+                    return if connected {
+                        EXIT_TUNNEL_FAILED
+                    } else {
+                        OnetunError::HandshakeTimeout.code()
+                    };
+                }
+                eprintln!(
+                    "The tunnel dropped, reconnecting (attempt {} of {})",
+                    failures + 1,
+                    attempts
+                );
+                if let Err(e) = handle.lock().await.reconnect().await {
+                    eprintln!("Failed to reconnect: {}", e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Saves the state of the tunnel, if it has a state file, then kills it.
+#[cfg_attr(not(unix), allow(dead_code))]
+async fn shutdown(handle: &Handle) {
+    if handle.state_file().is_some() {
+        if let Err(e) = handle.save_state().await {
+            eprintln!("Failed to save the state: {:#}", e);
+        }
+    }
+    handle.kill();
+}
+
+/// Dumps the recent traffic to a pcap file in the working directory.
+#[cfg_attr(not(unix), allow(dead_code))]
+async fn dump_recent_traffic(handle: &Handle) {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let path = format!("onetun-recent-{}.pcap", timestamp);
+    match handle.dump_recent_traffic(&path).await {
+        Ok(packets) => println!("Dumped {} recent packet(s) to {}", packets, path),
+        Err(e) => eprintln!("Failed to dump recent traffic: {:#}", e),
+    }
+}
 
-    start(config).await;
+/// Prints the active sessions to stdout.
+#[cfg_attr(not(unix), allow(dead_code))]
+async fn print_status(handle: &Handle) {
+    let connections = handle.connections().await;
+    println!("{} active connection(s)", connections.len());
+    for c in connections {
+        println!(
+            "{} {} -> {} in={}B out={}B age={}s stalled={}ms {}",
+            c.virtual_port,
+            c.peer_addr
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|| "-".into()),
+            c.destination,
+            c.bytes_in,
+            c.bytes_out,
+            c.age.as_secs(),
+            c.stalled.as_millis(),
+            c.state
+        );
+    }
 }